@@ -0,0 +1,491 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Flattened device tree describing the VM to the guest kernel, following
+//! the Devicetree Specification v0.2 structure block format.
+
+use std::collections::HashMap;
+use std::ffi::CStr;
+use std::result;
+
+use super::gic;
+use super::layout;
+use super::MmioDeviceInfo;
+use vm_memory::{GuestAddress, GuestMemoryError};
+
+const FDT_MAGIC: u32 = 0xd00d_feed;
+const FDT_VERSION: u32 = 17;
+const FDT_LAST_COMP_VERSION: u32 = 16;
+const FDT_HEADER_SIZE: usize = 40;
+
+const FDT_BEGIN_NODE: u32 = 0x1;
+const FDT_END_NODE: u32 = 0x2;
+const FDT_PROP: u32 = 0x3;
+const FDT_END: u32 = 0x9;
+
+// Phandles the nodes refer to each other with.
+const GIC_PHANDLE: u32 = 1;
+const CLOCK_PHANDLE: u32 = 2;
+const ITS_PHANDLE: u32 = 3;
+
+// Interrupt specifiers of the GIC binding: type, number and flags.
+const GIC_FDT_IRQ_TYPE_SPI: u32 = 0;
+const GIC_FDT_IRQ_TYPE_PPI: u32 = 1;
+const IRQ_TYPE_EDGE_RISING: u32 = 1;
+const IRQ_TYPE_LEVEL_HI: u32 = 4;
+
+// PPIs of the architected timer: secure, non-secure, virtual and hypervisor.
+const ARCH_TIMER_PPIS: [u32; 4] = [13, 14, 11, 10];
+// PPI of the GIC maintenance interrupt.
+const GIC_MAINTENANCE_PPI: u32 = 9;
+
+// Frequency of the clock of the PL011, which Linux needs to probe it.
+const APB_PCLK_FREQUENCY: u32 = 24_000_000;
+
+#[derive(Debug)]
+pub enum Error {
+    /// The device tree is larger than the space reserved for it.
+    FdtTooLarge(usize),
+    /// Failed writing the device tree to the guest memory.
+    WriteFdt(GuestMemoryError),
+}
+
+pub type Result<T> = result::Result<T, Error>;
+
+/// Builds a flattened device tree, one node at a time.
+#[derive(Default)]
+pub struct FdtWriter {
+    structure: Vec<u8>,
+    strings: Vec<u8>,
+    string_offsets: HashMap<String, u32>,
+    depth: usize,
+}
+
+impl FdtWriter {
+    pub fn new() -> Self {
+        FdtWriter::default()
+    }
+
+    fn push_u32(&mut self, value: u32) {
+        self.structure.extend_from_slice(&value.to_be_bytes());
+    }
+
+    fn align(&mut self) {
+        while self.structure.len() % 4 != 0 {
+            self.structure.push(0);
+        }
+    }
+
+    fn string_offset(&mut self, name: &str) -> u32 {
+        if let Some(offset) = self.string_offsets.get(name) {
+            return *offset;
+        }
+        let offset = self.strings.len() as u32;
+        self.strings.extend_from_slice(name.as_bytes());
+        self.strings.push(0);
+        self.string_offsets.insert(name.to_string(), offset);
+        offset
+    }
+
+    pub fn begin_node(&mut self, name: &str) {
+        self.push_u32(FDT_BEGIN_NODE);
+        self.structure.extend_from_slice(name.as_bytes());
+        self.structure.push(0);
+        self.align();
+        self.depth += 1;
+    }
+
+    pub fn end_node(&mut self) {
+        assert!(self.depth > 0, "unbalanced device tree node");
+        self.push_u32(FDT_END_NODE);
+        self.depth -= 1;
+    }
+
+    pub fn property(&mut self, name: &str, value: &[u8]) {
+        let name_offset = self.string_offset(name);
+        self.push_u32(FDT_PROP);
+        self.push_u32(value.len() as u32);
+        self.push_u32(name_offset);
+        self.structure.extend_from_slice(value);
+        self.align();
+    }
+
+    pub fn property_null(&mut self, name: &str) {
+        self.property(name, &[]);
+    }
+
+    pub fn property_u32(&mut self, name: &str, value: u32) {
+        self.property(name, &value.to_be_bytes());
+    }
+
+    pub fn property_array_u32(&mut self, name: &str, values: &[u32]) {
+        let bytes: Vec<u8> = values
+            .iter()
+            .flat_map(|v| v.to_be_bytes().to_vec())
+            .collect();
+        self.property(name, &bytes);
+    }
+
+    pub fn property_array_u64(&mut self, name: &str, values: &[u64]) {
+        let bytes: Vec<u8> = values
+            .iter()
+            .flat_map(|v| v.to_be_bytes().to_vec())
+            .collect();
+        self.property(name, &bytes);
+    }
+
+    pub fn property_string(&mut self, name: &str, value: &str) {
+        self.property_string_list(name, &[value]);
+    }
+
+    pub fn property_string_list(&mut self, name: &str, values: &[&str]) {
+        let mut bytes = Vec::new();
+        for value in values {
+            bytes.extend_from_slice(value.as_bytes());
+            bytes.push(0);
+        }
+        self.property(name, &bytes);
+    }
+
+    /// Returns the device tree blob, with its header and an empty memory
+    /// reservation block.
+    pub fn finish(mut self) -> Vec<u8> {
+        assert_eq!(self.depth, 0, "unbalanced device tree node");
+        self.push_u32(FDT_END);
+
+        // The memory reservation block must be 8 bytes aligned, and is
+        // terminated by an entry with a zero address and size.
+        let off_mem_rsvmap = FDT_HEADER_SIZE;
+        let off_dt_struct = off_mem_rsvmap + 16;
+        let off_dt_strings = off_dt_struct + self.structure.len();
+        let totalsize = off_dt_strings + self.strings.len();
+
+        let mut blob = Vec::with_capacity(totalsize);
+        for value in &[
+            FDT_MAGIC,
+            totalsize as u32,
+            off_dt_struct as u32,
+            off_dt_strings as u32,
+            off_mem_rsvmap as u32,
+            FDT_VERSION,
+            FDT_LAST_COMP_VERSION,
+            0, // boot_cpuid_phys
+            self.strings.len() as u32,
+            self.structure.len() as u32,
+        ] {
+            blob.extend_from_slice(&value.to_be_bytes());
+        }
+        blob.extend_from_slice(&[0u8; 16]);
+        blob.extend_from_slice(&self.structure);
+        blob.extend_from_slice(&self.strings);
+        blob
+    }
+}
+
+// KVM gives vCPU n an MPIDR with n modulo 16 in Aff0 and n / 16 in Aff1,
+// which is how the CPU nodes are identified.
+fn vcpu_mpidr(cpu_id: u8) -> u32 {
+    (u32::from(cpu_id) >> 4) << 8 | (u32::from(cpu_id) & 0xf)
+}
+
+fn create_cpu_nodes(fdt: &mut FdtWriter, num_cpus: u8) {
+    fdt.begin_node("cpus");
+    fdt.property_u32("#address-cells", 1);
+    fdt.property_u32("#size-cells", 0);
+    for cpu_id in 0..num_cpus {
+        let mpidr = vcpu_mpidr(cpu_id);
+        fdt.begin_node(&format!("cpu@{:x}", mpidr));
+        fdt.property_string("device_type", "cpu");
+        fdt.property_string("compatible", "arm,arm-v8");
+        fdt.property_string("enable-method", "psci");
+        fdt.property_u32("reg", mpidr);
+        fdt.end_node();
+    }
+    fdt.end_node();
+}
+
+fn create_memory_node(fdt: &mut FdtWriter, ram_regions: &[(GuestAddress, usize)]) {
+    let reg: Vec<u64> = ram_regions
+        .iter()
+        .flat_map(|(start, size)| vec![start.0, *size as u64])
+        .collect();
+    fdt.begin_node(&format!("memory@{:x}", layout::RAM_64BIT_START.0));
+    fdt.property_string("device_type", "memory");
+    fdt.property_array_u64("reg", &reg);
+    fdt.end_node();
+}
+
+fn create_chosen_node(fdt: &mut FdtWriter, cmdline: &CStr, serial: Option<&MmioDeviceInfo>) {
+    fdt.begin_node("chosen");
+    fdt.property("bootargs", cmdline.to_bytes_with_nul());
+    if let Some(serial) = serial {
+        fdt.property_string("stdout-path", &format!("/pl011@{:x}", serial.addr));
+    }
+    fdt.end_node();
+}
+
+fn create_gic_node(fdt: &mut FdtWriter, num_cpus: u8, its: bool) {
+    let (redist_addr, redist_size) = gic::redists_region(u64::from(num_cpus));
+    fdt.begin_node(&format!("intc@{:x}", layout::GIC_V3_DIST_START.0));
+    fdt.property_string("compatible", "arm,gic-v3");
+    fdt.property_null("interrupt-controller");
+    fdt.property_u32("#interrupt-cells", 3);
+    fdt.property_array_u64(
+        "reg",
+        &[
+            layout::GIC_V3_DIST_START.0,
+            layout::GIC_V3_DIST_SIZE,
+            redist_addr,
+            redist_size,
+        ],
+    );
+    fdt.property_u32("#address-cells", 2);
+    fdt.property_u32("#size-cells", 2);
+    fdt.property_null("ranges");
+    fdt.property_u32("phandle", GIC_PHANDLE);
+    fdt.property_array_u32(
+        "interrupts",
+        &[GIC_FDT_IRQ_TYPE_PPI, GIC_MAINTENANCE_PPI, IRQ_TYPE_LEVEL_HI],
+    );
+
+    if its {
+        fdt.begin_node(&format!("msic@{:x}", layout::GIC_V3_ITS_START.0));
+        fdt.property_string("compatible", "arm,gic-v3-its");
+        fdt.property_null("msi-controller");
+        fdt.property_u32("#msi-cells", 1);
+        fdt.property_array_u64(
+            "reg",
+            &[layout::GIC_V3_ITS_START.0, layout::GIC_V3_ITS_SIZE],
+        );
+        fdt.property_u32("phandle", ITS_PHANDLE);
+        fdt.end_node();
+    }
+
+    fdt.end_node();
+}
+
+fn create_timer_node(fdt: &mut FdtWriter) {
+    let interrupts: Vec<u32> = ARCH_TIMER_PPIS
+        .iter()
+        .flat_map(|ppi| vec![GIC_FDT_IRQ_TYPE_PPI, *ppi, IRQ_TYPE_LEVEL_HI])
+        .collect();
+    fdt.begin_node("timer");
+    fdt.property_string("compatible", "arm,armv8-timer");
+    fdt.property_null("always-on");
+    fdt.property_array_u32("interrupts", &interrupts);
+    fdt.end_node();
+}
+
+fn create_clock_node(fdt: &mut FdtWriter) {
+    fdt.begin_node("apb-pclk");
+    fdt.property_string("compatible", "fixed-clock");
+    fdt.property_u32("#clock-cells", 0);
+    fdt.property_u32("clock-frequency", APB_PCLK_FREQUENCY);
+    fdt.property_string("clock-output-names", "clk24mhz");
+    fdt.property_u32("phandle", CLOCK_PHANDLE);
+    fdt.end_node();
+}
+
+fn create_psci_node(fdt: &mut FdtWriter) {
+    // KVM handles the PSCI calls, made through the hypervisor call
+    // instruction.
+    fdt.begin_node("psci");
+    fdt.property_string("compatible", "arm,psci-0.2");
+    fdt.property_string("method", "hvc");
+    fdt.end_node();
+}
+
+fn create_serial_node(fdt: &mut FdtWriter, serial: &MmioDeviceInfo) {
+    fdt.begin_node(&format!("pl011@{:x}", serial.addr));
+    fdt.property_string_list("compatible", &["arm,pl011", "arm,primecell"]);
+    fdt.property_array_u64("reg", &[serial.addr, serial.len]);
+    fdt.property_u32("clocks", CLOCK_PHANDLE);
+    fdt.property_string("clock-names", "apb_pclk");
+    fdt.property_array_u32(
+        "interrupts",
+        &[GIC_FDT_IRQ_TYPE_SPI, serial.irq, IRQ_TYPE_EDGE_RISING],
+    );
+    fdt.end_node();
+}
+
+fn create_rtc_node(fdt: &mut FdtWriter, rtc: &MmioDeviceInfo) {
+    fdt.begin_node(&format!("rtc@{:x}", rtc.addr));
+    fdt.property_string_list("compatible", &["arm,pl031", "arm,primecell"]);
+    fdt.property_array_u64("reg", &[rtc.addr, rtc.len]);
+    fdt.property_u32("clocks", CLOCK_PHANDLE);
+    fdt.property_string("clock-names", "apb_pclk");
+    fdt.property_array_u32(
+        "interrupts",
+        &[GIC_FDT_IRQ_TYPE_SPI, rtc.irq, IRQ_TYPE_EDGE_RISING],
+    );
+    fdt.end_node();
+}
+
+fn create_virtio_node(fdt: &mut FdtWriter, device: &MmioDeviceInfo) {
+    fdt.begin_node(&format!("virtio_mmio@{:x}", device.addr));
+    fdt.property_string("compatible", "virtio,mmio");
+    fdt.property_array_u64("reg", &[device.addr, device.len]);
+    fdt.property_array_u32(
+        "interrupts",
+        &[GIC_FDT_IRQ_TYPE_SPI, device.irq, IRQ_TYPE_EDGE_RISING],
+    );
+    fdt.property_null("dma-coherent");
+    fdt.end_node();
+}
+
+/// Creates the device tree describing the memory, the vCPUs, the GIC, the
+/// serial port, the RTC and the virtio-mmio devices of the VM.
+#[allow(clippy::too_many_arguments)]
+pub fn create_fdt(
+    ram_regions: &[(GuestAddress, usize)],
+    cmdline: &CStr,
+    num_cpus: u8,
+    serial: Option<&MmioDeviceInfo>,
+    rtc: Option<&MmioDeviceInfo>,
+    virtio_devices: &[MmioDeviceInfo],
+    its: bool,
+) -> Result<Vec<u8>> {
+    let mut fdt = FdtWriter::new();
+
+    fdt.begin_node("");
+    fdt.property_string("compatible", "linux,dummy-virt");
+    fdt.property_u32("#address-cells", 2);
+    fdt.property_u32("#size-cells", 2);
+    fdt.property_u32("interrupt-parent", GIC_PHANDLE);
+
+    create_cpu_nodes(&mut fdt, num_cpus);
+    create_memory_node(&mut fdt, ram_regions);
+    create_chosen_node(&mut fdt, cmdline, serial);
+    create_gic_node(&mut fdt, num_cpus, its);
+    create_timer_node(&mut fdt);
+    create_clock_node(&mut fdt);
+    create_psci_node(&mut fdt);
+    if let Some(serial) = serial {
+        create_serial_node(&mut fdt, serial);
+    }
+    if let Some(rtc) = rtc {
+        create_rtc_node(&mut fdt, rtc);
+    }
+    for device in virtio_devices {
+        create_virtio_node(&mut fdt, device);
+    }
+
+    fdt.end_node();
+
+    let blob = fdt.finish();
+    if blob.len() > layout::FDT_MAX_SIZE {
+        return Err(Error::FdtTooLarge(blob.len()));
+    }
+
+    Ok(blob)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::CString;
+
+    fn read_u32(blob: &[u8], offset: usize) -> u32 {
+        let mut bytes = [0u8; 4];
+        bytes.copy_from_slice(&blob[offset..offset + 4]);
+        u32::from_be_bytes(bytes)
+    }
+
+    #[test]
+    fn test_fdt_header() {
+        let mut fdt = FdtWriter::new();
+        fdt.begin_node("");
+        fdt.property_u32("#address-cells", 2);
+        fdt.property_u32("#size-cells", 2);
+        fdt.end_node();
+        let blob = fdt.finish();
+
+        assert_eq!(read_u32(&blob, 0), FDT_MAGIC);
+        assert_eq!(read_u32(&blob, 4) as usize, blob.len());
+        let off_dt_struct = read_u32(&blob, 8) as usize;
+        let off_dt_strings = read_u32(&blob, 12) as usize;
+        assert_eq!(off_dt_struct % 4, 0);
+        assert_eq!(read_u32(&blob, 16) % 8, 0);
+        assert_eq!(read_u32(&blob, 20), FDT_VERSION);
+
+        // Root node, two properties sharing no name, then the end tokens.
+        assert_eq!(read_u32(&blob, off_dt_struct), FDT_BEGIN_NODE);
+        assert_eq!(read_u32(&blob, off_dt_struct + 8), FDT_PROP);
+        assert_eq!(read_u32(&blob, off_dt_struct + 12), 4);
+        assert_eq!(read_u32(&blob, off_dt_struct + 16), 0);
+        assert_eq!(read_u32(&blob, off_dt_struct + 20), 2);
+        assert_eq!(read_u32(&blob, off_dt_strings - 8), FDT_END_NODE);
+        assert_eq!(read_u32(&blob, off_dt_strings - 4), FDT_END);
+        assert_eq!(
+            &blob[off_dt_strings..],
+            &b"#address-cells\0#size-cells\0"[..]
+        );
+    }
+
+    #[test]
+    fn test_property_names_are_shared() {
+        let mut fdt = FdtWriter::new();
+        fdt.begin_node("");
+        fdt.begin_node("a");
+        fdt.property_string("compatible", "a");
+        fdt.end_node();
+        fdt.begin_node("b");
+        fdt.property_string("compatible", "b");
+        fdt.end_node();
+        fdt.end_node();
+        let blob = fdt.finish();
+
+        let off_dt_strings = read_u32(&blob, 12) as usize;
+        assert_eq!(&blob[off_dt_strings..], &b"compatible\0"[..]);
+    }
+
+    #[test]
+    fn test_create_fdt() {
+        let cmdline = CString::new("console=ttyAMA0").unwrap();
+        let serial = MmioDeviceInfo {
+            addr: layout::LEGACY_SERIAL_MAPPED_IO_START.0,
+            len: 0x1000,
+            irq: 1,
+        };
+        let rtc = MmioDeviceInfo {
+            addr: layout::LEGACY_RTC_MAPPED_IO_START.0,
+            len: 0x1000,
+            irq: 3,
+        };
+        let virtio = MmioDeviceInfo {
+            addr: 0x1000_0000,
+            len: 0x1000,
+            irq: 2,
+        };
+        let blob = create_fdt(
+            &[(layout::RAM_64BIT_START, 128 << 20)],
+            &cmdline,
+            4,
+            Some(&serial),
+            Some(&rtc),
+            &[virtio],
+            true,
+        )
+        .unwrap();
+
+        assert_eq!(read_u32(&blob, 0), FDT_MAGIC);
+        assert_eq!(read_u32(&blob, 4) as usize, blob.len());
+        let contains = |pattern: &[u8]| blob.windows(pattern.len()).any(|w| w == pattern);
+        assert!(contains(b"console=ttyAMA0\0"));
+        assert!(contains(b"cpu@3\0"));
+        assert!(contains(b"pl011@9000000\0"));
+        assert!(contains(b"rtc@9010000\0"));
+        assert!(contains(b"arm,pl031\0"));
+        assert!(contains(b"virtio_mmio@10000000\0"));
+        assert!(contains(b"arm,gic-v3-its\0"));
+    }
+
+    #[test]
+    fn test_vcpu_mpidr() {
+        assert_eq!(vcpu_mpidr(0), 0);
+        assert_eq!(vcpu_mpidr(15), 0xf);
+        assert_eq!(vcpu_mpidr(16), 0x100);
+        assert_eq!(vcpu_mpidr(33), 0x201);
+    }
+}
@@ -0,0 +1,144 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use std::result;
+
+use super::layout;
+use kvm_bindings::{
+    kvm_create_device, kvm_device_attr, kvm_device_type_KVM_DEV_TYPE_ARM_VGIC_ITS,
+    kvm_device_type_KVM_DEV_TYPE_ARM_VGIC_V3, KVM_DEV_ARM_VGIC_CTRL_INIT,
+    KVM_DEV_ARM_VGIC_GRP_ADDR, KVM_DEV_ARM_VGIC_GRP_CTRL, KVM_DEV_ARM_VGIC_GRP_NR_IRQS,
+    KVM_VGIC_ITS_ADDR_TYPE, KVM_VGIC_V3_ADDR_TYPE_DIST, KVM_VGIC_V3_ADDR_TYPE_REDIST,
+};
+use kvm_ioctls::{DeviceFd, VmFd};
+
+/// Number of interrupts of the GIC: the 32 private ones (SGIs and PPIs),
+/// followed by the SPIs given to the devices.
+pub const GIC_NR_IRQS: u32 = 32 + layout::IRQ_BASE + layout::IRQ_NUM;
+
+#[derive(Debug)]
+pub enum Error {
+    /// Failed to create the GIC device.
+    CreateGic(kvm_ioctls::Error),
+    /// Failed to set a GIC device attribute.
+    SetDeviceAttribute(kvm_ioctls::Error),
+    /// Too many vCPUs for the redistributors to fit in the memory layout.
+    TooManyVcpus(u64),
+}
+
+pub type Result<T> = result::Result<T, Error>;
+
+/// An in-kernel GICv3, with an optional ITS for MSIs.
+pub struct GicV3 {
+    _device: DeviceFd,
+    _its_device: Option<DeviceFd>,
+}
+
+/// Returns the address and size of the redistributors of `vcpu_count` vCPUs.
+pub fn redists_region(vcpu_count: u64) -> (u64, u64) {
+    (
+        layout::GIC_V3_REDIST_START.0,
+        vcpu_count * layout::GIC_V3_REDIST_SIZE,
+    )
+}
+
+fn set_device_attribute(device: &DeviceFd, group: u32, attr: u64, addr: u64) -> Result<()> {
+    let attr = kvm_device_attr {
+        flags: 0,
+        group,
+        attr,
+        addr,
+    };
+    device
+        .set_device_attr(&attr)
+        .map_err(Error::SetDeviceAttribute)
+}
+
+fn create_device(vm: &VmFd, type_: u32) -> Result<DeviceFd> {
+    let mut device = kvm_create_device {
+        type_,
+        fd: 0,
+        flags: 0,
+    };
+    vm.create_device(&mut device).map_err(Error::CreateGic)
+}
+
+/// Creates the GICv3 of the VM, along with an ITS if `its` is set.
+///
+/// This must be called once all the vCPUs are created, and before any of
+/// them runs.
+pub fn create_gic(vm: &VmFd, vcpu_count: u64, its: bool) -> Result<GicV3> {
+    if vcpu_count > layout::GIC_V3_REDIST_MAX_VCPUS {
+        return Err(Error::TooManyVcpus(vcpu_count));
+    }
+
+    let device = create_device(vm, kvm_device_type_KVM_DEV_TYPE_ARM_VGIC_V3)?;
+
+    // The addresses are passed by reference, KVM reads them from our memory.
+    let dist_addr = layout::GIC_V3_DIST_START.0;
+    set_device_attribute(
+        &device,
+        KVM_DEV_ARM_VGIC_GRP_ADDR,
+        u64::from(KVM_VGIC_V3_ADDR_TYPE_DIST),
+        &dist_addr as *const u64 as u64,
+    )?;
+    let (redist_addr, _) = redists_region(vcpu_count);
+    set_device_attribute(
+        &device,
+        KVM_DEV_ARM_VGIC_GRP_ADDR,
+        u64::from(KVM_VGIC_V3_ADDR_TYPE_REDIST),
+        &redist_addr as *const u64 as u64,
+    )?;
+
+    let nr_irqs: u32 = GIC_NR_IRQS;
+    set_device_attribute(
+        &device,
+        KVM_DEV_ARM_VGIC_GRP_NR_IRQS,
+        0,
+        &nr_irqs as *const u32 as u64,
+    )?;
+    set_device_attribute(
+        &device,
+        KVM_DEV_ARM_VGIC_GRP_CTRL,
+        u64::from(KVM_DEV_ARM_VGIC_CTRL_INIT),
+        0,
+    )?;
+
+    let its_device = if its {
+        let its_device = create_device(vm, kvm_device_type_KVM_DEV_TYPE_ARM_VGIC_ITS)?;
+        let its_addr = layout::GIC_V3_ITS_START.0;
+        set_device_attribute(
+            &its_device,
+            KVM_DEV_ARM_VGIC_GRP_ADDR,
+            u64::from(KVM_VGIC_ITS_ADDR_TYPE),
+            &its_addr as *const u64 as u64,
+        )?;
+        set_device_attribute(
+            &its_device,
+            KVM_DEV_ARM_VGIC_GRP_CTRL,
+            u64::from(KVM_DEV_ARM_VGIC_CTRL_INIT),
+            0,
+        )?;
+        Some(its_device)
+    } else {
+        None
+    };
+
+    Ok(GicV3 {
+        _device: device,
+        _its_device: its_device,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_redists_fit_below_serial() {
+        let (addr, size) = redists_region(layout::GIC_V3_REDIST_MAX_VCPUS);
+        assert!(addr + size <= layout::LEGACY_SERIAL_MAPPED_IO_START.0);
+        assert!(layout::GIC_V3_ITS_START.0 + layout::GIC_V3_ITS_SIZE <= addr);
+    }
+}
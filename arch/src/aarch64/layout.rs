@@ -1,7 +1,70 @@
 // Copyright 2019 Amazon.com, Inc. or its affiliates. All Rights Reserved.
 // SPDX-License-Identifier: Apache-2.0
 
-/// Kernel command line start address.
-pub const CMDLINE_START: usize = 0x0;
-/// Kernel command line start address maximum size.
-pub const CMDLINE_MAX_SIZE: usize = 0x0;
+use vm_memory::{GuestAddress, GuestUsize};
+
+/*
+
+Memory layout documentation and constants
+~~~~~~ ~~~~~~ ~~~~~~~~~~~~~ ~~~ ~~~~~~~~~
+
+Everything below 1GiB is reserved for the platform devices, the RAM starts
+right after. Constants are in order and grouped by range. Take care to update
+all references when making changes and keep them in order.
+
+*/
+
+// ** 32-bit reserved area (start: 0, length: 1GiB) **
+pub const MEM_32BIT_RESERVED_START: GuestAddress = GuestAddress(0);
+pub const MEM_32BIT_RESERVED_SIZE: GuestUsize = (1024 << 20);
+
+// == Fixed constants within the "32-bit reserved" range ==
+
+// GICv3 distributor (start: 128MiB, length: 64KiB)
+pub const GIC_V3_DIST_START: GuestAddress = GuestAddress(0x0800_0000);
+pub const GIC_V3_DIST_SIZE: GuestUsize = 0x1_0000;
+
+// GICv3 ITS (start: 128MiB + 512KiB, length: 128KiB)
+pub const GIC_V3_ITS_START: GuestAddress = GuestAddress(0x0808_0000);
+pub const GIC_V3_ITS_SIZE: GuestUsize = 0x2_0000;
+
+// GICv3 redistributors, one 128KiB frame per vCPU, up to the serial port.
+pub const GIC_V3_REDIST_START: GuestAddress = GuestAddress(0x080a_0000);
+pub const GIC_V3_REDIST_SIZE: GuestUsize = 0x2_0000;
+pub const GIC_V3_REDIST_MAX_VCPUS: u64 =
+    (LEGACY_SERIAL_MAPPED_IO_START.0 - GIC_V3_REDIST_START.0) / GIC_V3_REDIST_SIZE;
+
+// PL011 UART (start: 144MiB, length: 4KiB)
+pub const LEGACY_SERIAL_MAPPED_IO_START: GuestAddress = GuestAddress(0x0900_0000);
+
+// PL031 RTC (start: 144MiB + 64KiB, length: 4KiB)
+pub const LEGACY_RTC_MAPPED_IO_START: GuestAddress = GuestAddress(0x0901_0000);
+
+// Sub range: 32-bit devices (start: 256MiB, length: 768MiB)
+pub const MEM_32BIT_DEVICES_START: GuestAddress = GuestAddress(0x1000_0000);
+pub const MEM_32BIT_DEVICES_SIZE: GuestUsize = (768 << 20);
+
+// == End of "32-bit reserved" range. ==
+
+// ** RAM (start: 1GiB, length: varies) **
+pub const RAM_64BIT_START: GuestAddress = GuestAddress(0x4000_0000);
+
+// == Fixed addresses within the "RAM" range: ==
+
+/// The kernel Image is loaded at its text offset from the start of the RAM,
+/// which is 2MiB aligned as the arm64 boot protocol requires.
+pub const KERNEL_START: GuestAddress = RAM_64BIT_START;
+
+/// The device tree is written at the end of the RAM, and can't be larger
+/// than 2MiB.
+pub const FDT_MAX_SIZE: usize = 0x20_0000;
+
+/// Kernel command line maximum size, it is passed through the device tree.
+pub const CMDLINE_MAX_SIZE: usize = 2048;
+
+// ** Interrupts **
+
+/// The interrupt numbers given to the devices are GIC SPIs, SPI 0 being left
+/// unused as the allocator reserves the 0 value.
+pub const IRQ_BASE: u32 = 1;
+pub const IRQ_NUM: u32 = 95;
@@ -1,27 +1,269 @@
 // Copyright 2019 Amazon.com, Inc. or its affiliates. All Rights Reserved.
 // SPDX-License-Identifier: Apache-2.0
 
+pub mod fdt;
+pub mod gic;
 pub mod layout;
+pub mod regs;
 
-use memory_model::{GuestAddress, GuestMemory};
+use crate::{MmioHole, RegionType};
+use byteorder::{ByteOrder, LittleEndian};
+use kvm_ioctls::{VcpuFd, VmFd};
+use std::ffi::CStr;
+use std::io::{self, Read, Seek, SeekFrom};
+use vm_memory::{
+    Address, Bytes, GuestAddress, GuestMemory, GuestMemoryError, GuestMemoryMmap, GuestUsize,
+};
 
-/// Stub function that needs to be implemented when aarch64 functionality is added.
-pub fn arch_memory_regions(size: usize) -> Vec<(GuestAddress, usize, RegionType)> {
-    vec![(GuestAddress(0), size, RegionType::Ram)]
+// Magic number of the arm64 kernel Image header, "ARM\x64".
+const ARM64_IMAGE_MAGIC: u32 = 0x644d_5241;
+const ARM64_IMAGE_HEADER_SIZE: usize = 64;
+// Text offset of the kernels predating the image_size field of the header.
+const ARM64_DEFAULT_TEXT_OFFSET: u64 = 0x8_0000;
+
+#[derive(Debug)]
+pub enum Error {
+    /// Failed creating the device tree.
+    SetupFdt(fdt::Error),
+    /// Failed reading the kernel Image.
+    KernelImageRead(io::Error),
+    /// The kernel is not an arm64 Image.
+    InvalidKernelImage,
+    /// Failed loading the kernel Image into the guest memory.
+    KernelImageLoad(GuestMemoryError),
+    /// Failed initializing the vCPU or setting its core registers.
+    VcpuConfiguration(regs::Error),
+}
+
+impl From<Error> for super::Error {
+    fn from(e: Error) -> super::Error {
+        super::Error::AArch64Setup(e)
+    }
+}
+
+/// Location of a memory mapped device, along with the SPI it is wired to,
+/// as described in the device tree.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MmioDeviceInfo {
+    pub addr: u64,
+    pub len: u64,
+    pub irq: u32,
+}
+
+/// Initializes a vCPU and, for the boot vCPU when booting a kernel, sets its
+/// core registers. The secondary vCPUs are started through PSCI, from the
+/// boot one.
+///
+/// # Arguments
+///
+/// * `vm` - The VM the vCPU belongs to.
+/// * `fd` - The vCPU to configure.
+/// * `id` - Index of the vCPU.
+/// * `kernel_entry_point` - Address the kernel starts at.
+/// * `guest_mem` - The guest memory, holding the device tree.
+pub fn configure_vcpu(
+    vm: &VmFd,
+    fd: &VcpuFd,
+    id: u8,
+    kernel_entry_point: Option<GuestAddress>,
+    guest_mem: &GuestMemoryMmap,
+) -> super::Result<()> {
+    regs::setup_vcpu_init(vm, fd, id).map_err(Error::VcpuConfiguration)?;
+    if let Some(kernel_entry_point) = kernel_entry_point {
+        if id == 0 {
+            regs::setup_regs(fd, kernel_entry_point.raw_value(), guest_mem)
+                .map_err(Error::VcpuConfiguration)?;
+        }
+    }
+    Ok(())
 }
 
-/// Stub function that needs to be implemented when aarch64 functionality is added.
+/// Returns a Vec of the valid memory addresses.
+/// These should be used to configure the GuestMemory structure for the platform.
+/// For aarch64 the RAM is contiguous and starts right after the 32-bit devices
+/// area, which `mmio_hole` is part of.
+pub fn arch_memory_regions(
+    size: GuestUsize,
+    mmio_hole: MmioHole,
+) -> Vec<(GuestAddress, usize, RegionType)> {
+    vec![
+        (layout::RAM_64BIT_START, size as usize, RegionType::Ram),
+        (
+            mmio_hole.start,
+            mmio_hole.size as usize,
+            RegionType::SubRegion,
+        ),
+    ]
+}
+
+/// Checks the 32-bit MMIO hole is 1MiB aligned, and lies within the 32-bit
+/// devices area, below the RAM.
+pub fn check_mmio_hole(mmio_hole: &MmioHole) -> bool {
+    const ALIGNMENT: u64 = 1 << 20;
+
+    let end = match mmio_hole.start.checked_add(mmio_hole.size) {
+        Some(end) => end,
+        None => return false,
+    };
+
+    mmio_hole.size != 0
+        && mmio_hole.start.raw_value() % ALIGNMENT == 0
+        && mmio_hole.size % ALIGNMENT == 0
+        && mmio_hole.start >= layout::MEM_32BIT_DEVICES_START
+        && end <= layout::RAM_64BIT_START
+}
+
+/// Returns the address the device tree is written at, at the end of the RAM.
+pub fn get_fdt_addr(guest_mem: &GuestMemoryMmap) -> GuestAddress {
+    guest_mem
+        .last_addr()
+        .unchecked_sub(layout::FDT_MAX_SIZE as u64 - 1)
+}
+
+/// Loads an arm64 kernel Image, at its text offset from the start of the RAM.
+/// Returns the entry point, which is the start of the Image.
+pub fn load_kernel<F: Read + Seek>(
+    guest_mem: &GuestMemoryMmap,
+    kernel_image: &mut F,
+) -> super::Result<GuestAddress> {
+    let mut header = [0u8; ARM64_IMAGE_HEADER_SIZE];
+    kernel_image
+        .seek(SeekFrom::Start(0))
+        .and_then(|_| kernel_image.read_exact(&mut header))
+        .map_err(Error::KernelImageRead)?;
+
+    if LittleEndian::read_u32(&header[56..60]) != ARM64_IMAGE_MAGIC {
+        return Err(Error::InvalidKernelImage.into());
+    }
+
+    let image_size = LittleEndian::read_u64(&header[16..24]);
+    let text_offset = if image_size == 0 {
+        ARM64_DEFAULT_TEXT_OFFSET
+    } else {
+        LittleEndian::read_u64(&header[8..16])
+    };
+
+    let kernel_size = kernel_image
+        .seek(SeekFrom::End(0))
+        .and_then(|size| kernel_image.seek(SeekFrom::Start(0)).map(|_| size))
+        .map_err(Error::KernelImageRead)?;
+
+    let kernel_load = layout::KERNEL_START.unchecked_add(text_offset);
+    guest_mem
+        .read_exact_from(kernel_load, kernel_image, kernel_size as usize)
+        .map_err(Error::KernelImageLoad)?;
+
+    Ok(kernel_load)
+}
+
+/// Configures the system and should be called once per vm before starting vcpu threads.
+///
+/// # Arguments
+///
+/// * `guest_mem` - The memory to be used by the guest.
+/// * `ram_regions` - The guest RAM ranges, described in the device tree.
+/// * `cmdline` - The kernel command line.
+/// * `num_cpus` - Number of virtual CPUs the guest will have.
+/// * `serial` - The PL011 serial port, used as the console if present.
+/// * `rtc` - The PL031 RTC.
+/// * `virtio_devices` - The virtio-mmio devices.
+/// * `its` - Whether the GIC has an ITS.
+#[allow(clippy::too_many_arguments)]
 pub fn configure_system(
-    _guest_mem: &GuestMemory,
-    _cmdline_addr: GuestAddress,
-    _cmdline_size: usize,
-    _num_cpus: u8,
-    _rsdp_addr: Option<GuestAddress>,
+    guest_mem: &GuestMemoryMmap,
+    ram_regions: &[(GuestAddress, usize)],
+    cmdline: &CStr,
+    num_cpus: u8,
+    serial: Option<&MmioDeviceInfo>,
+    rtc: Option<&MmioDeviceInfo>,
+    virtio_devices: &[MmioDeviceInfo],
+    its: bool,
 ) -> super::Result<()> {
+    let fdt = fdt::create_fdt(
+        ram_regions,
+        cmdline,
+        num_cpus,
+        serial,
+        rtc,
+        virtio_devices,
+        its,
+    )
+    .map_err(Error::SetupFdt)?;
+
+    guest_mem
+        .write_slice(&fdt, get_fdt_addr(guest_mem))
+        .map_err(|e| Error::SetupFdt(fdt::Error::WriteFdt(e)))?;
+
     Ok(())
 }
 
-/// Stub function that needs to be implemented when aarch64 functionality is added.
-pub fn get_reserved_mem_addr() -> usize {
-    0
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::CString;
+    use std::io::Cursor;
+
+    fn ram(size: usize) -> (Vec<(GuestAddress, usize)>, GuestMemoryMmap) {
+        let ram_regions = vec![(layout::RAM_64BIT_START, size)];
+        let gm = GuestMemoryMmap::from_ranges(&ram_regions).unwrap();
+        (ram_regions, gm)
+    }
+
+    fn kernel_image(text_offset: u64, image_size: u64) -> Vec<u8> {
+        let mut image = vec![0u8; 0x1000];
+        LittleEndian::write_u64(&mut image[8..16], text_offset);
+        LittleEndian::write_u64(&mut image[16..24], image_size);
+        LittleEndian::write_u32(&mut image[56..60], ARM64_IMAGE_MAGIC);
+        image
+    }
+
+    #[test]
+    fn test_regions() {
+        let regions = arch_memory_regions(1 << 29, MmioHole::default());
+        assert_eq!(regions[0].0, layout::RAM_64BIT_START);
+        assert_eq!(regions[0].1, 1 << 29);
+        assert!(regions[0].2 == RegionType::Ram);
+        assert!(
+            layout::MEM_32BIT_DEVICES_START.0 + layout::MEM_32BIT_DEVICES_SIZE
+                <= layout::RAM_64BIT_START.0
+        );
+    }
+
+    #[test]
+    fn test_load_kernel() {
+        let (_, gm) = ram(128 << 20);
+
+        let image = kernel_image(0x10_0000, 0x1000);
+        let entry = load_kernel(&gm, &mut Cursor::new(&image)).unwrap();
+        assert_eq!(entry, layout::KERNEL_START.unchecked_add(0x10_0000));
+        let magic: u32 = gm.read_obj(entry.unchecked_add(56)).unwrap();
+        assert_eq!(magic, ARM64_IMAGE_MAGIC);
+
+        // The text offset is not reliable without an image size.
+        let image = kernel_image(0x10_0000, 0);
+        let entry = load_kernel(&gm, &mut Cursor::new(&image)).unwrap();
+        assert_eq!(
+            entry,
+            layout::KERNEL_START.unchecked_add(ARM64_DEFAULT_TEXT_OFFSET)
+        );
+
+        let mut image = kernel_image(0, 0x1000);
+        image[56] = 0;
+        assert!(load_kernel(&gm, &mut Cursor::new(&image)).is_err());
+    }
+
+    #[test]
+    fn test_system_configuration() {
+        let (ram_regions, gm) = ram(128 << 20);
+        let cmdline = CString::new("console=ttyAMA0").unwrap();
+        configure_system(&gm, &ram_regions, &cmdline, 2, None, None, &[], false).unwrap();
+
+        let fdt_addr = get_fdt_addr(&gm);
+        assert_eq!(
+            fdt_addr.unchecked_add(layout::FDT_MAX_SIZE as u64 - 1),
+            gm.last_addr()
+        );
+        let magic: u32 = gm.read_obj(fdt_addr).unwrap();
+        assert_eq!(u32::from_be(magic), 0xd00d_feed);
+    }
 }
@@ -0,0 +1,102 @@
+// Copyright 2019 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::{mem, result};
+
+use kvm_bindings::{
+    kvm_vcpu_init, KVM_ARM_VCPU_POWER_OFF, KVM_ARM_VCPU_PSCI_0_2, KVM_REG_ARM64, KVM_REG_ARM_CORE,
+    KVM_REG_SIZE_U64,
+};
+use kvm_ioctls::{VcpuFd, VmFd};
+use vm_memory::{GuestAddress, GuestMemoryMmap};
+
+// PSTATE: EL1h, with the debug, SError, IRQ and FIQ exceptions masked, as
+// the arm64 boot protocol requires.
+const PSR_MODE_EL1H: u64 = 0x0000_0005;
+const PSR_F_BIT: u64 = 0x0000_0040;
+const PSR_I_BIT: u64 = 0x0000_0080;
+const PSR_A_BIT: u64 = 0x0000_0100;
+const PSR_D_BIT: u64 = 0x0000_0200;
+const PSTATE_FAULT_BITS_64: u64 = PSR_MODE_EL1H | PSR_A_BIT | PSR_F_BIT | PSR_I_BIT | PSR_D_BIT;
+
+#[derive(Debug)]
+pub enum Error {
+    /// Failed to get the preferred target of the host CPU.
+    GetPreferredTarget(kvm_ioctls::Error),
+    /// Failed to initialize the vCPU.
+    VcpuInit(kvm_ioctls::Error),
+    /// Failed to set a core register.
+    SetCoreRegister(kvm_ioctls::Error),
+}
+
+pub type Result<T> = result::Result<T, Error>;
+
+// Returns the KVM_{GET,SET}_ONE_REG id of the core register found at
+// `offset` bytes in `struct kvm_regs`, which starts with `user_pt_regs`.
+fn arm64_core_reg(offset: usize) -> u64 {
+    KVM_REG_ARM64 | KVM_REG_SIZE_U64 | u64::from(KVM_REG_ARM_CORE) | (offset / 4) as u64
+}
+
+// Offsets of the registers in `user_pt_regs`: 31 general purpose registers
+// followed by sp, pc and pstate.
+const REG_X0_OFFSET: usize = 0;
+const REG_PC_OFFSET: usize = 32 * mem::size_of::<u64>();
+const REG_PSTATE_OFFSET: usize = 33 * mem::size_of::<u64>();
+
+/// Initializes a vCPU for the host CPU type, with PSCI 0.2 so that the guest
+/// can bring the secondary vCPUs up and power the VM off.
+///
+/// # Arguments
+///
+/// * `vm` - The VM the vCPU belongs to.
+/// * `vcpu` - Structure for the VCPU that holds the VCPU's fd.
+/// * `cpu_id` - Index of the vCPU, all but the first one start powered off.
+pub fn setup_vcpu_init(vm: &VmFd, vcpu: &VcpuFd, cpu_id: u8) -> Result<()> {
+    let mut kvi = kvm_vcpu_init::default();
+    vm.get_preferred_target(&mut kvi)
+        .map_err(Error::GetPreferredTarget)?;
+
+    kvi.features[0] |= 1 << KVM_ARM_VCPU_PSCI_0_2;
+    if cpu_id > 0 {
+        kvi.features[0] |= 1 << KVM_ARM_VCPU_POWER_OFF;
+    }
+
+    vcpu.vcpu_init(&kvi).map_err(Error::VcpuInit)
+}
+
+/// Configures the core registers of the boot vCPU, so that it starts in the
+/// kernel with the device tree address in x0.
+///
+/// # Arguments
+///
+/// * `vcpu` - Structure for the VCPU that holds the VCPU's fd.
+/// * `boot_ip` - Starting instruction pointer.
+/// * `guest_mem` - The guest memory, holding the device tree at its end.
+pub fn setup_regs(vcpu: &VcpuFd, boot_ip: u64, guest_mem: &GuestMemoryMmap) -> Result<()> {
+    vcpu.set_one_reg(arm64_core_reg(REG_PSTATE_OFFSET), PSTATE_FAULT_BITS_64)
+        .map_err(Error::SetCoreRegister)?;
+    vcpu.set_one_reg(arm64_core_reg(REG_PC_OFFSET), boot_ip)
+        .map_err(Error::SetCoreRegister)?;
+
+    let GuestAddress(fdt_addr) = super::get_fdt_addr(guest_mem);
+    vcpu.set_one_reg(arm64_core_reg(REG_X0_OFFSET), fdt_addr)
+        .map_err(Error::SetCoreRegister)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use kvm_bindings::user_pt_regs;
+
+    // Offset of pc in `user_pt_regs`, computed from a value of the structure.
+    fn memoffset_pc() -> usize {
+        let regs = user_pt_regs::default();
+        &regs.pc as *const u64 as usize - &regs as *const user_pt_regs as usize
+    }
+
+    #[test]
+    fn test_core_reg_offsets() {
+        assert_eq!(REG_PC_OFFSET, memoffset_pc());
+        assert_eq!(REG_PSTATE_OFFSET, REG_PC_OFFSET + mem::size_of::<u64>());
+    }
+}
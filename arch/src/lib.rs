@@ -21,9 +21,13 @@ extern crate linux_loader;
 extern crate vm_memory;
 
 use std::result;
+use vm_memory::{GuestAddress, GuestUsize};
 
 #[derive(Debug)]
 pub enum Error {
+    #[cfg(target_arch = "aarch64")]
+    /// AArch64 specific error triggered during system configuration.
+    AArch64Setup(aarch64::Error),
     #[cfg(target_arch = "x86_64")]
     /// X86_64 specific error triggered during system configuration.
     X86_64Setup(x86_64::Error),
@@ -52,13 +56,53 @@ pub enum RegionType {
     Reserved,
 }
 
+/// The 32-bit MMIO hole, from which the 32-bit PCI BARs are allocated.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MmioHole {
+    pub start: GuestAddress,
+    pub size: GuestUsize,
+}
+
+impl Default for MmioHole {
+    fn default() -> Self {
+        MmioHole {
+            start: layout::MEM_32BIT_DEVICES_START,
+            size: layout::MEM_32BIT_DEVICES_SIZE,
+        }
+    }
+}
+
+/// Parses a textual UUID into the byte layout expected by the SMBIOS System
+/// Information structure, where the first three fields are little endian.
+pub fn parse_uuid(uuid: &str) -> Option<[u8; 16]> {
+    let groups: Vec<&str> = uuid.split('-').collect();
+    if groups.len() != 5
+        || groups
+            .iter()
+            .zip([8, 4, 4, 4, 12].iter())
+            .any(|(g, len)| g.len() != *len || !g.chars().all(|c| c.is_ascii_hexdigit()))
+    {
+        return None;
+    }
+
+    let digits: String = groups.concat();
+    let mut bytes = [0u8; 16];
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&digits[i * 2..i * 2 + 2], 16).ok()?;
+    }
+    bytes[0..4].reverse();
+    bytes[4..6].reverse();
+    bytes[6..8].reverse();
+
+    Some(bytes)
+}
+
 #[cfg(target_arch = "aarch64")]
 pub mod aarch64;
 
 #[cfg(target_arch = "aarch64")]
 pub use aarch64::{
-    arch_memory_regions, configure_system, get_reserved_mem_addr, layout::CMDLINE_MAX_SIZE,
-    layout::CMDLINE_START,
+    arch_memory_regions, check_mmio_hole, configure_system, layout, layout::CMDLINE_MAX_SIZE,
 };
 
 #[cfg(target_arch = "x86_64")]
@@ -66,5 +110,20 @@ pub mod x86_64;
 
 #[cfg(target_arch = "x86_64")]
 pub use x86_64::{
-    arch_memory_regions, configure_system, layout, layout::CMDLINE_MAX_SIZE, layout::CMDLINE_START,
+    arch_memory_regions, check_mmio_hole, configure_system, layout, layout::CMDLINE_MAX_SIZE,
+    layout::CMDLINE_START, smbios,
 };
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn uuid_parsing() {
+        assert!(parse_uuid("4b9d8f70-2c5e-4c1f-8f3a-5a6b7c8d9e0f").is_some());
+        assert!(parse_uuid("4B9D8F70-2C5E-4C1F-8F3A-5A6B7C8D9E0F").is_some());
+        assert!(parse_uuid("4b9d8f702c5e4c1f8f3a5a6b7c8d9e0f").is_none());
+        assert!(parse_uuid("4b9d8f70-2c5e-4c1f-8f3a-5a6b7c8d9e0g").is_none());
+        assert!(parse_uuid("4b9d8f70-2c5e-4c1f-8f3a5-a6b7c8d9e0f").is_none());
+    }
+}
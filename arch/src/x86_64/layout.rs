@@ -56,11 +56,26 @@ pub const EBDA_START: GuestAddress = GuestAddress(0xa0000);
 // ACPI RSDP table
 pub const RSDP_POINTER: GuestAddress = EBDA_START;
 
+// VM generation ID, below the legacy BIOS area, the ACPI tables being
+// written upwards from the RSDP.
+pub const VMGENID_START: GuestAddress = GuestAddress(0xdf000);
+
+// Legacy BIOS area, which the end of the firmware is copied to (start: 896KiB, length: 128KiB)
+pub const LEGACY_BIOS_START: GuestAddress = GuestAddress(0xe0000);
+pub const LEGACY_BIOS_SIZE: GuestUsize = (128 << 10);
+
+// SMBIOS entry point, within the range the guest scans for it.
+pub const SMBIOS_START: GuestAddress = GuestAddress(0xf0000);
+
 // == End of "EBDA" range ==
 
 // ** High RAM (start: 1MiB, length: 3071MiB) **
 pub const HIGH_RAM_START: GuestAddress = GuestAddress(0x100000);
 
+// The 32-bit MMIO hole can be moved down, as long as it leaves this much RAM
+// below it for the kernel, the initramfs and the boot structures.
+pub const LOW_RAM_MIN_SIZE: GuestUsize = (256 << 20);
+
 // == No fixed addresses in the "High RAM" range ==
 
 // ** 32-bit reserved area (start: 3GiB, length: 1GiB) **
@@ -70,6 +85,7 @@ pub const MEM_32BIT_RESERVED_SIZE: GuestUsize = (1024 << 20);
 // == Fixed constants within the "32-bit reserved" range ==
 
 // Sub range: 32-bit PCI devices (start: 3GiB, length: 640Mib)
+// This is the default 32-bit MMIO hole, which the RAM below 4GiB stops at.
 pub const MEM_32BIT_DEVICES_START: GuestAddress = MEM_32BIT_RESERVED_START;
 pub const MEM_32BIT_DEVICES_SIZE: GuestUsize = (640 << 20);
 
@@ -85,8 +101,12 @@ pub const IOAPIC_SIZE: GuestUsize = 0x20;
 // APIC
 pub const APIC_START: GuestAddress = GuestAddress(0xfee0_0000);
 
-/// Address for the TSS setup.
-pub const KVM_TSS_ADDRESS: GuestAddress = GuestAddress(0xfffb_d000);
+/// Address for the TSS setup, right below the firmware.
+pub const KVM_TSS_ADDRESS: GuestAddress = GuestAddress(0xffbf_d000);
+
+// Firmware, ending at 4GiB so that it covers the reset vector (start: 4GiB - 4MiB, length: 4MiB)
+pub const FIRMWARE_START: GuestAddress = GuestAddress(0xffc0_0000);
+pub const FIRMWARE_MAX_SIZE: GuestUsize = (4 << 20);
 
 // == End of "32-bit reserved" range. ==
 
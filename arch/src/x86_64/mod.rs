@@ -10,8 +10,11 @@ pub mod interrupts;
 pub mod layout;
 mod mptable;
 pub mod regs;
+pub mod smbios;
 
-use crate::RegionType;
+use crate::{MmioHole, RegionType};
+use kvm_bindings::{kvm_cpuid_entry2, kvm_msr_entry, CpuId, Msrs, KVM_CPUID_FLAG_SIGNIFCANT_INDEX};
+use kvm_ioctls::VcpuFd;
 use linux_loader::loader::bootparam::{boot_params, setup_header};
 use std::mem;
 use vm_memory::{
@@ -21,6 +24,17 @@ use vm_memory::{
 const E820_RAM: u32 = 1;
 const E820_RESERVED: u32 = 2;
 
+// CPUID leaf enumerating the SGX capabilities, the EPC sections being
+// described from its sub-leaf 2 on.
+const SGX_CPUID_LEAF: u32 = 0x12;
+const SGX_EPC_FIRST_SUBLEAF: u32 = 2;
+const SGX_EPC_SECTION_VALID: u32 = 1;
+
+const MSR_IA32_FEATURE_CONTROL: u32 = 0x3a;
+const FEATURE_CONTROL_LOCKED: u64 = 1 << 0;
+const FEATURE_CONTROL_SGX_LC: u64 = 1 << 17;
+const FEATURE_CONTROL_SGX_ENABLE: u64 = 1 << 18;
+
 // This is a workaround to the Rust enforcement specifying that any implementation of a foreign
 // trait (in this case `DataInit`) where:
 // *    the type that is implementing the trait is foreign or
@@ -38,6 +52,22 @@ pub enum Error {
     E820Configuration,
     /// Error writing MP table to memory.
     MpTableSetup(mptable::Error),
+    /// The call to KVM_SET_CPUID2 failed.
+    SetSupportedCpusFailed(kvm_ioctls::Error),
+    /// Error configuring the MSR registers.
+    MSRSConfiguration(regs::Error),
+    /// Cannot set the MSR overrides.
+    SetMsrOverrides(kvm_ioctls::Error),
+    /// KVM rejected the override of the MSR with this index.
+    MsrOverrideRejected(u32),
+    /// Error configuring the general purpose registers.
+    REGSConfiguration(regs::Error),
+    /// Error configuring the floating point related registers.
+    FPUConfiguration(regs::Error),
+    /// Error configuring the special registers.
+    SREGSConfiguration(regs::Error),
+    /// Cannot set the local interruption due to bad configuration.
+    LocalIntConfiguration(interrupts::Error),
 }
 
 impl From<Error> for super::Error {
@@ -46,64 +76,295 @@ impl From<Error> for super::Error {
     }
 }
 
+#[derive(Copy, Clone, PartialEq)]
+pub enum CpuidReg {
+    EAX,
+    EBX,
+    ECX,
+    EDX,
+}
+
+pub struct CpuidPatch {
+    pub function: u32,
+    pub index: u32,
+    pub flags_bit: Option<u8>,
+    pub eax_bit: Option<u8>,
+    pub ebx_bit: Option<u8>,
+    pub ecx_bit: Option<u8>,
+    pub edx_bit: Option<u8>,
+}
+
+impl CpuidPatch {
+    fn set_cpuid_reg(
+        cpuid: &mut CpuId,
+        function: u32,
+        index: Option<u32>,
+        reg: CpuidReg,
+        value: u32,
+    ) {
+        let entries = cpuid.as_mut_slice();
+
+        for entry in entries.iter_mut() {
+            if entry.function == function && (index == None || index.unwrap() == entry.index) {
+                match reg {
+                    CpuidReg::EAX => {
+                        entry.eax = value;
+                    }
+                    CpuidReg::EBX => {
+                        entry.ebx = value;
+                    }
+                    CpuidReg::ECX => {
+                        entry.ecx = value;
+                    }
+                    CpuidReg::EDX => {
+                        entry.edx = value;
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn patch_cpuid(cpuid: &mut CpuId, patches: Vec<CpuidPatch>) {
+        let entries = cpuid.as_mut_slice();
+
+        for entry in entries.iter_mut() {
+            for patch in patches.iter() {
+                if entry.function == patch.function && entry.index == patch.index {
+                    if let Some(flags_bit) = patch.flags_bit {
+                        entry.flags |= 1 << flags_bit;
+                    }
+                    if let Some(eax_bit) = patch.eax_bit {
+                        entry.eax |= 1 << eax_bit;
+                    }
+                    if let Some(ebx_bit) = patch.ebx_bit {
+                        entry.ebx |= 1 << ebx_bit;
+                    }
+                    if let Some(ecx_bit) = patch.ecx_bit {
+                        entry.ecx |= 1 << ecx_bit;
+                    }
+                    if let Some(edx_bit) = patch.edx_bit {
+                        entry.edx |= 1 << edx_bit;
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// A section of SGX Enclave Page Cache in the guest physical address space.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SgxEpcSection {
+    pub start: GuestAddress,
+    pub size: GuestUsize,
+}
+
+fn cpuid_entry(cpuid: &CpuId, function: u32, index: u32) -> Option<&kvm_cpuid_entry2> {
+    cpuid
+        .as_slice()
+        .iter()
+        .find(|e| e.function == function && e.index == index)
+}
+
+/// Whether `cpuid` exposes SGX: the SGX feature flag of the leaf 7, along
+/// with the SGX1 instructions of the SGX leaf.
+pub fn sgx_supported(cpuid: &CpuId) -> bool {
+    cpuid_entry(cpuid, 7, 0).map_or(false, |e| e.ebx & (1 << 2) != 0)
+        && cpuid_entry(cpuid, SGX_CPUID_LEAF, 0).map_or(false, |e| e.eax & 1 != 0)
+}
+
+/// Describes the EPC `sections` in the SGX leaf of `cpuid`, replacing the
+/// sections it described so far.
+pub fn set_sgx_epc_sections(cpuid: &mut CpuId, sections: &[SgxEpcSection]) {
+    let mut entries: Vec<kvm_cpuid_entry2> = cpuid
+        .as_slice()
+        .iter()
+        .filter(|e| !(e.function == SGX_CPUID_LEAF && e.index >= SGX_EPC_FIRST_SUBLEAF))
+        .cloned()
+        .collect();
+
+    for (index, section) in sections.iter().enumerate() {
+        let start = section.start.raw_value();
+        entries.push(kvm_cpuid_entry2 {
+            function: SGX_CPUID_LEAF,
+            index: SGX_EPC_FIRST_SUBLEAF + index as u32,
+            flags: KVM_CPUID_FLAG_SIGNIFCANT_INDEX,
+            eax: (start as u32 & 0xffff_f000) | SGX_EPC_SECTION_VALID,
+            ebx: (start >> 32) as u32,
+            ecx: (section.size as u32 & 0xffff_f000) | SGX_EPC_SECTION_VALID,
+            edx: (section.size >> 32) as u32,
+            ..Default::default()
+        });
+    }
+    // The first invalid sub-leaf ends the list of sections.
+    entries.push(kvm_cpuid_entry2 {
+        function: SGX_CPUID_LEAF,
+        index: SGX_EPC_FIRST_SUBLEAF + sections.len() as u32,
+        flags: KVM_CPUID_FLAG_SIGNIFCANT_INDEX,
+        ..Default::default()
+    });
+
+    *cpuid = CpuId::from_entries(&entries);
+}
+
+/// Returns the IA32_FEATURE_CONTROL value enabling SGX in the guest, if
+/// `cpuid` exposes SGX along with EPC sections. The MSR is locked, as the
+/// firmware would, the guest kernel refusing to use SGX otherwise. The
+/// launch control is left to the guest when `cpuid` exposes it.
+pub fn sgx_feature_control(cpuid: &CpuId) -> Option<kvm_msr_entry> {
+    let has_epc = cpuid_entry(cpuid, SGX_CPUID_LEAF, SGX_EPC_FIRST_SUBLEAF)
+        .map_or(false, |e| e.eax & 0xf == SGX_EPC_SECTION_VALID);
+    if !sgx_supported(cpuid) || !has_epc {
+        return None;
+    }
+
+    let mut data = FEATURE_CONTROL_LOCKED | FEATURE_CONTROL_SGX_ENABLE;
+    if cpuid_entry(cpuid, 7, 0).map_or(false, |e| e.ecx & (1 << 30) != 0) {
+        data |= FEATURE_CONTROL_SGX_LC;
+    }
+
+    Some(kvm_msr_entry {
+        index: MSR_IA32_FEATURE_CONTROL,
+        data,
+        ..Default::default()
+    })
+}
+
+/// Configures the CPUID, the MSRs and, when booting a kernel, the registers
+/// of a vCPU. Fails with the first of `msr_overrides` KVM rejects, as it
+/// stops setting them there.
+///
+/// # Arguments
+///
+/// * `fd` - The vCPU to configure.
+/// * `id` - Index of the vCPU, exposed as its x2APIC ID.
+/// * `kernel_entry_point` - Address the kernel starts at. Without it, the
+///   registers are left in the x86 reset state KVM creates the vCPU in, with
+///   CS:IP pointing at 0xffff_fff0.
+/// * `guest_mem` - The guest memory, holding the page tables and the GDT.
+/// * `cpuid` - The CPUID of the VM.
+/// * `msr_overrides` - MSR values set after the default ones.
+/// * `lapic` - Whether the local APIC is emulated in kernel.
+pub fn configure_vcpu(
+    fd: &VcpuFd,
+    id: u8,
+    kernel_entry_point: Option<GuestAddress>,
+    guest_mem: &GuestMemoryMmap,
+    cpuid: CpuId,
+    msr_overrides: &[kvm_msr_entry],
+    lapic: bool,
+) -> super::Result<()> {
+    let mut cpuid = cpuid;
+    CpuidPatch::set_cpuid_reg(&mut cpuid, 0xb, None, CpuidReg::EDX, u32::from(id));
+    fd.set_cpuid2(&cpuid)
+        .map_err(Error::SetSupportedCpusFailed)?;
+
+    regs::setup_msrs(fd).map_err(Error::MSRSConfiguration)?;
+    if !msr_overrides.is_empty() {
+        let count = fd
+            .set_msrs(&Msrs::from_entries(msr_overrides))
+            .map_err(Error::SetMsrOverrides)?;
+        if let Some(rejected) = msr_overrides.get(count) {
+            return Err(Error::MsrOverrideRejected(rejected.index).into());
+        }
+    }
+    if let Some(kernel_entry_point) = kernel_entry_point {
+        regs::setup_regs(
+            fd,
+            kernel_entry_point.raw_value(),
+            layout::BOOT_STACK_POINTER.raw_value(),
+            layout::ZERO_PAGE_START.raw_value(),
+        )
+        .map_err(Error::REGSConfiguration)?;
+        regs::setup_fpu(fd).map_err(Error::FPUConfiguration)?;
+        regs::setup_sregs(guest_mem, fd).map_err(Error::SREGSConfiguration)?;
+    }
+    if lapic {
+        interrupts::set_lint(fd).map_err(Error::LocalIntConfiguration)?;
+    }
+    Ok(())
+}
+
 /// Returns a Vec of the valid memory addresses.
 /// These should be used to configure the GuestMemory structure for the platform.
 /// For x86_64 all addresses are valid from the start of the kernel except a
-/// carve out at the end of 32bit address space.
-pub fn arch_memory_regions(size: GuestUsize) -> Vec<(GuestAddress, usize, RegionType)> {
-    let reserved_memory_gap_start = layout::MEM_32BIT_RESERVED_START
-        .checked_add(layout::MEM_32BIT_DEVICES_SIZE)
-        .expect("32-bit reserved region is too large");
+/// carve out at the end of 32bit address space, starting with `mmio_hole`.
+pub fn arch_memory_regions(
+    size: GuestUsize,
+    mmio_hole: MmioHole,
+) -> Vec<(GuestAddress, usize, RegionType)> {
+    let reserved_memory_gap_start = mmio_hole
+        .start
+        .checked_add(mmio_hole.size)
+        .expect("32-bit MMIO hole is too large");
 
     let requested_memory_size = GuestAddress(size as u64);
     let mut regions = Vec::new();
 
     // case1: guest memory fits before the gap
-    if size as u64 <= layout::MEM_32BIT_RESERVED_START.raw_value() {
+    if size as u64 <= mmio_hole.start.raw_value() {
         regions.push((GuestAddress(0), size as usize, RegionType::Ram));
     // case2: guest memory extends beyond the gap
     } else {
         // push memory before the gap
         regions.push((
             GuestAddress(0),
-            layout::MEM_32BIT_RESERVED_START.raw_value() as usize,
+            mmio_hole.start.raw_value() as usize,
             RegionType::Ram,
         ));
         regions.push((
             layout::RAM_64BIT_START,
-            requested_memory_size.unchecked_offset_from(layout::MEM_32BIT_RESERVED_START) as usize,
+            requested_memory_size.unchecked_offset_from(mmio_hole.start) as usize,
             RegionType::Ram,
         ));
     }
 
     // Add the 32-bit device memory hole as a sub region.
     regions.push((
-        layout::MEM_32BIT_RESERVED_START,
-        layout::MEM_32BIT_DEVICES_SIZE as usize,
+        mmio_hole.start,
+        mmio_hole.size as usize,
         RegionType::SubRegion,
     ));
 
-    // Add the 32-bit reserved memory hole as a sub region.
+    // Add the rest of the 32-bit address space as a reserved region.
     regions.push((
         reserved_memory_gap_start,
-        (layout::MEM_32BIT_RESERVED_SIZE - layout::MEM_32BIT_DEVICES_SIZE) as usize,
+        layout::RAM_64BIT_START.unchecked_offset_from(reserved_memory_gap_start) as usize,
         RegionType::Reserved,
     ));
 
     regions
 }
 
+/// Checks the 32-bit MMIO hole is 1MiB aligned, leaves enough RAM below it
+/// to boot, and ends before the PCI MMCONFIG space.
+pub fn check_mmio_hole(mmio_hole: &MmioHole) -> bool {
+    const ALIGNMENT: u64 = 1 << 20;
+
+    let end = match mmio_hole.start.checked_add(mmio_hole.size) {
+        Some(end) => end,
+        None => return false,
+    };
+
+    mmio_hole.size != 0
+        && mmio_hole.start.raw_value() % ALIGNMENT == 0
+        && mmio_hole.size % ALIGNMENT == 0
+        && mmio_hole.start.raw_value() >= layout::LOW_RAM_MIN_SIZE
+        && end <= layout::PCI_MMCONFIG_START
+}
+
 /// Configures the system and should be called once per vm before starting vcpu threads.
 ///
 /// # Arguments
 ///
 /// * `guest_mem` - The memory to be used by the guest.
+/// * `ram_regions` - The guest RAM ranges, reported as usable in the e820 map.
 /// * `cmdline_addr` - Address in `guest_mem` where the kernel command line was loaded.
 /// * `cmdline_size` - Size of the kernel command line in bytes including the null terminator.
 /// * `num_cpus` - Number of virtual CPUs the guest will have.
 #[allow(clippy::too_many_arguments)]
 pub fn configure_system(
     guest_mem: &GuestMemoryMmap,
+    ram_regions: &[(GuestAddress, usize)],
     cmdline_addr: GuestAddress,
     cmdline_size: usize,
     num_cpus: u8,
@@ -133,37 +394,39 @@ pub fn configure_system(
         params.0.hdr.kernel_alignment = KERNEL_MIN_ALIGNMENT_BYTES;
     };
 
-    add_e820_entry(&mut params.0, 0, layout::EBDA_START.raw_value(), E820_RAM)?;
-
-    let mem_end = guest_mem.last_addr();
-    if mem_end < layout::MEM_32BIT_RESERVED_START {
-        add_e820_entry(
-            &mut params.0,
-            layout::HIGH_RAM_START.raw_value(),
-            mem_end.unchecked_offset_from(layout::HIGH_RAM_START) + 1,
-            E820_RAM,
-        )?;
-    } else {
-        add_e820_entry(
-            &mut params.0,
-            layout::HIGH_RAM_START.raw_value(),
-            layout::MEM_32BIT_RESERVED_START.unchecked_offset_from(layout::HIGH_RAM_START),
-            E820_RAM,
-        )?;
-        if mem_end > layout::RAM_64BIT_START {
-            add_e820_entry(
-                &mut params.0,
-                layout::RAM_64BIT_START.raw_value(),
-                mem_end.unchecked_offset_from(layout::RAM_64BIT_START) + 1,
-                E820_RAM,
-            )?;
+    for (start, size) in ram_regions {
+        let end = start.raw_value() + *size as u64;
+
+        // The EBDA and the legacy video and BIOS areas are carved out of the
+        // RAM below 1MiB.
+        if start.raw_value() < layout::HIGH_RAM_START.raw_value() {
+            if start < layout::EBDA_START {
+                add_e820_entry(
+                    &mut params.0,
+                    start.raw_value(),
+                    std::cmp::min(end, layout::EBDA_START.raw_value()) - start.raw_value(),
+                    E820_RAM,
+                )?;
+            }
+            if end > layout::HIGH_RAM_START.raw_value() {
+                add_e820_entry(
+                    &mut params.0,
+                    layout::HIGH_RAM_START.raw_value(),
+                    end - layout::HIGH_RAM_START.raw_value(),
+                    E820_RAM,
+                )?;
+            }
+        } else {
+            add_e820_entry(&mut params.0, start.raw_value(), *size as u64, E820_RAM)?;
         }
     }
 
+    // Everything above the 32-bit devices area up to 4GiB is reserved, the
+    // PCI MMCONFIG space, the IOAPIC and the local APIC being located there.
     add_e820_entry(
         &mut params.0,
-        layout::PCI_MMCONFIG_START.0,
-        layout::PCI_MMCONFIG_SIZE,
+        layout::PCI_MMCONFIG_START.raw_value(),
+        layout::RAM_64BIT_START.unchecked_offset_from(layout::PCI_MMCONFIG_START),
         E820_RESERVED,
     )?;
 
@@ -207,9 +470,72 @@ mod tests {
     use super::*;
     use linux_loader::loader::bootparam::boot_e820_entry;
 
+    #[test]
+    fn test_sgx_epc_sections() {
+        let mut cpuid = CpuId::from_entries(&[
+            kvm_cpuid_entry2 {
+                function: 7,
+                ebx: 1 << 2,
+                ..Default::default()
+            },
+            kvm_cpuid_entry2 {
+                function: SGX_CPUID_LEAF,
+                eax: 1,
+                ..Default::default()
+            },
+            kvm_cpuid_entry2 {
+                function: SGX_CPUID_LEAF,
+                index: 2,
+                eax: 0x1000_0001,
+                ecx: 0x1000_0001,
+                ..Default::default()
+            },
+        ]);
+        assert!(sgx_supported(&cpuid));
+
+        set_sgx_epc_sections(
+            &mut cpuid,
+            &[
+                SgxEpcSection {
+                    start: GuestAddress(0x1_0000_0000),
+                    size: 0x400_0000,
+                },
+                SgxEpcSection {
+                    start: GuestAddress(0x1_0400_0000),
+                    size: 0x1_0000_0000,
+                },
+            ],
+        );
+        let sections: Vec<(u32, u32, u32, u32, u32)> = cpuid
+            .as_slice()
+            .iter()
+            .filter(|e| e.function == SGX_CPUID_LEAF && e.index >= 2)
+            .map(|e| (e.index, e.eax, e.ebx, e.ecx, e.edx))
+            .collect();
+        assert_eq!(
+            sections,
+            vec![
+                (2, 0x1, 0x1, 0x400_0001, 0),
+                (3, 0x400_0001, 0x1, 0x1, 0x1),
+                (4, 0, 0, 0, 0),
+            ]
+        );
+
+        let msr = sgx_feature_control(&cpuid).unwrap();
+        assert_eq!(msr.index, MSR_IA32_FEATURE_CONTROL);
+        assert_eq!(
+            msr.data,
+            FEATURE_CONTROL_LOCKED | FEATURE_CONTROL_SGX_ENABLE
+        );
+
+        // Without EPC sections, the guest is left with SGX disabled.
+        set_sgx_epc_sections(&mut cpuid, &[]);
+        assert!(sgx_feature_control(&cpuid).is_none());
+    }
+
     #[test]
     fn regions_lt_4gb() {
-        let regions = arch_memory_regions(1 << 29 as GuestUsize);
+        let regions = arch_memory_regions(1 << 29 as GuestUsize, MmioHole::default());
         assert_eq!(3, regions.len());
         assert_eq!(GuestAddress(0), regions[0].0);
         assert_eq!(1usize << 29, regions[0].1);
@@ -217,51 +543,216 @@ mod tests {
 
     #[test]
     fn regions_gt_4gb() {
-        let regions = arch_memory_regions((1 << 32 as GuestUsize) + 0x8000);
+        let regions = arch_memory_regions((1 << 32 as GuestUsize) + 0x8000, MmioHole::default());
         assert_eq!(4, regions.len());
         assert_eq!(GuestAddress(0), regions[0].0);
         assert_eq!(GuestAddress(1 << 32), regions[1].0);
     }
 
+    #[test]
+    fn regions_moved_mmio_hole() {
+        let mmio_hole = MmioHole {
+            start: GuestAddress(1 << 30),
+            size: 1 << 30,
+        };
+        let regions = arch_memory_regions(3 << 30, mmio_hole);
+        assert_eq!(4, regions.len());
+        assert_eq!((GuestAddress(0), 1 << 30), (regions[0].0, regions[0].1));
+        assert_eq!(
+            (GuestAddress(1 << 32), 2 << 30),
+            (regions[1].0, regions[1].1)
+        );
+        assert_eq!(
+            (GuestAddress(1 << 30), 1 << 30),
+            (regions[2].0, regions[2].1)
+        );
+        assert_eq!(
+            (GuestAddress(2 << 30), 2 << 30),
+            (regions[3].0, regions[3].1)
+        );
+    }
+
+    #[test]
+    fn test_check_mmio_hole() {
+        assert!(check_mmio_hole(&MmioHole::default()));
+        assert!(check_mmio_hole(&MmioHole {
+            start: GuestAddress(1 << 30),
+            size: 2 << 30,
+        }));
+
+        // Too little RAM left below the hole.
+        assert!(!check_mmio_hole(&MmioHole {
+            start: GuestAddress(128 << 20),
+            size: 1 << 30,
+        }));
+        // Overlapping the PCI MMCONFIG space.
+        assert!(!check_mmio_hole(&MmioHole {
+            start: GuestAddress(3 << 30),
+            size: 1 << 30,
+        }));
+        // Unaligned.
+        assert!(!check_mmio_hole(&MmioHole {
+            start: GuestAddress((2 << 30) + 0x1000),
+            size: 256 << 20,
+        }));
+        assert!(!check_mmio_hole(&MmioHole {
+            start: GuestAddress(2 << 30),
+            size: 0,
+        }));
+    }
+
     #[test]
     fn test_system_configuration() {
         let no_vcpus = 4;
         let gm = GuestMemoryMmap::from_ranges(&vec![(GuestAddress(0), 0x10000)]).unwrap();
-        let config_err = configure_system(&gm, GuestAddress(0), 0, 1, None, None);
+        let config_err = configure_system(
+            &gm,
+            &[(GuestAddress(0), 0x10000)],
+            GuestAddress(0),
+            0,
+            1,
+            None,
+            None,
+        );
         assert!(config_err.is_err());
 
         // Now assigning some memory that falls before the 32bit memory hole.
         let mem_size = 128 << 20;
-        let arch_mem_regions = arch_memory_regions(mem_size);
+        let arch_mem_regions = arch_memory_regions(mem_size, MmioHole::default());
         let ram_regions: Vec<(GuestAddress, usize)> = arch_mem_regions
             .iter()
             .filter(|r| r.2 == RegionType::Ram)
             .map(|r| (r.0, r.1))
             .collect();
         let gm = GuestMemoryMmap::from_ranges(&ram_regions).unwrap();
-        configure_system(&gm, GuestAddress(0), 0, no_vcpus, None, None).unwrap();
+        configure_system(&gm, &ram_regions, GuestAddress(0), 0, no_vcpus, None, None).unwrap();
 
         // Now assigning some memory that is equal to the start of the 32bit memory hole.
         let mem_size = 3328 << 20;
-        let arch_mem_regions = arch_memory_regions(mem_size);
+        let arch_mem_regions = arch_memory_regions(mem_size, MmioHole::default());
         let ram_regions: Vec<(GuestAddress, usize)> = arch_mem_regions
             .iter()
             .filter(|r| r.2 == RegionType::Ram)
             .map(|r| (r.0, r.1))
             .collect();
         let gm = GuestMemoryMmap::from_ranges(&ram_regions).unwrap();
-        configure_system(&gm, GuestAddress(0), 0, no_vcpus, None, None).unwrap();
+        configure_system(&gm, &ram_regions, GuestAddress(0), 0, no_vcpus, None, None).unwrap();
 
         // Now assigning some memory that falls after the 32bit memory hole.
         let mem_size = 3330 << 20;
-        let arch_mem_regions = arch_memory_regions(mem_size);
+        let arch_mem_regions = arch_memory_regions(mem_size, MmioHole::default());
+        let ram_regions: Vec<(GuestAddress, usize)> = arch_mem_regions
+            .iter()
+            .filter(|r| r.2 == RegionType::Ram)
+            .map(|r| (r.0, r.1))
+            .collect();
+        let gm = GuestMemoryMmap::from_ranges(&ram_regions).unwrap();
+        configure_system(&gm, &ram_regions, GuestAddress(0), 0, no_vcpus, None, None).unwrap();
+    }
+
+    #[test]
+    fn test_e820_map_around_32bit_hole() {
+        let arch_mem_regions = arch_memory_regions(6 << 30, MmioHole::default());
+        let ram_regions: Vec<(GuestAddress, usize)> = arch_mem_regions
+            .iter()
+            .filter(|r| r.2 == RegionType::Ram)
+            .map(|r| (r.0, r.1))
+            .collect();
+        let gm = GuestMemoryMmap::from_ranges(&ram_regions).unwrap();
+        configure_system(&gm, &ram_regions, GuestAddress(0), 0, 1, None, None).unwrap();
+
+        let params: BootParamsWrapper = gm.read_obj(layout::ZERO_PAGE_START).unwrap();
+        let e820: Vec<(u64, u64, u32)> = params.0.e820_table[..params.0.e820_entries as usize]
+            .iter()
+            .map(|e| (e.addr, e.size, e.type_))
+            .collect();
+        assert_eq!(
+            e820,
+            vec![
+                (0, 0xa_0000, E820_RAM),
+                (0x10_0000, 0xc000_0000 - 0x10_0000, E820_RAM),
+                (0x1_0000_0000, 3 << 30, E820_RAM),
+                (0xe800_0000, 0x1800_0000, E820_RESERVED),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_e820_map_around_moved_32bit_hole() {
+        let mmio_hole = MmioHole {
+            start: GuestAddress(2 << 30),
+            size: 1 << 30,
+        };
+        let arch_mem_regions = arch_memory_regions(4 << 30, mmio_hole);
         let ram_regions: Vec<(GuestAddress, usize)> = arch_mem_regions
             .iter()
             .filter(|r| r.2 == RegionType::Ram)
             .map(|r| (r.0, r.1))
             .collect();
         let gm = GuestMemoryMmap::from_ranges(&ram_regions).unwrap();
-        configure_system(&gm, GuestAddress(0), 0, no_vcpus, None, None).unwrap();
+        configure_system(&gm, &ram_regions, GuestAddress(0), 0, 1, None, None).unwrap();
+
+        let params: BootParamsWrapper = gm.read_obj(layout::ZERO_PAGE_START).unwrap();
+        let e820: Vec<(u64, u64, u32)> = params.0.e820_table[..params.0.e820_entries as usize]
+            .iter()
+            .map(|e| (e.addr, e.size, e.type_))
+            .collect();
+        assert_eq!(
+            e820,
+            vec![
+                (0, 0xa_0000, E820_RAM),
+                (0x10_0000, 0x8000_0000 - 0x10_0000, E820_RAM),
+                (0x1_0000_0000, 2 << 30, E820_RAM),
+                (0xe800_0000, 0x1800_0000, E820_RESERVED),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_e820_map_with_hotplugged_memory() {
+        // Hotplugged memory is not contiguous with the boot RAM, the gap
+        // must not be reported as usable.
+        let ram_regions = vec![
+            (GuestAddress(0), 0x1000_0000),
+            (GuestAddress(0x1_0000_0000), 0x800_0000),
+        ];
+        let gm = GuestMemoryMmap::from_ranges(&ram_regions).unwrap();
+        configure_system(&gm, &ram_regions, GuestAddress(0), 0, 1, None, None).unwrap();
+
+        let params: BootParamsWrapper = gm.read_obj(layout::ZERO_PAGE_START).unwrap();
+        assert_eq!(params.0.e820_entries, 4);
+        assert_eq!(params.0.e820_table[1].addr, 0x10_0000);
+        assert_eq!(params.0.e820_table[1].size, 0x1000_0000 - 0x10_0000);
+        assert_eq!(params.0.e820_table[2].addr, 0x1_0000_0000);
+        assert_eq!(params.0.e820_table[2].size, 0x800_0000);
+    }
+
+    #[test]
+    fn test_e820_map_with_explicit_regions() {
+        // A region starting within the legacy areas below 1MiB only has its
+        // part above them reported as usable.
+        let ram_regions = vec![
+            (GuestAddress(0), 0xa_0000),
+            (GuestAddress(0xf_0000), 0x1000_0000 - 0xf_0000),
+            (GuestAddress(0x2_0000_0000), 1 << 30),
+        ];
+        let gm = GuestMemoryMmap::from_ranges(&ram_regions).unwrap();
+        configure_system(&gm, &ram_regions, GuestAddress(0), 0, 1, None, None).unwrap();
+
+        let params: BootParamsWrapper = gm.read_obj(layout::ZERO_PAGE_START).unwrap();
+        let e820: Vec<(u64, u64, u32)> = params.0.e820_table[..params.0.e820_entries as usize]
+            .iter()
+            .map(|e| (e.addr, e.size, e.type_))
+            .collect();
+        assert_eq!(
+            e820,
+            vec![
+                (0, 0xa_0000, E820_RAM),
+                (0x10_0000, 0x1000_0000 - 0x10_0000, E820_RAM),
+                (0x2_0000_0000, 1 << 30, E820_RAM),
+                (0xe800_0000, 0x1800_0000, E820_RESERVED),
+            ]
+        );
     }
 
     #[test]
@@ -0,0 +1,704 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+use std::mem;
+use std::result;
+use std::slice;
+
+use layout::{HIGH_RAM_START, SMBIOS_START};
+use parse_uuid;
+use vm_memory::{Address, ByteValued, Bytes, GuestAddress, GuestMemoryError, GuestMemoryMmap};
+
+#[derive(Debug)]
+pub enum Error {
+    /// There was too little guest memory to store the entire SMBIOS table.
+    NotEnoughMemory,
+    /// The SMBIOS table has too little address space to be stored.
+    AddressOverflow,
+    /// Failure to write SMBIOS entry point structure.
+    WriteSmbiosEp(GuestMemoryError),
+    /// Failure to write additional data to memory.
+    WriteData(GuestMemoryError),
+    /// The UUID is not formatted as 8-4-4-4-12 hexadecimal digits.
+    InvalidUuid,
+    /// A string holds a NUL byte, which terminates strings in the table.
+    InvalidString,
+}
+
+pub type Result<T> = result::Result<T, Error>;
+
+// Platform identification written into the System Information structure.
+#[derive(Default)]
+pub struct SmbiosConfig<'a> {
+    pub uuid: Option<&'a str>,
+    pub serial_number: Option<&'a str>,
+    pub oem_strings: &'a [String],
+}
+
+// Structure types, from the DMTF SMBIOS 3.2 specification.
+const BIOS_INFORMATION: u8 = 0;
+const SYSTEM_INFORMATION: u8 = 1;
+const BASEBOARD_INFORMATION: u8 = 2;
+const SYSTEM_ENCLOSURE: u8 = 3;
+const PROCESSOR_INFORMATION: u8 = 4;
+const OEM_STRINGS: u8 = 11;
+const PHYSICAL_MEMORY_ARRAY: u8 = 16;
+const MEMORY_DEVICE: u8 = 17;
+const SYSTEM_BOOT_INFORMATION: u8 = 32;
+const END_OF_TABLE: u8 = 127;
+
+const SM3_MAGIC_IDENT: [u8; 5] = *b"_SM3_";
+const SMBIOS_MAJOR_VERSION: u8 = 3;
+const SMBIOS_MINOR_VERSION: u8 = 2;
+const SMBIOS_ENTRY_POINT_REVISION: u8 = 1;
+
+const DEFAULT_MANUFACTURER: &str = "Cloud Hypervisor";
+const DEFAULT_PRODUCT: &str = "cloud-hypervisor";
+
+const BIOS_CHARACTERISTICS_NOT_SUPPORTED: u64 = 1 << 3;
+const BIOS_CHARACTERISTICS_EXT2_VIRTUAL_MACHINE: u8 = 1 << 4;
+const WAKE_UP_TYPE_POWER_SWITCH: u8 = 6;
+const BOARD_FEATURE_HOSTING: u8 = 1;
+const BOARD_TYPE_MOTHERBOARD: u8 = 0x0a;
+const CHASSIS_TYPE_OTHER: u8 = 1;
+const CHASSIS_STATE_SAFE: u8 = 3;
+const CHASSIS_SECURITY_UNKNOWN: u8 = 2;
+const PROCESSOR_TYPE_CENTRAL: u8 = 3;
+const PROCESSOR_FAMILY_OTHER: u8 = 1;
+const PROCESSOR_STATUS_ENABLED: u8 = 0x41;
+const PROCESSOR_UPGRADE_OTHER: u8 = 1;
+const PROCESSOR_CHARACTERISTICS_64BIT: u16 = 1 << 2;
+const MEMORY_ARRAY_LOCATION_SYSTEM_BOARD: u8 = 3;
+const MEMORY_ARRAY_USE_SYSTEM: u8 = 3;
+const MEMORY_ARRAY_ECC_NONE: u8 = 3;
+const MEMORY_FORM_FACTOR_DIMM: u8 = 9;
+const MEMORY_TYPE_RAM: u8 = 7;
+const MEMORY_TYPE_DETAIL_UNKNOWN: u16 = 1 << 2;
+const NO_HANDLE: u16 = 0xfffe;
+const UNKNOWN_HANDLE: u16 = 0xffff;
+// Sizes past these values are reported through the extended fields.
+const MAX_CAPACITY_USE_EXTENDED: u32 = 0x8000_0000;
+const SIZE_USE_EXTENDED: u16 = 0x7fff;
+
+#[repr(C, packed)]
+#[derive(Default, Copy, Clone)]
+struct Smbios30Entrypoint {
+    signature: [u8; 5],
+    checksum: u8,
+    length: u8,
+    majorver: u8,
+    minorver: u8,
+    docrev: u8,
+    revision: u8,
+    reserved: u8,
+    max_size: u32,
+    physptr: u64,
+}
+
+#[repr(C, packed)]
+#[derive(Default, Copy, Clone)]
+struct SmbiosHeader {
+    type_: u8,
+    length: u8,
+    handle: u16,
+}
+
+#[repr(C, packed)]
+#[derive(Default, Copy, Clone)]
+struct SmbiosBiosInfo {
+    header: SmbiosHeader,
+    vendor: u8,
+    version: u8,
+    start_addr: u16,
+    release_date: u8,
+    rom_size: u8,
+    characteristics: u64,
+    characteristics_ext1: u8,
+    characteristics_ext2: u8,
+    bios_major: u8,
+    bios_minor: u8,
+    ec_major: u8,
+    ec_minor: u8,
+}
+
+#[repr(C, packed)]
+#[derive(Default, Copy, Clone)]
+struct SmbiosSysInfo {
+    header: SmbiosHeader,
+    manufacturer: u8,
+    product_name: u8,
+    version: u8,
+    serial_number: u8,
+    uuid: [u8; 16],
+    wake_up_type: u8,
+    sku: u8,
+    family: u8,
+}
+
+#[repr(C, packed)]
+#[derive(Default, Copy, Clone)]
+struct SmbiosBaseboardInfo {
+    header: SmbiosHeader,
+    manufacturer: u8,
+    product_name: u8,
+    version: u8,
+    serial_number: u8,
+    asset_tag: u8,
+    feature_flags: u8,
+    location: u8,
+    chassis_handle: u16,
+    board_type: u8,
+    contained_objects: u8,
+}
+
+#[repr(C, packed)]
+#[derive(Default, Copy, Clone)]
+struct SmbiosChassisInfo {
+    header: SmbiosHeader,
+    manufacturer: u8,
+    chassis_type: u8,
+    version: u8,
+    serial_number: u8,
+    asset_tag: u8,
+    bootup_state: u8,
+    power_supply_state: u8,
+    thermal_state: u8,
+    security_status: u8,
+    oem_defined: u32,
+    height: u8,
+    power_cords: u8,
+    contained_elements: u8,
+    contained_element_length: u8,
+    sku: u8,
+}
+
+#[repr(C, packed)]
+#[derive(Default, Copy, Clone)]
+struct SmbiosProcessorInfo {
+    header: SmbiosHeader,
+    socket: u8,
+    processor_type: u8,
+    family: u8,
+    manufacturer: u8,
+    id: u64,
+    version: u8,
+    voltage: u8,
+    external_clock: u16,
+    max_speed: u16,
+    current_speed: u16,
+    status: u8,
+    upgrade: u8,
+    l1_cache_handle: u16,
+    l2_cache_handle: u16,
+    l3_cache_handle: u16,
+    serial_number: u8,
+    asset_tag: u8,
+    part_number: u8,
+    core_count: u8,
+    core_enabled: u8,
+    thread_count: u8,
+    characteristics: u16,
+    family2: u16,
+}
+
+#[repr(C, packed)]
+#[derive(Default, Copy, Clone)]
+struct SmbiosOemStrings {
+    header: SmbiosHeader,
+    count: u8,
+}
+
+#[repr(C, packed)]
+#[derive(Default, Copy, Clone)]
+struct SmbiosMemoryArray {
+    header: SmbiosHeader,
+    location: u8,
+    use_: u8,
+    error_correction: u8,
+    max_capacity: u32,
+    error_handle: u16,
+    num_devices: u16,
+    extended_max_capacity: u64,
+}
+
+#[repr(C, packed)]
+#[derive(Default, Copy, Clone)]
+struct SmbiosMemoryDevice {
+    header: SmbiosHeader,
+    array_handle: u16,
+    error_handle: u16,
+    total_width: u16,
+    data_width: u16,
+    size: u16,
+    form_factor: u8,
+    device_set: u8,
+    device_locator: u8,
+    bank_locator: u8,
+    memory_type: u8,
+    type_detail: u16,
+    speed: u16,
+    manufacturer: u8,
+    serial_number: u8,
+    asset_tag: u8,
+    part_number: u8,
+    attributes: u8,
+    extended_size: u32,
+    configured_speed: u16,
+    min_voltage: u16,
+    max_voltage: u16,
+    configured_voltage: u16,
+}
+
+#[repr(C, packed)]
+#[derive(Default, Copy, Clone)]
+struct SmbiosBootInfo {
+    header: SmbiosHeader,
+    reserved: [u8; 6],
+    status: u8,
+}
+
+#[repr(C, packed)]
+#[derive(Default, Copy, Clone)]
+struct SmbiosEndOfTable {
+    header: SmbiosHeader,
+}
+
+// These structures only hold plain data, reading them from data is a safe initialization.
+unsafe impl ByteValued for Smbios30Entrypoint {}
+unsafe impl ByteValued for SmbiosHeader {}
+unsafe impl ByteValued for SmbiosBiosInfo {}
+unsafe impl ByteValued for SmbiosSysInfo {}
+unsafe impl ByteValued for SmbiosBaseboardInfo {}
+unsafe impl ByteValued for SmbiosChassisInfo {}
+unsafe impl ByteValued for SmbiosProcessorInfo {}
+unsafe impl ByteValued for SmbiosOemStrings {}
+unsafe impl ByteValued for SmbiosMemoryArray {}
+unsafe impl ByteValued for SmbiosMemoryDevice {}
+unsafe impl ByteValued for SmbiosBootInfo {}
+unsafe impl ByteValued for SmbiosEndOfTable {}
+
+fn compute_checksum<T: Copy>(v: &T) -> u8 {
+    // Safe because we are only reading the bytes within the size of the `T` reference `v`.
+    let v_slice = unsafe { slice::from_raw_parts(v as *const T as *const u8, mem::size_of::<T>()) };
+    let mut checksum: u8 = 0;
+    for i in v_slice.iter() {
+        checksum = checksum.wrapping_add(*i);
+    }
+    checksum
+}
+
+// Strings referenced by a structure, numbered from 1 in the order they are
+// added. Empty strings are not stored and are referenced as 0.
+#[derive(Default)]
+struct StringSet<'a>(Vec<&'a str>);
+
+impl<'a> StringSet<'a> {
+    fn add(&mut self, s: &'a str) -> Result<u8> {
+        if s.is_empty() {
+            return Ok(0);
+        }
+        if s.contains('\0') {
+            return Err(Error::InvalidString);
+        }
+        self.0.push(s);
+        Ok(self.0.len() as u8)
+    }
+}
+
+// Sequentially writes the structures to guest memory, making sure they never
+// spill over the BIOS area into the high RAM.
+struct TableWriter<'a> {
+    mem: &'a GuestMemoryMmap,
+    curptr: GuestAddress,
+    handle: u16,
+}
+
+impl<'a> TableWriter<'a> {
+    fn write_bytes(&mut self, data: &[u8]) -> Result<()> {
+        let end = self
+            .curptr
+            .checked_add(data.len() as u64)
+            .ok_or(Error::AddressOverflow)?;
+        if end > HIGH_RAM_START {
+            return Err(Error::NotEnoughMemory);
+        }
+        self.mem
+            .write_slice(data, self.curptr)
+            .map_err(Error::WriteData)?;
+        self.curptr = end;
+        Ok(())
+    }
+
+    // Writes a structure followed by its string set, returning its handle.
+    fn write_structure<T: ByteValued>(
+        &mut self,
+        structure: &mut T,
+        type_: u8,
+        strings: StringSet,
+    ) -> Result<u16> {
+        let handle = self.handle;
+        self.handle += 1;
+
+        let header = SmbiosHeader {
+            type_,
+            length: mem::size_of::<T>() as u8,
+            handle,
+        };
+        structure.as_mut_slice()[..mem::size_of::<SmbiosHeader>()]
+            .copy_from_slice(header.as_slice());
+        self.write_bytes(structure.as_slice())?;
+
+        // The string set is terminated by an additional NUL byte, which means
+        // a structure without any string is followed by two NUL bytes.
+        if strings.0.is_empty() {
+            self.write_bytes(&[0])?;
+        }
+        for s in strings.0.iter() {
+            self.write_bytes(s.as_bytes())?;
+            self.write_bytes(&[0])?;
+        }
+        self.write_bytes(&[0])?;
+
+        Ok(handle)
+    }
+}
+
+/// Writes the SMBIOS tables describing the platform, the `num_cpus` vCPUs and
+/// the `ram_size` bytes of RAM, returning the size of the structure table.
+pub fn setup_smbios(
+    mem: &GuestMemoryMmap,
+    config: &SmbiosConfig,
+    num_cpus: u8,
+    ram_size: u64,
+) -> Result<u64> {
+    let uuid = match config.uuid {
+        Some(uuid) => parse_uuid(uuid).ok_or(Error::InvalidUuid)?,
+        None => [0u8; 16],
+    };
+
+    let physptr = SMBIOS_START
+        .checked_add(mem::size_of::<Smbios30Entrypoint>() as u64)
+        .ok_or(Error::AddressOverflow)?;
+    let mut writer = TableWriter {
+        mem,
+        curptr: physptr,
+        handle: 0,
+    };
+
+    {
+        let mut strings = StringSet::default();
+        let mut smbios_biosinfo = SmbiosBiosInfo {
+            vendor: strings.add(DEFAULT_MANUFACTURER)?,
+            version: strings.add("0")?,
+            start_addr: 0xe800,
+            characteristics: BIOS_CHARACTERISTICS_NOT_SUPPORTED,
+            characteristics_ext2: BIOS_CHARACTERISTICS_EXT2_VIRTUAL_MACHINE,
+            ec_major: 0xff,
+            ec_minor: 0xff,
+            ..Default::default()
+        };
+        writer.write_structure(&mut smbios_biosinfo, BIOS_INFORMATION, strings)?;
+    }
+
+    {
+        let mut strings = StringSet::default();
+        let mut smbios_sysinfo = SmbiosSysInfo {
+            manufacturer: strings.add(DEFAULT_MANUFACTURER)?,
+            product_name: strings.add(DEFAULT_PRODUCT)?,
+            serial_number: strings.add(config.serial_number.unwrap_or(""))?,
+            uuid,
+            wake_up_type: WAKE_UP_TYPE_POWER_SWITCH,
+            ..Default::default()
+        };
+        writer.write_structure(&mut smbios_sysinfo, SYSTEM_INFORMATION, strings)?;
+    }
+
+    // The baseboard refers to the chassis, which comes right after it.
+    let chassis_handle = writer.handle + 1;
+    {
+        let mut strings = StringSet::default();
+        let mut smbios_baseboard = SmbiosBaseboardInfo {
+            manufacturer: strings.add(DEFAULT_MANUFACTURER)?,
+            product_name: strings.add(DEFAULT_PRODUCT)?,
+            feature_flags: BOARD_FEATURE_HOSTING,
+            chassis_handle,
+            board_type: BOARD_TYPE_MOTHERBOARD,
+            ..Default::default()
+        };
+        writer.write_structure(&mut smbios_baseboard, BASEBOARD_INFORMATION, strings)?;
+    }
+
+    {
+        let mut strings = StringSet::default();
+        let mut smbios_chassis = SmbiosChassisInfo {
+            manufacturer: strings.add(DEFAULT_MANUFACTURER)?,
+            chassis_type: CHASSIS_TYPE_OTHER,
+            bootup_state: CHASSIS_STATE_SAFE,
+            power_supply_state: CHASSIS_STATE_SAFE,
+            thermal_state: CHASSIS_STATE_SAFE,
+            security_status: CHASSIS_SECURITY_UNKNOWN,
+            ..Default::default()
+        };
+        writer.write_structure(&mut smbios_chassis, SYSTEM_ENCLOSURE, strings)?;
+    }
+
+    for cpu_id in 0..num_cpus {
+        let socket = format!("CPU {}", cpu_id);
+        let mut strings = StringSet::default();
+        let mut smbios_processor = SmbiosProcessorInfo {
+            socket: strings.add(&socket)?,
+            processor_type: PROCESSOR_TYPE_CENTRAL,
+            family: PROCESSOR_FAMILY_OTHER,
+            status: PROCESSOR_STATUS_ENABLED,
+            upgrade: PROCESSOR_UPGRADE_OTHER,
+            l1_cache_handle: UNKNOWN_HANDLE,
+            l2_cache_handle: UNKNOWN_HANDLE,
+            l3_cache_handle: UNKNOWN_HANDLE,
+            core_count: 1,
+            core_enabled: 1,
+            thread_count: 1,
+            characteristics: PROCESSOR_CHARACTERISTICS_64BIT,
+            family2: PROCESSOR_FAMILY_OTHER as u16,
+            ..Default::default()
+        };
+        writer.write_structure(&mut smbios_processor, PROCESSOR_INFORMATION, strings)?;
+    }
+
+    if !config.oem_strings.is_empty() {
+        let mut strings = StringSet::default();
+        for s in config.oem_strings.iter() {
+            strings.add(s)?;
+        }
+        let mut smbios_oem_strings = SmbiosOemStrings {
+            count: strings.0.len() as u8,
+            ..Default::default()
+        };
+        writer.write_structure(&mut smbios_oem_strings, OEM_STRINGS, strings)?;
+    }
+
+    let ram_size_kib = ram_size >> 10;
+    let array_handle = {
+        let mut smbios_memory_array = SmbiosMemoryArray {
+            location: MEMORY_ARRAY_LOCATION_SYSTEM_BOARD,
+            use_: MEMORY_ARRAY_USE_SYSTEM,
+            error_correction: MEMORY_ARRAY_ECC_NONE,
+            error_handle: NO_HANDLE,
+            num_devices: 1,
+            ..Default::default()
+        };
+        if ram_size_kib < u64::from(MAX_CAPACITY_USE_EXTENDED) {
+            smbios_memory_array.max_capacity = ram_size_kib as u32;
+        } else {
+            smbios_memory_array.max_capacity = MAX_CAPACITY_USE_EXTENDED;
+            smbios_memory_array.extended_max_capacity = ram_size;
+        }
+        writer.write_structure(
+            &mut smbios_memory_array,
+            PHYSICAL_MEMORY_ARRAY,
+            StringSet::default(),
+        )?
+    };
+
+    {
+        let ram_size_mib = ram_size >> 20;
+        let mut strings = StringSet::default();
+        let mut smbios_memory_device = SmbiosMemoryDevice {
+            array_handle,
+            error_handle: NO_HANDLE,
+            total_width: 64,
+            data_width: 64,
+            form_factor: MEMORY_FORM_FACTOR_DIMM,
+            device_locator: strings.add("DIMM 0")?,
+            memory_type: MEMORY_TYPE_RAM,
+            type_detail: MEMORY_TYPE_DETAIL_UNKNOWN,
+            manufacturer: strings.add(DEFAULT_MANUFACTURER)?,
+            ..Default::default()
+        };
+        if ram_size_mib < u64::from(SIZE_USE_EXTENDED) {
+            smbios_memory_device.size = ram_size_mib as u16;
+        } else {
+            smbios_memory_device.size = SIZE_USE_EXTENDED;
+            smbios_memory_device.extended_size = ram_size_mib as u32;
+        }
+        writer.write_structure(&mut smbios_memory_device, MEMORY_DEVICE, strings)?;
+    }
+
+    writer.write_structure(
+        &mut SmbiosBootInfo::default(),
+        SYSTEM_BOOT_INFORMATION,
+        StringSet::default(),
+    )?;
+
+    writer.write_structure(
+        &mut SmbiosEndOfTable::default(),
+        END_OF_TABLE,
+        StringSet::default(),
+    )?;
+
+    let max_size = writer.curptr.unchecked_offset_from(physptr);
+
+    {
+        let mut smbios_ep = Smbios30Entrypoint {
+            signature: SM3_MAGIC_IDENT,
+            length: mem::size_of::<Smbios30Entrypoint>() as u8,
+            majorver: SMBIOS_MAJOR_VERSION,
+            minorver: SMBIOS_MINOR_VERSION,
+            revision: SMBIOS_ENTRY_POINT_REVISION,
+            max_size: max_size as u32,
+            physptr: physptr.raw_value(),
+            ..Default::default()
+        };
+        smbios_ep.checksum = (!compute_checksum(&smbios_ep)).wrapping_add(1);
+        mem.write_obj(smbios_ep, SMBIOS_START)
+            .map_err(Error::WriteSmbiosEp)?;
+    }
+
+    Ok(max_size)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn create_guest_memory() -> GuestMemoryMmap {
+        GuestMemoryMmap::from_ranges(&[(GuestAddress(0), HIGH_RAM_START.raw_value() as usize)])
+            .unwrap()
+    }
+
+    // Returns the address of the next structure, skipping the string set.
+    fn next_structure(mem: &GuestMemoryMmap, addr: GuestAddress) -> GuestAddress {
+        let header: SmbiosHeader = mem.read_obj(addr).unwrap();
+        let mut ptr = addr.unchecked_add(u64::from(header.length));
+        loop {
+            let first: u8 = mem.read_obj(ptr).unwrap();
+            ptr = ptr.unchecked_add(1);
+            let second: u8 = mem.read_obj(ptr).unwrap();
+            if first == 0 && second == 0 {
+                return ptr.unchecked_add(1);
+            }
+        }
+    }
+
+    // Returns the string `index` from the structure at `addr`.
+    fn read_string(mem: &GuestMemoryMmap, addr: GuestAddress, index: u8) -> String {
+        let header: SmbiosHeader = mem.read_obj(addr).unwrap();
+        let mut ptr = addr.unchecked_add(u64::from(header.length));
+        let mut strings = Vec::new();
+        loop {
+            let mut s = Vec::new();
+            loop {
+                let c: u8 = mem.read_obj(ptr).unwrap();
+                ptr = ptr.unchecked_add(1);
+                if c == 0 {
+                    break;
+                }
+                s.push(c);
+            }
+            if s.is_empty() {
+                break;
+            }
+            strings.push(String::from_utf8(s).unwrap());
+        }
+        strings[index as usize - 1].clone()
+    }
+
+    #[test]
+    fn struct_size() {
+        assert_eq!(mem::size_of::<Smbios30Entrypoint>(), 0x18);
+        assert_eq!(mem::size_of::<SmbiosBiosInfo>(), 0x18);
+        assert_eq!(mem::size_of::<SmbiosSysInfo>(), 0x1b);
+        assert_eq!(mem::size_of::<SmbiosBaseboardInfo>(), 0x0f);
+        assert_eq!(mem::size_of::<SmbiosChassisInfo>(), 0x16);
+        assert_eq!(mem::size_of::<SmbiosProcessorInfo>(), 0x2a);
+        assert_eq!(mem::size_of::<SmbiosMemoryArray>(), 0x17);
+        assert_eq!(mem::size_of::<SmbiosMemoryDevice>(), 0x28);
+        assert_eq!(mem::size_of::<SmbiosBootInfo>(), 0x0b);
+    }
+
+    #[test]
+    fn entrypoint_checksum() {
+        let mem = create_guest_memory();
+        let max_size = setup_smbios(&mem, &SmbiosConfig::default(), 2, 512 << 20).unwrap();
+
+        let smbios_ep: Smbios30Entrypoint = mem.read_obj(SMBIOS_START).unwrap();
+        assert_eq!(compute_checksum(&smbios_ep), 0);
+        assert_eq!(smbios_ep.signature, SM3_MAGIC_IDENT);
+        assert_eq!({ smbios_ep.max_size }, max_size as u32);
+    }
+
+    #[test]
+    fn table_layout() {
+        let mem = create_guest_memory();
+        let oem_strings = vec![
+            "ds=nocloud;s=http://10.0.2.2:8000/".to_string(),
+            "io.systemd.credential:foo=bar".to_string(),
+        ];
+        let config = SmbiosConfig {
+            uuid: Some("4b9d8f70-2c5e-4c1f-8f3a-5a6b7c8d9e0f"),
+            serial_number: Some("CH-0001"),
+            oem_strings: &oem_strings,
+        };
+        let max_size = setup_smbios(&mem, &config, 2, 8 << 30).unwrap();
+
+        let smbios_ep: Smbios30Entrypoint = mem.read_obj(SMBIOS_START).unwrap();
+        let table_start = GuestAddress(smbios_ep.physptr);
+        let mut types = Vec::new();
+        let mut addr = table_start;
+        loop {
+            let header: SmbiosHeader = mem.read_obj(addr).unwrap();
+            types.push(header.type_);
+
+            match header.type_ {
+                SYSTEM_INFORMATION => {
+                    let sysinfo: SmbiosSysInfo = mem.read_obj(addr).unwrap();
+                    assert_eq!(
+                        sysinfo.uuid,
+                        [
+                            0x70, 0x8f, 0x9d, 0x4b, 0x5e, 0x2c, 0x1f, 0x4c, 0x8f, 0x3a, 0x5a, 0x6b,
+                            0x7c, 0x8d, 0x9e, 0x0f
+                        ]
+                    );
+                    assert_eq!(read_string(&mem, addr, sysinfo.serial_number), "CH-0001");
+                }
+                OEM_STRINGS => {
+                    let oem: SmbiosOemStrings = mem.read_obj(addr).unwrap();
+                    assert_eq!(oem.count, 2);
+                    assert_eq!(read_string(&mem, addr, 1), oem_strings[0]);
+                    assert_eq!(read_string(&mem, addr, 2), oem_strings[1]);
+                }
+                MEMORY_DEVICE => {
+                    let device: SmbiosMemoryDevice = mem.read_obj(addr).unwrap();
+                    assert_eq!({ device.size }, 8192);
+                }
+                _ => {}
+            }
+
+            addr = next_structure(&mem, addr);
+            if header.type_ == END_OF_TABLE {
+                break;
+            }
+        }
+
+        assert_eq!(types, vec![0, 1, 2, 3, 4, 4, 11, 16, 17, 32, 127]);
+        assert_eq!(addr.unchecked_offset_from(table_start), max_size);
+    }
+
+    #[test]
+    fn invalid_config() {
+        let mem = create_guest_memory();
+        let config = SmbiosConfig {
+            uuid: Some("4b9d8f70-2c5e-4c1f-8f3a"),
+            ..Default::default()
+        };
+        assert!(setup_smbios(&mem, &config, 1, 512 << 20).is_err());
+
+        let oem_strings = vec!["foo\0bar".to_string()];
+        let config = SmbiosConfig {
+            oem_strings: &oem_strings,
+            ..Default::default()
+        };
+        assert!(setup_smbios(&mem, &config, 1, 512 << 20).is_err());
+    }
+}
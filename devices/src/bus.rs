@@ -132,24 +132,17 @@ impl Bus {
             return Err(Error::ZeroSizedRange);
         }
 
+        let mut devices = self.devices.write().unwrap();
+
         // Reject all cases where the new device's range overlaps with an existing device.
-        if self
-            .devices
-            .read()
-            .unwrap()
+        if devices
             .iter()
             .any(|(range, _dev)| range.overlaps(base, len))
         {
             return Err(Error::Overlap);
         }
 
-        if self
-            .devices
-            .write()
-            .unwrap()
-            .insert(BusRange { base, len }, device)
-            .is_some()
-        {
+        if devices.insert(BusRange { base, len }, device).is_some() {
             return Err(Error::Overlap);
         }
 
@@ -172,6 +165,10 @@ impl Bus {
     }
 
     /// Updates the address range for an existing device.
+    ///
+    /// The bus is locked for the whole update, so a concurrent access reaches
+    /// the device either at its old range or at its new one. The old range is
+    /// kept if the new one can't be used.
     pub fn update_range(
         &self,
         old_base: u64,
@@ -179,18 +176,40 @@ impl Bus {
         new_base: u64,
         new_len: u64,
     ) -> Result<()> {
+        if old_len == 0 || new_len == 0 {
+            return Err(Error::ZeroSizedRange);
+        }
+
+        let mut devices = self.devices.write().unwrap();
+
         // Retrieve the device corresponding to the range
-        let device = if let Some((_, _, dev)) = self.resolve(old_base) {
-            dev.clone()
-        } else {
-            return Err(Error::MissingAddressRange);
+        let old_range = BusRange {
+            base: old_base,
+            len: old_len,
         };
+        let device = match devices.get_key_value(&old_range) {
+            Some((range, dev)) if range.len == old_len => dev.clone(),
+            _ => return Err(Error::MissingAddressRange),
+        };
+
+        // The new range may only overlap with the one being replaced.
+        if devices
+            .keys()
+            .any(|range| range.base != old_base && range.overlaps(new_base, new_len))
+        {
+            return Err(Error::Overlap);
+        }
 
-        // Remove the old address range
-        self.remove(old_base, old_len)?;
+        devices.remove(&old_range);
+        devices.insert(
+            BusRange {
+                base: new_base,
+                len: new_len,
+            },
+            device,
+        );
 
-        // Insert the new address range
-        self.insert(device, new_base, new_len)
+        Ok(())
     }
 
     /// Reads data from the device that owns the range containing `addr` and puts it into `data`.
@@ -335,4 +354,35 @@ mod tests {
         assert!(!a.overlaps(0x1400, 0x100));
         assert!(!a.overlaps(0xf00, 0x100));
     }
+
+    #[test]
+    fn bus_update_range() {
+        let bus = Bus::new();
+        let constant = Arc::new(Mutex::new(ConstantDevice));
+        let dummy = Arc::new(Mutex::new(DummyDevice));
+        assert!(bus.insert(constant, 0x10, 0x10).is_ok());
+        assert!(bus.insert(dummy, 0x40, 0x10).is_ok());
+
+        assert!(bus.update_range(0x10, 0x10, 0x20, 0x10).is_ok());
+        assert!(!bus.read(0x10, &mut [0, 0, 0, 0]));
+        let mut values = [0, 0, 0, 0];
+        assert!(bus.read(0x25, &mut values));
+        assert_eq!(values, [5, 6, 7, 8]);
+
+        // Moving to a range overlapping the current one is fine.
+        assert!(bus.update_range(0x20, 0x10, 0x28, 0x10).is_ok());
+        assert!(bus.read(0x28, &mut values));
+        assert_eq!(values, [0, 1, 2, 3]);
+
+        // The old range is kept when the new one is already taken.
+        let result = bus.update_range(0x28, 0x10, 0x3c, 0x10);
+        assert_eq!(format!("{:?}", result), "Err(Overlap)");
+        assert!(bus.read(0x28, &mut values));
+        assert!(!bus.read(0x3c, &mut values));
+
+        // Only an exactly matching range can be moved.
+        assert!(bus.update_range(0x28, 0x8, 0x60, 0x8).is_err());
+        assert!(bus.update_range(0x2c, 0x10, 0x60, 0x10).is_err());
+        assert!(bus.update_range(0x28, 0x10, 0x60, 0).is_err());
+    }
 }
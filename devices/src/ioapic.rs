@@ -11,6 +11,7 @@
 
 use crate::BusDevice;
 use byteorder::{ByteOrder, LittleEndian};
+use std::fmt;
 use std::io;
 use std::result;
 use std::sync::Arc;
@@ -18,6 +19,10 @@ use vm_device::interrupt::{
     InterruptIndex, InterruptManager, InterruptSourceConfig, InterruptSourceGroup,
     MsiIrqGroupConfig, MsiIrqSourceConfig,
 };
+use vm_device::{
+    add_snapshot_state, snapshot_state, Migratable, MigratableError, Pausable, Snapshot,
+    Snapshotable,
+};
 use vm_memory::GuestAddress;
 
 #[derive(Debug)]
@@ -42,6 +47,38 @@ pub enum Error {
     EnableInterrupt(io::Error),
 }
 
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::InvalidDestinationMode => write!(f, "invalid destination mode"),
+            Error::InvalidTriggerMode => write!(f, "invalid trigger mode"),
+            Error::InvalidDeliveryMode => write!(f, "invalid delivery mode"),
+            Error::CreateInterruptSourceGroup(_) => {
+                write!(f, "failed creating the interrupt source group")
+            }
+            Error::TriggerInterrupt(_) => write!(f, "failed triggering the interrupt"),
+            Error::MaskInterrupt(_) => write!(f, "failed masking the interrupt"),
+            Error::UnmaskInterrupt(_) => write!(f, "failed unmasking the interrupt"),
+            Error::UpdateInterrupt(_) => write!(f, "failed updating the interrupt"),
+            Error::EnableInterrupt(_) => write!(f, "failed enabling the interrupt"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::CreateInterruptSourceGroup(e) => Some(e),
+            Error::TriggerInterrupt(e) => Some(e),
+            Error::MaskInterrupt(e) => Some(e),
+            Error::UnmaskInterrupt(e) => Some(e),
+            Error::UpdateInterrupt(e) => Some(e),
+            Error::EnableInterrupt(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
 type Result<T> = result::Result<T, Error>;
 
 // I/O REDIRECTION TABLE REGISTER
@@ -386,3 +423,57 @@ impl Ioapic {
         }
     }
 }
+
+// The interrupt source group is created again by the VMM, only the registers
+// need to be saved.
+impl Pausable for Ioapic {
+    fn pause(&mut self) -> result::Result<(), MigratableError> {
+        Ok(())
+    }
+
+    fn resume(&mut self) -> result::Result<(), MigratableError> {
+        Ok(())
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+struct IoapicState {
+    id: u32,
+    reg_sel: u32,
+    reg_entries: [RedirectionTableEntry; NUM_IOAPIC_PINS],
+}
+
+impl Snapshotable for Ioapic {
+    fn id(&self) -> String {
+        String::from("ioapic")
+    }
+
+    fn snapshot(&self) -> result::Result<Snapshot, MigratableError> {
+        let mut snapshot = Snapshot::new(&self.id());
+        add_snapshot_state(
+            &mut snapshot,
+            &IoapicState {
+                id: self.id,
+                reg_sel: self.reg_sel,
+                reg_entries: self.reg_entries,
+            },
+        )?;
+        Ok(snapshot)
+    }
+
+    fn restore(&mut self, snapshot: Snapshot) -> result::Result<(), MigratableError> {
+        if let Some(state) = snapshot_state::<IoapicState>(&snapshot)? {
+            self.id = state.id;
+            self.reg_sel = state.reg_sel;
+            self.reg_entries = state.reg_entries;
+            // The routes of the pins have to follow their restored entries.
+            for irq in 0..NUM_IOAPIC_PINS {
+                self.update_entry(irq)
+                    .map_err(|e| MigratableError::Restore(e.into()))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Migratable for Ioapic {}
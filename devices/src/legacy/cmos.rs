@@ -4,7 +4,12 @@
 
 use libc::{gmtime_r, time, time_t, tm};
 use std::cmp::min;
+use std::io;
 use std::mem;
+use vm_device::{
+    add_snapshot_state, snapshot_state, Migratable, MigratableError, Pausable, Snapshot,
+    Snapshotable,
+};
 
 use crate::BusDevice;
 
@@ -113,3 +118,58 @@ impl BusDevice for Cmos {
         }
     }
 }
+
+impl Pausable for Cmos {
+    fn pause(&mut self) -> Result<(), MigratableError> {
+        Ok(())
+    }
+
+    fn resume(&mut self) -> Result<(), MigratableError> {
+        Ok(())
+    }
+}
+
+// The time registers are read from the host clock, only the index and the
+// memory need to be saved.
+#[derive(Deserialize, Serialize)]
+struct CmosState {
+    index: u8,
+    data: Vec<u8>,
+}
+
+impl Snapshotable for Cmos {
+    fn id(&self) -> String {
+        String::from("cmos")
+    }
+
+    fn snapshot(&self) -> Result<Snapshot, MigratableError> {
+        let mut snapshot = Snapshot::new(&self.id());
+        add_snapshot_state(
+            &mut snapshot,
+            &CmosState {
+                index: self.index,
+                data: self.data.to_vec(),
+            },
+        )?;
+        Ok(snapshot)
+    }
+
+    fn restore(&mut self, snapshot: Snapshot) -> Result<(), MigratableError> {
+        if let Some(state) = snapshot_state::<CmosState>(&snapshot)? {
+            if state.data.len() != DATA_LEN {
+                return Err(MigratableError::Restore(
+                    io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("invalid CMOS size {}", state.data.len()),
+                    )
+                    .into(),
+                ));
+            }
+            self.index = state.index & INDEX_MASK;
+            self.data.copy_from_slice(&state.data);
+        }
+        Ok(())
+    }
+}
+
+impl Migratable for Cmos {}
@@ -2,42 +2,509 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE-BSD-3-Clause file.
 
+use std::collections::VecDeque;
+use std::io;
+use std::sync::Arc;
+use vm_device::interrupt::InterruptSourceGroup;
+use vm_device::{
+    add_snapshot_state, snapshot_state, Migratable, MigratableError, Pausable, Snapshot,
+    Snapshotable,
+};
 use vmm_sys_util::eventfd::EventFd;
 
 use BusDevice;
 
-/// A i8042 PS/2 controller that emulates just enough to shutdown the machine.
+// Offsets from the base of 0x60.
+const DATA: u64 = 0;
+const PORT_B: u64 = 1;
+const COMMAND: u64 = 4;
+
+const STATUS_OUT_FULL: u8 = 0x01;
+const STATUS_SYSTEM: u8 = 0x04;
+const STATUS_COMMAND: u8 = 0x08;
+const STATUS_UNLOCKED: u8 = 0x10;
+
+const CTR_KBD_INT: u8 = 0x01;
+const CTR_SYSTEM: u8 = 0x04;
+const CTR_KBD_DISABLED: u8 = 0x10;
+const CTR_AUX_DISABLED: u8 = 0x20;
+const CTR_XLATE: u8 = 0x40;
+
+const CMD_READ_CTR: u8 = 0x20;
+const CMD_WRITE_CTR: u8 = 0x60;
+const CMD_AUX_DISABLE: u8 = 0xa7;
+const CMD_AUX_ENABLE: u8 = 0xa8;
+const CMD_AUX_TEST: u8 = 0xa9;
+const CMD_SELF_TEST: u8 = 0xaa;
+const CMD_KBD_TEST: u8 = 0xab;
+const CMD_KBD_DISABLE: u8 = 0xad;
+const CMD_KBD_ENABLE: u8 = 0xae;
+const CMD_WRITE_KBD_OUT: u8 = 0xd2;
+const CMD_RESET: u8 = 0xfe;
+
+const SELF_TEST_OK: u8 = 0x55;
+const PORT_TEST_OK: u8 = 0x00;
+
+const KBD_CMD_SET_LEDS: u8 = 0xed;
+const KBD_CMD_ECHO: u8 = 0xee;
+const KBD_CMD_GET_ID: u8 = 0xf2;
+const KBD_CMD_SET_RATE: u8 = 0xf3;
+const KBD_CMD_RESET: u8 = 0xff;
+const KBD_ACK: u8 = 0xfa;
+const KBD_SELF_TEST_OK: u8 = 0xaa;
+const KBD_ID: [u8; 2] = [0xab, 0x83];
+
+// What the keyboard holds for the guest, its replies to commands included,
+// before refusing more.
+const KBD_BUFFER_SIZE: usize = 256;
+
+// The prefixes of the set 1 scancodes of the extended keys, and of the pause
+// key, which has no release.
+const SCANCODE_EXTENDED: u8 = 0xe0;
+const SCANCODE_PAUSE: u8 = 0xe1;
+const SCANCODE_RELEASE: u8 = 0x80;
+
+/// A i8042 PS/2 controller with a keyboard, which the VMM types on.
+///
+/// The controller emulates just enough for a guest to reset the machine,
+/// and to find a keyboard. The keyboard answers the commands of the guest,
+/// and sends the scancodes queued by `queue_keystrokes()`, raising its
+/// interrupt for each byte. There is no auxiliary device.
 pub struct I8042Device {
     reset_evt: EventFd,
+    interrupt: Arc<Box<dyn InterruptSourceGroup>>,
+    control: u8,
+    // The byte the guest reads from the data port, and whether it comes
+    // from the keyboard rather than from the controller.
+    output: Option<(u8, bool)>,
+    // Bytes from the keyboard, waiting for the output buffer to be empty.
+    kbd_buffer: VecDeque<u8>,
+    // The command waiting for its parameter, written to the data port.
+    pending_command: Option<u8>,
+    pending_kbd_command: Option<u8>,
+    last_write_command: bool,
 }
 
 impl I8042Device {
     /// Constructs a i8042 device that will signal the given event when the guest requests it.
-    pub fn new(reset_evt: EventFd) -> I8042Device {
-        I8042Device { reset_evt }
+    pub fn new(reset_evt: EventFd, interrupt: Arc<Box<dyn InterruptSourceGroup>>) -> I8042Device {
+        I8042Device {
+            reset_evt,
+            interrupt,
+            control: CTR_KBD_INT | CTR_SYSTEM | CTR_AUX_DISABLED | CTR_XLATE,
+            output: None,
+            kbd_buffer: VecDeque::new(),
+            pending_command: None,
+            pending_kbd_command: None,
+            last_write_command: false,
+        }
+    }
+
+    /// Queues scancodes for the guest to read from the keyboard, as the
+    /// guest expects them: from the set 1 when the controller translates, as
+    /// it does by default.
+    ///
+    /// Fails with `WouldBlock` when the keyboard buffer gets full, the guest
+    /// not reading it. The keys pressed by the scancodes queued until then
+    /// are released, rather than staying stuck.
+    pub fn queue_keystrokes(&mut self, scancodes: &[u8]) -> io::Result<()> {
+        // The keys pressed, by their code and whether they are extended.
+        let mut pressed: Vec<(bool, u8)> = Vec::new();
+        let mut rest = scancodes;
+        while !rest.is_empty() {
+            // A key is queued along with its prefix, if any.
+            let len = match rest[0] {
+                SCANCODE_EXTENDED => 2,
+                SCANCODE_PAUSE => 3,
+                _ => 1,
+            };
+            let (key, tail) = rest.split_at(len.min(rest.len()));
+            rest = tail;
+
+            match self.kbd_send(key) {
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    for (extended, code) in pressed {
+                        if extended {
+                            self.kbd_buffer.push_back(SCANCODE_EXTENDED);
+                        }
+                        self.kbd_buffer.push_back(code | SCANCODE_RELEASE);
+                    }
+                    return Err(io::Error::new(
+                        io::ErrorKind::WouldBlock,
+                        "keyboard buffer full",
+                    ));
+                }
+                result => result?,
+            }
+
+            let (extended, code) = match (key[0], key.len()) {
+                (SCANCODE_EXTENDED, 2) => (true, key[1]),
+                (SCANCODE_EXTENDED, _) | (SCANCODE_PAUSE, _) => continue,
+                (code, _) => (false, code),
+            };
+            let key = (extended, code & !SCANCODE_RELEASE);
+            pressed.retain(|k| *k != key);
+            if code & SCANCODE_RELEASE == 0 {
+                pressed.push(key);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn kbd_send(&mut self, bytes: &[u8]) -> io::Result<()> {
+        if self.kbd_buffer.len() + bytes.len() > KBD_BUFFER_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::WouldBlock,
+                "keyboard buffer full",
+            ));
+        }
+        self.kbd_buffer.extend(bytes);
+        self.fill_output()
+    }
+
+    // Moves the next keyboard byte to the output buffer, if it is empty and
+    // the keyboard enabled, and tells the guest.
+    fn fill_output(&mut self) -> io::Result<()> {
+        if self.output.is_some() || self.control & CTR_KBD_DISABLED != 0 {
+            return Ok(());
+        }
+        if let Some(v) = self.kbd_buffer.pop_front() {
+            self.output = Some((v, true));
+            if self.control & CTR_KBD_INT != 0 {
+                return self.interrupt.trigger(0);
+            }
+        }
+        Ok(())
+    }
+
+    // The controller replies through the output buffer, its reply being
+    // read before a keyboard byte that was already there.
+    fn controller_reply(&mut self, v: u8) {
+        if let Some((kbd_byte, true)) = self.output.replace((v, false)) {
+            self.kbd_buffer.push_front(kbd_byte);
+        }
+    }
+
+    fn status(&self) -> u8 {
+        let mut status = STATUS_SYSTEM | STATUS_UNLOCKED;
+        if self.output.is_some() {
+            status |= STATUS_OUT_FULL;
+        }
+        if self.last_write_command {
+            status |= STATUS_COMMAND;
+        }
+        status
+    }
+
+    fn read_data(&mut self) -> u8 {
+        match self.output.take() {
+            Some((v, _)) => {
+                self.fill_output_or_log();
+                v
+            }
+            None => 0,
+        }
+    }
+
+    fn write_command(&mut self, command: u8) {
+        self.pending_command = None;
+        match command {
+            CMD_READ_CTR => self.controller_reply(self.control),
+            CMD_WRITE_CTR | CMD_WRITE_KBD_OUT => self.pending_command = Some(command),
+            CMD_AUX_DISABLE => self.control |= CTR_AUX_DISABLED,
+            CMD_AUX_ENABLE => self.control &= !CTR_AUX_DISABLED,
+            // There is no auxiliary device, its clock line is stuck low.
+            CMD_AUX_TEST => self.controller_reply(0x01),
+            CMD_SELF_TEST => self.controller_reply(SELF_TEST_OK),
+            CMD_KBD_TEST => self.controller_reply(PORT_TEST_OK),
+            CMD_KBD_DISABLE => self.control |= CTR_KBD_DISABLED,
+            CMD_KBD_ENABLE => {
+                self.control &= !CTR_KBD_DISABLED;
+                self.fill_output_or_log();
+            }
+            CMD_RESET => {
+                debug!("i8042 reset signalled");
+                if let Err(e) = self.reset_evt.write(1) {
+                    error!("Error triggering i8042 reset event: {}", e);
+                }
+            }
+            _ => debug!("Unsupported i8042 command 0x{:x}", command),
+        }
+    }
+
+    fn write_data(&mut self, v: u8) {
+        match self.pending_command.take() {
+            Some(CMD_WRITE_CTR) => {
+                self.control = v;
+                self.fill_output_or_log();
+            }
+            Some(CMD_WRITE_KBD_OUT) => {
+                if let Err(e) = self.kbd_send(&[v]) {
+                    warn!("Dropping the i8042 keyboard output 0x{:x}: {}", v, e);
+                }
+            }
+            _ => self.write_kbd(v),
+        }
+    }
+
+    // The keyboard acknowledges everything, replying to the commands the
+    // guest probes it with.
+    fn write_kbd(&mut self, v: u8) {
+        // Writing to the keyboard enables it.
+        self.control &= !CTR_KBD_DISABLED;
+        let reply: &[u8] = match self.pending_kbd_command.take() {
+            // The parameter of the command.
+            Some(_) => &[KBD_ACK],
+            None => match v {
+                KBD_CMD_SET_LEDS | KBD_CMD_SET_RATE => {
+                    self.pending_kbd_command = Some(v);
+                    &[KBD_ACK]
+                }
+                KBD_CMD_ECHO => &[KBD_CMD_ECHO],
+                KBD_CMD_GET_ID => &[KBD_ACK, KBD_ID[0], KBD_ID[1]],
+                KBD_CMD_RESET => {
+                    self.kbd_buffer.clear();
+                    &[KBD_ACK, KBD_SELF_TEST_OK]
+                }
+                _ => &[KBD_ACK],
+            },
+        };
+        if let Err(e) = self.kbd_send(reply) {
+            warn!("Dropping the i8042 keyboard reply: {}", e);
+        }
+    }
+
+    fn fill_output_or_log(&mut self) {
+        if let Err(e) = self.fill_output() {
+            error!("Failed to trigger the i8042 keyboard interrupt: {}", e);
+        }
     }
 }
 
-// i8042 device is located at I/O port 0x61. We partially implement two 8-bit
-// registers: port 0x61 (I8042_PORT_B_REG, offset 0 from base of 0x61), and
-// port 0x64 (I8042_COMMAND_REG, offset 3 from base of 0x61).
+// i8042 device is located at I/O port 0x60. We partially implement three
+// 8-bit registers: port 0x60 (data, offset 0 from base of 0x60), port 0x61
+// (I8042_PORT_B_REG, offset 1), and port 0x64 (status and command, offset
+// 4).
 impl BusDevice for I8042Device {
     fn read(&mut self, _base: u64, offset: u64, data: &mut [u8]) {
-        if data.len() == 1 && offset == 3 {
-            data[0] = 0x0;
-        } else if data.len() == 1 && offset == 0 {
+        if data.len() != 1 {
+            return;
+        }
+        data[0] = match offset {
+            DATA => self.read_data(),
             // Like kvmtool, we return bit 5 set in I8042_PORT_B_REG to
             // avoid hang in pit_calibrate_tsc() in Linux kernel.
-            data[0] = 0x20;
-        }
+            PORT_B => 0x20,
+            COMMAND => self.status(),
+            _ => return,
+        };
     }
 
     fn write(&mut self, _base: u64, offset: u64, data: &[u8]) {
-        if data.len() == 1 && data[0] == 0xfe && offset == 3 {
-            debug!("i8042 reset signalled");
-            if let Err(e) = self.reset_evt.write(1) {
-                error!("Error triggering i8042 reset event: {}", e);
+        if data.len() != 1 {
+            return;
+        }
+        match offset {
+            DATA => {
+                self.last_write_command = false;
+                self.write_data(data[0]);
             }
+            COMMAND => {
+                self.last_write_command = true;
+                self.write_command(data[0]);
+            }
+            _ => {}
+        }
+    }
+}
+
+impl Pausable for I8042Device {
+    fn pause(&mut self) -> Result<(), MigratableError> {
+        Ok(())
+    }
+
+    fn resume(&mut self) -> Result<(), MigratableError> {
+        Ok(())
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+struct I8042State {
+    control: u8,
+    output: Option<(u8, bool)>,
+    kbd_buffer: VecDeque<u8>,
+    pending_command: Option<u8>,
+    pending_kbd_command: Option<u8>,
+    last_write_command: bool,
+}
+
+impl Snapshotable for I8042Device {
+    fn id(&self) -> String {
+        String::from("i8042")
+    }
+
+    fn snapshot(&self) -> Result<Snapshot, MigratableError> {
+        let mut snapshot = Snapshot::new(&self.id());
+        add_snapshot_state(
+            &mut snapshot,
+            &I8042State {
+                control: self.control,
+                output: self.output,
+                kbd_buffer: self.kbd_buffer.clone(),
+                pending_command: self.pending_command,
+                pending_kbd_command: self.pending_kbd_command,
+                last_write_command: self.last_write_command,
+            },
+        )?;
+        Ok(snapshot)
+    }
+
+    fn restore(&mut self, snapshot: Snapshot) -> Result<(), MigratableError> {
+        if let Some(state) = snapshot_state::<I8042State>(&snapshot)? {
+            self.control = state.control;
+            self.output = state.output;
+            self.kbd_buffer = state.kbd_buffer;
+            self.pending_command = state.pending_command;
+            self.pending_kbd_command = state.pending_kbd_command;
+            self.last_write_command = state.last_write_command;
+        }
+        Ok(())
+    }
+}
+
+impl Migratable for I8042Device {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libc::EFD_NONBLOCK;
+    use std::result;
+    use vm_device::interrupt::{InterruptIndex, InterruptSourceConfig};
+
+    struct TestInterrupt {
+        event_fd: EventFd,
+    }
+
+    impl InterruptSourceGroup for TestInterrupt {
+        fn trigger(&self, _index: InterruptIndex) -> result::Result<(), std::io::Error> {
+            self.event_fd.write(1)
+        }
+        fn update(
+            &self,
+            _index: InterruptIndex,
+            _config: InterruptSourceConfig,
+        ) -> result::Result<(), std::io::Error> {
+            Ok(())
         }
     }
+
+    fn i8042() -> (I8042Device, EventFd, EventFd) {
+        let reset_evt = EventFd::new(EFD_NONBLOCK).unwrap();
+        let intr_evt = EventFd::new(EFD_NONBLOCK).unwrap();
+        let i8042 = I8042Device::new(
+            reset_evt.try_clone().unwrap(),
+            Arc::new(Box::new(TestInterrupt {
+                event_fd: intr_evt.try_clone().unwrap(),
+            })),
+        );
+        (i8042, reset_evt, intr_evt)
+    }
+
+    fn read(i8042: &mut I8042Device, offset: u64) -> u8 {
+        let mut data = [0u8];
+        i8042.read(0x60, offset, &mut data);
+        data[0]
+    }
+
+    #[test]
+    fn test_keystrokes() {
+        let (mut i8042, _, intr_evt) = i8042();
+        assert_eq!(read(&mut i8042, COMMAND) & STATUS_OUT_FULL, 0);
+
+        // 'a' pressed and released.
+        i8042.queue_keystrokes(&[0x1e, 0x9e]).unwrap();
+        assert_eq!(intr_evt.read().unwrap(), 1);
+        assert_ne!(read(&mut i8042, COMMAND) & STATUS_OUT_FULL, 0);
+        assert_eq!(read(&mut i8042, DATA), 0x1e);
+        assert_eq!(intr_evt.read().unwrap(), 1);
+        assert_eq!(read(&mut i8042, DATA), 0x9e);
+        assert_eq!(read(&mut i8042, COMMAND) & STATUS_OUT_FULL, 0);
+        assert!(intr_evt.read().is_err());
+
+        // The keystrokes wait while the keyboard is disabled.
+        i8042.write(0x60, COMMAND, &[CMD_KBD_DISABLE]);
+        i8042.queue_keystrokes(&[0x1c]).unwrap();
+        assert_eq!(read(&mut i8042, COMMAND) & STATUS_OUT_FULL, 0);
+        i8042.write(0x60, COMMAND, &[CMD_KBD_ENABLE]);
+        assert_eq!(read(&mut i8042, DATA), 0x1c);
+
+        // Once the buffer is full, the keystrokes are refused rather than
+        // lost.
+        i8042
+            .queue_keystrokes(&[0x39; KBD_BUFFER_SIZE + 1])
+            .unwrap();
+        let e = i8042.queue_keystrokes(&[0x39]).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::WouldBlock);
+        read(&mut i8042, DATA);
+        i8042.queue_keystrokes(&[0x39]).unwrap();
+    }
+
+    #[test]
+    fn test_keystrokes_released() {
+        let (mut i8042, _, _) = i8042();
+
+        // Ctrl, right Alt, 'a' pressed, 'a' released, and no room left for
+        // the rest: Ctrl and right Alt are released.
+        let mut scancodes = vec![0x1d, 0xe0, 0x38, 0x1e, 0x9e];
+        scancodes.resize(KBD_BUFFER_SIZE, 0x39);
+        scancodes.extend_from_slice(&[0xe0, 0xb8, 0x9d]);
+        let e = i8042.queue_keystrokes(&scancodes).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::WouldBlock);
+
+        let mut read_scancodes = Vec::new();
+        while read(&mut i8042, COMMAND) & STATUS_OUT_FULL != 0 {
+            read_scancodes.push(read(&mut i8042, DATA));
+        }
+        // The space bar pressed again and again was never released.
+        let mut expected = scancodes[..KBD_BUFFER_SIZE].to_vec();
+        expected.extend_from_slice(&[0x9d, 0xe0, 0xb8, 0xb9]);
+        assert_eq!(read_scancodes, expected);
+    }
+
+    #[test]
+    fn test_linux_probe() {
+        let (mut i8042, reset_evt, _) = i8042();
+
+        i8042.write(0x60, COMMAND, &[CMD_SELF_TEST]);
+        assert_eq!(read(&mut i8042, DATA), SELF_TEST_OK);
+
+        i8042.write(0x60, COMMAND, &[CMD_READ_CTR]);
+        let control = read(&mut i8042, DATA);
+        assert_eq!(control & CTR_KBD_INT, CTR_KBD_INT);
+        i8042.write(0x60, COMMAND, &[CMD_WRITE_CTR]);
+        i8042.write(0x60, DATA, &[control & !CTR_KBD_INT]);
+        i8042.write(0x60, COMMAND, &[CMD_READ_CTR]);
+        assert_eq!(read(&mut i8042, DATA), control & !CTR_KBD_INT);
+
+        // A controller reply comes before the keystrokes.
+        i8042.queue_keystroke(0x1e).unwrap();
+        i8042.write(0x60, COMMAND, &[CMD_KBD_TEST]);
+        assert_eq!(read(&mut i8042, DATA), PORT_TEST_OK);
+        assert_eq!(read(&mut i8042, DATA), 0x1e);
+
+        i8042.write(0x60, DATA, &[KBD_CMD_GET_ID]);
+        assert_eq!(read(&mut i8042, DATA), KBD_ACK);
+        assert_eq!(read(&mut i8042, DATA), KBD_ID[0]);
+        assert_eq!(read(&mut i8042, DATA), KBD_ID[1]);
+        i8042.write(0x60, DATA, &[KBD_CMD_SET_LEDS]);
+        i8042.write(0x60, DATA, &[0x02]);
+        assert_eq!(read(&mut i8042, DATA), KBD_ACK);
+        assert_eq!(read(&mut i8042, DATA), KBD_ACK);
+        assert_eq!(read(&mut i8042, COMMAND) & STATUS_OUT_FULL, 0);
+
+        assert_eq!(read(&mut i8042, PORT_B), 0x20);
+        i8042.write(0x60, COMMAND, &[CMD_RESET]);
+        assert_eq!(reset_evt.read().unwrap(), 1);
+    }
 }
@@ -8,9 +8,15 @@
 #[cfg(feature = "cmos")]
 mod cmos;
 mod i8042;
+mod pic;
+mod rtc_pl031;
 mod serial;
+mod uart_pl011;
 
 #[cfg(feature = "cmos")]
 pub use self::cmos::Cmos;
 pub use self::i8042::I8042Device;
-pub use self::serial::Serial;
+pub use self::pic::Pic;
+pub use self::rtc_pl031::{Rtc, PL031_SIZE};
+pub use self::serial::{Serial, SerialOutput};
+pub use self::uart_pl011::{Pl011, PL011_SIZE};
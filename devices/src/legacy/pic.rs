@@ -0,0 +1,166 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Minimal 8259A programmable interrupt controller.
+//!
+//! With a split irqchip, KVM doesn't emulate the PICs and the IOAPIC
+//! delivers all the legacy interrupts. This shim only answers the guest
+//! accesses to the PIC ports, as a PIC with all its lines masked would.
+
+use crate::BusDevice;
+
+// Offsets of the command and data ports.
+const PIC_COMMAND: u64 = 0;
+const PIC_DATA: u64 = 1;
+
+// ICW1, the first word of the initialization sequence.
+const ICW1_INIT: u8 = 0x10;
+const ICW1_SINGLE: u8 = 0x02;
+const ICW1_NEEDS_ICW4: u8 = 0x01;
+
+// No interrupt line can be unmasked.
+const PIC_IMR: u8 = 0xff;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum InitState {
+    Icw2,
+    Icw3,
+    Icw4,
+    Done,
+}
+
+/// One 8259A chip, the master at port 0x20 or the slave at port 0xa0.
+///
+/// The guest can go through the initialization sequence, but the interrupt
+/// mask register always reads back as fully masked and no interrupt is ever
+/// requested or in service. Guests probing the mask register, as Linux does,
+/// find out the PIC is unusable and rely on the IOAPIC.
+pub struct Pic {
+    init_state: InitState,
+    single: bool,
+    needs_icw4: bool,
+}
+
+impl Pic {
+    /// Constructs a PIC, left initialized.
+    pub fn new() -> Pic {
+        Pic {
+            init_state: InitState::Done,
+            single: false,
+            needs_icw4: false,
+        }
+    }
+
+    fn write_command(&mut self, val: u8) {
+        // Other commands are either OCW2 (EOIs, priority rotation) or OCW3
+        // (register selection), with nothing pending there's no state to
+        // update.
+        if val & ICW1_INIT != 0 {
+            self.single = val & ICW1_SINGLE != 0;
+            self.needs_icw4 = val & ICW1_NEEDS_ICW4 != 0;
+            self.init_state = InitState::Icw2;
+        }
+    }
+
+    fn write_data(&mut self, _val: u8) {
+        // Outside of the initialization sequence, this writes the interrupt
+        // mask register, which is ignored.
+        self.init_state = match self.init_state {
+            InitState::Icw2 if !self.single => InitState::Icw3,
+            InitState::Icw2 | InitState::Icw3 if self.needs_icw4 => InitState::Icw4,
+            _ => InitState::Done,
+        };
+    }
+}
+
+impl Default for Pic {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl BusDevice for Pic {
+    fn read(&mut self, _base: u64, offset: u64, data: &mut [u8]) {
+        if data.len() != 1 {
+            warn!("PIC: invalid read size {}", data.len());
+            return;
+        }
+
+        data[0] = match offset {
+            // The interrupt request and in-service registers are empty.
+            PIC_COMMAND => 0,
+            PIC_DATA => PIC_IMR,
+            _ => {
+                warn!("PIC: unexpected read at offset {}", offset);
+                0
+            }
+        };
+    }
+
+    fn write(&mut self, _base: u64, offset: u64, data: &[u8]) {
+        if data.len() != 1 {
+            warn!("PIC: invalid write size {}", data.len());
+            return;
+        }
+
+        match offset {
+            PIC_COMMAND => self.write_command(data[0]),
+            PIC_DATA => self.write_data(data[0]),
+            _ => warn!("PIC: unexpected write at offset {}", offset),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read(pic: &mut Pic, offset: u64) -> u8 {
+        let mut data = [0u8];
+        pic.read(0, offset, &mut data);
+        data[0]
+    }
+
+    fn write(pic: &mut Pic, offset: u64, val: u8) {
+        pic.write(0, offset, &[val]);
+    }
+
+    #[test]
+    fn pic_init_sequence() {
+        let mut pic = Pic::new();
+
+        // Cascaded mode, expecting ICW4, as Linux programs the master.
+        write(&mut pic, PIC_COMMAND, ICW1_INIT | ICW1_NEEDS_ICW4);
+        assert_eq!(pic.init_state, InitState::Icw2);
+        write(&mut pic, PIC_DATA, 0x30);
+        assert_eq!(pic.init_state, InitState::Icw3);
+        write(&mut pic, PIC_DATA, 0x04);
+        assert_eq!(pic.init_state, InitState::Icw4);
+        write(&mut pic, PIC_DATA, 0x01);
+        assert_eq!(pic.init_state, InitState::Done);
+
+        // Single mode, without ICW4.
+        write(&mut pic, PIC_COMMAND, ICW1_INIT | ICW1_SINGLE);
+        write(&mut pic, PIC_DATA, 0x30);
+        assert_eq!(pic.init_state, InitState::Done);
+    }
+
+    #[test]
+    fn pic_always_masked() {
+        let mut pic = Pic::new();
+
+        // Linux unmasks all but the cascade line to probe the PIC.
+        write(&mut pic, PIC_DATA, 0xfb);
+        assert_eq!(read(&mut pic, PIC_DATA), PIC_IMR);
+
+        // Reading the IRR (OCW3 defaults) and the ISR.
+        assert_eq!(read(&mut pic, PIC_COMMAND), 0);
+        write(&mut pic, PIC_COMMAND, 0x0b);
+        assert_eq!(read(&mut pic, PIC_COMMAND), 0);
+
+        // EOIs are accepted.
+        write(&mut pic, PIC_COMMAND, 0x20);
+        assert_eq!(pic.init_state, InitState::Done);
+    }
+}
@@ -0,0 +1,273 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! ARM PrimeCell Real Time Clock (PL031)
+//!
+//! This module implements an ARM PrimeCell RTC (PL031), a 32-bit counter
+//! incremented every second, which arm64 guests read the wall clock time
+//! from.
+
+use crate::BusDevice;
+use byteorder::{ByteOrder, LittleEndian};
+use std::sync::Arc;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use vm_device::interrupt::InterruptSourceGroup;
+
+// Register offsets.
+const RTCDR: u64 = 0x0;
+const RTCMR: u64 = 0x4;
+const RTCLR: u64 = 0x8;
+const RTCCR: u64 = 0xc;
+const RTCIMSC: u64 = 0x10;
+const RTCRIS: u64 = 0x14;
+const RTCMIS: u64 = 0x18;
+const RTCICR: u64 = 0x1c;
+
+// The match interrupt is the only interrupt of the device.
+const RTC_INT_MATCH: u32 = 0x1;
+
+// Peripheral and PrimeCell identification registers, from 0xfe0 to 0xffc.
+const PL031_ID: [u8; 8] = [0x31, 0x10, 0x14, 0x00, 0x0d, 0xf0, 0x05, 0xb1];
+
+// Size of the register space.
+pub const PL031_SIZE: u64 = 0x1000;
+
+fn host_time() -> u32 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as u32)
+        .unwrap_or(0)
+}
+
+/// A PL031 device following the PL031 specification.
+///
+/// The counter starts from the host time, and goes on from the value loaded
+/// by the guest. There is no timer behind the match register, the match
+/// interrupt is raised on the first guest access once the counter reached
+/// the match value.
+pub struct Rtc {
+    // Counter value loaded last, and when it was.
+    load: u32,
+    load_time: Instant,
+    match_value: u32,
+    // Counter value the match value was last compared with.
+    checked: u32,
+    int_enabled: u32,
+    int_level: u32,
+    irq: Arc<Box<dyn InterruptSourceGroup>>,
+}
+
+impl Rtc {
+    /// Constructs an AMBA PL031 RTC device.
+    pub fn new(irq: Arc<Box<dyn InterruptSourceGroup>>) -> Rtc {
+        let load = host_time();
+        Rtc {
+            load,
+            load_time: Instant::now(),
+            match_value: 0,
+            checked: load,
+            int_enabled: 0,
+            int_level: 0,
+            irq,
+        }
+    }
+
+    fn counter(&self) -> u32 {
+        self.load
+            .wrapping_add(self.load_time.elapsed().as_secs() as u32)
+    }
+
+    fn interrupt_pending(&self) -> bool {
+        self.int_level & self.int_enabled != 0
+    }
+
+    // Latches the match interrupt if the counter went through the match
+    // value since the last check, and signals it if it just became pending.
+    fn update_interrupt(&mut self, was_pending: bool) {
+        let counter = self.counter();
+        let to_match = self.match_value.wrapping_sub(self.checked);
+        if to_match != 0 && to_match <= counter.wrapping_sub(self.checked) {
+            self.int_level |= RTC_INT_MATCH;
+        }
+        self.checked = counter;
+
+        if !was_pending && self.interrupt_pending() {
+            if let Err(e) = self.irq.trigger(0) {
+                warn!("PL031: failed to signal the interrupt: {}", e);
+            }
+        }
+    }
+
+    fn handle_write(&mut self, offset: u64, val: u32) {
+        let was_pending = self.interrupt_pending();
+        match offset {
+            // The data register is read-only.
+            RTCDR => {}
+            // The new match and counter values match from the current second.
+            RTCMR => {
+                self.match_value = val;
+                self.checked = self.counter().wrapping_sub(1);
+            }
+            RTCLR => {
+                self.load = val;
+                self.load_time = Instant::now();
+                self.checked = val.wrapping_sub(1);
+            }
+            // The counter always runs, it can't be stopped.
+            RTCCR => {}
+            RTCIMSC => {
+                self.int_enabled = val & RTC_INT_MATCH;
+            }
+            RTCICR => {
+                self.int_level &= !val;
+            }
+            _ => {
+                warn!("PL031: unexpected write at offset {:#x}", offset);
+            }
+        }
+        self.update_interrupt(was_pending);
+    }
+}
+
+impl BusDevice for Rtc {
+    fn read(&mut self, _base: u64, offset: u64, data: &mut [u8]) {
+        if data.len() > 4 {
+            warn!("PL031: invalid read size {}", data.len());
+            return;
+        }
+
+        self.update_interrupt(self.interrupt_pending());
+        let v = if (0xfe0..0x1000).contains(&offset) {
+            u32::from(PL031_ID[((offset - 0xfe0) >> 2) as usize])
+        } else {
+            match offset {
+                RTCDR => self.counter(),
+                RTCMR => self.match_value,
+                RTCLR => self.load,
+                RTCCR => 1,
+                RTCIMSC => self.int_enabled,
+                RTCRIS => self.int_level,
+                RTCMIS => self.int_level & self.int_enabled,
+                _ => {
+                    warn!("PL031: unexpected read at offset {:#x}", offset);
+                    0
+                }
+            }
+        };
+
+        let mut bytes = [0u8; 4];
+        LittleEndian::write_u32(&mut bytes, v);
+        let len = data.len();
+        data.copy_from_slice(&bytes[..len]);
+    }
+
+    fn write(&mut self, _base: u64, offset: u64, data: &[u8]) {
+        if data.len() > 4 {
+            warn!("PL031: invalid write size {}", data.len());
+            return;
+        }
+
+        let mut bytes = [0u8; 4];
+        bytes[..data.len()].copy_from_slice(data);
+        self.handle_write(offset, LittleEndian::read_u32(&bytes));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io;
+    use vm_device::interrupt::{InterruptIndex, InterruptSourceConfig};
+    use vmm_sys_util::eventfd::EventFd;
+
+    struct TestInterrupt {
+        event_fd: EventFd,
+    }
+
+    impl InterruptSourceGroup for TestInterrupt {
+        fn trigger(&self, _index: InterruptIndex) -> io::Result<()> {
+            self.event_fd.write(1)
+        }
+        fn update(&self, _index: InterruptIndex, _config: InterruptSourceConfig) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn new_rtc(intr_evt: &EventFd) -> Rtc {
+        Rtc::new(Arc::new(Box::new(TestInterrupt {
+            event_fd: intr_evt.try_clone().unwrap(),
+        })))
+    }
+
+    fn read_reg(rtc: &mut Rtc, offset: u64) -> u32 {
+        let mut data = [0u8; 4];
+        rtc.read(0, offset, &mut data);
+        LittleEndian::read_u32(&data)
+    }
+
+    fn write_reg(rtc: &mut Rtc, offset: u64, val: u32) {
+        let mut data = [0u8; 4];
+        LittleEndian::write_u32(&mut data, val);
+        rtc.write(0, offset, &data);
+    }
+
+    #[test]
+    fn pl031_counter() {
+        let intr_evt = EventFd::new(0).unwrap();
+        let mut rtc = new_rtc(&intr_evt);
+
+        let now = host_time();
+        let counter = read_reg(&mut rtc, RTCDR);
+        assert!(now.wrapping_sub(counter) <= 1);
+        assert_eq!(read_reg(&mut rtc, RTCCR), 1);
+
+        write_reg(&mut rtc, RTCLR, 1000);
+        assert_eq!(read_reg(&mut rtc, RTCLR), 1000);
+        assert!(read_reg(&mut rtc, RTCDR).wrapping_sub(1000) <= 1);
+
+        // The data register is read-only.
+        write_reg(&mut rtc, RTCDR, 0);
+        assert!(read_reg(&mut rtc, RTCDR) >= 1000);
+    }
+
+    #[test]
+    fn pl031_match_interrupt() {
+        let intr_evt = EventFd::new(0).unwrap();
+        let mut rtc = new_rtc(&intr_evt);
+
+        write_reg(&mut rtc, RTCLR, 1000);
+        write_reg(&mut rtc, RTCMR, 2000);
+        write_reg(&mut rtc, RTCIMSC, RTC_INT_MATCH);
+        assert_eq!(read_reg(&mut rtc, RTCRIS), 0);
+        assert_eq!(read_reg(&mut rtc, RTCMIS), 0);
+
+        // Loading the match time raises the interrupt.
+        write_reg(&mut rtc, RTCLR, 2000);
+        assert_eq!(intr_evt.read().unwrap(), 1);
+        assert_eq!(read_reg(&mut rtc, RTCRIS), RTC_INT_MATCH);
+        assert_eq!(read_reg(&mut rtc, RTCMIS), RTC_INT_MATCH);
+
+        write_reg(&mut rtc, RTCICR, RTC_INT_MATCH);
+        assert_eq!(read_reg(&mut rtc, RTCRIS), 0);
+
+        // A masked interrupt is latched, not signaled.
+        write_reg(&mut rtc, RTCIMSC, 0);
+        write_reg(&mut rtc, RTCMR, 1000);
+        write_reg(&mut rtc, RTCLR, 1000);
+        assert_eq!(read_reg(&mut rtc, RTCRIS), RTC_INT_MATCH);
+        assert_eq!(read_reg(&mut rtc, RTCMIS), 0);
+    }
+
+    #[test]
+    fn pl031_id() {
+        let intr_evt = EventFd::new(0).unwrap();
+        let mut rtc = new_rtc(&intr_evt);
+
+        let id: Vec<u8> = (0xfe0..0x1000)
+            .step_by(4)
+            .map(|offset| read_reg(&mut rtc, offset) as u8)
+            .collect();
+        assert_eq!(id, PL031_ID);
+    }
+}
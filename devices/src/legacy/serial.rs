@@ -10,13 +10,21 @@ use std::collections::VecDeque;
 use std::sync::Arc;
 use std::{io, result};
 use vm_device::interrupt::InterruptSourceGroup;
+use vm_device::{
+    add_snapshot_state, snapshot_state, Migratable, MigratableError, Pausable, Snapshot,
+    Snapshotable,
+};
 use vmm_sys_util::errno::Result;
 
-const LOOP_SIZE: usize = 0x40;
+const FIFO_SIZE: usize = 16;
+// Input waiting for room in the receiver FIFO, past which it is dropped
+// rather than piling up while the guest isn't reading the port.
+const MAX_PENDING_INPUT: usize = 64 << 10;
 
 const DATA: u8 = 0;
 const IER: u8 = 1;
 const IIR: u8 = 2;
+const FCR: u8 = 2;
 const LCR: u8 = 3;
 const MCR: u8 = 4;
 const LSR: u8 = 5;
@@ -28,62 +36,104 @@ const DLAB_HIGH: u8 = 1;
 
 const IER_RECV_BIT: u8 = 0x1;
 const IER_THR_BIT: u8 = 0x2;
+const IER_LSR_BIT: u8 = 0x4;
 const IER_FIFO_BITS: u8 = 0x0f;
 
 const IIR_FIFO_BITS: u8 = 0xc0;
 const IIR_NONE_BIT: u8 = 0x1;
 const IIR_THR_BIT: u8 = 0x2;
 const IIR_RECV_BIT: u8 = 0x4;
+const IIR_LSR_BITS: u8 = 0x6;
+
+const FCR_FIFO_BIT: u8 = 0x1;
+const FCR_CLEAR_RECV_BIT: u8 = 0x2;
+const FCR_TRIGGER_BITS: u8 = 0xc0;
 
 const LCR_DLAB_BIT: u8 = 0x80;
 
 const LSR_DATA_BIT: u8 = 0x1;
+const LSR_OVERRUN_BIT: u8 = 0x2;
 const LSR_EMPTY_BIT: u8 = 0x20;
 const LSR_IDLE_BIT: u8 = 0x40;
 
+const MCR_DTR_BIT: u8 = 0x1;
+const MCR_RTS_BIT: u8 = 0x2;
+const MCR_OUT1_BIT: u8 = 0x4;
+const MCR_OUT2_BIT: u8 = 0x8;
 const MCR_LOOP_BIT: u8 = 0x10;
 
-const DEFAULT_INTERRUPT_IDENTIFICATION: u8 = IIR_NONE_BIT; // no pending interrupt
+const MSR_CTS_BIT: u8 = 0x10;
+const MSR_DSR_BIT: u8 = 0x20;
+const MSR_RI_BIT: u8 = 0x40;
+const MSR_DCD_BIT: u8 = 0x80;
+
 const DEFAULT_LINE_STATUS: u8 = LSR_EMPTY_BIT | LSR_IDLE_BIT; // THR empty and line is idle
 const DEFAULT_LINE_CONTROL: u8 = 0x3; // 8-bits per character
 const DEFAULT_MODEM_CONTROL: u8 = 0x8; // Auxiliary output 2
 const DEFAULT_MODEM_STATUS: u8 = 0x20 | 0x10 | 0x80; // data ready, clear to send, carrier detect
 const DEFAULT_BAUD_DIVISOR: u16 = 12; // 9600 bps
 
+/// Destination of the output of the serial ports.
+pub trait SerialOutput: io::Write + Send {
+    /// Whether `len` more bytes can be written without any being dropped. The
+    /// ports report their transmitter as busy otherwise, so that the guest
+    /// waits for the destination to catch up.
+    fn has_room(&self, _len: usize) -> bool {
+        true
+    }
+}
+
+impl SerialOutput for io::Stdout {}
+
 /// Emulates serial COM ports commonly seen on x86 I/O ports 0x3f8/0x2f8/0x3e8/0x2e8.
 ///
-/// This can optionally write the guest's output to a Write trait object. To send input to the
-/// guest, use `queue_input_bytes`.
+/// This can optionally write the guest's output to a SerialOutput trait object. To send input to
+/// the guest, use `queue_input_bytes`.
+///
+/// The port behaves as a 16550A. Output is written as soon as the guest puts it in the transmit
+/// holding register, so the transmitter FIFO is always empty, unless the output has no room for a
+/// FIFO's worth of bytes. Input goes through the 16 bytes receiver FIFO, and up to 64KiB of what
+/// doesn't fit waits for the guest to make room instead of being lost.
 pub struct Serial {
     interrupt_enable: u8,
-    interrupt_identification: u8,
+    // Set when the transmit holding register got empty, until the guest
+    // fills it again or acknowledges it by reading the IIR.
+    thr_interrupt: bool,
     interrupt: Arc<Box<dyn InterruptSourceGroup>>,
+    fifo_control: u8,
     line_control: u8,
+    // Only holds the overrun error, the other bits tell the FIFOs state.
     line_status: u8,
     modem_control: u8,
     modem_status: u8,
     scratch: u8,
     baud_divisor: u16,
     in_buffer: VecDeque<u8>,
-    out: Option<Box<dyn io::Write + Send>>,
+    // Input queued by the VMM, waiting for room in the receiver FIFO.
+    in_pending: VecDeque<u8>,
+    overruns: u64,
+    out: Option<Box<dyn SerialOutput>>,
 }
 
 impl Serial {
     pub fn new(
         interrupt: Arc<Box<dyn InterruptSourceGroup>>,
-        out: Option<Box<dyn io::Write + Send>>,
+        out: Option<Box<dyn SerialOutput>>,
     ) -> Serial {
         Serial {
             interrupt_enable: 0,
-            interrupt_identification: DEFAULT_INTERRUPT_IDENTIFICATION,
+            thr_interrupt: false,
             interrupt,
+            fifo_control: 0,
             line_control: DEFAULT_LINE_CONTROL,
-            line_status: DEFAULT_LINE_STATUS,
+            line_status: 0,
             modem_control: DEFAULT_MODEM_CONTROL,
             modem_status: DEFAULT_MODEM_STATUS,
             scratch: 0,
             baud_divisor: DEFAULT_BAUD_DIVISOR,
-            in_buffer: VecDeque::new(),
+            in_buffer: VecDeque::with_capacity(FIFO_SIZE),
+            in_pending: VecDeque::new(),
+            overruns: 0,
             out,
         }
     }
@@ -91,7 +141,7 @@ impl Serial {
     /// Constructs a Serial port ready for output.
     pub fn new_out(
         interrupt: Arc<Box<dyn InterruptSourceGroup>>,
-        out: Box<dyn io::Write + Send>,
+        out: Box<dyn SerialOutput>,
     ) -> Serial {
         Self::new(interrupt, Some(out))
     }
@@ -103,12 +153,25 @@ impl Serial {
 
     /// Queues raw bytes for the guest to read and signals the interrupt if the line status would
     /// change.
+    ///
+    /// The bytes not fitting in the receiver FIFO are kept until the guest reads it, unless too
+    /// many are waiting already, in which case they are dropped as an overrun.
     pub fn queue_input_bytes(&mut self, c: &[u8]) -> Result<()> {
-        if !self.is_loop() {
-            self.in_buffer.extend(c);
-            self.recv_data()?;
+        let room = MAX_PENDING_INPUT.saturating_sub(self.in_pending.len());
+        if c.len() > room {
+            self.line_status |= LSR_OVERRUN_BIT;
+            self.overruns += (c.len() - room) as u64;
+            debug!("Serial: dropped {} input bytes", c.len() - room);
         }
-        Ok(())
+        self.in_pending.extend(&c[..c.len().min(room)]);
+        self.fill_in_buffer();
+        self.update_interrupt()
+    }
+
+    /// Number of input bytes lost because the guest didn't read them in
+    /// time.
+    pub fn overruns(&self) -> u64 {
+        self.overruns
     }
 
     fn is_dlab_set(&self) -> bool {
@@ -123,36 +186,68 @@ impl Serial {
         (self.interrupt_enable & IER_THR_BIT) != 0
     }
 
+    fn is_lsr_intr_enabled(&self) -> bool {
+        (self.interrupt_enable & IER_LSR_BIT) != 0
+    }
+
     fn is_loop(&self) -> bool {
         (self.modem_control & MCR_LOOP_BIT) != 0
     }
 
-    fn add_intr_bit(&mut self, bit: u8) {
-        self.interrupt_identification &= !IIR_NONE_BIT;
-        self.interrupt_identification |= bit;
+    fn is_fifo_enabled(&self) -> bool {
+        (self.fifo_control & FCR_FIFO_BIT) != 0
     }
 
-    fn del_intr_bit(&mut self, bit: u8) {
-        self.interrupt_identification &= !bit;
-        if self.interrupt_identification == 0x0 {
-            self.interrupt_identification = IIR_NONE_BIT;
+    // Without FIFOs, the port only holds one received byte, as a 16450.
+    fn in_buffer_size(&self) -> usize {
+        if self.is_fifo_enabled() {
+            FIFO_SIZE
+        } else {
+            1
         }
     }
 
-    fn thr_empty(&mut self) -> Result<()> {
-        if self.is_thr_intr_enabled() {
-            self.add_intr_bit(IIR_THR_BIT);
-            self.trigger_interrupt()?
+    // Moves the queued input in the receiver FIFO, unless the port is
+    // disconnected from the line by the loopback mode.
+    fn fill_in_buffer(&mut self) {
+        if self.is_loop() {
+            return;
+        }
+        while self.in_buffer.len() < self.in_buffer_size() {
+            match self.in_pending.pop_front() {
+                Some(v) => self.in_buffer.push_back(v),
+                None => break,
+            }
+        }
+    }
+
+    fn loop_data(&mut self, v: u8) {
+        if self.in_buffer.len() < self.in_buffer_size() {
+            self.in_buffer.push_back(v);
+        } else {
+            self.line_status |= LSR_OVERRUN_BIT;
+            self.overruns += 1;
+            debug!("Serial: receiver FIFO overrun");
         }
-        Ok(())
     }
 
-    fn recv_data(&mut self) -> Result<()> {
-        if self.is_recv_intr_enabled() {
-            self.add_intr_bit(IIR_RECV_BIT);
+    // Identifies the pending interrupt with the highest priority.
+    fn interrupt_identification(&self) -> u8 {
+        if self.is_lsr_intr_enabled() && (self.line_status & LSR_OVERRUN_BIT) != 0 {
+            IIR_LSR_BITS
+        } else if self.is_recv_intr_enabled() && !self.in_buffer.is_empty() {
+            IIR_RECV_BIT
+        } else if self.is_thr_intr_enabled() && self.thr_interrupt {
+            IIR_THR_BIT
+        } else {
+            IIR_NONE_BIT
+        }
+    }
+
+    fn update_interrupt(&mut self) -> Result<()> {
+        if self.interrupt_identification() != IIR_NONE_BIT {
             self.trigger_interrupt()?
         }
-        self.line_status |= LSR_DATA_BIT;
         Ok(())
     }
 
@@ -160,8 +255,46 @@ impl Serial {
         self.interrupt.trigger(0)
     }
 
-    fn iir_reset(&mut self) {
-        self.interrupt_identification = DEFAULT_INTERRUPT_IDENTIFICATION;
+    // Whether the output can't take a FIFO's worth of bytes, the guest being
+    // told to wait.
+    fn is_out_busy(&self) -> bool {
+        match self.out.as_ref() {
+            Some(out) if !self.is_loop() => !out.has_room(FIFO_SIZE),
+            _ => false,
+        }
+    }
+
+    fn line_status(&self) -> u8 {
+        let mut v = self.line_status | DEFAULT_LINE_STATUS;
+        if !self.in_buffer.is_empty() {
+            v |= LSR_DATA_BIT;
+        }
+        if self.is_out_busy() {
+            v &= !(LSR_EMPTY_BIT | LSR_IDLE_BIT);
+        }
+        v
+    }
+
+    fn modem_status(&self) -> u8 {
+        if !self.is_loop() {
+            return self.modem_status;
+        }
+
+        // In loopback mode, the modem control outputs drive the inputs.
+        let mut v = 0;
+        if (self.modem_control & MCR_RTS_BIT) != 0 {
+            v |= MSR_CTS_BIT;
+        }
+        if (self.modem_control & MCR_DTR_BIT) != 0 {
+            v |= MSR_DSR_BIT;
+        }
+        if (self.modem_control & MCR_OUT1_BIT) != 0 {
+            v |= MSR_RI_BIT;
+        }
+        if (self.modem_control & MCR_OUT2_BIT) != 0 {
+            v |= MSR_DCD_BIT;
+        }
+        v
     }
 
     fn handle_write(&mut self, offset: u8, v: u8) -> Result<()> {
@@ -173,22 +306,45 @@ impl Serial {
                 self.baud_divisor = (self.baud_divisor & 0x00ff) | ((u16::from(v)) << 8)
             }
             DATA => {
+                self.thr_interrupt = false;
                 if self.is_loop() {
-                    if self.in_buffer.len() < LOOP_SIZE {
-                        self.in_buffer.push_back(v);
-                        self.recv_data()?;
-                    }
-                } else {
-                    if let Some(out) = self.out.as_mut() {
-                        out.write_all(&[v])?;
-                        out.flush()?;
-                    }
-                    self.thr_empty()?;
+                    self.loop_data(v);
+                } else if let Some(out) = self.out.as_mut() {
+                    out.write_all(&[v])?;
+                    out.flush()?;
+                }
+                // The byte is gone, the holding register is empty again.
+                self.thr_interrupt = true;
+                self.update_interrupt()?;
+            }
+            IER => {
+                // Enabling the interrupt while the holding register is
+                // empty raises it right away, which guests rely on to start
+                // transmitting.
+                if !self.is_thr_intr_enabled() && (v & IER_THR_BIT) != 0 {
+                    self.thr_interrupt = true;
                 }
+                self.interrupt_enable = v & IER_FIFO_BITS;
+                self.update_interrupt()?;
+            }
+            FCR => {
+                let fifo_control = v & (FCR_FIFO_BIT | FCR_TRIGGER_BITS);
+                // Switching the FIFOs on or off clears them.
+                if (v & FCR_CLEAR_RECV_BIT) != 0
+                    || (fifo_control ^ self.fifo_control) & FCR_FIFO_BIT != 0
+                {
+                    self.in_buffer.clear();
+                }
+                self.fifo_control = fifo_control;
+                self.fill_in_buffer();
+                self.update_interrupt()?;
             }
-            IER => self.interrupt_enable = v & IER_FIFO_BITS,
             LCR => self.line_control = v,
-            MCR => self.modem_control = v,
+            MCR => {
+                self.modem_control = v;
+                self.fill_in_buffer();
+                self.update_interrupt()?;
+            }
             SCR => self.scratch = v,
             _ => {}
         }
@@ -206,22 +362,32 @@ impl BusDevice for Serial {
             DLAB_LOW if self.is_dlab_set() => self.baud_divisor as u8,
             DLAB_HIGH if self.is_dlab_set() => (self.baud_divisor >> 8) as u8,
             DATA => {
-                self.del_intr_bit(IIR_RECV_BIT);
-                if self.in_buffer.len() <= 1 {
-                    self.line_status &= !LSR_DATA_BIT;
-                }
-                self.in_buffer.pop_front().unwrap_or_default()
+                let v = self.in_buffer.pop_front().unwrap_or_default();
+                self.fill_in_buffer();
+                v
             }
             IER => self.interrupt_enable,
             IIR => {
-                let v = self.interrupt_identification | IIR_FIFO_BITS;
-                self.iir_reset();
-                v
+                let v = self.interrupt_identification();
+                // Reading the identification acknowledges the transmitter
+                // interrupt, the others last as long as their condition.
+                if v == IIR_THR_BIT {
+                    self.thr_interrupt = false;
+                }
+                if self.is_fifo_enabled() {
+                    v | IIR_FIFO_BITS
+                } else {
+                    v
+                }
             }
             LCR => self.line_control,
             MCR => self.modem_control,
-            LSR => self.line_status,
-            MSR => self.modem_status,
+            LSR => {
+                let v = self.line_status();
+                self.line_status &= !LSR_OVERRUN_BIT;
+                v
+            }
+            MSR => self.modem_status(),
             SCR => self.scratch,
             _ => 0,
         };
@@ -236,6 +402,80 @@ impl BusDevice for Serial {
     }
 }
 
+impl Pausable for Serial {
+    fn pause(&mut self) -> result::Result<(), MigratableError> {
+        Ok(())
+    }
+
+    fn resume(&mut self) -> result::Result<(), MigratableError> {
+        Ok(())
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+struct SerialState {
+    interrupt_enable: u8,
+    thr_interrupt: bool,
+    fifo_control: u8,
+    line_control: u8,
+    line_status: u8,
+    modem_control: u8,
+    modem_status: u8,
+    scratch: u8,
+    baud_divisor: u16,
+    in_buffer: VecDeque<u8>,
+    in_pending: VecDeque<u8>,
+    overruns: u64,
+}
+
+impl Snapshotable for Serial {
+    fn id(&self) -> String {
+        String::from("serial")
+    }
+
+    fn snapshot(&self) -> result::Result<Snapshot, MigratableError> {
+        let mut snapshot = Snapshot::new(&self.id());
+        add_snapshot_state(
+            &mut snapshot,
+            &SerialState {
+                interrupt_enable: self.interrupt_enable,
+                thr_interrupt: self.thr_interrupt,
+                fifo_control: self.fifo_control,
+                line_control: self.line_control,
+                line_status: self.line_status,
+                modem_control: self.modem_control,
+                modem_status: self.modem_status,
+                scratch: self.scratch,
+                baud_divisor: self.baud_divisor,
+                in_buffer: self.in_buffer.clone(),
+                in_pending: self.in_pending.clone(),
+                overruns: self.overruns,
+            },
+        )?;
+        Ok(snapshot)
+    }
+
+    fn restore(&mut self, snapshot: Snapshot) -> result::Result<(), MigratableError> {
+        if let Some(state) = snapshot_state::<SerialState>(&snapshot)? {
+            self.interrupt_enable = state.interrupt_enable;
+            self.thr_interrupt = state.thr_interrupt;
+            self.fifo_control = state.fifo_control;
+            self.line_control = state.line_control;
+            self.line_status = state.line_status;
+            self.modem_control = state.modem_control;
+            self.modem_status = state.modem_status;
+            self.scratch = state.scratch;
+            self.baud_divisor = state.baud_divisor;
+            self.in_buffer = state.in_buffer;
+            self.in_pending = state.in_pending;
+            self.overruns = state.overruns;
+        }
+        Ok(())
+    }
+}
+
+impl Migratable for Serial {}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -289,6 +529,32 @@ mod tests {
         }
     }
 
+    impl SerialOutput for SharedBuffer {}
+
+    // Output with room for a given number of bytes, the bytes written past
+    // it being lost.
+    #[derive(Clone)]
+    struct BoundedBuffer {
+        room: Arc<Mutex<usize>>,
+    }
+
+    impl io::Write for BoundedBuffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let mut room = self.room.lock().unwrap();
+            *room = room.saturating_sub(buf.len());
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl SerialOutput for BoundedBuffer {
+        fn has_room(&self, len: usize) -> bool {
+            *self.room.lock().unwrap() >= len
+        }
+    }
+
     #[test]
     fn serial_output() {
         let intr_evt = EventFd::new(0).unwrap();
@@ -357,10 +623,12 @@ mod tests {
         // write 1 to the interrupt event fd, so that read doesn't block in case the event fd
         // counter doesn't change (for 0 it blocks)
         assert!(intr_evt.write(1).is_ok());
+        // Enabling the interrupt with the holding register empty raises it,
+        // as does sending a byte.
         serial.write(0, IER as u64, &[IER_THR_BIT]);
         serial.write(0, DATA as u64, &['a' as u8]);
 
-        assert_eq!(intr_evt.read().unwrap(), 2);
+        assert_eq!(intr_evt.read().unwrap(), 3);
         let mut data = [0u8];
         serial.read(0, IER as u64, &mut data[..]);
         assert_eq!(data[0] & IER_FIFO_BITS, IER_THR_BIT);
@@ -395,6 +663,7 @@ mod tests {
             intr_evt.try_clone().unwrap(),
         ))));
 
+        serial.write(0, FCR as u64, &[FCR_FIFO_BIT]);
         serial.write(0, MCR as u64, &[MCR_LOOP_BIT as u8]);
         serial.write(0, DATA as u64, &['a' as u8]);
         serial.write(0, DATA as u64, &['b' as u8]);
@@ -402,7 +671,7 @@ mod tests {
 
         let mut data = [0u8];
         serial.read(0, MSR as u64, &mut data[..]);
-        assert_eq!(data[0], DEFAULT_MODEM_STATUS as u8);
+        assert_eq!(data[0], 0);
         serial.read(0, MCR as u64, &mut data[..]);
         assert_eq!(data[0], MCR_LOOP_BIT as u8);
         serial.read(0, DATA as u64, &mut data[..]);
@@ -426,4 +695,225 @@ mod tests {
         serial.read(0, SCR as u64, &mut data[..]);
         assert_eq!(data[0], 0x12 as u8);
     }
+
+    #[test]
+    fn serial_linux_probe() {
+        let intr_evt = EventFd::new(0).unwrap();
+        let mut serial = Serial::new_sink(Arc::new(Box::new(TestInterrupt::new(
+            intr_evt.try_clone().unwrap(),
+        ))));
+        let mut data = [0u8];
+
+        // Scratch register test.
+        for v in &[0xa5u8, 0x5a] {
+            serial.write(0, SCR as u64, &[*v]);
+            serial.read(0, SCR as u64, &mut data[..]);
+            assert_eq!(data[0], *v);
+        }
+
+        // Interrupt enable register test.
+        serial.write(0, IER as u64, &[0]);
+        serial.read(0, IER as u64, &mut data[..]);
+        assert_eq!(data[0] & 0x0f, 0);
+        serial.write(0, IER as u64, &[0x0f]);
+        serial.read(0, IER as u64, &mut data[..]);
+        assert_eq!(data[0] & 0x0f, 0x0f);
+        serial.write(0, IER as u64, &[0]);
+
+        // Loopback test, RTS and OUT2 showing up as CTS and DCD.
+        serial.write(0, MCR as u64, &[MCR_LOOP_BIT | MCR_OUT2_BIT | MCR_RTS_BIT]);
+        serial.read(0, MSR as u64, &mut data[..]);
+        assert_eq!(data[0] & 0xf0, MSR_DCD_BIT | MSR_CTS_BIT);
+        serial.write(0, MCR as u64, &[DEFAULT_MODEM_CONTROL]);
+        serial.read(0, MSR as u64, &mut data[..]);
+        assert_eq!(data[0], DEFAULT_MODEM_STATUS);
+
+        // FIFOs detection, telling a 16550A.
+        serial.read(0, IIR as u64, &mut data[..]);
+        assert_eq!(data[0], IIR_NONE_BIT);
+        serial.write(0, FCR as u64, &[FCR_FIFO_BIT]);
+        serial.read(0, IIR as u64, &mut data[..]);
+        assert_eq!(data[0] >> 6, 3);
+    }
+
+    #[test]
+    fn serial_iir_priority() {
+        let intr_evt = EventFd::new(0).unwrap();
+        let mut serial = Serial::new_sink(Arc::new(Box::new(TestInterrupt::new(
+            intr_evt.try_clone().unwrap(),
+        ))));
+
+        serial.write(0, FCR as u64, &[FCR_FIFO_BIT]);
+        serial.write(0, IER as u64, &[IER_RECV_BIT | IER_THR_BIT]);
+        serial.queue_input_bytes(&['a' as u8]).unwrap();
+
+        // Received data comes before the empty holding register.
+        let mut data = [0u8];
+        serial.read(0, IIR as u64, &mut data[..]);
+        assert_eq!(data[0], IIR_FIFO_BITS | IIR_RECV_BIT);
+        serial.read(0, DATA as u64, &mut data[..]);
+        assert_eq!(data[0], 'a' as u8);
+
+        // Reading the identification clears the transmitter interrupt.
+        serial.read(0, IIR as u64, &mut data[..]);
+        assert_eq!(data[0], IIR_FIFO_BITS | IIR_THR_BIT);
+        serial.read(0, IIR as u64, &mut data[..]);
+        assert_eq!(data[0], IIR_FIFO_BITS | IIR_NONE_BIT);
+    }
+
+    #[test]
+    fn serial_input_fifo() {
+        let intr_evt = EventFd::new(0).unwrap();
+        let mut serial = Serial::new_sink(Arc::new(Box::new(TestInterrupt::new(
+            intr_evt.try_clone().unwrap(),
+        ))));
+
+        serial.write(0, FCR as u64, &[FCR_FIFO_BIT]);
+        let input: Vec<u8> = (0..(FIFO_SIZE as u8 * 2 + 3)).collect();
+        serial.queue_input_bytes(&input).unwrap();
+        assert_eq!(serial.in_buffer.len(), FIFO_SIZE);
+
+        // Nothing gets lost when the input doesn't fit in the FIFO.
+        let mut data = [0u8];
+        let mut output = Vec::new();
+        loop {
+            serial.read(0, LSR as u64, &mut data[..]);
+            if data[0] & LSR_DATA_BIT == 0 {
+                break;
+            }
+            assert_eq!(data[0] & LSR_OVERRUN_BIT, 0);
+            serial.read(0, DATA as u64, &mut data[..]);
+            output.push(data[0]);
+        }
+        assert_eq!(output, input);
+        assert_eq!(serial.overruns(), 0);
+    }
+
+    #[test]
+    fn serial_input_dropped() {
+        let intr_evt = EventFd::new(0).unwrap();
+        let mut serial = Serial::new_sink(Arc::new(Box::new(TestInterrupt::new(
+            intr_evt.try_clone().unwrap(),
+        ))));
+
+        serial.write(0, FCR as u64, &[FCR_FIFO_BIT]);
+        let input = vec![0x61u8; FIFO_SIZE + MAX_PENDING_INPUT - 1];
+        serial.queue_input_bytes(&input).unwrap();
+        assert_eq!(serial.overruns(), 0);
+
+        // The input past the limit is dropped.
+        serial.queue_input_bytes(&[0x62, 0x63, 0x64]).unwrap();
+        assert_eq!(serial.overruns(), 2);
+        assert_eq!(serial.in_pending.len(), MAX_PENDING_INPUT);
+        assert_eq!(serial.in_pending.back(), Some(&0x62));
+
+        let mut data = [0u8];
+        serial.read(0, LSR as u64, &mut data[..]);
+        assert_eq!(
+            data[0],
+            DEFAULT_LINE_STATUS | LSR_OVERRUN_BIT | LSR_DATA_BIT
+        );
+    }
+
+    #[test]
+    fn serial_loop_overrun() {
+        let intr_evt = EventFd::new(0).unwrap();
+        let mut serial = Serial::new_sink(Arc::new(Box::new(TestInterrupt::new(
+            intr_evt.try_clone().unwrap(),
+        ))));
+
+        serial.write(0, FCR as u64, &[FCR_FIFO_BIT]);
+        serial.write(0, MCR as u64, &[MCR_LOOP_BIT]);
+        serial.write(0, IER as u64, &[IER_LSR_BIT]);
+        for i in 0..=FIFO_SIZE {
+            serial.write(0, DATA as u64, &[i as u8]);
+        }
+        assert_eq!(serial.overruns(), 1);
+
+        let mut data = [0u8];
+        serial.read(0, IIR as u64, &mut data[..]);
+        assert_eq!(data[0], IIR_FIFO_BITS | IIR_LSR_BITS);
+
+        // The error is cleared once reported.
+        serial.read(0, LSR as u64, &mut data[..]);
+        assert_eq!(
+            data[0],
+            DEFAULT_LINE_STATUS | LSR_OVERRUN_BIT | LSR_DATA_BIT
+        );
+        serial.read(0, LSR as u64, &mut data[..]);
+        assert_eq!(data[0], DEFAULT_LINE_STATUS | LSR_DATA_BIT);
+        serial.read(0, IIR as u64, &mut data[..]);
+        assert_eq!(data[0], IIR_FIFO_BITS | IIR_NONE_BIT);
+
+        // The byte which didn't fit is lost.
+        for i in 0..FIFO_SIZE {
+            serial.read(0, DATA as u64, &mut data[..]);
+            assert_eq!(data[0], i as u8);
+        }
+        serial.read(0, LSR as u64, &mut data[..]);
+        assert_eq!(data[0], DEFAULT_LINE_STATUS);
+    }
+
+    #[test]
+    fn serial_output_busy() {
+        let intr_evt = EventFd::new(0).unwrap();
+        let serial_out = BoundedBuffer {
+            room: Arc::new(Mutex::new(FIFO_SIZE + 1)),
+        };
+        let mut serial = Serial::new_out(
+            Arc::new(Box::new(TestInterrupt::new(intr_evt.try_clone().unwrap()))),
+            Box::new(serial_out.clone()),
+        );
+
+        let mut data = [0u8];
+        serial.read(0, LSR as u64, &mut data[..]);
+        assert_eq!(data[0], DEFAULT_LINE_STATUS);
+
+        // The transmitter looks busy once the output is short of room.
+        serial.write(0, DATA as u64, &['a' as u8]);
+        serial.write(0, DATA as u64, &['b' as u8]);
+        serial.read(0, LSR as u64, &mut data[..]);
+        assert_eq!(data[0] & (LSR_EMPTY_BIT | LSR_IDLE_BIT), 0);
+
+        // But not in loopback mode, the output being disconnected.
+        serial.write(0, MCR as u64, &[MCR_LOOP_BIT]);
+        serial.read(0, LSR as u64, &mut data[..]);
+        assert_eq!(data[0], DEFAULT_LINE_STATUS);
+        serial.write(0, MCR as u64, &[DEFAULT_MODEM_CONTROL]);
+
+        *serial_out.room.lock().unwrap() = FIFO_SIZE;
+        serial.read(0, LSR as u64, &mut data[..]);
+        assert_eq!(data[0], DEFAULT_LINE_STATUS);
+    }
+
+    #[test]
+    fn serial_snapshot() {
+        let intr_evt = EventFd::new(0).unwrap();
+        let mut serial = Serial::new_sink(Arc::new(Box::new(TestInterrupt::new(
+            intr_evt.try_clone().unwrap(),
+        ))));
+
+        serial.write(0, LCR as u64, &[LCR_DLAB_BIT]);
+        serial.write(0, DLAB_LOW as u64, &[0x34]);
+        serial.write(0, LCR as u64, &[DEFAULT_LINE_CONTROL]);
+        serial.write(0, SCR as u64, &[0x42]);
+        serial.queue_input_bytes(&['a' as u8, 'b' as u8]).unwrap();
+        let snapshot = serial.snapshot().unwrap();
+
+        let mut restored = Serial::new_sink(Arc::new(Box::new(TestInterrupt::new(
+            intr_evt.try_clone().unwrap(),
+        ))));
+        restored.restore(snapshot).unwrap();
+
+        let mut data = [0u8];
+        restored.read(0, SCR as u64, &mut data[..]);
+        assert_eq!(data[0], 0x42);
+        restored.read(0, DATA as u64, &mut data[..]);
+        assert_eq!(data[0], 'a' as u8);
+        restored.read(0, DATA as u64, &mut data[..]);
+        assert_eq!(data[0], 'b' as u8);
+        restored.write(0, LCR as u64, &[LCR_DLAB_BIT]);
+        restored.read(0, DLAB_LOW as u64, &mut data[..]);
+        assert_eq!(data[0], 0x34);
+    }
 }
@@ -0,0 +1,378 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! ARM PrimeCell UART (PL011)
+//!
+//! This module implements an ARM PrimeCell UART (PL011), the serial port
+//! found on most arm64 platforms and described to the guest in the device
+//! tree.
+
+use super::SerialOutput;
+use crate::BusDevice;
+use byteorder::{ByteOrder, LittleEndian};
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::{io, result};
+use vm_device::interrupt::InterruptSourceGroup;
+use vmm_sys_util::errno::Result;
+
+// Register indexes, the registers being 32-bit wide.
+const UARTDR: u64 = 0;
+const UARTRSR_UARTECR: u64 = 1;
+const UARTFR: u64 = 6;
+const UARTILPR: u64 = 8;
+const UARTIBRD: u64 = 9;
+const UARTFBRD: u64 = 10;
+const UARTLCR_H: u64 = 11;
+const UARTCR: u64 = 12;
+const UARTIFLS: u64 = 13;
+const UARTIMSC: u64 = 14;
+const UARTRIS: u64 = 15;
+const UARTMIS: u64 = 16;
+const UARTICR: u64 = 17;
+const UARTDMACR: u64 = 18;
+
+const PL011_INT_TX: u32 = 0x20;
+const PL011_INT_RX: u32 = 0x10;
+
+const PL011_FLAG_RXFF: u32 = 0x40;
+const PL011_FLAG_RXFE: u32 = 0x10;
+const PL011_FLAG_TXFF: u32 = 0x20;
+const PL011_FLAG_TXFE: u32 = 0x80;
+
+const PL011_FIFO_SZ: usize = 16;
+
+// Peripheral and PrimeCell identification registers, from 0xfe0 to 0xffc,
+// which the guest reads to probe the device on the AMBA bus.
+const PL011_ID: [u8; 8] = [0x11, 0x10, 0x14, 0x00, 0x0d, 0xf0, 0x05, 0xb1];
+
+// Size of the register space.
+pub const PL011_SIZE: u64 = 0x1000;
+
+/// A PL011 device following the PL011 specification.
+///
+/// This can optionally write the guest's output to a SerialOutput trait
+/// object, the transmit FIFO looking full while it has no room for a FIFO's
+/// worth of bytes. To send input to the guest, use `queue_input_bytes`.
+pub struct Pl011 {
+    flags: u32,
+    lcr: u32,
+    rsr: u32,
+    cr: u32,
+    dmacr: u32,
+    int_enabled: u32,
+    int_level: u32,
+    read_fifo: VecDeque<u32>,
+    ilpr: u32,
+    ibrd: u32,
+    fbrd: u32,
+    ifl: u32,
+    read_trigger: u32,
+    irq: Arc<Box<dyn InterruptSourceGroup>>,
+    out: Option<Box<dyn SerialOutput>>,
+}
+
+impl Pl011 {
+    /// Constructs an AMBA PL011 UART device.
+    pub fn new(
+        irq: Arc<Box<dyn InterruptSourceGroup>>,
+        out: Option<Box<dyn SerialOutput>>,
+    ) -> Pl011 {
+        Pl011 {
+            flags: PL011_FLAG_RXFE | PL011_FLAG_TXFE,
+            lcr: 0,
+            rsr: 0,
+            cr: 0x300,
+            dmacr: 0,
+            int_enabled: 0,
+            int_level: 0,
+            read_fifo: VecDeque::new(),
+            ilpr: 0,
+            ibrd: 0,
+            fbrd: 0,
+            ifl: 0x12,
+            read_trigger: 1,
+            irq,
+            out,
+        }
+    }
+
+    /// Queues raw bytes for the guest to read and signals the interrupt.
+    pub fn queue_input_bytes(&mut self, c: &[u8]) -> Result<()> {
+        for &byte in c {
+            self.read_fifo.push_back(u32::from(byte));
+        }
+        self.flags &= !PL011_FLAG_RXFE;
+        if self.read_fifo.len() >= PL011_FIFO_SZ {
+            self.flags |= PL011_FLAG_RXFF;
+        }
+        if self.read_fifo.len() >= self.read_trigger as usize {
+            self.int_level |= PL011_INT_RX;
+        }
+        self.update_interrupt()?;
+        Ok(())
+    }
+
+    fn handle_write(&mut self, offset: u64, val: u32) -> Result<()> {
+        match offset >> 2 {
+            UARTDR => {
+                self.int_level |= PL011_INT_TX;
+                if let Some(out) = self.out.as_mut() {
+                    out.write_all(&[val.to_le_bytes()[0]])?;
+                    out.flush()?;
+                }
+            }
+            UARTRSR_UARTECR => {
+                self.rsr = 0;
+            }
+            // The flag register is read-only.
+            UARTFR => {}
+            UARTILPR => {
+                self.ilpr = val;
+            }
+            UARTIBRD => {
+                self.ibrd = val;
+            }
+            UARTFBRD => {
+                self.fbrd = val;
+            }
+            UARTLCR_H => {
+                self.lcr = val;
+            }
+            UARTCR => {
+                self.cr = val;
+            }
+            UARTIFLS => {
+                self.ifl = val;
+            }
+            UARTIMSC => {
+                self.int_enabled = val;
+            }
+            UARTICR => {
+                self.int_level &= !val;
+            }
+            UARTDMACR => {
+                self.dmacr = val;
+                if val & 3 != 0 {
+                    warn!("PL011: DMA not implemented");
+                }
+            }
+            _ => {
+                warn!("PL011: unexpected write at offset {:#x}", offset);
+                return Ok(());
+            }
+        }
+        self.update_interrupt()?;
+        Ok(())
+    }
+
+    fn flags(&self) -> u32 {
+        match self.out.as_ref() {
+            Some(out) if !out.has_room(PL011_FIFO_SZ) => {
+                (self.flags & !PL011_FLAG_TXFE) | PL011_FLAG_TXFF
+            }
+            _ => self.flags,
+        }
+    }
+
+    fn update_interrupt(&mut self) -> result::Result<(), io::Error> {
+        if self.int_level & self.int_enabled != 0 {
+            self.trigger_interrupt()?;
+        }
+        Ok(())
+    }
+
+    fn trigger_interrupt(&mut self) -> result::Result<(), io::Error> {
+        self.irq.trigger(0)
+    }
+}
+
+impl BusDevice for Pl011 {
+    fn read(&mut self, _base: u64, offset: u64, data: &mut [u8]) {
+        if data.len() > 4 {
+            warn!("PL011: invalid read size {}", data.len());
+            return;
+        }
+
+        let v = if (0x3f8..0x400).contains(&(offset >> 2)) {
+            // Identification registers, one byte each in the low bits
+            // of consecutive 32-bit registers.
+            u32::from(PL011_ID[((offset - 0xfe0) >> 2) as usize])
+        } else {
+            match offset >> 2 {
+                UARTDR => {
+                    let c = self.read_fifo.pop_front().unwrap_or_default();
+                    self.flags &= !PL011_FLAG_RXFF;
+                    if self.read_fifo.is_empty() {
+                        self.flags |= PL011_FLAG_RXFE;
+                    }
+                    if self.read_fifo.len() < self.read_trigger as usize {
+                        self.int_level &= !PL011_INT_RX;
+                    }
+                    self.rsr = c >> 8;
+                    c
+                }
+                UARTRSR_UARTECR => self.rsr,
+                UARTFR => self.flags(),
+                UARTILPR => self.ilpr,
+                UARTIBRD => self.ibrd,
+                UARTFBRD => self.fbrd,
+                UARTLCR_H => self.lcr,
+                UARTCR => self.cr,
+                UARTIFLS => self.ifl,
+                UARTIMSC => self.int_enabled,
+                UARTRIS => self.int_level,
+                UARTMIS => self.int_level & self.int_enabled,
+                UARTDMACR => self.dmacr,
+                _ => 0,
+            }
+        };
+
+        let mut bytes = [0u8; 4];
+        LittleEndian::write_u32(&mut bytes, v);
+        let len = data.len();
+        data.copy_from_slice(&bytes[..len]);
+    }
+
+    fn write(&mut self, _base: u64, offset: u64, data: &[u8]) {
+        if data.len() > 4 {
+            warn!("PL011: invalid write size {}", data.len());
+            return;
+        }
+
+        let mut bytes = [0u8; 4];
+        bytes[..data.len()].copy_from_slice(data);
+        if let Err(e) = self.handle_write(offset, LittleEndian::read_u32(&bytes)) {
+            warn!("PL011: failed to write to the device: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+    use vm_device::interrupt::{InterruptIndex, InterruptSourceConfig};
+    use vmm_sys_util::eventfd::EventFd;
+
+    struct TestInterrupt {
+        event_fd: EventFd,
+    }
+
+    impl InterruptSourceGroup for TestInterrupt {
+        fn trigger(&self, _index: InterruptIndex) -> result::Result<(), std::io::Error> {
+            self.event_fd.write(1)
+        }
+        fn update(
+            &self,
+            _index: InterruptIndex,
+            _config: InterruptSourceConfig,
+        ) -> result::Result<(), std::io::Error> {
+            Ok(())
+        }
+    }
+
+    #[derive(Clone)]
+    struct SharedBuffer {
+        buf: Arc<Mutex<Vec<u8>>>,
+    }
+
+    impl io::Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.buf.lock().unwrap().write(buf)
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            self.buf.lock().unwrap().flush()
+        }
+    }
+
+    impl SerialOutput for SharedBuffer {}
+
+    fn new_pl011(intr_evt: &EventFd, out: Option<SharedBuffer>) -> Pl011 {
+        Pl011::new(
+            Arc::new(Box::new(TestInterrupt {
+                event_fd: intr_evt.try_clone().unwrap(),
+            })),
+            out.map(|out| Box::new(out) as Box<dyn SerialOutput>),
+        )
+    }
+
+    #[test]
+    fn pl011_output() {
+        let intr_evt = EventFd::new(0).unwrap();
+        let pl011_out = SharedBuffer {
+            buf: Arc::new(Mutex::new(Vec::new())),
+        };
+        let mut pl011 = new_pl011(&intr_evt, Some(pl011_out.clone()));
+
+        pl011.write(0, UARTDR, &[b'a', 0, 0, 0]);
+        pl011.write(0, UARTDR, &[b'b']);
+        assert_eq!(pl011_out.buf.lock().unwrap().as_slice(), b"ab");
+    }
+
+    #[test]
+    fn pl011_input() {
+        let intr_evt = EventFd::new(0).unwrap();
+        let mut pl011 = new_pl011(&intr_evt, None);
+
+        // write 1 to the interrupt event fd, so that read doesn't block in case the event fd
+        // counter doesn't change (for 0 it blocks)
+        assert!(intr_evt.write(1).is_ok());
+        pl011.write(0, UARTIMSC << 2, &[PL011_INT_RX as u8, 0, 0, 0]);
+        pl011.queue_input_bytes(b"ab").unwrap();
+        assert_eq!(intr_evt.read().unwrap(), 2);
+
+        let mut data = [0u8; 4];
+        pl011.read(0, UARTFR << 2, &mut data);
+        assert_eq!(LittleEndian::read_u32(&data) & PL011_FLAG_RXFE, 0);
+        pl011.read(0, UARTMIS << 2, &mut data);
+        assert_eq!(LittleEndian::read_u32(&data), PL011_INT_RX);
+
+        pl011.read(0, UARTDR, &mut data);
+        assert_eq!(data[0], b'a');
+        pl011.read(0, UARTDR, &mut data);
+        assert_eq!(data[0], b'b');
+
+        pl011.read(0, UARTFR << 2, &mut data);
+        assert_ne!(LittleEndian::read_u32(&data) & PL011_FLAG_RXFE, 0);
+        pl011.read(0, UARTRIS << 2, &mut data);
+        assert_eq!(LittleEndian::read_u32(&data) & PL011_INT_RX, 0);
+    }
+
+    #[test]
+    fn pl011_masked_input() {
+        let intr_evt = EventFd::new(0).unwrap();
+        let mut pl011 = new_pl011(&intr_evt, None);
+
+        // The RX interrupt is latched but not signaled until it is unmasked.
+        pl011.queue_input_bytes(b"a").unwrap();
+        let mut data = [0u8; 4];
+        pl011.read(0, UARTRIS << 2, &mut data);
+        assert_eq!(LittleEndian::read_u32(&data) & PL011_INT_RX, PL011_INT_RX);
+        pl011.read(0, UARTMIS << 2, &mut data);
+        assert_eq!(LittleEndian::read_u32(&data), 0);
+
+        pl011.write(0, UARTIMSC << 2, &[PL011_INT_RX as u8, 0, 0, 0]);
+        assert_eq!(intr_evt.read().unwrap(), 1);
+
+        // Clearing the TX interrupt leaves the RX one alone.
+        pl011.write(0, UARTICR << 2, &[PL011_INT_TX as u8, 0, 0, 0]);
+        pl011.read(0, UARTRIS << 2, &mut data);
+        assert_eq!(LittleEndian::read_u32(&data), PL011_INT_RX);
+    }
+
+    #[test]
+    fn pl011_id() {
+        let intr_evt = EventFd::new(0).unwrap();
+        let mut pl011 = new_pl011(&intr_evt, None);
+
+        let mut id = Vec::new();
+        for offset in (0xfe0..0x1000).step_by(4) {
+            let mut data = [0u8; 4];
+            pl011.read(0, offset, &mut data);
+            id.push(data[0]);
+        }
+        assert_eq!(id, PL011_ID);
+    }
+}
@@ -13,10 +13,14 @@ extern crate epoll;
 extern crate libc;
 #[macro_use]
 extern crate log;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
 extern crate vm_device;
 extern crate vm_memory;
 extern crate vmm_sys_util;
 
+use std::fmt;
 use std::fs::File;
 use std::io;
 
@@ -68,10 +72,40 @@ pub enum Error {
     IoError(io::Error),
 }
 
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::FailedReadingQueue { event_type, .. } => {
+                write!(f, "failed reading the {} queue event", event_type)
+            }
+            Error::FailedReadTap => write!(f, "failed reading the tap interface"),
+            Error::FailedSignalingUsedQueue(_) => write!(f, "failed signaling the used queue"),
+            Error::PayloadExpected => write!(f, "epoll handler payload expected"),
+            Error::UnknownEvent { device, event } => {
+                write!(f, "unknown event {} for the {} device", event, device)
+            }
+            Error::IoError(_) => write!(f, "I/O error"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::FailedSignalingUsedQueue(e) => Some(e),
+            Error::IoError(e) => Some(e),
+            Error::FailedReadingQueue { underlying, .. } => Some(underlying),
+            _ => None,
+        }
+    }
+}
+
 bitflags! {
     pub struct HotPlugNotificationFlags: u8 {
         const NO_DEVICES_CHANGED = 0;
         const CPU_DEVICES_CHANGED = 0b1;
         const MEMORY_DEVICES_CHANGED = 0b10;
+        const PCI_DEVICES_CHANGED = 0b100;
+        const GENERATION_ID_CHANGED = 0b1000;
     }
 }
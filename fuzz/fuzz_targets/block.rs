@@ -0,0 +1,59 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use std::io::Cursor;
+use vm_memory::{Bytes, GuestAddress, GuestMemoryMmap};
+use vm_virtio::block::{Request, SECTOR_SIZE};
+use vm_virtio::Queue;
+
+const MEM_SIZE: usize = 0x10000;
+const QUEUE_SIZE: u16 = 16;
+const DESC_TABLE: u64 = 0;
+const AVAIL_RING: u64 = DESC_TABLE + 16 * QUEUE_SIZE as u64;
+const USED_RING: u64 = 0x1000;
+const DISK_SIZE: u64 = 0x1000;
+
+// The fuzzer bytes make up guest memory, with the queue rings laid out at
+// fixed addresses so that most inputs reach the request parser.
+fuzz_target!(|bytes: &[u8]| {
+    if bytes.len() > MEM_SIZE {
+        return;
+    }
+
+    let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), MEM_SIZE)]).unwrap();
+    mem.write_slice(bytes, GuestAddress(0)).unwrap();
+
+    let mut queue = Queue::new(QUEUE_SIZE);
+    queue.desc_table = GuestAddress(DESC_TABLE);
+    queue.avail_ring = GuestAddress(AVAIL_RING);
+    queue.used_ring = GuestAddress(USED_RING);
+    queue.enable(true);
+    if !queue.is_valid(&mem) {
+        return;
+    }
+
+    let mut disk = Cursor::new(vec![0u8; DISK_SIZE as usize]);
+    let mut used = Vec::new();
+    for head in queue.iter(&mem) {
+        let index = head.index;
+        let len = match Request::parse(head) {
+            Ok(mut request) => {
+                let status = match request.execute(&mut disk, DISK_SIZE / SECTOR_SIZE, &[]) {
+                    Ok(_) => 0,
+                    Err(_) => 1,
+                };
+                request.complete(status).unwrap_or(0)
+            }
+            Err(_) => 0,
+        };
+        used.push((index, len));
+    }
+
+    for (index, len) in used {
+        queue.add_used(&mem, index, len);
+    }
+});
@@ -0,0 +1,61 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use std::convert::TryInto;
+use vm_memory::{Bytes, GuestAddress, GuestMemoryMmap};
+use vm_virtio::{Queue, Reader, Writer};
+
+const MEM_SIZE: usize = 0x10000;
+const QUEUE_MAX_SIZE: u16 = 256;
+// Queue size, next_avail, and the descriptor table, avail and used ring
+// addresses.
+const HEADER_SIZE: usize = 10;
+
+fuzz_target!(|bytes: &[u8]| {
+    if bytes.len() < HEADER_SIZE || bytes.len() - HEADER_SIZE > MEM_SIZE {
+        return;
+    }
+
+    let u16_at = |offset: usize| u16::from_le_bytes(bytes[offset..offset + 2].try_into().unwrap());
+
+    let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), MEM_SIZE)]).unwrap();
+    mem.write_slice(&bytes[HEADER_SIZE..], GuestAddress(0))
+        .unwrap();
+
+    let mut queue = Queue::new(QUEUE_MAX_SIZE);
+    queue.size = u16_at(0);
+    queue.next_avail.0 = u16_at(2);
+    queue.desc_table = GuestAddress(u64::from(u16_at(4)));
+    queue.avail_ring = GuestAddress(u64::from(u16_at(6)));
+    queue.used_ring = GuestAddress(u64::from(u16_at(8)));
+    queue.enable(true);
+    if !queue.is_valid(&mem) {
+        return;
+    }
+
+    let mut used = Vec::new();
+    for head in queue.iter(&mem) {
+        let index = head.index;
+        let mut len = 0;
+
+        for desc in head.clone() {
+            len += desc.len as usize;
+        }
+
+        if let (Ok(mut reader), Ok(mut writer)) = (Reader::new(head.clone()), Writer::new(head)) {
+            let mut buf = vec![0u8; reader.available_bytes()];
+            let _ = std::io::Read::read(&mut reader, &mut buf);
+            let _ = std::io::Write::write(&mut writer, &buf);
+        }
+
+        used.push((index, len as u32));
+    }
+
+    for (index, len) in used {
+        queue.add_used(&mem, index, len);
+    }
+});
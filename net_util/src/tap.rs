@@ -220,6 +220,42 @@ impl Tap {
         Ok(())
     }
 
+    /// Turn promiscuous mode on or off for the tap interface.
+    pub fn set_promisc(&self, on: bool) -> Result<()> {
+        let sock = create_socket().map_err(Error::NetUtil)?;
+
+        let mut ifreq = self.get_ifreq();
+
+        // ioctl is safe. Called with a valid sock fd, and we check the return.
+        #[allow(clippy::cast_lossless)]
+        let ret = unsafe {
+            ioctl_with_mut_ref(&sock, net_gen::sockios::SIOCGIFFLAGS as c_ulong, &mut ifreq)
+        };
+        if ret < 0 {
+            return Err(Error::IoctlError(IoError::last_os_error()));
+        }
+
+        // We only access one field of the ifru union, hence this is safe.
+        unsafe {
+            let ifru_flags = ifreq.ifr_ifru.ifru_flags.as_mut();
+            if on {
+                *ifru_flags |= net_gen::net_device_flags_IFF_PROMISC as i16;
+            } else {
+                *ifru_flags &= !(net_gen::net_device_flags_IFF_PROMISC as i16);
+            }
+        }
+
+        // ioctl is safe. Called with a valid sock fd, and we check the return.
+        #[allow(clippy::cast_lossless)]
+        let ret =
+            unsafe { ioctl_with_ref(&sock, net_gen::sockios::SIOCSIFFLAGS as c_ulong, &ifreq) };
+        if ret < 0 {
+            return Err(Error::IoctlError(IoError::last_os_error()));
+        }
+
+        Ok(())
+    }
+
     /// Set the size of the vnet hdr.
     pub fn set_vnet_hdr_size(&self, size: c_int) -> Result<()> {
         // ioctl is safe. Called with a valid tap fd, and we check the return.
@@ -471,6 +507,13 @@ mod tests {
         assert!(ret.is_ok());
     }
 
+    #[test]
+    fn test_tap_set_promisc() {
+        let tap = Tap::new(1).unwrap();
+        assert!(tap.set_promisc(true).is_ok());
+        assert!(tap.set_promisc(false).is_ok());
+    }
+
     #[test]
     fn test_tap_get_ifreq() {
         let tap = Tap::new(1).unwrap();
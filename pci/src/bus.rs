@@ -10,12 +10,14 @@ use byteorder::{ByteOrder, LittleEndian};
 use devices::BusDevice;
 use std;
 use std::any::Any;
+use std::collections::{HashMap, HashSet};
 use std::ops::DerefMut;
 use std::sync::{Arc, Mutex, Weak};
 use vm_memory::{Address, GuestAddress, GuestUsize};
 
 const VENDOR_ID_INTEL: u16 = 0x8086;
 const DEVICE_ID_INTEL_VIRT_PCIE_HOST: u16 = 0x0d57;
+const NUM_DEVICE_IDS: u32 = 32;
 
 /// Errors for device manager.
 #[derive(Debug)]
@@ -28,6 +30,18 @@ pub enum PciRootError {
     PioInsert(devices::BusError),
     /// Could not add a device to the mmio bus.
     MmioInsert(devices::BusError),
+    /// Could not remove a device from the port io bus.
+    PioRemove(devices::BusError),
+    /// Could not remove a device from the mmio bus.
+    MmioRemove(devices::BusError),
+    /// All the device ids of the bus are in use.
+    NoPciDeviceSlotAvailable,
+    /// No device is attached to the bus with this device id.
+    InvalidPciDeviceSlot(u32),
+    /// Another device is attached to the bus with this device id.
+    PciDeviceSlotInUse(u32),
+    /// The device id is either the host bridge one or beyond the last one.
+    PciDeviceSlotOutOfRange(u32),
 }
 pub type Result<T> = std::result::Result<T, PciRootError>;
 
@@ -77,20 +91,23 @@ impl PciDevice for PciRoot {
 }
 
 pub struct PciBus {
-    /// Devices attached to this bus.
+    /// Devices attached to this bus, indexed by device id.
     /// Device 0 is host bridge.
-    devices: Vec<Arc<Mutex<dyn PciDevice>>>,
+    devices: HashMap<u32, Arc<Mutex<dyn PciDevice>>>,
+    /// Device ids only given to the devices asking for them.
+    reserved_device_ids: HashSet<u32>,
     device_reloc: Weak<dyn DeviceRelocation>,
 }
 
 impl PciBus {
     pub fn new(pci_root: PciRoot, device_reloc: Weak<dyn DeviceRelocation>) -> Self {
-        let mut devices: Vec<Arc<Mutex<dyn PciDevice>>> = Vec::new();
+        let mut devices: HashMap<u32, Arc<Mutex<dyn PciDevice>>> = HashMap::new();
 
-        devices.push(Arc::new(Mutex::new(pci_root)));
+        devices.insert(0, Arc::new(Mutex::new(pci_root)));
 
         PciBus {
             devices,
+            reserved_device_ids: HashSet::new(),
             device_reloc,
         }
     }
@@ -119,13 +136,81 @@ impl PciBus {
         Ok(())
     }
 
+    pub fn unregister_mapping(
+        &self,
+        io_bus: &devices::Bus,
+        mmio_bus: &devices::Bus,
+        bars: Vec<(GuestAddress, GuestUsize, PciBarRegionType)>,
+    ) -> Result<()> {
+        for (address, size, type_) in bars {
+            match type_ {
+                PciBarRegionType::IORegion => {
+                    io_bus
+                        .remove(address.raw_value(), size)
+                        .map_err(PciRootError::PioRemove)?;
+                }
+                PciBarRegionType::Memory32BitRegion | PciBarRegionType::Memory64BitRegion => {
+                    mmio_bus
+                        .remove(address.raw_value(), size)
+                        .map_err(PciRootError::MmioRemove)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Attaches the device using the id returned by `next_device_id()`.
     pub fn add_device(&mut self, device: Arc<Mutex<dyn PciDevice>>) -> Result<()> {
-        self.devices.push(device);
+        let device_id = self.next_device_id()?;
+        self.devices.insert(device_id, device);
+        Ok(())
+    }
+
+    /// Attaches the device using `device_id`, which must be free.
+    pub fn add_device_with_id(
+        &mut self,
+        device_id: u32,
+        device: Arc<Mutex<dyn PciDevice>>,
+    ) -> Result<()> {
+        self.check_device_id(device_id)?;
+        self.devices.insert(device_id, device);
+        Ok(())
+    }
+
+    /// Detaches the device, freeing its device id for the next device added.
+    pub fn remove_device(&mut self, device_id: u32) -> Result<Arc<Mutex<dyn PciDevice>>> {
+        self.devices
+            .remove(&device_id)
+            .ok_or(PciRootError::InvalidPciDeviceSlot(device_id))
+    }
+
+    /// Returns the lowest device id neither in use nor reserved.
+    pub fn next_device_id(&self) -> Result<u32> {
+        (0..NUM_DEVICE_IDS)
+            .find(|id| !self.devices.contains_key(id) && !self.reserved_device_ids.contains(id))
+            .ok_or(PciRootError::NoPciDeviceSlotAvailable)
+    }
+
+    /// Keeps `device_id` out of the ids returned by `next_device_id()`, for
+    /// a device asking for it to be attached later on.
+    pub fn reserve_device_id(&mut self, device_id: u32) -> Result<()> {
+        if device_id == 0 || device_id >= NUM_DEVICE_IDS {
+            return Err(PciRootError::PciDeviceSlotOutOfRange(device_id));
+        }
+        self.reserved_device_ids.insert(device_id);
         Ok(())
     }
 
-    pub fn next_device_id(&self) -> u32 {
-        self.devices.len() as u32
+    /// Checks a device can be attached using `device_id`, be it reserved or
+    /// not.
+    pub fn check_device_id(&self, device_id: u32) -> Result<()> {
+        if device_id == 0 || device_id >= NUM_DEVICE_IDS {
+            return Err(PciRootError::PciDeviceSlotOutOfRange(device_id));
+        }
+        if self.devices.contains_key(&device_id) {
+            return Err(PciRootError::PciDeviceSlotInUse(device_id));
+        }
+        Ok(())
     }
 }
 
@@ -150,7 +235,7 @@ impl PciConfigIo {
         }
 
         let (bus, device, function, register) =
-            parse_config_address(self.config_address & !0x8000_0000);
+            parse_io_config_address(self.config_address & !0x8000_0000);
 
         // Only support one bus.
         if bus != 0 {
@@ -166,7 +251,7 @@ impl PciConfigIo {
             .lock()
             .unwrap()
             .devices
-            .get(device)
+            .get(&(device as u32))
             .map_or(0xffff_ffff, |d| {
                 d.lock().unwrap().read_config_register(register)
             })
@@ -183,7 +268,7 @@ impl PciConfigIo {
         }
 
         let (bus, device, _function, register) =
-            parse_config_address(self.config_address & !0x8000_0000);
+            parse_io_config_address(self.config_address & !0x8000_0000);
 
         // Only support one bus.
         if bus != 0 {
@@ -191,7 +276,7 @@ impl PciConfigIo {
         }
 
         let pci_bus = self.pci_bus.lock().unwrap();
-        if let Some(d) = pci_bus.devices.get(device) {
+        if let Some(d) = pci_bus.devices.get(&(device as u32)) {
             let mut device = d.lock().unwrap();
 
             // Find out if one of the device's BAR is being reprogrammed, and
@@ -277,18 +362,23 @@ impl PciConfigMmio {
     }
 
     fn config_space_read(&self, config_address: u32) -> u32 {
-        let (bus, device, _function, register) = parse_config_address(config_address);
+        let (bus, device, function, register) = parse_mmio_config_address(config_address);
 
         // Only support one bus.
         if bus != 0 {
             return 0xffff_ffff;
         }
 
+        // Don't support multi-function devices.
+        if function > 0 {
+            return 0xffff_ffff;
+        }
+
         self.pci_bus
             .lock()
             .unwrap()
             .devices
-            .get(device)
+            .get(&(device as u32))
             .map_or(0xffff_ffff, |d| {
                 d.lock().unwrap().read_config_register(register)
             })
@@ -299,15 +389,20 @@ impl PciConfigMmio {
             return;
         }
 
-        let (bus, device, _function, register) = parse_config_address(config_address);
+        let (bus, device, function, register) = parse_mmio_config_address(config_address);
 
         // Only support one bus.
         if bus != 0 {
             return;
         }
 
+        // Don't support multi-function devices.
+        if function > 0 {
+            return;
+        }
+
         let pci_bus = self.pci_bus.lock().unwrap();
-        if let Some(d) = pci_bus.devices.get(device) {
+        if let Some(d) = pci_bus.devices.get(&(device as u32)) {
             let mut device = d.lock().unwrap();
 
             // Find out if one of the device's BAR is being reprogrammed, and
@@ -356,8 +451,30 @@ impl BusDevice for PciConfigMmio {
     }
 }
 
+// Parse the MMCONFIG address offset to a (bus, device, function, register) tuple.
+// Each function gets 4KiB of configuration space.
+fn parse_mmio_config_address(config_address: u32) -> (usize, usize, usize, usize) {
+    const BUS_NUMBER_OFFSET: usize = 20;
+    const BUS_NUMBER_MASK: u32 = 0x00ff;
+    const DEVICE_NUMBER_OFFSET: usize = 15;
+    const DEVICE_NUMBER_MASK: u32 = 0x1f;
+    const FUNCTION_NUMBER_OFFSET: usize = 12;
+    const FUNCTION_NUMBER_MASK: u32 = 0x07;
+    const REGISTER_NUMBER_OFFSET: usize = 2;
+    const REGISTER_NUMBER_MASK: u32 = 0x3ff;
+
+    let bus_number = ((config_address >> BUS_NUMBER_OFFSET) & BUS_NUMBER_MASK) as usize;
+    let device_number = ((config_address >> DEVICE_NUMBER_OFFSET) & DEVICE_NUMBER_MASK) as usize;
+    let function_number =
+        ((config_address >> FUNCTION_NUMBER_OFFSET) & FUNCTION_NUMBER_MASK) as usize;
+    let register_number =
+        ((config_address >> REGISTER_NUMBER_OFFSET) & REGISTER_NUMBER_MASK) as usize;
+
+    (bus_number, device_number, function_number, register_number)
+}
+
 // Parse the CONFIG_ADDRESS register to a (bus, device, function, register) tuple.
-fn parse_config_address(config_address: u32) -> (usize, usize, usize, usize) {
+fn parse_io_config_address(config_address: u32) -> (usize, usize, usize, usize) {
     const BUS_NUMBER_OFFSET: usize = 16;
     const BUS_NUMBER_MASK: u32 = 0x00ff;
     const DEVICE_NUMBER_OFFSET: usize = 11;
@@ -376,3 +493,209 @@ fn parse_config_address(config_address: u32) -> (usize, usize, usize, usize) {
 
     (bus_number, device_number, function_number, register_number)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::configuration::{PciBarConfiguration, PciBarPrefetchable};
+    use crate::device::BarReprogrammingParams;
+
+    #[test]
+    fn test_parse_io_config_address() {
+        // Bus 1, device 3, function 2, register 0x3c.
+        assert_eq!(parse_io_config_address(0x0001_1af0), (1, 3, 2, 0x3c));
+        // Only the first 256 bytes are reachable.
+        assert_eq!(parse_io_config_address(0x0000_00fc), (0, 0, 0, 0x3f));
+    }
+
+    #[test]
+    fn test_parse_mmio_config_address() {
+        // Bus 1, device 3, function 2, register 0x3c.
+        assert_eq!(parse_mmio_config_address(0x0011_a0f0), (1, 3, 2, 0x3c));
+        // The extended configuration space is reachable.
+        assert_eq!(parse_mmio_config_address(0x0000_0ffc), (0, 0, 0, 0x3ff));
+        assert_eq!(parse_mmio_config_address(0x0000_8100), (0, 1, 0, 0x40));
+    }
+
+    struct NoRelocation;
+
+    impl DeviceRelocation for NoRelocation {
+        fn move_bar(
+            &self,
+            _old_base: u64,
+            _new_base: u64,
+            _len: u64,
+            _pci_dev: &mut dyn PciDevice,
+            _region_type: PciBarRegionType,
+        ) -> std::result::Result<(), std::io::Error> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_device_id_reuse() {
+        let mut bus = PciBus::new(
+            PciRoot::new(None),
+            Weak::<NoRelocation>::new() as Weak<dyn DeviceRelocation>,
+        );
+        for _ in 1..NUM_DEVICE_IDS {
+            bus.add_device(Arc::new(Mutex::new(PciRoot::new(None))))
+                .unwrap();
+        }
+        assert!(bus.next_device_id().is_err());
+
+        bus.remove_device(3).unwrap();
+        assert!(bus.remove_device(3).is_err());
+        assert_eq!(bus.next_device_id().unwrap(), 3);
+
+        bus.add_device(Arc::new(Mutex::new(PciRoot::new(None))))
+            .unwrap();
+        assert!(bus.next_device_id().is_err());
+    }
+
+    #[test]
+    fn test_requested_device_id() {
+        let mut bus = PciBus::new(
+            PciRoot::new(None),
+            Weak::<NoRelocation>::new() as Weak<dyn DeviceRelocation>,
+        );
+        bus.reserve_device_id(1).unwrap();
+        bus.reserve_device_id(3).unwrap();
+        assert!(bus.reserve_device_id(0).is_err());
+        assert!(bus.reserve_device_id(NUM_DEVICE_IDS).is_err());
+
+        // The reserved ids are skipped, unless asked for.
+        assert_eq!(bus.next_device_id().unwrap(), 2);
+        bus.add_device(Arc::new(Mutex::new(PciRoot::new(None))))
+            .unwrap();
+        assert_eq!(bus.next_device_id().unwrap(), 4);
+        bus.add_device_with_id(3, Arc::new(Mutex::new(PciRoot::new(None))))
+            .unwrap();
+
+        match bus.add_device_with_id(2, Arc::new(Mutex::new(PciRoot::new(None)))) {
+            Err(PciRootError::PciDeviceSlotInUse(2)) => {}
+            _ => panic!("device id 2 is in use"),
+        }
+        match bus.add_device_with_id(0, Arc::new(Mutex::new(PciRoot::new(None)))) {
+            Err(PciRootError::PciDeviceSlotOutOfRange(0)) => {}
+            _ => panic!("device id 0 is the host bridge one"),
+        }
+        assert!(bus.check_device_id(NUM_DEVICE_IDS).is_err());
+
+        bus.remove_device(3).unwrap();
+        assert!(bus.check_device_id(3).is_ok());
+        assert_eq!(bus.next_device_id().unwrap(), 4);
+    }
+
+    struct BarDevice {
+        config: PciConfiguration,
+    }
+
+    impl BusDevice for BarDevice {
+        fn read(&mut self, _base: u64, offset: u64, data: &mut [u8]) {
+            data[0] = offset as u8;
+        }
+    }
+
+    impl PciDevice for BarDevice {
+        fn write_config_register(&mut self, reg_idx: usize, offset: u64, data: &[u8]) {
+            self.config.write_config_register(reg_idx, offset, data);
+        }
+
+        fn read_config_register(&mut self, reg_idx: usize) -> u32 {
+            self.config.read_reg(reg_idx)
+        }
+
+        fn detect_bar_reprogramming(
+            &mut self,
+            reg_idx: usize,
+            data: &[u8],
+        ) -> Option<BarReprogrammingParams> {
+            self.config.detect_bar_reprogramming(reg_idx, data)
+        }
+
+        fn as_any(&mut self) -> &mut dyn Any {
+            self
+        }
+    }
+
+    struct BusRelocation {
+        mmio_bus: Arc<devices::Bus>,
+    }
+
+    impl DeviceRelocation for BusRelocation {
+        fn move_bar(
+            &self,
+            old_base: u64,
+            new_base: u64,
+            len: u64,
+            pci_dev: &mut dyn PciDevice,
+            _region_type: PciBarRegionType,
+        ) -> std::result::Result<(), std::io::Error> {
+            self.mmio_bus
+                .update_range(old_base, len, new_base, len)
+                .map_err(std::io::Error::from)?;
+            pci_dev.move_bar(old_base, new_base)
+        }
+    }
+
+    #[test]
+    fn test_bar_reprogramming() {
+        let io_bus = devices::Bus::new();
+        let mmio_bus = Arc::new(devices::Bus::new());
+        let reloc: Arc<dyn DeviceRelocation> = Arc::new(BusRelocation {
+            mmio_bus: mmio_bus.clone(),
+        });
+        let pci_bus = Arc::new(Mutex::new(PciBus::new(
+            PciRoot::new(None),
+            Arc::downgrade(&reloc),
+        )));
+
+        let mut config = PciRoot::new(None).config;
+        config
+            .add_pci_bar(
+                &PciBarConfiguration::new(
+                    0,
+                    0x1000,
+                    PciBarRegionType::Memory32BitRegion,
+                    PciBarPrefetchable::NotPrefetchable,
+                )
+                .set_address(0xc000_0000),
+            )
+            .unwrap();
+        let device = Arc::new(Mutex::new(BarDevice { config }));
+        pci_bus.lock().unwrap().add_device(device.clone()).unwrap();
+        pci_bus
+            .lock()
+            .unwrap()
+            .register_mapping(
+                device,
+                &io_bus,
+                &mmio_bus,
+                vec![(
+                    GuestAddress(0xc000_0000),
+                    0x1000,
+                    PciBarRegionType::Memory32BitRegion,
+                )],
+            )
+            .unwrap();
+
+        // Device 1, BAR0, then the new address.
+        let mut config_io = PciConfigIo::new(pci_bus);
+        config_io.write(0, 0, &0x8000_0810u32.to_le_bytes());
+        config_io.write(0, 4, &0xd000_0000u32.to_le_bytes());
+
+        let mut data = [0xffu8];
+        assert!(!mmio_bus.read(0xc000_0010, &mut data));
+        assert!(mmio_bus.read(0xd000_0010, &mut data));
+        assert_eq!(data, [0x10]);
+
+        let mut bar = [0u8; 4];
+        config_io.read(0, 4, &mut bar);
+        assert_eq!(u32::from_le_bytes(bar) & !0xf, 0xd000_0000);
+
+        // Sizing the BAR doesn't move it.
+        config_io.write(0, 4, &0xffff_ffffu32.to_le_bytes());
+        assert!(mmio_bus.read(0xd000_0010, &mut data));
+    }
+}
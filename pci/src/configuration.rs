@@ -9,8 +9,9 @@ use crate::{MsixConfig, PciInterruptPin};
 use byteorder::{ByteOrder, LittleEndian};
 use std::fmt::{self, Display};
 
-// The number of 32bit registers in the config space, 256 bytes.
-const NUM_CONFIGURATION_REGISTERS: usize = 64;
+// The 4KiB PCI Express configuration space. Registers after the first 64
+// are only reachable through the memory-mapped configuration mechanism.
+const NUM_CONFIGURATION_REGISTERS: usize = 1024;
 
 const STATUS_REG: usize = 1;
 const STATUS_REG_CAPABILITIES_USED_MASK: u32 = 0x0010_0000;
@@ -171,6 +172,26 @@ impl PciSubclass for PciNetworkControllerSubclass {
     }
 }
 
+/// Subclasses of the BaseSystemPeripheral class.
+#[allow(dead_code)]
+#[derive(Copy, Clone)]
+pub enum PciBaseSystemPeripheralSubclass {
+    InterruptController = 0x00,
+    DmaController = 0x01,
+    Timer = 0x02,
+    RtcController = 0x03,
+    PciHotplugController = 0x04,
+    SdHostController = 0x05,
+    Iommu = 0x06,
+    Other = 0x80,
+}
+
+impl PciSubclass for PciBaseSystemPeripheralSubclass {
+    fn get_register_value(&self) -> u8 {
+        *self as u8
+    }
+}
+
 /// A PCI class programming interface. Each combination of `PciClassCode` and
 /// `PciSubclass` can specify a set of register-level programming interfaces.
 /// This trait is implemented by each programming interface.
@@ -244,6 +265,14 @@ pub trait PciCapability {
     fn id(&self) -> PciCapabilityID;
 }
 
+/// The configuration space of a PCI node, as set by the guest.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct PciConfigurationState {
+    pub registers: Vec<u32>,
+    pub bar_addr: Vec<u32>,
+    pub rom_bar_addr: u32,
+}
+
 /// Contains the configuration space of a PCI node.
 /// See the [specification](https://en.wikipedia.org/wiki/PCI_configuration_space).
 /// The configuration space is accessed with DWORD reads and writes from the guest.
@@ -301,6 +330,7 @@ pub enum Error {
     RomBarInUse(usize),
     RomBarInvalid(usize),
     RomBarSizeInvalid(u64),
+    StateInvalid,
 }
 pub type Result<T> = std::result::Result<T, Error>;
 
@@ -328,6 +358,7 @@ impl Display for Error {
             RomBarInUse(b) => write!(f, "rom bar {} already used", b),
             RomBarInvalid(b) => write!(f, "rom bar {} invalid, max {}", b, NUM_BAR_REGS - 1),
             RomBarSizeInvalid(s) => write!(f, "rom bar address {} not a power of two", s),
+            StateInvalid => write!(f, "invalid configuration space state"),
         }
     }
 }
@@ -390,6 +421,68 @@ impl PciConfiguration {
         }
     }
 
+    pub fn state(&self) -> PciConfigurationState {
+        PciConfigurationState {
+            registers: self.registers.to_vec(),
+            bar_addr: self.bar_addr.to_vec(),
+            rom_bar_addr: self.rom_bar_addr,
+        }
+    }
+
+    /// Restores a state returned by `state()`, returning the BARs the guest
+    /// moved before it was saved. The device is still mapped at their former
+    /// address, so the caller has to relocate them.
+    pub fn set_state(
+        &mut self,
+        state: &PciConfigurationState,
+    ) -> Result<Vec<BarReprogrammingParams>> {
+        if state.registers.len() != NUM_CONFIGURATION_REGISTERS
+            || state.bar_addr.len() != NUM_BAR_REGS
+        {
+            return Err(Error::StateInvalid);
+        }
+
+        let mut moved_bars = Vec::new();
+        for bar_num in 0..NUM_BAR_REGS {
+            let region_type = match self.bar_type[bar_num] {
+                Some(region_type) => region_type,
+                None => continue,
+            };
+            let old_base = self.get_bar_addr(bar_num);
+            let mut len = u64::from(self.bar_size[bar_num]);
+            self.bar_addr[bar_num] = state.bar_addr[bar_num];
+            if region_type == PciBarRegionType::Memory64BitRegion {
+                len |= u64::from(self.bar_size[bar_num + 1]) << 32;
+                self.bar_addr[bar_num + 1] = state.bar_addr[bar_num + 1];
+            }
+            let new_base = self.get_bar_addr(bar_num);
+            if new_base != old_base {
+                moved_bars.push(BarReprogrammingParams {
+                    old_base,
+                    new_base,
+                    len,
+                    region_type,
+                });
+            }
+        }
+        if self.rom_bar_used {
+            let old_base = u64::from(self.rom_bar_addr & ROM_BAR_ADDR_MASK);
+            let new_base = u64::from(state.rom_bar_addr & ROM_BAR_ADDR_MASK);
+            self.rom_bar_addr = state.rom_bar_addr;
+            if new_base != old_base {
+                moved_bars.push(BarReprogrammingParams {
+                    old_base,
+                    new_base,
+                    len: u64::from(self.rom_bar_size),
+                    region_type: PciBarRegionType::Memory32BitRegion,
+                });
+            }
+        }
+
+        self.registers.copy_from_slice(&state.registers);
+        Ok(moved_bars)
+    }
+
     /// Reads a 32bit register from `reg_idx` in the register map.
     pub fn read_reg(&self, reg_idx: usize) -> u32 {
         *(self.registers.get(reg_idx).unwrap_or(&0xffff_ffff))
@@ -397,7 +490,13 @@ impl PciConfiguration {
 
     /// Writes a 32bit register to `reg_idx` in the register map.
     pub fn write_reg(&mut self, reg_idx: usize, value: u32) {
-        let mut mask = self.writable_bits[reg_idx];
+        let mut mask = match self.writable_bits.get(reg_idx) {
+            Some(mask) => *mask,
+            None => {
+                warn!("bad PCI register write {}", reg_idx);
+                return;
+            }
+        };
 
         if reg_idx >= BAR0_REG && reg_idx < BAR0_REG + NUM_BAR_REGS {
             // Handle very specific case where the BAR is being written with
@@ -414,11 +513,8 @@ impl PciConfiguration {
             }
         }
 
-        if let Some(r) = self.registers.get_mut(reg_idx) {
-            *r = (*r & !self.writable_bits[reg_idx]) | (value & mask);
-        } else {
-            warn!("bad PCI register write {}", reg_idx);
-        }
+        let r = &mut self.registers[reg_idx];
+        *r = (*r & !self.writable_bits[reg_idx]) | (value & mask);
     }
 
     /// Writes a 16bit word to `offset`. `offset` must be 16bit aligned.
@@ -674,7 +770,7 @@ impl PciConfiguration {
 
         let value = LittleEndian::read_u32(data);
 
-        let mask = self.writable_bits[reg_idx];
+        let mask = *self.writable_bits.get(reg_idx)?;
         if reg_idx >= BAR0_REG && reg_idx < BAR0_REG + NUM_BAR_REGS {
             let bar_idx = reg_idx - 4;
             if (value & mask) != (self.bar_addr[bar_idx] & mask) {
@@ -820,6 +916,11 @@ impl PciBarConfiguration {
         self
     }
 
+    pub fn set_prefetchable(mut self, prefetchable: PciBarPrefetchable) -> Self {
+        self.prefetchable = prefetchable;
+        self
+    }
+
     pub fn get_size(&self) -> u64 {
         self.size
     }
@@ -934,4 +1035,50 @@ mod tests {
         assert_eq!(subclass, 0x01);
         assert_eq!(prog_if, 0x5a);
     }
+
+    #[test]
+    fn restore_moved_bar() {
+        let create_config = || {
+            let mut cfg = PciConfiguration::new(
+                0x1234,
+                0x5678,
+                PciClassCode::MultimediaController,
+                &PciMultimediaSubclass::AudioController,
+                None,
+                PciHeaderType::Device,
+                0xABCD,
+                0x2468,
+                None,
+            );
+            cfg.add_pci_bar(
+                &PciBarConfiguration::new(
+                    0,
+                    0x1000,
+                    PciBarRegionType::Memory32BitRegion,
+                    PciBarPrefetchable::NotPrefetchable,
+                )
+                .set_address(0xe000_0000),
+            )
+            .unwrap();
+            cfg
+        };
+
+        let mut cfg = create_config();
+        let value = 0xd000_0000u32.to_le_bytes();
+        assert!(cfg.detect_bar_reprogramming(BAR0_REG, &value).is_some());
+        cfg.write_config_register(BAR0_REG, 0, &value);
+        let state = cfg.state();
+
+        // The restored configuration reports the BAR to move back where the
+        // guest put it.
+        let mut restored = create_config();
+        let moved_bars = restored.set_state(&state).unwrap();
+        assert_eq!(moved_bars.len(), 1);
+        assert_eq!(moved_bars[0].old_base, 0xe000_0000);
+        assert_eq!(moved_bars[0].new_base, 0xd000_0000);
+        assert_eq!(moved_bars[0].len, 0x1000);
+        assert_eq!(restored.get_bar_addr(0), 0xd000_0000);
+
+        assert!(restored.set_state(&state).unwrap().is_empty());
+    }
 }
@@ -0,0 +1,598 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Emulates the watchdog timer of the Intel 6300ESB I/O controller hub, as
+//! driven by the Linux `i6300esb` driver.
+//!
+//! Once enabled through the lock register of the configuration space, the
+//! watchdog counts down its first stage, then its second one, and expires at
+//! the end of the second stage unless the guest reloads it in the meantime.
+//! Its registers, mapped by BAR 0, can only be written after writing the
+//! unlock sequence to the reload register.
+//!
+//! The first stage doesn't raise any interrupt, the guest driver disabling
+//! them anyway. The countdown runs on a timerfd, waited on by a dedicated
+//! thread, and stops while the VM is paused.
+
+use crate::configuration::{
+    PciBarConfiguration, PciBarRegionType, PciBaseSystemPeripheralSubclass, PciClassCode,
+    PciConfiguration, PciHeaderType,
+};
+use crate::device::{BarReprogrammingParams, Error as PciDeviceError, PciDevice, Result};
+use byteorder::{ByteOrder, LittleEndian};
+use devices::BusDevice;
+use std::any::Any;
+use std::fs::File;
+use std::io;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use vm_allocator::SystemAllocator;
+use vm_device::{Migratable, MigratableError, Pausable, Snapshotable};
+use vm_memory::{GuestAddress, GuestUsize};
+use vmm_sys_util::eventfd::EventFd;
+use vmm_sys_util::timerfd::TimerFd;
+
+const VENDOR_ID_INTEL: u16 = 0x8086;
+const DEVICE_ID_INTEL_ESB_WDT: u16 = 0x25ab;
+
+// Watchdog registers of the configuration space.
+const ESB_CONFIG_REG: usize = 0x60;
+const ESB_LOCK_REG: usize = 0x68;
+
+// Bits of the config register.
+const ESB_WDT_FREQ: u16 = 1 << 2;
+const ESB_WDT_REBOOT: u16 = 1 << 5;
+
+// Bits of the lock register.
+const ESB_WDT_LOCK: u8 = 1 << 0;
+const ESB_WDT_ENABLE: u8 = 1 << 1;
+const ESB_WDT_FUNC: u8 = 1 << 2;
+
+// Registers of BAR 0.
+const ESB_BAR_SIZE: u64 = 0x10;
+const ESB_TIMER1_REG: u64 = 0x00;
+const ESB_TIMER2_REG: u64 = 0x04;
+const ESB_RELOAD_REG: u64 = 0x0c;
+
+// Bits of the reload register.
+const ESB_WDT_RELOAD: u32 = 1 << 8;
+const ESB_WDT_TIMEOUT: u32 = 1 << 9;
+
+// Values written to the reload register to unlock the next register write.
+const ESB_UNLOCK1: u32 = 0x80;
+const ESB_UNLOCK2: u32 = 0x86;
+
+// The preload registers hold 20 bits.
+const ESB_PRELOAD_MASK: u32 = 0xf_ffff;
+
+// The preload values are in ticks of the 33MHz clock, shifted by 15 bits
+// for the 1KHz scale, or by 5 bits for the 1MHz one.
+const ESB_CLOCK_MHZ: u64 = 33;
+const ESB_1KHZ_SHIFT: u32 = 15;
+const ESB_1MHZ_SHIFT: u32 = 5;
+
+// The preload value giving `timeout` to each stage, at the 1KHz scale.
+fn preload_for(timeout: Duration) -> u32 {
+    let ticks = (timeout.as_nanos() as u64).saturating_mul(ESB_CLOCK_MHZ) / 1000;
+    ((ticks >> ESB_1KHZ_SHIFT) as u32)
+        .max(1)
+        .min(ESB_PRELOAD_MASK)
+}
+
+struct WatchdogState {
+    timer: TimerFd,
+    reboot: bool,
+    clock_1mhz: bool,
+    locked: bool,
+    enabled: bool,
+    free_run: bool,
+    timer1_preload: u32,
+    timer2_preload: u32,
+    stage: u8,
+    unlock_state: u8,
+    timed_out: bool,
+    paused: bool,
+}
+
+impl WatchdogState {
+    fn config_reg(&self) -> u16 {
+        let mut value = 0;
+        if !self.reboot {
+            value |= ESB_WDT_REBOOT;
+        }
+        if self.clock_1mhz {
+            value |= ESB_WDT_FREQ;
+        }
+        value
+    }
+
+    fn lock_reg(&self) -> u8 {
+        let mut value = 0;
+        if self.locked {
+            value |= ESB_WDT_LOCK;
+        }
+        if self.enabled {
+            value |= ESB_WDT_ENABLE;
+        }
+        if self.free_run {
+            value |= ESB_WDT_FUNC;
+        }
+        value
+    }
+
+    fn write_lock_reg(&mut self, value: u8) {
+        // Once locked, the watchdog can't be disabled until the next reset.
+        if self.locked {
+            return;
+        }
+        self.locked = value & ESB_WDT_LOCK != 0;
+        self.free_run = value & ESB_WDT_FUNC != 0;
+        let was_enabled = self.enabled;
+        self.enabled = value & ESB_WDT_ENABLE != 0;
+        if self.enabled && !was_enabled {
+            self.restart_timer(1);
+        } else if !self.enabled {
+            self.stop_timer();
+        }
+    }
+
+    fn stage_duration(&self, stage: u8) -> Duration {
+        let preload = if stage == 1 {
+            self.timer1_preload
+        } else {
+            self.timer2_preload
+        };
+        let shift = if self.clock_1mhz {
+            ESB_1MHZ_SHIFT
+        } else {
+            ESB_1KHZ_SHIFT
+        };
+        let nanos = (u64::from(preload) << shift) * 1000 / ESB_CLOCK_MHZ;
+        // A null duration would disarm the timer.
+        Duration::from_nanos(nanos.max(1))
+    }
+
+    fn restart_timer(&mut self, stage: u8) {
+        self.stage = stage;
+        if !self.enabled || self.paused {
+            return;
+        }
+        let duration = self.stage_duration(stage);
+        if let Err(e) = self.timer.reset(duration, None) {
+            error!("Failed arming the watchdog timer: {}", e);
+        }
+    }
+
+    fn stop_timer(&mut self) {
+        if let Err(e) = self.timer.clear() {
+            error!("Failed disarming the watchdog timer: {}", e);
+        }
+    }
+
+    // Moves on to the next stage once the current one is over. Returns true
+    // when the watchdog expires.
+    fn stage_over(&mut self) -> bool {
+        if self.stage == 1 {
+            self.restart_timer(2);
+            return false;
+        }
+
+        let expired = self.reboot;
+        if expired {
+            self.timed_out = true;
+        }
+        if self.free_run {
+            self.restart_timer(1);
+        }
+        expired
+    }
+
+    fn write_bar(&mut self, offset: u64, value: u32) {
+        if offset == ESB_RELOAD_REG && value == ESB_UNLOCK1 {
+            self.unlock_state = 1;
+            return;
+        }
+        if offset == ESB_RELOAD_REG && value == ESB_UNLOCK2 && self.unlock_state == 1 {
+            self.unlock_state = 2;
+            return;
+        }
+        // Without the unlock sequence, the write is ignored.
+        if self.unlock_state != 2 {
+            return;
+        }
+        self.unlock_state = 0;
+
+        match offset {
+            ESB_TIMER1_REG => self.timer1_preload = value & ESB_PRELOAD_MASK,
+            ESB_TIMER2_REG => self.timer2_preload = value & ESB_PRELOAD_MASK,
+            ESB_RELOAD_REG => {
+                if value & ESB_WDT_RELOAD != 0 {
+                    self.restart_timer(1);
+                }
+                if value & ESB_WDT_TIMEOUT != 0 {
+                    self.timed_out = false;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn read_bar(&self, offset: u64) -> u32 {
+        if offset == ESB_RELOAD_REG && self.timed_out {
+            ESB_WDT_TIMEOUT
+        } else {
+            0
+        }
+    }
+}
+
+// Waits for the end of the stages of the watchdog, until `kill_evt` is
+// written to.
+fn run_timer(state: Arc<Mutex<WatchdogState>>, kill_evt: EventFd, expiry_evt: Option<EventFd>) {
+    const TIMER_EVENT: u64 = 0;
+    const KILL_EVENT: u64 = 1;
+
+    let timer_fd = state.lock().unwrap().timer.as_raw_fd();
+    let epoll_fd = match epoll::create(true) {
+        Ok(fd) => fd,
+        Err(e) => {
+            error!("Failed creating the watchdog epoll context: {}", e);
+            return;
+        }
+    };
+    // Safe because the fd has just been created, and is only owned by the
+    // file, which closes it.
+    let _epoll_file = unsafe { File::from_raw_fd(epoll_fd) };
+    for (fd, event) in &[(timer_fd, TIMER_EVENT), (kill_evt.as_raw_fd(), KILL_EVENT)] {
+        if let Err(e) = epoll::ctl(
+            epoll_fd,
+            epoll::ControlOptions::EPOLL_CTL_ADD,
+            *fd,
+            epoll::Event::new(epoll::Events::EPOLLIN, *event),
+        ) {
+            error!("Failed watching the watchdog timer: {}", e);
+            return;
+        }
+    }
+
+    let mut events = vec![epoll::Event::new(epoll::Events::empty(), 0); 2];
+    'epoll: loop {
+        let num_events = match epoll::wait(epoll_fd, -1, &mut events[..]) {
+            Ok(res) => res,
+            Err(e) => {
+                if e.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                error!("Failed waiting for the watchdog timer: {}", e);
+                break;
+            }
+        };
+
+        for event in events.iter().take(num_events) {
+            if event.data == KILL_EVENT {
+                break 'epoll;
+            }
+
+            let expired = {
+                let mut state = state.lock().unwrap();
+                match state.timer.wait() {
+                    Ok(_) => state.stage_over(),
+                    // The guest reloaded the timer in the meantime.
+                    Err(e) if e.errno() == libc::EAGAIN => false,
+                    Err(e) => {
+                        error!("Failed reading the watchdog timer: {}", e);
+                        break 'epoll;
+                    }
+                }
+            };
+            if expired {
+                warn!("The watchdog expired");
+                event!("watchdog", "expired");
+                if let Some(expiry_evt) = expiry_evt.as_ref() {
+                    if let Err(e) = expiry_evt.write(1) {
+                        error!("Failed signaling the watchdog expiry: {}", e);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// The watchdog timer of the Intel 6300ESB, as a PCI device.
+pub struct I6300esbWatchdog {
+    config: PciConfiguration,
+    state: Arc<Mutex<WatchdogState>>,
+    kill_evt: EventFd,
+}
+
+impl I6300esbWatchdog {
+    /// Creates a disabled watchdog, whose stages last half of `timeout` until
+    /// the guest programs them. `expiry_evt` is written to when the watchdog
+    /// expires, which is otherwise only reported as an event.
+    pub fn new(timeout: Duration, expiry_evt: Option<EventFd>) -> io::Result<Self> {
+        let timer = TimerFd::new()?;
+        // The guest may reload the timer between the end of a stage and its
+        // handling, reading the timer must not block then.
+        // Safe because the fd is valid, and only its flags are changed.
+        let ret = unsafe { libc::fcntl(timer.as_raw_fd(), libc::F_SETFL, libc::O_NONBLOCK) };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let preload = preload_for(timeout / 2);
+        let state = Arc::new(Mutex::new(WatchdogState {
+            timer,
+            reboot: true,
+            clock_1mhz: false,
+            locked: false,
+            enabled: false,
+            free_run: false,
+            timer1_preload: preload,
+            timer2_preload: preload,
+            stage: 1,
+            unlock_state: 0,
+            timed_out: false,
+            paused: false,
+        }));
+
+        let kill_evt = EventFd::new(libc::EFD_NONBLOCK)?;
+        let thread_state = state.clone();
+        let thread_kill_evt = kill_evt.try_clone()?;
+        thread::Builder::new()
+            .name("watchdog".to_string())
+            .spawn(move || run_timer(thread_state, thread_kill_evt, expiry_evt))?;
+
+        let config = PciConfiguration::new(
+            VENDOR_ID_INTEL,
+            DEVICE_ID_INTEL_ESB_WDT,
+            PciClassCode::BaseSystemPeripheral,
+            &PciBaseSystemPeripheralSubclass::Other,
+            None,
+            PciHeaderType::Device,
+            0,
+            0,
+            None,
+        );
+
+        Ok(I6300esbWatchdog {
+            config,
+            state,
+            kill_evt,
+        })
+    }
+}
+
+impl Drop for I6300esbWatchdog {
+    fn drop(&mut self) {
+        // Ignore the result because there is nothing we can do about it.
+        let _ = self.kill_evt.write(1);
+    }
+}
+
+impl BusDevice for I6300esbWatchdog {
+    fn read(&mut self, base: u64, offset: u64, data: &mut [u8]) {
+        self.read_bar(base, offset, data)
+    }
+
+    fn write(&mut self, base: u64, offset: u64, data: &[u8]) {
+        self.write_bar(base, offset, data)
+    }
+}
+
+impl PciDevice for I6300esbWatchdog {
+    fn allocate_bars(
+        &mut self,
+        allocator: &mut SystemAllocator,
+    ) -> Result<Vec<(GuestAddress, GuestUsize, PciBarRegionType)>> {
+        let region_type = PciBarRegionType::Memory32BitRegion;
+        let addr = allocator
+            .allocate_mmio_hole_addresses(None, ESB_BAR_SIZE, None)
+            .ok_or(PciDeviceError::IoAllocationFailed(ESB_BAR_SIZE))?;
+        let config = PciBarConfiguration::default()
+            .set_register_index(0)
+            .set_address(addr.0)
+            .set_size(ESB_BAR_SIZE)
+            .set_region_type(region_type);
+        self.config
+            .add_pci_bar(&config)
+            .map_err(|e| PciDeviceError::IoRegistrationFailed(addr.0, e))?;
+
+        Ok(vec![(addr, ESB_BAR_SIZE, region_type)])
+    }
+
+    fn write_config_register(&mut self, reg_idx: usize, offset: u64, data: &[u8]) {
+        let reg = reg_idx * 4 + offset as usize;
+        let mut state = self.state.lock().unwrap();
+        match (reg, data.len()) {
+            (ESB_CONFIG_REG, 2) => {
+                let value = LittleEndian::read_u16(data);
+                state.reboot = value & ESB_WDT_REBOOT == 0;
+                state.clock_1mhz = value & ESB_WDT_FREQ != 0;
+            }
+            (ESB_LOCK_REG, 1) => state.write_lock_reg(data[0]),
+            _ => self.config.write_config_register(reg_idx, offset, data),
+        }
+    }
+
+    fn read_config_register(&mut self, reg_idx: usize) -> u32 {
+        let state = self.state.lock().unwrap();
+        match reg_idx * 4 {
+            ESB_CONFIG_REG => u32::from(state.config_reg()),
+            ESB_LOCK_REG => u32::from(state.lock_reg()),
+            _ => self.config.read_reg(reg_idx),
+        }
+    }
+
+    fn detect_bar_reprogramming(
+        &mut self,
+        reg_idx: usize,
+        data: &[u8],
+    ) -> Option<BarReprogrammingParams> {
+        self.config.detect_bar_reprogramming(reg_idx, data)
+    }
+
+    fn read_bar(&mut self, _base: u64, offset: u64, data: &mut [u8]) {
+        let value = self.state.lock().unwrap().read_bar(offset);
+        match data.len() {
+            1 => data[0] = value as u8,
+            2 => LittleEndian::write_u16(data, value as u16),
+            4 => LittleEndian::write_u32(data, value),
+            _ => {}
+        }
+    }
+
+    fn write_bar(&mut self, _base: u64, offset: u64, data: &[u8]) {
+        let value = match data.len() {
+            1 => u32::from(data[0]),
+            2 => u32::from(LittleEndian::read_u16(data)),
+            4 => LittleEndian::read_u32(data),
+            _ => return,
+        };
+        self.state.lock().unwrap().write_bar(offset, value);
+    }
+
+    fn as_any(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+impl Pausable for I6300esbWatchdog {
+    fn pause(&mut self) -> std::result::Result<(), MigratableError> {
+        let mut state = self.state.lock().unwrap();
+        state.paused = true;
+        state.stop_timer();
+        Ok(())
+    }
+
+    // The current stage starts over, the time spent in it before the pause
+    // being lost.
+    fn resume(&mut self) -> std::result::Result<(), MigratableError> {
+        let mut state = self.state.lock().unwrap();
+        state.paused = false;
+        let stage = state.stage;
+        state.restart_timer(stage);
+        Ok(())
+    }
+}
+
+// The timers and the configuration space are not saved yet, a VM with a
+// watchdog cannot be snapshot.
+impl Snapshotable for I6300esbWatchdog {
+    fn id(&self) -> String {
+        String::from("i6300esb")
+    }
+}
+impl Migratable for I6300esbWatchdog {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BAR_ADDR: u64 = 0xd000_0000;
+
+    fn unlock(bus: &devices::Bus) {
+        assert!(bus.write(
+            BAR_ADDR + ESB_RELOAD_REG,
+            &(ESB_UNLOCK1 as u16).to_le_bytes()
+        ));
+        assert!(bus.write(
+            BAR_ADDR + ESB_RELOAD_REG,
+            &(ESB_UNLOCK2 as u16).to_le_bytes()
+        ));
+    }
+
+    #[test]
+    fn test_expiry() {
+        let expiry_evt = EventFd::new(libc::EFD_NONBLOCK).unwrap();
+        let watchdog = Arc::new(Mutex::new(
+            I6300esbWatchdog::new(
+                Duration::from_secs(30),
+                Some(expiry_evt.try_clone().unwrap()),
+            )
+            .unwrap(),
+        ));
+        let bus = devices::Bus::new();
+        bus.insert(watchdog.clone(), BAR_ADDR, ESB_BAR_SIZE)
+            .unwrap();
+
+        // Stages of about 1ms, at the 1MHz scale. The preload is only written
+        // after the unlock sequence.
+        let mut dev = watchdog.lock().unwrap();
+        dev.write_config_register(ESB_CONFIG_REG / 4, 0, &ESB_WDT_FREQ.to_le_bytes());
+        assert_eq!(dev.read_config_register(ESB_CONFIG_REG / 4), 0x4);
+        drop(dev);
+        assert!(bus.write(BAR_ADDR + ESB_TIMER1_REG, &1000u32.to_le_bytes()));
+        assert_eq!(
+            watchdog
+                .lock()
+                .unwrap()
+                .state
+                .lock()
+                .unwrap()
+                .timer1_preload,
+            15106
+        );
+        for reg in &[ESB_TIMER1_REG, ESB_TIMER2_REG] {
+            unlock(&bus);
+            assert!(bus.write(BAR_ADDR + reg, &1000u32.to_le_bytes()));
+        }
+
+        // Nothing happens until the guest enables the watchdog.
+        thread::sleep(Duration::from_millis(20));
+        assert!(expiry_evt.read().is_err());
+
+        watchdog
+            .lock()
+            .unwrap()
+            .write_config_register(ESB_LOCK_REG / 4, 0, &[ESB_WDT_ENABLE]);
+        let mut data = [0u8; 2];
+        for _ in 0..100 {
+            if expiry_evt.read().is_ok() {
+                // The guest can tell the watchdog expired.
+                bus.read(BAR_ADDR + ESB_RELOAD_REG, &mut data);
+                assert_eq!(u16::from_le_bytes(data), ESB_WDT_TIMEOUT as u16);
+                return;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        panic!("The watchdog didn't expire");
+    }
+
+    #[test]
+    fn test_reload() {
+        let watchdog = I6300esbWatchdog::new(Duration::from_secs(30), None).unwrap();
+        let mut state = watchdog.state.lock().unwrap();
+        assert_eq!(
+            state.stage_duration(1),
+            Duration::from_nanos(14_999_800_242)
+        );
+
+        state.write_lock_reg(ESB_WDT_ENABLE);
+        assert!(state.timer.is_armed().unwrap());
+        assert!(!state.stage_over());
+        assert_eq!(state.stage, 2);
+
+        // Reloading starts the first stage over.
+        state.write_bar(ESB_RELOAD_REG, ESB_UNLOCK1);
+        state.write_bar(ESB_RELOAD_REG, ESB_UNLOCK2);
+        state.write_bar(ESB_RELOAD_REG, ESB_WDT_RELOAD);
+        assert_eq!(state.stage, 1);
+
+        // The guest can disable it, unless it's locked.
+        state.write_lock_reg(0);
+        assert!(!state.timer.is_armed().unwrap());
+        state.write_lock_reg(ESB_WDT_ENABLE | ESB_WDT_LOCK);
+        state.write_lock_reg(0);
+        assert_eq!(state.lock_reg(), ESB_WDT_ENABLE | ESB_WDT_LOCK);
+
+        // Without reboot, the second stage only ends.
+        state.reboot = false;
+        state.stage = 2;
+        assert!(!state.stage_over());
+        assert!(!state.timed_out);
+    }
+}
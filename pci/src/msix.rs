@@ -6,6 +6,7 @@
 extern crate byteorder;
 extern crate vm_memory;
 
+use std::io;
 use std::sync::Arc;
 
 use crate::{PciCapability, PciCapabilityID};
@@ -25,7 +26,7 @@ const FUNCTION_MASK_MASK: u16 = (1 << FUNCTION_MASK_BIT) as u16;
 const MSIX_ENABLE_MASK: u16 = (1 << MSIX_ENABLE_BIT) as u16;
 pub const MSIX_TABLE_ENTRY_SIZE: usize = 16;
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
 pub struct MsixTableEntry {
     pub msg_addr_lo: u32,
     pub msg_addr_hi: u32,
@@ -50,6 +51,15 @@ impl Default for MsixTableEntry {
     }
 }
 
+/// The MSI-X table and pending bits of a device, as set by the guest.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct MsixConfigState {
+    pub masked: bool,
+    pub enabled: bool,
+    pub table_entries: Vec<MsixTableEntry>,
+    pub pba_entries: Vec<u64>,
+}
+
 pub struct MsixConfig {
     pub table_entries: Vec<MsixTableEntry>,
     pub pba_entries: Vec<u64>,
@@ -84,6 +94,55 @@ impl MsixConfig {
         self.masked
     }
 
+    pub fn state(&self) -> MsixConfigState {
+        MsixConfigState {
+            masked: self.masked,
+            enabled: self.enabled,
+            table_entries: self.table_entries.clone(),
+            pba_entries: self.pba_entries.clone(),
+        }
+    }
+
+    /// Restores a state returned by `state()`, and sets the interrupt routes
+    /// up again if MSI-X is enabled.
+    pub fn set_state(&mut self, state: &MsixConfigState) -> io::Result<()> {
+        if state.table_entries.len() != self.table_entries.len()
+            || state.pba_entries.len() != self.pba_entries.len()
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "MSI-X table of {} vectors instead of {}",
+                    state.table_entries.len(),
+                    self.table_entries.len()
+                ),
+            ));
+        }
+
+        self.table_entries = state.table_entries.clone();
+        self.pba_entries = state.pba_entries.clone();
+        self.masked = state.masked;
+        self.enabled = state.enabled;
+
+        if self.enabled && !self.masked {
+            for (idx, table_entry) in self.table_entries.iter().enumerate() {
+                let config = MsiIrqSourceConfig {
+                    high_addr: table_entry.msg_addr_hi,
+                    low_addr: table_entry.msg_addr_lo,
+                    data: table_entry.msg_data,
+                };
+                self.interrupt_source_group
+                    .update(idx as InterruptIndex, InterruptSourceConfig::MsiIrq(config))?;
+                if table_entry.masked() {
+                    self.interrupt_source_group.mask(idx as InterruptIndex)?;
+                }
+            }
+            self.interrupt_source_group.enable()?;
+        }
+
+        Ok(())
+    }
+
     pub fn enabled(&self) -> bool {
         self.enabled
     }
@@ -0,0 +1,36 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+#[macro_use(crate_version, crate_authors)]
+extern crate clap;
+extern crate vmm;
+
+use clap::{App, Arg};
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter};
+use std::process;
+
+fn main() {
+    let cmd_arguments = App::new("bus-trace")
+        .version(crate_version!())
+        .author(crate_authors!())
+        .about("Convert a binary bus trace, written by --trace-bus, to text.")
+        .arg(
+            Arg::with_name("trace")
+                .help("Binary bus trace")
+                .required(true),
+        )
+        .get_matches();
+
+    let path = cmd_arguments.value_of("trace").unwrap();
+    let result = File::open(path).and_then(|file| {
+        let stdout = io::stdout();
+        vmm::bus_trace::convert(BufReader::new(file), BufWriter::new(stdout.lock()))
+    });
+    if let Err(e) = result {
+        eprintln!("Cannot convert {}: {}", path, e);
+        process::exit(1);
+    }
+}
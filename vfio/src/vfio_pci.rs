@@ -10,12 +10,10 @@ extern crate vm_allocator;
 use crate::vfio_device::VfioDevice;
 use byteorder::{ByteOrder, LittleEndian};
 use devices::BusDevice;
-use kvm_bindings::kvm_userspace_memory_region;
-use kvm_ioctls::*;
 use pci::{
     msi_num_enabled_vectors, BarReprogrammingParams, MsiConfig, MsixCap, MsixConfig,
-    PciBarConfiguration, PciBarRegionType, PciCapabilityID, PciClassCode, PciConfiguration,
-    PciDevice, PciDeviceError, PciHeaderType, PciSubclass, MSIX_TABLE_ENTRY_SIZE,
+    PciBarConfiguration, PciBarPrefetchable, PciBarRegionType, PciCapabilityID, PciClassCode,
+    PciConfiguration, PciDevice, PciDeviceError, PciHeaderType, PciSubclass, MSIX_TABLE_ENTRY_SIZE,
 };
 use std::any::Any;
 use std::os::unix::io::AsRawFd;
@@ -27,6 +25,7 @@ use vm_allocator::SystemAllocator;
 use vm_device::interrupt::{
     InterruptIndex, InterruptManager, InterruptSourceGroup, MsiIrqGroupConfig,
 };
+use vm_device::ExternalMemoryMapping;
 use vm_memory::{Address, GuestAddress, GuestUsize};
 use vmm_sys_util::eventfd::EventFd;
 
@@ -37,7 +36,7 @@ pub enum VfioPciError {
     InterruptSourceGroupCreate(io::Error),
     IrqFd(kvm_ioctls::Error),
     NewVfioPciDevice,
-    MapRegionGuest(kvm_ioctls::Error),
+    MapRegionGuest(io::Error),
     SetGsiRouting(kvm_ioctls::Error),
     MsiNotConfigured,
     MsixNotConfigured,
@@ -275,7 +274,7 @@ impl VfioPciConfig {
 /// The VMM creates a VfioDevice, then assigns it to a VfioPciDevice,
 /// which then gets added to the PCI bus.
 pub struct VfioPciDevice {
-    vm_fd: Arc<VmFd>,
+    memory: Arc<dyn ExternalMemoryMapping>,
     device: Arc<VfioDevice>,
     vfio_pci_configuration: VfioPciConfig,
     configuration: PciConfiguration,
@@ -286,7 +285,7 @@ pub struct VfioPciDevice {
 impl VfioPciDevice {
     /// Constructs a new Vfio Pci device for the given Vfio device
     pub fn new(
-        vm_fd: &Arc<VmFd>,
+        memory: Arc<dyn ExternalMemoryMapping>,
         device: VfioDevice,
         interrupt_manager: &Arc<dyn InterruptManager<GroupConfig = MsiIrqGroupConfig>>,
     ) -> Result<Self> {
@@ -308,7 +307,7 @@ impl VfioPciDevice {
         let vfio_pci_configuration = VfioPciConfig::new(Arc::clone(&device));
 
         let mut vfio_pci_device = VfioPciDevice {
-            vm_fd: vm_fd.clone(),
+            memory,
             device,
             configuration,
             vfio_pci_configuration,
@@ -492,19 +491,10 @@ impl VfioPciDevice {
     }
 
     /// Map MMIO regions into the guest, and avoid VM exits when the guest tries
-    /// to reach those regions.
-    ///
-    /// # Arguments
-    ///
-    /// * `vm` - The KVM VM file descriptor. It is used to set the VFIO MMIO regions
-    ///          as KVM user memory regions.
-    /// * `mem_slot` - The KVM memory slot to set the user memopry regions.
-    /// # Return value
-    ///
-    /// This function returns the updated KVM memory slot id.
-    pub fn map_mmio_regions(&mut self, vm: &Arc<VmFd>, mem_slot: u32) -> Result<u32> {
+    /// to reach those regions. Each mapped region takes a memory slot of its
+    /// own, given back when the device is dropped.
+    pub fn map_mmio_regions(&mut self) -> Result<()> {
         let fd = self.device.as_raw_fd();
-        let mut new_mem_slot = mem_slot;
 
         for region in self.mmio_regions.iter_mut() {
             // We want to skip the mapping of the BAR containing the MSI-X
@@ -548,35 +538,35 @@ impl VfioPciDevice {
                     continue;
                 }
 
-                let mem_region = kvm_userspace_memory_region {
-                    slot: new_mem_slot as u32,
-                    guest_phys_addr: region.start.raw_value() + mmap_offset,
-                    memory_size: mmap_size as u64,
-                    userspace_addr: host_addr as u64,
-                    flags: 0,
-                };
-
-                // Safe because the guest regions are guaranteed not to overlap.
-                unsafe {
-                    vm.set_user_memory_region(mem_region)
-                        .map_err(VfioPciError::MapRegionGuest)?;
-                }
+                // The host mapping is kept until the slot is unmapped.
+                let new_mem_slot = self
+                    .memory
+                    .map(
+                        region.start.raw_value() + mmap_offset,
+                        mmap_size as u64,
+                        host_addr as u64,
+                    )
+                    .map_err(VfioPciError::MapRegionGuest)?;
 
                 // Update the region with memory mapped info.
                 region.mem_slot = Some(new_mem_slot);
                 region.host_addr = Some(host_addr as u64);
                 region.mmap_size = Some(mmap_size as usize);
-
-                new_mem_slot += 1;
             }
         }
 
-        Ok(new_mem_slot)
+        Ok(())
     }
 
     pub fn unmap_mmio_regions(&mut self) {
-        for region in self.mmio_regions.iter() {
-            if let (Some(addr), Some(size)) = (region.host_addr, region.mmap_size) {
+        for region in self.mmio_regions.iter_mut() {
+            // The guest stops accessing the memory before it goes away.
+            if let Some(mem_slot) = region.mem_slot.take() {
+                if let Err(e) = self.memory.unmap(mem_slot) {
+                    error!("Could not unmap region from the guest, error:{}", e);
+                }
+            }
+            if let (Some(addr), Some(size)) = (region.host_addr.take(), region.mmap_size.take()) {
                 let ret = unsafe { libc::munmap(addr as *mut libc::c_void, size) };
                 if ret != 0 {
                     error!(
@@ -631,6 +621,8 @@ const PCI_CONFIG_IO_BAR: u32 = 0x1;
 const PCI_CONFIG_MEMORY_BAR_FLAG_MASK: u32 = 0xf;
 // 64-bit memory bar flag.
 const PCI_CONFIG_MEMORY_BAR_64BIT: u32 = 0x4;
+// Prefetchable BAR bit
+const PCI_CONFIG_MEMORY_BAR_PREFETCHABLE: u32 = 0x8;
 // PCI config register size (4 bytes).
 const PCI_CONFIG_REGISTER_SIZE: usize = 4;
 // Number of BARs for a PCI device
@@ -762,7 +754,7 @@ impl PciDevice for VfioPciDevice {
                 };
                 if is_64bit_bar {
                     bar_addr = allocator
-                        .allocate_mmio_addresses(None, region_size, Some(bar_alignment))
+                        .allocate_high_mmio_addresses(None, region_size, Some(bar_alignment))
                         .ok_or_else(|| PciDeviceError::IoAllocationFailed(region_size))?;
                 } else {
                     bar_addr = allocator
@@ -777,12 +769,23 @@ impl PciDevice for VfioPciDevice {
                 bar_id as usize
             };
 
+            // The guest sees the BAR as prefetchable if the host device does.
+            let prefetchable = if !io_bar
+                && bar_id != VFIO_PCI_ROM_REGION_INDEX
+                && lsb_flag & PCI_CONFIG_MEMORY_BAR_PREFETCHABLE != 0
+            {
+                PciBarPrefetchable::Prefetchable
+            } else {
+                PciBarPrefetchable::NotPrefetchable
+            };
+
             // We can now build our BAR configuration block.
             let config = PciBarConfiguration::default()
                 .set_register_index(reg_idx)
                 .set_address(bar_addr.raw_value())
                 .set_size(region_size)
-                .set_region_type(region_type);
+                .set_region_type(region_type)
+                .set_prefetchable(prefetchable);
 
             if bar_id == VFIO_PCI_ROM_REGION_INDEX {
                 self.configuration
@@ -942,39 +945,8 @@ impl PciDevice for VfioPciDevice {
                 region.start = GuestAddress(new_base);
 
                 if let Some(mem_slot) = region.mem_slot {
-                    if let Some(host_addr) = region.host_addr {
-                        let (mmap_offset, mmap_size) = self.device.get_region_mmap(region.index);
-
-                        // Remove old region from KVM
-                        let old_mem_region = kvm_userspace_memory_region {
-                            slot: mem_slot,
-                            guest_phys_addr: old_base + mmap_offset,
-                            memory_size: 0,
-                            userspace_addr: host_addr,
-                            flags: 0,
-                        };
-                        // Safe because the guest regions are guaranteed not to overlap.
-                        unsafe {
-                            self.vm_fd
-                                .set_user_memory_region(old_mem_region)
-                                .map_err(|e| io::Error::from_raw_os_error(e.errno()))?;
-                        }
-
-                        // Insert new region to KVM
-                        let new_mem_region = kvm_userspace_memory_region {
-                            slot: mem_slot,
-                            guest_phys_addr: new_base + mmap_offset,
-                            memory_size: mmap_size as u64,
-                            userspace_addr: host_addr,
-                            flags: 0,
-                        };
-                        // Safe because the guest regions are guaranteed not to overlap.
-                        unsafe {
-                            self.vm_fd
-                                .set_user_memory_region(new_mem_region)
-                                .map_err(|e| io::Error::from_raw_os_error(e.errno()))?;
-                        }
-                    }
+                    let (mmap_offset, _) = self.device.get_region_mmap(region.index);
+                    self.memory.remap(mem_slot, new_base + mmap_offset)?;
                 }
             }
         }
@@ -26,6 +26,8 @@ use super::{
 pub mod vhost_binding;
 use self::vhost_binding::*;
 
+#[cfg(feature = "vhost-net")]
+pub mod net;
 #[cfg(feature = "vhost-vsock")]
 pub mod vsock;
 
@@ -43,9 +45,9 @@ fn guest_addr(addr: u64) -> GuestAddress {
 }
 
 /// Represent an in-kernel vhost device backend.
-pub trait VhostKernBackend<'a>: AsRawFd {
+pub trait VhostKernBackend: AsRawFd {
     /// Associated type to access the guest's memory.
-    type M: GuestMemory<'a>;
+    type M: GuestMemory;
 
     /// Get the object to access the guest's memory.
     fn mem(&self) -> &Self::M;
@@ -87,7 +89,7 @@ pub trait VhostKernBackend<'a>: AsRawFd {
     }
 }
 
-impl<'a, T: VhostKernBackend<'a>> VhostBackend for T {
+impl<T: VhostKernBackend> VhostBackend for T {
     /// Set the current process as the owner of this file descriptor.
     /// This must be run before any other vhost ioctls.
     fn set_owner(&mut self) -> Result<()> {
@@ -201,8 +203,8 @@ impl<'a, T: VhostKernBackend<'a>> VhostBackend for T {
             config_data.queue_max_size,
             config_data.queue_size,
             guest_addr(config_data.desc_table_addr),
-            guest_addr(config_data.used_ring_addr),
             guest_addr(config_data.avail_ring_addr),
+            guest_addr(config_data.used_ring_addr),
         ) {
             return Err(Error::InvalidQueue);
         }
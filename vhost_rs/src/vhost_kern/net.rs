@@ -0,0 +1,71 @@
+// Copyright © 2020 Intel Corporation
+// SPDX-License-Identifier: Apache-2.0 or BSD-3-Clause
+
+//! Kernel-based net vhost backend.
+
+use std::fs::{File, OpenOptions};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::{AsRawFd, RawFd};
+
+use super::vhost_binding::{vhost_vring_file, VHOST_NET_SET_BACKEND};
+use super::{ioctl_result, Error, Result, VhostKernBackend};
+use libc;
+use vm_memory::GuestMemory;
+use vmm_sys_util::ioctl::ioctl_with_ref;
+
+const VHOST_PATH: &str = "/dev/vhost-net";
+
+/// Handle for running VHOST_NET ioctls.
+///
+/// A handle drives a single queue pair, the receive queue having the index 0 and the transmit
+/// queue the index 1.
+pub struct Net<M: GuestMemory> {
+    fd: File,
+    mem: M,
+}
+
+impl<M: GuestMemory + Clone> Net<M> {
+    /// Open a handle to a new VHOST-NET instance.
+    pub fn new(mem: &M) -> Result<Self> {
+        Ok(Net {
+            fd: OpenOptions::new()
+                .read(true)
+                .write(true)
+                .custom_flags(libc::O_CLOEXEC | libc::O_NONBLOCK)
+                .open(VHOST_PATH)
+                .map_err(Error::VhostOpen)?,
+            mem: mem.clone(),
+        })
+    }
+
+    /// Set the TAP file descriptor the queue moves the frames from or to, or detach the queue
+    /// from its backend when `fd` is `None`.
+    ///
+    /// # Arguments
+    /// * `queue_index` - Index of the queue to modify.
+    /// * `fd` - TAP file descriptor, or `None` to stop the queue.
+    pub fn set_backend(&self, queue_index: usize, fd: Option<&dyn AsRawFd>) -> Result<()> {
+        let vring_file = vhost_vring_file {
+            index: queue_index as u32,
+            fd: fd.map_or(-1, |fd| fd.as_raw_fd()),
+        };
+
+        // This ioctl is called on a valid vhost fd and has its return value checked.
+        let ret = unsafe { ioctl_with_ref(&self.fd, VHOST_NET_SET_BACKEND(), &vring_file) };
+        ioctl_result(ret, ())
+    }
+}
+
+impl<M: GuestMemory> VhostKernBackend for Net<M> {
+    type M = M;
+
+    fn mem(&self) -> &Self::M {
+        &self.mem
+    }
+}
+
+impl<M: GuestMemory> AsRawFd for Net<M> {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd.as_raw_fd()
+    }
+}
@@ -8,7 +8,6 @@
 //! Kernel-based vsock vhost backend.
 
 use std::fs::{File, OpenOptions};
-use std::marker::PhantomData;
 use std::os::unix::fs::OpenOptionsExt;
 use std::os::unix::io::{AsRawFd, RawFd};
 
@@ -21,13 +20,12 @@ use vmm_sys_util::ioctl::ioctl_with_ref;
 const VHOST_PATH: &str = "/dev/vhost-vsock";
 
 /// Handle for running VHOST_VSOCK ioctls.
-pub struct Vsock<'a, M: GuestMemory<'a>> {
+pub struct Vsock<M: GuestMemory> {
     fd: File,
     mem: M,
-    _phatomdata: PhantomData<&'a M>, // Get rid of unused type parameter `a
 }
 
-impl<'a, M: GuestMemory<'a>> Vsock<'a, M> {
+impl<M: GuestMemory + Clone> Vsock<M> {
     /// Open a handle to a new VHOST-VSOCK instance.
     pub fn new(mem: &M) -> Result<Self> {
         Ok(Vsock {
@@ -38,7 +36,6 @@ impl<'a, M: GuestMemory<'a>> Vsock<'a, M> {
                 .open(VHOST_PATH)
                 .map_err(Error::VhostOpen)?,
             mem: mem.clone(),
-            _phatomdata: PhantomData,
         })
     }
 
@@ -69,7 +66,7 @@ impl<'a, M: GuestMemory<'a>> Vsock<'a, M> {
     }
 }
 
-impl<'a, M: GuestMemory<'a>> VhostKernBackend<'a> for Vsock<'a, M> {
+impl<M: GuestMemory> VhostKernBackend for Vsock<M> {
     type M = M;
 
     fn mem(&self) -> &Self::M {
@@ -77,7 +74,7 @@ impl<'a, M: GuestMemory<'a>> VhostKernBackend<'a> for Vsock<'a, M> {
     }
 }
 
-impl<'a, M: GuestMemory<'a>> AsRawFd for Vsock<'a, M> {
+impl<M: GuestMemory> AsRawFd for Vsock<M> {
     fn as_raw_fd(&self) -> RawFd {
         self.fd.as_raw_fd()
     }
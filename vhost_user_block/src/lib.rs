@@ -30,7 +30,7 @@ use std::vec::Vec;
 use vhost_rs::vhost_user::message::*;
 use vhost_user_backend::{VhostUserBackend, VhostUserDaemon, Vring, VringWorker};
 use virtio_bindings::bindings::virtio_blk::*;
-use vm_memory::{Bytes, GuestMemoryError, GuestMemoryMmap};
+use vm_memory::{GuestMemoryError, GuestMemoryMmap};
 use vm_virtio::block::{build_disk_image_id, Request};
 
 const QUEUE_SIZE: usize = 1024;
@@ -124,33 +124,29 @@ impl VhostUserBlkBackend {
 
         while let Some(head) = vring.mut_queue().iter(mem).next() {
             debug!("got an element in the queue");
-            let len;
-            match Request::parse(&head, mem) {
-                Ok(request) => {
+            let index = head.index;
+            let len = match Request::parse(head) {
+                Ok(mut request) => {
                     debug!("element is a valid request");
                     let status = match request.execute(
                         &mut self.disk_image,
                         self.disk_nsectors,
-                        mem,
                         &self.disk_image_id,
                     ) {
-                        Ok(l) => {
-                            len = l;
-                            VIRTIO_BLK_S_OK
-                        }
-                        Err(e) => {
-                            len = 1;
-                            e.status()
-                        }
+                        Ok(_) => VIRTIO_BLK_S_OK,
+                        Err(e) => e.status(),
                     };
-                    mem.write_obj(status, request.status_addr).unwrap();
+                    request.complete(status).unwrap_or_else(|e| {
+                        error!("failed to complete request: {:?}", e);
+                        0
+                    })
                 }
                 Err(err) => {
                     error!("failed to parse available descriptor chain: {:?}", err);
-                    len = 0;
+                    0
                 }
-            }
-            vring.mut_queue().add_used(mem, head.index, len);
+            };
+            vring.mut_queue().add_used(mem, index, len);
             used_any = true;
         }
 
@@ -254,7 +254,7 @@ impl VhostUserNetBackend {
     fn process_tx(&mut self, mut queue: &mut Queue, index: usize) -> Result<()> {
         let mem = self.mem.as_ref().ok_or(Error::NoMemoryConfigured)?;
 
-        self.txs[index].process_desc_chain(&mem, &mut self.taps[index].0, &mut queue);
+        self.txs[index].process_desc_chain(&mem, &mut self.taps[index].0, &mut queue, None);
 
         Ok(())
     }
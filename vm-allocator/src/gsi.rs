@@ -3,6 +3,7 @@
 // SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
 
 use std::collections::btree_map::BTreeMap;
+use std::collections::BTreeSet;
 use std::result;
 
 #[derive(Debug)]
@@ -31,6 +32,8 @@ pub struct GsiAllocator {
     apics: BTreeMap<u32, u32>,
     next_irq: u32,
     next_gsi: u32,
+    // GSIs given back, handed out again before the next ones.
+    freed_gsis: BTreeSet<u32>,
 }
 
 impl GsiAllocator {
@@ -40,6 +43,7 @@ impl GsiAllocator {
             apics: BTreeMap::new(),
             next_irq: 0xffff_ffff,
             next_gsi: 0,
+            freed_gsis: BTreeSet::new(),
         };
 
         for apic in &apics {
@@ -59,11 +63,23 @@ impl GsiAllocator {
 
     /// Allocate a GSI
     pub fn allocate_gsi(&mut self) -> Result<u32> {
+        if let Some(gsi) = self.freed_gsis.iter().next().copied() {
+            self.freed_gsis.remove(&gsi);
+            return Ok(gsi);
+        }
+
         self.next_gsi = self.next_gsi.checked_add(1).ok_or(Error::Overflow)?;
 
         Ok(self.next_gsi - 1)
     }
 
+    /// Free a GSI allocated through allocate_gsi()
+    pub fn free_gsi(&mut self, gsi: u32) {
+        if gsi < self.next_gsi {
+            self.freed_gsis.insert(gsi);
+        }
+    }
+
     /// Allocate an IRQ
     pub fn allocate_irq(&mut self) -> Result<u32> {
         let mut irq: u32 = 0;
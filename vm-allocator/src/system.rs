@@ -32,6 +32,7 @@ fn pagesize() -> usize {
 ///           GuestAddress(0x1000), 0x10000,
 ///           GuestAddress(0x10000000), 0x10000000,
 ///           GuestAddress(0x20000000), 0x100000,
+///           GuestAddress(0x30000000), 0x10000000,
 ///           vec![GsiApic::new(5, 19)]).unwrap();
 ///    assert_eq!(allocator.allocate_irq(), Some(5));
 ///    assert_eq!(allocator.allocate_irq(), Some(6));
@@ -42,6 +43,7 @@ pub struct SystemAllocator {
     io_address_space: AddressAllocator,
     mmio_address_space: AddressAllocator,
     mmio_hole_address_space: AddressAllocator,
+    high_mmio_address_space: AddressAllocator,
     gsi_allocator: GsiAllocator,
 }
 
@@ -53,7 +55,12 @@ impl SystemAllocator {
     /// * `io_size` - The size of IO memory.
     /// * `mmio_base` - The starting address of MMIO memory.
     /// * `mmio_size` - The size of MMIO memory.
+    /// * `mmio_hole_base` - The starting address of the 32 bits MMIO hole.
+    /// * `mmio_hole_size` - The size of the 32 bits MMIO hole.
+    /// * `high_mmio_base` - The starting address of the MMIO aperture above RAM.
+    /// * `high_mmio_size` - The size of the MMIO aperture above RAM.
     /// * `first_irq` - The first irq number to give out.
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         io_base: GuestAddress,
         io_size: GuestUsize,
@@ -61,12 +68,15 @@ impl SystemAllocator {
         mmio_size: GuestUsize,
         mmio_hole_base: GuestAddress,
         mmio_hole_size: GuestUsize,
+        high_mmio_base: GuestAddress,
+        high_mmio_size: GuestUsize,
         apics: Vec<GsiApic>,
     ) -> Option<Self> {
         Some(SystemAllocator {
             io_address_space: AddressAllocator::new(io_base, io_size)?,
             mmio_address_space: AddressAllocator::new(mmio_base, mmio_size)?,
             mmio_hole_address_space: AddressAllocator::new(mmio_hole_base, mmio_hole_size)?,
+            high_mmio_address_space: AddressAllocator::new(high_mmio_base, high_mmio_size)?,
             gsi_allocator: GsiAllocator::new(apics),
         })
     }
@@ -81,6 +91,11 @@ impl SystemAllocator {
         self.gsi_allocator.allocate_gsi().ok()
     }
 
+    /// Frees a GSI reserved through allocate_gsi().
+    pub fn free_gsi(&mut self, gsi: u32) {
+        self.gsi_allocator.free_gsi(gsi)
+    }
+
     /// Reserves a section of `size` bytes of IO address space.
     pub fn allocate_io_addresses(
         &mut self,
@@ -120,6 +135,20 @@ impl SystemAllocator {
         )
     }
 
+    /// Reserves a section of `size` bytes of the MMIO aperture above RAM.
+    pub fn allocate_high_mmio_addresses(
+        &mut self,
+        address: Option<GuestAddress>,
+        size: GuestUsize,
+        align_size: Option<GuestUsize>,
+    ) -> Option<GuestAddress> {
+        self.high_mmio_address_space.allocate(
+            address,
+            size,
+            Some(align_size.unwrap_or(pagesize() as u64)),
+        )
+    }
+
     /// Free an IO address range.
     /// We can only free a range if it matches exactly an already allocated range.
     pub fn free_io_addresses(&mut self, address: GuestAddress, size: GuestUsize) {
@@ -131,4 +160,162 @@ impl SystemAllocator {
     pub fn free_mmio_addresses(&mut self, address: GuestAddress, size: GuestUsize) {
         self.mmio_address_space.free(address, size)
     }
+
+    /// Free an MMIO address range from the 32 bits hole.
+    /// We can only free a range if it matches exactly an already allocated range.
+    pub fn free_mmio_hole_addresses(&mut self, address: GuestAddress, size: GuestUsize) {
+        self.mmio_hole_address_space.free(address, size)
+    }
+
+    /// Free an MMIO address range from the aperture above RAM.
+    /// We can only free a range if it matches exactly an already allocated range.
+    pub fn free_high_mmio_addresses(&mut self, address: GuestAddress, size: GuestUsize) {
+        self.high_mmio_address_space.free(address, size)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn create_allocator() -> SystemAllocator {
+        SystemAllocator::new(
+            GuestAddress(0x1000),
+            0x1000,
+            GuestAddress(0x1_0000_0000),
+            0x1_0000_0000,
+            GuestAddress(0xc000_0000),
+            0x1000_0000,
+            GuestAddress(0x2_0000_0000),
+            0x1_0000_0000,
+            vec![GsiApic::new(5, 19)],
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn allocate_gsi() {
+        let mut allocator = create_allocator();
+        assert_eq!(allocator.allocate_gsi(), Some(24));
+        assert_eq!(allocator.allocate_gsi(), Some(25));
+        assert_eq!(allocator.allocate_irq(), Some(5));
+    }
+
+    #[test]
+    fn free_gsi() {
+        let mut allocator = create_allocator();
+        assert_eq!(allocator.allocate_gsi(), Some(24));
+        assert_eq!(allocator.allocate_gsi(), Some(25));
+        assert_eq!(allocator.allocate_gsi(), Some(26));
+        allocator.free_gsi(25);
+        allocator.free_gsi(24);
+        assert_eq!(allocator.allocate_gsi(), Some(24));
+        assert_eq!(allocator.allocate_gsi(), Some(25));
+        assert_eq!(allocator.allocate_gsi(), Some(27));
+    }
+
+    #[test]
+    fn allocate_io_addresses() {
+        let mut allocator = create_allocator();
+        assert_eq!(
+            allocator.allocate_io_addresses(Some(GuestAddress(0x1800)), 0x100, None),
+            Some(GuestAddress(0x1800))
+        );
+        assert_eq!(
+            allocator.allocate_io_addresses(Some(GuestAddress(0x1880)), 0x100, None),
+            None
+        );
+        assert_eq!(
+            allocator.allocate_io_addresses(Some(GuestAddress(0x1f80)), 0x100, None),
+            None
+        );
+        allocator.free_io_addresses(GuestAddress(0x1800), 0x100);
+        assert_eq!(
+            allocator.allocate_io_addresses(Some(GuestAddress(0x1880)), 0x100, None),
+            Some(GuestAddress(0x1880))
+        );
+    }
+
+    #[test]
+    fn allocate_mmio_addresses_around_hole() {
+        let mut allocator = create_allocator();
+
+        // 32 bits BARs come from the hole below 4 GiB.
+        let addr = allocator
+            .allocate_mmio_hole_addresses(None, 0x10_0000, None)
+            .unwrap();
+        assert!(addr >= GuestAddress(0xc000_0000) && addr < GuestAddress(0xd000_0000));
+        assert_eq!(
+            allocator.allocate_mmio_hole_addresses(Some(GuestAddress(0xb000_0000)), 0x1000, None),
+            None
+        );
+
+        // 64 bits BARs are placed in the aperture above RAM.
+        let addr = allocator
+            .allocate_high_mmio_addresses(None, 0x10_0000, None)
+            .unwrap();
+        assert!(addr >= GuestAddress(0x2_0000_0000) && addr < GuestAddress(0x3_0000_0000));
+        assert_eq!(
+            allocator.allocate_high_mmio_addresses(Some(GuestAddress(0xc000_0000)), 0x1000, None),
+            None
+        );
+        assert_eq!(
+            allocator.allocate_high_mmio_addresses(Some(GuestAddress(0x1_0000_0000)), 0x1000, None),
+            None
+        );
+    }
+
+    #[test]
+    fn free_high_mmio_addresses() {
+        let mut allocator = create_allocator();
+        let addr = GuestAddress(0x2_0000_0000);
+        assert_eq!(
+            allocator.allocate_high_mmio_addresses(Some(addr), 0x1000, None),
+            Some(addr)
+        );
+        assert_eq!(
+            allocator.allocate_high_mmio_addresses(Some(addr), 0x1000, None),
+            None
+        );
+
+        // The RAM address space doesn't overlap with the aperture.
+        allocator.free_mmio_addresses(addr, 0x1000);
+        assert_eq!(
+            allocator.allocate_high_mmio_addresses(Some(addr), 0x1000, None),
+            None
+        );
+
+        allocator.free_high_mmio_addresses(addr, 0x1000);
+        assert_eq!(
+            allocator.allocate_high_mmio_addresses(Some(addr), 0x1000, None),
+            Some(addr)
+        );
+    }
+
+    #[test]
+    fn free_mmio_hole_addresses() {
+        let mut allocator = create_allocator();
+        let addr = GuestAddress(0xc800_0000);
+        assert_eq!(
+            allocator.allocate_mmio_hole_addresses(Some(addr), 0x1000, None),
+            Some(addr)
+        );
+        assert_eq!(
+            allocator.allocate_mmio_hole_addresses(Some(addr), 0x1000, None),
+            None
+        );
+
+        // Freeing from the wrong address space does nothing.
+        allocator.free_mmio_addresses(addr, 0x1000);
+        assert_eq!(
+            allocator.allocate_mmio_hole_addresses(Some(addr), 0x1000, None),
+            None
+        );
+
+        allocator.free_mmio_hole_addresses(addr, 0x1000);
+        assert_eq!(
+            allocator.allocate_mmio_hole_addresses(Some(addr), 0x1000, None),
+            Some(addr)
+        );
+    }
 }
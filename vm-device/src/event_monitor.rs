@@ -0,0 +1,159 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Stream of the lifecycle events of the VMM, for management layers to be
+//! notified of them rather than polling.
+//!
+//! Once a monitor is set, each event is written to it as a single line of
+//! JSON, carrying a timestamp, the part of the VMM it comes from, its name,
+//! and some properties. The events are handed to a dedicated thread through
+//! a bounded channel, so that a slow consumer can never stall the VMM:
+//! events not fitting in the channel are dropped, and counted by the
+//! `event_monitor_dropped_events` metric.
+//!
+//! Events are logged with the `event!` macro, which does nothing until a
+//! monitor is set.
+
+use crate::metrics::{self, Metric};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// Events waiting to be written, beyond which new ones get dropped.
+const EVENT_QUEUE_LEN: usize = 256;
+
+/// A lifecycle event, as written to the monitor.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct Event {
+    /// Time since the Unix epoch.
+    pub timestamp: Duration,
+    pub source: String,
+    pub event: String,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub properties: BTreeMap<String, String>,
+}
+
+lazy_static! {
+    static ref MONITOR: Mutex<Option<SyncSender<Event>>> = Mutex::new(None);
+    static ref DROPPED_EVENTS: Arc<Metric> =
+        metrics::registry().counter("event_monitor_dropped_events", "event_monitor");
+}
+
+fn write_events(file: File, receiver: Receiver<Event>) -> io::Result<()> {
+    let mut writer = BufWriter::new(file);
+    for event in receiver {
+        serde_json::to_writer(&mut writer, &event)?;
+        writer.write_all(b"\n")?;
+        // Every event is delivered as soon as it is logged.
+        writer.flush()?;
+    }
+    Ok(())
+}
+
+/// Starts writing the events to `file`, which can be a regular file, a pipe
+/// or a socket, from a dedicated thread. The thread runs until `close()` is
+/// called, or writing to `file` fails.
+pub fn set_monitor(file: File) -> io::Result<thread::JoinHandle<io::Result<()>>> {
+    let (sender, receiver) = sync_channel(EVENT_QUEUE_LEN);
+    let thread = thread::Builder::new()
+        .name("event-monitor".to_string())
+        .spawn(move || write_events(file, receiver))?;
+
+    *MONITOR.lock().unwrap() = Some(sender);
+    Ok(thread)
+}
+
+/// Stops logging events. The monitor thread exits once it has written the
+/// events logged so far.
+pub fn close() {
+    MONITOR.lock().unwrap().take();
+}
+
+/// Logs `event` from `source`, if a monitor is set. Prefer the `event!`
+/// macro.
+pub fn event_log(source: &str, event: &str, properties: BTreeMap<String, String>) {
+    let mut monitor = MONITOR.lock().unwrap();
+    let sender = match monitor.as_ref() {
+        Some(sender) => sender,
+        None => return,
+    };
+
+    let event = Event {
+        timestamp: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default(),
+        source: source.to_string(),
+        event: event.to_string(),
+        properties,
+    };
+    match sender.try_send(event) {
+        Ok(()) => {}
+        Err(TrySendError::Full(_)) => DROPPED_EVENTS.inc(),
+        // The monitor thread failed writing, and is gone.
+        Err(TrySendError::Disconnected(_)) => {
+            monitor.take();
+        }
+    }
+}
+
+/// Logs an event, e.g. `event!("vm", "booted")`, or with some properties,
+/// `event!("vcpu", "error", "id" => 0, "error" => e)`. The values of the
+/// properties only need to implement `Display`.
+#[macro_export]
+macro_rules! event {
+    ($source:expr, $event:expr) => {
+        $crate::event_monitor::event_log($source, $event, ::std::collections::BTreeMap::new())
+    };
+    ($source:expr, $event:expr, $($key:expr => $value:expr),+ $(,)?) => {{
+        let mut properties = ::std::collections::BTreeMap::new();
+        $(properties.insert($key.to_string(), $value.to_string());)+
+        $crate::event_monitor::event_log($source, $event, properties)
+    }};
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader};
+    use std::os::unix::io::{FromRawFd, IntoRawFd};
+    use std::os::unix::net::UnixStream;
+
+    #[test]
+    fn test_event_monitor() {
+        // Nothing is logged, nor buffered, without a monitor.
+        event!("vm", "ignored");
+
+        let (consumer, producer) = UnixStream::pair().unwrap();
+        // Safe because the fd has just been released by the stream.
+        let file = unsafe { File::from_raw_fd(producer.into_raw_fd()) };
+        let thread = set_monitor(file).unwrap();
+
+        event!("vm", "booting");
+        event!("vm", "booted", "vcpus" => 2);
+        event!("vmm", "shutdown");
+        close();
+        event!("vm", "ignored");
+        thread.join().unwrap().unwrap();
+
+        let events: Vec<Event> = BufReader::new(consumer)
+            .lines()
+            .map(|line| serde_json::from_str(&line.unwrap()).unwrap())
+            .collect();
+        let names: Vec<(&str, &str)> = events
+            .iter()
+            .map(|e| (e.source.as_str(), e.event.as_str()))
+            .collect();
+        assert_eq!(
+            names,
+            vec![("vm", "booting"), ("vm", "booted"), ("vmm", "shutdown")]
+        );
+        assert!(events[0].properties.is_empty());
+        assert_eq!(events[1].properties["vcpus"], "2");
+        assert!(events[0].timestamp <= events[2].timestamp);
+    }
+}
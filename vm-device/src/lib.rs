@@ -1,9 +1,18 @@
+#[macro_use]
+extern crate lazy_static;
 extern crate serde;
+#[macro_use]
+extern crate serde_derive;
 extern crate thiserror;
 extern crate vm_memory;
 
+pub mod event_monitor;
 pub mod interrupt;
+pub mod metrics;
 
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
 use vm_memory::{
     Address, GuestAddress, GuestMemory, GuestMemoryMmap, GuestMemoryRegion, GuestRegionMmap,
     MemoryRegionAddress,
@@ -23,6 +32,21 @@ pub trait ExternalDmaMapping: Send + Sync {
     fn unmap(&self, iova: u64, size: u64) -> std::result::Result<(), std::io::Error>;
 }
 
+/// Trait meant for mapping host memory which isn't guest RAM, such as the
+/// BARs of an assigned device, into the guest physical address space. Each
+/// mapping takes one of the memory slots of the hypervisor.
+pub trait ExternalMemoryMapping: Send + Sync {
+    /// Map the `size` bytes at `host_addr` at `gpa`, returning the slot
+    /// taken. The host memory must stay mapped until the slot is unmapped.
+    fn map(&self, gpa: u64, size: u64, host_addr: u64) -> std::result::Result<u32, std::io::Error>;
+
+    /// Move the memory mapped in a slot to `gpa`
+    fn remap(&self, slot: u32, gpa: u64) -> std::result::Result<(), std::io::Error>;
+
+    /// Unmap the memory mapped in a slot, freeing it
+    fn unmap(&self, slot: u32) -> std::result::Result<(), std::io::Error>;
+}
+
 #[derive(Error, Debug)]
 pub enum MigratableError {
     #[error("Failed to pause migratable component: {0}")]
@@ -30,6 +54,12 @@ pub enum MigratableError {
 
     #[error("Failed to resume migratable component: {0}")]
     Resume(#[source] anyhow::Error),
+
+    #[error("Failed to snapshot migratable component: {0}")]
+    Snapshot(#[source] anyhow::Error),
+
+    #[error("Failed to restore migratable component: {0}")]
+    Restore(#[source] anyhow::Error),
 }
 
 /// A Pausable component can be paused and resumed.
@@ -41,8 +71,107 @@ pub trait Pausable {
     fn resume(&mut self) -> std::result::Result<(), MigratableError>;
 }
 
+/// A raw piece of a component state.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct SnapshotDataSection {
+    /// The section id.
+    pub id: String,
+
+    /// The section serialized data.
+    pub snapshot: Vec<u8>,
+}
+
+/// The state of a component, made of its own data sections and of the
+/// snapshots of its sub-components.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct Snapshot {
+    /// The snapshot id, which is the id of the snapshotted component.
+    pub id: String,
+
+    /// The sub-components snapshots, keyed by their id.
+    pub snapshots: BTreeMap<String, Box<Snapshot>>,
+
+    /// The component data sections, keyed by their id.
+    pub snapshot_data: HashMap<String, SnapshotDataSection>,
+}
+
+impl Snapshot {
+    /// Creates an empty snapshot for the component `id`.
+    pub fn new(id: &str) -> Self {
+        Snapshot {
+            id: id.to_string(),
+            ..Default::default()
+        }
+    }
+
+    /// Adds a sub-component snapshot.
+    pub fn add_snapshot(&mut self, snapshot: Snapshot) {
+        self.snapshots
+            .insert(snapshot.id.clone(), Box::new(snapshot));
+    }
+
+    /// Adds a data section to the component snapshot.
+    pub fn add_data_section(&mut self, section: SnapshotDataSection) {
+        self.snapshot_data.insert(section.id.clone(), section);
+    }
+}
+
+// The data section holding the state of a device.
+const SNAPSHOT_STATE: &str = "state";
+
+/// Adds `state` to `snapshot`, serialized as its state section.
+pub fn add_snapshot_state<S: Serialize>(
+    snapshot: &mut Snapshot,
+    state: &S,
+) -> std::result::Result<(), MigratableError> {
+    snapshot.add_data_section(SnapshotDataSection {
+        id: SNAPSHOT_STATE.to_string(),
+        snapshot: serde_json::to_vec(state).map_err(|e| MigratableError::Snapshot(e.into()))?,
+    });
+    Ok(())
+}
+
+/// Returns the state section of `snapshot`, if it has one.
+pub fn snapshot_state<S: DeserializeOwned>(
+    snapshot: &Snapshot,
+) -> std::result::Result<Option<S>, MigratableError> {
+    match snapshot.snapshot_data.get(SNAPSHOT_STATE) {
+        Some(section) => serde_json::from_slice(&section.snapshot)
+            .map(Some)
+            .map_err(|e| MigratableError::Restore(e.into())),
+        None => Ok(None),
+    }
+}
+
 /// A snapshotable component can be snapshoted.
-pub trait Snapshotable {}
+///
+/// The default implementation refuses to be snapshot, so that the components
+/// not saving their state yet make the whole snapshot fail rather than being
+/// silently reset on restore.
+pub trait Snapshotable {
+    /// The component id, unique among its siblings.
+    fn id(&self) -> String {
+        String::new()
+    }
+
+    /// Takes a snapshot of the component. The component is expected to be
+    /// paused.
+    fn snapshot(&self) -> std::result::Result<Snapshot, MigratableError> {
+        Err(MigratableError::Snapshot(anyhow::anyhow!(
+            "{} is not snapshotable",
+            std::any::type_name::<Self>()
+        )))
+    }
+
+    /// Restores the component from a snapshot taken by `snapshot()`.
+    fn restore(&mut self, snapshot: Snapshot) -> std::result::Result<(), MigratableError> {
+        let _ = snapshot;
+        Err(MigratableError::Restore(anyhow::anyhow!(
+            "{} is not snapshotable",
+            std::any::type_name::<Self>()
+        )))
+    }
+}
 
 /// Trait to be implemented by any component (device, CPU, RAM, etc) that
 /// can be migrated.
@@ -68,6 +197,10 @@ fn get_region_host_address_range(
 /// range within a single memory region.
 /// Return None if it is out of bounds or if addr+size overlaps a single region.
 ///
+/// Computing the pointer is safe, dereferencing it isn't: it stays valid for
+/// `size` bytes only as long as the region holding it is mapped, and the
+/// guest may change the memory it points to at any time.
+///
 /// This is a temporary vm-memory wrapper.
 pub fn get_host_address_range(
     mem: &GuestMemoryMmap,
@@ -0,0 +1,252 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Counters and gauges describing the activity of the VMM, such as the vCPU
+//! exits or the I/O performed by each device.
+//!
+//! Metrics are registered once, by name and by the id of the component they
+//! describe, in a process wide registry. The component then keeps a handle
+//! on each of its metrics, so that updating them on the hot paths boils down
+//! to a single relaxed atomic operation, never waiting on a lock. Only
+//! registering and reading the whole registry take its lock.
+
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// How the value of a metric evolves.
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MetricKind {
+    /// Only ever goes up, e.g. a number of operations.
+    Counter,
+    /// Can go up and down, e.g. a size.
+    Gauge,
+}
+
+impl MetricKind {
+    fn as_str(self) -> &'static str {
+        match self {
+            MetricKind::Counter => "counter",
+            MetricKind::Gauge => "gauge",
+        }
+    }
+}
+
+/// A single value, updated without ever blocking.
+///
+/// The default value isn't part of any registry, which lets components be
+/// instrumented unconditionally, whether or not their metrics get exported.
+#[derive(Debug, Default)]
+pub struct Metric(AtomicU64);
+
+impl Metric {
+    /// Increments the metric by one.
+    pub fn inc(&self) {
+        self.add(1);
+    }
+
+    /// Increments the metric by `value`.
+    pub fn add(&self, value: u64) {
+        self.0.fetch_add(value, Ordering::Relaxed);
+    }
+
+    /// Sets the metric to `value`, for gauges.
+    pub fn set(&self, value: u64) {
+        self.0.store(value, Ordering::Relaxed);
+    }
+
+    /// The current value of the metric.
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// The value of a metric at the time the registry was read.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct MetricSample {
+    pub name: String,
+    pub id: String,
+    pub kind: MetricKind,
+    pub value: u64,
+}
+
+struct RegisteredMetric {
+    kind: MetricKind,
+    metric: Arc<Metric>,
+}
+
+/// A set of metrics, keyed by name and component id.
+#[derive(Default)]
+pub struct Registry {
+    metrics: Mutex<BTreeMap<(&'static str, String), RegisteredMetric>>,
+}
+
+impl Registry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the counter `name` of the component `id`, registering it
+    /// first if needed.
+    pub fn counter(&self, name: &'static str, id: &str) -> Arc<Metric> {
+        self.register(name, id, MetricKind::Counter)
+    }
+
+    /// Returns the gauge `name` of the component `id`, registering it first
+    /// if needed.
+    pub fn gauge(&self, name: &'static str, id: &str) -> Arc<Metric> {
+        self.register(name, id, MetricKind::Gauge)
+    }
+
+    // A component registered again, e.g. a device which has been unplugged
+    // and plugged back, keeps on updating the same metrics, so that counters
+    // never go backwards.
+    fn register(&self, name: &'static str, id: &str, kind: MetricKind) -> Arc<Metric> {
+        let mut metrics = self.metrics.lock().unwrap();
+        let registered =
+            metrics
+                .entry((name, id.to_string()))
+                .or_insert_with(|| RegisteredMetric {
+                    kind,
+                    metric: Arc::new(Metric::default()),
+                });
+        Arc::clone(&registered.metric)
+    }
+
+    /// The current value of every metric, sorted by name and id.
+    pub fn samples(&self) -> Vec<MetricSample> {
+        self.metrics
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|((name, id), registered)| MetricSample {
+                name: (*name).to_string(),
+                id: id.clone(),
+                kind: registered.kind,
+                value: registered.metric.get(),
+            })
+            .collect()
+    }
+
+    /// Writes the current value of every metric in the Prometheus text
+    /// exposition format, the component id being the `id` label.
+    pub fn write_prometheus<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        let mut current = None;
+        for sample in self.samples() {
+            if current.as_ref() != Some(&sample.name) {
+                writeln!(
+                    w,
+                    "# TYPE {}{} {}",
+                    PROMETHEUS_PREFIX,
+                    sample.name,
+                    sample.kind.as_str()
+                )?;
+                current = Some(sample.name.clone());
+            }
+            writeln!(
+                w,
+                "{}{}{{id=\"{}\"}} {}",
+                PROMETHEUS_PREFIX,
+                sample.name,
+                escape_label_value(&sample.id),
+                sample.value
+            )?;
+        }
+        Ok(())
+    }
+}
+
+const PROMETHEUS_PREFIX: &str = "cloud_hypervisor_";
+
+fn escape_label_value(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '"' => escaped.push_str("\\\""),
+            '\n' => escaped.push_str("\\n"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+lazy_static! {
+    static ref REGISTRY: Registry = Registry::new();
+}
+
+/// The registry every component of the VMM registers its metrics in.
+pub fn registry() -> &'static Registry {
+    &REGISTRY
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_register() {
+        let registry = Registry::new();
+
+        let exits = registry.counter("vcpu_exits", "0");
+        exits.inc();
+        exits.add(2);
+        // Registering again returns the same metric.
+        assert_eq!(registry.counter("vcpu_exits", "0").get(), 3);
+        assert_eq!(registry.counter("vcpu_exits", "1").get(), 0);
+
+        let size = registry.gauge("balloon_actual_bytes", "balloon");
+        size.set(4096);
+        size.set(1024);
+
+        assert_eq!(
+            registry.samples(),
+            vec![
+                MetricSample {
+                    name: "balloon_actual_bytes".to_string(),
+                    id: "balloon".to_string(),
+                    kind: MetricKind::Gauge,
+                    value: 1024,
+                },
+                MetricSample {
+                    name: "vcpu_exits".to_string(),
+                    id: "0".to_string(),
+                    kind: MetricKind::Counter,
+                    value: 3,
+                },
+                MetricSample {
+                    name: "vcpu_exits".to_string(),
+                    id: "1".to_string(),
+                    kind: MetricKind::Counter,
+                    value: 0,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_write_prometheus() {
+        let registry = Registry::new();
+        registry
+            .counter("block_read_bytes", "/tmp/disk.img")
+            .add(512);
+        registry.counter("block_read_bytes", "\"odd\\name\"").add(8);
+        registry
+            .gauge("balloon_actual_bytes", "balloon")
+            .set(1 << 20);
+
+        let mut text = Vec::new();
+        registry.write_prometheus(&mut text).unwrap();
+        assert_eq!(
+            String::from_utf8(text).unwrap(),
+            "# TYPE cloud_hypervisor_balloon_actual_bytes gauge\n\
+             cloud_hypervisor_balloon_actual_bytes{id=\"balloon\"} 1048576\n\
+             # TYPE cloud_hypervisor_block_read_bytes counter\n\
+             cloud_hypervisor_block_read_bytes{id=\"\\\"odd\\\\name\\\"\"} 8\n\
+             cloud_hypervisor_block_read_bytes{id=\"/tmp/disk.img\"} 512\n"
+        );
+    }
+}
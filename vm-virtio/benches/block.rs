@@ -0,0 +1,141 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+
+// 4KiB random reads from a raw disk image, executed synchronously as the
+// block device does by default, or submitted through io_uring. Each
+// iteration processes a batch of requests, the way the device handles a
+// guest notification, so the throughput is reported in IOPS.
+//
+// The io_uring variant needs the feature to be enabled:
+//   cargo bench -p vm-virtio --features io_uring --bench block
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use std::io::Write;
+use std::os::unix::io::AsRawFd;
+use virtio_bindings::bindings::virtio_blk::VIRTIO_BLK_T_IN;
+use vm_memory::{Address, Bytes, GuestAddress, GuestMemoryMmap};
+use vm_virtio::testing::{create_guest_memory, Buffer, Virtqueue, VirtqueueBuilder};
+use vm_virtio::{IoUringDisk, Queue, RawFile, Request};
+
+const QUEUE_SIZE: u16 = 256;
+const DISK_SIZE: u64 = 64 << 20;
+const BLOCK_SIZE: u32 = 4096;
+const SECTOR_SIZE: u64 = 512;
+
+const HEADERS_START: GuestAddress = GuestAddress(0x10_0000);
+const STATUS_START: GuestAddress = GuestAddress(0x20_0000);
+const DATA_START: GuestAddress = GuestAddress(0x100_0000);
+
+// Small LCG, so that both variants read the same sectors.
+struct Sectors(u64);
+
+impl Iterator for Sectors {
+    type Item = u64;
+
+    fn next(&mut self) -> Option<u64> {
+        self.0 = self
+            .0
+            .wrapping_mul(6_364_136_223_846_793_005)
+            .wrapping_add(1_442_695_040_888_963_407);
+        let blocks = DISK_SIZE / u64::from(BLOCK_SIZE);
+        Some((self.0 >> 33) % blocks * u64::from(BLOCK_SIZE) / SECTOR_SIZE)
+    }
+}
+
+fn create_disk() -> tempfile::NamedTempFile {
+    let mut disk = tempfile::NamedTempFile::new().unwrap();
+    let chunk: Vec<u8> = (0..1 << 20).map(|i| i as u8).collect();
+    for _ in 0..DISK_SIZE / chunk.len() as u64 {
+        disk.write_all(&chunk).unwrap();
+    }
+    disk.as_file().sync_all().unwrap();
+    disk
+}
+
+fn add_reads(mem: &GuestMemoryMmap, vq: &mut Virtqueue, sectors: &mut Sectors, count: u16) {
+    for i in 0..count {
+        let header = HEADERS_START.unchecked_add(u64::from(i) * 16);
+        mem.write_obj(VIRTIO_BLK_T_IN, header).unwrap();
+        mem.write_obj(sectors.next().unwrap(), header.unchecked_add(8))
+            .unwrap();
+
+        let data = DATA_START.unchecked_add(u64::from(i) * u64::from(BLOCK_SIZE));
+        let status = STATUS_START.unchecked_add(u64::from(i));
+        vq.add_chain(&[
+            Buffer::readable(header, 16),
+            Buffer::writable(data, BLOCK_SIZE),
+            Buffer::writable(status, 1),
+        ]);
+    }
+}
+
+fn sync_reads(mem: &GuestMemoryMmap, queue: &mut Queue, disk: &mut RawFile) {
+    let nsectors = DISK_SIZE / SECTOR_SIZE;
+    let mut used = Vec::new();
+    for head in queue.iter(mem) {
+        let index = head.index;
+        let mut request = Request::parse(head).unwrap();
+        request.execute(disk, nsectors, &[]).unwrap();
+        used.push((index, request.complete(0).unwrap()));
+    }
+    for (index, len) in used {
+        queue.add_used(mem, index, len);
+    }
+}
+
+fn io_uring_reads(mem: &GuestMemoryMmap, queue: &mut Queue, disk: &mut IoUringDisk) {
+    let nsectors = DISK_SIZE / SECTOR_SIZE;
+    let mut submitted = 0;
+    for head in queue.iter(mem) {
+        let index = head.index;
+        let request = Request::parse(head).unwrap();
+        assert!(disk.queue(&request, index, nsectors).unwrap());
+        submitted += 1;
+    }
+
+    while submitted > 0 {
+        disk.submit(1).unwrap();
+        for (index, len) in disk.complete(mem) {
+            queue.add_used(mem, index, len);
+            submitted -= 1;
+        }
+    }
+}
+
+fn random_reads(c: &mut Criterion) {
+    let mem = create_guest_memory(0x200_0000);
+    let disk = create_disk();
+
+    let mut group = c.benchmark_group("random_reads_4k");
+    for batch in [1u16, 16, 64].iter() {
+        group.throughput(Throughput::Elements(u64::from(*batch)));
+
+        let mut vq = VirtqueueBuilder::new(&mem, QUEUE_SIZE).build();
+        let mut queue = vq.create_queue();
+        let mut sectors = Sectors(0);
+        let mut raw_disk = RawFile::new(disk.reopen().unwrap(), false);
+        group.bench_with_input(BenchmarkId::new("sync", batch), batch, |b, batch| {
+            b.iter(|| {
+                add_reads(&mem, &mut vq, &mut sectors, *batch);
+                sync_reads(&mem, &mut queue, &mut raw_disk);
+            })
+        });
+
+        let mut vq = VirtqueueBuilder::new(&mem, QUEUE_SIZE).build();
+        let mut queue = vq.create_queue();
+        let mut sectors = Sectors(0);
+        let mut io_uring_disk =
+            IoUringDisk::new(disk.as_file().as_raw_fd(), u32::from(QUEUE_SIZE)).unwrap();
+        group.bench_with_input(BenchmarkId::new("io_uring", batch), batch, |b, batch| {
+            b.iter(|| {
+                add_reads(&mem, &mut vq, &mut sectors, *batch);
+                io_uring_reads(&mem, &mut queue, &mut io_uring_disk);
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, random_reads);
+criterion_main!(benches);
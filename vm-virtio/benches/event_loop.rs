@@ -0,0 +1,76 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+
+// Latency of a device event, from the notification to the handler having
+// run, when the handler has a loop of its own as opposed to sharing the loop
+// with other devices.
+//
+// To spot regressions, record a baseline before a change and compare:
+//   cargo bench -p vm-virtio --bench event_loop -- --save-baseline before
+//   cargo bench -p vm-virtio --bench event_loop -- --baseline before
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::result;
+use vm_virtio::event_loop::{DeviceEventLoop, EpollHandler};
+use vm_virtio::{DeviceEventT, Error as DeviceError};
+use vmm_sys_util::eventfd::EventFd;
+
+const NOTIFY_EVENT: DeviceEventT = 0;
+
+// Acknowledges each notification, as a device signaling the guest would.
+struct EchoHandler {
+    notify_evt: EventFd,
+    ack_evt: EventFd,
+}
+
+impl EpollHandler for EchoHandler {
+    fn events(&self) -> Vec<(RawFd, DeviceEventT)> {
+        vec![(self.notify_evt.as_raw_fd(), NOTIFY_EVENT)]
+    }
+
+    fn handle_event(&mut self, _event: DeviceEventT) -> result::Result<(), DeviceError> {
+        let value = self.notify_evt.read().map_err(DeviceError::IoError)?;
+        self.ack_evt.write(value).map_err(DeviceError::IoError)
+    }
+}
+
+fn echo_handler() -> (EventFd, EventFd, Box<dyn EpollHandler>) {
+    let notify_evt = EventFd::new(libc::EFD_NONBLOCK).unwrap();
+    // Blocking, for the benchmark to wait on it.
+    let ack_evt = EventFd::new(0).unwrap();
+    let handler = EchoHandler {
+        notify_evt: notify_evt.try_clone().unwrap(),
+        ack_evt: ack_evt.try_clone().unwrap(),
+    };
+    (notify_evt, ack_evt, Box::new(handler))
+}
+
+fn event_latency(c: &mut Criterion) {
+    let mut group = c.benchmark_group("event_latency");
+    // A single device on its loop, as a device with a dedicated thread, then
+    // sharing the loop with idle devices.
+    for devices in [1usize, 16, 64].iter() {
+        let event_loop = DeviceEventLoop::new("bench_devices").unwrap();
+        let (notify_evt, ack_evt, handler) = echo_handler();
+        let _handle = event_loop.add(handler).unwrap();
+        let _idle: Vec<_> = (1..*devices)
+            .map(|_| {
+                let (notify_evt, ack_evt, handler) = echo_handler();
+                (notify_evt, ack_evt, event_loop.add(handler).unwrap())
+            })
+            .collect();
+
+        group.bench_with_input(BenchmarkId::from_parameter(devices), devices, |b, _| {
+            b.iter(|| {
+                notify_evt.write(1).unwrap();
+                ack_evt.read().unwrap();
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, event_latency);
+criterion_main!(benches);
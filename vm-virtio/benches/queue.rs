@@ -0,0 +1,56 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+
+// Queue processing, as done by the device side for each guest notification:
+// pop every available chain, walk its descriptors and return it through the
+// used ring.
+//
+// Queue doesn't implement VIRTIO_RING_F_EVENT_IDX, so there is no event-idx
+// variant to compare against yet.
+//
+// To spot regressions, record a baseline before a change and compare:
+//   cargo bench -p vm-virtio -- --save-baseline before
+//   cargo bench -p vm-virtio -- --baseline before
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use vm_memory::GuestAddress;
+use vm_virtio::testing::{create_guest_memory, Buffer, VirtqueueBuilder};
+
+const QUEUE_SIZE: u16 = 256;
+
+fn queue_chains(c: &mut Criterion) {
+    let mem = create_guest_memory(0x10_0000);
+    let header = Buffer::readable(GuestAddress(0x8_0000), 16);
+    let data = Buffer::writable(GuestAddress(0x9_0000), 4096);
+    let status = Buffer::writable(GuestAddress(0xa_0000), 1);
+
+    let mut group = c.benchmark_group("queue_chains");
+    for chains in [1u16, 16, 128].iter() {
+        let mut vq = VirtqueueBuilder::new(&mem, QUEUE_SIZE).build();
+        let mut queue = vq.create_queue();
+
+        group.throughput(Throughput::Elements(u64::from(*chains)));
+        group.bench_with_input(BenchmarkId::from_parameter(chains), chains, |b, chains| {
+            b.iter(|| {
+                // Three descriptors per chain, as for a block request.
+                for _ in 0..*chains {
+                    vq.add_chain(&[header, data, status]);
+                }
+
+                let mut used = Vec::with_capacity(*chains as usize);
+                for head in queue.iter(&mem) {
+                    let len: u32 = head.clone().into_iter().map(|d| d.len).sum();
+                    used.push((head.index, len));
+                }
+                for (index, len) in used {
+                    queue.add_used(&mem, index, len);
+                }
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, queue_chains);
+criterion_main!(benches);
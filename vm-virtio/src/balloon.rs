@@ -0,0 +1,925 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use super::Error as DeviceError;
+use super::{
+    ActivateError, ActivateResult, DeviceEventT, Queue, VirtioDevice, VirtioDeviceType,
+    VIRTIO_F_VERSION_1,
+};
+use crate::{join_epoll_threads, Reader, VirtioInterrupt, VirtioInterruptType};
+use arc_swap::ArcSwap;
+use epoll;
+use libc::EFD_NONBLOCK;
+use std::cmp;
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+use std::mem::size_of;
+use std::os::unix::io::AsRawFd;
+use std::result;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use vm_device::metrics::{self, Metric};
+use vm_device::{Migratable, MigratableError, Pausable, Snapshotable};
+use vm_memory::{Address, ByteValued, GuestAddress, GuestMemoryMmap};
+use vmm_sys_util::eventfd::EventFd;
+use vmm_sys_util::timerfd::TimerFd;
+
+const QUEUE_SIZE: u16 = 128;
+// Inflate and deflate queues, followed by the statistics one and the free
+// page reporting one, when enabled.
+const QUEUE_SIZES: &[u16] = &[QUEUE_SIZE, QUEUE_SIZE, QUEUE_SIZE, QUEUE_SIZE];
+const INFLATE_QUEUE: usize = 0;
+const DEFLATE_QUEUE: usize = 1;
+const STATS_QUEUE: usize = 2;
+
+// The driver asks for statistics through a dedicated queue.
+const VIRTIO_BALLOON_F_STATS_VQ: u32 = 1;
+// The driver deflates the balloon when the guest runs out of memory.
+const VIRTIO_BALLOON_F_DEFLATE_ON_OOM: u32 = 2;
+// The driver reports the free pages of the guest through a dedicated queue.
+const VIRTIO_BALLOON_F_REPORTING: u32 = 5;
+
+// Balloon pages are 4KiB, whatever the guest page size.
+const VIRTIO_BALLOON_PFN_SHIFT: u64 = 12;
+
+// Statistics tags, from linux/virtio_balloon.h.
+const STATS_TAGS: &[&str] = &[
+    "swap_in",
+    "swap_out",
+    "major_faults",
+    "minor_faults",
+    "free_memory",
+    "total_memory",
+    "available_memory",
+    "disk_caches",
+    "hugetlb_allocations",
+    "hugetlb_failures",
+];
+
+// New descriptors are pending on the inflate queue.
+const INFLATE_QUEUE_EVENT: DeviceEventT = 0;
+// New descriptors are pending on the deflate queue.
+const DEFLATE_QUEUE_EVENT: DeviceEventT = 1;
+// The driver gave the statistics back.
+const STATS_QUEUE_EVENT: DeviceEventT = 2;
+// Time to ask the driver for new statistics.
+const STATS_TIMER_EVENT: DeviceEventT = 3;
+// The device has been dropped.
+const KILL_EVENT: DeviceEventT = 4;
+// The device should be paused.
+const PAUSE_EVENT: DeviceEventT = 5;
+// The driver reported free pages.
+const REPORTING_QUEUE_EVENT: DeviceEventT = 6;
+
+/// The latest memory statistics of the guest, by name, e.g. `free_memory`.
+/// Only the ones reported by the driver are present.
+pub type BalloonStats = BTreeMap<String, u64>;
+
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C, packed)]
+struct VirtioBalloonConfig {
+    num_pages: u32,
+    actual: u32,
+}
+
+unsafe impl ByteValued for VirtioBalloonConfig {}
+
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C, packed)]
+struct VirtioBalloonStat {
+    tag: u16,
+    val: u64,
+}
+
+unsafe impl ByteValued for VirtioBalloonStat {}
+
+// Parses the tag/value array filled by the driver, skipping the tags it
+// doesn't know about.
+fn parse_stats(mut reader: Reader) -> io::Result<BalloonStats> {
+    let mut stats = BalloonStats::new();
+    while reader.available_bytes() >= size_of::<VirtioBalloonStat>() {
+        let stat: VirtioBalloonStat = reader.read_obj()?;
+        let (tag, val) = (stat.tag, stat.val);
+        match STATS_TAGS.get(tag as usize) {
+            Some(name) => {
+                stats.insert((*name).to_string(), val);
+            }
+            None => debug!("Unknown balloon statistics tag {}", tag),
+        }
+    }
+
+    Ok(stats)
+}
+
+fn host_page_size() -> usize {
+    // Safe because sysconf() has no side effect.
+    match unsafe { libc::sysconf(libc::_SC_PAGESIZE) } {
+        size if size > 0 => size as usize,
+        _ => 4096,
+    }
+}
+
+// Gives the memory of a guest RAM range back to the host, the guest reading
+// zeros from it afterwards. The range must be within a single RAM region.
+// Only the host pages fully within the range are released, as madvise()
+// works on whole pages and would otherwise zero the memory around it.
+fn release_range(mem: &GuestMemoryMmap, addr: GuestAddress, size: usize) -> io::Result<()> {
+    let host_addr = vm_device::get_host_address_range(mem, addr, size)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "not within the guest RAM"))?;
+
+    let page_mask = host_page_size() - 1;
+    let start = (host_addr as usize + page_mask) & !page_mask;
+    let end = (host_addr as usize + size) & !page_mask;
+    if end <= start {
+        return Ok(());
+    }
+
+    // Safe because the range is within the guest memory mapping, which only
+    // gets zeroed.
+    let ret =
+        unsafe { libc::madvise(start as *mut libc::c_void, end - start, libc::MADV_DONTNEED) };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+// Gives the memory of a page put in the balloon back to the host.
+fn release_page(mem: &GuestMemoryMmap, pfn: u32) {
+    let addr = GuestAddress(u64::from(pfn) << VIRTIO_BALLOON_PFN_SHIFT);
+    if let Err(e) = release_range(mem, addr, 1 << VIRTIO_BALLOON_PFN_SHIFT) {
+        error!("Failed to release balloon page 0x{:x}: {}", pfn, e);
+    }
+}
+
+struct BalloonEpollHandler {
+    queues: Vec<Queue>,
+    mem: Arc<ArcSwap<GuestMemoryMmap>>,
+    interrupt_cb: Arc<dyn VirtioInterrupt>,
+    queue_evts: Vec<EventFd>,
+    stats_timer: Option<TimerFd>,
+    // The statistics buffer, held until the next request.
+    stats_desc_index: Option<u16>,
+    stats: Arc<Mutex<BalloonStats>>,
+    reporting_queue: Option<usize>,
+    reported_metric: Arc<Metric>,
+    kill_evt: EventFd,
+    pause_evt: EventFd,
+}
+
+impl BalloonEpollHandler {
+    // Pages going in the balloon are released, the ones going out are
+    // faulted back in by the guest when it uses them again.
+    fn process_page_queue(&mut self, queue_index: usize) -> bool {
+        let queue = &mut self.queues[queue_index];
+
+        let mut used_desc_heads = Vec::new();
+        let mem = self.mem.load();
+        for avail_desc in queue.iter(&mem) {
+            let index = avail_desc.index;
+
+            match Reader::new(avail_desc) {
+                Ok(mut reader) => {
+                    while reader.available_bytes() >= size_of::<u32>() {
+                        match reader.read_obj::<u32>() {
+                            Ok(pfn) if queue_index == INFLATE_QUEUE => release_page(&mem, pfn),
+                            Ok(_) => {}
+                            Err(e) => {
+                                error!("Failed to read balloon page: {:?}", e);
+                                break;
+                            }
+                        }
+                    }
+                }
+                Err(e) => error!("Invalid descriptor chain: {:?}", e),
+            }
+
+            used_desc_heads.push(index);
+        }
+
+        for &desc_index in &used_desc_heads {
+            queue.add_used(&mem, desc_index, 0);
+        }
+        !used_desc_heads.is_empty()
+    }
+
+    // Records the statistics the driver gave back, keeping the buffer for
+    // the next request.
+    fn process_stats_queue(&mut self) -> bool {
+        let queue = &mut self.queues[STATS_QUEUE];
+
+        let mut used_desc_heads = Vec::new();
+        let mem = self.mem.load();
+        for avail_desc in queue.iter(&mem) {
+            let index = avail_desc.index;
+
+            match Reader::new(avail_desc) {
+                Ok(reader) => match parse_stats(reader) {
+                    Ok(stats) => *self.stats.lock().unwrap() = stats,
+                    Err(e) => error!("Failed to parse balloon statistics: {:?}", e),
+                },
+                Err(e) => error!("Invalid descriptor chain: {:?}", e),
+            }
+
+            // Only one buffer is expected, a previous one is given back.
+            if let Some(prev) = self.stats_desc_index.replace(index) {
+                used_desc_heads.push(prev);
+            }
+        }
+
+        for &desc_index in &used_desc_heads {
+            queue.add_used(&mem, desc_index, 0);
+        }
+        !used_desc_heads.is_empty()
+    }
+
+    // Releases the free ranges reported by the driver, and gives the buffers
+    // back right away, the driver not reusing the pages until then.
+    fn process_reporting_queue(&mut self, queue_index: usize) -> bool {
+        let queue = &mut self.queues[queue_index];
+
+        let mut used_desc_heads = Vec::new();
+        let mem = self.mem.load();
+        for avail_desc in queue.iter(&mem) {
+            let index = avail_desc.index;
+
+            for desc in avail_desc {
+                match release_range(&mem, desc.addr, desc.len as usize) {
+                    Ok(()) => self.reported_metric.add(u64::from(desc.len)),
+                    Err(e) => error!(
+                        "Failed to release the free range 0x{:x}+0x{:x}: {}",
+                        desc.addr.raw_value(),
+                        desc.len,
+                        e
+                    ),
+                }
+            }
+
+            used_desc_heads.push(index);
+        }
+
+        for &desc_index in &used_desc_heads {
+            queue.add_used(&mem, desc_index, 0);
+        }
+        !used_desc_heads.is_empty()
+    }
+
+    // Gives the statistics buffer back, for the driver to fill it again.
+    // Nothing happens until the driver returned the previous one, so that a
+    // guest not answering doesn't get more requests.
+    fn request_stats(&mut self) -> bool {
+        match self.stats_desc_index.take() {
+            Some(desc_index) => {
+                let mem = self.mem.load();
+                self.queues[STATS_QUEUE].add_used(&mem, desc_index, 0);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn signal_used_queue(&self, queue_index: usize) -> result::Result<(), DeviceError> {
+        self.interrupt_cb
+            .trigger(&VirtioInterruptType::Queue, Some(&self.queues[queue_index]))
+            .map_err(|e| {
+                error!("Failed to signal used queue: {:?}", e);
+                DeviceError::FailedSignalingUsedQueue(e)
+            })
+    }
+
+    fn run(&mut self, paused: Arc<AtomicBool>) -> result::Result<(), DeviceError> {
+        // Create the epoll file descriptor
+        let epoll_fd = epoll::create(true).map_err(DeviceError::EpollCreateFd)?;
+
+        // Add events
+        let mut fds = vec![
+            (
+                self.queue_evts[INFLATE_QUEUE].as_raw_fd(),
+                INFLATE_QUEUE_EVENT,
+            ),
+            (
+                self.queue_evts[DEFLATE_QUEUE].as_raw_fd(),
+                DEFLATE_QUEUE_EVENT,
+            ),
+            (self.kill_evt.as_raw_fd(), KILL_EVENT),
+            (self.pause_evt.as_raw_fd(), PAUSE_EVENT),
+        ];
+        if let Some(stats_timer) = self.stats_timer.as_ref() {
+            fds.push((self.queue_evts[STATS_QUEUE].as_raw_fd(), STATS_QUEUE_EVENT));
+            fds.push((stats_timer.as_raw_fd(), STATS_TIMER_EVENT));
+        }
+        if let Some(queue_index) = self.reporting_queue {
+            fds.push((
+                self.queue_evts[queue_index].as_raw_fd(),
+                REPORTING_QUEUE_EVENT,
+            ));
+        }
+        for (fd, event) in fds {
+            epoll::ctl(
+                epoll_fd,
+                epoll::ControlOptions::EPOLL_CTL_ADD,
+                fd,
+                epoll::Event::new(epoll::Events::EPOLLIN, u64::from(event)),
+            )
+            .map_err(DeviceError::EpollCtl)?;
+        }
+
+        const EPOLL_EVENTS_LEN: usize = 100;
+        let mut events = vec![epoll::Event::new(epoll::Events::empty(), 0); EPOLL_EVENTS_LEN];
+
+        'epoll: loop {
+            let num_events = match epoll::wait(epoll_fd, -1, &mut events[..]) {
+                Ok(res) => res,
+                Err(e) => {
+                    if e.kind() == io::ErrorKind::Interrupted {
+                        // It's well defined from the epoll_wait() syscall
+                        // documentation that the epoll loop can be interrupted
+                        // before any of the requested events occurred or the
+                        // timeout expired. In both those cases, epoll_wait()
+                        // returns an error of type EINTR, but this should not
+                        // be considered as a regular error. Instead it is more
+                        // appropriate to retry, by calling into epoll_wait().
+                        continue;
+                    }
+                    return Err(DeviceError::EpollWait(e));
+                }
+            };
+
+            for event in events.iter().take(num_events) {
+                let ev_type = event.data as u16;
+
+                let (queue_index, needs_signal) = match ev_type {
+                    INFLATE_QUEUE_EVENT | DEFLATE_QUEUE_EVENT => {
+                        let queue_index = ev_type as usize;
+                        if let Err(e) = self.queue_evts[queue_index].read() {
+                            error!("Failed to get queue event: {:?}", e);
+                            break 'epoll;
+                        }
+                        (queue_index, self.process_page_queue(queue_index))
+                    }
+                    STATS_QUEUE_EVENT => {
+                        if let Err(e) = self.queue_evts[STATS_QUEUE].read() {
+                            error!("Failed to get queue event: {:?}", e);
+                            break 'epoll;
+                        }
+                        (STATS_QUEUE, self.process_stats_queue())
+                    }
+                    STATS_TIMER_EVENT => {
+                        if let Some(stats_timer) = self.stats_timer.as_mut() {
+                            if let Err(e) = stats_timer.wait() {
+                                error!("Failed to get timer event: {:?}", e);
+                                break 'epoll;
+                            }
+                        }
+                        (STATS_QUEUE, self.request_stats())
+                    }
+                    REPORTING_QUEUE_EVENT => {
+                        // Only registered with a reporting queue.
+                        let queue_index = self.reporting_queue.unwrap();
+                        if let Err(e) = self.queue_evts[queue_index].read() {
+                            error!("Failed to get queue event: {:?}", e);
+                            break 'epoll;
+                        }
+                        (queue_index, self.process_reporting_queue(queue_index))
+                    }
+                    KILL_EVENT => {
+                        debug!("KILL_EVENT received, stopping epoll loop");
+                        break 'epoll;
+                    }
+                    PAUSE_EVENT => {
+                        debug!("PAUSE_EVENT received, pausing virtio-balloon epoll loop");
+                        // We loop here to handle spurious park() returns.
+                        // Until we have not resumed, the paused boolean will
+                        // be true.
+                        while paused.load(Ordering::SeqCst) {
+                            thread::park();
+                        }
+                        continue;
+                    }
+                    _ => {
+                        error!("Unknown event for virtio-balloon");
+                        continue;
+                    }
+                };
+
+                if needs_signal {
+                    if let Err(e) = self.signal_used_queue(queue_index) {
+                        error!("Failed to signal used queue: {:?}", e);
+                        break 'epoll;
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Virtio device letting the guest give its memory back to the host, and
+/// report its memory statistics and free pages.
+pub struct Balloon {
+    kill_evt: Option<EventFd>,
+    pause_evt: Option<EventFd>,
+    avail_features: u64,
+    acked_features: u64,
+    config: VirtioBalloonConfig,
+    stats_polling_interval: u64,
+    free_page_reporting: bool,
+    stats: Arc<Mutex<BalloonStats>>,
+    queue_evts: Option<Vec<EventFd>>,
+    interrupt_cb: Option<Arc<dyn VirtioInterrupt>>,
+    epoll_threads: Option<Vec<thread::JoinHandle<result::Result<(), DeviceError>>>>,
+    paused: Arc<AtomicBool>,
+    actual_metric: Arc<Metric>,
+    reported_metric: Arc<Metric>,
+}
+
+impl Balloon {
+    /// Create a new virtio balloon device, asking the guest for `size` bytes.
+    /// The guest statistics are polled every `stats_polling_interval`
+    /// seconds, 0 disabling them. With `free_page_reporting`, the guest
+    /// reports its free pages, which are given back to the host. With
+    /// `deflate_on_oom`, the guest takes memory back from the balloon rather
+    /// than running out of it.
+    pub fn new(
+        size: u64,
+        stats_polling_interval: u64,
+        free_page_reporting: bool,
+        deflate_on_oom: bool,
+    ) -> io::Result<Balloon> {
+        let mut avail_features = 1u64 << VIRTIO_F_VERSION_1;
+        if stats_polling_interval > 0 {
+            avail_features |= 1u64 << VIRTIO_BALLOON_F_STATS_VQ;
+        }
+        if deflate_on_oom {
+            avail_features |= 1u64 << VIRTIO_BALLOON_F_DEFLATE_ON_OOM;
+        }
+        if free_page_reporting {
+            avail_features |= 1u64 << VIRTIO_BALLOON_F_REPORTING;
+        }
+
+        let config = VirtioBalloonConfig {
+            num_pages: (size >> VIRTIO_BALLOON_PFN_SHIFT) as u32,
+            actual: 0,
+        };
+
+        Ok(Balloon {
+            kill_evt: None,
+            pause_evt: None,
+            avail_features,
+            acked_features: 0u64,
+            config,
+            stats_polling_interval,
+            free_page_reporting,
+            stats: Arc::new(Mutex::new(BalloonStats::new())),
+            queue_evts: None,
+            interrupt_cb: None,
+            epoll_threads: None,
+            paused: Arc::new(AtomicBool::new(false)),
+            actual_metric: Arc::new(Metric::default()),
+            reported_metric: Arc::new(Metric::default()),
+        })
+    }
+
+    /// Exports the size of the balloon as a gauge, and the free memory
+    /// reported by the guest as a counter, under `id`.
+    pub fn set_metrics_id(&mut self, id: &str) {
+        self.actual_metric = metrics::registry().gauge("balloon_actual_bytes", id);
+        self.actual_metric.set(self.actual());
+        self.reported_metric = metrics::registry().counter("balloon_reported_bytes", id);
+    }
+
+    /// The latest statistics reported by the guest, empty until it first
+    /// answers.
+    pub fn stats(&self) -> BalloonStats {
+        self.stats.lock().unwrap().clone()
+    }
+
+    /// The memory currently in the balloon, in bytes.
+    pub fn actual(&self) -> u64 {
+        u64::from(self.config.actual) << VIRTIO_BALLOON_PFN_SHIFT
+    }
+
+    /// Asks the guest for `size` bytes instead, the driver inflating or
+    /// deflating the balloon to match.
+    pub fn set_target(&mut self, size: u64) -> io::Result<()> {
+        self.config.num_pages = (size >> VIRTIO_BALLOON_PFN_SHIFT) as u32;
+        // Before being activated, the driver reads the target on its own.
+        if let Some(interrupt_cb) = self.interrupt_cb.as_ref() {
+            interrupt_cb.signal_config_change()?;
+        }
+
+        Ok(())
+    }
+
+    fn num_queues(&self) -> usize {
+        let mut num_queues = 2;
+        if self.stats_polling_interval > 0 {
+            num_queues += 1;
+        }
+        if self.free_page_reporting {
+            num_queues += 1;
+        }
+        num_queues
+    }
+
+    // The driver only sets up the queues of the features it acked, the
+    // reporting queue coming right after the statistics one if any.
+    fn reporting_queue(&self) -> Option<usize> {
+        if self.acked_features & (1u64 << VIRTIO_BALLOON_F_REPORTING) == 0 {
+            None
+        } else if self.acked_features & (1u64 << VIRTIO_BALLOON_F_STATS_VQ) != 0 {
+            Some(STATS_QUEUE + 1)
+        } else {
+            Some(STATS_QUEUE)
+        }
+    }
+}
+
+impl Drop for Balloon {
+    fn drop(&mut self) {
+        if let Some(kill_evt) = self.kill_evt.take() {
+            // Ignore the result because there is nothing we can do about it.
+            let _ = kill_evt.write(1);
+        }
+    }
+}
+
+impl VirtioDevice for Balloon {
+    fn device_type(&self) -> u32 {
+        VirtioDeviceType::TYPE_BALLOON as u32
+    }
+
+    fn queue_max_sizes(&self) -> &[u16] {
+        &QUEUE_SIZES[..self.num_queues()]
+    }
+
+    fn features(&self) -> u64 {
+        self.avail_features
+    }
+
+    fn ack_features(&mut self, value: u64) {
+        let mut v = value;
+        // Check if the guest is ACK'ing a feature that we didn't claim to have.
+        let unrequested_features = v & !self.avail_features;
+        if unrequested_features != 0 {
+            warn!("Received acknowledge request for unknown feature.");
+
+            // Don't count these features as acked.
+            v &= !unrequested_features;
+        }
+        self.acked_features |= v;
+    }
+
+    fn read_config(&self, offset: u64, mut data: &mut [u8]) {
+        let config_slice = self.config.as_slice();
+        let config_len = config_slice.len() as u64;
+        if offset >= config_len {
+            error!("Failed to read config space");
+            return;
+        }
+        if let Some(end) = offset.checked_add(data.len() as u64) {
+            // This write can't fail, offset and end are checked against config_len.
+            data.write_all(&config_slice[offset as usize..cmp::min(end, config_len) as usize])
+                .unwrap();
+        }
+    }
+
+    // Only the number of pages actually in the balloon is written by the
+    // driver.
+    fn write_config(&mut self, offset: u64, data: &[u8]) {
+        let config_slice = self.config.as_mut_slice();
+        let data_len = data.len() as u64;
+        let config_len = config_slice.len() as u64;
+        if offset < size_of::<u32>() as u64 || offset + data_len > config_len {
+            error!("Failed to write config space");
+            return;
+        }
+        let (_, right) = config_slice.split_at_mut(offset as usize);
+        right[..data.len()].copy_from_slice(&data[..]);
+        self.actual_metric.set(self.actual());
+    }
+
+    fn activate(
+        &mut self,
+        mem: Arc<ArcSwap<GuestMemoryMmap>>,
+        interrupt_cb: Arc<dyn VirtioInterrupt>,
+        queues: Vec<Queue>,
+        queue_evts: Vec<EventFd>,
+    ) -> ActivateResult {
+        let num_queues = self.num_queues();
+        if queues.len() != num_queues || queue_evts.len() != num_queues {
+            error!(
+                "Cannot perform activate. Expected {} queue(s), got {}",
+                num_queues,
+                queues.len()
+            );
+            return Err(ActivateError::BadActivate);
+        }
+
+        let (self_kill_evt, kill_evt) = EventFd::new(EFD_NONBLOCK)
+            .and_then(|e| Ok((e.try_clone()?, e)))
+            .map_err(|e| {
+                error!("failed creating kill EventFd pair: {}", e);
+                ActivateError::BadActivate
+            })?;
+        self.kill_evt = Some(self_kill_evt);
+
+        let (self_pause_evt, pause_evt) = EventFd::new(EFD_NONBLOCK)
+            .and_then(|e| Ok((e.try_clone()?, e)))
+            .map_err(|e| {
+                error!("failed creating pause EventFd pair: {}", e);
+                ActivateError::BadActivate
+            })?;
+        self.pause_evt = Some(self_pause_evt);
+
+        // Save the interrupt EventFD as we need to return it on reset
+        // but clone it to pass into the thread.
+        self.interrupt_cb = Some(interrupt_cb.clone());
+
+        let mut tmp_queue_evts: Vec<EventFd> = Vec::new();
+        for queue_evt in queue_evts.iter() {
+            // Save the queue EventFD as we need to return it on reset
+            // but clone it to pass into the thread.
+            tmp_queue_evts.push(queue_evt.try_clone().map_err(|e| {
+                error!("failed to clone queue EventFd: {}", e);
+                ActivateError::BadActivate
+            })?);
+        }
+        self.queue_evts = Some(tmp_queue_evts);
+
+        let stats_timer = if self.acked_features & (1u64 << VIRTIO_BALLOON_F_STATS_VQ) != 0 {
+            let interval = Duration::from_secs(self.stats_polling_interval);
+            let mut timer = TimerFd::new().map_err(|e| {
+                error!("failed creating statistics timer: {}", e);
+                ActivateError::BadActivate
+            })?;
+            timer.reset(interval, Some(interval)).map_err(|e| {
+                error!("failed arming statistics timer: {}", e);
+                ActivateError::BadActivate
+            })?;
+            Some(timer)
+        } else {
+            None
+        };
+
+        let mut handler = BalloonEpollHandler {
+            queues,
+            mem,
+            interrupt_cb,
+            queue_evts,
+            stats_timer,
+            stats_desc_index: None,
+            stats: self.stats.clone(),
+            reporting_queue: self.reporting_queue(),
+            reported_metric: self.reported_metric.clone(),
+            kill_evt,
+            pause_evt,
+        };
+
+        let paused = self.paused.clone();
+        let mut epoll_threads = Vec::new();
+        thread::Builder::new()
+            .name("virtio_balloon".to_string())
+            .spawn(move || handler.run(paused))
+            .map(|thread| epoll_threads.push(thread))
+            .map_err(|e| {
+                error!("failed to clone the virtio-balloon epoll thread: {}", e);
+                ActivateError::BadActivate
+            })?;
+
+        self.epoll_threads = Some(epoll_threads);
+
+        Ok(())
+    }
+
+    fn reset(&mut self) -> Option<(Arc<dyn VirtioInterrupt>, Vec<EventFd>)> {
+        // We first must resume the virtio thread if it was paused.
+        if self.pause_evt.take().is_some() {
+            self.resume().ok()?;
+        }
+
+        // Then kill it.
+        if let Some(kill_evt) = self.kill_evt.take() {
+            // Ignore the result because there is nothing we can do about it.
+            let _ = kill_evt.write(1);
+        }
+        join_epoll_threads(self.epoll_threads.take());
+
+        self.acked_features = 0;
+        // The guest took all of its memory back.
+        self.config.actual = 0;
+        self.actual_metric.set(0);
+
+        // Return the interrupt and queue EventFDs
+        Some((
+            self.interrupt_cb.take().unwrap(),
+            self.queue_evts.take().unwrap(),
+        ))
+    }
+}
+
+virtio_pausable!(Balloon);
+impl Snapshotable for Balloon {}
+impl Migratable for Balloon {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{create_guest_memory, Buffer, CountingInterrupt, VirtqueueBuilder};
+    use vm_memory::{Address, Bytes};
+
+    fn create_handler(mem: &GuestMemoryMmap, queues: Vec<Queue>) -> BalloonEpollHandler {
+        BalloonEpollHandler {
+            queue_evts: queues
+                .iter()
+                .map(|_| EventFd::new(EFD_NONBLOCK).unwrap())
+                .collect(),
+            queues,
+            mem: Arc::new(ArcSwap::new(Arc::new(mem.clone()))),
+            interrupt_cb: Arc::new(CountingInterrupt::default()),
+            stats_timer: None,
+            stats_desc_index: None,
+            stats: Arc::new(Mutex::new(BalloonStats::new())),
+            reporting_queue: None,
+            reported_metric: Arc::new(Metric::default()),
+            kill_evt: EventFd::new(EFD_NONBLOCK).unwrap(),
+            pause_evt: EventFd::new(EFD_NONBLOCK).unwrap(),
+        }
+    }
+
+    fn write_stats(mem: &GuestMemoryMmap, addr: GuestAddress, stats: &[(u16, u64)]) {
+        for (i, (tag, val)) in stats.iter().enumerate() {
+            let stat = VirtioBalloonStat {
+                tag: *tag,
+                val: *val,
+            };
+            mem.write_obj(
+                stat,
+                addr.unchecked_add((i * size_of::<VirtioBalloonStat>()) as u64),
+            )
+            .unwrap();
+        }
+    }
+
+    #[test]
+    fn test_inflate() {
+        let mem = create_guest_memory(0x20000);
+        let mut vqs: Vec<_> = (0..2)
+            .map(|i| {
+                VirtqueueBuilder::new(&mem, QUEUE_SIZE)
+                    .start(GuestAddress(0x1000 + i * 0x2000))
+                    .build()
+            })
+            .collect();
+        let queues = vqs.iter().map(|vq| vq.create_queue()).collect();
+
+        // The page at 0x10000 goes in the balloon, and gets zeroed.
+        mem.write_obj(0xffu8, GuestAddress(0x10000)).unwrap();
+        mem.write_obj(0x10u32, GuestAddress(0x8000)).unwrap();
+        let head = vqs[INFLATE_QUEUE].add_chain(&[Buffer::readable(GuestAddress(0x8000), 4)]);
+
+        let mut handler = create_handler(&mem, queues);
+        assert!(handler.process_page_queue(INFLATE_QUEUE));
+        assert_eq!(vqs[INFLATE_QUEUE].used_elem(0), (u32::from(head), 0));
+        assert_eq!(mem.read_obj::<u8>(GuestAddress(0x10000)).unwrap(), 0);
+
+        // Deflating leaves the memory alone.
+        mem.write_obj(0xffu8, GuestAddress(0x10000)).unwrap();
+        vqs[DEFLATE_QUEUE].add_chain(&[Buffer::readable(GuestAddress(0x8000), 4)]);
+        assert!(handler.process_page_queue(DEFLATE_QUEUE));
+        assert_eq!(mem.read_obj::<u8>(GuestAddress(0x10000)).unwrap(), 0xff);
+    }
+
+    #[test]
+    fn test_free_page_reporting() {
+        let mem = create_guest_memory(0x20000);
+        let mut vq = VirtqueueBuilder::new(&mem, QUEUE_SIZE)
+            .start(GuestAddress(0x1000))
+            .build();
+        let queues = vec![vq.create_queue()];
+        let mut handler = create_handler(&mem, queues);
+
+        // Both free ranges are released, the one past the guest RAM is
+        // skipped, as is the memory around the one smaller than a page, and
+        // the buffers are given back.
+        mem.write_obj(0xffu8, GuestAddress(0x10000)).unwrap();
+        mem.write_obj(0xffu8, GuestAddress(0x13000)).unwrap();
+        mem.write_obj(0xffu8, GuestAddress(0x18000)).unwrap();
+        let head = vq.add_chain(&[
+            Buffer::writable(GuestAddress(0x10000), 0x2000),
+            Buffer::writable(GuestAddress(0x1f000), 0x2000),
+            Buffer::writable(GuestAddress(0x13000), 0x1000),
+            Buffer::writable(GuestAddress(0x17c00), 0x800),
+        ]);
+        assert!(handler.process_reporting_queue(0));
+        assert_eq!(vq.used_elem(0), (u32::from(head), 0));
+        assert_eq!(mem.read_obj::<u8>(GuestAddress(0x10000)).unwrap(), 0);
+        assert_eq!(mem.read_obj::<u8>(GuestAddress(0x13000)).unwrap(), 0);
+        assert_eq!(mem.read_obj::<u8>(GuestAddress(0x18000)).unwrap(), 0xff);
+        assert_eq!(handler.reported_metric.get(), 0x3800);
+
+        assert!(!handler.process_reporting_queue(0));
+    }
+
+    #[test]
+    fn test_stats() {
+        let mem = create_guest_memory(0x20000);
+        let mut vqs: Vec<_> = (0..3)
+            .map(|i| {
+                VirtqueueBuilder::new(&mem, QUEUE_SIZE)
+                    .start(GuestAddress(0x1000 + i * 0x2000))
+                    .build()
+            })
+            .collect();
+        let queues = vqs.iter().map(|vq| vq.create_queue()).collect();
+        let mut handler = create_handler(&mem, queues);
+
+        // Nothing to ask for before the driver provides the buffer.
+        assert!(!handler.request_stats());
+
+        // Free and available memory, and a tag from a newer driver.
+        write_stats(
+            &mem,
+            GuestAddress(0x8000),
+            &[(4, 0x1000_0000), (6, 0x2000_0000), (42, 1)],
+        );
+        let head = vqs[STATS_QUEUE].add_chain(&[Buffer::readable(GuestAddress(0x8000), 30)]);
+        assert!(!handler.process_stats_queue());
+
+        let mut expected = BalloonStats::new();
+        expected.insert("free_memory".to_string(), 0x1000_0000);
+        expected.insert("available_memory".to_string(), 0x2000_0000);
+        assert_eq!(*handler.stats.lock().unwrap(), expected);
+
+        // The buffer is given back once, until the driver returns it.
+        assert!(handler.request_stats());
+        assert_eq!(vqs[STATS_QUEUE].used_elem(0), (u32::from(head), 0));
+        assert!(!handler.request_stats());
+
+        write_stats(&mem, GuestAddress(0x8000), &[(2, 7)]);
+        vqs[STATS_QUEUE].add_chain(&[Buffer::readable(GuestAddress(0x8000), 10)]);
+        handler.process_stats_queue();
+        assert_eq!(handler.stats.lock().unwrap().get("major_faults"), Some(&7));
+        assert_eq!(handler.stats.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_config() {
+        let mut balloon = Balloon::new(0x10_0000, 0, false, false).unwrap();
+        balloon.set_metrics_id("test_config");
+        assert_eq!(balloon.queue_max_sizes().len(), 2);
+        assert_eq!(balloon.features() & (1 << VIRTIO_BALLOON_F_STATS_VQ), 0);
+
+        let mut data = [0u8; 4];
+        balloon.read_config(0, &mut data);
+        assert_eq!(u32::from_le_bytes(data), 0x100);
+
+        // The number of pages asked for is read-only.
+        balloon.write_config(0, &[0u8; 4]);
+        balloon.write_config(4, &0x80u32.to_le_bytes());
+        balloon.read_config(0, &mut data);
+        assert_eq!(u32::from_le_bytes(data), 0x100);
+        assert_eq!(balloon.actual(), 0x8_0000);
+
+        balloon.set_target(0x20_0000).unwrap();
+        balloon.read_config(0, &mut data);
+        assert_eq!(u32::from_le_bytes(data), 0x200);
+        assert_eq!(
+            metrics::registry()
+                .gauge("balloon_actual_bytes", "test_config")
+                .get(),
+            0x8_0000
+        );
+
+        let balloon = Balloon::new(0, 5, false, false).unwrap();
+        assert_eq!(balloon.queue_max_sizes().len(), 3);
+        assert_ne!(balloon.features() & (1 << VIRTIO_BALLOON_F_STATS_VQ), 0);
+        assert_eq!(balloon.reporting_queue(), None);
+
+        // The reporting queue comes after the statistics one, when the
+        // driver acked both.
+        let mut balloon = Balloon::new(0, 5, true, false).unwrap();
+        assert_eq!(balloon.queue_max_sizes().len(), 4);
+        assert_ne!(balloon.features() & (1 << VIRTIO_BALLOON_F_REPORTING), 0);
+        assert_eq!(balloon.reporting_queue(), None);
+        balloon.ack_features(1 << VIRTIO_BALLOON_F_REPORTING);
+        assert_eq!(balloon.reporting_queue(), Some(2));
+        balloon.ack_features(1 << VIRTIO_BALLOON_F_STATS_VQ);
+        assert_eq!(balloon.reporting_queue(), Some(3));
+        let mut balloon = Balloon::new(0, 0, true, false).unwrap();
+        balloon.ack_features(balloon.features());
+        assert_eq!(balloon.reporting_queue(), Some(2));
+
+        let balloon = Balloon::new(0, 0, false, true).unwrap();
+        assert_eq!(balloon.queue_max_sizes().len(), 2);
+        assert_ne!(
+            balloon.features() & (1 << VIRTIO_BALLOON_F_DEFLATE_ON_OOM),
+            0
+        );
+    }
+}
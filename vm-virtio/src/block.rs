@@ -9,17 +9,22 @@
 // SPDX-License-Identifier: Apache-2.0 AND BSD-3-Clause
 
 use super::Error as DeviceError;
+#[cfg(feature = "io_uring")]
+use super::{block_io_uring_is_supported, IoUringDisk};
 use super::{
-    ActivateError, ActivateResult, DescriptorChain, DeviceEventT, Queue, VirtioDevice,
-    VirtioDeviceType, VirtioInterruptType,
+    ActivateError, ActivateResult, DescriptorChain, DeviceEventT, Queue, Reader, VirtioDevice,
+    VirtioDeviceType, VirtioInterruptType, Writer,
 };
-use crate::VirtioInterrupt;
+use crate::descriptor_utils::Error as DescriptorError;
+use crate::device::{add_snapshot_state, snapshot_state};
+use crate::{join_epoll_threads, VirtioInterrupt};
 use arc_swap::ArcSwap;
 use epoll;
 use libc::{c_void, EFD_NONBLOCK};
 use std::alloc::{alloc_zeroed, dealloc, Layout};
 use std::cmp;
 use std::convert::TryInto;
+use std::fmt;
 use std::fs::{File, Metadata};
 use std::io::{self, Read, Seek, SeekFrom, Write};
 use std::ops::DerefMut;
@@ -28,12 +33,15 @@ use std::os::unix::io::{AsRawFd, RawFd};
 use std::path::PathBuf;
 use std::result;
 use std::slice;
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::{channel, sync_channel, RecvTimeoutError, Sender, SyncSender, TrySendError};
 use std::sync::{Arc, Mutex};
 use std::thread;
+use std::time::Duration;
 use virtio_bindings::bindings::virtio_blk::*;
-use vm_device::{Migratable, MigratableError, Pausable, Snapshotable};
-use vm_memory::{ByteValued, Bytes, GuestAddress, GuestMemory, GuestMemoryError, GuestMemoryMmap};
+use vm_device::metrics::{self, Metric};
+use vm_device::{Migratable, MigratableError, Pausable, Snapshot, Snapshotable};
+use vm_memory::{ByteValued, GuestAddress, GuestMemoryMmap};
 use vmm_sys_util::{eventfd::EventFd, seek_hole::SeekHole, write_zeroes::PunchHole};
 
 const SECTOR_SHIFT: u8 = 9;
@@ -47,13 +55,18 @@ pub const KILL_EVENT: DeviceEventT = 1;
 pub const BLOCK_EVENTS_COUNT: usize = 2;
 // The device should be paused.
 const PAUSE_EVENT: DeviceEventT = 3;
+// Some requests submitted through io_uring have completed.
+#[cfg(feature = "io_uring")]
+const COMPLETION_EVENT: DeviceEventT = 4;
+
+// Threads performing the I/O of the requests with a timeout, shared by all
+// the queues of a device.
+// Disk I/O a queue may have pending behind a stalled one, the requests
+// beyond failing right away.
+const IO_JOBS_MAX: usize = 256;
 
 #[derive(Debug)]
 pub enum Error {
-    /// Guest gave us bad memory addresses.
-    GuestMemory(GuestMemoryError),
-    /// Guest gave us offsets that would have overflowed a usize.
-    CheckedOffset(GuestAddress, usize),
     /// Guest gave us a write only descriptor that protocol says to read from.
     UnexpectedWriteOnlyDescriptor,
     /// Guest gave us a read only descriptor that protocol says to write to.
@@ -66,16 +79,89 @@ pub enum Error {
     GetFileMetadata,
     /// The requested operation would cause a seek beyond disk end.
     InvalidOffset,
+    /// Guest gave us an invalid descriptor chain.
+    DescriptorChain(DescriptorError),
+    /// Failed writing the request status.
+    WriteStatus(io::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::UnexpectedWriteOnlyDescriptor => write!(
+                f,
+                "guest gave us a write only descriptor that protocol says to read from"
+            ),
+            Error::UnexpectedReadOnlyDescriptor => write!(
+                f,
+                "guest gave us a read only descriptor that protocol says to write to"
+            ),
+            Error::DescriptorChainTooShort => {
+                write!(f, "guest gave us too few descriptors in a descriptor chain")
+            }
+            Error::DescriptorLengthTooSmall => {
+                write!(f, "guest gave us a descriptor that was too short to use")
+            }
+            Error::GetFileMetadata => write!(f, "cannot get the disk image metadata"),
+            Error::InvalidOffset => write!(
+                f,
+                "the requested operation would cause a seek beyond disk end"
+            ),
+            Error::DescriptorChain(_) => write!(f, "guest gave us an invalid descriptor chain"),
+            Error::WriteStatus(_) => write!(f, "failed writing the request status"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::DescriptorChain(e) => Some(e),
+            Error::WriteStatus(e) => Some(e),
+            _ => None,
+        }
+    }
 }
 
 #[derive(Debug)]
 pub enum ExecuteError {
     BadRequest(Error),
     Flush(io::Error),
-    Read(GuestMemoryError),
+    Read(io::Error),
     Seek(io::Error),
-    Write(GuestMemoryError),
+    Write(io::Error),
     Unsupported(u32),
+    SubmissionQueueFull,
+    /// The disk didn't complete the I/O before the request timed out.
+    Timeout,
+}
+
+impl fmt::Display for ExecuteError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ExecuteError::BadRequest(_) => write!(f, "bad request"),
+            ExecuteError::Flush(_) => write!(f, "failed to flush"),
+            ExecuteError::Read(_) => write!(f, "failed to read"),
+            ExecuteError::Seek(_) => write!(f, "failed to seek"),
+            ExecuteError::Write(_) => write!(f, "failed to write"),
+            ExecuteError::Unsupported(e) => write!(f, "unsupported request type {}", e),
+            ExecuteError::SubmissionQueueFull => write!(f, "the submission queue is full"),
+            ExecuteError::Timeout => write!(f, "the request timed out"),
+        }
+    }
+}
+
+impl std::error::Error for ExecuteError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ExecuteError::BadRequest(e) => Some(e),
+            ExecuteError::Flush(e) => Some(e),
+            ExecuteError::Read(e) => Some(e),
+            ExecuteError::Seek(e) => Some(e),
+            ExecuteError::Write(e) => Some(e),
+            _ => None,
+        }
+    }
 }
 
 impl ExecuteError {
@@ -87,6 +173,8 @@ impl ExecuteError {
             ExecuteError::Seek(_) => VIRTIO_BLK_S_IOERR,
             ExecuteError::Write(_) => VIRTIO_BLK_S_IOERR,
             ExecuteError::Unsupported(_) => VIRTIO_BLK_S_UNSUPP,
+            ExecuteError::SubmissionQueueFull => VIRTIO_BLK_S_IOERR,
+            ExecuteError::Timeout => VIRTIO_BLK_S_IOERR,
         }
     }
 }
@@ -409,30 +497,27 @@ pub enum RequestType {
     Unsupported(u32),
 }
 
-pub fn request_type(
-    mem: &GuestMemoryMmap,
-    desc_addr: GuestAddress,
-) -> result::Result<RequestType, Error> {
-    let type_ = mem.read_obj(desc_addr).map_err(Error::GuestMemory)?;
+pub fn request_type(type_: u32) -> RequestType {
     match type_ {
-        VIRTIO_BLK_T_IN => Ok(RequestType::In),
-        VIRTIO_BLK_T_OUT => Ok(RequestType::Out),
-        VIRTIO_BLK_T_FLUSH => Ok(RequestType::Flush),
-        VIRTIO_BLK_T_GET_ID => Ok(RequestType::GetDeviceID),
-        t => Ok(RequestType::Unsupported(t)),
+        VIRTIO_BLK_T_IN => RequestType::In,
+        VIRTIO_BLK_T_OUT => RequestType::Out,
+        VIRTIO_BLK_T_FLUSH => RequestType::Flush,
+        VIRTIO_BLK_T_GET_ID => RequestType::GetDeviceID,
+        t => RequestType::Unsupported(t),
     }
 }
 
-fn sector(mem: &GuestMemoryMmap, desc_addr: GuestAddress) -> result::Result<u64, Error> {
-    const SECTOR_OFFSET: usize = 8;
-    let addr = match mem.checked_offset(desc_addr, SECTOR_OFFSET) {
-        Some(v) => v,
-        None => return Err(Error::CheckedOffset(desc_addr, SECTOR_OFFSET)),
-    };
-
-    mem.read_obj(addr).map_err(Error::GuestMemory)
+/// The header at the beginning of every virtio-blk request.
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C)]
+struct RequestHeader {
+    type_: u32,
+    _reserved: u32,
+    sector: u64,
 }
 
+unsafe impl ByteValued for RequestHeader {}
+
 fn build_device_id(disk_path: &PathBuf) -> result::Result<String, Error> {
     let blk_metadata = match disk_path.metadata() {
         Err(_) => return Err(Error::GetFileMetadata),
@@ -465,88 +550,94 @@ pub fn build_disk_image_id(disk_path: &PathBuf) -> Vec<u8> {
     default_disk_image_id
 }
 
-pub struct Request {
+/// The longest serial the driver reads from a disk, in bytes.
+pub const DISK_SERIAL_MAX_LEN: usize = VIRTIO_BLK_ID_BYTES as usize;
+
+// The id the guest reads for a disk given a serial, zero padded.
+fn build_serial_disk_image_id(serial: &str) -> Vec<u8> {
+    let mut disk_image_id = vec![0; DISK_SERIAL_MAX_LEN];
+    let bytes_to_copy = cmp::min(serial.len(), DISK_SERIAL_MAX_LEN);
+    disk_image_id[..bytes_to_copy].clone_from_slice(&serial.as_bytes()[..bytes_to_copy]);
+    disk_image_id
+}
+
+pub struct Request<'a> {
     request_type: RequestType,
     sector: u64,
-    data_addr: GuestAddress,
-    data_len: u32,
-    pub status_addr: GuestAddress,
+    reader: Reader<'a>,
+    writer: Writer<'a>,
+    status_writer: Writer<'a>,
 }
 
-impl Request {
-    pub fn parse(
-        avail_desc: &DescriptorChain,
-        mem: &GuestMemoryMmap,
-    ) -> result::Result<Request, Error> {
+impl<'a> Request<'a> {
+    pub fn parse(avail_desc: DescriptorChain<'a>) -> result::Result<Request<'a>, Error> {
         // The head contains the request type which MUST be readable.
         if avail_desc.is_write_only() {
             return Err(Error::UnexpectedWriteOnlyDescriptor);
         }
 
-        let mut req = Request {
-            request_type: request_type(&mem, avail_desc.addr)?,
-            sector: sector(&mem, avail_desc.addr)?,
-            data_addr: GuestAddress(0),
-            data_len: 0,
-            status_addr: GuestAddress(0),
-        };
-
-        let data_desc;
-        let status_desc;
-        let desc = avail_desc
-            .next_descriptor()
-            .ok_or(Error::DescriptorChainTooShort)?;
-
-        if !desc.has_next() {
-            status_desc = desc;
-            // Only flush requests are allowed to skip the data descriptor.
-            if req.request_type != RequestType::Flush {
-                return Err(Error::DescriptorChainTooShort);
-            }
-        } else {
-            data_desc = desc;
-            status_desc = data_desc
-                .next_descriptor()
-                .ok_or(Error::DescriptorChainTooShort)?;
-
-            if data_desc.is_write_only() && req.request_type == RequestType::Out {
+        let mut reader = Reader::new(avail_desc.clone()).map_err(Error::DescriptorChain)?;
+        let mut writer = Writer::new(avail_desc).map_err(Error::DescriptorChain)?;
+
+        let header: RequestHeader = reader
+            .read_obj()
+            .map_err(|_| Error::DescriptorChainTooShort)?;
+        let request_type = request_type(header.type_);
+
+        // The status MUST be the last writable byte of the chain.
+        let status_offset = writer
+            .available_bytes()
+            .checked_sub(1)
+            .ok_or(Error::DescriptorLengthTooSmall)?;
+        let status_writer = writer
+            .split_at(status_offset)
+            .map_err(Error::DescriptorChain)?;
+
+        match request_type {
+            RequestType::Out if writer.available_bytes() > 0 => {
                 return Err(Error::UnexpectedWriteOnlyDescriptor);
             }
-            if !data_desc.is_write_only() && req.request_type == RequestType::In {
-                return Err(Error::UnexpectedReadOnlyDescriptor);
-            }
-            if !data_desc.is_write_only() && req.request_type == RequestType::GetDeviceID {
+            RequestType::In | RequestType::GetDeviceID if reader.available_bytes() > 0 => {
                 return Err(Error::UnexpectedReadOnlyDescriptor);
             }
-
-            req.data_addr = data_desc.addr;
-            req.data_len = data_desc.len;
+            _ => {}
         }
 
-        // The status MUST always be writable.
-        if !status_desc.is_write_only() {
-            return Err(Error::UnexpectedReadOnlyDescriptor);
-        }
+        Ok(Request {
+            request_type,
+            sector: header.sector,
+            reader,
+            writer,
+            status_writer,
+        })
+    }
 
-        if status_desc.len < 1 {
-            return Err(Error::DescriptorLengthTooSmall);
-        }
+    pub fn request_type(&self) -> RequestType {
+        self.request_type
+    }
 
-        req.status_addr = status_desc.addr;
+    /// Offset in the disk image where the request data starts.
+    pub fn offset(&self) -> u64 {
+        self.sector << SECTOR_SHIFT
+    }
 
-        Ok(req)
+    /// Length of the request data, which isn't checked against the disk
+    /// size.
+    pub fn data_len(&self) -> u64 {
+        let data_len = match self.request_type {
+            RequestType::Out => self.reader.available_bytes(),
+            _ => self.writer.available_bytes(),
+        };
+        data_len as u64
     }
 
-    #[allow(clippy::ptr_arg)]
-    pub fn execute<T: Seek + Read + Write>(
-        &self,
-        disk: &mut T,
-        disk_nsectors: u64,
-        mem: &GuestMemoryMmap,
-        disk_id: &Vec<u8>,
-    ) -> result::Result<u32, ExecuteError> {
-        let mut top: u64 = u64::from(self.data_len) / SECTOR_SIZE;
-        if u64::from(self.data_len) % SECTOR_SIZE != 0 {
+    /// Returns the length of the request data, after checking it doesn't
+    /// go past the end of the disk.
+    pub fn checked_data_len(&self, disk_nsectors: u64) -> result::Result<u64, ExecuteError> {
+        let data_len = self.data_len();
+
+        let mut top: u64 = data_len / SECTOR_SIZE;
+        if data_len % SECTOR_SIZE != 0 {
             top += 1;
         }
         top = top
@@ -556,35 +647,269 @@ impl Request {
             return Err(ExecuteError::BadRequest(Error::InvalidOffset));
         }
 
+        Ok(data_len)
+    }
+
+    /// Returns the host mapping of the request data, for the disk image to
+    /// be accessed without going through the guest memory accessors.
+    pub fn data_iovecs(&self) -> result::Result<Vec<libc::iovec>, Error> {
+        match self.request_type {
+            RequestType::Out => self.reader.iovecs(),
+            _ => self.writer.iovecs(),
+        }
+        .map_err(Error::DescriptorChain)
+    }
+
+    /// Guest address of the status byte, for the request to be completed
+    /// once its descriptor chain is gone.
+    pub fn status_address(&self) -> GuestAddress {
+        // Parsing made sure the status byte is there.
+        self.status_writer.next_guest_address().unwrap()
+    }
+
+    pub fn execute<T: Seek + Read + Write>(
+        &mut self,
+        disk: &mut T,
+        disk_nsectors: u64,
+        disk_id: &[u8],
+    ) -> result::Result<(), ExecuteError> {
+        let data_len = self.checked_data_len(disk_nsectors)?;
+
         disk.seek(SeekFrom::Start(self.sector << SECTOR_SHIFT))
             .map_err(ExecuteError::Seek)?;
 
         match self.request_type {
             RequestType::In => {
-                mem.read_exact_from(self.data_addr, disk, self.data_len as usize)
+                self.writer
+                    .write_all_from(disk, data_len as usize)
                     .map_err(ExecuteError::Read)?;
-                return Ok(self.data_len);
             }
             RequestType::Out => {
-                mem.write_all_to(self.data_addr, disk, self.data_len as usize)
+                self.reader
+                    .read_exact_to(disk, data_len as usize)
                     .map_err(ExecuteError::Write)?;
             }
-            RequestType::Flush => match disk.flush() {
-                Ok(_) => {
-                    return Ok(0);
+            RequestType::Flush => disk.flush().map_err(ExecuteError::Flush)?,
+            RequestType::GetDeviceID => {
+                if (data_len as usize) < disk_id.len() {
+                    return Err(ExecuteError::BadRequest(Error::InvalidOffset));
                 }
-                Err(e) => return Err(ExecuteError::Flush(e)),
-            },
+                self.writer
+                    .write_all(disk_id)
+                    .map_err(ExecuteError::Write)?;
+            }
+            RequestType::Unsupported(t) => return Err(ExecuteError::Unsupported(t)),
+        };
+        Ok(())
+    }
+
+    /// Executes the request as `execute()` does, but has `worker` perform
+    /// the disk I/O, on a copy of the data, failing the request once its
+    /// timeout expires.
+    fn execute_with_timeout<T: 'static + DiskFile + Send>(
+        &mut self,
+        disk: &Arc<Mutex<T>>,
+        worker: &IoWorker,
+        disk_nsectors: u64,
+        disk_id: &[u8],
+    ) -> result::Result<(), ExecuteError> {
+        let data_len = self.checked_data_len(disk_nsectors)? as usize;
+        let offset = self.sector << SECTOR_SHIFT;
+        let disk = disk.clone();
+
+        match self.request_type {
+            RequestType::In => {
+                let data = worker
+                    .run(move || {
+                        let mut disk = disk.lock().unwrap();
+                        disk.seek(SeekFrom::Start(offset))?;
+                        let mut data = vec![0u8; data_len];
+                        disk.read_exact(&mut data)?;
+                        Ok(data)
+                    })
+                    .map_err(|e| timed_out_or(e, ExecuteError::Read))?;
+                self.writer.write_all(&data).map_err(ExecuteError::Read)?;
+            }
+            RequestType::Out => {
+                let mut data = vec![0u8; data_len];
+                self.reader
+                    .read_exact(&mut data)
+                    .map_err(ExecuteError::Write)?;
+                worker
+                    .run(move || {
+                        let mut disk = disk.lock().unwrap();
+                        disk.seek(SeekFrom::Start(offset))?;
+                        disk.write_all(&data)?;
+                        Ok(Vec::new())
+                    })
+                    .map_err(|e| timed_out_or(e, ExecuteError::Write))?;
+            }
+            RequestType::Flush => {
+                worker
+                    .run(move || {
+                        disk.lock().unwrap().flush()?;
+                        Ok(Vec::new())
+                    })
+                    .map_err(|e| timed_out_or(e, ExecuteError::Flush))?;
+            }
             RequestType::GetDeviceID => {
-                if (self.data_len as usize) < disk_id.len() {
+                if data_len < disk_id.len() {
                     return Err(ExecuteError::BadRequest(Error::InvalidOffset));
                 }
-                mem.write_slice(&disk_id.as_slice(), self.data_addr)
+                self.writer
+                    .write_all(disk_id)
                     .map_err(ExecuteError::Write)?;
             }
             RequestType::Unsupported(t) => return Err(ExecuteError::Unsupported(t)),
         };
-        Ok(0)
+        Ok(())
+    }
+
+    /// Writes the request status, and returns the total number of bytes
+    /// written to the guest, to be reported through the used ring.
+    pub fn complete(&mut self, status: u32) -> result::Result<u32, Error> {
+        self.status_writer
+            .write_obj(status as u8)
+            .map_err(Error::WriteStatus)?;
+
+        Ok((self.writer.bytes_written() + self.status_writer.bytes_written()) as u32)
+    }
+}
+
+/// Counters describing the activity of a block device.
+#[derive(Clone, Default)]
+pub struct BlockMetrics {
+    read_bytes: Arc<Metric>,
+    write_bytes: Arc<Metric>,
+    read_ops: Arc<Metric>,
+    write_ops: Arc<Metric>,
+    notifications: Arc<Metric>,
+    epoll_iterations: Arc<Metric>,
+    timeouts: Arc<Metric>,
+}
+
+impl BlockMetrics {
+    /// Registers the metrics of the device `id`.
+    pub fn new(id: &str) -> Self {
+        let registry = metrics::registry();
+        BlockMetrics {
+            read_bytes: registry.counter("block_read_bytes", id),
+            write_bytes: registry.counter("block_write_bytes", id),
+            read_ops: registry.counter("block_read_ops", id),
+            write_ops: registry.counter("block_write_ops", id),
+            notifications: registry.counter("block_queue_notifications", id),
+            epoll_iterations: registry.counter("block_epoll_iterations", id),
+            timeouts: registry.counter("block_request_timeouts", id),
+        }
+    }
+
+    fn account(&self, request_type: RequestType, data_len: u64) {
+        match request_type {
+            RequestType::In => {
+                self.read_ops.inc();
+                self.read_bytes.add(data_len);
+            }
+            RequestType::Out => {
+                self.write_ops.inc();
+                self.write_bytes.add(data_len);
+            }
+            _ => {}
+        }
+    }
+}
+
+fn timed_out_or(e: io::Error, error: fn(io::Error) -> ExecuteError) -> ExecuteError {
+    if e.kind() == io::ErrorKind::TimedOut {
+        ExecuteError::Timeout
+    } else {
+        error(e)
+    }
+}
+
+// Disk I/O run by a worker, returning the data read if any.
+type IoJob = Box<dyn FnOnce() -> io::Result<Vec<u8>> + Send>;
+
+// A job queued to a worker, with the flag set once its handler gave up on
+// it.
+struct QueuedIoJob {
+    job: IoJob,
+    cancelled: Arc<AtomicBool>,
+    result: Sender<io::Result<Vec<u8>>>,
+}
+
+// Thread performing the disk I/O of the requests of a queue. The handler
+// waits for each job up to the timeout, then fails the request and moves on
+// to the next one, so that stalled storage never stalls the queue. The jobs
+// run in the order of the requests, and the ones timing out before being
+// started are dropped. A job which timed out while running still runs to
+// completion, e.g. a write may still reach the disk, but before any request
+// the guest submitted after seeing it fail.
+struct IoWorker {
+    jobs: SyncSender<QueuedIoJob>,
+    timeout: Duration,
+}
+
+impl IoWorker {
+    fn new(timeout: Duration) -> io::Result<Self> {
+        let (jobs, receiver) = sync_channel::<QueuedIoJob>(IO_JOBS_MAX);
+        thread::Builder::new()
+            .name("virtio_blk_io".to_string())
+            .spawn(move || {
+                // Returns once the handler is gone.
+                for queued in receiver.iter() {
+                    if queued.cancelled.load(Ordering::SeqCst) {
+                        continue;
+                    }
+                    // The handler may have given up on the result since.
+                    let _ = queued.result.send((queued.job)());
+                }
+            })?;
+
+        Ok(IoWorker { jobs, timeout })
+    }
+
+    fn run<F>(&self, job: F) -> io::Result<Vec<u8>>
+    where
+        F: FnOnce() -> io::Result<Vec<u8>> + Send + 'static,
+    {
+        let (result_sender, result) = channel();
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let queued = QueuedIoJob {
+            job: Box::new(job),
+            cancelled: cancelled.clone(),
+            result: result_sender,
+        };
+        match self.jobs.try_send(queued) {
+            Ok(()) => {}
+            // The worker is that far behind, the disk is stalled.
+            Err(TrySendError::Full(_)) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "too many pending block I/O",
+                ))
+            }
+            Err(TrySendError::Disconnected(_)) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::BrokenPipe,
+                    "no block I/O worker",
+                ))
+            }
+        }
+
+        match result.recv_timeout(self.timeout) {
+            Ok(result) => result,
+            Err(RecvTimeoutError::Timeout) => {
+                cancelled.store(true, Ordering::SeqCst);
+                Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "block I/O timed out",
+                ))
+            }
+            Err(RecvTimeoutError::Disconnected) => Err(io::Error::new(
+                io::ErrorKind::Other,
+                "block I/O worker failed",
+            )),
+        }
     }
 }
 
@@ -592,61 +917,136 @@ struct BlockEpollHandler<T: DiskFile> {
     queue: Queue,
     mem: Arc<ArcSwap<GuestMemoryMmap>>,
     disk_image: Arc<Mutex<T>>,
-    disk_nsectors: u64,
+    disk_nsectors: Arc<AtomicU64>,
     interrupt_cb: Arc<dyn VirtioInterrupt>,
     disk_image_id: Vec<u8>,
     kill_evt: EventFd,
     pause_evt: EventFd,
+    #[cfg(feature = "io_uring")]
+    io_uring: Option<IoUringDisk>,
+    io_worker: Option<IoWorker>,
+    metrics: BlockMetrics,
 }
 
-impl<T: DiskFile> BlockEpollHandler<T> {
+impl<T: 'static + DiskFile + Send> BlockEpollHandler<T> {
     fn process_queue(&mut self) -> bool {
         let queue = &mut self.queue;
 
         let mut used_desc_heads = Vec::new();
         let mut used_count = 0;
         let mem = self.mem.load();
+        #[cfg(feature = "io_uring")]
+        let mut submitted = false;
         for avail_desc in queue.iter(&mem) {
-            let len;
-            match Request::parse(&avail_desc, &mem) {
-                Ok(request) => {
-                    let mut disk_image_locked = self.disk_image.lock().unwrap();
-                    let mut disk_image = disk_image_locked.deref_mut();
-                    let status = match request.execute(
-                        &mut disk_image,
-                        self.disk_nsectors,
-                        &mem,
-                        &self.disk_image_id,
-                    ) {
-                        Ok(l) => {
-                            len = l;
+            let index = avail_desc.index;
+            let len = match Request::parse(avail_desc) {
+                Ok(mut request) => {
+                    let request_type = request.request_type();
+                    let data_len = request.data_len();
+                    #[cfg(feature = "io_uring")]
+                    {
+                        if let Some(io_uring) = self.io_uring.as_mut() {
+                            match io_uring.queue(
+                                &request,
+                                index,
+                                self.disk_nsectors.load(Ordering::SeqCst),
+                            ) {
+                                Ok(true) => {
+                                    // Completed through process_completions(),
+                                    // which doesn't know about the request
+                                    // type anymore, hence the accounting on
+                                    // submission.
+                                    self.metrics.account(request_type, data_len);
+                                    submitted = true;
+                                    continue;
+                                }
+                                Ok(false) => {}
+                                Err(e) => {
+                                    error!("Failed to submit request: {:?}", e);
+                                    let len = request.complete(e.status()).unwrap_or_else(|e| {
+                                        error!("Failed to complete request: {:?}", e);
+                                        0
+                                    });
+                                    used_desc_heads.push((index, len));
+                                    used_count += 1;
+                                    continue;
+                                }
+                            }
+                        }
+                    }
+
+                    let disk_nsectors = self.disk_nsectors.load(Ordering::SeqCst);
+                    let result = match &self.io_worker {
+                        Some(io_worker) => request.execute_with_timeout(
+                            &self.disk_image,
+                            io_worker,
+                            disk_nsectors,
+                            &self.disk_image_id,
+                        ),
+                        None => {
+                            let mut disk_image_locked = self.disk_image.lock().unwrap();
+                            let mut disk_image = disk_image_locked.deref_mut();
+                            request.execute(&mut disk_image, disk_nsectors, &self.disk_image_id)
+                        }
+                    };
+                    let status = match result {
+                        Ok(_) => {
+                            self.metrics.account(request_type, data_len);
                             VIRTIO_BLK_S_OK
                         }
                         Err(e) => {
+                            if let ExecuteError::Timeout = e {
+                                self.metrics.timeouts.inc();
+                            }
                             error!("Failed to execute request: {:?}", e);
-                            len = 1; // We need at least 1 byte for the status.
                             e.status()
                         }
                     };
-                    // We use unwrap because the request parsing process already checked that the
-                    // status_addr was valid.
-                    mem.write_obj(status, request.status_addr).unwrap();
+                    request.complete(status).unwrap_or_else(|e| {
+                        error!("Failed to complete request: {:?}", e);
+                        0
+                    })
                 }
                 Err(e) => {
                     error!("Failed to parse available descriptor chain: {:?}", e);
-                    len = 0;
+                    0
                 }
-            }
-            used_desc_heads.push((avail_desc.index, len));
+            };
+            used_desc_heads.push((index, len));
             used_count += 1;
         }
 
+        #[cfg(feature = "io_uring")]
+        {
+            if submitted {
+                if let Some(io_uring) = self.io_uring.as_mut() {
+                    if let Err(e) = io_uring.submit(0) {
+                        error!("Failed to submit requests: {:?}", e);
+                    }
+                }
+            }
+        }
+
         for &(desc_index, len) in used_desc_heads.iter() {
             queue.add_used(&mem, desc_index, len);
         }
         used_count > 0
     }
 
+    #[cfg(feature = "io_uring")]
+    fn process_completions(&mut self) -> bool {
+        let mem = self.mem.load();
+        let used_desc_heads = match self.io_uring.as_mut() {
+            Some(io_uring) => io_uring.complete(&mem),
+            None => return false,
+        };
+
+        for &(desc_index, len) in used_desc_heads.iter() {
+            self.queue.add_used(&mem, desc_index, len);
+        }
+        !used_desc_heads.is_empty()
+    }
+
     fn signal_used_queue(&self) -> result::Result<(), DeviceError> {
         self.interrupt_cb
             .trigger(&VirtioInterruptType::Queue, Some(&self.queue))
@@ -662,10 +1062,13 @@ impl<T: DiskFile> BlockEpollHandler<T> {
         mut disk_image: T,
         disk_path: &PathBuf,
     ) -> result::Result<(), DeviceError> {
-        self.disk_nsectors = disk_image
-            .seek(SeekFrom::End(0))
-            .map_err(DeviceError::IoError)?
-            / SECTOR_SIZE;
+        self.disk_nsectors.store(
+            disk_image
+                .seek(SeekFrom::End(0))
+                .map_err(DeviceError::IoError)?
+                / SECTOR_SIZE,
+            Ordering::SeqCst,
+        );
         self.disk_image_id = build_disk_image_id(disk_path);
         self.disk_image = Arc::new(Mutex::new(disk_image));
         Ok(())
@@ -701,6 +1104,18 @@ impl<T: DiskFile> BlockEpollHandler<T> {
             epoll::Event::new(epoll::Events::EPOLLIN, u64::from(PAUSE_EVENT)),
         )
         .map_err(DeviceError::EpollCtl)?;
+        #[cfg(feature = "io_uring")]
+        {
+            if let Some(io_uring) = &self.io_uring {
+                epoll::ctl(
+                    epoll_fd,
+                    epoll::ControlOptions::EPOLL_CTL_ADD,
+                    io_uring.completion_evt().as_raw_fd(),
+                    epoll::Event::new(epoll::Events::EPOLLIN, u64::from(COMPLETION_EVENT)),
+                )
+                .map_err(DeviceError::EpollCtl)?;
+            }
+        }
 
         const EPOLL_EVENTS_LEN: usize = 100;
         let mut events = vec![epoll::Event::new(epoll::Events::empty(), 0); EPOLL_EVENTS_LEN];
@@ -723,11 +1138,14 @@ impl<T: DiskFile> BlockEpollHandler<T> {
                 }
             };
 
+            self.metrics.epoll_iterations.inc();
+
             for event in events.iter().take(num_events) {
                 let ev_type = event.data as u16;
 
                 match ev_type {
                     QUEUE_AVAIL_EVENT => {
+                        self.metrics.notifications.inc();
                         if let Err(e) = queue_evt.read() {
                             error!("Failed to get queue event: {:?}", e);
                             break 'epoll;
@@ -738,6 +1156,19 @@ impl<T: DiskFile> BlockEpollHandler<T> {
                             }
                         }
                     }
+                    #[cfg(feature = "io_uring")]
+                    COMPLETION_EVENT => {
+                        let completion_evt = self.io_uring.as_ref().unwrap().completion_evt();
+                        if let Err(e) = completion_evt.read() {
+                            error!("Failed to get completion event: {:?}", e);
+                            break 'epoll;
+                        } else if self.process_completions() {
+                            if let Err(e) = self.signal_used_queue() {
+                                error!("Failed to signal used queue: {:?}", e);
+                                break 'epoll;
+                            }
+                        }
+                    }
                     KILL_EVENT => {
                         debug!("KILL_EVENT received, stopping epoll loop");
                         break 'epoll;
@@ -803,7 +1234,7 @@ pub struct Block<T: DiskFile> {
     kill_evt: Option<EventFd>,
     disk_image: Arc<Mutex<T>>,
     disk_path: PathBuf,
-    disk_nsectors: u64,
+    disk_nsectors: Arc<AtomicU64>,
     avail_features: u64,
     acked_features: u64,
     config: VirtioBlockConfig,
@@ -813,6 +1244,11 @@ pub struct Block<T: DiskFile> {
     pause_evt: Option<EventFd>,
     paused: Arc<AtomicBool>,
     queue_size: Vec<u16>,
+    #[cfg(feature = "io_uring")]
+    io_uring_fd: Option<RawFd>,
+    request_timeout: Option<Duration>,
+    serial: Option<String>,
+    metrics: BlockMetrics,
 }
 
 impl<T: DiskFile> Block<T> {
@@ -861,7 +1297,7 @@ impl<T: DiskFile> Block<T> {
             kill_evt: None,
             disk_image: Arc::new(Mutex::new(disk_image)),
             disk_path,
-            disk_nsectors,
+            disk_nsectors: Arc::new(AtomicU64::new(disk_nsectors)),
             avail_features,
             acked_features: 0u64,
             config,
@@ -871,8 +1307,115 @@ impl<T: DiskFile> Block<T> {
             pause_evt: None,
             paused: Arc::new(AtomicBool::new(false)),
             queue_size: vec![queue_size; num_queues],
+            #[cfg(feature = "io_uring")]
+            io_uring_fd: None,
+            request_timeout: None,
+            serial: None,
+            metrics: BlockMetrics::default(),
         })
     }
+
+    /// Exports the metrics of the device, under `id`.
+    pub fn set_metrics_id(&mut self, id: &str) {
+        self.metrics = BlockMetrics::new(id);
+    }
+
+    /// Fails the requests the disk doesn't complete within `timeout`, from
+    /// the next activation on. The requests submitted through io_uring are
+    /// not bounded.
+    pub fn set_request_timeout(&mut self, timeout: Option<Duration>) {
+        self.request_timeout = timeout;
+    }
+
+    /// Gives the guest `serial` as the id of the disk, from the next
+    /// activation on, instead of one derived from the disk image file.
+    pub fn set_serial(&mut self, serial: Option<String>) {
+        self.serial = serial;
+    }
+
+    /// Updates the capacity reported to the guest, and notifies the driver
+    /// about the configuration change if the device is activated.
+    pub fn set_capacity(&mut self, nsectors: u64) -> io::Result<()> {
+        self.config.capacity = nsectors;
+
+        if let Some(interrupt_cb) = &self.interrupt_cb {
+            interrupt_cb.signal_config_change()?;
+        }
+
+        Ok(())
+    }
+
+    /// Resizes the device to `new_size` bytes, or to the current size of
+    /// the backing file, which is looked up again, when not given.
+    ///
+    /// The backing file must already be at least `new_size` bytes long, as
+    /// the guest would otherwise be allowed to access sectors past its end.
+    /// Shrinking the device is refused unless `force` is set, since the
+    /// guest may be using the sectors being removed.
+    pub fn resize(&mut self, new_size: Option<u64>, force: bool) -> io::Result<()> {
+        let disk_size = self.disk_image.lock().unwrap().seek(SeekFrom::End(0))?;
+        let new_size = new_size.unwrap_or(disk_size);
+        if new_size > disk_size {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "Requested size {} exceeds backing file size {}",
+                    new_size, disk_size
+                ),
+            ));
+        }
+        if new_size % SECTOR_SIZE != 0 {
+            warn!(
+                "Disk size {} is not a multiple of sector size {}; \
+                 the remainder will not be visible to the guest.",
+                new_size, SECTOR_SIZE
+            );
+        }
+
+        let nsectors = new_size / SECTOR_SIZE;
+        let current_nsectors = self.disk_nsectors.load(Ordering::SeqCst);
+        if nsectors < current_nsectors && !force {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "Refusing to shrink the disk from {} to {} sectors",
+                    current_nsectors, nsectors
+                ),
+            ));
+        }
+
+        self.disk_nsectors.store(nsectors, Ordering::SeqCst);
+        self.set_capacity(nsectors)
+    }
+}
+
+#[cfg(feature = "io_uring")]
+impl Block<RawFile> {
+    /// Lets the device execute reads, writes and flushes through io_uring,
+    /// if the host supports it. Returns whether it will.
+    ///
+    /// Images opened with O_DIRECT stay on the synchronous path, which is
+    /// the one taking care of their alignment constraints.
+    pub fn enable_io_uring(&mut self) -> bool {
+        let disk_image = self.disk_image.lock().unwrap();
+        if disk_image.alignment != 0 || !block_io_uring_is_supported() {
+            return false;
+        }
+
+        self.io_uring_fd = Some(disk_image.file.as_raw_fd());
+        true
+    }
+}
+
+/// Allows a block device to be resized regardless of its disk image format.
+pub trait BlockResize: Send {
+    fn resize(&mut self, new_size: Option<u64>, force: bool) -> io::Result<()>;
+}
+
+impl<T: 'static + DiskFile + Send> BlockResize for Block<T> {
+    fn resize(&mut self, new_size: Option<u64>, force: bool) -> io::Result<()> {
+        Block::resize(self, new_size, force)
+    }
 }
 
 impl<T: DiskFile> Drop for Block<T> {
@@ -969,7 +1512,10 @@ impl<T: 'static + DiskFile + Send> VirtioDevice for Block<T> {
             })?;
         self.pause_evt = Some(self_pause_evt);
 
-        let disk_image_id = build_disk_image_id(&self.disk_path);
+        let disk_image_id = match &self.serial {
+            Some(serial) => build_serial_disk_image_id(serial),
+            None => build_disk_image_id(&self.disk_path),
+        };
 
         let mut tmp_queue_evts: Vec<EventFd> = Vec::new();
         for queue_evt in queue_evts.iter() {
@@ -995,15 +1541,37 @@ impl<T: 'static + DiskFile + Send> VirtioDevice for Block<T> {
 
         let mut epoll_threads = Vec::new();
         for _ in 0..self.queue_size.len() {
+            let queue = queues.remove(0);
+            #[cfg(feature = "io_uring")]
+            let io_uring = match self.io_uring_fd {
+                Some(disk_fd) => Some(
+                    IoUringDisk::new(disk_fd, u32::from(queue.get_max_size())).map_err(|e| {
+                        error!("failed to set up io_uring: {}", e);
+                        ActivateError::BadActivate
+                    })?,
+                ),
+                None => None,
+            };
+            let io_worker = match self.request_timeout {
+                Some(timeout) => Some(IoWorker::new(timeout).map_err(|e| {
+                    error!("failed to spawn the virtio-blk I/O worker: {}", e);
+                    ActivateError::BadActivate
+                })?),
+                None => None,
+            };
             let mut handler = BlockEpollHandler {
-                queue: queues.remove(0),
+                queue,
                 mem: mem.clone(),
                 disk_image: self.disk_image.clone(),
-                disk_nsectors: self.disk_nsectors,
+                disk_nsectors: self.disk_nsectors.clone(),
                 interrupt_cb: interrupt_cb.clone(),
                 disk_image_id: disk_image_id.clone(),
                 kill_evt: kill_evt.try_clone().unwrap(),
                 pause_evt: pause_evt.try_clone().unwrap(),
+                #[cfg(feature = "io_uring")]
+                io_uring,
+                io_worker,
+                metrics: self.metrics.clone(),
             };
 
             let queue_evt = queue_evts.remove(0);
@@ -1037,6 +1605,11 @@ impl<T: 'static + DiskFile + Send> VirtioDevice for Block<T> {
             // Ignore the result because there is nothing we can do about it.
             let _ = kill_evt.write(1);
         }
+        join_epoll_threads(self.epoll_threads.take());
+
+        self.acked_features = 0;
+        // The driver may have changed the cache mode.
+        self.config.wce = 0;
 
         // Return the interrupt and queue EventFDs
         Some((
@@ -1044,8 +1617,399 @@ impl<T: 'static + DiskFile + Send> VirtioDevice for Block<T> {
             self.queue_evts.take().unwrap(),
         ))
     }
+
+    fn ready_to_remove(&self) -> bool {
+        // Each epoll thread holds a reference to the disk image until it
+        // exits, after completing the request it was processing.
+        Arc::strong_count(&self.disk_image) == 1
+    }
 }
 
 virtio_pausable!(Block, T: 'static + DiskFile + Send);
-impl<T: 'static + DiskFile + Send> Snapshotable for Block<T> {}
+
+#[derive(Deserialize, Serialize)]
+struct BlockState {
+    avail_features: u64,
+    acked_features: u64,
+}
+
+impl<T: 'static + DiskFile + Send> Snapshotable for Block<T> {
+    // The position of the queues is saved by the transport, from the used
+    // rings, which only works as long as the requests complete in order.
+    fn snapshot(&self) -> result::Result<Snapshot, MigratableError> {
+        #[cfg(feature = "io_uring")]
+        {
+            if self.io_uring_fd.is_some() {
+                return Err(MigratableError::Snapshot(anyhow!(
+                    "the requests in flight through io_uring can't be saved"
+                )));
+            }
+        }
+
+        let mut snapshot = Snapshot::new(&self.id());
+        add_snapshot_state(
+            &mut snapshot,
+            &BlockState {
+                avail_features: self.avail_features,
+                acked_features: self.acked_features,
+            },
+        )?;
+        Ok(snapshot)
+    }
+
+    fn restore(&mut self, snapshot: Snapshot) -> result::Result<(), MigratableError> {
+        if let Some(state) = snapshot_state::<BlockState>(&snapshot)? {
+            // E.g. the disk was made read-only since.
+            if state.avail_features != self.avail_features {
+                return Err(MigratableError::Restore(anyhow!(
+                    "virtio-blk features changed from 0x{:x} to 0x{:x}",
+                    state.avail_features,
+                    self.avail_features
+                )));
+            }
+            self.acked_features = state.acked_features;
+        }
+        Ok(())
+    }
+}
 impl<T: 'static + DiskFile + Send> Migratable for Block<T> {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{create_guest_memory, Buffer, CountingInterrupt, VirtqueueBuilder};
+    use std::io::Cursor;
+    use vm_memory::{Bytes, GuestAddress};
+
+    #[test]
+    fn test_process_queue_uses_driver_queue_size() {
+        const MAX_QUEUE_SIZE: u16 = 256;
+        const DRIVER_QUEUE_SIZE: u16 = 64;
+
+        let mem = create_guest_memory(0x10000);
+        let mut vq = VirtqueueBuilder::new(&mem, DRIVER_QUEUE_SIZE).build();
+
+        // The device offers more entries than the driver programmed.
+        let mut queue = Queue::new(MAX_QUEUE_SIZE);
+        queue.size = DRIVER_QUEUE_SIZE;
+        queue.ready = true;
+        queue.desc_table = vq.desc_table();
+        queue.avail_ring = vq.avail_ring();
+        queue.used_ring = vq.used_ring();
+
+        let mut handler = BlockEpollHandler {
+            queue,
+            mem: Arc::new(ArcSwap::new(Arc::new(mem.clone()))),
+            disk_image: Arc::new(Mutex::new(Cursor::new(vec![0u8; 0x1000]))),
+            disk_nsectors: Arc::new(AtomicU64::new(0x1000 / SECTOR_SIZE)),
+            interrupt_cb: Arc::new(CountingInterrupt::default()),
+            disk_image_id: vec![0; VIRTIO_BLK_ID_BYTES as usize],
+            kill_evt: EventFd::new(EFD_NONBLOCK).unwrap(),
+            pause_evt: EventFd::new(EFD_NONBLOCK).unwrap(),
+            #[cfg(feature = "io_uring")]
+            io_uring: None,
+            io_worker: None,
+            metrics: BlockMetrics::default(),
+        };
+
+        let header = GuestAddress(0x8000);
+        let status = GuestAddress(0x9000);
+        mem.write_obj(VIRTIO_BLK_T_FLUSH, header).unwrap();
+
+        // Go past the end of the rings, so that the device has to wrap
+        // around using the size the driver programmed.
+        for i in 0..=DRIVER_QUEUE_SIZE {
+            mem.write_obj(0xffu8, status).unwrap();
+            let head = vq.add_chain(&[Buffer::readable(header, 16), Buffer::writable(status, 1)]);
+
+            assert!(handler.process_queue());
+            assert_eq!(vq.used_idx(), i + 1);
+            assert_eq!(vq.used_elem(i), (u32::from(head), 1));
+            assert_eq!(mem.read_obj::<u8>(status).unwrap(), VIRTIO_BLK_S_OK as u8);
+        }
+    }
+
+    #[test]
+    fn test_metrics() {
+        let mem = create_guest_memory(0x10000);
+        let mut vq = VirtqueueBuilder::new(&mem, 16).build();
+        let queue_evt = EventFd::new(EFD_NONBLOCK).unwrap();
+
+        let mut block = Block::new(
+            Cursor::new(vec![0u8; 0x1000]),
+            PathBuf::from("/dev/null"),
+            false,
+            false,
+            1,
+            16,
+        )
+        .unwrap();
+        block.set_metrics_id("test_metrics");
+        block
+            .activate(
+                Arc::new(ArcSwap::new(Arc::new(mem.clone()))),
+                Arc::new(CountingInterrupt::default()),
+                vec![vq.create_queue()],
+                vec![queue_evt.try_clone().unwrap()],
+            )
+            .unwrap();
+
+        let (out_header, in_header) = (GuestAddress(0x8000), GuestAddress(0x8100));
+        let data = GuestAddress(0x9000);
+        let status = GuestAddress(0xa000);
+        mem.write_obj(VIRTIO_BLK_T_OUT, out_header).unwrap();
+        mem.write_obj(VIRTIO_BLK_T_IN, in_header).unwrap();
+        vq.add_chain(&[
+            Buffer::readable(out_header, 16),
+            Buffer::readable(data, 0x400),
+            Buffer::writable(status, 1),
+        ]);
+        vq.add_chain(&[
+            Buffer::readable(in_header, 16),
+            Buffer::writable(data, 0x200),
+            Buffer::writable(status, 1),
+        ]);
+        queue_evt.write(1).unwrap();
+
+        for _ in 0..100 {
+            if vq.used_idx() == 2 {
+                break;
+            }
+            thread::sleep(std::time::Duration::from_millis(10));
+        }
+        assert_eq!(vq.used_idx(), 2);
+
+        let registry = metrics::registry();
+        assert_eq!(registry.counter("block_write_ops", "test_metrics").get(), 1);
+        assert_eq!(
+            registry.counter("block_write_bytes", "test_metrics").get(),
+            0x400
+        );
+        assert_eq!(registry.counter("block_read_ops", "test_metrics").get(), 1);
+        assert_eq!(
+            registry.counter("block_read_bytes", "test_metrics").get(),
+            0x200
+        );
+        assert_eq!(
+            registry
+                .counter("block_queue_notifications", "test_metrics")
+                .get(),
+            1
+        );
+        assert!(
+            registry
+                .counter("block_epoll_iterations", "test_metrics")
+                .get()
+                >= 1
+        );
+
+        assert!(block.reset().is_some());
+    }
+
+    #[test]
+    fn test_ready_to_remove_after_reset() {
+        let mem = create_guest_memory(0x10000);
+        let queue = VirtqueueBuilder::new(&mem, 16).build().create_queue();
+
+        let mut block = Block::new(
+            Cursor::new(vec![0u8; 0x1000]),
+            PathBuf::from("/dev/null"),
+            false,
+            false,
+            1,
+            16,
+        )
+        .unwrap();
+        assert!(block.ready_to_remove());
+
+        block
+            .activate(
+                Arc::new(ArcSwap::new(Arc::new(mem.clone()))),
+                Arc::new(CountingInterrupt::default()),
+                vec![queue],
+                vec![EventFd::new(EFD_NONBLOCK).unwrap()],
+            )
+            .unwrap();
+        assert!(!block.ready_to_remove());
+
+        assert!(block.reset().is_some());
+
+        // The epoll thread exits once it has seen the kill event.
+        for _ in 0..100 {
+            if block.ready_to_remove() {
+                break;
+            }
+            thread::sleep(std::time::Duration::from_millis(10));
+        }
+        assert!(block.ready_to_remove());
+    }
+
+    #[test]
+    fn test_resize() {
+        let mut block = Block::new(
+            Cursor::new(vec![0u8; 0x1000]),
+            PathBuf::from("/dev/null"),
+            false,
+            false,
+            1,
+            16,
+        )
+        .unwrap();
+        assert_eq!({ block.config.capacity }, 0x1000 / SECTOR_SIZE);
+
+        // The backing file is looked up again when no size is given.
+        block.disk_image.lock().unwrap().get_mut().resize(0x4000, 0);
+        block.resize(None, false).unwrap();
+        assert_eq!({ block.config.capacity }, 0x4000 / SECTOR_SIZE);
+        assert_eq!(
+            block.disk_nsectors.load(Ordering::SeqCst),
+            0x4000 / SECTOR_SIZE
+        );
+
+        // The device can't get bigger than its backing file.
+        assert!(block.resize(Some(0x8000), false).is_err());
+        assert_eq!({ block.config.capacity }, 0x4000 / SECTOR_SIZE);
+
+        // Shrinking has to be forced.
+        assert!(block.resize(Some(0x2000), false).is_err());
+        assert_eq!({ block.config.capacity }, 0x4000 / SECTOR_SIZE);
+        block.resize(Some(0x2000), true).unwrap();
+        assert_eq!({ block.config.capacity }, 0x2000 / SECTOR_SIZE);
+    }
+
+    // A disk whose reads block for as long as it is stalled.
+    #[derive(Clone)]
+    struct StallingDisk {
+        data: Cursor<Vec<u8>>,
+        stalled: Arc<(Mutex<bool>, std::sync::Condvar)>,
+    }
+
+    impl Read for StallingDisk {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let (stalled, resumed) = &*self.stalled;
+            let mut stalled = stalled.lock().unwrap();
+            while *stalled {
+                stalled = resumed.wait(stalled).unwrap();
+            }
+            self.data.read(buf)
+        }
+    }
+
+    impl Write for StallingDisk {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.data.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Seek for StallingDisk {
+        fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+            self.data.seek(pos)
+        }
+    }
+
+    #[test]
+    fn test_request_timeout() {
+        let mem = create_guest_memory(0x10000);
+        let mut vq = VirtqueueBuilder::new(&mem, 16).build();
+        let queue = vq.create_queue();
+
+        let stalled = Arc::new((Mutex::new(true), std::sync::Condvar::new()));
+        let disk = StallingDisk {
+            data: Cursor::new(vec![0xa5u8; 0x1000]),
+            stalled: stalled.clone(),
+        };
+        let mut handler = BlockEpollHandler {
+            queue,
+            mem: Arc::new(ArcSwap::new(Arc::new(mem.clone()))),
+            disk_image: Arc::new(Mutex::new(disk)),
+            disk_nsectors: Arc::new(AtomicU64::new(0x1000 / SECTOR_SIZE)),
+            interrupt_cb: Arc::new(CountingInterrupt::default()),
+            disk_image_id: vec![0; VIRTIO_BLK_ID_BYTES as usize],
+            kill_evt: EventFd::new(EFD_NONBLOCK).unwrap(),
+            pause_evt: EventFd::new(EFD_NONBLOCK).unwrap(),
+            #[cfg(feature = "io_uring")]
+            io_uring: None,
+            io_worker: Some(IoWorker::new(Duration::from_millis(200)).unwrap()),
+            metrics: BlockMetrics::new("test_request_timeout"),
+        };
+
+        let header = GuestAddress(0x8000);
+        let data = GuestAddress(0x9000);
+        let status = GuestAddress(0xa000);
+        mem.write_obj(VIRTIO_BLK_T_IN, header).unwrap();
+        let chain = [
+            Buffer::readable(header, 16),
+            Buffer::writable(data, 0x200),
+            Buffer::writable(status, 1),
+        ];
+
+        // The stalled read fails the request, instead of blocking the queue.
+        vq.add_chain(&chain);
+        assert!(handler.process_queue());
+        assert_eq!(vq.used_idx(), 1);
+        assert_eq!(
+            mem.read_obj::<u8>(status).unwrap(),
+            VIRTIO_BLK_S_IOERR as u8
+        );
+        assert_eq!(
+            metrics::registry()
+                .counter("block_request_timeouts", "test_request_timeout")
+                .get(),
+            1
+        );
+
+        // Once the disk is back, the requests complete again.
+        *stalled.0.lock().unwrap() = false;
+        stalled.1.notify_all();
+        vq.add_chain(&chain);
+        assert!(handler.process_queue());
+        assert_eq!(vq.used_idx(), 2);
+        assert_eq!(mem.read_obj::<u8>(status).unwrap(), VIRTIO_BLK_S_OK as u8);
+        assert_eq!(mem.read_obj::<u8>(data).unwrap(), 0xa5);
+    }
+
+    #[test]
+    fn test_io_worker_cancel() {
+        let worker = IoWorker::new(Duration::from_millis(200)).unwrap();
+
+        // A job stalling the worker, then one queued behind it.
+        let (release, stalled) = channel::<()>();
+        let e = worker
+            .run(move || {
+                stalled.recv().unwrap();
+                Ok(Vec::new())
+            })
+            .unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::TimedOut);
+        let ran = Arc::new(AtomicBool::new(false));
+        let job_ran = ran.clone();
+        let e = worker
+            .run(move || {
+                job_ran.store(true, Ordering::SeqCst);
+                Ok(Vec::new())
+            })
+            .unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::TimedOut);
+
+        // The job which timed out before being started never runs.
+        release.send(()).unwrap();
+        assert_eq!(worker.run(|| Ok(vec![1])).unwrap(), vec![1]);
+        assert!(!ran.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn test_serial_disk_image_id() {
+        let id = build_serial_disk_image_id("data-disk");
+        assert_eq!(id.len(), DISK_SERIAL_MAX_LEN);
+        assert_eq!(&id[..9], b"data-disk");
+        assert!(id[9..].iter().all(|b| *b == 0));
+
+        // The driver reads no more than the first bytes.
+        let id = build_serial_disk_image_id("0123456789abcdefghijklmn");
+        assert_eq!(&id[..], b"0123456789abcdefghij");
+    }
+}
@@ -0,0 +1,214 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Asynchronous execution of virtio-block requests through io_uring.
+//!
+//! Reads, writes and flushes are handed over to the kernel instead of being
+//! executed on the device thread, and their completion is signaled through
+//! an eventfd registered with the ring. Every other request type is still
+//! executed synchronously by the block device.
+
+use crate::block::{ExecuteError, Request, RequestType};
+use io_uring::{opcode, squeue, types, IoUring, Probe};
+use libc::EFD_NONBLOCK;
+use std::collections::HashMap;
+use std::io;
+use std::os::unix::io::{AsRawFd, RawFd};
+use virtio_bindings::bindings::virtio_blk::*;
+use vm_memory::{Bytes, GuestAddress, GuestMemoryMmap};
+use vmm_sys_util::eventfd::EventFd;
+
+/// Returns whether the host kernel provides everything `IoUringDisk` relies
+/// on, so that the synchronous path can be used otherwise.
+pub fn block_io_uring_is_supported() -> bool {
+    let io_uring = match IoUring::new(1) {
+        Ok(io_uring) => io_uring,
+        Err(e) => {
+            info!("io_uring not supported: cannot create a ring: {}", e);
+            return false;
+        }
+    };
+
+    let submitter = io_uring.submitter();
+    let evt = match EventFd::new(EFD_NONBLOCK) {
+        Ok(evt) => evt,
+        Err(e) => {
+            info!("io_uring not supported: cannot create an EventFd: {}", e);
+            return false;
+        }
+    };
+    if let Err(e) = submitter.register_eventfd(evt.as_raw_fd()) {
+        info!("io_uring not supported: cannot register an EventFd: {}", e);
+        return false;
+    }
+
+    let mut probe = Probe::new();
+    if let Err(e) = submitter.register_probe(&mut probe) {
+        info!("io_uring not supported: cannot probe the opcodes: {}", e);
+        return false;
+    }
+
+    [
+        opcode::Readv::CODE,
+        opcode::Writev::CODE,
+        opcode::Fsync::CODE,
+    ]
+    .iter()
+    .all(|code| probe.is_supported(*code))
+}
+
+struct InflightRequest {
+    status_addr: GuestAddress,
+    // Number of data bytes written to the guest on success.
+    data_len: u32,
+    // Number of bytes the request reads or writes. The request fails if
+    // fewer were transferred, rather than handing the guest partial data.
+    transfer_len: u32,
+    // The kernel may only read those once the request gets executed, hence
+    // they have to be kept around until its completion.
+    _iovecs: Vec<libc::iovec>,
+}
+
+/// Executes virtio-block requests asynchronously on a raw disk image.
+pub struct IoUringDisk {
+    io_uring: IoUring,
+    completion_evt: EventFd,
+    disk_fd: RawFd,
+    // Requests submitted to the kernel, indexed by descriptor chain head.
+    inflight: HashMap<u16, InflightRequest>,
+}
+
+// The only fields that aren't Send are the raw pointers of the iovecs, and
+// they point to guest memory, which outlives any device.
+unsafe impl Send for IoUringDisk {}
+
+impl IoUringDisk {
+    /// Creates a ring operating on `disk_fd`, which can hold `depth`
+    /// requests. As requests stay in flight until they complete, it must be
+    /// at least the size of the queue being served.
+    pub fn new(disk_fd: RawFd, depth: u32) -> io::Result<Self> {
+        let io_uring = IoUring::new(depth)?;
+        let completion_evt = EventFd::new(EFD_NONBLOCK)?;
+        io_uring
+            .submitter()
+            .register_eventfd(completion_evt.as_raw_fd())?;
+
+        Ok(IoUringDisk {
+            io_uring,
+            completion_evt,
+            disk_fd,
+            inflight: HashMap::new(),
+        })
+    }
+
+    /// Signaled whenever some requests have completed.
+    pub fn completion_evt(&self) -> &EventFd {
+        &self.completion_evt
+    }
+
+    /// Queues `request` for submission. Returns `false` if its type must be
+    /// executed synchronously.
+    pub fn queue(
+        &mut self,
+        request: &Request,
+        desc_index: u16,
+        disk_nsectors: u64,
+    ) -> Result<bool, ExecuteError> {
+        let fd = types::Fd(self.disk_fd);
+        let request_type = request.request_type();
+        let (entry, data_len, iovecs) = match request_type {
+            RequestType::In | RequestType::Out => {
+                let data_len = request.checked_data_len(disk_nsectors)?;
+                let iovecs = request.data_iovecs().map_err(ExecuteError::BadRequest)?;
+                let entry = if request_type == RequestType::In {
+                    opcode::Readv::new(fd, iovecs.as_ptr(), iovecs.len() as u32)
+                        .offset(request.offset() as i64)
+                        .build()
+                } else {
+                    opcode::Writev::new(fd, iovecs.as_ptr(), iovecs.len() as u32)
+                        .offset(request.offset() as i64)
+                        .build()
+                };
+                (entry, data_len as u32, iovecs)
+            }
+            RequestType::Flush => (opcode::Fsync::new(fd).build(), 0, Vec::new()),
+            _ => return Ok(false),
+        };
+
+        self.push(entry.user_data(u64::from(desc_index)))?;
+        self.inflight.insert(
+            desc_index,
+            InflightRequest {
+                status_addr: request.status_address(),
+                data_len: if request_type == RequestType::In {
+                    data_len
+                } else {
+                    0
+                },
+                transfer_len: data_len,
+                _iovecs: iovecs,
+            },
+        );
+
+        Ok(true)
+    }
+
+    fn push(&mut self, entry: squeue::Entry) -> Result<(), ExecuteError> {
+        // Safe because the buffers the entry refers to are kept alive along
+        // with the inflight request, until the kernel is done with them.
+        unsafe { self.io_uring.submission().available().push(entry) }
+            .map_err(|_| ExecuteError::SubmissionQueueFull)
+    }
+
+    /// Submits the queued requests to the kernel, waiting for at least
+    /// `want` of them to complete.
+    pub fn submit(&mut self, want: usize) -> io::Result<()> {
+        self.io_uring.submit_and_wait(want).map(|_| ())
+    }
+
+    /// Writes the status of the completed requests to the guest, and
+    /// returns their descriptor chain head and length, to be added to the
+    /// used ring.
+    pub fn complete(&mut self, mem: &GuestMemoryMmap) -> Vec<(u16, u32)> {
+        let mut used_desc_heads = Vec::new();
+        for entry in self.io_uring.completion().available() {
+            let desc_index = entry.user_data() as u16;
+            let request = match self.inflight.remove(&desc_index) {
+                Some(request) => request,
+                None => {
+                    error!("Completion for unknown request {}", desc_index);
+                    continue;
+                }
+            };
+
+            let (status, data_len) = if entry.result() < 0 {
+                error!(
+                    "Failed to execute request: {}",
+                    io::Error::from_raw_os_error(-entry.result())
+                );
+                (VIRTIO_BLK_S_IOERR, 0)
+            } else if (entry.result() as u32) < request.transfer_len {
+                error!(
+                    "Failed to execute request: {} bytes transferred out of {}",
+                    entry.result(),
+                    request.transfer_len
+                );
+                (VIRTIO_BLK_S_IOERR, 0)
+            } else {
+                (VIRTIO_BLK_S_OK, request.data_len)
+            };
+
+            let len = match mem.write_obj(status as u8, request.status_addr) {
+                Ok(_) => data_len + 1,
+                Err(e) => {
+                    error!("Failed to complete request: {:?}", e);
+                    0
+                }
+            };
+            used_desc_heads.push((desc_index, len));
+        }
+
+        used_desc_heads
+    }
+}
@@ -6,7 +6,7 @@ use super::{
     ActivateError, ActivateResult, DeviceEventT, Queue, VirtioDevice, VirtioDeviceType,
     VirtioInterruptType, VIRTIO_F_IOMMU_PLATFORM, VIRTIO_F_VERSION_1,
 };
-use crate::VirtioInterrupt;
+use crate::{join_epoll_threads, VirtioInterrupt};
 use arc_swap::ArcSwap;
 use epoll;
 use libc::EFD_NONBLOCK;
@@ -21,7 +21,7 @@ use std::result;
 use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::{Arc, Mutex};
 use std::thread;
-use vm_device::{Migratable, MigratableError, Pausable, Snapshotable};
+use vm_device::{Migratable, MigratableError, Pausable, Snapshot, Snapshotable};
 use vm_memory::{ByteValued, Bytes, GuestMemoryMmap};
 use vmm_sys_util::eventfd::EventFd;
 
@@ -552,6 +552,9 @@ impl VirtioDevice for Console {
             // Ignore the result because there is nothing we can do about it.
             let _ = kill_evt.write(1);
         }
+        join_epoll_threads(self.epoll_threads.take());
+
+        self.acked_features = 0;
 
         // Return the interrupt and queue EventFDs
         Some((
@@ -562,5 +565,15 @@ impl VirtioDevice for Console {
 }
 
 virtio_pausable!(Console);
-impl Snapshotable for Console {}
+// The size of the console is set by the VMM, and the input not read yet by
+// the guest is lost.
+impl Snapshotable for Console {
+    fn snapshot(&self) -> result::Result<Snapshot, MigratableError> {
+        Ok(Snapshot::new(&self.id()))
+    }
+
+    fn restore(&mut self, _snapshot: Snapshot) -> result::Result<(), MigratableError> {
+        Ok(())
+    }
+}
 impl Migratable for Console {}
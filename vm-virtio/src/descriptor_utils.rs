@@ -0,0 +1,662 @@
+// Copyright 2019 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE-BSD-3-Clause file.
+//
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0 AND BSD-3-Clause
+
+//! Helpers to access the buffers described by a virtio descriptor chain.
+//!
+//! `Reader` covers the device-readable descriptors of a chain and `Writer`
+//! covers the device-writable ones. Both present their descriptors as one
+//! contiguous stream through `std::io::Read` and `std::io::Write`, and keep
+//! track of how many bytes have been consumed so far.
+
+use std::cmp;
+use std::collections::VecDeque;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::result;
+
+use crate::queue::DescriptorChain;
+use vm_memory::{
+    Address, ByteValued, Bytes, GuestAddress, GuestMemory, GuestMemoryError, GuestMemoryMmap,
+    GuestMemoryRegion,
+};
+
+#[derive(Debug)]
+pub enum Error {
+    /// The total length of the descriptor chain overflows a usize.
+    DescriptorChainOverflow,
+    /// A descriptor points to memory the guest does not own.
+    InvalidGuestAddress(GuestAddress),
+    /// The buffer cannot be split at the given offset.
+    SplitOutOfBounds(usize),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::DescriptorChainOverflow => write!(
+                f,
+                "the total length of the descriptor chain overflows a usize"
+            ),
+            Error::InvalidGuestAddress(e) => write!(
+                f,
+                "a descriptor points to memory the guest does not own: {:#x}",
+                e.0
+            ),
+            Error::SplitOutOfBounds(e) => write!(f, "the buffer cannot be split at offset {}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            _ => None,
+        }
+    }
+}
+
+pub type Result<T> = result::Result<T, Error>;
+
+fn io_error(e: GuestMemoryError) -> io::Error {
+    match e {
+        GuestMemoryError::IOError(e) => e,
+        e => io::Error::new(io::ErrorKind::Other, format!("{:?}", e)),
+    }
+}
+
+#[derive(Clone)]
+struct DescriptorChainConsumer<'a> {
+    mem: &'a GuestMemoryMmap,
+    // Each buffer is contained in a single guest memory region.
+    buffers: VecDeque<(GuestAddress, usize)>,
+    bytes_consumed: usize,
+}
+
+impl<'a> DescriptorChainConsumer<'a> {
+    fn new<I>(mem: &'a GuestMemoryMmap, descriptors: I) -> Result<Self>
+    where
+        I: Iterator<Item = DescriptorChain<'a>>,
+    {
+        let mut buffers = VecDeque::new();
+        let mut total_len: usize = 0;
+
+        for desc in descriptors {
+            total_len = total_len
+                .checked_add(desc.len as usize)
+                .ok_or(Error::DescriptorChainOverflow)?;
+
+            // Split the descriptor on guest memory region boundaries, so that
+            // it does not matter whether the regions are contiguous or not.
+            let mut addr = desc.addr;
+            let mut remaining = desc.len as usize;
+            while remaining > 0 {
+                let region = mem
+                    .find_region(addr)
+                    .ok_or(Error::InvalidGuestAddress(addr))?;
+                let region_end = region.start_addr().raw_value() + region.len();
+                let len = cmp::min(remaining as u64, region_end - addr.raw_value()) as usize;
+
+                buffers.push_back((addr, len));
+                addr = addr.unchecked_add(len as u64);
+                remaining -= len;
+            }
+        }
+
+        Ok(DescriptorChainConsumer {
+            mem,
+            buffers,
+            bytes_consumed: 0,
+        })
+    }
+
+    fn available_bytes(&self) -> usize {
+        // The sum can't overflow as it was checked at creation time.
+        self.buffers.iter().map(|&(_, len)| len).sum()
+    }
+
+    fn bytes_consumed(&self) -> usize {
+        self.bytes_consumed
+    }
+
+    fn iovecs(&self) -> Result<Vec<libc::iovec>> {
+        self.buffers
+            .iter()
+            .map(|&(addr, len)| {
+                let host_addr = self
+                    .mem
+                    .get_host_address(addr)
+                    .map_err(|_| Error::InvalidGuestAddress(addr))?;
+                Ok(libc::iovec {
+                    iov_base: host_addr as *mut libc::c_void,
+                    iov_len: len,
+                })
+            })
+            .collect()
+    }
+
+    // Hands at most `count` bytes over to `f`, one buffer at a time. `f`
+    // returns the number of bytes it actually handled, and consumption
+    // stops early if that is less than what it was given.
+    fn consume<F>(&mut self, count: usize, mut f: F) -> io::Result<usize>
+    where
+        F: FnMut(GuestAddress, usize) -> io::Result<usize>,
+    {
+        let mut total = 0;
+        while total < count {
+            let (addr, len) = match self.buffers.front() {
+                Some(&buffer) => buffer,
+                None => break,
+            };
+
+            let wanted = cmp::min(len, count - total);
+            let done = f(addr, wanted)?;
+
+            total += done;
+            self.bytes_consumed += done;
+            if done == len {
+                self.buffers.pop_front();
+            } else if let Some(front) = self.buffers.front_mut() {
+                *front = (addr.unchecked_add(done as u64), len - done);
+            }
+
+            if done < wanted {
+                break;
+            }
+        }
+
+        Ok(total)
+    }
+
+    // Keeps the first `offset` bytes and returns a consumer for the rest.
+    fn split_at(&mut self, offset: usize) -> Result<Self> {
+        let mut remaining = offset;
+        let mut index = 0;
+        while index < self.buffers.len() {
+            let (addr, len) = self.buffers[index];
+            if remaining < len {
+                if remaining > 0 {
+                    self.buffers[index] = (addr, remaining);
+                    self.buffers.insert(
+                        index + 1,
+                        (addr.unchecked_add(remaining as u64), len - remaining),
+                    );
+                    index += 1;
+                }
+                break;
+            }
+            remaining -= len;
+            index += 1;
+        }
+
+        if index == self.buffers.len() && remaining > 0 {
+            return Err(Error::SplitOutOfBounds(offset));
+        }
+
+        Ok(DescriptorChainConsumer {
+            mem: self.mem,
+            buffers: self.buffers.split_off(index),
+            bytes_consumed: 0,
+        })
+    }
+}
+
+/// Provides high-level interface over the sequence of readable descriptors
+/// in a descriptor chain.
+#[derive(Clone)]
+pub struct Reader<'a> {
+    buffer: DescriptorChainConsumer<'a>,
+}
+
+impl<'a> Reader<'a> {
+    /// Construct a new Reader wrapper over the readable descriptors of `desc_chain`.
+    pub fn new(desc_chain: DescriptorChain<'a>) -> Result<Reader<'a>> {
+        let mem = desc_chain.mem;
+        Ok(Reader {
+            buffer: DescriptorChainConsumer::new(mem, desc_chain.into_iter().readable())?,
+        })
+    }
+
+    /// Reads an object from the descriptor chain buffer.
+    pub fn read_obj<T: ByteValued>(&mut self) -> io::Result<T> {
+        let mut obj = T::default();
+        self.read_exact(obj.as_mut_slice())?;
+        Ok(obj)
+    }
+
+    /// Reads `count` bytes from the descriptor chain buffer and writes them
+    /// to `dst`. Returns the number of bytes written, which may be less than
+    /// `count` if there isn't enough data left in the chain.
+    pub fn read_to<F: Write>(&mut self, mut dst: F, count: usize) -> io::Result<usize> {
+        let mem = self.buffer.mem;
+        self.buffer.consume(count, |addr, len| {
+            mem.write_to(addr, &mut dst, len).map_err(io_error)
+        })
+    }
+
+    /// Reads exactly `count` bytes from the descriptor chain buffer and
+    /// writes them to `dst`.
+    pub fn read_exact_to<F: Write>(&mut self, dst: F, count: usize) -> io::Result<()> {
+        if self.read_to(dst, count)? != count {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "failed to fill the whole buffer",
+            ));
+        }
+        Ok(())
+    }
+
+    /// Returns the number of bytes left to be read from the descriptor chain.
+    pub fn available_bytes(&self) -> usize {
+        self.buffer.available_bytes()
+    }
+
+    /// Returns the number of bytes read from the descriptor chain so far.
+    pub fn bytes_read(&self) -> usize {
+        self.buffer.bytes_consumed()
+    }
+
+    /// Returns the host mapping of the bytes left to be read, so that they
+    /// can be handed over to the kernel directly. Reading through them is
+    /// not accounted for by this `Reader`.
+    pub fn iovecs(&self) -> Result<Vec<libc::iovec>> {
+        self.buffer.iovecs()
+    }
+
+    /// Splits this `Reader` into two at the given offset. This `Reader`
+    /// keeps the first `offset` bytes, the returned one gets the rest.
+    pub fn split_at(&mut self, offset: usize) -> Result<Reader<'a>> {
+        Ok(Reader {
+            buffer: self.buffer.split_at(offset)?,
+        })
+    }
+}
+
+impl<'a> Read for Reader<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mem = self.buffer.mem;
+        let mut offset = 0;
+        self.buffer.consume(buf.len(), |addr, len| {
+            mem.read_slice(&mut buf[offset..offset + len], addr)
+                .map_err(io_error)?;
+            offset += len;
+            Ok(len)
+        })
+    }
+}
+
+/// Provides high-level interface over the sequence of writable descriptors
+/// in a descriptor chain.
+#[derive(Clone)]
+pub struct Writer<'a> {
+    buffer: DescriptorChainConsumer<'a>,
+}
+
+impl<'a> Writer<'a> {
+    /// Construct a new Writer wrapper over the writable descriptors of `desc_chain`.
+    pub fn new(desc_chain: DescriptorChain<'a>) -> Result<Writer<'a>> {
+        let mem = desc_chain.mem;
+        Ok(Writer {
+            buffer: DescriptorChainConsumer::new(mem, desc_chain.into_iter().writable())?,
+        })
+    }
+
+    /// Writes an object to the descriptor chain buffer.
+    pub fn write_obj<T: ByteValued>(&mut self, val: T) -> io::Result<()> {
+        self.write_all(val.as_slice())
+    }
+
+    /// Reads at most `count` bytes from `src` and writes them to the
+    /// descriptor chain buffer. Returns the number of bytes written, which
+    /// may be less than `count` if `src` or the chain runs out of space.
+    pub fn write_from<F: Read>(&mut self, mut src: F, count: usize) -> io::Result<usize> {
+        let mem = self.buffer.mem;
+        self.buffer.consume(count, |addr, len| {
+            mem.read_from(addr, &mut src, len).map_err(io_error)
+        })
+    }
+
+    /// Reads exactly `count` bytes from `src` and writes them to the
+    /// descriptor chain buffer.
+    pub fn write_all_from<F: Read>(&mut self, src: F, count: usize) -> io::Result<()> {
+        if self.write_from(src, count)? != count {
+            return Err(io::Error::new(
+                io::ErrorKind::WriteZero,
+                "failed to write the whole buffer",
+            ));
+        }
+        Ok(())
+    }
+
+    /// Returns the number of bytes left to be written to the descriptor chain.
+    pub fn available_bytes(&self) -> usize {
+        self.buffer.available_bytes()
+    }
+
+    /// Returns the number of bytes written to the descriptor chain so far.
+    pub fn bytes_written(&self) -> usize {
+        self.buffer.bytes_consumed()
+    }
+
+    /// Returns the host mapping of the bytes left to be written, so that
+    /// they can be handed over to the kernel directly. Writing through them
+    /// is not accounted for by this `Writer`.
+    pub fn iovecs(&self) -> Result<Vec<libc::iovec>> {
+        self.buffer.iovecs()
+    }
+
+    /// Returns the guest address the next byte would be written to, if any
+    /// space is left.
+    pub fn next_guest_address(&self) -> Option<GuestAddress> {
+        self.buffer.buffers.front().map(|&(addr, _)| addr)
+    }
+
+    /// Splits this `Writer` into two at the given offset. This `Writer`
+    /// keeps the first `offset` bytes, the returned one gets the rest.
+    pub fn split_at(&mut self, offset: usize) -> Result<Writer<'a>> {
+        Ok(Writer {
+            buffer: self.buffer.split_at(offset)?,
+        })
+    }
+}
+
+impl<'a> Write for Writer<'a> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mem = self.buffer.mem;
+        let mut offset = 0;
+        self.buffer.consume(buf.len(), |addr, len| {
+            mem.write_slice(&buf[offset..offset + len], addr)
+                .map_err(io_error)?;
+            offset += len;
+            Ok(len)
+        })
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        // Nothing to flush since the writes go straight into guest memory.
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::queue::tests::VirtQueue;
+    use crate::queue::{VIRTQ_DESC_F_NEXT, VIRTQ_DESC_F_WRITE};
+
+    const QUEUE_SIZE: u16 = 16;
+
+    #[derive(Copy, Clone, PartialEq)]
+    enum DescriptorType {
+        Readable,
+        Writable,
+    }
+
+    // Lays the descriptors out back to back, starting at `buffers_start`,
+    // and returns the resulting chain.
+    fn create_descriptor_chain<'a>(
+        mem: &'a GuestMemoryMmap,
+        vq: &VirtQueue,
+        buffers_start: GuestAddress,
+        descriptors: &[(DescriptorType, u32)],
+    ) -> DescriptorChain<'a> {
+        let mut addr = buffers_start;
+        for (index, &(desc_type, len)) in descriptors.iter().enumerate() {
+            let mut flags = 0;
+            if desc_type == DescriptorType::Writable {
+                flags |= VIRTQ_DESC_F_WRITE;
+            }
+            if index + 1 < descriptors.len() {
+                flags |= VIRTQ_DESC_F_NEXT;
+            }
+
+            vq.dtable[index].set(addr.raw_value(), len, flags, (index + 1) as u16);
+            addr = addr.unchecked_add(u64::from(len));
+        }
+
+        DescriptorChain::checked_new(mem, vq.start(), QUEUE_SIZE, 0, None).unwrap()
+    }
+
+    #[test]
+    fn test_reader_and_writer_split_descriptors() {
+        use DescriptorType::*;
+
+        let mem = &GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = VirtQueue::new(GuestAddress(0), mem, QUEUE_SIZE);
+        let chain = create_descriptor_chain(
+            mem,
+            &vq,
+            GuestAddress(0x1000),
+            &[
+                (Readable, 8),
+                (Readable, 16),
+                (Readable, 18),
+                (Writable, 64),
+                (Writable, 1),
+            ],
+        );
+
+        let reader = Reader::new(chain.clone()).unwrap();
+        assert_eq!(reader.available_bytes(), 42);
+        assert_eq!(reader.bytes_read(), 0);
+
+        let writer = Writer::new(chain).unwrap();
+        assert_eq!(writer.available_bytes(), 65);
+        assert_eq!(writer.bytes_written(), 0);
+    }
+
+    #[test]
+    fn test_reader_read_across_descriptors() {
+        use DescriptorType::*;
+
+        let mem = &GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = VirtQueue::new(GuestAddress(0), mem, QUEUE_SIZE);
+        let data: Vec<u8> = (0..32).collect();
+        mem.write_slice(&data, GuestAddress(0x1000)).unwrap();
+
+        let chain = create_descriptor_chain(
+            mem,
+            &vq,
+            GuestAddress(0x1000),
+            &[(Readable, 3), (Readable, 0), (Readable, 13), (Readable, 16)],
+        );
+        let mut reader = Reader::new(chain).unwrap();
+
+        let mut buf = [0u8; 10];
+        reader.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf[..], &data[..10]);
+        assert_eq!(reader.bytes_read(), 10);
+        assert_eq!(reader.available_bytes(), 22);
+
+        let value: u64 = reader.read_obj().unwrap();
+        assert_eq!(value.to_le_bytes(), data[10..18]);
+
+        let mut rest = Vec::new();
+        assert_eq!(reader.read_to(&mut rest, 64).unwrap(), 14);
+        assert_eq!(&rest[..], &data[18..]);
+        assert_eq!(reader.available_bytes(), 0);
+        assert_eq!(reader.bytes_read(), 32);
+
+        // Nothing left to read.
+        assert_eq!(reader.read(&mut buf).unwrap(), 0);
+        assert!(reader.read_obj::<u8>().is_err());
+    }
+
+    #[test]
+    fn test_writer_write_across_descriptors() {
+        use DescriptorType::*;
+
+        let mem = &GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = VirtQueue::new(GuestAddress(0), mem, QUEUE_SIZE);
+        let chain = create_descriptor_chain(
+            mem,
+            &vq,
+            GuestAddress(0x1000),
+            &[(Readable, 8), (Writable, 5), (Writable, 0), (Writable, 11)],
+        );
+        let mut writer = Writer::new(chain).unwrap();
+        assert_eq!(writer.available_bytes(), 16);
+
+        writer.write_obj(0x0706_0504_0302_0100u64).unwrap();
+        let data: Vec<u8> = (8..16).collect();
+        writer.write_all_from(&data[..], data.len()).unwrap();
+        assert_eq!(writer.bytes_written(), 16);
+        assert_eq!(writer.available_bytes(), 0);
+
+        // The writable buffers start right after the readable one.
+        let mut buf = [0u8; 16];
+        mem.read_slice(&mut buf, GuestAddress(0x1008)).unwrap();
+        let expected: Vec<u8> = (0..16).collect();
+        assert_eq!(&buf[..], &expected[..]);
+
+        // The chain is full.
+        assert_eq!(writer.write(&[0xff]).unwrap(), 0);
+        assert!(writer.write_all_from(&[0xffu8][..], 1).is_err());
+    }
+
+    #[test]
+    fn test_writer_short_source() {
+        use DescriptorType::*;
+
+        let mem = &GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = VirtQueue::new(GuestAddress(0), mem, QUEUE_SIZE);
+        let chain = create_descriptor_chain(
+            mem,
+            &vq,
+            GuestAddress(0x1000),
+            &[(Writable, 4), (Writable, 4)],
+        );
+        let mut writer = Writer::new(chain).unwrap();
+
+        // The source runs dry halfway through the second descriptor.
+        let data = [0xaau8; 6];
+        assert_eq!(writer.write_from(&data[..], 8).unwrap(), 6);
+        assert_eq!(writer.bytes_written(), 6);
+        assert_eq!(writer.available_bytes(), 2);
+    }
+
+    #[test]
+    fn test_split_at() {
+        use DescriptorType::*;
+
+        let mem = &GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = VirtQueue::new(GuestAddress(0), mem, QUEUE_SIZE);
+        let chain = create_descriptor_chain(
+            mem,
+            &vq,
+            GuestAddress(0x1000),
+            &[(Writable, 16), (Writable, 8), (Writable, 1)],
+        );
+
+        // Split in the middle of a descriptor.
+        let mut writer = Writer::new(chain.clone()).unwrap();
+        let other = writer.split_at(20).unwrap();
+        assert_eq!(writer.available_bytes(), 20);
+        assert_eq!(other.available_bytes(), 5);
+
+        // Split on a descriptor boundary.
+        let mut writer = Writer::new(chain.clone()).unwrap();
+        let mut status = writer.split_at(24).unwrap();
+        assert_eq!(writer.available_bytes(), 24);
+        assert_eq!(status.available_bytes(), 1);
+        status.write_obj(0x5au8).unwrap();
+        assert_eq!(mem.read_obj::<u8>(GuestAddress(0x1018)).unwrap(), 0x5a);
+
+        // Split at both ends.
+        let mut writer = Writer::new(chain.clone()).unwrap();
+        let other = writer.split_at(0).unwrap();
+        assert_eq!(writer.available_bytes(), 0);
+        assert_eq!(other.available_bytes(), 25);
+
+        let mut writer = Writer::new(chain.clone()).unwrap();
+        let other = writer.split_at(25).unwrap();
+        assert_eq!(writer.available_bytes(), 25);
+        assert_eq!(other.available_bytes(), 0);
+
+        // Out of bounds.
+        let mut writer = Writer::new(chain).unwrap();
+        match writer.split_at(26) {
+            Err(Error::SplitOutOfBounds(26)) => (),
+            _ => panic!("Splitting out of bounds should have failed"),
+        }
+    }
+
+    #[test]
+    fn test_descriptor_across_memory_regions() {
+        use DescriptorType::*;
+
+        // Two contiguous regions, followed by a third one after a hole.
+        let mem = &GuestMemoryMmap::from_ranges(&[
+            (GuestAddress(0), 0x2000),
+            (GuestAddress(0x2000), 0x1000),
+            (GuestAddress(0x4000), 0x1000),
+        ])
+        .unwrap();
+        let vq = VirtQueue::new(GuestAddress(0), mem, QUEUE_SIZE);
+
+        // A single descriptor spanning the boundary between two contiguous regions.
+        let chain = create_descriptor_chain(mem, &vq, GuestAddress(0x1ff8), &[(Writable, 16)]);
+        let mut writer = Writer::new(chain).unwrap();
+        let data: Vec<u8> = (0..16).collect();
+        writer.write_all(&data).unwrap();
+        assert_eq!(writer.bytes_written(), 16);
+
+        let chain = create_descriptor_chain(mem, &vq, GuestAddress(0x1ff8), &[(Readable, 16)]);
+        let mut reader = Reader::new(chain).unwrap();
+        let mut buf = [0u8; 16];
+        reader.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf[..], &data[..]);
+
+        // A descriptor covering the hole between the second and third regions.
+        let chain = create_descriptor_chain(mem, &vq, GuestAddress(0x2ff8), &[(Writable, 0x1010)]);
+        match Writer::new(chain) {
+            Err(Error::InvalidGuestAddress(GuestAddress(0x3000))) => (),
+            _ => panic!("Descriptor covering a memory hole should have been rejected"),
+        }
+    }
+    #[test]
+    fn test_iovecs() {
+        use DescriptorType::*;
+
+        let mem = &GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = VirtQueue::new(GuestAddress(0), mem, QUEUE_SIZE);
+        let chain = create_descriptor_chain(
+            mem,
+            &vq,
+            GuestAddress(0x1000),
+            &[
+                (Readable, 16),
+                (Writable, 512),
+                (Writable, 512),
+                (Writable, 1),
+            ],
+        );
+        let host_base = mem.get_host_address(GuestAddress(0x1000)).unwrap() as usize;
+
+        let reader = Reader::new(chain.clone()).unwrap();
+        let iovecs = reader.iovecs().unwrap();
+        assert_eq!(iovecs.len(), 1);
+        assert_eq!(iovecs[0].iov_base as usize, host_base);
+        assert_eq!(iovecs[0].iov_len, 16);
+
+        let mut writer = Writer::new(chain).unwrap();
+        let status_writer = writer.split_at(1024).unwrap();
+        let iovecs = writer.iovecs().unwrap();
+        assert_eq!(iovecs.len(), 2);
+        assert_eq!(iovecs[0].iov_base as usize, host_base + 16);
+        assert_eq!(iovecs[1].iov_base as usize, host_base + 16 + 512);
+        assert_eq!(iovecs[1].iov_len, 512);
+        assert_eq!(
+            status_writer.next_guest_address(),
+            Some(GuestAddress(0x1000 + 16 + 1024))
+        );
+
+        writer.write_all(&[0u8; 1024]).unwrap();
+        assert!(writer.iovecs().unwrap().is_empty());
+        assert_eq!(writer.next_guest_address(), None);
+    }
+}
@@ -9,6 +9,9 @@
 use super::*;
 use arc_swap::ArcSwap;
 use std::sync::Arc;
+use std::thread;
+pub(crate) use vm_device::{add_snapshot_state, snapshot_state};
+use vm_device::{MigratableError, Snapshot, Snapshotable};
 use vm_memory::{GuestAddress, GuestMemoryMmap, GuestUsize};
 use vmm_sys_util::eventfd::EventFd;
 
@@ -30,10 +33,35 @@ pub trait VirtioInterrupt: Send + Sync {
     ) -> Option<&EventFd> {
         None
     }
+
+    /// Notifies the driver that the device configuration space has been
+    /// updated. The transport sets the configuration change bit in the
+    /// interrupt status register and fires the configuration interrupt.
+    fn signal_config_change(&self) -> std::result::Result<(), std::io::Error> {
+        self.trigger(&VirtioInterruptType::Config, None)
+    }
+}
+
+/// Direction of a device access to a driver provided buffer.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DmaAccess {
+    /// The device reads from the buffer.
+    Read,
+    /// The device writes to the buffer.
+    Write,
 }
 
-pub type VirtioIommuRemapping =
-    Box<dyn Fn(u64) -> std::result::Result<u64, std::io::Error> + Send + Sync>;
+/// Translates the addresses provided by the driver into guest physical
+/// addresses, e.g. when the device sits behind a virtio-iommu. Queues with no
+/// mapping installed use the driver addresses as they are.
+pub trait DmaMapping: Send + Sync {
+    fn translate(
+        &self,
+        addr: u64,
+        len: u64,
+        access: DmaAccess,
+    ) -> std::result::Result<u64, std::io::Error>;
+}
 
 #[derive(Clone)]
 pub struct VirtioSharedMemory {
@@ -55,7 +83,10 @@ pub struct VirtioSharedMemoryList {
 /// and all the events, memory, and queues for device operation will be moved into the device.
 /// Optionally, a virtio device can implement device reset in which it returns said resources and
 /// resets its internal.
-pub trait VirtioDevice: Send {
+///
+/// The transport saves the device state along with its own, the queues included, and activates
+/// the device again when a restored VM is resumed.
+pub trait VirtioDevice: Send + Snapshotable {
     /// The virtio device type.
     fn device_type(&self) -> u32;
 
@@ -93,6 +124,12 @@ pub trait VirtioDevice: Send {
         None
     }
 
+    /// Returns whether the device has stopped processing its queues, with no
+    /// request left in flight. Polled after a reset, before unplugging it.
+    fn ready_to_remove(&self) -> bool {
+        true
+    }
+
     /// Returns the list of shared memory regions required by the device.
     fn get_shm_regions(&self) -> Option<VirtioSharedMemoryList> {
         None
@@ -103,6 +140,21 @@ pub trait VirtioDevice: Send {
     }
 }
 
+/// Waits for the epoll threads of a device to exit once they were told to,
+/// so that they no longer access the queues when the device gets activated
+/// again.
+pub fn join_epoll_threads<E: std::fmt::Debug>(
+    threads: Option<Vec<thread::JoinHandle<std::result::Result<(), E>>>>,
+) {
+    for thread in threads.into_iter().flatten() {
+        match thread.join() {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => error!("Epoll thread failed: {:?}", e),
+            Err(_) => error!("Epoll thread panicked"),
+        }
+    }
+}
+
 /// Trait providing address translation the same way a physical DMA remapping
 /// table would provide translation between an IOVA and a physical address.
 /// The goal of this trait is to be used by virtio devices to perform the
@@ -232,3 +284,40 @@ macro_rules! virtio_ctrl_q_pausable {
         }
     };
 }
+
+// Pausable implementation for the devices whose handler is registered with a
+// device event loop, through an `EpollHandlerHandle` kept as `handler`.
+#[macro_export]
+macro_rules! event_loop_pausable {
+    ($type:ident) => {
+        impl Pausable for $type {
+            fn pause(&mut self) -> result::Result<(), MigratableError> {
+                debug!(
+                    "Pausing virtio-{}",
+                    VirtioDeviceType::from(self.device_type())
+                );
+                if let Some(handler) = &self.handler {
+                    handler
+                        .pause()
+                        .map_err(|e| MigratableError::Pause(e.into()))?;
+                }
+
+                Ok(())
+            }
+
+            fn resume(&mut self) -> result::Result<(), MigratableError> {
+                debug!(
+                    "Resuming virtio-{}",
+                    VirtioDeviceType::from(self.device_type())
+                );
+                if let Some(handler) = &self.handler {
+                    handler
+                        .resume()
+                        .map_err(|e| MigratableError::Resume(e.into()))?;
+                }
+
+                Ok(())
+            }
+        }
+    };
+}
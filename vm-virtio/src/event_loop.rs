@@ -0,0 +1,586 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Event loop shared by the virtio devices.
+//!
+//! Rather than each device spawning a thread running its own epoll loop, the
+//! devices register an `EpollHandler` when they get activated. Its fds are
+//! added to a single epoll instance, keyed by the id of the handler and the
+//! `DeviceEventT` of each fd, and a single thread dispatches the events to
+//! the handlers. Pausing, resuming and removing a handler are control
+//! messages processed by that thread, so that a device doesn't need kill or
+//! pause EventFds.
+//!
+//! A handler which can block while handling an event, e.g. because it does
+//! synchronous disk I/O, must say so through `is_blocking()`. It then runs on
+//! a loop of its own, so that it can't delay the other devices.
+
+use super::DeviceEventT;
+use super::Error as DeviceError;
+use epoll;
+use libc::EFD_NONBLOCK;
+use std::collections::HashMap;
+use std::io;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::result;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use vmm_sys_util::eventfd::EventFd;
+
+// Token of the control EventFd, no handler ever getting the id 0.
+const CONTROL_TOKEN: u64 = 0;
+
+/// Handles the events of a device, from a device event loop.
+pub trait EpollHandler: Send {
+    /// The fds to watch for readability, along with the event each of them
+    /// stands for.
+    fn events(&self) -> Vec<(RawFd, DeviceEventT)>;
+
+    /// Handles `event`. Returning an error removes the handler from the
+    /// loop.
+    fn handle_event(&mut self, event: DeviceEventT) -> result::Result<(), DeviceError>;
+
+    /// Whether handling an event can block, in which case the handler gets a
+    /// thread of its own.
+    fn is_blocking(&self) -> bool {
+        false
+    }
+}
+
+enum Control {
+    Add(u32, Box<dyn EpollHandler>, Sender<io::Result<()>>),
+    Pause(u32, Sender<()>),
+    Resume(u32, Sender<io::Result<()>>),
+    Remove(u32, Sender<()>),
+    Stop,
+}
+
+fn token(id: u32, event: DeviceEventT) -> u64 {
+    (u64::from(id) << 16) | u64::from(event)
+}
+
+struct RegisteredHandler {
+    handler: Box<dyn EpollHandler>,
+    paused: bool,
+}
+
+struct LoopThread {
+    epoll_fd: RawFd,
+    control_evt: EventFd,
+    control: Receiver<Control>,
+    handlers: HashMap<u32, RegisteredHandler>,
+}
+
+impl LoopThread {
+    fn add_fds(&self, id: u32, handler: &dyn EpollHandler) -> io::Result<()> {
+        for (fd, event) in handler.events() {
+            epoll::ctl(
+                self.epoll_fd,
+                epoll::ControlOptions::EPOLL_CTL_ADD,
+                fd,
+                epoll::Event::new(epoll::Events::EPOLLIN, token(id, event)),
+            )?;
+        }
+        Ok(())
+    }
+
+    // Fds which have not been added, or have already been closed, don't
+    // matter, hence the errors being ignored.
+    fn remove_fds(&self, handler: &dyn EpollHandler) {
+        for (fd, _) in handler.events() {
+            let _ = epoll::ctl(
+                self.epoll_fd,
+                epoll::ControlOptions::EPOLL_CTL_DEL,
+                fd,
+                epoll::Event::new(epoll::Events::empty(), 0),
+            );
+        }
+    }
+
+    // Returns false once the loop has to stop.
+    fn handle_control(&mut self) -> bool {
+        let _ = self.control_evt.read();
+
+        while let Ok(control) = self.control.try_recv() {
+            match control {
+                Control::Add(id, handler, result) => {
+                    let added = self.add_fds(id, handler.as_ref());
+                    if added.is_ok() {
+                        self.handlers.insert(
+                            id,
+                            RegisteredHandler {
+                                handler,
+                                paused: false,
+                            },
+                        );
+                    } else {
+                        self.remove_fds(handler.as_ref());
+                    }
+                    let _ = result.send(added);
+                }
+                // A paused handler has its fds removed, for the loop not to
+                // spin on them.
+                Control::Pause(id, done) => {
+                    if let Some(registered) = self.handlers.get_mut(&id) {
+                        if !registered.paused {
+                            registered.paused = true;
+                            let registered = &self.handlers[&id];
+                            self.remove_fds(registered.handler.as_ref());
+                        }
+                    }
+                    let _ = done.send(());
+                }
+                Control::Resume(id, result) => {
+                    let mut resumed = Ok(());
+                    if let Some(registered) = self.handlers.get(&id) {
+                        if registered.paused {
+                            resumed = self.add_fds(id, registered.handler.as_ref());
+                            if resumed.is_ok() {
+                                self.handlers.get_mut(&id).unwrap().paused = false;
+                            }
+                        }
+                    }
+                    let _ = result.send(resumed);
+                }
+                Control::Remove(id, done) => {
+                    if let Some(registered) = self.handlers.remove(&id) {
+                        self.remove_fds(registered.handler.as_ref());
+                    }
+                    let _ = done.send(());
+                }
+                Control::Stop => return false,
+            }
+        }
+
+        true
+    }
+
+    fn handle_event(&mut self, id: u32, event: DeviceEventT) {
+        let result = match self.handlers.get_mut(&id) {
+            // The handler may have been removed, or paused, while handling
+            // the previous events.
+            Some(registered) if !registered.paused => registered.handler.handle_event(event),
+            _ => return,
+        };
+
+        if let Err(e) = result {
+            error!("Stopping the handling of device events: {}", e);
+            if let Some(registered) = self.handlers.remove(&id) {
+                self.remove_fds(registered.handler.as_ref());
+            }
+        }
+    }
+
+    fn run(&mut self) -> result::Result<(), DeviceError> {
+        epoll::ctl(
+            self.epoll_fd,
+            epoll::ControlOptions::EPOLL_CTL_ADD,
+            self.control_evt.as_raw_fd(),
+            epoll::Event::new(epoll::Events::EPOLLIN, CONTROL_TOKEN),
+        )
+        .map_err(DeviceError::EpollCtl)?;
+
+        const EPOLL_EVENTS_LEN: usize = 100;
+        let mut events = vec![epoll::Event::new(epoll::Events::empty(), 0); EPOLL_EVENTS_LEN];
+
+        loop {
+            let num_events = match epoll::wait(self.epoll_fd, -1, &mut events[..]) {
+                Ok(res) => res,
+                Err(e) => {
+                    if e.kind() == io::ErrorKind::Interrupted {
+                        // It's well defined from the epoll_wait() syscall
+                        // documentation that the epoll loop can be interrupted
+                        // before any of the requested events occurred or the
+                        // timeout expired. In both those cases, epoll_wait()
+                        // returns an error of type EINTR, but this should not
+                        // be considered as a regular error. Instead it is more
+                        // appropriate to retry, by calling into epoll_wait().
+                        continue;
+                    }
+                    return Err(DeviceError::EpollWait(e));
+                }
+            };
+
+            for event in events.iter().take(num_events) {
+                let data = event.data;
+                if data == CONTROL_TOKEN {
+                    if !self.handle_control() {
+                        return Ok(());
+                    }
+                } else {
+                    self.handle_event((data >> 16) as u32, data as DeviceEventT);
+                }
+            }
+        }
+    }
+}
+
+impl Drop for LoopThread {
+    fn drop(&mut self) {
+        // Safe because the fd is owned by the loop, and not used anymore.
+        unsafe { libc::close(self.epoll_fd) };
+    }
+}
+
+/// A thread dispatching the events of a set of handlers.
+pub struct DeviceEventLoop {
+    control_evt: EventFd,
+    // Senders aren't Sync, while the loop is shared by the devices.
+    control: Mutex<Sender<Control>>,
+    next_id: AtomicU32,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl DeviceEventLoop {
+    /// Starts a new event loop, running on a thread named `name`. The thread
+    /// exits, and is joined, once the loop is dropped.
+    pub fn new(name: &str) -> io::Result<Arc<DeviceEventLoop>> {
+        let epoll_fd = epoll::create(true)?;
+        let (sender, receiver) = channel();
+        let control_evt = EventFd::new(EFD_NONBLOCK)?;
+
+        let mut loop_thread = LoopThread {
+            epoll_fd,
+            control_evt: control_evt.try_clone()?,
+            control: receiver,
+            handlers: HashMap::new(),
+        };
+        let thread = thread::Builder::new()
+            .name(name.to_string())
+            .spawn(move || {
+                if let Err(e) = loop_thread.run() {
+                    error!("Device event loop failed: {}", e);
+                }
+            })?;
+
+        Ok(Arc::new(DeviceEventLoop {
+            control_evt,
+            control: Mutex::new(sender),
+            next_id: AtomicU32::new(1),
+            thread: Some(thread),
+        }))
+    }
+
+    // Whether the caller runs from a handler of this loop, which mustn't
+    // wait for the loop.
+    fn on_loop_thread(&self) -> bool {
+        self.thread
+            .as_ref()
+            .map_or(false, |t| t.thread().id() == thread::current().id())
+    }
+
+    fn send(&self, control: Control) -> io::Result<()> {
+        self.control
+            .lock()
+            .unwrap()
+            .send(control)
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "device event loop is gone"))?;
+        self.control_evt.write(1)
+    }
+
+    /// Starts dispatching the events of `handler`, until the returned handle
+    /// is dropped.
+    pub fn add(self: &Arc<Self>, handler: Box<dyn EpollHandler>) -> io::Result<EpollHandlerHandle> {
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        let (result_sender, result) = channel();
+        self.send(Control::Add(id, handler, result_sender))?;
+        wait_result(&result)?;
+
+        Ok(EpollHandlerHandle {
+            id,
+            event_loop: Arc::clone(self),
+        })
+    }
+}
+
+impl Drop for DeviceEventLoop {
+    fn drop(&mut self) {
+        let _ = self.send(Control::Stop);
+        if self.on_loop_thread() {
+            return;
+        }
+        if let Some(thread) = self.thread.take() {
+            if thread.join().is_err() {
+                error!("Failed joining the device event loop thread");
+            }
+        }
+    }
+}
+
+fn wait_result(result: &Receiver<io::Result<()>>) -> io::Result<()> {
+    result
+        .recv()
+        .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "device event loop is gone"))?
+}
+
+lazy_static! {
+    static ref SHARED_LOOP: Mutex<Option<Arc<DeviceEventLoop>>> = Mutex::new(None);
+}
+
+/// Starts dispatching the events of `handler`, from the loop shared by the
+/// devices, or from a new loop if the handler blocks, in which case `name`
+/// names its thread.
+pub fn register(name: &str, handler: Box<dyn EpollHandler>) -> io::Result<EpollHandlerHandle> {
+    if handler.is_blocking() {
+        return DeviceEventLoop::new(name)?.add(handler);
+    }
+
+    let event_loop = {
+        let mut shared_loop = SHARED_LOOP.lock().unwrap();
+        if shared_loop.is_none() {
+            *shared_loop = Some(DeviceEventLoop::new("virtio_devices")?);
+        }
+        Arc::clone(shared_loop.as_ref().unwrap())
+    };
+    event_loop.add(handler)
+}
+
+/// A handler registered with a device event loop. Dropping it removes the
+/// handler from the loop, waiting for the handler not to be running anymore,
+/// and for the thread of the loop to exit if the handler had one of its own.
+pub struct EpollHandlerHandle {
+    id: u32,
+    event_loop: Arc<DeviceEventLoop>,
+}
+
+impl EpollHandlerHandle {
+    /// Stops dispatching events to the handler. The handler is guaranteed
+    /// not to be running anymore once this returns.
+    pub fn pause(&self) -> io::Result<()> {
+        let (done_sender, done) = channel();
+        self.event_loop.send(Control::Pause(self.id, done_sender))?;
+        done.recv()
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "device event loop is gone"))
+    }
+
+    /// Dispatches the events to the handler again, including the ones which
+    /// happened while it was paused.
+    pub fn resume(&self) -> io::Result<()> {
+        let (result_sender, result) = channel();
+        self.event_loop
+            .send(Control::Resume(self.id, result_sender))?;
+        wait_result(&result)
+    }
+}
+
+impl Drop for EpollHandlerHandle {
+    fn drop(&mut self) {
+        let (done_sender, done) = channel();
+        if let Err(e) = self.event_loop.send(Control::Remove(self.id, done_sender)) {
+            error!("Failed removing the device event handler: {}", e);
+            return;
+        }
+        // A handler dropping the handle of another one can't wait for its
+        // own loop.
+        if !self.event_loop.on_loop_thread() {
+            let _ = done.recv();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicU64;
+    use std::time::{Duration, Instant};
+
+    const FIRST_EVENT: DeviceEventT = 0;
+    const SECOND_EVENT: DeviceEventT = 1;
+
+    struct CountingHandler {
+        evts: Vec<EventFd>,
+        count: Arc<AtomicU64>,
+        blocking: bool,
+    }
+
+    impl EpollHandler for CountingHandler {
+        fn events(&self) -> Vec<(RawFd, DeviceEventT)> {
+            vec![
+                (self.evts[0].as_raw_fd(), FIRST_EVENT),
+                (self.evts[1].as_raw_fd(), SECOND_EVENT),
+            ]
+        }
+
+        fn handle_event(&mut self, event: DeviceEventT) -> result::Result<(), DeviceError> {
+            let value = self.evts[event as usize]
+                .read()
+                .map_err(DeviceError::IoError)?;
+            if self.blocking {
+                thread::sleep(Duration::from_millis(1));
+            }
+            self.count.fetch_add(value, Ordering::SeqCst);
+            Ok(())
+        }
+
+        fn is_blocking(&self) -> bool {
+            self.blocking
+        }
+    }
+
+    struct Device {
+        evts: Vec<EventFd>,
+        count: Arc<AtomicU64>,
+        handle: EpollHandlerHandle,
+    }
+
+    fn wait_for(condition: impl Fn() -> bool) -> bool {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while Instant::now() < deadline {
+            if condition() {
+                return true;
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+        condition()
+    }
+
+    #[test]
+    fn test_stress_mixed_devices() {
+        const DEVICES: usize = 16;
+        const ROUNDS: u64 = 200;
+
+        // One device out of four blocks, and gets its own thread.
+        let devices: Vec<Device> = (0..DEVICES)
+            .map(|i| {
+                let evts: Vec<EventFd> = (0..2)
+                    .map(|_| EventFd::new(EFD_NONBLOCK).unwrap())
+                    .collect();
+                let count = Arc::new(AtomicU64::new(0));
+                let handler = CountingHandler {
+                    evts: evts.iter().map(|evt| evt.try_clone().unwrap()).collect(),
+                    count: Arc::clone(&count),
+                    blocking: i % 4 == 0,
+                };
+                let handle = register(&format!("test_device{}", i), Box::new(handler)).unwrap();
+                Device {
+                    evts,
+                    count,
+                    handle,
+                }
+            })
+            .collect();
+
+        // Notify every device from several threads at once.
+        let notifiers: Vec<_> = (0..4)
+            .map(|_| {
+                let evts: Vec<EventFd> = devices
+                    .iter()
+                    .flat_map(|device| device.evts.iter())
+                    .map(|evt| evt.try_clone().unwrap())
+                    .collect();
+                thread::spawn(move || {
+                    for _ in 0..ROUNDS {
+                        for evt in evts.iter() {
+                            evt.write(1).unwrap();
+                        }
+                    }
+                })
+            })
+            .collect();
+        for notifier in notifiers {
+            notifier.join().unwrap();
+        }
+
+        let expected = 4 * 2 * ROUNDS;
+        assert!(wait_for(|| devices
+            .iter()
+            .all(|device| device.count.load(Ordering::SeqCst) == expected)));
+
+        // Paused devices don't handle events, until they are resumed.
+        for device in devices.iter().step_by(3) {
+            device.handle.pause().unwrap();
+        }
+        for device in devices.iter() {
+            device.evts[1].write(1).unwrap();
+        }
+        assert!(wait_for(|| devices
+            .iter()
+            .enumerate()
+            .all(|(i, device)| device.count.load(Ordering::SeqCst)
+                == if i % 3 == 0 { expected } else { expected + 1 })));
+        for device in devices.iter().step_by(3) {
+            device.handle.resume().unwrap();
+        }
+        assert!(wait_for(|| devices
+            .iter()
+            .all(
+                |device| device.count.load(Ordering::SeqCst) == expected + 1
+            )));
+
+        // Removed devices don't handle events anymore.
+        let counts: Vec<Arc<AtomicU64>> = devices.iter().map(|d| Arc::clone(&d.count)).collect();
+        let evts: Vec<EventFd> = devices
+            .iter()
+            .map(|d| d.evts[0].try_clone().unwrap())
+            .collect();
+        drop(devices);
+        for evt in evts.iter() {
+            evt.write(1).unwrap();
+        }
+        thread::sleep(Duration::from_millis(50));
+        assert!(counts
+            .iter()
+            .all(|count| count.load(Ordering::SeqCst) == expected + 1));
+    }
+
+    struct FailingHandler {
+        evt: EventFd,
+        count: Arc<AtomicU64>,
+    }
+
+    impl EpollHandler for FailingHandler {
+        fn events(&self) -> Vec<(RawFd, DeviceEventT)> {
+            vec![(self.evt.as_raw_fd(), FIRST_EVENT)]
+        }
+
+        fn handle_event(&mut self, event: DeviceEventT) -> result::Result<(), DeviceError> {
+            self.count.fetch_add(1, Ordering::SeqCst);
+            Err(DeviceError::UnknownEvent {
+                device: "test",
+                event,
+            })
+        }
+    }
+
+    #[test]
+    fn test_failing_handler() {
+        let event_loop = DeviceEventLoop::new("test_failing").unwrap();
+        let evt = EventFd::new(EFD_NONBLOCK).unwrap();
+        let count = Arc::new(AtomicU64::new(0));
+        let _handle = event_loop
+            .add(Box::new(FailingHandler {
+                evt: evt.try_clone().unwrap(),
+                count: Arc::clone(&count),
+            }))
+            .unwrap();
+
+        // The event is never read, but the handler is removed on its first
+        // failure rather than being called forever.
+        evt.write(1).unwrap();
+        assert!(wait_for(|| count.load(Ordering::SeqCst) == 1));
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(count.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_drop_waits_for_handler() {
+        for blocking in &[false, true] {
+            let count = Arc::new(AtomicU64::new(0));
+            let handler = CountingHandler {
+                evts: (0..2)
+                    .map(|_| EventFd::new(EFD_NONBLOCK).unwrap())
+                    .collect(),
+                count: Arc::clone(&count),
+                blocking: *blocking,
+            };
+            let handle = register("test_drop", Box::new(handler)).unwrap();
+
+            // The handler is gone, along with its thread, once the handle is.
+            drop(handle);
+            assert_eq!(Arc::strong_count(&count), 1);
+        }
+    }
+}
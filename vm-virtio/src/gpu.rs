@@ -0,0 +1,1457 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! virtio-gpu device, limited to 2D operations on a single scanout.
+//!
+//! The driver renders into resources backed by guest memory, which get
+//! copied into host side buffers on TRANSFER_TO_HOST_2D. Flushing the
+//! resource bound to the scanout updates the `Framebuffer`, which holds what
+//! the guest displays and can be read from the VMM at any time.
+
+use super::Error as DeviceError;
+use super::{
+    ActivateError, ActivateResult, DescriptorChain, DeviceEventT, Queue, VirtioDevice,
+    VirtioDeviceType, VIRTIO_F_VERSION_1,
+};
+use crate::descriptor_utils::Error as DescriptorError;
+use crate::{join_epoll_threads, Reader, VirtioInterrupt, VirtioInterruptType, Writer};
+use arc_swap::ArcSwap;
+use epoll;
+use libc::EFD_NONBLOCK;
+use std::cmp;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io::{self, Write};
+use std::mem::size_of;
+use std::os::unix::io::AsRawFd;
+use std::result;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use vm_device::{Migratable, MigratableError, Pausable, Snapshotable};
+use vm_memory::{Address, ByteValued, Bytes, GuestAddress, GuestMemoryError, GuestMemoryMmap};
+use vmm_sys_util::eventfd::EventFd;
+
+const QUEUE_SIZE: u16 = 256;
+const NUM_QUEUES: usize = 2;
+const QUEUE_SIZES: &[u16] = &[QUEUE_SIZE; NUM_QUEUES];
+
+const CONTROL_QUEUE: usize = 0;
+const CURSOR_QUEUE: usize = 1;
+
+// New descriptors are pending on the control queue.
+const CONTROL_QUEUE_EVENT: DeviceEventT = 0;
+// New descriptors are pending on the cursor queue.
+const CURSOR_QUEUE_EVENT: DeviceEventT = 1;
+// The device has been dropped.
+const KILL_EVENT: DeviceEventT = 2;
+// The device should be paused.
+const PAUSE_EVENT: DeviceEventT = 3;
+
+/// Default resolution of the scanout.
+pub const GPU_DEFAULT_WIDTH: u32 = 1024;
+pub const GPU_DEFAULT_HEIGHT: u32 = 768;
+/// Largest width or height of the scanout.
+pub const GPU_MAX_DIMENSION: u32 = 8192;
+
+// Host memory the driver can allocate for its resources.
+const MAX_RESOURCES_SIZE: u64 = 256 << 20;
+
+// Every supported format has 4 bytes per pixel.
+const BYTES_PER_PIXEL: usize = 4;
+
+// Number of display modes in the GET_DISPLAY_INFO response.
+const VIRTIO_GPU_MAX_SCANOUTS: usize = 16;
+
+// 2D commands.
+const VIRTIO_GPU_CMD_GET_DISPLAY_INFO: u32 = 0x0100;
+const VIRTIO_GPU_CMD_RESOURCE_CREATE_2D: u32 = 0x0101;
+const VIRTIO_GPU_CMD_RESOURCE_UNREF: u32 = 0x0102;
+const VIRTIO_GPU_CMD_SET_SCANOUT: u32 = 0x0103;
+const VIRTIO_GPU_CMD_RESOURCE_FLUSH: u32 = 0x0104;
+const VIRTIO_GPU_CMD_TRANSFER_TO_HOST_2D: u32 = 0x0105;
+const VIRTIO_GPU_CMD_RESOURCE_ATTACH_BACKING: u32 = 0x0106;
+const VIRTIO_GPU_CMD_RESOURCE_DETACH_BACKING: u32 = 0x0107;
+
+// Success responses.
+const VIRTIO_GPU_RESP_OK_NODATA: u32 = 0x1100;
+const VIRTIO_GPU_RESP_OK_DISPLAY_INFO: u32 = 0x1101;
+
+// Error responses.
+const VIRTIO_GPU_RESP_ERR_UNSPEC: u32 = 0x1200;
+const VIRTIO_GPU_RESP_ERR_OUT_OF_MEMORY: u32 = 0x1201;
+const VIRTIO_GPU_RESP_ERR_INVALID_SCANOUT_ID: u32 = 0x1202;
+const VIRTIO_GPU_RESP_ERR_INVALID_RESOURCE_ID: u32 = 0x1203;
+const VIRTIO_GPU_RESP_ERR_INVALID_PARAMETER: u32 = 0x1205;
+
+// The response must carry the fence of the command.
+const VIRTIO_GPU_FLAG_FENCE: u32 = 1;
+
+// Formats, named after the order of their bytes in memory.
+const VIRTIO_GPU_FORMAT_B8G8R8A8_UNORM: u32 = 1;
+const VIRTIO_GPU_FORMAT_B8G8R8X8_UNORM: u32 = 2;
+const VIRTIO_GPU_FORMAT_A8R8G8B8_UNORM: u32 = 3;
+const VIRTIO_GPU_FORMAT_X8R8G8B8_UNORM: u32 = 4;
+const VIRTIO_GPU_FORMAT_R8G8B8A8_UNORM: u32 = 67;
+const VIRTIO_GPU_FORMAT_X8B8G8R8_UNORM: u32 = 68;
+const VIRTIO_GPU_FORMAT_A8B8G8R8_UNORM: u32 = 121;
+const VIRTIO_GPU_FORMAT_R8G8B8X8_UNORM: u32 = 134;
+
+#[derive(Debug)]
+pub enum Error {
+    /// Guest gave us an invalid descriptor chain.
+    DescriptorChain(DescriptorError),
+    /// Failed reading the request.
+    ReadRequest(io::Error),
+    /// Failed writing the response.
+    WriteResponse(io::Error),
+    /// Failed accessing the backing of a resource.
+    GuestMemory(GuestMemoryError),
+    /// The backing of a resource is too small for the transfer.
+    BackingTooSmall,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::DescriptorChain(_) => write!(f, "guest gave us an invalid descriptor chain"),
+            Error::ReadRequest(_) => write!(f, "failed reading the request"),
+            Error::WriteResponse(_) => write!(f, "failed writing the response"),
+            Error::GuestMemory(_) => write!(f, "failed accessing the backing of a resource"),
+            Error::BackingTooSmall => {
+                write!(f, "the backing of a resource is too small for the transfer")
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::DescriptorChain(e) => Some(e),
+            Error::ReadRequest(e) => Some(e),
+            Error::WriteResponse(e) => Some(e),
+            Error::GuestMemory(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C, packed)]
+struct VirtioGpuConfig {
+    events_read: u32,
+    events_clear: u32,
+    num_scanouts: u32,
+    reserved: u32,
+}
+
+unsafe impl ByteValued for VirtioGpuConfig {}
+
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C, packed)]
+struct VirtioGpuCtrlHdr {
+    type_: u32,
+    flags: u32,
+    fence_id: u64,
+    ctx_id: u32,
+    padding: u32,
+}
+
+unsafe impl ByteValued for VirtioGpuCtrlHdr {}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+#[repr(C, packed)]
+struct VirtioGpuRect {
+    x: u32,
+    y: u32,
+    width: u32,
+    height: u32,
+}
+
+unsafe impl ByteValued for VirtioGpuRect {}
+
+impl VirtioGpuRect {
+    // Whether the rectangle fits within `width` x `height`.
+    fn fits(&self, width: u32, height: u32) -> bool {
+        u64::from(self.x) + u64::from(self.width) <= u64::from(width)
+            && u64::from(self.y) + u64::from(self.height) <= u64::from(height)
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C, packed)]
+struct VirtioGpuDisplayOne {
+    r: VirtioGpuRect,
+    enabled: u32,
+    flags: u32,
+}
+
+unsafe impl ByteValued for VirtioGpuDisplayOne {}
+
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C, packed)]
+struct VirtioGpuResourceCreate2d {
+    resource_id: u32,
+    format: u32,
+    width: u32,
+    height: u32,
+}
+
+unsafe impl ByteValued for VirtioGpuResourceCreate2d {}
+
+// Also the layout of RESOURCE_DETACH_BACKING.
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C, packed)]
+struct VirtioGpuResourceUnref {
+    resource_id: u32,
+    padding: u32,
+}
+
+unsafe impl ByteValued for VirtioGpuResourceUnref {}
+
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C, packed)]
+struct VirtioGpuSetScanout {
+    r: VirtioGpuRect,
+    scanout_id: u32,
+    resource_id: u32,
+}
+
+unsafe impl ByteValued for VirtioGpuSetScanout {}
+
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C, packed)]
+struct VirtioGpuResourceFlush {
+    r: VirtioGpuRect,
+    resource_id: u32,
+    padding: u32,
+}
+
+unsafe impl ByteValued for VirtioGpuResourceFlush {}
+
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C, packed)]
+struct VirtioGpuTransferToHost2d {
+    r: VirtioGpuRect,
+    offset: u64,
+    resource_id: u32,
+    padding: u32,
+}
+
+unsafe impl ByteValued for VirtioGpuTransferToHost2d {}
+
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C, packed)]
+struct VirtioGpuResourceAttachBacking {
+    resource_id: u32,
+    nr_entries: u32,
+}
+
+unsafe impl ByteValued for VirtioGpuResourceAttachBacking {}
+
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C, packed)]
+struct VirtioGpuMemEntry {
+    addr: u64,
+    length: u32,
+    padding: u32,
+}
+
+unsafe impl ByteValued for VirtioGpuMemEntry {}
+
+// Offsets of the red, green and blue bytes within a pixel, None if the
+// format isn't supported.
+fn rgb_offsets(format: u32) -> Option<[usize; 3]> {
+    match format {
+        VIRTIO_GPU_FORMAT_B8G8R8A8_UNORM | VIRTIO_GPU_FORMAT_B8G8R8X8_UNORM => Some([2, 1, 0]),
+        VIRTIO_GPU_FORMAT_A8R8G8B8_UNORM | VIRTIO_GPU_FORMAT_X8R8G8B8_UNORM => Some([1, 2, 3]),
+        VIRTIO_GPU_FORMAT_R8G8B8A8_UNORM | VIRTIO_GPU_FORMAT_R8G8B8X8_UNORM => Some([0, 1, 2]),
+        VIRTIO_GPU_FORMAT_X8B8G8R8_UNORM | VIRTIO_GPU_FORMAT_A8B8G8R8_UNORM => Some([3, 2, 1]),
+        _ => None,
+    }
+}
+
+/// What the guest displays, as last flushed by the driver. It is black
+/// until the driver sets the scanout up.
+pub struct Framebuffer {
+    width: u32,
+    height: u32,
+    data: Vec<u8>,
+}
+
+impl Framebuffer {
+    fn new(width: u32, height: u32) -> Self {
+        Framebuffer {
+            width,
+            height,
+            data: vec![0; width as usize * height as usize * BYTES_PER_PIXEL],
+        }
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    /// The pixels, row after row. Each one is 4 bytes: blue, green, red,
+    /// and an unused one.
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// Writes the content as a binary PPM image.
+    pub fn write_ppm<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        write!(w, "P6\n{} {}\n255\n", self.width, self.height)?;
+        let mut row = Vec::with_capacity(self.width as usize * 3);
+        for line in self.data.chunks(self.width as usize * BYTES_PER_PIXEL) {
+            row.clear();
+            for pixel in line.chunks(BYTES_PER_PIXEL) {
+                row.extend_from_slice(&[pixel[2], pixel[1], pixel[0]]);
+            }
+            w.write_all(&row)?;
+        }
+        w.flush()
+    }
+
+    fn clear(&mut self) {
+        for b in self.data.iter_mut() {
+            *b = 0;
+        }
+    }
+}
+
+struct Resource {
+    width: u32,
+    height: u32,
+    format: u32,
+    data: Vec<u8>,
+    // Guest memory the driver renders into, as (address, length) pairs.
+    backing: Option<Vec<(GuestAddress, u32)>>,
+}
+
+// The resource shown on the scanout, from the top left corner of `r`.
+struct Scanout {
+    resource_id: u32,
+    r: VirtioGpuRect,
+}
+
+// Response to a control command, the header aside.
+enum Response {
+    NoData,
+    DisplayInfo([VirtioGpuDisplayOne; VIRTIO_GPU_MAX_SCANOUTS]),
+    Err(u32),
+}
+
+// Copies the bytes found at `offset` in the backing of a resource.
+fn read_backing(
+    mem: &GuestMemoryMmap,
+    backing: &[(GuestAddress, u32)],
+    mut offset: u64,
+    buf: &mut [u8],
+) -> result::Result<(), Error> {
+    let mut done = 0;
+    for (addr, len) in backing {
+        if done == buf.len() {
+            break;
+        }
+        let len = u64::from(*len);
+        if offset >= len {
+            offset -= len;
+            continue;
+        }
+
+        let count = cmp::min(len - offset, (buf.len() - done) as u64) as usize;
+        let addr = addr.checked_add(offset).ok_or(Error::GuestMemory(
+            GuestMemoryError::InvalidGuestAddress(*addr),
+        ))?;
+        mem.read_slice(&mut buf[done..done + count], addr)
+            .map_err(Error::GuestMemory)?;
+        done += count;
+        offset = 0;
+    }
+
+    if done < buf.len() {
+        return Err(Error::BackingTooSmall);
+    }
+
+    Ok(())
+}
+
+// Executes the 2D commands of the driver.
+struct Renderer {
+    width: u32,
+    height: u32,
+    resources: BTreeMap<u32, Resource>,
+    resources_size: u64,
+    scanout: Option<Scanout>,
+    framebuffer: Arc<Mutex<Framebuffer>>,
+}
+
+impl Renderer {
+    fn new(framebuffer: Arc<Mutex<Framebuffer>>) -> Self {
+        let (width, height) = {
+            let fb = framebuffer.lock().unwrap();
+            (fb.width, fb.height)
+        };
+
+        Renderer {
+            width,
+            height,
+            resources: BTreeMap::new(),
+            resources_size: 0,
+            scanout: None,
+            framebuffer,
+        }
+    }
+
+    fn handle_ctrl(
+        &mut self,
+        mem: &GuestMemoryMmap,
+        avail_desc: DescriptorChain,
+    ) -> result::Result<u32, Error> {
+        let mut reader = Reader::new(avail_desc.clone()).map_err(Error::DescriptorChain)?;
+        let mut writer = Writer::new(avail_desc).map_err(Error::DescriptorChain)?;
+
+        let hdr: VirtioGpuCtrlHdr = reader.read_obj().map_err(Error::ReadRequest)?;
+        let cmd_type = hdr.type_;
+        let response = match self.execute(mem, cmd_type, &mut reader) {
+            Ok(response) => response,
+            Err(e) => {
+                error!("Failed to execute GPU command 0x{:x}: {}", cmd_type, e);
+                Response::Err(VIRTIO_GPU_RESP_ERR_UNSPEC)
+            }
+        };
+
+        let mut resp_hdr = VirtioGpuCtrlHdr {
+            type_: match response {
+                Response::NoData => VIRTIO_GPU_RESP_OK_NODATA,
+                Response::DisplayInfo(_) => VIRTIO_GPU_RESP_OK_DISPLAY_INFO,
+                Response::Err(e) => e,
+            },
+            ..Default::default()
+        };
+        // Commands complete synchronously, the fence can be signaled
+        // right away.
+        if hdr.flags & VIRTIO_GPU_FLAG_FENCE != 0 {
+            resp_hdr.flags = VIRTIO_GPU_FLAG_FENCE;
+            resp_hdr.fence_id = hdr.fence_id;
+            resp_hdr.ctx_id = hdr.ctx_id;
+        }
+
+        writer.write_obj(resp_hdr).map_err(Error::WriteResponse)?;
+        if let Response::DisplayInfo(pmodes) = response {
+            for pmode in pmodes.iter() {
+                writer.write_obj(*pmode).map_err(Error::WriteResponse)?;
+            }
+        }
+
+        Ok(writer.bytes_written() as u32)
+    }
+
+    fn execute(
+        &mut self,
+        mem: &GuestMemoryMmap,
+        cmd_type: u32,
+        reader: &mut Reader,
+    ) -> result::Result<Response, Error> {
+        let response = match cmd_type {
+            VIRTIO_GPU_CMD_GET_DISPLAY_INFO => self.display_info(),
+            VIRTIO_GPU_CMD_RESOURCE_CREATE_2D => {
+                self.resource_create_2d(reader.read_obj().map_err(Error::ReadRequest)?)
+            }
+            VIRTIO_GPU_CMD_RESOURCE_UNREF => {
+                let cmd: VirtioGpuResourceUnref = reader.read_obj().map_err(Error::ReadRequest)?;
+                self.resource_unref(cmd.resource_id)
+            }
+            VIRTIO_GPU_CMD_SET_SCANOUT => {
+                self.set_scanout(reader.read_obj().map_err(Error::ReadRequest)?)
+            }
+            VIRTIO_GPU_CMD_RESOURCE_FLUSH => {
+                self.resource_flush(reader.read_obj().map_err(Error::ReadRequest)?)
+            }
+            VIRTIO_GPU_CMD_TRANSFER_TO_HOST_2D => {
+                self.transfer_to_host_2d(mem, reader.read_obj().map_err(Error::ReadRequest)?)?
+            }
+            VIRTIO_GPU_CMD_RESOURCE_ATTACH_BACKING => self.attach_backing(reader)?,
+            VIRTIO_GPU_CMD_RESOURCE_DETACH_BACKING => {
+                let cmd: VirtioGpuResourceUnref = reader.read_obj().map_err(Error::ReadRequest)?;
+                let resource_id = cmd.resource_id;
+                match self.resources.get_mut(&resource_id) {
+                    Some(resource) => {
+                        resource.backing = None;
+                        Response::NoData
+                    }
+                    None => Response::Err(VIRTIO_GPU_RESP_ERR_INVALID_RESOURCE_ID),
+                }
+            }
+            t => {
+                debug!("Unsupported GPU command 0x{:x}", t);
+                Response::Err(VIRTIO_GPU_RESP_ERR_UNSPEC)
+            }
+        };
+
+        Ok(response)
+    }
+
+    fn display_info(&self) -> Response {
+        let mut pmodes = [VirtioGpuDisplayOne::default(); VIRTIO_GPU_MAX_SCANOUTS];
+        pmodes[0] = VirtioGpuDisplayOne {
+            r: VirtioGpuRect {
+                x: 0,
+                y: 0,
+                width: self.width,
+                height: self.height,
+            },
+            enabled: 1,
+            flags: 0,
+        };
+        Response::DisplayInfo(pmodes)
+    }
+
+    fn resource_create_2d(&mut self, cmd: VirtioGpuResourceCreate2d) -> Response {
+        let (resource_id, format, width, height) =
+            (cmd.resource_id, cmd.format, cmd.width, cmd.height);
+        if resource_id == 0 || self.resources.contains_key(&resource_id) {
+            return Response::Err(VIRTIO_GPU_RESP_ERR_INVALID_RESOURCE_ID);
+        }
+        if rgb_offsets(format).is_none() {
+            debug!("Unsupported GPU resource format {}", format);
+            return Response::Err(VIRTIO_GPU_RESP_ERR_INVALID_PARAMETER);
+        }
+        if width == 0 || height == 0 {
+            return Response::Err(VIRTIO_GPU_RESP_ERR_INVALID_PARAMETER);
+        }
+
+        // The dimensions come from the guest, their product can overflow.
+        let size = match u64::from(width)
+            .checked_mul(u64::from(height))
+            .and_then(|s| s.checked_mul(BYTES_PER_PIXEL as u64))
+        {
+            Some(size) if size <= MAX_RESOURCES_SIZE - self.resources_size => size,
+            _ => return Response::Err(VIRTIO_GPU_RESP_ERR_OUT_OF_MEMORY),
+        };
+        self.resources_size += size;
+        self.resources.insert(
+            resource_id,
+            Resource {
+                width,
+                height,
+                format,
+                data: vec![0; size as usize],
+                backing: None,
+            },
+        );
+
+        Response::NoData
+    }
+
+    fn resource_unref(&mut self, resource_id: u32) -> Response {
+        let resource = match self.resources.remove(&resource_id) {
+            Some(resource) => resource,
+            None => return Response::Err(VIRTIO_GPU_RESP_ERR_INVALID_RESOURCE_ID),
+        };
+        self.resources_size -= resource.data.len() as u64;
+
+        if self.scanout.as_ref().map(|s| s.resource_id) == Some(resource_id) {
+            self.scanout = None;
+            self.framebuffer.lock().unwrap().clear();
+        }
+
+        Response::NoData
+    }
+
+    fn set_scanout(&mut self, cmd: VirtioGpuSetScanout) -> Response {
+        let (r, scanout_id, resource_id) = (cmd.r, cmd.scanout_id, cmd.resource_id);
+        if scanout_id != 0 {
+            return Response::Err(VIRTIO_GPU_RESP_ERR_INVALID_SCANOUT_ID);
+        }
+
+        // The scanout gets disabled.
+        if resource_id == 0 {
+            self.scanout = None;
+            self.framebuffer.lock().unwrap().clear();
+            return Response::NoData;
+        }
+
+        let resource = match self.resources.get(&resource_id) {
+            Some(resource) => resource,
+            None => return Response::Err(VIRTIO_GPU_RESP_ERR_INVALID_RESOURCE_ID),
+        };
+        if !r.fits(resource.width, resource.height) {
+            return Response::Err(VIRTIO_GPU_RESP_ERR_INVALID_PARAMETER);
+        }
+
+        self.scanout = Some(Scanout { resource_id, r });
+        self.framebuffer.lock().unwrap().clear();
+        self.update_framebuffer(r);
+
+        Response::NoData
+    }
+
+    fn resource_flush(&mut self, cmd: VirtioGpuResourceFlush) -> Response {
+        let (r, resource_id) = (cmd.r, cmd.resource_id);
+        let resource = match self.resources.get(&resource_id) {
+            Some(resource) => resource,
+            None => return Response::Err(VIRTIO_GPU_RESP_ERR_INVALID_RESOURCE_ID),
+        };
+        if !r.fits(resource.width, resource.height) {
+            return Response::Err(VIRTIO_GPU_RESP_ERR_INVALID_PARAMETER);
+        }
+
+        if self.scanout.as_ref().map(|s| s.resource_id) == Some(resource_id) {
+            self.update_framebuffer(r);
+        }
+
+        Response::NoData
+    }
+
+    fn transfer_to_host_2d(
+        &mut self,
+        mem: &GuestMemoryMmap,
+        cmd: VirtioGpuTransferToHost2d,
+    ) -> result::Result<Response, Error> {
+        let (r, offset, resource_id) = (cmd.r, cmd.offset, cmd.resource_id);
+        let resource = match self.resources.get_mut(&resource_id) {
+            Some(resource) => resource,
+            None => return Ok(Response::Err(VIRTIO_GPU_RESP_ERR_INVALID_RESOURCE_ID)),
+        };
+        if !r.fits(resource.width, resource.height) {
+            return Ok(Response::Err(VIRTIO_GPU_RESP_ERR_INVALID_PARAMETER));
+        }
+        let backing = match resource.backing.as_ref() {
+            Some(backing) => backing,
+            None => return Ok(Response::Err(VIRTIO_GPU_RESP_ERR_UNSPEC)),
+        };
+
+        let stride = resource.width as usize * BYTES_PER_PIXEL;
+        let start = r.y as usize * stride + r.x as usize * BYTES_PER_PIXEL;
+        if r.x == 0 && r.width == resource.width {
+            // Whole rows are contiguous, in the backing as in the resource.
+            let end = start + r.height as usize * stride;
+            read_backing(mem, backing, offset, &mut resource.data[start..end])?;
+        } else {
+            let len = r.width as usize * BYTES_PER_PIXEL;
+            for row in 0..r.height as usize {
+                let src = offset
+                    .checked_add((row * stride) as u64)
+                    .ok_or(Error::BackingTooSmall)?;
+                let dst = start + row * stride;
+                read_backing(mem, backing, src, &mut resource.data[dst..dst + len])?;
+            }
+        }
+
+        Ok(Response::NoData)
+    }
+
+    fn attach_backing(&mut self, reader: &mut Reader) -> result::Result<Response, Error> {
+        let cmd: VirtioGpuResourceAttachBacking = reader.read_obj().map_err(Error::ReadRequest)?;
+        let nr_entries = cmd.nr_entries as usize;
+        if reader.available_bytes() < nr_entries * size_of::<VirtioGpuMemEntry>() {
+            return Err(Error::ReadRequest(io::Error::from(
+                io::ErrorKind::UnexpectedEof,
+            )));
+        }
+
+        let mut backing = Vec::with_capacity(nr_entries);
+        for _ in 0..nr_entries {
+            let entry: VirtioGpuMemEntry = reader.read_obj().map_err(Error::ReadRequest)?;
+            backing.push((GuestAddress(entry.addr), entry.length));
+        }
+
+        let resource_id = cmd.resource_id;
+        match self.resources.get_mut(&resource_id) {
+            Some(resource) => {
+                resource.backing = Some(backing);
+                Ok(Response::NoData)
+            }
+            None => Ok(Response::Err(VIRTIO_GPU_RESP_ERR_INVALID_RESOURCE_ID)),
+        }
+    }
+
+    // Copies the part of the scanout resource within `r` to the framebuffer,
+    // converting it on the way.
+    fn update_framebuffer(&self, r: VirtioGpuRect) {
+        let scanout = match self.scanout.as_ref() {
+            Some(scanout) => scanout,
+            None => return,
+        };
+        let resource = &self.resources[&scanout.resource_id];
+        let [red, green, blue] = rgb_offsets(resource.format).unwrap();
+        let mut fb = self.framebuffer.lock().unwrap();
+
+        // Only the part of the resource shown on the scanout is copied.
+        let (sx, sy) = (scanout.r.x, scanout.r.y);
+        let x_end = cmp::min(r.x + r.width, sx + cmp::min(scanout.r.width, fb.width));
+        let y_end = cmp::min(r.y + r.height, sy + cmp::min(scanout.r.height, fb.height));
+        let x_start = cmp::max(r.x, sx);
+        let y_start = cmp::max(r.y, sy);
+        if x_start >= x_end || y_start >= y_end {
+            return;
+        }
+
+        let src_stride = resource.width as usize * BYTES_PER_PIXEL;
+        let dst_stride = fb.width as usize * BYTES_PER_PIXEL;
+        for y in y_start..y_end {
+            for x in x_start..x_end {
+                let src = y as usize * src_stride + x as usize * BYTES_PER_PIXEL;
+                let dst = (y - sy) as usize * dst_stride + (x - sx) as usize * BYTES_PER_PIXEL;
+                let pixel = &resource.data[src..src + BYTES_PER_PIXEL];
+                fb.data[dst..dst + BYTES_PER_PIXEL].copy_from_slice(&[
+                    pixel[blue],
+                    pixel[green],
+                    pixel[red],
+                    0,
+                ]);
+            }
+        }
+    }
+}
+
+struct GpuEpollHandler {
+    queues: Vec<Queue>,
+    mem: Arc<ArcSwap<GuestMemoryMmap>>,
+    interrupt_cb: Arc<dyn VirtioInterrupt>,
+    queue_evts: Vec<EventFd>,
+    renderer: Renderer,
+    kill_evt: EventFd,
+    pause_evt: EventFd,
+}
+
+impl GpuEpollHandler {
+    fn process_ctrl_queue(&mut self) -> bool {
+        let queue = &mut self.queues[CONTROL_QUEUE];
+
+        let mut used_desc_heads = Vec::new();
+        let mem = self.mem.load();
+        for avail_desc in queue.iter(&mem) {
+            let index = avail_desc.index;
+            let len = self
+                .renderer
+                .handle_ctrl(&mem, avail_desc)
+                .unwrap_or_else(|e| {
+                    error!("Failed to process GPU command: {}", e);
+                    0
+                });
+            used_desc_heads.push((index, len));
+        }
+
+        for &(desc_index, len) in used_desc_heads.iter() {
+            queue.add_used(&mem, desc_index, len);
+        }
+        !used_desc_heads.is_empty()
+    }
+
+    // The cursor isn't part of the framebuffer, its updates are only
+    // acknowledged.
+    fn process_cursor_queue(&mut self) -> bool {
+        let queue = &mut self.queues[CURSOR_QUEUE];
+
+        let mut used_desc_heads = Vec::new();
+        let mem = self.mem.load();
+        for avail_desc in queue.iter(&mem) {
+            used_desc_heads.push(avail_desc.index);
+        }
+
+        for &desc_index in used_desc_heads.iter() {
+            queue.add_used(&mem, desc_index, 0);
+        }
+        !used_desc_heads.is_empty()
+    }
+
+    fn signal_used_queue(&self, queue_index: usize) -> result::Result<(), DeviceError> {
+        self.interrupt_cb
+            .trigger(&VirtioInterruptType::Queue, Some(&self.queues[queue_index]))
+            .map_err(|e| {
+                error!("Failed to signal used queue: {:?}", e);
+                DeviceError::FailedSignalingUsedQueue(e)
+            })
+    }
+
+    fn run(&mut self, paused: Arc<AtomicBool>) -> result::Result<(), DeviceError> {
+        // Create the epoll file descriptor
+        let epoll_fd = epoll::create(true).map_err(DeviceError::EpollCreateFd)?;
+
+        // Add events
+        for (fd, event) in &[
+            (
+                self.queue_evts[CONTROL_QUEUE].as_raw_fd(),
+                CONTROL_QUEUE_EVENT,
+            ),
+            (
+                self.queue_evts[CURSOR_QUEUE].as_raw_fd(),
+                CURSOR_QUEUE_EVENT,
+            ),
+            (self.kill_evt.as_raw_fd(), KILL_EVENT),
+            (self.pause_evt.as_raw_fd(), PAUSE_EVENT),
+        ] {
+            epoll::ctl(
+                epoll_fd,
+                epoll::ControlOptions::EPOLL_CTL_ADD,
+                *fd,
+                epoll::Event::new(epoll::Events::EPOLLIN, u64::from(*event)),
+            )
+            .map_err(DeviceError::EpollCtl)?;
+        }
+
+        const EPOLL_EVENTS_LEN: usize = 100;
+        let mut events = vec![epoll::Event::new(epoll::Events::empty(), 0); EPOLL_EVENTS_LEN];
+
+        'epoll: loop {
+            let num_events = match epoll::wait(epoll_fd, -1, &mut events[..]) {
+                Ok(res) => res,
+                Err(e) => {
+                    if e.kind() == io::ErrorKind::Interrupted {
+                        // It's well defined from the epoll_wait() syscall
+                        // documentation that the epoll loop can be interrupted
+                        // before any of the requested events occurred or the
+                        // timeout expired. In both those cases, epoll_wait()
+                        // returns an error of type EINTR, but this should not
+                        // be considered as a regular error. Instead it is more
+                        // appropriate to retry, by calling into epoll_wait().
+                        continue;
+                    }
+                    return Err(DeviceError::EpollWait(e));
+                }
+            };
+
+            for event in events.iter().take(num_events) {
+                let ev_type = event.data as u16;
+
+                let (queue_index, needs_signal) = match ev_type {
+                    CONTROL_QUEUE_EVENT | CURSOR_QUEUE_EVENT => {
+                        let queue_index = ev_type as usize;
+                        if let Err(e) = self.queue_evts[queue_index].read() {
+                            error!("Failed to get queue event: {:?}", e);
+                            break 'epoll;
+                        }
+                        let needs_signal = if queue_index == CONTROL_QUEUE {
+                            self.process_ctrl_queue()
+                        } else {
+                            self.process_cursor_queue()
+                        };
+                        (queue_index, needs_signal)
+                    }
+                    KILL_EVENT => {
+                        debug!("KILL_EVENT received, stopping epoll loop");
+                        break 'epoll;
+                    }
+                    PAUSE_EVENT => {
+                        debug!("PAUSE_EVENT received, pausing virtio-gpu epoll loop");
+                        // We loop here to handle spurious park() returns.
+                        // Until we have not resumed, the paused boolean will
+                        // be true.
+                        while paused.load(Ordering::SeqCst) {
+                            thread::park();
+                        }
+                        continue;
+                    }
+                    _ => {
+                        error!("Unknown event for virtio-gpu");
+                        continue;
+                    }
+                };
+
+                if needs_signal {
+                    if let Err(e) = self.signal_used_queue(queue_index) {
+                        error!("Failed to signal used queue: {:?}", e);
+                        break 'epoll;
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Virtio device providing the guest with a framebuffer console, through
+/// the 2D subset of virtio-gpu, on a single scanout.
+pub struct Gpu {
+    kill_evt: Option<EventFd>,
+    pause_evt: Option<EventFd>,
+    avail_features: u64,
+    acked_features: u64,
+    config: VirtioGpuConfig,
+    framebuffer: Arc<Mutex<Framebuffer>>,
+    queue_evts: Option<Vec<EventFd>>,
+    interrupt_cb: Option<Arc<dyn VirtioInterrupt>>,
+    epoll_threads: Option<Vec<thread::JoinHandle<result::Result<(), DeviceError>>>>,
+    paused: Arc<AtomicBool>,
+}
+
+impl Gpu {
+    /// Create a new virtio-gpu device, whose scanout has a fixed resolution
+    /// of `width` x `height`.
+    pub fn new(width: u32, height: u32) -> Gpu {
+        Gpu {
+            kill_evt: None,
+            pause_evt: None,
+            avail_features: 1u64 << VIRTIO_F_VERSION_1,
+            acked_features: 0u64,
+            config: VirtioGpuConfig {
+                num_scanouts: 1,
+                ..Default::default()
+            },
+            framebuffer: Arc::new(Mutex::new(Framebuffer::new(width, height))),
+            queue_evts: None,
+            interrupt_cb: None,
+            epoll_threads: None,
+            paused: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// What the guest displays. It stays valid, and keeps being updated,
+    /// across device resets.
+    pub fn framebuffer(&self) -> Arc<Mutex<Framebuffer>> {
+        self.framebuffer.clone()
+    }
+}
+
+impl Drop for Gpu {
+    fn drop(&mut self) {
+        if let Some(kill_evt) = self.kill_evt.take() {
+            // Ignore the result because there is nothing we can do about it.
+            let _ = kill_evt.write(1);
+        }
+    }
+}
+
+impl VirtioDevice for Gpu {
+    fn device_type(&self) -> u32 {
+        VirtioDeviceType::TYPE_GPU as u32
+    }
+
+    fn queue_max_sizes(&self) -> &[u16] {
+        QUEUE_SIZES
+    }
+
+    fn features(&self) -> u64 {
+        self.avail_features
+    }
+
+    fn ack_features(&mut self, value: u64) {
+        let mut v = value;
+        // Check if the guest is ACK'ing a feature that we didn't claim to have.
+        let unrequested_features = v & !self.avail_features;
+        if unrequested_features != 0 {
+            warn!("Received acknowledge request for unknown feature.");
+
+            // Don't count these features as acked.
+            v &= !unrequested_features;
+        }
+        self.acked_features |= v;
+    }
+
+    fn read_config(&self, offset: u64, mut data: &mut [u8]) {
+        let config_slice = self.config.as_slice();
+        let config_len = config_slice.len() as u64;
+        if offset >= config_len {
+            error!("Failed to read config space");
+            return;
+        }
+        if let Some(end) = offset.checked_add(data.len() as u64) {
+            // This write can't fail, offset and end are checked against config_len.
+            data.write_all(&config_slice[offset as usize..cmp::min(end, config_len) as usize])
+                .unwrap();
+        }
+    }
+
+    // Only events_clear is writable. No event is ever raised, hence there is
+    // nothing to clear.
+    fn write_config(&mut self, offset: u64, data: &[u8]) {
+        if offset != size_of::<u32>() as u64 || data.len() != size_of::<u32>() {
+            error!("Failed to write config space");
+        }
+    }
+
+    fn activate(
+        &mut self,
+        mem: Arc<ArcSwap<GuestMemoryMmap>>,
+        interrupt_cb: Arc<dyn VirtioInterrupt>,
+        queues: Vec<Queue>,
+        queue_evts: Vec<EventFd>,
+    ) -> ActivateResult {
+        if queues.len() != NUM_QUEUES || queue_evts.len() != NUM_QUEUES {
+            error!(
+                "Cannot perform activate. Expected {} queue(s), got {}",
+                NUM_QUEUES,
+                queues.len()
+            );
+            return Err(ActivateError::BadActivate);
+        }
+
+        let (self_kill_evt, kill_evt) = EventFd::new(EFD_NONBLOCK)
+            .and_then(|e| Ok((e.try_clone()?, e)))
+            .map_err(|e| {
+                error!("failed creating kill EventFd pair: {}", e);
+                ActivateError::BadActivate
+            })?;
+        self.kill_evt = Some(self_kill_evt);
+
+        let (self_pause_evt, pause_evt) = EventFd::new(EFD_NONBLOCK)
+            .and_then(|e| Ok((e.try_clone()?, e)))
+            .map_err(|e| {
+                error!("failed creating pause EventFd pair: {}", e);
+                ActivateError::BadActivate
+            })?;
+        self.pause_evt = Some(self_pause_evt);
+
+        // Save the interrupt EventFD as we need to return it on reset
+        // but clone it to pass into the thread.
+        self.interrupt_cb = Some(interrupt_cb.clone());
+
+        let mut tmp_queue_evts: Vec<EventFd> = Vec::new();
+        for queue_evt in queue_evts.iter() {
+            // Save the queue EventFD as we need to return it on reset
+            // but clone it to pass into the thread.
+            tmp_queue_evts.push(queue_evt.try_clone().map_err(|e| {
+                error!("failed to clone queue EventFd: {}", e);
+                ActivateError::BadActivate
+            })?);
+        }
+        self.queue_evts = Some(tmp_queue_evts);
+
+        // The resources don't survive a reset, the driver creates them
+        // again.
+        let mut handler = GpuEpollHandler {
+            queues,
+            mem,
+            interrupt_cb,
+            queue_evts,
+            renderer: Renderer::new(self.framebuffer.clone()),
+            kill_evt,
+            pause_evt,
+        };
+
+        let paused = self.paused.clone();
+        let mut epoll_threads = Vec::new();
+        thread::Builder::new()
+            .name("virtio_gpu".to_string())
+            .spawn(move || handler.run(paused))
+            .map(|thread| epoll_threads.push(thread))
+            .map_err(|e| {
+                error!("failed to clone the virtio-gpu epoll thread: {}", e);
+                ActivateError::BadActivate
+            })?;
+
+        self.epoll_threads = Some(epoll_threads);
+
+        Ok(())
+    }
+
+    fn reset(&mut self) -> Option<(Arc<dyn VirtioInterrupt>, Vec<EventFd>)> {
+        // We first must resume the virtio thread if it was paused.
+        if self.pause_evt.take().is_some() {
+            self.resume().ok()?;
+        }
+
+        // Then kill it.
+        if let Some(kill_evt) = self.kill_evt.take() {
+            // Ignore the result because there is nothing we can do about it.
+            let _ = kill_evt.write(1);
+        }
+        join_epoll_threads(self.epoll_threads.take());
+
+        self.acked_features = 0;
+
+        // Return the interrupt and queue EventFDs
+        Some((
+            self.interrupt_cb.take().unwrap(),
+            self.queue_evts.take().unwrap(),
+        ))
+    }
+}
+
+virtio_pausable!(Gpu);
+impl Snapshotable for Gpu {}
+impl Migratable for Gpu {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{create_guest_memory, Buffer, CountingInterrupt, VirtqueueBuilder};
+
+    const REQUEST_ADDR: GuestAddress = GuestAddress(0x10000);
+    const RESPONSE_ADDR: GuestAddress = GuestAddress(0x11000);
+    const BACKING_ADDR: GuestAddress = GuestAddress(0x20000);
+
+    fn create_handler(mem: &GuestMemoryMmap, queues: Vec<Queue>) -> GpuEpollHandler {
+        GpuEpollHandler {
+            queue_evts: queues
+                .iter()
+                .map(|_| EventFd::new(EFD_NONBLOCK).unwrap())
+                .collect(),
+            queues,
+            mem: Arc::new(ArcSwap::new(Arc::new(mem.clone()))),
+            interrupt_cb: Arc::new(CountingInterrupt::default()),
+            renderer: Renderer::new(Arc::new(Mutex::new(Framebuffer::new(4, 2)))),
+            kill_evt: EventFd::new(EFD_NONBLOCK).unwrap(),
+            pause_evt: EventFd::new(EFD_NONBLOCK).unwrap(),
+        }
+    }
+
+    // Sends a command made of a header and a body, and returns the response
+    // type.
+    fn send<T: ByteValued>(
+        mem: &GuestMemoryMmap,
+        vq: &mut crate::testing::Virtqueue,
+        handler: &mut GpuEpollHandler,
+        cmd_type: u32,
+        body: T,
+        extra: &[u8],
+    ) -> u32 {
+        let hdr = VirtioGpuCtrlHdr {
+            type_: cmd_type,
+            ..Default::default()
+        };
+        let hdr_len = size_of::<VirtioGpuCtrlHdr>();
+        let body_len = size_of::<T>();
+        mem.write_obj(hdr, REQUEST_ADDR).unwrap();
+        mem.write_obj(body, REQUEST_ADDR.unchecked_add(hdr_len as u64))
+            .unwrap();
+        mem.write_slice(
+            extra,
+            REQUEST_ADDR.unchecked_add((hdr_len + body_len) as u64),
+        )
+        .unwrap();
+        vq.add_chain(&[
+            Buffer::readable(REQUEST_ADDR, (hdr_len + body_len + extra.len()) as u32),
+            Buffer::writable(RESPONSE_ADDR, 0x200),
+        ]);
+
+        assert!(handler.process_ctrl_queue());
+        let resp: VirtioGpuCtrlHdr = mem.read_obj(RESPONSE_ADDR).unwrap();
+        resp.type_
+    }
+
+    #[test]
+    fn test_display_info() {
+        let mem = create_guest_memory(0x40000);
+        let mut vq = VirtqueueBuilder::new(&mem, QUEUE_SIZE).build();
+        let queues = vec![vq.create_queue(), vq.create_queue()];
+        let mut handler = create_handler(&mem, queues);
+
+        let hdr = VirtioGpuCtrlHdr {
+            type_: VIRTIO_GPU_CMD_GET_DISPLAY_INFO,
+            flags: VIRTIO_GPU_FLAG_FENCE,
+            fence_id: 42,
+            ..Default::default()
+        };
+        mem.write_obj(hdr, REQUEST_ADDR).unwrap();
+        let head = vq.add_chain(&[
+            Buffer::readable(REQUEST_ADDR, size_of::<VirtioGpuCtrlHdr>() as u32),
+            Buffer::writable(RESPONSE_ADDR, 0x200),
+        ]);
+        assert!(handler.process_ctrl_queue());
+        assert_eq!(vq.used_elem(0), (u32::from(head), 24 + 16 * 24));
+
+        let resp: VirtioGpuCtrlHdr = mem.read_obj(RESPONSE_ADDR).unwrap();
+        let (resp_type, flags, fence_id) = (resp.type_, resp.flags, resp.fence_id);
+        assert_eq!(resp_type, VIRTIO_GPU_RESP_OK_DISPLAY_INFO);
+        assert_eq!(flags, VIRTIO_GPU_FLAG_FENCE);
+        assert_eq!(fence_id, 42);
+
+        let pmode: VirtioGpuDisplayOne = mem.read_obj(RESPONSE_ADDR.unchecked_add(24)).unwrap();
+        let (r, enabled) = (pmode.r, pmode.enabled);
+        assert_eq!(
+            r,
+            VirtioGpuRect {
+                x: 0,
+                y: 0,
+                width: 4,
+                height: 2
+            }
+        );
+        assert_eq!(enabled, 1);
+        let pmode: VirtioGpuDisplayOne = mem.read_obj(RESPONSE_ADDR.unchecked_add(48)).unwrap();
+        let enabled = pmode.enabled;
+        assert_eq!(enabled, 0);
+    }
+
+    #[test]
+    fn test_render() {
+        let mem = create_guest_memory(0x40000);
+        let mut vq = VirtqueueBuilder::new(&mem, QUEUE_SIZE).build();
+        let queues = vec![vq.create_queue(), vq.create_queue()];
+        let mut handler = create_handler(&mem, queues);
+        let framebuffer = handler.renderer.framebuffer.clone();
+        let full = VirtioGpuRect {
+            x: 0,
+            y: 0,
+            width: 4,
+            height: 2,
+        };
+
+        // Unknown resources and formats are refused.
+        let create = VirtioGpuResourceCreate2d {
+            resource_id: 1,
+            format: 0,
+            width: 4,
+            height: 2,
+        };
+        assert_eq!(
+            send(
+                &mem,
+                &mut vq,
+                &mut handler,
+                VIRTIO_GPU_CMD_RESOURCE_CREATE_2D,
+                create,
+                &[]
+            ),
+            VIRTIO_GPU_RESP_ERR_INVALID_PARAMETER
+        );
+        let scanout = VirtioGpuSetScanout {
+            r: full,
+            scanout_id: 0,
+            resource_id: 1,
+        };
+        assert_eq!(
+            send(
+                &mem,
+                &mut vq,
+                &mut handler,
+                VIRTIO_GPU_CMD_SET_SCANOUT,
+                scanout,
+                &[]
+            ),
+            VIRTIO_GPU_RESP_ERR_INVALID_RESOURCE_ID
+        );
+
+        let create = VirtioGpuResourceCreate2d {
+            format: VIRTIO_GPU_FORMAT_R8G8B8A8_UNORM,
+            ..create
+        };
+        assert_eq!(
+            send(
+                &mem,
+                &mut vq,
+                &mut handler,
+                VIRTIO_GPU_CMD_RESOURCE_CREATE_2D,
+                create,
+                &[]
+            ),
+            VIRTIO_GPU_RESP_OK_NODATA
+        );
+
+        // The backing is split in two, in the middle of the first row.
+        let pixels: Vec<u8> = (0..32).collect();
+        mem.write_slice(&pixels[..8], BACKING_ADDR).unwrap();
+        mem.write_slice(&pixels[8..], BACKING_ADDR.unchecked_add(0x1000))
+            .unwrap();
+        let attach = VirtioGpuResourceAttachBacking {
+            resource_id: 1,
+            nr_entries: 2,
+        };
+        let entries = [
+            VirtioGpuMemEntry {
+                addr: BACKING_ADDR.raw_value(),
+                length: 8,
+                padding: 0,
+            },
+            VirtioGpuMemEntry {
+                addr: BACKING_ADDR.raw_value() + 0x1000,
+                length: 24,
+                padding: 0,
+            },
+        ];
+        let extra = [entries[0].as_slice(), entries[1].as_slice()].concat();
+        assert_eq!(
+            send(
+                &mem,
+                &mut vq,
+                &mut handler,
+                VIRTIO_GPU_CMD_RESOURCE_ATTACH_BACKING,
+                attach,
+                &extra
+            ),
+            VIRTIO_GPU_RESP_OK_NODATA
+        );
+
+        // Show the resource, and transfer a single pixel, the second one of
+        // the second row.
+        assert_eq!(
+            send(
+                &mem,
+                &mut vq,
+                &mut handler,
+                VIRTIO_GPU_CMD_SET_SCANOUT,
+                scanout,
+                &[]
+            ),
+            VIRTIO_GPU_RESP_OK_NODATA
+        );
+        let pixel = VirtioGpuRect {
+            x: 1,
+            y: 1,
+            width: 1,
+            height: 1,
+        };
+        let transfer = VirtioGpuTransferToHost2d {
+            r: pixel,
+            offset: 20,
+            resource_id: 1,
+            padding: 0,
+        };
+        assert_eq!(
+            send(
+                &mem,
+                &mut vq,
+                &mut handler,
+                VIRTIO_GPU_CMD_TRANSFER_TO_HOST_2D,
+                transfer,
+                &[]
+            ),
+            VIRTIO_GPU_RESP_OK_NODATA
+        );
+        // Nothing changes until the resource is flushed.
+        assert!(framebuffer.lock().unwrap().data().iter().all(|b| *b == 0));
+
+        let flush = VirtioGpuResourceFlush {
+            r: full,
+            resource_id: 1,
+            padding: 0,
+        };
+        assert_eq!(
+            send(
+                &mem,
+                &mut vq,
+                &mut handler,
+                VIRTIO_GPU_CMD_RESOURCE_FLUSH,
+                flush,
+                &[]
+            ),
+            VIRTIO_GPU_RESP_OK_NODATA
+        );
+        {
+            let fb = framebuffer.lock().unwrap();
+            assert_eq!(&fb.data()[20..24], &[22, 21, 20, 0]);
+            assert!(fb.data()[..20].iter().all(|b| *b == 0));
+        }
+
+        // Now the whole resource, which is contiguous in the backing.
+        let transfer = VirtioGpuTransferToHost2d {
+            r: full,
+            offset: 0,
+            ..transfer
+        };
+        send(
+            &mem,
+            &mut vq,
+            &mut handler,
+            VIRTIO_GPU_CMD_TRANSFER_TO_HOST_2D,
+            transfer,
+            &[],
+        );
+        send(
+            &mem,
+            &mut vq,
+            &mut handler,
+            VIRTIO_GPU_CMD_RESOURCE_FLUSH,
+            flush,
+            &[],
+        );
+        {
+            let fb = framebuffer.lock().unwrap();
+            assert_eq!(&fb.data()[..8], &[2, 1, 0, 0, 6, 5, 4, 0]);
+            assert_eq!(&fb.data()[28..], &[30, 29, 28, 0]);
+
+            let mut ppm = Vec::new();
+            fb.write_ppm(&mut ppm).unwrap();
+            assert_eq!(&ppm[..11], b"P6\n4 2\n255\n");
+            assert_eq!(ppm.len(), 11 + 8 * 3);
+            assert_eq!(&ppm[11..17], &[0, 1, 2, 4, 5, 6]);
+        }
+
+        // Out of bounds transfers are refused.
+        let transfer = VirtioGpuTransferToHost2d {
+            r: VirtioGpuRect {
+                x: 3,
+                width: 2,
+                ..pixel
+            },
+            ..transfer
+        };
+        assert_eq!(
+            send(
+                &mem,
+                &mut vq,
+                &mut handler,
+                VIRTIO_GPU_CMD_TRANSFER_TO_HOST_2D,
+                transfer,
+                &[]
+            ),
+            VIRTIO_GPU_RESP_ERR_INVALID_PARAMETER
+        );
+
+        // Releasing the resource disables the scanout.
+        let unref = VirtioGpuResourceUnref {
+            resource_id: 1,
+            padding: 0,
+        };
+        assert_eq!(
+            send(
+                &mem,
+                &mut vq,
+                &mut handler,
+                VIRTIO_GPU_CMD_RESOURCE_UNREF,
+                unref,
+                &[]
+            ),
+            VIRTIO_GPU_RESP_OK_NODATA
+        );
+        assert!(framebuffer.lock().unwrap().data().iter().all(|b| *b == 0));
+        assert_eq!(handler.renderer.resources_size, 0);
+    }
+
+    #[test]
+    fn test_resources_size() {
+        let framebuffer = Arc::new(Mutex::new(Framebuffer::new(4, 2)));
+        let mut renderer = Renderer::new(framebuffer);
+
+        let create = VirtioGpuResourceCreate2d {
+            resource_id: 1,
+            format: VIRTIO_GPU_FORMAT_B8G8R8X8_UNORM,
+            width: 8192,
+            height: 8192,
+        };
+        match renderer.resource_create_2d(create) {
+            Response::NoData => {}
+            _ => panic!("resource creation failed"),
+        }
+        // Out of memory, then an already used id.
+        let create = VirtioGpuResourceCreate2d {
+            resource_id: 2,
+            width: 1,
+            height: 1,
+            ..create
+        };
+        match renderer.resource_create_2d(create) {
+            Response::Err(e) => assert_eq!(e, VIRTIO_GPU_RESP_ERR_OUT_OF_MEMORY),
+            _ => panic!("resource creation succeeded"),
+        }
+        let create = VirtioGpuResourceCreate2d {
+            resource_id: 1,
+            ..create
+        };
+        match renderer.resource_create_2d(create) {
+            Response::Err(e) => assert_eq!(e, VIRTIO_GPU_RESP_ERR_INVALID_RESOURCE_ID),
+            _ => panic!("resource creation succeeded"),
+        }
+        // Dimensions whose product overflows.
+        let create = VirtioGpuResourceCreate2d {
+            resource_id: 3,
+            width: u32::MAX,
+            height: u32::MAX,
+            ..create
+        };
+        match renderer.resource_create_2d(create) {
+            Response::Err(e) => assert_eq!(e, VIRTIO_GPU_RESP_ERR_OUT_OF_MEMORY),
+            _ => panic!("resource creation succeeded"),
+        }
+    }
+}
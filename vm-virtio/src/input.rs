@@ -0,0 +1,636 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! virtio-input device, combining a keyboard and an absolute pointer.
+//!
+//! The events are evdev ones, as defined by Linux. They are produced by the
+//! VMM, e.g. from the input of a remote display client, through an
+//! `InputEvents` handle, and delivered to the guest as soon as the driver
+//! provides buffers for them.
+
+use super::Error as DeviceError;
+use super::{
+    ActivateError, ActivateResult, DeviceEventT, Queue, VirtioDevice, VirtioDeviceType,
+    VIRTIO_F_VERSION_1,
+};
+use crate::event_loop::{self, EpollHandler, EpollHandlerHandle};
+use crate::{VirtioInterrupt, VirtioInterruptType, Writer};
+use arc_swap::ArcSwap;
+use libc::EFD_NONBLOCK;
+use std::cmp;
+use std::collections::VecDeque;
+use std::io::{self, Write};
+use std::mem::size_of;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::result;
+use std::sync::{Arc, Mutex};
+use vm_device::{Migratable, MigratableError, Pausable, Snapshot, Snapshotable};
+use vm_memory::{ByteValued, GuestMemoryMmap};
+use vmm_sys_util::eventfd::EventFd;
+
+const QUEUE_SIZE: u16 = 64;
+const NUM_QUEUES: usize = 2;
+const QUEUE_SIZES: &[u16] = &[QUEUE_SIZE; NUM_QUEUES];
+
+const EVENT_QUEUE: usize = 0;
+const STATUS_QUEUE: usize = 1;
+
+// New descriptors are pending on the event queue.
+const EVENT_QUEUE_EVENT: DeviceEventT = 0;
+// New descriptors are pending on the status queue.
+const STATUS_QUEUE_EVENT: DeviceEventT = 1;
+// Some events have been sent by the VMM.
+const INPUT_EVENT: DeviceEventT = 2;
+
+// Events waiting for the driver to provide buffers, beyond which new ones
+// get dropped.
+const MAX_PENDING_EVENTS: usize = 1024;
+
+// Configuration selectors.
+const VIRTIO_INPUT_CFG_ID_NAME: u8 = 0x01;
+const VIRTIO_INPUT_CFG_ID_DEVIDS: u8 = 0x03;
+const VIRTIO_INPUT_CFG_EV_BITS: u8 = 0x11;
+const VIRTIO_INPUT_CFG_ABS_INFO: u8 = 0x12;
+
+const INPUT_NAME: &[u8] = b"Cloud Hypervisor Input";
+const BUS_VIRTUAL: u16 = 0x06;
+
+/// Event types.
+pub const EV_SYN: u16 = 0x00;
+pub const EV_KEY: u16 = 0x01;
+pub const EV_REL: u16 = 0x02;
+pub const EV_ABS: u16 = 0x03;
+
+/// Event codes.
+pub const SYN_REPORT: u16 = 0x00;
+pub const REL_WHEEL: u16 = 0x08;
+pub const ABS_X: u16 = 0x00;
+pub const ABS_Y: u16 = 0x01;
+pub const BTN_LEFT: u16 = 0x110;
+pub const BTN_RIGHT: u16 = 0x111;
+pub const BTN_MIDDLE: u16 = 0x112;
+
+/// Largest keyboard key code the device reports.
+pub const KEY_MAX_CODE: u16 = 0xff;
+
+/// Range of the absolute pointer coordinates, whatever the resolution of
+/// the display.
+pub const INPUT_ABS_MAX: u32 = 0x7fff;
+
+/// An evdev event.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct InputEvent {
+    pub type_: u16,
+    pub code: u16,
+    pub value: u32,
+}
+
+impl InputEvent {
+    pub fn new(type_: u16, code: u16, value: u32) -> Self {
+        InputEvent { type_, code, value }
+    }
+
+    /// Terminates a set of events describing a single change.
+    pub fn sync() -> Self {
+        Self::new(EV_SYN, SYN_REPORT, 0)
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C, packed)]
+struct VirtioInputEvent {
+    type_: u16,
+    code: u16,
+    value: u32,
+}
+
+unsafe impl ByteValued for VirtioInputEvent {}
+
+impl From<InputEvent> for VirtioInputEvent {
+    fn from(event: InputEvent) -> Self {
+        VirtioInputEvent {
+            type_: event.type_.to_le(),
+            code: event.code.to_le(),
+            value: event.value.to_le(),
+        }
+    }
+}
+
+#[derive(Copy, Clone)]
+#[repr(C, packed)]
+struct VirtioInputConfig {
+    select: u8,
+    subsel: u8,
+    size: u8,
+    reserved: [u8; 5],
+    u: [u8; 128],
+}
+
+impl Default for VirtioInputConfig {
+    fn default() -> Self {
+        VirtioInputConfig {
+            select: 0,
+            subsel: 0,
+            size: 0,
+            reserved: [0; 5],
+            u: [0; 128],
+        }
+    }
+}
+
+unsafe impl ByteValued for VirtioInputConfig {}
+
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C, packed)]
+struct VirtioInputAbsInfo {
+    min: u32,
+    max: u32,
+    fuzz: u32,
+    flat: u32,
+    res: u32,
+}
+
+unsafe impl ByteValued for VirtioInputAbsInfo {}
+
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C, packed)]
+struct VirtioInputDevids {
+    bustype: u16,
+    vendor: u16,
+    product: u16,
+    version: u16,
+}
+
+unsafe impl ByteValued for VirtioInputDevids {}
+
+fn bitmap(codes: &[u16]) -> Vec<u8> {
+    let mut bitmap = Vec::new();
+    for &code in codes {
+        let byte = code as usize / 8;
+        if bitmap.len() <= byte {
+            bitmap.resize(byte + 1, 0);
+        }
+        bitmap[byte] |= 1 << (code % 8);
+    }
+    bitmap
+}
+
+// What the device reports for the `select` and `subsel` the driver chose,
+// empty for anything it doesn't support.
+fn config_data(select: u8, subsel: u8) -> Vec<u8> {
+    match (select, u16::from(subsel)) {
+        (VIRTIO_INPUT_CFG_ID_NAME, 0) => INPUT_NAME.to_vec(),
+        (VIRTIO_INPUT_CFG_ID_DEVIDS, 0) => VirtioInputDevids {
+            bustype: BUS_VIRTUAL.to_le(),
+            vendor: 0,
+            product: 0,
+            version: 1u16.to_le(),
+        }
+        .as_slice()
+        .to_vec(),
+        (VIRTIO_INPUT_CFG_EV_BITS, EV_KEY) => {
+            let mut codes: Vec<u16> = (1..=KEY_MAX_CODE).collect();
+            codes.extend_from_slice(&[BTN_LEFT, BTN_RIGHT, BTN_MIDDLE]);
+            bitmap(&codes)
+        }
+        (VIRTIO_INPUT_CFG_EV_BITS, EV_REL) => bitmap(&[REL_WHEEL]),
+        (VIRTIO_INPUT_CFG_EV_BITS, EV_ABS) => bitmap(&[ABS_X, ABS_Y]),
+        (VIRTIO_INPUT_CFG_ABS_INFO, ABS_X) | (VIRTIO_INPUT_CFG_ABS_INFO, ABS_Y) => {
+            VirtioInputAbsInfo {
+                max: INPUT_ABS_MAX.to_le(),
+                ..Default::default()
+            }
+            .as_slice()
+            .to_vec()
+        }
+        _ => Vec::new(),
+    }
+}
+
+struct PendingEvents {
+    events: Mutex<VecDeque<InputEvent>>,
+    evt: EventFd,
+}
+
+/// Sends events to the guest. It stays valid across device resets.
+#[derive(Clone)]
+pub struct InputEvents {
+    pending: Arc<PendingEvents>,
+}
+
+impl InputEvents {
+    fn new() -> io::Result<Self> {
+        Ok(InputEvents {
+            pending: Arc::new(PendingEvents {
+                events: Mutex::new(VecDeque::new()),
+                evt: EventFd::new(EFD_NONBLOCK)?,
+            }),
+        })
+    }
+
+    /// Queues `events` for the guest, which should end with a sync event.
+    /// They are dropped altogether if the driver doesn't keep up.
+    pub fn send(&self, events: &[InputEvent]) {
+        {
+            let mut pending = self.pending.events.lock().unwrap();
+            if pending.len() + events.len() > MAX_PENDING_EVENTS {
+                warn!("Dropping input events, the guest doesn't consume them");
+                return;
+            }
+            pending.extend(events);
+        }
+
+        if let Err(e) = self.pending.evt.write(1) {
+            error!("Failed to signal input events: {}", e);
+        }
+    }
+}
+
+struct InputEpollHandler {
+    queues: Vec<Queue>,
+    mem: Arc<ArcSwap<GuestMemoryMmap>>,
+    interrupt_cb: Arc<dyn VirtioInterrupt>,
+    queue_evts: Vec<EventFd>,
+    events: InputEvents,
+}
+
+impl InputEpollHandler {
+    // Delivers as many pending events as the driver provided buffers for.
+    fn process_event_queue(&mut self) -> bool {
+        let queue = &mut self.queues[EVENT_QUEUE];
+        let mut pending = self.events.pending.events.lock().unwrap();
+
+        let mut used_count = 0;
+        let mem = self.mem.load();
+        while !pending.is_empty() {
+            let avail_desc = match queue.iter(&mem).next() {
+                Some(avail_desc) => avail_desc,
+                None => break,
+            };
+            let index = avail_desc.index;
+            // Only dequeued once the driver has provided a buffer.
+            let event = VirtioInputEvent::from(pending.pop_front().unwrap());
+            let len = match Writer::new(avail_desc)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+                .and_then(|mut writer| writer.write_obj(event))
+            {
+                Ok(()) => size_of::<VirtioInputEvent>() as u32,
+                Err(e) => {
+                    error!("Failed to write input event: {}", e);
+                    0
+                }
+            };
+            queue.add_used(&mem, index, len);
+            used_count += 1;
+        }
+
+        used_count > 0
+    }
+
+    // The status the driver reports, e.g. the keyboard LEDs, doesn't have
+    // anywhere to go, it is only acknowledged.
+    fn process_status_queue(&mut self) -> bool {
+        let queue = &mut self.queues[STATUS_QUEUE];
+
+        let mut used_desc_heads = Vec::new();
+        let mem = self.mem.load();
+        for avail_desc in queue.iter(&mem) {
+            used_desc_heads.push(avail_desc.index);
+        }
+
+        for &desc_index in used_desc_heads.iter() {
+            queue.add_used(&mem, desc_index, 0);
+        }
+        !used_desc_heads.is_empty()
+    }
+
+    fn signal_used_queue(&self, queue_index: usize) -> result::Result<(), DeviceError> {
+        self.interrupt_cb
+            .trigger(&VirtioInterruptType::Queue, Some(&self.queues[queue_index]))
+            .map_err(|e| {
+                error!("Failed to signal used queue: {:?}", e);
+                DeviceError::FailedSignalingUsedQueue(e)
+            })
+    }
+}
+
+impl EpollHandler for InputEpollHandler {
+    fn events(&self) -> Vec<(RawFd, DeviceEventT)> {
+        vec![
+            (self.queue_evts[EVENT_QUEUE].as_raw_fd(), EVENT_QUEUE_EVENT),
+            (
+                self.queue_evts[STATUS_QUEUE].as_raw_fd(),
+                STATUS_QUEUE_EVENT,
+            ),
+            (self.events.pending.evt.as_raw_fd(), INPUT_EVENT),
+        ]
+    }
+
+    fn handle_event(&mut self, event: DeviceEventT) -> result::Result<(), DeviceError> {
+        let (queue_index, needs_signal) = match event {
+            EVENT_QUEUE_EVENT | STATUS_QUEUE_EVENT => {
+                let queue_index = event as usize;
+                self.queue_evts[queue_index].read().map_err(|e| {
+                    DeviceError::FailedReadingQueue {
+                        event_type: "queue",
+                        underlying: e,
+                    }
+                })?;
+                let needs_signal = if queue_index == EVENT_QUEUE {
+                    self.process_event_queue()
+                } else {
+                    self.process_status_queue()
+                };
+                (queue_index, needs_signal)
+            }
+            INPUT_EVENT => {
+                self.events
+                    .pending
+                    .evt
+                    .read()
+                    .map_err(DeviceError::IoError)?;
+                (EVENT_QUEUE, self.process_event_queue())
+            }
+            _ => {
+                return Err(DeviceError::UnknownEvent {
+                    device: "input",
+                    event,
+                })
+            }
+        };
+
+        if needs_signal {
+            self.signal_used_queue(queue_index)?;
+        }
+        Ok(())
+    }
+}
+
+/// Virtio device providing the guest with a keyboard and an absolute
+/// pointer, driven by the VMM.
+pub struct Input {
+    avail_features: u64,
+    acked_features: u64,
+    config: VirtioInputConfig,
+    events: InputEvents,
+    queue_evts: Option<Vec<EventFd>>,
+    interrupt_cb: Option<Arc<dyn VirtioInterrupt>>,
+    handler: Option<EpollHandlerHandle>,
+}
+
+impl Input {
+    /// Create a new virtio-input device.
+    pub fn new() -> io::Result<Input> {
+        Ok(Input {
+            avail_features: 1u64 << VIRTIO_F_VERSION_1,
+            acked_features: 0u64,
+            config: VirtioInputConfig::default(),
+            events: InputEvents::new()?,
+            queue_evts: None,
+            interrupt_cb: None,
+            handler: None,
+        })
+    }
+
+    /// The handle through which events are sent to the guest.
+    pub fn events(&self) -> InputEvents {
+        self.events.clone()
+    }
+
+    fn update_config(&mut self) {
+        let data = config_data(self.config.select, self.config.subsel);
+        let len = cmp::min(data.len(), self.config.u.len());
+        self.config.size = len as u8;
+        self.config.u = [0; 128];
+        self.config.u[..len].copy_from_slice(&data[..len]);
+    }
+}
+
+impl VirtioDevice for Input {
+    fn device_type(&self) -> u32 {
+        VirtioDeviceType::TYPE_INPUT as u32
+    }
+
+    fn queue_max_sizes(&self) -> &[u16] {
+        QUEUE_SIZES
+    }
+
+    fn features(&self) -> u64 {
+        self.avail_features
+    }
+
+    fn ack_features(&mut self, value: u64) {
+        let mut v = value;
+        // Check if the guest is ACK'ing a feature that we didn't claim to have.
+        let unrequested_features = v & !self.avail_features;
+        if unrequested_features != 0 {
+            warn!("Received acknowledge request for unknown feature.");
+
+            // Don't count these features as acked.
+            v &= !unrequested_features;
+        }
+        self.acked_features |= v;
+    }
+
+    fn read_config(&self, offset: u64, mut data: &mut [u8]) {
+        let config_slice = self.config.as_slice();
+        let config_len = config_slice.len() as u64;
+        if offset >= config_len {
+            error!("Failed to read config space");
+            return;
+        }
+        if let Some(end) = offset.checked_add(data.len() as u64) {
+            // This write can't fail, offset and end are checked against config_len.
+            data.write_all(&config_slice[offset as usize..cmp::min(end, config_len) as usize])
+                .unwrap();
+        }
+    }
+
+    // Only select and subsel are writable, the rest of the configuration
+    // being what they designate.
+    fn write_config(&mut self, offset: u64, data: &[u8]) {
+        let data_len = data.len() as u64;
+        if offset.checked_add(data_len).map_or(true, |end| end > 2) {
+            error!("Failed to write config space");
+            return;
+        }
+        self.config.as_mut_slice()[offset as usize..(offset + data_len) as usize]
+            .copy_from_slice(data);
+        self.update_config();
+    }
+
+    fn activate(
+        &mut self,
+        mem: Arc<ArcSwap<GuestMemoryMmap>>,
+        interrupt_cb: Arc<dyn VirtioInterrupt>,
+        queues: Vec<Queue>,
+        queue_evts: Vec<EventFd>,
+    ) -> ActivateResult {
+        if queues.len() != NUM_QUEUES || queue_evts.len() != NUM_QUEUES {
+            error!(
+                "Cannot perform activate. Expected {} queue(s), got {}",
+                NUM_QUEUES,
+                queues.len()
+            );
+            return Err(ActivateError::BadActivate);
+        }
+
+        // Save the interrupt EventFD as we need to return it on reset
+        // but clone it to pass into the handler.
+        self.interrupt_cb = Some(interrupt_cb.clone());
+
+        let mut tmp_queue_evts: Vec<EventFd> = Vec::new();
+        for queue_evt in queue_evts.iter() {
+            // Save the queue EventFD as we need to return it on reset
+            // but clone it to pass into the handler.
+            tmp_queue_evts.push(queue_evt.try_clone().map_err(|e| {
+                error!("failed to clone queue EventFd: {}", e);
+                ActivateError::BadActivate
+            })?);
+        }
+        self.queue_evts = Some(tmp_queue_evts);
+
+        let handler = InputEpollHandler {
+            queues,
+            mem,
+            interrupt_cb,
+            queue_evts,
+            events: self.events.clone(),
+        };
+
+        self.handler = Some(
+            event_loop::register("virtio_input", Box::new(handler)).map_err(|e| {
+                error!("failed to register the virtio-input handler: {}", e);
+                ActivateError::BadActivate
+            })?,
+        );
+
+        Ok(())
+    }
+
+    fn reset(&mut self) -> Option<(Arc<dyn VirtioInterrupt>, Vec<EventFd>)> {
+        // Dropping the handle removes the handler from the event loop,
+        // whether it was paused or not.
+        self.handler.take();
+
+        self.acked_features = 0;
+        // The driver finds the configuration as it was when the device was
+        // created.
+        self.config.select = 0;
+        self.config.subsel = 0;
+        self.update_config();
+
+        // Return the interrupt and queue EventFDs
+        Some((
+            self.interrupt_cb.take().unwrap(),
+            self.queue_evts.take().unwrap(),
+        ))
+    }
+}
+
+event_loop_pausable!(Input);
+// The driver selects the configuration it reads every time, only the
+// features it acked matter, which the transport restores.
+impl Snapshotable for Input {
+    fn snapshot(&self) -> result::Result<Snapshot, MigratableError> {
+        Ok(Snapshot::new(&self.id()))
+    }
+
+    fn restore(&mut self, _snapshot: Snapshot) -> result::Result<(), MigratableError> {
+        Ok(())
+    }
+}
+impl Migratable for Input {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{create_guest_memory, Buffer, CountingInterrupt, VirtqueueBuilder};
+    use vm_memory::{Bytes, GuestAddress};
+
+    fn read_selected(input: &mut Input, select: u8, subsel: u8) -> Vec<u8> {
+        input.write_config(0, &[select, subsel]);
+        let mut size = [0u8];
+        input.read_config(2, &mut size);
+        let mut data = vec![0u8; size[0] as usize];
+        input.read_config(8, &mut data);
+        data
+    }
+
+    #[test]
+    fn test_config() {
+        let mut input = Input::new().unwrap();
+
+        assert_eq!(
+            read_selected(&mut input, VIRTIO_INPUT_CFG_ID_NAME, 0),
+            INPUT_NAME
+        );
+        assert_eq!(
+            read_selected(&mut input, VIRTIO_INPUT_CFG_EV_BITS, EV_ABS as u8),
+            vec![0x03]
+        );
+        assert_eq!(
+            read_selected(&mut input, VIRTIO_INPUT_CFG_EV_BITS, EV_REL as u8),
+            vec![0x00, 0x01]
+        );
+
+        let keys = read_selected(&mut input, VIRTIO_INPUT_CFG_EV_BITS, EV_KEY as u8);
+        assert_eq!(keys.len(), BTN_MIDDLE as usize / 8 + 1);
+        assert_eq!(keys[0], 0xfe);
+        assert_eq!(keys[BTN_LEFT as usize / 8], 0x07);
+
+        let abs_info = read_selected(&mut input, VIRTIO_INPUT_CFG_ABS_INFO, ABS_Y as u8);
+        assert_eq!(abs_info.len(), size_of::<VirtioInputAbsInfo>());
+        assert_eq!(&abs_info[4..8], &INPUT_ABS_MAX.to_le_bytes());
+
+        // Nothing is reported for unsupported selectors.
+        assert!(read_selected(&mut input, VIRTIO_INPUT_CFG_EV_BITS, 0x11).is_empty());
+    }
+
+    #[test]
+    fn test_send_events() {
+        let mem = create_guest_memory(0x10000);
+        let mut vq = VirtqueueBuilder::new(&mem, QUEUE_SIZE).build();
+        let input = Input::new().unwrap();
+        let mut handler = InputEpollHandler {
+            queues: vec![vq.create_queue(), vq.create_queue()],
+            mem: Arc::new(ArcSwap::new(Arc::new(mem.clone()))),
+            interrupt_cb: Arc::new(CountingInterrupt::default()),
+            queue_evts: vec![
+                EventFd::new(EFD_NONBLOCK).unwrap(),
+                EventFd::new(EFD_NONBLOCK).unwrap(),
+            ],
+            events: input.events(),
+        };
+
+        input.events().send(&[
+            InputEvent::new(EV_KEY, 30, 1),
+            InputEvent::sync(),
+            InputEvent::new(EV_KEY, 30, 0),
+        ]);
+        assert_eq!(handler.events.pending.evt.read().unwrap(), 1);
+
+        // The events wait for buffers.
+        assert!(!handler.process_event_queue());
+
+        let head = vq.add_chain(&[Buffer::writable(GuestAddress(0x8000), 8)]);
+        vq.add_chain(&[Buffer::writable(GuestAddress(0x8008), 8)]);
+        assert!(handler.process_event_queue());
+        assert_eq!(vq.used_idx(), 2);
+        assert_eq!(vq.used_elem(0), (u32::from(head), 8));
+
+        let mut event = [0u8; 8];
+        mem.read_slice(&mut event, GuestAddress(0x8000)).unwrap();
+        assert_eq!(event, [1, 0, 30, 0, 1, 0, 0, 0]);
+        mem.read_slice(&mut event, GuestAddress(0x8008)).unwrap();
+        assert_eq!(event, [0; 8]);
+
+        // The last event is delivered once another buffer shows up.
+        assert_eq!(handler.events.pending.events.lock().unwrap().len(), 1);
+        vq.add_chain(&[Buffer::writable(GuestAddress(0x8010), 8)]);
+        assert!(handler.process_event_queue());
+        mem.read_slice(&mut event, GuestAddress(0x8010)).unwrap();
+        assert_eq!(event, [1, 0, 30, 0, 0, 0, 0, 0]);
+    }
+}
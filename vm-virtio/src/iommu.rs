@@ -7,7 +7,7 @@ use super::{
     ActivateError, ActivateResult, DescriptorChain, DeviceEventT, Queue, VirtioDevice,
     VirtioDeviceType, VIRTIO_F_VERSION_1,
 };
-use crate::{DmaRemapping, VirtioInterrupt, VirtioInterruptType};
+use crate::{join_epoll_threads, DmaRemapping, VirtioInterrupt, VirtioInterruptType};
 use arc_swap::ArcSwap;
 use epoll;
 use libc::EFD_NONBLOCK;
@@ -16,7 +16,6 @@ use std::collections::BTreeMap;
 use std::fmt::{self, Display};
 use std::io::{self, Write};
 use std::mem::size_of;
-use std::ops::Bound::Included;
 use std::os::unix::io::AsRawFd;
 use std::result;
 use std::sync::atomic::{AtomicBool, Ordering};
@@ -56,9 +55,7 @@ const MSI_IOVA_START: u64 = 0xfee0_0000;
 const MSI_IOVA_END: u64 = 0xfeef_ffff;
 
 /// Virtio IOMMU features
-#[allow(unused)]
 const VIRTIO_IOMMU_F_INPUT_RANGE: u32 = 0;
-#[allow(unused)]
 const VIRTIO_IOMMU_F_DOMAIN_BITS: u32 = 1;
 #[allow(unused)]
 const VIRTIO_IOMMU_F_MAP_UNMAP: u32 = 2;
@@ -165,11 +162,8 @@ const VIRTIO_IOMMU_S_IOERR: u8 = 1;
 const VIRTIO_IOMMU_S_UNSUPP: u8 = 2;
 #[allow(unused)]
 const VIRTIO_IOMMU_S_DEVERR: u8 = 3;
-#[allow(unused)]
 const VIRTIO_IOMMU_S_INVAL: u8 = 4;
-#[allow(unused)]
 const VIRTIO_IOMMU_S_RANGE: u8 = 5;
-#[allow(unused)]
 const VIRTIO_IOMMU_S_NOENT: u8 = 6;
 #[allow(unused)]
 const VIRTIO_IOMMU_S_FAULT: u8 = 7;
@@ -406,6 +400,7 @@ impl Request {
 
         // Create the reply
         let mut reply: Vec<u8> = Vec::new();
+        let mut status = VIRTIO_IOMMU_S_OK;
 
         let hdr_len = match req_head.type_ {
             VIRTIO_IOMMU_T_ATTACH => {
@@ -422,22 +417,31 @@ impl Request {
                 let domain = req.domain;
                 let endpoint = req.endpoint;
 
-                // Add endpoint associated with specific domain
-                mapping.endpoints.write().unwrap().insert(endpoint, domain);
+                // An external endpoint moved from another domain loses the
+                // mappings of that domain.
+                let ext_map = ext_mapping.get(&endpoint);
+                if let Some(ext_map) = ext_map {
+                    if let Some(previous) = mapping.endpoint_domain(endpoint) {
+                        if previous != domain {
+                            Request::leave_external_domain(
+                                mapping,
+                                ext_map,
+                                ext_domain_mapping,
+                                previous,
+                            )?;
+                        }
+                    }
+                }
+
+                mapping.attach(domain, endpoint);
 
                 // If the endpoint is part of the list of devices with an
                 // external mapping, insert a new entry for the corresponding
                 // domain, with the same reference to the trait.
-                if let Some(map) = ext_mapping.get(&endpoint) {
+                if let Some(map) = ext_map {
                     ext_domain_mapping.insert(domain, map.clone());
                 }
 
-                // Add new domain with no mapping if the entry didn't exist yet
-                let mut mappings = mapping.mappings.write().unwrap();
-                if !mappings.contains_key(&domain) {
-                    mappings.insert(domain, BTreeMap::new());
-                }
-
                 0
             }
             VIRTIO_IOMMU_T_DETACH => {
@@ -456,13 +460,19 @@ impl Request {
 
                 // If the endpoint is part of the list of devices with an
                 // external mapping, remove the entry for the corresponding
-                // domain.
-                if ext_mapping.contains_key(&endpoint) {
-                    ext_domain_mapping.remove(&domain);
+                // domain, along with the mappings of the domain.
+                if let Some(ext_map) = ext_mapping.get(&endpoint) {
+                    if mapping.endpoint_domain(endpoint) == Some(domain) {
+                        Request::leave_external_domain(
+                            mapping,
+                            ext_map,
+                            ext_domain_mapping,
+                            domain,
+                        )?;
+                    }
                 }
 
-                // Remove endpoint associated with specific domain
-                mapping.endpoints.write().unwrap().remove(&endpoint);
+                status = mapping.detach(domain, endpoint);
 
                 0
             }
@@ -479,25 +489,16 @@ impl Request {
                 // Copy the value to use it as a proper reference.
                 let domain = req.domain;
 
-                // Trigger external mapping if necessary.
-                if let Some(ext_map) = ext_domain_mapping.get(&domain) {
-                    let size = req.virt_end - req.virt_start + 1;
-                    ext_map
-                        .map(req.virt_start, req.phys_start, size)
-                        .map_err(Error::ExternalMapping)?;
-                }
+                status = mapping.map(domain, req.virt_start, req.virt_end, req.phys_start);
 
-                // Add new mapping associated with the domain
-                if let Some(entry) = mapping.mappings.write().unwrap().get_mut(&domain) {
-                    entry.insert(
-                        req.virt_start,
-                        Mapping {
-                            gpa: req.phys_start,
-                            size: req.virt_end - req.virt_start + 1,
-                        },
-                    );
-                } else {
-                    return Err(Error::InvalidMapRequest);
+                // Trigger external mapping if necessary.
+                if status == VIRTIO_IOMMU_S_OK {
+                    if let Some(ext_map) = ext_domain_mapping.get(&domain) {
+                        let size = req.virt_end - req.virt_start + 1;
+                        ext_map
+                            .map(req.virt_start, req.phys_start, size)
+                            .map_err(Error::ExternalMapping)?;
+                    }
                 }
 
                 0
@@ -516,17 +517,21 @@ impl Request {
                 let domain = req.domain;
                 let virt_start = req.virt_start;
 
-                // Trigger external unmapping if necessary.
-                if let Some(ext_map) = ext_domain_mapping.get(&domain) {
-                    let size = req.virt_end - virt_start + 1;
-                    ext_map
-                        .unmap(virt_start, size)
-                        .map_err(Error::ExternalUnmapping)?;
-                }
-
-                // Add new mapping associated with the domain
-                if let Some(entry) = mapping.mappings.write().unwrap().get_mut(&domain) {
-                    entry.remove(&virt_start);
+                // Remove the mappings from the domain first, as they
+                // tell which ranges need to be externally unmapped.
+                let unmapped = mapping.unmap(domain, virt_start, req.virt_end);
+                match unmapped {
+                    Ok(ranges) => {
+                        // Trigger external unmapping if necessary.
+                        if let Some(ext_map) = ext_domain_mapping.get(&domain) {
+                            for (iova, size) in ranges {
+                                ext_map
+                                    .unmap(iova, size)
+                                    .map_err(Error::ExternalUnmapping)?;
+                            }
+                        }
+                    }
+                    Err(e) => status = e,
                 }
 
                 0
@@ -574,7 +579,7 @@ impl Request {
         }
 
         let tail = VirtioIommuReqTail {
-            status: VIRTIO_IOMMU_S_OK,
+            status,
             ..Default::default()
         };
         reply.extend_from_slice(tail.as_slice());
@@ -584,6 +589,24 @@ impl Request {
 
         Ok((hdr_len as usize) + size_of::<VirtioIommuReqTail>())
     }
+
+    // Unmaps the mappings of `domain` from an external endpoint leaving it,
+    // for the device not to keep DMA access to them.
+    fn leave_external_domain(
+        mapping: &IommuMapping,
+        ext_map: &Arc<dyn ExternalDmaMapping>,
+        ext_domain_mapping: &mut BTreeMap<u32, Arc<dyn ExternalDmaMapping>>,
+        domain: u32,
+    ) -> result::Result<(), Error> {
+        ext_domain_mapping.remove(&domain);
+        for (iova, size) in mapping.domain_mappings(domain) {
+            ext_map
+                .unmap(iova, size)
+                .map_err(Error::ExternalUnmapping)?;
+        }
+
+        Ok(())
+    }
 }
 
 struct IommuEpollHandler {
@@ -758,32 +781,176 @@ struct Mapping {
 pub struct IommuMapping {
     // Domain related to an endpoint.
     endpoints: Arc<RwLock<BTreeMap<u32, u32>>>,
-    // List of mappings per domain.
+    // Mappings per domain, indexed by their first IOVA. The mappings of a
+    // domain never overlap, so the one containing an IOVA is the last one
+    // starting before it.
     mappings: Arc<RwLock<BTreeMap<u32, BTreeMap<u64, Mapping>>>>,
 }
 
+impl IommuMapping {
+    // Attaches the endpoint to the domain, creating the domain if needed.
+    // An endpoint attached to another domain is moved to the new one.
+    fn attach(&self, domain: u32, endpoint: u32) {
+        let previous = self.endpoints.write().unwrap().insert(endpoint, domain);
+        if let Some(previous) = previous {
+            if previous != domain {
+                self.remove_domain_if_unused(previous);
+            }
+        }
+
+        self.mappings
+            .write()
+            .unwrap()
+            .entry(domain)
+            .or_insert_with(BTreeMap::new);
+    }
+
+    fn detach(&self, domain: u32, endpoint: u32) -> u8 {
+        {
+            let mut endpoints = self.endpoints.write().unwrap();
+            match endpoints.get(&endpoint) {
+                Some(&d) if d == domain => {}
+                Some(_) => return VIRTIO_IOMMU_S_INVAL,
+                None => return VIRTIO_IOMMU_S_NOENT,
+            }
+            endpoints.remove(&endpoint);
+        }
+
+        self.remove_domain_if_unused(domain);
+
+        VIRTIO_IOMMU_S_OK
+    }
+
+    // The domain the endpoint is attached to, if any.
+    fn endpoint_domain(&self, endpoint: u32) -> Option<u32> {
+        self.endpoints.read().unwrap().get(&endpoint).copied()
+    }
+
+    // The (IOVA, size) of each mapping of the domain.
+    fn domain_mappings(&self, domain: u32) -> Vec<(u64, u64)> {
+        self.mappings
+            .read()
+            .unwrap()
+            .get(&domain)
+            .map(|mappings| {
+                mappings
+                    .iter()
+                    .map(|(&start, mapping)| (start, mapping.size))
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    // A domain and its mappings disappear with its last endpoint.
+    fn remove_domain_if_unused(&self, domain: u32) {
+        if !self
+            .endpoints
+            .read()
+            .unwrap()
+            .values()
+            .any(|&d| d == domain)
+        {
+            self.mappings.write().unwrap().remove(&domain);
+        }
+    }
+
+    fn map(&self, domain: u32, virt_start: u64, virt_end: u64, gpa: u64) -> u8 {
+        if virt_end < virt_start || gpa.checked_add(virt_end - virt_start).is_none() {
+            return VIRTIO_IOMMU_S_RANGE;
+        }
+
+        let mut mappings = self.mappings.write().unwrap();
+        let domain_mappings = match mappings.get_mut(&domain) {
+            Some(domain_mappings) => domain_mappings,
+            None => return VIRTIO_IOMMU_S_NOENT,
+        };
+
+        // Overlapping an existing mapping is not allowed.
+        if let Some((&start, mapping)) = domain_mappings.range(..=virt_end).next_back() {
+            if start + (mapping.size - 1) >= virt_start {
+                return VIRTIO_IOMMU_S_INVAL;
+            }
+        }
+
+        domain_mappings.insert(
+            virt_start,
+            Mapping {
+                gpa,
+                size: virt_end - virt_start + 1,
+            },
+        );
+
+        VIRTIO_IOMMU_S_OK
+    }
+
+    // Removes the mappings contained in the range, returning the (IOVA, size)
+    // of each of them. Splitting a mapping is not allowed.
+    fn unmap(
+        &self,
+        domain: u32,
+        virt_start: u64,
+        virt_end: u64,
+    ) -> result::Result<Vec<(u64, u64)>, u8> {
+        if virt_end < virt_start {
+            return Err(VIRTIO_IOMMU_S_RANGE);
+        }
+
+        let mut mappings = self.mappings.write().unwrap();
+        let domain_mappings = match mappings.get_mut(&domain) {
+            Some(domain_mappings) => domain_mappings,
+            None => return Err(VIRTIO_IOMMU_S_NOENT),
+        };
+
+        let mut unmapped = Vec::new();
+        for (&start, mapping) in domain_mappings.range(..=virt_end) {
+            let end = start + (mapping.size - 1);
+            if end < virt_start {
+                continue;
+            }
+            if start < virt_start || end > virt_end {
+                return Err(VIRTIO_IOMMU_S_RANGE);
+            }
+            unmapped.push((start, mapping.size));
+        }
+
+        for (start, _) in unmapped.iter() {
+            domain_mappings.remove(start);
+        }
+
+        Ok(unmapped)
+    }
+}
+
 impl DmaRemapping for IommuMapping {
     fn translate(&self, id: u32, addr: u64) -> std::result::Result<u64, std::io::Error> {
         debug!("Translate addr 0x{:x}", addr);
-        if let Some(domain) = self.endpoints.read().unwrap().get(&id) {
-            if let Some(mapping) = self.mappings.read().unwrap().get(domain) {
-                let range_start = if VIRTIO_IOMMU_PAGE_SIZE_MASK > addr {
-                    0
-                } else {
-                    addr - VIRTIO_IOMMU_PAGE_SIZE_MASK
-                };
-                for (&key, &value) in mapping.range((Included(&range_start), Included(&addr))) {
-                    if addr >= key && addr < key + value.size {
-                        let new_addr = addr - key + value.gpa;
-                        debug!("Into new_addr 0x{:x}", new_addr);
-                        return Ok(new_addr);
-                    }
+        let domain = self
+            .endpoints
+            .read()
+            .unwrap()
+            .get(&id)
+            .copied()
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::Other,
+                    format!("endpoint {} is not attached to any domain", id),
+                )
+            })?;
+
+        if let Some(mappings) = self.mappings.read().unwrap().get(&domain) {
+            if let Some((&start, mapping)) = mappings.range(..=addr).next_back() {
+                if addr - start < mapping.size {
+                    let new_addr = addr - start + mapping.gpa;
+                    debug!("Into new_addr 0x{:x}", new_addr);
+                    return Ok(new_addr);
                 }
             }
         }
 
-        debug!("Into same addr...");
-        Ok(addr)
+        Err(io::Error::new(
+            io::ErrorKind::Other,
+            format!("addr 0x{:x} is not mapped in domain {}", addr, domain),
+        ))
     }
 }
 
@@ -806,6 +973,14 @@ impl Iommu {
     pub fn new() -> io::Result<(Self, Arc<IommuMapping>)> {
         let config = VirtioIommuConfig {
             page_size_mask: VIRTIO_IOMMU_PAGE_SIZE_MASK,
+            input_range: VirtioIommuRange64 {
+                start: 0,
+                end: u64::MAX,
+            },
+            domain_range: VirtioIommuRange32 {
+                start: 0,
+                end: u32::MAX,
+            },
             probe_size: PROBE_PROP_SIZE,
             ..Default::default()
         };
@@ -820,6 +995,8 @@ impl Iommu {
                 kill_evt: None,
                 pause_evt: None,
                 avail_features: 1u64 << VIRTIO_F_VERSION_1
+                    | 1u64 << VIRTIO_IOMMU_F_INPUT_RANGE
+                    | 1u64 << VIRTIO_IOMMU_F_DOMAIN_BITS
                     | 1u64 << VIRTIO_IOMMU_F_MAP_UNMAP
                     | 1u64 << VIRTIO_IOMMU_F_PROBE,
                 acked_features: 0u64,
@@ -1024,6 +1201,9 @@ impl VirtioDevice for Iommu {
             // Ignore the result because there is nothing we can do about it.
             let _ = kill_evt.write(1);
         }
+        join_epoll_threads(self.epoll_threads.take());
+
+        self.acked_features = 0;
 
         // Return the interrupt and queue EventFDs
         Some((
@@ -1036,3 +1216,261 @@ impl VirtioDevice for Iommu {
 virtio_pausable!(Iommu);
 impl Snapshotable for Iommu {}
 impl Migratable for Iommu {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{create_guest_memory, Buffer, Virtqueue, VirtqueueBuilder};
+    use std::sync::Mutex;
+
+    fn new_mapping() -> IommuMapping {
+        IommuMapping {
+            endpoints: Arc::new(RwLock::new(BTreeMap::new())),
+            mappings: Arc::new(RwLock::new(BTreeMap::new())),
+        }
+    }
+
+    #[test]
+    fn test_translate() {
+        let mapping = new_mapping();
+        // Not attached yet.
+        assert!(mapping.translate(8, 0x1000).is_err());
+
+        mapping.attach(1, 8);
+        assert_eq!(mapping.map(1, 0x1000, 0x1fff, 0x10_0000), VIRTIO_IOMMU_S_OK);
+        assert_eq!(
+            mapping.map(1, 0x40_0000, 0x7f_ffff, 0x80_0000),
+            VIRTIO_IOMMU_S_OK
+        );
+
+        assert_eq!(mapping.translate(8, 0x1000).unwrap(), 0x10_0000);
+        assert_eq!(mapping.translate(8, 0x1fff).unwrap(), 0x10_0fff);
+        // Far from the start of a large mapping.
+        assert_eq!(mapping.translate(8, 0x7f_f000).unwrap(), 0xbf_f000);
+        assert!(mapping.translate(8, 0xfff).is_err());
+        assert!(mapping.translate(8, 0x2000).is_err());
+        assert!(mapping.translate(8, 0x80_0000).is_err());
+    }
+
+    #[test]
+    fn test_map_unmap() {
+        let mapping = new_mapping();
+        assert_eq!(mapping.map(1, 0x1000, 0x1fff, 0), VIRTIO_IOMMU_S_NOENT);
+
+        mapping.attach(1, 8);
+        assert_eq!(mapping.map(1, 0x2000, 0x1fff, 0), VIRTIO_IOMMU_S_RANGE);
+        assert_eq!(mapping.map(1, 0x1000, 0x2fff, 0), VIRTIO_IOMMU_S_OK);
+        assert_eq!(mapping.map(1, 0x2000, 0x3fff, 0), VIRTIO_IOMMU_S_INVAL);
+        assert_eq!(mapping.map(1, 0x0, 0x1000, 0), VIRTIO_IOMMU_S_INVAL);
+        assert_eq!(mapping.map(1, 0x3000, 0x3fff, 0x8000), VIRTIO_IOMMU_S_OK);
+
+        // Splitting a mapping is not allowed.
+        assert_eq!(mapping.unmap(1, 0x1000, 0x1fff), Err(VIRTIO_IOMMU_S_RANGE));
+        assert_eq!(
+            mapping.unmap(1, 0x0, 0xffff),
+            Ok(vec![(0x1000, 0x2000), (0x3000, 0x1000)])
+        );
+        assert!(mapping.translate(8, 0x3000).is_err());
+        assert_eq!(mapping.unmap(1, 0x0, 0xffff), Ok(vec![]));
+    }
+
+    #[test]
+    fn test_attach_detach() {
+        let mapping = new_mapping();
+        mapping.attach(1, 8);
+        mapping.attach(1, 16);
+        assert_eq!(mapping.map(1, 0x1000, 0x1fff, 0x4000), VIRTIO_IOMMU_S_OK);
+        assert_eq!(mapping.translate(16, 0x1000).unwrap(), 0x4000);
+
+        assert_eq!(mapping.detach(2, 8), VIRTIO_IOMMU_S_INVAL);
+        assert_eq!(mapping.detach(1, 8), VIRTIO_IOMMU_S_OK);
+        assert_eq!(mapping.detach(1, 8), VIRTIO_IOMMU_S_NOENT);
+        assert_eq!(mapping.translate(16, 0x1000).unwrap(), 0x4000);
+
+        // The domain goes away with its last endpoint.
+        mapping.attach(2, 16);
+        assert!(mapping.mappings.read().unwrap().get(&1).is_none());
+        mapping.attach(1, 8);
+        assert!(mapping.translate(8, 0x1000).is_err());
+    }
+
+    #[test]
+    fn test_request_status() {
+        let mem = create_guest_memory(0x10000);
+        let mut vq = VirtqueueBuilder::new(&mem, 16).build();
+        let mut queue = vq.create_queue();
+        let mapping = Arc::new(new_mapping());
+
+        let req_addr = GuestAddress(0x8000);
+        let status_addr = GuestAddress(0x9000);
+        let head = VirtioIommuReqHead {
+            type_: VIRTIO_IOMMU_T_MAP,
+            ..Default::default()
+        };
+        let map = VirtioIommuReqMap {
+            domain: 1,
+            virt_start: 0x1000,
+            virt_end: 0x1fff,
+            phys_start: 0x4000,
+            flags: VIRTIO_IOMMU_MAP_F_READ,
+        };
+        mem.write_obj(head, req_addr).unwrap();
+        mem.write_obj(
+            map,
+            req_addr.unchecked_add(size_of::<VirtioIommuReqHead>() as u64),
+        )
+        .unwrap();
+        vq.add_chain(&[
+            Buffer::readable(
+                req_addr,
+                (size_of::<VirtioIommuReqHead>() + size_of::<VirtioIommuReqMap>()) as u32,
+            ),
+            Buffer::writable(status_addr, size_of::<VirtioIommuReqTail>() as u32),
+        ]);
+
+        let desc = queue.iter(&mem).next().unwrap();
+        let len = Request::parse(
+            &desc,
+            &mem,
+            &mapping,
+            &BTreeMap::new(),
+            &mut BTreeMap::new(),
+        )
+        .unwrap();
+        assert_eq!(len, size_of::<VirtioIommuReqTail>());
+
+        // The domain doesn't exist as no endpoint was attached to it.
+        let tail: VirtioIommuReqTail = mem.read_obj(status_addr).unwrap();
+        assert_eq!(tail.status, VIRTIO_IOMMU_S_NOENT);
+    }
+
+    #[derive(Default)]
+    struct RecordingDmaMapping {
+        unmapped: Mutex<Vec<(u64, u64)>>,
+    }
+
+    impl ExternalDmaMapping for RecordingDmaMapping {
+        fn map(&self, _iova: u64, _gpa: u64, _size: u64) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn unmap(&self, iova: u64, size: u64) -> io::Result<()> {
+            self.unmapped.lock().unwrap().push((iova, size));
+            Ok(())
+        }
+    }
+
+    struct Requests<'a> {
+        mem: &'a GuestMemoryMmap,
+        vq: Virtqueue<'a>,
+        queue: Queue,
+        mapping: Arc<IommuMapping>,
+        ext_mapping: BTreeMap<u32, Arc<dyn ExternalDmaMapping>>,
+        ext_domain_mapping: BTreeMap<u32, Arc<dyn ExternalDmaMapping>>,
+    }
+
+    impl<'a> Requests<'a> {
+        // Sends a request of type `type_`, returning its status.
+        fn send<T: ByteValued>(&mut self, type_: u8, req: T) -> u8 {
+            let req_addr = GuestAddress(0x8000);
+            let status_addr = GuestAddress(0x9000);
+            let head = VirtioIommuReqHead {
+                type_,
+                ..Default::default()
+            };
+            self.mem.write_obj(head, req_addr).unwrap();
+            self.mem
+                .write_obj(
+                    req,
+                    req_addr.unchecked_add(size_of::<VirtioIommuReqHead>() as u64),
+                )
+                .unwrap();
+            self.vq.add_chain(&[
+                Buffer::readable(
+                    req_addr,
+                    (size_of::<VirtioIommuReqHead>() + size_of::<T>()) as u32,
+                ),
+                Buffer::writable(status_addr, size_of::<VirtioIommuReqTail>() as u32),
+            ]);
+
+            let desc = self.queue.iter(self.mem).next().unwrap();
+            Request::parse(
+                &desc,
+                self.mem,
+                &self.mapping,
+                &self.ext_mapping,
+                &mut self.ext_domain_mapping,
+            )
+            .unwrap();
+            let tail: VirtioIommuReqTail = self.mem.read_obj(status_addr).unwrap();
+            tail.status
+        }
+
+        fn attach(&mut self, domain: u32, endpoint: u32) -> u8 {
+            let req = VirtioIommuReqAttach {
+                domain,
+                endpoint,
+                ..Default::default()
+            };
+            self.send(VIRTIO_IOMMU_T_ATTACH, req)
+        }
+
+        fn detach(&mut self, domain: u32, endpoint: u32) -> u8 {
+            let req = VirtioIommuReqDetach {
+                domain,
+                endpoint,
+                ..Default::default()
+            };
+            self.send(VIRTIO_IOMMU_T_DETACH, req)
+        }
+
+        fn map(&mut self, domain: u32, virt_start: u64, virt_end: u64) -> u8 {
+            let req = VirtioIommuReqMap {
+                domain,
+                virt_start,
+                virt_end,
+                phys_start: 0x4000,
+                flags: VIRTIO_IOMMU_MAP_F_READ,
+            };
+            self.send(VIRTIO_IOMMU_T_MAP, req)
+        }
+    }
+
+    #[test]
+    fn test_external_endpoint_leaving_domain() {
+        let mem = create_guest_memory(0x10000);
+        let vq = VirtqueueBuilder::new(&mem, 16).build();
+        let queue = vq.create_queue();
+        let ext_map = Arc::new(RecordingDmaMapping::default());
+        let mut ext_mapping: BTreeMap<u32, Arc<dyn ExternalDmaMapping>> = BTreeMap::new();
+        ext_mapping.insert(8, ext_map.clone());
+        let mut requests = Requests {
+            mem: &mem,
+            vq,
+            queue,
+            mapping: Arc::new(new_mapping()),
+            ext_mapping,
+            ext_domain_mapping: BTreeMap::new(),
+        };
+
+        // Detaching the device unmaps the mappings of its domain.
+        assert_eq!(requests.attach(1, 8), VIRTIO_IOMMU_S_OK);
+        assert_eq!(requests.map(1, 0x1000, 0x1fff), VIRTIO_IOMMU_S_OK);
+        assert_eq!(requests.detach(1, 8), VIRTIO_IOMMU_S_OK);
+        assert_eq!(*ext_map.unmapped.lock().unwrap(), vec![(0x1000, 0x1000)]);
+        assert!(requests.ext_domain_mapping.is_empty());
+        assert!(requests.mapping.translate(8, 0x1000).is_err());
+
+        // So does moving it to another domain.
+        ext_map.unmapped.lock().unwrap().clear();
+        assert_eq!(requests.attach(1, 8), VIRTIO_IOMMU_S_OK);
+        assert_eq!(requests.map(1, 0x2000, 0x2fff), VIRTIO_IOMMU_S_OK);
+        assert_eq!(requests.attach(2, 8), VIRTIO_IOMMU_S_OK);
+        assert_eq!(*ext_map.unmapped.lock().unwrap(), vec![(0x2000, 0x1000)]);
+        assert_eq!(
+            requests.ext_domain_mapping.keys().collect::<Vec<_>>(),
+            vec![&2]
+        );
+        assert!(requests.mapping.translate(8, 0x2000).is_err());
+    }
+}
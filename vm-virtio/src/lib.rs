@@ -10,14 +10,23 @@
 
 //! Implements virtio devices, queues, and transport mechanisms.
 
+#[macro_use]
+extern crate anyhow;
 extern crate arc_swap;
 extern crate epoll;
 #[macro_use]
+extern crate lazy_static;
+#[macro_use]
 extern crate log;
 #[cfg(feature = "pci_support")]
 extern crate pci;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+extern crate serde_json;
 extern crate vhost_rs;
 extern crate virtio_bindings;
+#[macro_use]
 extern crate vm_device;
 extern crate vm_memory;
 
@@ -26,24 +35,42 @@ use std::io;
 
 #[macro_use]
 mod device;
+mod balloon;
 pub mod block;
+#[cfg(feature = "io_uring")]
+mod block_io_uring;
 mod console;
+pub mod descriptor_utils;
+pub mod event_loop;
+pub mod gpu;
+pub mod input;
 mod iommu;
 pub mod net;
+mod net_pcap;
 pub mod net_util;
+mod net_vhost;
 mod pmem;
 mod queue;
 mod rng;
+pub mod scsi;
+// Also used by the benchmarks, hence not restricted to tests.
+#[doc(hidden)]
+pub mod testing;
 pub mod vsock;
 
 pub mod transport;
 pub mod vhost_user;
 
+pub use self::balloon::*;
 pub use self::block::*;
+#[cfg(feature = "io_uring")]
+pub use self::block_io_uring::*;
 pub use self::console::*;
+pub use self::descriptor_utils::{Reader, Writer};
 pub use self::device::*;
 pub use self::iommu::*;
 pub use self::net::*;
+pub use self::net_pcap::*;
 pub use self::net_util::*;
 pub use self::pmem::*;
 pub use self::queue::*;
@@ -72,6 +99,7 @@ enum VirtioDeviceType {
     TYPE_CONSOLE = 3,
     TYPE_RNG = 4,
     TYPE_BALLOON = 5,
+    TYPE_SCSI = 8,
     TYPE_9P = 9,
     TYPE_GPU = 16,
     TYPE_INPUT = 18,
@@ -90,6 +118,7 @@ impl From<u32> for VirtioDeviceType {
             3 => VirtioDeviceType::TYPE_CONSOLE,
             4 => VirtioDeviceType::TYPE_RNG,
             5 => VirtioDeviceType::TYPE_BALLOON,
+            8 => VirtioDeviceType::TYPE_SCSI,
             9 => VirtioDeviceType::TYPE_9P,
             16 => VirtioDeviceType::TYPE_GPU,
             18 => VirtioDeviceType::TYPE_INPUT,
@@ -113,6 +142,7 @@ impl fmt::Display for VirtioDeviceType {
             VirtioDeviceType::TYPE_CONSOLE => "console",
             VirtioDeviceType::TYPE_RNG => "rng",
             VirtioDeviceType::TYPE_BALLOON => "balloon",
+            VirtioDeviceType::TYPE_SCSI => "scsi",
             VirtioDeviceType::TYPE_GPU => "gpu",
             VirtioDeviceType::TYPE_9P => "9p",
             VirtioDeviceType::TYPE_INPUT => "input",
@@ -151,10 +181,62 @@ pub enum ActivateError {
     VhostUserBlkSetup(vhost_user::Error),
     /// Failed to reset vhost-user daemon.
     VhostUserReset(vhost_user::Error),
+    /// Failed to hand the queues over to vhost-net.
+    VhostNetSetup(vhost_rs::Error),
+}
+
+impl fmt::Display for ActivateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ActivateError::EpollCtl(_) => write!(f, "failed to register an event with epoll"),
+            ActivateError::BadActivate => write!(f, "bad activation request"),
+            ActivateError::BadQueueNum => write!(f, "queue number is not correct"),
+            ActivateError::CloneKillEventFd => write!(f, "failed to clone the kill eventfd"),
+            ActivateError::VhostIrqCreate => {
+                write!(f, "failed to create the vhost-user interrupt eventfd")
+            }
+            ActivateError::VhostUserSetup(_) => write!(f, "failed to setup vhost-user daemon"),
+            ActivateError::VhostUserNetSetup(_) => {
+                write!(f, "failed to setup vhost-user-net daemon")
+            }
+            ActivateError::VhostUserBlkSetup(_) => {
+                write!(f, "failed to setup vhost-user-blk daemon")
+            }
+            ActivateError::VhostUserReset(_) => write!(f, "failed to reset vhost-user daemon"),
+            ActivateError::VhostNetSetup(_) => write!(f, "failed to setup vhost-net"),
+        }
+    }
+}
+
+impl std::error::Error for ActivateError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ActivateError::EpollCtl(e) => Some(e),
+            ActivateError::VhostUserSetup(e) => Some(e),
+            ActivateError::VhostUserNetSetup(e) => Some(e),
+            ActivateError::VhostUserBlkSetup(e) => Some(e),
+            ActivateError::VhostUserReset(e) => Some(e),
+            _ => None,
+        }
+    }
 }
 
 pub type ActivateResult = std::result::Result<(), ActivateError>;
 
+/// Checks the features accepted by the driver allow the device to be used.
+///
+/// Only the modern virtio 1.0 interface is implemented, and the legacy one is
+/// intentionally unsupported. The transports always offer VIRTIO_F_VERSION_1,
+/// so a driver not accepting it is a legacy driver, and is refused.
+#[cfg(any(feature = "pci_support", feature = "mmio_support"))]
+fn check_driver_features(driver_features: u64) -> ActivateResult {
+    if driver_features & (1u64 << VIRTIO_F_VERSION_1) == 0 {
+        return Err(ActivateError::BadActivate);
+    }
+
+    Ok(())
+}
+
 pub type DeviceEventT = u16;
 
 #[derive(Debug)]
@@ -176,3 +258,39 @@ pub enum Error {
     EpollWait(io::Error),
     FailedSignalingDriver(io::Error),
 }
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::FailedReadingQueue { event_type, .. } => {
+                write!(f, "failed reading the {} queue event", event_type)
+            }
+            Error::FailedReadTap => write!(f, "failed reading the tap interface"),
+            Error::FailedSignalingUsedQueue(_) => write!(f, "failed signaling the used queue"),
+            Error::PayloadExpected => write!(f, "epoll handler payload expected"),
+            Error::UnknownEvent { device, event } => {
+                write!(f, "unknown event {} for the {} device", event, device)
+            }
+            Error::IoError(_) => write!(f, "I/O error"),
+            Error::EpollCreateFd(_) => write!(f, "failed creating the epoll fd"),
+            Error::EpollCtl(_) => write!(f, "failed registering an event with epoll"),
+            Error::EpollWait(_) => write!(f, "failed waiting for epoll events"),
+            Error::FailedSignalingDriver(_) => write!(f, "failed signaling the driver"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::FailedSignalingUsedQueue(e) => Some(e),
+            Error::IoError(e) => Some(e),
+            Error::EpollCreateFd(e) => Some(e),
+            Error::EpollCtl(e) => Some(e),
+            Error::EpollWait(e) => Some(e),
+            Error::FailedSignalingDriver(e) => Some(e),
+            Error::FailedReadingQueue { underlying, .. } => Some(underlying),
+            _ => None,
+        }
+    }
+}
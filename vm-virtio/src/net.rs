@@ -7,30 +7,37 @@
 
 use super::net_util::{
     build_net_config_space, build_net_config_space_with_mq, open_tap, register_listener,
-    unregister_listener, CtrlVirtio, NetCtrlEpollHandler, RxVirtio, TxVirtio, VirtioNetConfig,
-    KILL_EVENT, NET_EVENTS_COUNT, PAUSE_EVENT, RX_QUEUE_EVENT, RX_TAP_EVENT, TX_QUEUE_EVENT,
+    unregister_listener, vnet_hdr_len, CtrlVirtio, NetCtrlEpollHandler, RxFilter, RxVirtio,
+    TxVirtio, VirtioNetConfig, KILL_EVENT, NET_EVENTS_COUNT, PAUSE_EVENT, RX_QUEUE_EVENT,
+    RX_TAP_EVENT, TX_QUEUE_EVENT,
+};
+use super::net_vhost::{
+    vhost_net_features, VhostNetInterruptRelay, VhostNetQueuePair, VHOST_NET_DATAPATH_FEATURES,
 };
 use super::Error as DeviceError;
 use super::{
     ActivateError, ActivateResult, Queue, VirtioDevice, VirtioDeviceType, VirtioInterruptType,
 };
-use crate::VirtioInterrupt;
+use crate::event_loop::{self, EpollHandlerHandle};
+use crate::{join_epoll_threads, PcapWriter, VirtioInterrupt};
 use arc_swap::ArcSwap;
 use epoll;
 use libc::EAGAIN;
 use libc::EFD_NONBLOCK;
 use net_util::{MacAddr, Tap};
 use std::cmp;
+use std::fmt;
 use std::io::Read;
 use std::io::{self, Write};
 use std::net::Ipv4Addr;
 use std::os::unix::io::{AsRawFd, RawFd};
 use std::result;
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 use std::thread;
 use std::vec::Vec;
 use virtio_bindings::bindings::virtio_net::*;
+use vm_device::metrics::{self, Metric};
 use vm_device::{Migratable, MigratableError, Pausable, Snapshotable};
 use vm_memory::{ByteValued, GuestMemoryMmap};
 use vmm_sys_util::eventfd::EventFd;
@@ -39,15 +46,67 @@ use vmm_sys_util::eventfd::EventFd;
 pub enum Error {
     /// Failed to open taps.
     OpenTap(super::net_util::Error),
+    /// Failed to hand the guest memory table over to vhost-net.
+    VhostNetMemory(vhost_rs::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::OpenTap(_) => write!(f, "failed to open taps"),
+            Error::VhostNetMemory(_) => write!(f, "failed to update the vhost-net memory table"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::OpenTap(e) => Some(e),
+            Error::VhostNetMemory(e) => Some(e),
+        }
+    }
 }
 
 pub type Result<T> = result::Result<T, Error>;
 
+/// Counters describing the activity of a network device, the frame sizes
+/// not including the virtio-net header.
+#[derive(Clone, Default)]
+pub struct NetMetrics {
+    rx_packets: Arc<Metric>,
+    rx_bytes: Arc<Metric>,
+    tx_packets: Arc<Metric>,
+    tx_bytes: Arc<Metric>,
+    rx_dropped: Arc<Metric>,
+    notifications: Arc<Metric>,
+    epoll_iterations: Arc<Metric>,
+}
+
+impl NetMetrics {
+    /// Registers the metrics of the device `id`.
+    pub fn new(id: &str) -> Self {
+        let registry = metrics::registry();
+        NetMetrics {
+            rx_packets: registry.counter("net_rx_packets", id),
+            rx_bytes: registry.counter("net_rx_bytes", id),
+            tx_packets: registry.counter("net_tx_packets", id),
+            tx_bytes: registry.counter("net_tx_bytes", id),
+            rx_dropped: registry.counter("net_rx_dropped", id),
+            notifications: registry.counter("net_queue_notifications", id),
+            epoll_iterations: registry.counter("net_epoll_iterations", id),
+        }
+    }
+}
+
 struct NetEpollHandler {
     mem: Arc<ArcSwap<GuestMemoryMmap>>,
     tap: Tap,
     rx: RxVirtio,
     tx: TxVirtio,
+    rx_filter: Arc<Mutex<RxFilter>>,
+    pcap: Option<PcapWriter>,
+    metrics: NetMetrics,
     interrupt_cb: Arc<dyn VirtioInterrupt>,
     kill_evt: EventFd,
     pause_evt: EventFd,
@@ -70,10 +129,17 @@ impl NetEpollHandler {
     // is made available by the driver.
     fn rx_single_frame(&mut self, mut queue: &mut Queue) -> bool {
         let mem = self.mem.load();
-        let next_desc = queue.iter(&mem).next();
+        let delivered = if self.rx.mergeable_rx_buffers {
+            self.rx.process_mergeable(&mem, &mut queue)
+        } else {
+            match queue.iter(&mem).next() {
+                Some(desc) => return self.rx.process_desc_chain(&mem, Some(desc), &mut queue),
+                None => false,
+            }
+        };
 
-        if next_desc.is_none() {
-            // Queue has no available descriptors
+        if !delivered {
+            // The driver hasn't made enough room available
             if self.rx_tap_listening {
                 unregister_listener(
                     self.epoll_fd,
@@ -84,10 +150,9 @@ impl NetEpollHandler {
                 .unwrap();
                 self.rx_tap_listening = false;
             }
-            return false;
         }
 
-        self.rx.process_desc_chain(&mem, next_desc, &mut queue)
+        delivered
     }
 
     fn process_rx(&mut self, queue: &mut Queue) -> result::Result<(), DeviceError> {
@@ -95,6 +160,19 @@ impl NetEpollHandler {
         loop {
             match self.read_tap() {
                 Ok(count) => {
+                    let frame = self.rx.frame_buf.get(vnet_hdr_len()..count).unwrap_or(&[]);
+                    if !self.rx_filter.lock().unwrap().accepts(frame) {
+                        // Dropped as requested by the driver.
+                        continue;
+                    }
+                    if let Some(pcap) = &self.pcap {
+                        pcap.capture(frame);
+                    }
+                    // A frame which can't be delivered right away is kept
+                    // until the driver provides a buffer, so it's already
+                    // accounted for.
+                    self.metrics.rx_packets.inc();
+                    self.metrics.rx_bytes.add(frame.len() as u64);
                     self.rx.bytes_read = count;
                     if !self.rx_single_frame(queue) {
                         self.rx.deferred_frame = true;
@@ -144,7 +222,11 @@ impl NetEpollHandler {
     fn process_tx(&mut self, mut queue: &mut Queue) -> result::Result<(), DeviceError> {
         let mem = self.mem.load();
 
-        self.tx.process_desc_chain(&mem, &mut self.tap, &mut queue);
+        let (frames, bytes) =
+            self.tx
+                .process_desc_chain(&mem, &mut self.tap, &mut queue, self.pcap.as_ref());
+        self.metrics.tx_packets.add(frames);
+        self.metrics.tx_bytes.add(bytes);
 
         Ok(())
     }
@@ -255,14 +337,18 @@ impl NetEpollHandler {
                 }
             };
 
+            self.metrics.epoll_iterations.inc();
+
             for event in events.iter().take(num_events) {
                 let ev_type = event.data as u16;
 
                 match ev_type {
                     RX_QUEUE_EVENT => {
+                        self.metrics.notifications.inc();
                         self.handle_rx_event(&mut queues[0], &queue_evts[0]);
                     }
                     TX_QUEUE_EVENT => {
+                        self.metrics.notifications.inc();
                         self.handle_tx_event(&mut queues[1], &queue_evts[1]);
                     }
                     RX_TAP_EVENT => {
@@ -304,6 +390,14 @@ pub struct Net {
     ctrl_queue_epoll_thread: Option<thread::JoinHandle<result::Result<(), DeviceError>>>,
     paused: Arc<AtomicBool>,
     queue_size: Vec<u16>,
+    guest_mac: Option<MacAddr>,
+    rx_filter: Arc<Mutex<RxFilter>>,
+    pcap: Option<PcapWriter>,
+    metrics: NetMetrics,
+    // Features supported by vhost-net, when it runs the datapath.
+    vhost_net_features: Option<u64>,
+    vhost_queue_pairs: Vec<VhostNetQueuePair>,
+    vhost_interrupt_relay: Option<EpollHandlerHandle>,
 }
 
 impl Net {
@@ -321,13 +415,16 @@ impl Net {
             | 1 << VIRTIO_NET_F_GUEST_UFO
             | 1 << VIRTIO_NET_F_HOST_TSO4
             | 1 << VIRTIO_NET_F_HOST_UFO
+            | 1 << VIRTIO_NET_F_MRG_RXBUF
+            | 1 << VIRTIO_NET_F_STATUS
             | 1 << VIRTIO_F_VERSION_1;
 
         if iommu {
             avail_features |= 1u64 << VIRTIO_F_IOMMU_PLATFORM;
         }
 
-        avail_features |= 1 << VIRTIO_NET_F_CTRL_VQ;
+        avail_features |=
+            1 << VIRTIO_NET_F_CTRL_VQ | 1 << VIRTIO_NET_F_CTRL_RX | 1 << VIRTIO_NET_F_CTRL_VLAN;
         let queue_num = num_queues + 1;
 
         let mut config = VirtioNetConfig::default();
@@ -336,6 +433,7 @@ impl Net {
         } else {
             build_net_config_space_with_mq(&mut config, num_queues, &mut avail_features);
         }
+        config.status = VIRTIO_NET_S_LINK_UP as u16;
 
         Ok(Net {
             kill_evt: None,
@@ -350,6 +448,13 @@ impl Net {
             ctrl_queue_epoll_thread: None,
             paused: Arc::new(AtomicBool::new(false)),
             queue_size: vec![queue_size; queue_num],
+            guest_mac,
+            rx_filter: Arc::new(Mutex::new(RxFilter::new(guest_mac))),
+            pcap: None,
+            metrics: NetMetrics::default(),
+            vhost_net_features: None,
+            vhost_queue_pairs: Vec::new(),
+            vhost_interrupt_relay: None,
         })
     }
 
@@ -368,6 +473,119 @@ impl Net {
 
         Self::new_with_tap(taps, guest_mac, iommu, num_queues, queue_size)
     }
+
+    /// Captures every frame received or sent by the guest, without their
+    /// virtio-net header.
+    pub fn set_pcap(&mut self, pcap: PcapWriter) {
+        self.pcap = Some(pcap);
+    }
+
+    /// Moves the datapath to the kernel, through vhost-net, before the driver
+    /// negotiates the features. The device keeps its userspace datapath when
+    /// the host can't run it in the kernel, with a warning.
+    pub fn enable_vhost_net(&mut self, mem: &GuestMemoryMmap) {
+        if self.avail_features & 1 << VIRTIO_F_IOMMU_PLATFORM != 0 {
+            warn!("vhost-net can't run behind a virtual IOMMU, using the userspace datapath");
+            return;
+        }
+        let vhost_features = match vhost_net_features(mem) {
+            Ok(features) => features,
+            Err(e) => {
+                warn!(
+                    "vhost-net is not available ({}), using the userspace datapath",
+                    e
+                );
+                return;
+            }
+        };
+        // The transports only support virtio 1.0 drivers.
+        if vhost_features & 1 << VIRTIO_F_VERSION_1 == 0 {
+            warn!("vhost-net doesn't support virtio 1.0, using the userspace datapath");
+            return;
+        }
+        if self.pcap.is_some() {
+            warn!("The frames moved by vhost-net can't be captured");
+        }
+
+        // The kernel doesn't filter the frames it receives.
+        self.avail_features &= !(1 << VIRTIO_NET_F_CTRL_RX | 1 << VIRTIO_NET_F_CTRL_VLAN);
+        self.avail_features &= !VHOST_NET_DATAPATH_FEATURES | vhost_features;
+        self.vhost_net_features = Some(vhost_features);
+    }
+
+    /// Lets vhost-net access the guest memory after a memory region has been
+    /// hotplugged. Nothing is needed from the userspace datapath, which
+    /// always accesses the current guest memory.
+    pub fn update_memory(&self, mem: &GuestMemoryMmap) -> Result<()> {
+        for queue_pair in self.vhost_queue_pairs.iter() {
+            queue_pair
+                .update_memory(mem)
+                .map_err(Error::VhostNetMemory)?;
+        }
+        Ok(())
+    }
+
+    fn activate_vhost_net(
+        &mut self,
+        mem: &GuestMemoryMmap,
+        interrupt_cb: &Arc<dyn VirtioInterrupt>,
+        taps: Vec<Tap>,
+        mut queues: Vec<Queue>,
+        queue_evts: Vec<EventFd>,
+        vhost_features: u64,
+    ) -> ActivateResult {
+        let features = self.acked_features & vhost_features;
+        let mut call_evts = Vec::new();
+        for (index, tap) in taps.into_iter().enumerate() {
+            let (queue_pair, mut queue_pair_call_evts) = VhostNetQueuePair::new(
+                mem,
+                tap,
+                features,
+                queues.drain(..2).collect(),
+                &queue_evts[2 * index..2 * index + 2],
+                interrupt_cb,
+            )
+            .map_err(ActivateError::VhostNetSetup)?;
+            self.vhost_queue_pairs.push(queue_pair);
+            call_evts.append(&mut queue_pair_call_evts);
+        }
+
+        if !call_evts.is_empty() {
+            let relay = VhostNetInterruptRelay {
+                interrupt_cb: interrupt_cb.clone(),
+                call_evts,
+            };
+            self.vhost_interrupt_relay = Some(
+                event_loop::register("virtio_net", Box::new(relay)).map_err(|e| {
+                    error!("failed to register the vhost-net interrupt relay: {}", e);
+                    ActivateError::BadActivate
+                })?,
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Exports the metrics of the device, under `id`.
+    pub fn set_metrics_id(&mut self, id: &str) {
+        self.metrics = NetMetrics::new(id);
+    }
+
+    /// Updates the link status reported to the guest, and notifies the
+    /// driver about the configuration change if the device is activated.
+    pub fn set_link_status(&mut self, up: bool) -> io::Result<()> {
+        if up {
+            self.config.status |= VIRTIO_NET_S_LINK_UP as u16;
+        } else {
+            self.config.status &= !(VIRTIO_NET_S_LINK_UP as u16);
+        }
+
+        if let Some(interrupt_cb) = &self.interrupt_cb {
+            interrupt_cb.signal_config_change()?;
+        }
+
+        Ok(())
+    }
 }
 
 impl Drop for Net {
@@ -478,6 +696,11 @@ impl VirtioDevice for Net {
             }
             self.queue_evts = Some(tmp_queue_evts);
 
+            // The driver starts from a clean filtering state.
+            let mut rx_filter = RxFilter::new(self.guest_mac);
+            rx_filter.vlan_filtering = self.acked_features & 1 << VIRTIO_NET_F_CTRL_VLAN != 0;
+            *self.rx_filter.lock().unwrap() = rx_filter;
+
             let queue_num = queues.len();
             if (self.acked_features & 1 << VIRTIO_NET_F_CTRL_VQ) != 0 && queue_num % 2 != 0 {
                 let cvq_queue = queues.remove(queue_num - 1);
@@ -485,9 +708,15 @@ impl VirtioDevice for Net {
 
                 let mut ctrl_handler = NetCtrlEpollHandler {
                     mem: mem.clone(),
+                    interrupt_cb: interrupt_cb.clone(),
                     kill_evt: kill_evt.try_clone().unwrap(),
                     pause_evt: pause_evt.try_clone().unwrap(),
-                    ctrl_q: CtrlVirtio::new(cvq_queue, cvq_queue_evt),
+                    ctrl_q: CtrlVirtio::new(
+                        cvq_queue,
+                        cvq_queue_evt,
+                        self.rx_filter.clone(),
+                        taps.first().cloned(),
+                    ),
                     epoll_fd: 0,
                 };
 
@@ -502,9 +731,22 @@ impl VirtioDevice for Net {
                     })?;
             }
 
+            if let Some(vhost_features) = self.vhost_net_features {
+                return self.activate_vhost_net(
+                    mem.load().as_ref(),
+                    &interrupt_cb,
+                    taps,
+                    queues,
+                    queue_evts,
+                    vhost_features,
+                );
+            }
+
             let mut epoll_threads = Vec::new();
             for _ in 0..taps.len() {
-                let rx = RxVirtio::new();
+                let mut rx = RxVirtio::new();
+                rx.mergeable_rx_buffers = self.acked_features & 1 << VIRTIO_NET_F_MRG_RXBUF != 0;
+                rx.dropped_frames = self.metrics.rx_dropped.clone();
                 let tx = TxVirtio::new();
                 let rx_tap_listening = false;
 
@@ -521,6 +763,9 @@ impl VirtioDevice for Net {
                     tap: taps.remove(0),
                     rx,
                     tx,
+                    rx_filter: self.rx_filter.clone(),
+                    pcap: self.pcap.clone(),
+                    metrics: self.metrics.clone(),
                     interrupt_cb: interrupt_cb.clone(),
                     kill_evt: kill_evt.try_clone().unwrap(),
                     pause_evt: pause_evt.try_clone().unwrap(),
@@ -556,6 +801,22 @@ impl VirtioDevice for Net {
             // Ignore the result because there is nothing we can do about it.
             let _ = kill_evt.write(1);
         }
+        join_epoll_threads(self.epoll_threads.take());
+        join_epoll_threads(
+            self.ctrl_queue_epoll_thread
+                .take()
+                .map(|thread| vec![thread]),
+        );
+
+        // Closing vhost-net detaches it from the queues and the TAP.
+        self.vhost_interrupt_relay = None;
+        self.vhost_queue_pairs.clear();
+
+        self.acked_features = 0;
+        // The driver may have written another MAC address.
+        if let Some(mac) = self.guest_mac {
+            self.config.mac.copy_from_slice(mac.get_bytes());
+        }
 
         // Return the interrupt and queue EventFDs
         Some((
@@ -565,6 +826,45 @@ impl VirtioDevice for Net {
     }
 }
 
-virtio_ctrl_q_pausable!(Net);
+virtio_pausable_trait!(Net);
+
+impl Pausable for Net {
+    fn pause(&mut self) -> result::Result<(), MigratableError> {
+        // The kernel stops moving frames before the queues are frozen.
+        for queue_pair in self.vhost_queue_pairs.iter() {
+            queue_pair
+                .stop()
+                .map_err(|e| MigratableError::Pause(anyhow!("Could not stop vhost-net: {}", e)))?;
+        }
+        if let Some(relay) = &self.vhost_interrupt_relay {
+            relay
+                .pause()
+                .map_err(|e| MigratableError::Pause(e.into()))?;
+        }
+
+        self.virtio_pause()
+    }
+
+    fn resume(&mut self) -> result::Result<(), MigratableError> {
+        self.virtio_resume()?;
+
+        if let Some(ctrl_queue_epoll_thread) = &self.ctrl_queue_epoll_thread {
+            ctrl_queue_epoll_thread.thread().unpark();
+        }
+        if let Some(relay) = &self.vhost_interrupt_relay {
+            relay
+                .resume()
+                .map_err(|e| MigratableError::Resume(e.into()))?;
+        }
+        for queue_pair in self.vhost_queue_pairs.iter() {
+            queue_pair.start().map_err(|e| {
+                MigratableError::Resume(anyhow!("Could not start vhost-net: {}", e))
+            })?;
+        }
+
+        Ok(())
+    }
+}
+
 impl Snapshotable for Net {}
 impl Migratable for Net {}
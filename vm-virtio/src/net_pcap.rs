@@ -0,0 +1,235 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Capture of the frames going through a virtio-net device, in the pcap
+//! format understood by tcpdump and Wireshark.
+//!
+//! Frames are handed over to a dedicated thread doing the actual writing,
+//! through a bounded channel. When the thread can't keep up, frames are
+//! dropped and counted instead of slowing down the device.
+
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender, TryRecvError, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const PCAP_MAGIC: u32 = 0xa1b2_c3d4;
+const PCAP_VERSION_MAJOR: u16 = 2;
+const PCAP_VERSION_MINOR: u16 = 4;
+const LINKTYPE_ETHERNET: u32 = 1;
+
+/// Default number of bytes captured from each frame.
+pub const PCAP_DEFAULT_SNAPLEN: u32 = 65535;
+
+// Number of frames waiting to be written before new ones get dropped.
+const PCAP_CHANNEL_DEPTH: usize = 1024;
+
+struct CapturedFrame {
+    timestamp: SystemTime,
+    orig_len: u32,
+    data: Vec<u8>,
+}
+
+struct PcapThread {
+    thread: Mutex<Option<thread::JoinHandle<()>>>,
+    dropped: AtomicU64,
+}
+
+impl Drop for PcapThread {
+    fn drop(&mut self) {
+        // This only runs once every sender is gone, which lets the thread
+        // flush the remaining frames and exit.
+        if let Some(thread) = self.thread.lock().unwrap().take() {
+            let _ = thread.join();
+        }
+
+        let dropped = self.dropped.load(Ordering::Relaxed);
+        if dropped > 0 {
+            warn!("{} frame(s) could not be captured", dropped);
+        }
+    }
+}
+
+/// Appends frames to a pcap file. It can be cloned so that every queue pair
+/// of a device captures into the same file.
+#[derive(Clone)]
+pub struct PcapWriter {
+    // Declared first, so that the sender is dropped before the thread gets
+    // joined.
+    sender: SyncSender<CapturedFrame>,
+    snaplen: u32,
+    shared: Arc<PcapThread>,
+}
+
+impl PcapWriter {
+    /// Creates the file at `path` and writes the pcap global header. Only
+    /// the first `snaplen` bytes of each frame are captured.
+    pub fn new(path: &Path, snaplen: u32) -> io::Result<Self> {
+        let mut file = BufWriter::new(File::create(path)?);
+        write_global_header(&mut file, snaplen)?;
+        file.flush()?;
+
+        let (sender, receiver) = sync_channel(PCAP_CHANNEL_DEPTH);
+        let thread = thread::Builder::new()
+            .name("net_pcap".to_string())
+            .spawn(move || {
+                if let Err(e) = write_frames(&mut file, &receiver) {
+                    error!("Failed to write captured frames: {}", e);
+                }
+            })?;
+
+        Ok(PcapWriter {
+            sender,
+            snaplen,
+            shared: Arc::new(PcapThread {
+                thread: Mutex::new(Some(thread)),
+                dropped: AtomicU64::new(0),
+            }),
+        })
+    }
+
+    /// Captures an ethernet frame. This never blocks: the frame is dropped
+    /// if too many frames are already waiting to be written.
+    pub fn capture(&self, frame: &[u8]) {
+        let len = std::cmp::min(frame.len(), self.snaplen as usize);
+        let captured = CapturedFrame {
+            timestamp: SystemTime::now(),
+            orig_len: frame.len() as u32,
+            data: frame[..len].to_vec(),
+        };
+
+        match self.sender.try_send(captured) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) | Err(TrySendError::Disconnected(_)) => {
+                self.shared.dropped.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Number of frames which have been dropped so far.
+    pub fn dropped(&self) -> u64 {
+        self.shared.dropped.load(Ordering::Relaxed)
+    }
+}
+
+fn write_global_header<W: Write>(w: &mut W, snaplen: u32) -> io::Result<()> {
+    w.write_all(&PCAP_MAGIC.to_ne_bytes())?;
+    w.write_all(&PCAP_VERSION_MAJOR.to_ne_bytes())?;
+    w.write_all(&PCAP_VERSION_MINOR.to_ne_bytes())?;
+    // Timestamps are in UTC, with no accuracy information.
+    w.write_all(&0i32.to_ne_bytes())?;
+    w.write_all(&0u32.to_ne_bytes())?;
+    w.write_all(&snaplen.to_ne_bytes())?;
+    w.write_all(&LINKTYPE_ETHERNET.to_ne_bytes())
+}
+
+fn write_frame<W: Write>(w: &mut W, frame: &CapturedFrame) -> io::Result<()> {
+    let timestamp = frame
+        .timestamp
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    w.write_all(&(timestamp.as_secs() as u32).to_ne_bytes())?;
+    w.write_all(&timestamp.subsec_micros().to_ne_bytes())?;
+    w.write_all(&(frame.data.len() as u32).to_ne_bytes())?;
+    w.write_all(&frame.orig_len.to_ne_bytes())?;
+    w.write_all(&frame.data)
+}
+
+fn write_frames<W: Write>(w: &mut W, receiver: &Receiver<CapturedFrame>) -> io::Result<()> {
+    loop {
+        let frame = match receiver.try_recv() {
+            Ok(frame) => frame,
+            Err(TryRecvError::Empty) => {
+                // Nothing left to write for now, make what has been captured
+                // so far visible before waiting for more.
+                w.flush()?;
+                match receiver.recv() {
+                    Ok(frame) => frame,
+                    Err(_) => return Ok(()),
+                }
+            }
+            Err(TryRecvError::Disconnected) => return w.flush(),
+        };
+        write_frame(w, &frame)?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::time::Duration;
+
+    fn read_u32(buf: &[u8], offset: usize) -> u32 {
+        let mut bytes = [0u8; 4];
+        bytes.copy_from_slice(&buf[offset..offset + 4]);
+        u32::from_ne_bytes(bytes)
+    }
+
+    #[test]
+    fn test_global_header() {
+        let mut buf = Vec::new();
+        write_global_header(&mut buf, 128).unwrap();
+
+        assert_eq!(buf.len(), 24);
+        assert_eq!(read_u32(&buf, 0), PCAP_MAGIC);
+        assert_eq!(
+            &buf[4..8],
+            &[2u16.to_ne_bytes(), 4u16.to_ne_bytes()].concat()[..]
+        );
+        assert_eq!(read_u32(&buf, 16), 128);
+        assert_eq!(read_u32(&buf, 20), LINKTYPE_ETHERNET);
+    }
+
+    #[test]
+    fn test_write_frame() {
+        let mut buf = Vec::new();
+        let frame = CapturedFrame {
+            timestamp: UNIX_EPOCH + Duration::new(1000, 2_000_000),
+            orig_len: 60,
+            data: vec![0xaa; 16],
+        };
+        write_frame(&mut buf, &frame).unwrap();
+
+        assert_eq!(buf.len(), 16 + 16);
+        assert_eq!(read_u32(&buf, 0), 1000);
+        assert_eq!(read_u32(&buf, 4), 2000);
+        assert_eq!(read_u32(&buf, 8), 16);
+        assert_eq!(read_u32(&buf, 12), 60);
+        assert_eq!(&buf[16..], &[0xaa; 16][..]);
+    }
+
+    #[test]
+    fn test_capture() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("net.pcap");
+
+        let pcap = PcapWriter::new(&path, 32).unwrap();
+        let other = pcap.clone();
+        pcap.capture(&[0x11; 20]);
+        other.capture(&[0x22; 100]);
+        assert_eq!(pcap.dropped(), 0);
+        // Dropping the last writer flushes the file.
+        drop(pcap);
+        drop(other);
+
+        let buf = fs::read(&path).unwrap();
+        assert_eq!(buf.len(), 24 + 16 + 20 + 16 + 32);
+        assert_eq!(read_u32(&buf, 16), 32);
+
+        let first = 24;
+        assert_eq!(read_u32(&buf, first + 8), 20);
+        assert_eq!(read_u32(&buf, first + 12), 20);
+        assert_eq!(&buf[first + 16..first + 36], &[0x11; 20][..]);
+
+        let second = first + 36;
+        assert_eq!(read_u32(&buf, second + 8), 32);
+        assert_eq!(read_u32(&buf, second + 12), 100);
+        assert_eq!(&buf[second + 16..], &[0x22; 32][..]);
+    }
+}
@@ -3,17 +3,24 @@
 // SPDX-License-Identifier: Apache-2.0 AND BSD-3-Clause
 
 use super::Error as DeviceError;
-use super::{DescriptorChain, DeviceEventT, Queue};
+use super::{
+    DescriptorChain, DeviceEventT, Queue, Reader, VirtioInterrupt, VirtioInterruptType, Writer,
+};
+use crate::descriptor_utils::Error as DescriptorError;
+use crate::PcapWriter;
 use arc_swap::ArcSwap;
-use net_util::{MacAddr, Tap, TapError};
+use net_util::{MacAddr, Tap, TapError, MAC_ADDR_LEN};
 use std::cmp;
-use std::io::{self, Write};
+use std::collections::HashSet;
+use std::fmt;
+use std::io::{self, Read, Write};
 use std::mem;
 use std::net::Ipv4Addr;
 use std::os::unix::io::{AsRawFd, RawFd};
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 use virtio_bindings::bindings::virtio_net::*;
+use vm_device::metrics::Metric;
 use vm_memory::{ByteValued, Bytes, GuestAddress, GuestMemoryError, GuestMemoryMmap};
 use vmm_sys_util::eventfd::EventFd;
 
@@ -23,6 +30,8 @@ type Result<T> = std::result::Result<T, Error>;
 /// includes the 12-byte virtio net header.
 /// http://docs.oasis-open.org/virtio/virtio/v1.0/virtio-v1.0.html#x1-1740003
 const MAX_BUFFER_SIZE: usize = 65562;
+// Offset of the `num_buffers` field in the virtio-net header.
+const NUM_BUFFERS_OFFSET: usize = 10;
 const QUEUE_SIZE: usize = 256;
 
 // The guest has made a buffer available to receive a frame into.
@@ -42,6 +51,13 @@ const CTRL_QUEUE_EVENT: DeviceEventT = 0;
 // Number of DeviceEventT events supported by this implementation.
 const CTRL_EVENT_COUNT: usize = 3;
 
+// Number of MAC addresses of each kind the receive filter holds. Past that,
+// every frame of the kind is accepted.
+const MAC_TABLE_ENTRIES: usize = 64;
+// VLAN ids are 12 bits long.
+const MAX_VLAN_ID: u16 = 0xfff;
+const ETH_P_8021Q: u16 = 0x8100;
+
 #[repr(C, packed)]
 #[derive(Copy, Clone, Debug, Default)]
 pub struct VirtioNetConfig {
@@ -58,22 +74,22 @@ unsafe impl ByteValued for VirtioNetConfig {}
 
 #[derive(Debug)]
 pub enum Error {
-    /// Read process MQ.
-    FailedProcessMQ,
+    /// Invalid control queue descriptor chain.
+    DescriptorChain(DescriptorError),
     /// Read queue failed.
     GuestMemory(GuestMemoryError),
     /// Invalid ctrl class
     InvalidCtlClass,
     /// Invalid ctrl command
     InvalidCtlCmd,
-    /// Invalid descriptor
-    InvalidDesc,
     /// Invalid queue pairs number
     InvalidQueuePairsNum,
+    /// Invalid VLAN id.
+    InvalidVlanId(u16),
     /// No memory passed in.
     NoMemory,
-    /// No ueue pairs nummber.
-    NoQueuePairsNum,
+    /// Reading the control command failed.
+    ReadCtrlCommand(io::Error),
     /// Open tap device failed.
     TapOpen(TapError),
     /// Setting tap IP failed.
@@ -86,11 +102,154 @@ pub enum Error {
     TapSetVnetHdrSize(TapError),
     /// Enabling tap interface failed.
     TapEnable(TapError),
+    /// Writing the control command ack failed.
+    WriteCtrlAck(io::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::DescriptorChain(_) => write!(f, "invalid control queue descriptor chain"),
+            Error::GuestMemory(_) => write!(f, "failed reading the queue"),
+            Error::InvalidCtlClass => write!(f, "invalid ctrl class"),
+            Error::InvalidCtlCmd => write!(f, "invalid ctrl command"),
+            Error::InvalidQueuePairsNum => write!(f, "invalid queue pairs number"),
+            Error::InvalidVlanId(e) => write!(f, "invalid VLAN id {}", e),
+            Error::NoMemory => write!(f, "no memory passed in"),
+            Error::ReadCtrlCommand(_) => write!(f, "reading the control command failed"),
+            Error::TapOpen(e) => write!(f, "open tap device failed: {:?}", e),
+            Error::TapSetIp(e) => write!(f, "setting tap IP failed: {:?}", e),
+            Error::TapSetNetmask(e) => write!(f, "setting tap netmask failed: {:?}", e),
+            Error::TapSetOffload(e) => {
+                write!(f, "setting tap interface offload flags failed: {:?}", e)
+            }
+            Error::TapSetVnetHdrSize(e) => write!(f, "setting vnet header size failed: {:?}", e),
+            Error::TapEnable(e) => write!(f, "enabling tap interface failed: {:?}", e),
+            Error::WriteCtrlAck(_) => write!(f, "writing the control command ack failed"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::DescriptorChain(e) => Some(e),
+            Error::GuestMemory(e) => Some(e),
+            Error::ReadCtrlCommand(e) => Some(e),
+            Error::WriteCtrlAck(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// Receive filtering state, as set up by the driver through the control
+/// queue, deciding which frames coming from the tap reach the guest.
+#[derive(Clone, Debug)]
+pub struct RxFilter {
+    pub promisc: bool,
+    pub allmulti: bool,
+    /// The device MAC address, unicast frames aren't filtered when unknown.
+    pub mac: Option<MacAddr>,
+    pub unicast: Vec<MacAddr>,
+    pub unicast_overflow: bool,
+    pub multicast: Vec<MacAddr>,
+    pub multicast_overflow: bool,
+    /// Only set once VIRTIO_NET_F_CTRL_VLAN has been negotiated.
+    pub vlan_filtering: bool,
+    pub vlans: HashSet<u16>,
+}
+
+impl RxFilter {
+    /// Frames aren't filtered until the driver asks for it, as drivers not
+    /// using the control queue expect to receive everything.
+    pub fn new(mac: Option<MacAddr>) -> Self {
+        RxFilter {
+            promisc: true,
+            allmulti: false,
+            mac,
+            unicast: Vec::new(),
+            unicast_overflow: false,
+            multicast: Vec::new(),
+            multicast_overflow: false,
+            vlan_filtering: false,
+            vlans: HashSet::new(),
+        }
+    }
+
+    /// Returns whether `frame`, starting with its Ethernet header, must be
+    /// passed to the guest.
+    pub fn accepts(&self, frame: &[u8]) -> bool {
+        if self.promisc || frame.len() < 16 {
+            return true;
+        }
+
+        if self.vlan_filtering && u16::from_be_bytes([frame[12], frame[13]]) == ETH_P_8021Q {
+            let vid = u16::from_be_bytes([frame[14], frame[15]]) & MAX_VLAN_ID;
+            if !self.vlans.contains(&vid) {
+                return false;
+            }
+        }
+
+        let dest = MacAddr::from_bytes_unchecked(&frame[..MAC_ADDR_LEN]);
+        if frame[0] & 1 != 0 {
+            // Multicast, including broadcast.
+            frame[..MAC_ADDR_LEN] == [0xff; MAC_ADDR_LEN]
+                || self.allmulti
+                || self.multicast_overflow
+                || self.multicast.contains(&dest)
+        } else {
+            self.unicast_overflow
+                || self.mac.map_or(true, |mac| mac == dest)
+                || self.unicast.contains(&dest)
+        }
+    }
+}
+
+// Reads a MAC address table, made of a 32-bit entries count followed by the
+// entries. Returns the addresses, or None if the table is too large to be
+// held.
+fn read_mac_table(reader: &mut Reader) -> Result<Option<Vec<MacAddr>>> {
+    let entries = u32::from_le(reader.read_obj::<u32>().map_err(Error::ReadCtrlCommand)?) as usize;
+    if entries > reader.available_bytes() / MAC_ADDR_LEN {
+        return Err(Error::ReadCtrlCommand(io::Error::from(
+            io::ErrorKind::UnexpectedEof,
+        )));
+    }
+
+    let mut macs = Vec::with_capacity(cmp::min(entries, MAC_TABLE_ENTRIES));
+    for _ in 0..entries {
+        let mut mac = [0u8; MAC_ADDR_LEN];
+        reader
+            .read_exact(&mut mac)
+            .map_err(Error::ReadCtrlCommand)?;
+        if macs.len() < MAC_TABLE_ENTRIES {
+            macs.push(MacAddr::from_bytes_unchecked(&mac));
+        }
+    }
+
+    Ok(if entries > MAC_TABLE_ENTRIES {
+        None
+    } else {
+        Some(macs)
+    })
 }
 
+/// The header at the beginning of every control command.
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C)]
+struct CtrlHeader {
+    class: u8,
+    cmd: u8,
+}
+
+unsafe impl ByteValued for CtrlHeader {}
+
 pub struct CtrlVirtio {
     pub queue_evt: EventFd,
     pub queue: Queue,
+    rx_filter: Arc<Mutex<RxFilter>>,
+    // Where promiscuous mode is mirrored, when available.
+    tap: Option<Tap>,
 }
 
 impl std::clone::Clone for CtrlVirtio {
@@ -98,68 +257,136 @@ impl std::clone::Clone for CtrlVirtio {
         CtrlVirtio {
             queue_evt: self.queue_evt.try_clone().unwrap(),
             queue: self.queue.clone(),
+            rx_filter: self.rx_filter.clone(),
+            tap: self.tap.clone(),
         }
     }
 }
 
 impl CtrlVirtio {
-    pub fn new(queue: Queue, queue_evt: EventFd) -> Self {
-        CtrlVirtio { queue_evt, queue }
+    pub fn new(
+        queue: Queue,
+        queue_evt: EventFd,
+        rx_filter: Arc<Mutex<RxFilter>>,
+        tap: Option<Tap>,
+    ) -> Self {
+        CtrlVirtio {
+            queue_evt,
+            queue,
+            rx_filter,
+            tap,
+        }
     }
 
-    fn process_mq(&self, mem: &GuestMemoryMmap, avail_desc: DescriptorChain) -> Result<()> {
-        let mq_desc = if avail_desc.has_next() {
-            avail_desc.next_descriptor().unwrap()
-        } else {
-            return Err(Error::NoQueuePairsNum);
-        };
-        let queue_pairs = mem
-            .read_obj::<u16>(mq_desc.addr)
-            .map_err(Error::GuestMemory)?;
-        if (queue_pairs < VIRTIO_NET_CTRL_MQ_VQ_PAIRS_MIN as u16)
-            || (queue_pairs > VIRTIO_NET_CTRL_MQ_VQ_PAIRS_MAX as u16)
-        {
-            return Err(Error::InvalidQueuePairsNum);
+    /// Executes the pending control commands, and returns whether any of
+    /// them has been added to the used ring.
+    pub fn process_cvq(&mut self, mem: &GuestMemoryMmap) -> Result<bool> {
+        let mut used_desc_heads = Vec::new();
+        for avail_desc in self.queue.iter(&mem) {
+            let index = avail_desc.index;
+            let len =
+                Self::process_command(&self.rx_filter, &self.tap, avail_desc).unwrap_or_else(|e| {
+                    error!("failed to process control command: {:?}", e);
+                    0
+                });
+            used_desc_heads.push((index, len));
         }
-        let status_desc = if mq_desc.has_next() {
-            mq_desc.next_descriptor().unwrap()
-        } else {
-            return Err(Error::NoQueuePairsNum);
+
+        for &(desc_index, len) in used_desc_heads.iter() {
+            self.queue.add_used(&mem, desc_index, len);
+        }
+
+        Ok(!used_desc_heads.is_empty())
+    }
+
+    // Executes a single command, and returns the number of bytes written
+    // to the guest. Commands that can't be executed are answered with
+    // VIRTIO_NET_ERR.
+    fn process_command(
+        rx_filter: &Mutex<RxFilter>,
+        tap: &Option<Tap>,
+        avail_desc: DescriptorChain,
+    ) -> Result<u32> {
+        let mut reader = Reader::new(avail_desc.clone()).map_err(Error::DescriptorChain)?;
+        let mut writer = Writer::new(avail_desc).map_err(Error::DescriptorChain)?;
+
+        let header: CtrlHeader = reader.read_obj().map_err(Error::ReadCtrlCommand)?;
+        let ack = match Self::execute(rx_filter, tap, header, &mut reader) {
+            Ok(_) => VIRTIO_NET_OK,
+            Err(e) => {
+                warn!(
+                    "control command {}:{} failed: {:?}",
+                    header.class, header.cmd, e
+                );
+                VIRTIO_NET_ERR
+            }
         };
-        mem.write_obj::<u8>(0, status_desc.addr)
-            .map_err(Error::GuestMemory)?;
 
-        Ok(())
+        writer.write_obj(ack as u8).map_err(Error::WriteCtrlAck)?;
+
+        Ok(writer.bytes_written() as u32)
     }
 
-    pub fn process_cvq(&mut self, mem: &GuestMemoryMmap) -> Result<()> {
-        let mut used_desc_heads = [(0, 0); QUEUE_SIZE];
-        let mut used_count = 0;
-        if let Some(avail_desc) = self.queue.iter(&mem).next() {
-            used_desc_heads[used_count] = (avail_desc.index, avail_desc.len);
-            used_count += 1;
-            let ctrl_hdr = mem
-                .read_obj::<u16>(avail_desc.addr)
-                .map_err(Error::GuestMemory)?;
-            let ctrl_hdr_v = ctrl_hdr.as_slice();
-            let class = ctrl_hdr_v[0];
-            let cmd = ctrl_hdr_v[1];
-            match u32::from(class) {
-                VIRTIO_NET_CTRL_MQ => {
-                    if u32::from(cmd) != VIRTIO_NET_CTRL_MQ_VQ_PAIRS_SET {
-                        return Err(Error::InvalidCtlCmd);
-                    }
-                    if let Err(_e) = self.process_mq(&mem, avail_desc) {
-                        return Err(Error::FailedProcessMQ);
+    fn execute(
+        rx_filter: &Mutex<RxFilter>,
+        tap: &Option<Tap>,
+        header: CtrlHeader,
+        reader: &mut Reader,
+    ) -> Result<()> {
+        match (u32::from(header.class), u32::from(header.cmd)) {
+            (VIRTIO_NET_CTRL_RX, VIRTIO_NET_CTRL_RX_PROMISC) => {
+                let on = reader.read_obj::<u8>().map_err(Error::ReadCtrlCommand)? != 0;
+                rx_filter.lock().unwrap().promisc = on;
+                // Only a hint for the host side, the filtering is done by
+                // the device anyway.
+                if let Some(tap) = tap {
+                    if let Err(e) = tap.set_promisc(on) {
+                        warn!("failed setting tap promiscuous mode: {:?}", e);
                     }
                 }
-                _ => return Err(Error::InvalidCtlClass),
             }
-        } else {
-            return Err(Error::InvalidDesc);
-        }
-        for &(desc_index, len) in &used_desc_heads[..used_count] {
-            self.queue.add_used(&mem, desc_index, len);
+            (VIRTIO_NET_CTRL_RX, VIRTIO_NET_CTRL_RX_ALLMULTI) => {
+                let on = reader.read_obj::<u8>().map_err(Error::ReadCtrlCommand)? != 0;
+                rx_filter.lock().unwrap().allmulti = on;
+            }
+            (VIRTIO_NET_CTRL_MAC, VIRTIO_NET_CTRL_MAC_TABLE_SET) => {
+                let unicast = read_mac_table(reader)?;
+                let multicast = read_mac_table(reader)?;
+
+                let mut rx_filter = rx_filter.lock().unwrap();
+                rx_filter.unicast_overflow = unicast.is_none();
+                rx_filter.unicast = unicast.unwrap_or_default();
+                rx_filter.multicast_overflow = multicast.is_none();
+                rx_filter.multicast = multicast.unwrap_or_default();
+            }
+            (VIRTIO_NET_CTRL_VLAN, VIRTIO_NET_CTRL_VLAN_ADD)
+            | (VIRTIO_NET_CTRL_VLAN, VIRTIO_NET_CTRL_VLAN_DEL) => {
+                let vid = u16::from_le(reader.read_obj::<u16>().map_err(Error::ReadCtrlCommand)?);
+                if vid > MAX_VLAN_ID {
+                    return Err(Error::InvalidVlanId(vid));
+                }
+
+                let mut rx_filter = rx_filter.lock().unwrap();
+                if u32::from(header.cmd) == VIRTIO_NET_CTRL_VLAN_ADD {
+                    rx_filter.vlans.insert(vid);
+                } else {
+                    rx_filter.vlans.remove(&vid);
+                }
+            }
+            (VIRTIO_NET_CTRL_MQ, VIRTIO_NET_CTRL_MQ_VQ_PAIRS_SET) => {
+                let queue_pairs =
+                    u16::from_le(reader.read_obj::<u16>().map_err(Error::ReadCtrlCommand)?);
+                if (queue_pairs < VIRTIO_NET_CTRL_MQ_VQ_PAIRS_MIN as u16)
+                    || (queue_pairs > VIRTIO_NET_CTRL_MQ_VQ_PAIRS_MAX as u16)
+                {
+                    return Err(Error::InvalidQueuePairsNum);
+                }
+            }
+            (VIRTIO_NET_CTRL_RX, _)
+            | (VIRTIO_NET_CTRL_MAC, _)
+            | (VIRTIO_NET_CTRL_VLAN, _)
+            | (VIRTIO_NET_CTRL_MQ, _) => return Err(Error::InvalidCtlCmd),
+            _ => return Err(Error::InvalidCtlClass),
         }
 
         Ok(())
@@ -196,6 +423,7 @@ pub fn unregister_listener(
 
 pub struct NetCtrlEpollHandler {
     pub mem: Arc<ArcSwap<GuestMemoryMmap>>,
+    pub interrupt_cb: Arc<dyn VirtioInterrupt>,
     pub kill_evt: EventFd,
     pub pause_evt: EventFd,
     pub ctrl_q: CtrlVirtio,
@@ -251,8 +479,17 @@ impl NetCtrlEpollHandler {
                         if let Err(e) = self.ctrl_q.queue_evt.read() {
                             error!("failed to get ctl queue event: {:?}", e);
                         }
-                        if let Err(e) = self.ctrl_q.process_cvq(&mem) {
-                            error!("failed to process ctrl queue: {:?}", e);
+                        match self.ctrl_q.process_cvq(&mem) {
+                            Ok(true) => {
+                                if let Err(e) = self
+                                    .interrupt_cb
+                                    .trigger(&VirtioInterruptType::Queue, Some(&self.ctrl_q.queue))
+                                {
+                                    error!("failed to signal used ctrl queue: {:?}", e);
+                                }
+                            }
+                            Ok(false) => {}
+                            Err(e) => error!("failed to process ctrl queue: {:?}", e),
                         }
                     }
                     KILL_EVENT => {
@@ -298,7 +535,18 @@ impl TxVirtio {
         }
     }
 
-    pub fn process_desc_chain(&mut self, mem: &GuestMemoryMmap, tap: &mut Tap, queue: &mut Queue) {
+    /// Sends the frames available on `queue` through `tap`, and returns how
+    /// many of them were sent, along with their size without the virtio-net
+    /// header.
+    pub fn process_desc_chain(
+        &mut self,
+        mem: &GuestMemoryMmap,
+        tap: &mut Tap,
+        queue: &mut Queue,
+        pcap: Option<&PcapWriter>,
+    ) -> (u64, u64) {
+        let mut frames = 0;
+        let mut bytes = 0;
         while let Some(avail_desc) = queue.iter(&mem).next() {
             let head_index = avail_desc.index;
             let mut read_count = 0;
@@ -335,15 +583,26 @@ impl TxVirtio {
                 }
             }
 
+            if let (Some(pcap), Some(frame)) =
+                (pcap, self.frame_buf.get(vnet_hdr_len()..read_count))
+            {
+                pcap.capture(frame);
+            }
+
             let write_result = tap.write(&self.frame_buf[..read_count]);
             match write_result {
-                Ok(_) => {}
+                Ok(_) => {
+                    frames += 1;
+                    bytes += read_count.saturating_sub(vnet_hdr_len()) as u64;
+                }
                 Err(e) => {
                     println!("net: tx: error failed to write to tap: {}", e);
                 }
             };
             queue.add_used(&mem, head_index, 0);
         }
+
+        (frames, bytes)
     }
 }
 
@@ -351,8 +610,13 @@ impl TxVirtio {
 pub struct RxVirtio {
     pub deferred_frame: bool,
     pub deferred_irqs: bool,
+    /// Set once VIRTIO_NET_F_MRG_RXBUF has been negotiated, frames can then
+    /// be spread across several descriptor chains.
+    pub mergeable_rx_buffers: bool,
     pub bytes_read: usize,
     pub frame_buf: [u8; MAX_BUFFER_SIZE],
+    /// Counts the frames dropped as the whole receive queue can't hold them.
+    pub dropped_frames: Arc<Metric>,
 }
 
 impl Default for RxVirtio {
@@ -366,8 +630,10 @@ impl RxVirtio {
         RxVirtio {
             deferred_frame: false,
             deferred_irqs: false,
+            mergeable_rx_buffers: false,
             bytes_read: 0,
             frame_buf: [0u8; MAX_BUFFER_SIZE],
+            dropped_frames: Arc::new(Metric::default()),
         }
     }
 
@@ -379,6 +645,7 @@ impl RxVirtio {
     ) -> bool {
         let head_index = next_desc.as_ref().unwrap().index;
         let mut write_count = 0;
+        self.set_num_buffers(1);
 
         // Copy from frame into buffer, which may span multiple descriptors.
         loop {
@@ -420,6 +687,97 @@ impl RxVirtio {
 
         write_count >= self.bytes_read
     }
+
+    /// Copies the frame into as many of the available descriptor chains as
+    /// it takes, once VIRTIO_NET_F_MRG_RXBUF has been negotiated. Returns
+    /// false, leaving the queue untouched, if the driver hasn't made enough
+    /// room available for the whole frame yet. A frame the whole queue can't
+    /// hold is dropped instead, as the driver can't make more room.
+    ///
+    /// The chains not starting with a writable buffer are returned to the
+    /// driver unused.
+    pub fn process_mergeable(&mut self, mem: &GuestMemoryMmap, queue: &mut Queue) -> bool {
+        let next_avail = queue.next_avail;
+        let mut chains = Vec::new();
+        let mut room = 0;
+        let mut desc_count = 0;
+        for avail_desc in queue.iter(&mem) {
+            let head_index = avail_desc.index;
+            let mut buffers = Vec::new();
+            let mut writable = avail_desc.is_write_only();
+            if !writable {
+                warn!(
+                    "Skipping receive descriptor chain {} not writable",
+                    head_index
+                );
+            }
+            let mut next_desc = Some(avail_desc);
+            while let Some(desc) = next_desc {
+                writable &= desc.is_write_only();
+                if writable {
+                    buffers.push((desc.addr, desc.len as usize));
+                    room += desc.len as usize;
+                }
+                desc_count += 1;
+                next_desc = desc.next_descriptor();
+            }
+            chains.push((head_index, buffers));
+
+            if room >= self.bytes_read {
+                break;
+            }
+        }
+
+        if room < self.bytes_read {
+            // The chains are taken again once the driver adds more of them.
+            queue.next_avail = next_avail;
+            if desc_count < queue.actual_size() as usize {
+                return false;
+            }
+            warn!(
+                "Dropping a {} bytes frame not fitting in the receive queue",
+                self.bytes_read
+            );
+            self.dropped_frames.inc();
+            return true;
+        }
+
+        let num_buffers = chains
+            .iter()
+            .filter(|(_, buffers)| !buffers.is_empty())
+            .count();
+        self.set_num_buffers(num_buffers as u16);
+
+        let mut write_count = 0;
+        let mut used = Vec::with_capacity(chains.len());
+        for (head_index, buffers) in chains {
+            let mut chain_count = 0;
+            for (addr, len) in buffers {
+                let limit = cmp::min(write_count + len, self.bytes_read);
+                if let Err(e) = mem.write_slice(&self.frame_buf[write_count..limit], addr) {
+                    error!("Failed to write slice: {:?}", e);
+                }
+                chain_count += limit - write_count;
+                write_count = limit;
+            }
+            used.push((head_index, chain_count as u32));
+        }
+        queue.add_used_batch(&mem, &used);
+
+        // Mark that we have at least one pending packet and we need to interrupt the guest.
+        self.deferred_irqs = true;
+
+        true
+    }
+
+    // Tells the driver how many descriptor chains the frame spans, through
+    // its virtio-net header.
+    fn set_num_buffers(&mut self, num_buffers: u16) {
+        if self.bytes_read >= vnet_hdr_len() {
+            self.frame_buf[NUM_BUFFERS_OFFSET..NUM_BUFFERS_OFFSET + 2]
+                .copy_from_slice(&num_buffers.to_le_bytes());
+        }
+    }
 }
 
 pub fn build_net_config_space(
@@ -448,7 +806,7 @@ pub fn build_net_config_space_with_mq(
     }
 }
 
-fn vnet_hdr_len() -> usize {
+pub(crate) fn vnet_hdr_len() -> usize {
     mem::size_of::<virtio_net_hdr_v1>()
 }
 
@@ -496,3 +854,360 @@ pub fn open_tap(
     }
     Ok(taps)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{create_guest_memory, Buffer, Virtqueue, VirtqueueBuilder};
+    use libc::EFD_NONBLOCK;
+
+    const HEADER_ADDR: GuestAddress = GuestAddress(0x1000);
+    const DATA_ADDR: GuestAddress = GuestAddress(0x2000);
+    const ACK_ADDR: GuestAddress = GuestAddress(0x3000);
+
+    const UNICAST: [u8; 6] = [0x52, 0x54, 0x00, 0x12, 0x34, 0x56];
+    const MULTICAST: [u8; 6] = [0x01, 0x00, 0x5e, 0x00, 0x00, 0xfb];
+
+    fn create_ctrl(vq: &Virtqueue) -> (CtrlVirtio, Arc<Mutex<RxFilter>>) {
+        let rx_filter = Arc::new(Mutex::new(RxFilter::new(None)));
+        let ctrl = CtrlVirtio::new(
+            vq.create_queue(),
+            EventFd::new(EFD_NONBLOCK).unwrap(),
+            rx_filter.clone(),
+            None,
+        );
+        (ctrl, rx_filter)
+    }
+
+    // Sends a command through the control queue, and returns its ack.
+    fn send_command(
+        mem: &GuestMemoryMmap,
+        vq: &mut Virtqueue,
+        ctrl: &mut CtrlVirtio,
+        class: u32,
+        cmd: u32,
+        data: &[u8],
+    ) -> u32 {
+        mem.write_slice(&[class as u8, cmd as u8], HEADER_ADDR)
+            .unwrap();
+        mem.write_slice(data, DATA_ADDR).unwrap();
+        mem.write_obj(0xffu8, ACK_ADDR).unwrap();
+
+        let mut buffers = vec![Buffer::readable(HEADER_ADDR, 2)];
+        if !data.is_empty() {
+            buffers.push(Buffer::readable(DATA_ADDR, data.len() as u32));
+        }
+        buffers.push(Buffer::writable(ACK_ADDR, 1));
+        let head = vq.add_chain(&buffers);
+
+        assert!(ctrl.process_cvq(mem).unwrap());
+        let used_idx = vq.used_idx();
+        assert_eq!(vq.used_elem(used_idx.wrapping_sub(1)), (u32::from(head), 1));
+
+        u32::from(mem.read_obj::<u8>(ACK_ADDR).unwrap())
+    }
+
+    fn mac_table(macs: &[[u8; 6]]) -> Vec<u8> {
+        let mut table = (macs.len() as u32).to_le_bytes().to_vec();
+        for mac in macs {
+            table.extend_from_slice(mac);
+        }
+        table
+    }
+
+    fn frame(dest: [u8; 6], vid: Option<u16>) -> Vec<u8> {
+        let mut frame = dest.to_vec();
+        frame.extend_from_slice(&[0x52, 0x54, 0x00, 0xab, 0xcd, 0xef]);
+        if let Some(vid) = vid {
+            frame.extend_from_slice(&ETH_P_8021Q.to_be_bytes());
+            frame.extend_from_slice(&vid.to_be_bytes());
+        }
+        frame.extend_from_slice(&[0x08, 0x00]);
+        frame.resize(64, 0);
+        frame
+    }
+
+    #[test]
+    fn test_ctrl_rx_mode() {
+        let mem = create_guest_memory(0x10000);
+        let mut vq = VirtqueueBuilder::new(&mem, 16)
+            .start(GuestAddress(0x8000))
+            .build();
+        let (mut ctrl, rx_filter) = create_ctrl(&vq);
+        assert!(rx_filter.lock().unwrap().promisc);
+
+        let ack = send_command(
+            &mem,
+            &mut vq,
+            &mut ctrl,
+            VIRTIO_NET_CTRL_RX,
+            VIRTIO_NET_CTRL_RX_PROMISC,
+            &[0],
+        );
+        assert_eq!(ack, VIRTIO_NET_OK);
+        assert!(!rx_filter.lock().unwrap().promisc);
+
+        let ack = send_command(
+            &mem,
+            &mut vq,
+            &mut ctrl,
+            VIRTIO_NET_CTRL_RX,
+            VIRTIO_NET_CTRL_RX_ALLMULTI,
+            &[1],
+        );
+        assert_eq!(ack, VIRTIO_NET_OK);
+        assert!(rx_filter.lock().unwrap().allmulti);
+    }
+
+    #[test]
+    fn test_ctrl_mac_table() {
+        let mem = create_guest_memory(0x10000);
+        let mut vq = VirtqueueBuilder::new(&mem, 16)
+            .start(GuestAddress(0x8000))
+            .build();
+        let (mut ctrl, rx_filter) = create_ctrl(&vq);
+
+        let mut data = mac_table(&[UNICAST]);
+        data.extend(mac_table(&[MULTICAST]));
+        let ack = send_command(
+            &mem,
+            &mut vq,
+            &mut ctrl,
+            VIRTIO_NET_CTRL_MAC,
+            VIRTIO_NET_CTRL_MAC_TABLE_SET,
+            &data,
+        );
+        assert_eq!(ack, VIRTIO_NET_OK);
+        {
+            let rx_filter = rx_filter.lock().unwrap();
+            assert_eq!(
+                rx_filter.unicast,
+                vec![MacAddr::from_bytes_unchecked(&UNICAST)]
+            );
+            assert_eq!(
+                rx_filter.multicast,
+                vec![MacAddr::from_bytes_unchecked(&MULTICAST)]
+            );
+            assert!(!rx_filter.unicast_overflow && !rx_filter.multicast_overflow);
+        }
+
+        // Too many multicast addresses to be held.
+        let mut data = mac_table(&[]);
+        data.extend(mac_table(&[MULTICAST; MAC_TABLE_ENTRIES + 1]));
+        let ack = send_command(
+            &mem,
+            &mut vq,
+            &mut ctrl,
+            VIRTIO_NET_CTRL_MAC,
+            VIRTIO_NET_CTRL_MAC_TABLE_SET,
+            &data,
+        );
+        assert_eq!(ack, VIRTIO_NET_OK);
+        {
+            let rx_filter = rx_filter.lock().unwrap();
+            assert!(rx_filter.unicast.is_empty());
+            assert!(rx_filter.multicast_overflow);
+        }
+
+        // The multicast table is missing.
+        let ack = send_command(
+            &mem,
+            &mut vq,
+            &mut ctrl,
+            VIRTIO_NET_CTRL_MAC,
+            VIRTIO_NET_CTRL_MAC_TABLE_SET,
+            &mac_table(&[UNICAST]),
+        );
+        assert_eq!(ack, VIRTIO_NET_ERR);
+    }
+
+    #[test]
+    fn test_ctrl_vlan() {
+        let mem = create_guest_memory(0x10000);
+        let mut vq = VirtqueueBuilder::new(&mem, 16)
+            .start(GuestAddress(0x8000))
+            .build();
+        let (mut ctrl, rx_filter) = create_ctrl(&vq);
+
+        let ack = send_command(
+            &mem,
+            &mut vq,
+            &mut ctrl,
+            VIRTIO_NET_CTRL_VLAN,
+            VIRTIO_NET_CTRL_VLAN_ADD,
+            &5u16.to_le_bytes(),
+        );
+        assert_eq!(ack, VIRTIO_NET_OK);
+        assert!(rx_filter.lock().unwrap().vlans.contains(&5));
+
+        let ack = send_command(
+            &mem,
+            &mut vq,
+            &mut ctrl,
+            VIRTIO_NET_CTRL_VLAN,
+            VIRTIO_NET_CTRL_VLAN_DEL,
+            &5u16.to_le_bytes(),
+        );
+        assert_eq!(ack, VIRTIO_NET_OK);
+        assert!(rx_filter.lock().unwrap().vlans.is_empty());
+
+        let ack = send_command(
+            &mem,
+            &mut vq,
+            &mut ctrl,
+            VIRTIO_NET_CTRL_VLAN,
+            VIRTIO_NET_CTRL_VLAN_ADD,
+            &4096u16.to_le_bytes(),
+        );
+        assert_eq!(ack, VIRTIO_NET_ERR);
+    }
+
+    #[test]
+    fn test_ctrl_unknown_commands() {
+        let mem = create_guest_memory(0x10000);
+        let mut vq = VirtqueueBuilder::new(&mem, 16)
+            .start(GuestAddress(0x8000))
+            .build();
+        let (mut ctrl, _) = create_ctrl(&vq);
+
+        // VIRTIO_NET_CTRL_RX_NOBCAST, VIRTIO_NET_F_CTRL_RX_EXTRA isn't offered.
+        let ack = send_command(&mem, &mut vq, &mut ctrl, VIRTIO_NET_CTRL_RX, 5, &[1]);
+        assert_eq!(ack, VIRTIO_NET_ERR);
+
+        let ack = send_command(&mem, &mut vq, &mut ctrl, 0x42, 0, &[]);
+        assert_eq!(ack, VIRTIO_NET_ERR);
+
+        // Missing the on/off byte.
+        let ack = send_command(
+            &mem,
+            &mut vq,
+            &mut ctrl,
+            VIRTIO_NET_CTRL_RX,
+            VIRTIO_NET_CTRL_RX_PROMISC,
+            &[],
+        );
+        assert_eq!(ack, VIRTIO_NET_ERR);
+    }
+
+    #[test]
+    fn test_rx_mergeable_buffers() {
+        let mem = create_guest_memory(0x10000);
+        let mut vq = VirtqueueBuilder::new(&mem, 16)
+            .start(GuestAddress(0x8000))
+            .build();
+        let mut queue = vq.create_queue();
+
+        let mut rx = RxVirtio::new();
+        rx.mergeable_rx_buffers = true;
+        let frame: Vec<u8> = (0..9000).map(|i| i as u8).collect();
+        let hdr_len = vnet_hdr_len();
+        rx.frame_buf[..hdr_len].copy_from_slice(&[0xff; 12]);
+        rx.frame_buf[hdr_len..hdr_len + frame.len()].copy_from_slice(&frame);
+        rx.bytes_read = hdr_len + frame.len();
+
+        // The first chain is made of two buffers.
+        let head0 = vq.add_chain(&[
+            Buffer::writable(GuestAddress(0x1000), 0x800),
+            Buffer::writable(GuestAddress(0x1800), 0x800),
+        ]);
+        let head1 = vq.add_chain(&[Buffer::writable(GuestAddress(0x2000), 0x1000)]);
+
+        // Not enough room for the frame, which is left for later.
+        assert!(!rx.process_mergeable(&mem, &mut queue));
+        assert_eq!(vq.used_idx(), 0);
+        assert_eq!(queue.next_avail.0, 0);
+
+        let head2 = vq.add_chain(&[Buffer::writable(GuestAddress(0x3000), 0x1000)]);
+        let head3 = vq.add_chain(&[Buffer::writable(GuestAddress(0x4000), 0x1000)]);
+        assert!(rx.process_mergeable(&mem, &mut queue));
+        assert!(rx.deferred_irqs);
+        assert_eq!(vq.used_idx(), 3);
+        assert_eq!(vq.used_elem(0), (u32::from(head0), 0x1000));
+        assert_eq!(vq.used_elem(1), (u32::from(head1), 0x1000));
+        assert_eq!(
+            vq.used_elem(2),
+            (u32::from(head2), (rx.bytes_read - 0x2000) as u32)
+        );
+
+        // The last chain is left for the next frame.
+        assert_eq!(queue.next_avail.0, 3);
+        assert_eq!(queue.iter(&mem).next().unwrap().index, head3);
+
+        let mut hdr = [0u8; 12];
+        mem.read_slice(&mut hdr, GuestAddress(0x1000)).unwrap();
+        assert_eq!(u16::from_le_bytes([hdr[10], hdr[11]]), 3);
+        let mut data = vec![0u8; frame.len()];
+        mem.read_slice(&mut data, GuestAddress(0x1000 + hdr_len as u64))
+            .unwrap();
+        assert_eq!(data, frame);
+    }
+
+    #[test]
+    fn test_rx_mergeable_buffers_exhausted() {
+        let mem = create_guest_memory(0x10000);
+        let mut vq = VirtqueueBuilder::new(&mem, 4)
+            .start(GuestAddress(0x8000))
+            .build();
+        let mut queue = vq.create_queue();
+
+        let mut rx = RxVirtio::new();
+        rx.mergeable_rx_buffers = true;
+
+        // A chain starting with a read-only buffer is returned unused.
+        let head0 = vq.add_chain(&[Buffer::readable(GuestAddress(0x1000), 0x1000)]);
+        let head1 = vq.add_chain(&[Buffer::writable(GuestAddress(0x2000), 0x1000)]);
+        rx.bytes_read = 0x800;
+        assert!(rx.process_mergeable(&mem, &mut queue));
+        assert_eq!(vq.used_idx(), 2);
+        assert_eq!(vq.used_elem(0), (u32::from(head0), 0));
+        assert_eq!(vq.used_elem(1), (u32::from(head1), 0x800));
+        let mut hdr = [0u8; 12];
+        mem.read_slice(&mut hdr, GuestAddress(0x2000)).unwrap();
+        assert_eq!(u16::from_le_bytes([hdr[10], hdr[11]]), 1);
+
+        // The whole queue can't hold the frame, which is dropped.
+        for i in 0..4 {
+            vq.add_chain(&[Buffer::writable(GuestAddress(0x1000 * (i + 1)), 0x100)]);
+        }
+        rx.bytes_read = 9000;
+        assert!(rx.process_mergeable(&mem, &mut queue));
+        assert_eq!(rx.dropped_frames.get(), 1);
+        assert_eq!(vq.used_idx(), 2);
+        // The chains are left for the next frame.
+        assert_eq!(queue.next_avail.0, 2);
+    }
+
+    #[test]
+    fn test_rx_filter() {
+        let mut rx_filter = RxFilter::new(Some(MacAddr::from_bytes_unchecked(&UNICAST)));
+        let other_unicast = [0x52, 0x54, 0x00, 0x65, 0x43, 0x21];
+
+        // Everything goes through by default.
+        assert!(rx_filter.accepts(&frame(other_unicast, None)));
+
+        rx_filter.promisc = false;
+        assert!(rx_filter.accepts(&frame(UNICAST, None)));
+        assert!(!rx_filter.accepts(&frame(other_unicast, None)));
+        assert!(rx_filter.accepts(&frame([0xff; 6], None)));
+        assert!(!rx_filter.accepts(&frame(MULTICAST, None)));
+
+        rx_filter.unicast = vec![MacAddr::from_bytes_unchecked(&other_unicast)];
+        rx_filter.multicast = vec![MacAddr::from_bytes_unchecked(&MULTICAST)];
+        assert!(rx_filter.accepts(&frame(other_unicast, None)));
+        assert!(rx_filter.accepts(&frame(MULTICAST, None)));
+
+        rx_filter.multicast.clear();
+        rx_filter.allmulti = true;
+        assert!(rx_filter.accepts(&frame(MULTICAST, None)));
+
+        rx_filter.vlan_filtering = true;
+        rx_filter.vlans.insert(5);
+        assert!(rx_filter.accepts(&frame(UNICAST, Some(5))));
+        assert!(!rx_filter.accepts(&frame(UNICAST, Some(6))));
+        assert!(rx_filter.accepts(&frame(UNICAST, None)));
+
+        // Unicast frames can't be filtered without knowing the device MAC.
+        rx_filter.mac = None;
+        assert!(rx_filter.accepts(&frame(UNICAST, None)));
+    }
+}
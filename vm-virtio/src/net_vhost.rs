@@ -0,0 +1,211 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Kernel datapath of the virtio-net device, through vhost-net.
+//!
+//! The kernel moves the frames between the TAP interface and the queues of a
+//! queue pair, without copying them through the VMM. The VMM hands it the
+//! guest memory table and the rings, then the guest kicks it through the
+//! queue EventFds, and it interrupts the guest through irqfds. The control
+//! queue stays with the VMM.
+//!
+//! With a transport providing no irqfd for the queues, the kernel signals
+//! EventFds instead, which a device event loop turns into interrupts.
+
+use super::Error as DeviceError;
+use super::{DeviceEventT, Queue, VirtioInterrupt, VirtioInterruptType};
+use crate::event_loop::EpollHandler;
+use libc::EFD_NONBLOCK;
+use net_util::Tap;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::result;
+use std::sync::Arc;
+use vhost_rs::vhost_kern::net::Net as VhostNet;
+use vhost_rs::{VhostBackend, VhostUserMemoryRegionInfo, VringConfigData};
+use virtio_bindings::bindings::virtio_net::{VIRTIO_F_VERSION_1, VIRTIO_NET_F_MRG_RXBUF};
+use virtio_bindings::bindings::virtio_ring::{
+    VIRTIO_RING_F_EVENT_IDX, VIRTIO_RING_F_INDIRECT_DESC,
+};
+use vm_memory::{Address, GuestMemory, GuestMemoryMmap, GuestMemoryRegion};
+use vmm_sys_util::eventfd::EventFd;
+
+/// Features of the device implemented by the datapath, rather than by the
+/// TAP interface or the control queue, which vhost-net must support for the
+/// device to offer them.
+pub const VHOST_NET_DATAPATH_FEATURES: u64 = 1 << VIRTIO_F_VERSION_1
+    | 1 << VIRTIO_NET_F_MRG_RXBUF
+    | 1 << VIRTIO_RING_F_EVENT_IDX
+    | 1 << VIRTIO_RING_F_INDIRECT_DESC;
+
+/// Returns the features vhost-net supports, failing when the host can't run
+/// the datapath in the kernel.
+pub fn vhost_net_features(mem: &GuestMemoryMmap) -> vhost_rs::Result<u64> {
+    VhostNet::new(mem)?.get_features()
+}
+
+/// The kernel datapath of a queue pair.
+pub struct VhostNetQueuePair {
+    vhost: VhostNet<GuestMemoryMmap>,
+    tap: Tap,
+}
+
+impl VhostNetQueuePair {
+    /// Hands the receive and transmit `queues` over to vhost-net, which
+    /// starts moving the frames from and to `tap`. Also returns the EventFds
+    /// to turn into interrupts, for the queues which have no irqfd.
+    pub fn new(
+        mem: &GuestMemoryMmap,
+        tap: Tap,
+        features: u64,
+        queues: Vec<Queue>,
+        queue_evts: &[EventFd],
+        interrupt_cb: &Arc<dyn VirtioInterrupt>,
+    ) -> vhost_rs::Result<(Self, Vec<(EventFd, Queue)>)> {
+        let mut vhost = VhostNet::new(mem)?;
+        vhost.set_owner()?;
+        vhost.set_features(features)?;
+
+        vhost.set_mem_table(&memory_table(mem))?;
+
+        let mut call_evts = Vec::new();
+        for (index, queue) in queues.into_iter().enumerate() {
+            vhost.set_vring_num(index, queue.actual_size())?;
+            vhost.set_vring_addr(
+                index,
+                &VringConfigData {
+                    queue_max_size: queue.get_max_size(),
+                    queue_size: queue.actual_size(),
+                    flags: 0,
+                    desc_table_addr: queue.desc_table.raw_value(),
+                    used_ring_addr: queue.used_ring.raw_value(),
+                    avail_ring_addr: queue.avail_ring.raw_value(),
+                    log_addr: None,
+                },
+            )?;
+            // Not 0 for the queues of a restored device.
+            vhost.set_vring_base(index, queue.next_avail.0)?;
+
+            match interrupt_cb.notifier(&VirtioInterruptType::Queue, Some(&queue)) {
+                Some(irqfd) => vhost.set_vring_call(index, irqfd)?,
+                None => {
+                    let call_evt = EventFd::new(EFD_NONBLOCK).map_err(vhost_rs::Error::IOError)?;
+                    vhost.set_vring_call(index, &call_evt)?;
+                    call_evts.push((call_evt, queue));
+                }
+            }
+            vhost.set_vring_kick(index, &queue_evts[index])?;
+        }
+
+        let queue_pair = VhostNetQueuePair { vhost, tap };
+        queue_pair.start()?;
+
+        Ok((queue_pair, call_evts))
+    }
+
+    /// Lets the kernel move the frames of the queue pair.
+    pub fn start(&self) -> vhost_rs::Result<()> {
+        for index in 0..2 {
+            self.vhost.set_backend(index, Some(&self.tap))?;
+        }
+        Ok(())
+    }
+
+    /// Hands the guest memory table over to vhost-net again, after a memory
+    /// region has been hotplugged, so the kernel can access the buffers the
+    /// guest places in it.
+    pub fn update_memory(&self, mem: &GuestMemoryMmap) -> vhost_rs::Result<()> {
+        self.vhost.set_mem_table(&memory_table(mem))
+    }
+
+    /// Stops the kernel from moving frames, the queues being left as they
+    /// are.
+    pub fn stop(&self) -> vhost_rs::Result<()> {
+        for index in 0..2 {
+            self.vhost.set_backend(index, None)?;
+        }
+        Ok(())
+    }
+}
+
+fn memory_table(mem: &GuestMemoryMmap) -> Vec<VhostUserMemoryRegionInfo> {
+    let mut regions = Vec::new();
+    let _: result::Result<(), ()> = mem.with_regions(|_, region| {
+        regions.push(VhostUserMemoryRegionInfo {
+            guest_phys_addr: region.start_addr().raw_value(),
+            memory_size: region.len() as u64,
+            userspace_addr: region.as_ptr() as u64,
+            mmap_offset: 0,
+            mmap_handle: -1,
+        });
+        Ok(())
+    });
+    regions
+}
+
+/// Turns the signals of vhost-net into interrupts, for the queues which have
+/// no irqfd.
+pub struct VhostNetInterruptRelay {
+    pub interrupt_cb: Arc<dyn VirtioInterrupt>,
+    pub call_evts: Vec<(EventFd, Queue)>,
+}
+
+impl EpollHandler for VhostNetInterruptRelay {
+    fn events(&self) -> Vec<(RawFd, DeviceEventT)> {
+        self.call_evts
+            .iter()
+            .enumerate()
+            .map(|(index, (call_evt, _))| (call_evt.as_raw_fd(), index as DeviceEventT))
+            .collect()
+    }
+
+    fn handle_event(&mut self, event: DeviceEventT) -> result::Result<(), DeviceError> {
+        let (call_evt, queue) =
+            self.call_evts
+                .get(event as usize)
+                .ok_or(DeviceError::UnknownEvent {
+                    device: "net",
+                    event,
+                })?;
+        call_evt
+            .read()
+            .map_err(|e| DeviceError::FailedReadingQueue {
+                event_type: "vhost-net call",
+                underlying: e,
+            })?;
+        self.interrupt_cb
+            .trigger(&VirtioInterruptType::Queue, Some(queue))
+            .map_err(DeviceError::FailedSignalingUsedQueue)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::CountingInterrupt;
+    use std::sync::atomic::Ordering;
+
+    #[test]
+    fn test_interrupt_relay() {
+        let interrupt = Arc::new(CountingInterrupt::default());
+        let call_evts: Vec<(EventFd, Queue)> = (0..2)
+            .map(|_| (EventFd::new(EFD_NONBLOCK).unwrap(), Queue::new(256)))
+            .collect();
+        call_evts[1].0.write(1).unwrap();
+        let mut relay = VhostNetInterruptRelay {
+            interrupt_cb: interrupt.clone(),
+            call_evts,
+        };
+
+        let events = relay.events();
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[1].1, 1);
+
+        relay.handle_event(1).unwrap();
+        assert_eq!(interrupt.count.load(Ordering::SeqCst), 1);
+        // The signal has been consumed.
+        assert!(relay.handle_event(1).is_err());
+        assert!(relay.handle_event(2).is_err());
+        assert_eq!(interrupt.count.load(Ordering::SeqCst), 1);
+    }
+}
@@ -11,7 +11,7 @@ use super::{
     ActivateError, ActivateResult, DescriptorChain, DeviceEventT, Queue, VirtioDevice,
     VirtioDeviceType, VIRTIO_F_IOMMU_PLATFORM, VIRTIO_F_VERSION_1,
 };
-use crate::{VirtioInterrupt, VirtioInterruptType};
+use crate::{join_epoll_threads, VirtioInterrupt, VirtioInterruptType};
 use arc_swap::ArcSwap;
 use epoll;
 use libc::EFD_NONBLOCK;
@@ -25,7 +25,7 @@ use std::result;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use std::thread;
-use vm_device::{Migratable, MigratableError, Pausable, Snapshotable};
+use vm_device::{Migratable, MigratableError, Pausable, Snapshot, Snapshotable};
 use vm_memory::{
     Address, ByteValued, Bytes, GuestAddress, GuestMemoryError, GuestMemoryMmap, GuestUsize,
 };
@@ -494,6 +494,9 @@ impl VirtioDevice for Pmem {
             // Ignore the result because there is nothing we can do about it.
             let _ = kill_evt.write(1);
         }
+        join_epoll_threads(self.epoll_threads.take());
+
+        self.acked_features = 0;
 
         // Return the interrupt and queue EventFDs
         Some((
@@ -504,5 +507,15 @@ impl VirtioDevice for Pmem {
 }
 
 virtio_pausable!(Pmem);
-impl Snapshotable for Pmem {}
+// The content of the device lives in its backing file, and the features the
+// driver acked are restored by the transport along with the queues.
+impl Snapshotable for Pmem {
+    fn snapshot(&self) -> result::Result<Snapshot, MigratableError> {
+        Ok(Snapshot::new(&self.id()))
+    }
+
+    fn restore(&mut self, _snapshot: Snapshot) -> result::Result<(), MigratableError> {
+        Ok(())
+    }
+}
 impl Migratable for Pmem {}
@@ -9,11 +9,13 @@
 // SPDX-License-Identifier: Apache-2.0 AND BSD-3-Clause
 
 use std::cmp::min;
+use std::fmt::{self, Display};
+use std::io;
 use std::num::Wrapping;
 use std::sync::atomic::{fence, Ordering};
 use std::sync::Arc;
 
-use crate::device::VirtioIommuRemapping;
+use crate::device::{DmaAccess, DmaMapping};
 use vm_memory::{
     Address, ByteValued, Bytes, GuestAddress, GuestMemory, GuestMemoryMmap, GuestUsize,
 };
@@ -29,13 +31,127 @@ pub(super) const VIRTQ_DESC_F_WRITE: u16 = 0x2;
 // The Virtio Spec 1.0 defines the alignment of VirtIO descriptor is 16 bytes,
 // which fulfills the explicit constraint of GuestMemoryMmap::read_obj().
 
+// Translates `addr` through `dma_mapping`, if there is one.
+fn translate(
+    dma_mapping: &Option<Arc<dyn DmaMapping>>,
+    addr: GuestAddress,
+    len: u64,
+    access: DmaAccess,
+) -> io::Result<GuestAddress> {
+    match dma_mapping {
+        Some(dma_mapping) => dma_mapping
+            .translate(addr.raw_value(), len, access)
+            .map(GuestAddress),
+        None => Ok(addr),
+    }
+}
+
+/// Reasons for a descriptor chain to be cut short.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DescChainError {
+    /// The chain holds more descriptors than the queue size.
+    TooLong,
+    /// The chain links back to the descriptor at this index.
+    Loop(u16),
+}
+
+impl Display for DescChainError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DescChainError::TooLong => write!(f, "descriptor chain longer than the queue"),
+            DescChainError::Loop(index) => {
+                write!(f, "descriptor chain loops back to descriptor {}", index)
+            }
+        }
+    }
+}
+
+impl std::error::Error for DescChainError {}
+
+/// Reasons for the queue configuration set by the driver to be refused.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum QueueConfigError {
+    /// The size is zero, not a power of two, or bigger than the maximum.
+    InvalidSize(u16),
+    /// The descriptor table isn't 16 bytes aligned.
+    MisalignedDescTable(GuestAddress),
+    /// The available ring isn't 2 bytes aligned.
+    MisalignedAvailRing(GuestAddress),
+    /// The used ring isn't 4 bytes aligned.
+    MisalignedUsedRing(GuestAddress),
+}
+
+impl Display for QueueConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::QueueConfigError::*;
+
+        match self {
+            InvalidSize(size) => write!(f, "invalid queue size {}", size),
+            MisalignedDescTable(addr) => write!(
+                f,
+                "descriptor table at 0x{:x} breaks alignment constraints",
+                addr.raw_value()
+            ),
+            MisalignedAvailRing(addr) => write!(
+                f,
+                "available ring at 0x{:x} breaks alignment constraints",
+                addr.raw_value()
+            ),
+            MisalignedUsedRing(addr) => write!(
+                f,
+                "used ring at 0x{:x} breaks alignment constraints",
+                addr.raw_value()
+            ),
+        }
+    }
+}
+
+impl std::error::Error for QueueConfigError {}
+
 /// An iterator over a single descriptor chain.  Not to be confused with AvailIter,
 /// which iterates over the descriptor chain heads in a queue.
+///
+/// The iteration stops at the first descriptor linking to a descriptor
+/// already visited, or past the queue size, the reason being logged.
 pub struct DescIter<'a> {
     next: Option<DescriptorChain<'a>>,
+    // Bitmap of the visited descriptor indexes, allocated when following
+    // the first link only, so that single descriptor chains don't pay for it.
+    visited: Vec<u64>,
 }
 
 impl<'a> DescIter<'a> {
+    fn is_visited(&self, index: u16) -> bool {
+        self.visited[index as usize / 64] & (1 << (index % 64)) != 0
+    }
+
+    fn mark_visited(&mut self, index: u16) {
+        self.visited[index as usize / 64] |= 1 << (index % 64);
+    }
+
+    fn follow(&mut self, current: &DescriptorChain<'a>) -> Option<DescriptorChain<'a>> {
+        if current.flags & VIRTQ_DESC_F_NEXT == 0 {
+            return None;
+        }
+
+        let error = if current.ttl <= 1 {
+            DescChainError::TooLong
+        } else {
+            if self.visited.is_empty() {
+                self.visited = vec![0; (current.queue_size as usize + 63) / 64];
+            }
+            self.mark_visited(current.index);
+            // Indexes past the queue size are rejected by checked_new().
+            if current.next >= current.queue_size || !self.is_visited(current.next) {
+                return current.next_descriptor();
+            }
+            DescChainError::Loop(current.next)
+        };
+
+        error!("Invalid descriptor chain: {}", error);
+        None
+    }
+
     /// Returns an iterator that only yields the readable descriptors in the chain.
     pub fn readable(self) -> impl Iterator<Item = DescriptorChain<'a>> {
         self.filter(|d| !d.is_write_only())
@@ -52,7 +168,7 @@ impl<'a> Iterator for DescIter<'a> {
 
     fn next(&mut self) -> Option<Self::Item> {
         if let Some(current) = self.next.take() {
-            self.next = current.next_descriptor();
+            self.next = self.follow(&current);
             Some(current)
         } else {
             None
@@ -78,7 +194,7 @@ pub struct DescriptorChain<'a> {
     desc_table: GuestAddress,
     queue_size: u16,
     ttl: u16, // used to prevent infinite chain cycles
-    iommu_mapping_cb: Option<Arc<VirtioIommuRemapping>>,
+    dma_mapping: Option<Arc<dyn DmaMapping>>,
 
     /// Reference to guest memory
     pub mem: &'a GuestMemoryMmap,
@@ -106,7 +222,7 @@ impl<'a> DescriptorChain<'a> {
         desc_table: GuestAddress,
         queue_size: u16,
         index: u16,
-        iommu_mapping_cb: Option<Arc<VirtioIommuRemapping>>,
+        dma_mapping: Option<Arc<dyn DmaMapping>>,
     ) -> Option<DescriptorChain> {
         if index >= queue_size {
             return None;
@@ -129,10 +245,22 @@ impl<'a> DescriptorChain<'a> {
         };
 
         // Translate address if necessary
-        let desc_addr = if let Some(iommu_mapping_cb) = &iommu_mapping_cb {
-            (iommu_mapping_cb)(desc.addr).unwrap()
+        let access = if desc.flags & VIRTQ_DESC_F_WRITE != 0 {
+            DmaAccess::Write
         } else {
-            desc.addr
+            DmaAccess::Read
+        };
+        let desc_addr = match translate(
+            &dma_mapping,
+            GuestAddress(desc.addr),
+            u64::from(desc.len),
+            access,
+        ) {
+            Ok(addr) => addr,
+            Err(e) => {
+                error!("Failed to translate descriptor address: {}", e);
+                return None;
+            }
         };
 
         let chain = DescriptorChain {
@@ -141,11 +269,11 @@ impl<'a> DescriptorChain<'a> {
             queue_size,
             ttl: queue_size,
             index,
-            addr: GuestAddress(desc_addr),
+            addr: desc_addr,
             len: desc.len,
             flags: desc.flags,
             next: desc.next,
-            iommu_mapping_cb,
+            dma_mapping,
         };
 
         if chain.is_valid() {
@@ -187,7 +315,7 @@ impl<'a> DescriptorChain<'a> {
                 self.desc_table,
                 self.queue_size,
                 self.next,
-                self.iommu_mapping_cb.clone(),
+                self.dma_mapping.clone(),
             )
             .map(|mut c| {
                 c.ttl = self.ttl - 1;
@@ -204,7 +332,10 @@ impl<'a> IntoIterator for DescriptorChain<'a> {
     type IntoIter = DescIter<'a>;
 
     fn into_iter(self) -> Self::IntoIter {
-        DescIter { next: Some(self) }
+        DescIter {
+            next: Some(self),
+            visited: Vec::new(),
+        }
     }
 }
 
@@ -217,7 +348,7 @@ pub struct AvailIter<'a, 'b> {
     last_index: Wrapping<u16>,
     queue_size: u16,
     next_avail: &'b mut Wrapping<u16>,
-    iommu_mapping_cb: Option<Arc<VirtioIommuRemapping>>,
+    dma_mapping: Option<Arc<dyn DmaMapping>>,
 }
 
 impl<'a, 'b> AvailIter<'a, 'b> {
@@ -230,7 +361,7 @@ impl<'a, 'b> AvailIter<'a, 'b> {
             last_index: Wrapping(0),
             queue_size: 0,
             next_avail: q_next_avail,
-            iommu_mapping_cb: None,
+            dma_mapping: None,
         }
     }
 }
@@ -239,11 +370,13 @@ impl<'a, 'b> Iterator for AvailIter<'a, 'b> {
     type Item = DescriptorChain<'a>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        if self.next_index == self.last_index {
+        if self.next_index == self.last_index || self.queue_size == 0 {
             return None;
         }
 
-        let offset = (4 + (self.next_index.0 % self.queue_size) * 2) as usize;
+        // Computed as usize, as the offset doesn't fit in a u16 for the
+        // largest queues.
+        let offset = 4 + (self.next_index.0 % self.queue_size) as usize * 2;
         let avail_addr = match self.mem.checked_offset(self.avail_ring, offset) {
             Some(a) => a,
             None => return None,
@@ -265,7 +398,7 @@ impl<'a, 'b> Iterator for AvailIter<'a, 'b> {
             self.desc_table,
             self.queue_size,
             desc_index,
-            self.iommu_mapping_cb.clone(),
+            self.dma_mapping.clone(),
         );
         if ret.is_some() {
             *self.next_avail += Wrapping(1);
@@ -301,7 +434,20 @@ pub struct Queue {
     pub next_avail: Wrapping<u16>,
     pub next_used: Wrapping<u16>,
 
-    pub iommu_mapping_cb: Option<Arc<VirtioIommuRemapping>>,
+    pub dma_mapping: Option<Arc<dyn DmaMapping>>,
+}
+
+/// The configuration and position of a queue, as saved in a snapshot.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct QueueState {
+    pub size: u16,
+    pub ready: bool,
+    pub vector: u16,
+    pub desc_table: u64,
+    pub avail_ring: u64,
+    pub used_ring: u64,
+    pub next_avail: u16,
+    pub next_used: u16,
 }
 
 impl Queue {
@@ -317,7 +463,7 @@ impl Queue {
             used_ring: GuestAddress(0),
             next_avail: Wrapping(0),
             next_used: Wrapping(0),
-            iommu_mapping_cb: None,
+            dma_mapping: None,
         }
     }
 
@@ -325,18 +471,83 @@ impl Queue {
         self.max_size
     }
 
+    /// Sets the queue size selected by the driver, which must be a power of
+    /// two no bigger than the maximum size. An invalid size is refused and
+    /// the current one kept.
+    pub fn set_size(&mut self, size: u16) -> Result<(), QueueConfigError> {
+        if !self.valid_size(size) {
+            return Err(QueueConfigError::InvalidSize(size));
+        }
+        self.size = size;
+        Ok(())
+    }
+
+    fn valid_size(&self, size: u16) -> bool {
+        size != 0 && size <= self.max_size && size.is_power_of_two()
+    }
+
+    // Checks the configuration the driver set before the queue can be made
+    // ready, the ring addresses being checked against the guest memory by
+    // `is_valid()` only, since they may still need translating.
+    fn check_config(&self) -> Result<(), QueueConfigError> {
+        if !self.valid_size(self.size) {
+            return Err(QueueConfigError::InvalidSize(self.size));
+        }
+        if self.desc_table.mask(0xf) != 0 {
+            return Err(QueueConfigError::MisalignedDescTable(self.desc_table));
+        }
+        if self.avail_ring.mask(0x1) != 0 {
+            return Err(QueueConfigError::MisalignedAvailRing(self.avail_ring));
+        }
+        if self.used_ring.mask(0x3) != 0 {
+            return Err(QueueConfigError::MisalignedUsedRing(self.used_ring));
+        }
+        Ok(())
+    }
+
+    fn translate_rings(&mut self) -> io::Result<()> {
+        let size = u64::from(self.actual_size());
+        let desc_table = translate(
+            &self.dma_mapping,
+            self.desc_table,
+            16 * size,
+            DmaAccess::Read,
+        )?;
+        let avail_ring = translate(
+            &self.dma_mapping,
+            self.avail_ring,
+            6 + 2 * size,
+            DmaAccess::Read,
+        )?;
+        let used_ring = translate(
+            &self.dma_mapping,
+            self.used_ring,
+            6 + 8 * size,
+            DmaAccess::Write,
+        )?;
+
+        self.desc_table = desc_table;
+        self.avail_ring = avail_ring;
+        self.used_ring = used_ring;
+        Ok(())
+    }
+
+    /// Marks the queue ready, or not. The queue stays disabled if the driver
+    /// hasn't set a valid size and aligned ring addresses.
     pub fn enable(&mut self, set: bool) {
         self.ready = set;
 
         if set {
+            if let Err(e) = self.check_config() {
+                error!("Refusing to enable virtio queue: {}", e);
+                self.ready = false;
+                return;
+            }
+
             // Translate address of descriptor table and vrings.
-            if let Some(iommu_mapping_cb) = &self.iommu_mapping_cb {
-                self.desc_table =
-                    GuestAddress((iommu_mapping_cb)(self.desc_table.raw_value()).unwrap());
-                self.avail_ring =
-                    GuestAddress((iommu_mapping_cb)(self.avail_ring.raw_value()).unwrap());
-                self.used_ring =
-                    GuestAddress((iommu_mapping_cb)(self.used_ring.raw_value()).unwrap());
+            if let Err(e) = self.translate_rings() {
+                error!("Failed to translate queue addresses: {}", e);
+                self.ready = false;
             }
         } else {
             self.desc_table = GuestAddress(0);
@@ -351,6 +562,56 @@ impl Queue {
         min(self.size, self.max_size)
     }
 
+    /// Returns the state of the queue, its position being read from the used
+    /// ring in `mem`: a device works on its own copy of the queue, and the
+    /// requests it hasn't completed yet are run again once restored.
+    pub fn state(&self, mem: &GuestMemoryMmap) -> QueueState {
+        let mut next = self.next_used.0;
+        if self.ready {
+            match mem.read_obj::<u16>(self.used_ring.unchecked_add(2)) {
+                Ok(used_idx) => next = used_idx,
+                Err(e) => warn!("Failed to read the used ring index: {}", e),
+            }
+        }
+
+        QueueState {
+            size: self.size,
+            ready: self.ready,
+            vector: self.vector,
+            desc_table: self.desc_table.raw_value(),
+            avail_ring: self.avail_ring.raw_value(),
+            used_ring: self.used_ring.raw_value(),
+            next_avail: next,
+            next_used: next,
+        }
+    }
+
+    /// Restores a state returned by `state()`. The addresses of a ready
+    /// queue are already translated, and used as they are.
+    pub fn set_state(&mut self, state: &QueueState) -> Result<(), QueueConfigError> {
+        let mut queue = Queue {
+            max_size: self.max_size,
+            size: state.size,
+            ready: state.ready,
+            vector: state.vector,
+            desc_table: GuestAddress(state.desc_table),
+            avail_ring: GuestAddress(state.avail_ring),
+            used_ring: GuestAddress(state.used_ring),
+            next_avail: Wrapping(state.next_avail),
+            next_used: Wrapping(state.next_used),
+            dma_mapping: self.dma_mapping.take(),
+        };
+        if queue.ready {
+            if let Err(e) = queue.check_config() {
+                self.dma_mapping = queue.dma_mapping.take();
+                return Err(e);
+            }
+        }
+
+        *self = queue;
+        Ok(())
+    }
+
     /// Reset the queue to a state that is acceptable for a device reset
     pub fn reset(&mut self) {
         self.ready = false;
@@ -443,36 +704,69 @@ impl Queue {
             last_index: Wrapping(last_index),
             queue_size,
             next_avail: &mut self.next_avail,
-            iommu_mapping_cb: self.iommu_mapping_cb.clone(),
+            dma_mapping: self.dma_mapping.clone(),
         }
     }
 
     /// Puts an available descriptor head into the used ring for use by the guest.
     pub fn add_used(&mut self, mem: &GuestMemoryMmap, desc_index: u16, len: u32) {
-        if desc_index >= self.actual_size() {
-            error!(
-                "attempted to add out of bounds descriptor to used ring: {}",
-                desc_index
-            );
-            return;
-        }
+        self.add_used_batch(mem, &[(desc_index, len)]);
+    }
 
+    /// Puts several available descriptor heads, along with the number of
+    /// bytes written to each, into the used ring. The used ring index is
+    /// only updated once, so that the guest sees all of them at once.
+    pub fn add_used_batch(&mut self, mem: &GuestMemoryMmap, used: &[(u16, u32)]) {
         let used_ring = self.used_ring;
-        let next_used = u64::from(self.next_used.0 % self.actual_size());
-        let used_elem = used_ring.unchecked_add(4 + next_used * 8);
+        let used_idx = match mem.checked_offset(used_ring, 2) {
+            Some(idx) => idx,
+            None => {
+                error!("used ring index is out of bounds");
+                return;
+            }
+        };
 
-        // These writes can't fail as we are guaranteed to be within the descriptor ring.
-        mem.write_obj(u32::from(desc_index), used_elem).unwrap();
-        mem.write_obj(len as u32, used_elem.unchecked_add(4))
-            .unwrap();
+        let next_used = self.next_used;
+        for &(desc_index, len) in used {
+            if desc_index >= self.actual_size() {
+                error!(
+                    "attempted to add out of bounds descriptor to used ring: {}",
+                    desc_index
+                );
+                continue;
+            }
+
+            let slot = (self.next_used.0 % self.actual_size()) as usize;
+            let used_elem = match mem.checked_offset(used_ring, 4 + slot * 8) {
+                Some(elem) => elem,
+                None => {
+                    error!("used ring element {} is out of bounds", slot);
+                    break;
+                }
+            };
+
+            // The ring was validated when the queue was enabled, but the guest
+            // is free to unplug the memory behind it afterwards.
+            if let Err(e) = mem
+                .write_obj(u32::from(desc_index), used_elem)
+                .and_then(|_| mem.write_obj(len as u32, used_elem.unchecked_add(4)))
+            {
+                error!("Failed to write the used ring element: {}", e);
+                break;
+            }
 
-        self.next_used += Wrapping(1);
+            self.next_used += Wrapping(1);
+        }
+        if self.next_used == next_used {
+            return;
+        }
 
         // This fence ensures all descriptor writes are visible before the index update is.
         fence(Ordering::Release);
 
-        mem.write_obj(self.next_used.0 as u16, used_ring.unchecked_add(2))
-            .unwrap();
+        if let Err(e) = mem.write_obj(self.next_used.0 as u16, used_idx) {
+            error!("Failed to write the used ring index: {}", e);
+        }
     }
 
     /// Goes back one position in the available descriptor chain offered by the driver.
@@ -914,4 +1208,236 @@ pub(crate) mod tests {
         assert_eq!(x.id, 1);
         assert_eq!(x.len, 0x1000);
     }
+
+    #[test]
+    fn test_add_used_out_of_bounds_ring() {
+        let m = &GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = VirtQueue::new(GuestAddress(0), m, 16);
+
+        // The used ring ends up outside of guest memory.
+        let mut q = vq.create_queue();
+        q.used_ring = GuestAddress(0xfff0);
+        q.add_used(m, 1, 0x1000);
+        assert_eq!(q.next_used, Wrapping(0));
+
+        q.used_ring = GuestAddress(u64::max_value() - 1);
+        q.add_used(m, 1, 0x1000);
+        assert_eq!(q.next_used, Wrapping(0));
+    }
+
+    #[test]
+    fn test_iter_large_queue() {
+        let m = &GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = VirtQueue::new(GuestAddress(0), m, 16);
+
+        // The driver claims the largest possible queue, while the avail ring
+        // index points at the end of it.
+        let mut q = vq.create_queue();
+        q.max_size = 32768;
+        q.size = 32768;
+        q.next_avail = Wrapping(32767);
+        vq.avail.idx.set(32768);
+        assert!(q.iter(m).next().is_none());
+
+        // A zero sized queue has no descriptor to offer.
+        q.size = 0;
+        q.next_avail = Wrapping(0);
+        assert!(q.iter(m).next().is_none());
+    }
+
+    #[test]
+    fn test_desc_iter_self_loop() {
+        let m = &GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = VirtQueue::new(GuestAddress(0), m, 16);
+
+        // The descriptor links to itself.
+        vq.dtable[0].set(0x1000, 0x100, VIRTQ_DESC_F_NEXT, 0);
+
+        let c = DescriptorChain::checked_new(m, vq.start(), 16, 0, None).unwrap();
+        let mut iter = c.into_iter();
+        assert_eq!(iter.next().unwrap().index, 0);
+        assert!(iter.next().is_none());
+        assert!(iter.next().is_none());
+    }
+
+    #[test]
+    fn test_desc_iter_loop() {
+        let m = &GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = VirtQueue::new(GuestAddress(0), m, 16);
+
+        // 0 -> 1 -> 2 -> 1
+        vq.dtable[0].set(0x1000, 0x100, VIRTQ_DESC_F_NEXT, 1);
+        vq.dtable[1].set(0x2000, 0x100, VIRTQ_DESC_F_NEXT, 2);
+        vq.dtable[2].set(0x3000, 0x100, VIRTQ_DESC_F_NEXT, 1);
+
+        let c = DescriptorChain::checked_new(m, vq.start(), 16, 0, None).unwrap();
+        let indexes: Vec<u16> = c.into_iter().map(|d| d.index).collect();
+        assert_eq!(indexes, vec![0, 1, 2]);
+
+        // A well formed chain is followed up to its end.
+        vq.dtable[2].set(0x3000, 0x100, 0, 0);
+        let c = DescriptorChain::checked_new(m, vq.start(), 16, 0, None).unwrap();
+        assert_eq!(c.into_iter().count(), 3);
+    }
+
+    #[test]
+    fn test_desc_iter_too_long() {
+        let m = &GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = VirtQueue::new(GuestAddress(0), m, 16);
+
+        // Every descriptor of the table is chained, the last one linking
+        // back to the head.
+        for i in 0..16 {
+            vq.dtable[i].set(0x1000, 0x100, VIRTQ_DESC_F_NEXT, (i as u16 + 1) % 16);
+        }
+
+        let c = DescriptorChain::checked_new(m, vq.start(), 16, 0, None).unwrap();
+        assert_eq!(c.into_iter().count(), 16);
+    }
+
+    const IOVA_BASE: u64 = 0x1_0000_0000;
+
+    // Maps [IOVA_BASE, IOVA_BASE + 0x10000) onto the start of guest memory,
+    // recording the requested translations.
+    #[derive(Default)]
+    struct OffsetMapping {
+        calls: std::sync::Mutex<Vec<(u64, u64, DmaAccess)>>,
+    }
+
+    impl DmaMapping for OffsetMapping {
+        fn translate(&self, addr: u64, len: u64, access: DmaAccess) -> io::Result<u64> {
+            self.calls.lock().unwrap().push((addr, len, access));
+            if addr < IOVA_BASE || addr + len > IOVA_BASE + 0x10000 {
+                return Err(io::Error::new(io::ErrorKind::Other, "unmapped"));
+            }
+            Ok(addr - IOVA_BASE)
+        }
+    }
+
+    #[test]
+    fn test_dma_mapping_descriptor_chain() {
+        let m = &GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = VirtQueue::new(GuestAddress(0), m, 16);
+        let mapping = Arc::new(OffsetMapping::default());
+
+        vq.dtable[0].set(IOVA_BASE + 0x1000, 0x100, VIRTQ_DESC_F_NEXT, 1);
+        vq.dtable[1].set(IOVA_BASE + 0x2000, 0x200, VIRTQ_DESC_F_WRITE, 0);
+
+        let c = DescriptorChain::checked_new(m, vq.start(), 16, 0, Some(mapping.clone())).unwrap();
+        assert_eq!(c.addr, GuestAddress(0x1000));
+        let c = c.next_descriptor().unwrap();
+        assert_eq!(c.addr, GuestAddress(0x2000));
+        assert_eq!(
+            *mapping.calls.lock().unwrap(),
+            vec![
+                (IOVA_BASE + 0x1000, 0x100, DmaAccess::Read),
+                (IOVA_BASE + 0x2000, 0x200, DmaAccess::Write),
+            ]
+        );
+
+        // Without a mapping, the IOVA is used as a guest address.
+        assert!(DescriptorChain::checked_new(m, vq.start(), 16, 0, None).is_none());
+
+        // A failed translation invalidates the descriptor.
+        vq.dtable[0].addr.set(0x1000);
+        assert!(DescriptorChain::checked_new(m, vq.start(), 16, 0, Some(mapping)).is_none());
+    }
+
+    #[test]
+    fn test_dma_mapping_enable() {
+        let m = &GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = VirtQueue::new(GuestAddress(0), m, 16);
+        let mapping = Arc::new(OffsetMapping::default());
+
+        let mut q = vq.create_queue();
+        q.dma_mapping = Some(mapping.clone());
+        q.desc_table = GuestAddress(IOVA_BASE + vq.dtable_start().0);
+        q.avail_ring = GuestAddress(IOVA_BASE + vq.avail_start().0);
+        q.used_ring = GuestAddress(IOVA_BASE + vq.used_start().0);
+        q.enable(true);
+
+        assert!(q.ready);
+        assert_eq!(q.desc_table, vq.dtable_start());
+        assert_eq!(q.avail_ring, vq.avail_start());
+        assert_eq!(q.used_ring, vq.used_start());
+        assert!(q.is_valid(m));
+        assert_eq!(
+            *mapping.calls.lock().unwrap(),
+            vec![
+                (IOVA_BASE + vq.dtable_start().0, 16 * 16, DmaAccess::Read),
+                (IOVA_BASE + vq.avail_start().0, 6 + 2 * 16, DmaAccess::Read),
+                (IOVA_BASE + vq.used_start().0, 6 + 8 * 16, DmaAccess::Write),
+            ]
+        );
+
+        // The queue can't be used if its rings can't be translated.
+        let mut q = vq.create_queue();
+        q.dma_mapping = Some(mapping);
+        q.enable(true);
+        assert!(!q.ready);
+        assert!(!q.is_valid(m));
+    }
+
+    #[test]
+    fn test_set_size() {
+        let mut q = Queue::new(256);
+
+        assert_eq!(q.set_size(0), Err(QueueConfigError::InvalidSize(0)));
+        assert_eq!(q.set_size(100), Err(QueueConfigError::InvalidSize(100)));
+        assert_eq!(q.set_size(512), Err(QueueConfigError::InvalidSize(512)));
+        assert_eq!(q.size, 256);
+
+        assert_eq!(q.set_size(64), Ok(()));
+        assert_eq!(q.size, 64);
+        assert_eq!(q.actual_size(), 64);
+    }
+
+    #[test]
+    fn test_enable_checks_config() {
+        let m = &GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = VirtQueue::new(GuestAddress(0), m, 16);
+
+        let mut q = vq.create_queue();
+        q.ready = false;
+        q.enable(true);
+        assert!(q.ready);
+
+        // The size can still be set directly, bypassing set_size().
+        let mut q = vq.create_queue();
+        q.size = 0;
+        q.enable(true);
+        assert!(!q.ready);
+
+        let mut q = vq.create_queue();
+        q.size = 12;
+        q.enable(true);
+        assert!(!q.ready);
+
+        let mut q = vq.create_queue();
+        q.desc_table = GuestAddress(vq.dtable_start().0 + 8);
+        assert_eq!(
+            q.check_config(),
+            Err(QueueConfigError::MisalignedDescTable(q.desc_table))
+        );
+        q.enable(true);
+        assert!(!q.ready);
+
+        let mut q = vq.create_queue();
+        q.avail_ring = GuestAddress(vq.avail_start().0 + 1);
+        assert_eq!(
+            q.check_config(),
+            Err(QueueConfigError::MisalignedAvailRing(q.avail_ring))
+        );
+        q.enable(true);
+        assert!(!q.ready);
+
+        let mut q = vq.create_queue();
+        q.used_ring = GuestAddress(vq.used_start().0 + 2);
+        assert_eq!(
+            q.check_config(),
+            Err(QueueConfigError::MisalignedUsedRing(q.used_ring))
+        );
+        q.enable(true);
+        assert!(!q.ready);
+    }
 }
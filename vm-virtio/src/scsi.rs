@@ -0,0 +1,1138 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use super::Error as DeviceError;
+use super::{
+    ActivateError, ActivateResult, DescriptorChain, DeviceEventT, DiskFile, Queue, VirtioDevice,
+    VirtioDeviceType, SECTOR_SIZE, VIRTIO_F_IOMMU_PLATFORM, VIRTIO_F_VERSION_1,
+};
+use crate::descriptor_utils::Error as DescriptorError;
+use crate::{join_epoll_threads, Reader, VirtioInterrupt, VirtioInterruptType, Writer};
+use arc_swap::ArcSwap;
+use epoll;
+use libc::EFD_NONBLOCK;
+use std::cmp;
+use std::collections::BTreeMap;
+use std::convert::TryInto;
+use std::fmt;
+use std::io::{self, Seek, SeekFrom, Write};
+use std::ops::DerefMut;
+use std::os::unix::io::AsRawFd;
+use std::result;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use vm_device::{Migratable, MigratableError, Pausable, Snapshotable};
+use vm_memory::{ByteValued, GuestMemoryMmap};
+use vmm_sys_util::eventfd::EventFd;
+
+const QUEUE_SIZE: u16 = 256;
+const NUM_QUEUES: usize = 3;
+const QUEUE_SIZES: &[u16] = &[QUEUE_SIZE; NUM_QUEUES];
+
+const CONTROL_QUEUE: usize = 0;
+const EVENT_QUEUE: usize = 1;
+const REQUEST_QUEUE: usize = 2;
+
+// New descriptors are pending on the control queue.
+const CONTROL_QUEUE_EVENT: DeviceEventT = 0;
+// New descriptors are pending on the event queue.
+const EVENT_QUEUE_EVENT: DeviceEventT = 1;
+// New descriptors are pending on the request queue.
+const REQUEST_QUEUE_EVENT: DeviceEventT = 2;
+// The device has been dropped.
+const KILL_EVENT: DeviceEventT = 3;
+// The device should be paused.
+const PAUSE_EVENT: DeviceEventT = 4;
+
+// Default sizes from the virtio specification, which the request and
+// response layouts are built on.
+const CDB_SIZE: usize = 32;
+const SENSE_SIZE: usize = 96;
+// Size of struct virtio_scsi_event.
+const EVENT_INFO_SIZE: u32 = 16;
+
+/// Highest LUN that can be addressed with the single level LUN format.
+pub const MAX_LUN: u16 = 0x3fff;
+
+// Control queue request types.
+const VIRTIO_SCSI_T_TMF: u32 = 0;
+const VIRTIO_SCSI_T_AN_QUERY: u32 = 1;
+const VIRTIO_SCSI_T_AN_SUBSCRIBE: u32 = 2;
+
+// Response codes.
+const VIRTIO_SCSI_S_OK: u8 = 0;
+const VIRTIO_SCSI_S_OVERRUN: u8 = 1;
+const VIRTIO_SCSI_S_BAD_TARGET: u8 = 3;
+const VIRTIO_SCSI_S_FAILURE: u8 = 9;
+const VIRTIO_SCSI_S_FUNCTION_COMPLETE: u8 = 0;
+
+// SAM status codes.
+const SAM_STAT_GOOD: u8 = 0x00;
+const SAM_STAT_CHECK_CONDITION: u8 = 0x02;
+
+// Operation codes.
+const TEST_UNIT_READY: u8 = 0x00;
+const INQUIRY: u8 = 0x12;
+const READ_CAPACITY_10: u8 = 0x25;
+const READ_10: u8 = 0x28;
+const WRITE_10: u8 = 0x2a;
+const READ_16: u8 = 0x88;
+const WRITE_16: u8 = 0x8a;
+const SERVICE_ACTION_IN_16: u8 = 0x9e;
+const REPORT_LUNS: u8 = 0xa0;
+
+// SERVICE ACTION IN(16) service actions.
+const SAI_READ_CAPACITY_16: u8 = 0x10;
+
+// Sense keys.
+const MEDIUM_ERROR: u8 = 0x03;
+const ILLEGAL_REQUEST: u8 = 0x05;
+
+#[derive(Debug)]
+pub enum Error {
+    /// Guest gave us too few descriptors in a descriptor chain.
+    DescriptorChainTooShort,
+    /// Guest gave us a descriptor that was too short to use.
+    DescriptorLengthTooSmall,
+    /// Guest gave us an invalid descriptor chain.
+    DescriptorChain(DescriptorError),
+    /// Guest sent a control request of an unknown type.
+    UnsupportedControlRequest(u32),
+    /// Failed writing the response.
+    WriteResponse(io::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::DescriptorChainTooShort => {
+                write!(f, "guest gave us too few descriptors in a descriptor chain")
+            }
+            Error::DescriptorLengthTooSmall => {
+                write!(f, "guest gave us a descriptor that was too short to use")
+            }
+            Error::DescriptorChain(_) => write!(f, "guest gave us an invalid descriptor chain"),
+            Error::UnsupportedControlRequest(e) => {
+                write!(f, "guest sent a control request of an unknown type: {}", e)
+            }
+            Error::WriteResponse(_) => write!(f, "failed writing the response"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::DescriptorChain(e) => Some(e),
+            Error::WriteResponse(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C, packed)]
+pub struct VirtioScsiConfig {
+    pub num_queues: u32,
+    pub seg_max: u32,
+    pub max_sectors: u32,
+    pub cmd_per_lun: u32,
+    pub event_info_size: u32,
+    pub sense_size: u32,
+    pub cdb_size: u32,
+    pub max_channel: u16,
+    pub max_target: u16,
+    pub max_lun: u32,
+}
+
+unsafe impl ByteValued for VirtioScsiConfig {}
+
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C, packed)]
+struct VirtioScsiCmdReq {
+    lun: [u8; 8],
+    tag: u64,
+    task_attr: u8,
+    prio: u8,
+    crn: u8,
+    cdb: [u8; CDB_SIZE],
+}
+
+unsafe impl ByteValued for VirtioScsiCmdReq {}
+
+// The sense buffer following this header is written separately.
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C, packed)]
+struct VirtioScsiCmdRespHeader {
+    sense_len: u32,
+    resid: u32,
+    status_qualifier: u16,
+    status: u8,
+    response: u8,
+}
+
+unsafe impl ByteValued for VirtioScsiCmdRespHeader {}
+
+const CMD_RESP_SIZE: usize = std::mem::size_of::<VirtioScsiCmdRespHeader>() + SENSE_SIZE;
+
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C, packed)]
+struct VirtioScsiCtrlAnResp {
+    event_actual: u32,
+    response: u8,
+}
+
+unsafe impl ByteValued for VirtioScsiCtrlAnResp {}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+struct Sense {
+    key: u8,
+    asc: u8,
+    ascq: u8,
+}
+
+const SENSE_INVALID_OPCODE: Sense = Sense {
+    key: ILLEGAL_REQUEST,
+    asc: 0x20,
+    ascq: 0x00,
+};
+const SENSE_LBA_OUT_OF_RANGE: Sense = Sense {
+    key: ILLEGAL_REQUEST,
+    asc: 0x21,
+    ascq: 0x00,
+};
+const SENSE_INVALID_FIELD_IN_CDB: Sense = Sense {
+    key: ILLEGAL_REQUEST,
+    asc: 0x24,
+    ascq: 0x00,
+};
+const SENSE_LUN_NOT_SUPPORTED: Sense = Sense {
+    key: ILLEGAL_REQUEST,
+    asc: 0x25,
+    ascq: 0x00,
+};
+const SENSE_WRITE_ERROR: Sense = Sense {
+    key: MEDIUM_ERROR,
+    asc: 0x0c,
+    ascq: 0x00,
+};
+const SENSE_UNRECOVERED_READ_ERROR: Sense = Sense {
+    key: MEDIUM_ERROR,
+    asc: 0x11,
+    ascq: 0x00,
+};
+
+impl Sense {
+    /// Fills `buf` with fixed format sense data, returning its length.
+    fn write_fixed(&self, buf: &mut [u8]) -> usize {
+        buf[0] = 0x70;
+        buf[2] = self.key;
+        // Additional sense length.
+        buf[7] = 10;
+        buf[12] = self.asc;
+        buf[13] = self.ascq;
+        18
+    }
+}
+
+#[derive(Debug)]
+enum CmdError {
+    CheckCondition(Sense),
+    Overrun,
+    Failure(io::Error),
+}
+
+impl fmt::Display for CmdError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CmdError::CheckCondition(e) => write!(f, "check condition, sense {:?}", e),
+            CmdError::Overrun => write!(f, "data overrun"),
+            CmdError::Failure(_) => write!(f, "I/O failure"),
+        }
+    }
+}
+
+impl std::error::Error for CmdError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            CmdError::Failure(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+fn be16(b: &[u8]) -> u16 {
+    u16::from_be_bytes(b[..2].try_into().unwrap())
+}
+
+fn be32(b: &[u8]) -> u32 {
+    u32::from_be_bytes(b[..4].try_into().unwrap())
+}
+
+fn be64(b: &[u8]) -> u64 {
+    u64::from_be_bytes(b[..8].try_into().unwrap())
+}
+
+// Only single level LUNs behind channel 0, target 0 are addressable, using
+// the format drivers put in the request: 1, target, lun >> 8 | 0x40, lun.
+fn decode_lun(lun: &[u8; 8]) -> Option<u16> {
+    if lun[0] != 1 || lun[1] != 0 || lun[4..].iter().any(|b| *b != 0) {
+        return None;
+    }
+    Some((u16::from(lun[2] & 0x3f) << 8) | u16::from(lun[3]))
+}
+
+// Copies `buf` to the guest, truncated to the allocation length.
+fn write_data_in(
+    data_in: &mut Writer,
+    buf: &[u8],
+    alloc_len: usize,
+) -> result::Result<(), CmdError> {
+    let len = cmp::min(cmp::min(buf.len(), alloc_len), data_in.available_bytes());
+    data_in.write_all(&buf[..len]).map_err(CmdError::Failure)
+}
+
+#[derive(Clone)]
+struct Lun<T: DiskFile> {
+    disk: Arc<Mutex<T>>,
+    nsectors: u64,
+}
+
+impl<T: DiskFile> Lun<T> {
+    fn read(&self, lba: u64, nblocks: u64, data_in: &mut Writer) -> result::Result<(), CmdError> {
+        let len = self.check_range(lba, nblocks, data_in.available_bytes())?;
+        let mut disk = self.disk.lock().unwrap();
+        disk.seek(SeekFrom::Start(lba * SECTOR_SIZE))
+            .and_then(|_| data_in.write_all_from(disk.deref_mut(), len))
+            .map_err(|e| {
+                error!("Failed to read from SCSI disk: {:?}", e);
+                CmdError::CheckCondition(SENSE_UNRECOVERED_READ_ERROR)
+            })
+    }
+
+    fn write(&self, lba: u64, nblocks: u64, data_out: &mut Reader) -> result::Result<(), CmdError> {
+        let len = self.check_range(lba, nblocks, data_out.available_bytes())?;
+        let mut disk = self.disk.lock().unwrap();
+        disk.seek(SeekFrom::Start(lba * SECTOR_SIZE))
+            .and_then(|_| data_out.read_exact_to(disk.deref_mut(), len))
+            .map_err(|e| {
+                error!("Failed to write to SCSI disk: {:?}", e);
+                CmdError::CheckCondition(SENSE_WRITE_ERROR)
+            })
+    }
+
+    // Returns the length of the transfer, in bytes.
+    fn check_range(
+        &self,
+        lba: u64,
+        nblocks: u64,
+        buf_len: usize,
+    ) -> result::Result<usize, CmdError> {
+        match lba.checked_add(nblocks) {
+            Some(end) if end <= self.nsectors => {}
+            _ => return Err(CmdError::CheckCondition(SENSE_LBA_OUT_OF_RANGE)),
+        }
+        let len = (nblocks * SECTOR_SIZE) as usize;
+        if len > buf_len {
+            return Err(CmdError::Overrun);
+        }
+        Ok(len)
+    }
+
+    fn read_capacity_10(&self, data_in: &mut Writer) -> result::Result<(), CmdError> {
+        let last_lba = cmp::min(self.nsectors.saturating_sub(1), u64::from(u32::max_value()));
+        let mut buf = [0u8; 8];
+        buf[0..4].copy_from_slice(&(last_lba as u32).to_be_bytes());
+        buf[4..8].copy_from_slice(&(SECTOR_SIZE as u32).to_be_bytes());
+        write_data_in(data_in, &buf, buf.len())
+    }
+
+    fn read_capacity_16(&self, cdb: &[u8], data_in: &mut Writer) -> result::Result<(), CmdError> {
+        let mut buf = [0u8; 32];
+        buf[0..8].copy_from_slice(&self.nsectors.saturating_sub(1).to_be_bytes());
+        buf[8..12].copy_from_slice(&(SECTOR_SIZE as u32).to_be_bytes());
+        write_data_in(data_in, &buf, be32(&cdb[10..]) as usize)
+    }
+}
+
+/// The single target of the host bus adapter, holding every LUN.
+#[derive(Clone)]
+struct Target<T: DiskFile> {
+    luns: BTreeMap<u16, Lun<T>>,
+}
+
+impl<T: DiskFile> Target<T> {
+    fn handle_cmd(&self, avail_desc: DescriptorChain) -> result::Result<u32, Error> {
+        let mut reader = Reader::new(avail_desc.clone()).map_err(Error::DescriptorChain)?;
+        let mut writer = Writer::new(avail_desc).map_err(Error::DescriptorChain)?;
+
+        let req: VirtioScsiCmdReq = reader
+            .read_obj()
+            .map_err(|_| Error::DescriptorChainTooShort)?;
+        if writer.available_bytes() < CMD_RESP_SIZE {
+            return Err(Error::DescriptorLengthTooSmall);
+        }
+        let mut data_in = writer
+            .split_at(CMD_RESP_SIZE)
+            .map_err(Error::DescriptorChain)?;
+
+        let mut resp = VirtioScsiCmdRespHeader::default();
+        let mut sense = [0u8; SENSE_SIZE];
+        match decode_lun(&req.lun) {
+            Some(lun) => match self.execute_cdb(lun, &req.cdb, &mut reader, &mut data_in) {
+                Ok(()) => resp.status = SAM_STAT_GOOD,
+                Err(CmdError::CheckCondition(s)) => {
+                    resp.status = SAM_STAT_CHECK_CONDITION;
+                    resp.sense_len = s.write_fixed(&mut sense) as u32;
+                }
+                Err(CmdError::Overrun) => resp.response = VIRTIO_SCSI_S_OVERRUN,
+                Err(CmdError::Failure(e)) => {
+                    error!("Failed to execute SCSI command: {:?}", e);
+                    resp.response = VIRTIO_SCSI_S_FAILURE;
+                }
+            },
+            None => resp.response = VIRTIO_SCSI_S_BAD_TARGET,
+        }
+        // A command transfers data in a single direction, so only one of
+        // these can be non zero.
+        resp.resid = (reader.available_bytes() + data_in.available_bytes()) as u32;
+
+        writer.write_obj(resp).map_err(Error::WriteResponse)?;
+        writer.write_all(&sense).map_err(Error::WriteResponse)?;
+
+        Ok((writer.bytes_written() + data_in.bytes_written()) as u32)
+    }
+
+    fn execute_cdb(
+        &self,
+        lun: u16,
+        cdb: &[u8],
+        data_out: &mut Reader,
+        data_in: &mut Writer,
+    ) -> result::Result<(), CmdError> {
+        // The guest must be able to discover which LUNs exist, no matter
+        // which one it addresses.
+        let disk = match (cdb[0], self.luns.get(&lun)) {
+            (REPORT_LUNS, _) => return self.report_luns(cdb, data_in),
+            (INQUIRY, None) => return inquiry(cdb, false, data_in),
+            (_, None) => return Err(CmdError::CheckCondition(SENSE_LUN_NOT_SUPPORTED)),
+            (_, Some(disk)) => disk,
+        };
+
+        match cdb[0] {
+            TEST_UNIT_READY => Ok(()),
+            INQUIRY => inquiry(cdb, true, data_in),
+            READ_CAPACITY_10 => disk.read_capacity_10(data_in),
+            SERVICE_ACTION_IN_16 if cdb[1] & 0x1f == SAI_READ_CAPACITY_16 => {
+                disk.read_capacity_16(cdb, data_in)
+            }
+            READ_10 => disk.read(
+                u64::from(be32(&cdb[2..])),
+                u64::from(be16(&cdb[7..])),
+                data_in,
+            ),
+            WRITE_10 => disk.write(
+                u64::from(be32(&cdb[2..])),
+                u64::from(be16(&cdb[7..])),
+                data_out,
+            ),
+            READ_16 => disk.read(be64(&cdb[2..]), u64::from(be32(&cdb[10..])), data_in),
+            WRITE_16 => disk.write(be64(&cdb[2..]), u64::from(be32(&cdb[10..])), data_out),
+            op => {
+                debug!("Unsupported SCSI command 0x{:x}", op);
+                Err(CmdError::CheckCondition(SENSE_INVALID_OPCODE))
+            }
+        }
+    }
+
+    fn report_luns(&self, cdb: &[u8], data_in: &mut Writer) -> result::Result<(), CmdError> {
+        let mut buf = vec![0u8; 8];
+        buf[0..4].copy_from_slice(&((self.luns.len() * 8) as u32).to_be_bytes());
+        for lun in self.luns.keys() {
+            let mut entry = [0u8; 8];
+            // Use the peripheral device addressing method when possible,
+            // and the flat space one otherwise.
+            if *lun > 0xff {
+                entry[0] = 0x40 | (lun >> 8) as u8;
+            }
+            entry[1] = *lun as u8;
+            buf.extend_from_slice(&entry);
+        }
+        write_data_in(data_in, &buf, be32(&cdb[6..]) as usize)
+    }
+
+    fn handle_ctrl(&self, avail_desc: DescriptorChain) -> result::Result<u32, Error> {
+        let mut reader = Reader::new(avail_desc.clone()).map_err(Error::DescriptorChain)?;
+        let mut writer = Writer::new(avail_desc).map_err(Error::DescriptorChain)?;
+
+        let req_type: u32 = reader
+            .read_obj()
+            .map_err(|_| Error::DescriptorChainTooShort)?;
+        let res = match req_type {
+            // Commands complete synchronously, hence there is never anything
+            // left to abort or reset.
+            VIRTIO_SCSI_T_TMF => writer.write_obj(VIRTIO_SCSI_S_FUNCTION_COMPLETE),
+            // No asynchronous notification is supported.
+            VIRTIO_SCSI_T_AN_QUERY | VIRTIO_SCSI_T_AN_SUBSCRIBE => {
+                writer.write_obj(VirtioScsiCtrlAnResp {
+                    event_actual: 0,
+                    response: VIRTIO_SCSI_S_OK,
+                })
+            }
+            t => return Err(Error::UnsupportedControlRequest(t)),
+        };
+        res.map_err(Error::WriteResponse)?;
+
+        Ok(writer.bytes_written() as u32)
+    }
+}
+
+fn inquiry(cdb: &[u8], present: bool, data_in: &mut Writer) -> result::Result<(), CmdError> {
+    // Direct access block device, or no device at all behind this LUN.
+    let peripheral = if present { 0x00 } else { 0x7f };
+    let evpd = cdb[1] & 0x1 != 0;
+    let page = cdb[2];
+    let alloc_len = be16(&cdb[3..]) as usize;
+
+    if evpd {
+        if page != 0x00 {
+            return Err(CmdError::CheckCondition(SENSE_INVALID_FIELD_IN_CDB));
+        }
+        // Supported VPD pages, which is only this one.
+        return write_data_in(data_in, &[peripheral, 0x00, 0x00, 0x01, 0x00], alloc_len);
+    }
+    if page != 0 {
+        return Err(CmdError::CheckCondition(SENSE_INVALID_FIELD_IN_CDB));
+    }
+
+    let mut buf = [0u8; 36];
+    buf[0] = peripheral;
+    // SPC-3, with the standard response data format.
+    buf[2] = 0x05;
+    buf[3] = 0x02;
+    buf[4] = (buf.len() - 5) as u8;
+    // Command queuing.
+    buf[7] = 0x02;
+    buf[8..16].copy_from_slice(b"CLOUDHV ");
+    buf[16..32].copy_from_slice(b"VIRTUAL DISK    ");
+    buf[32..36].copy_from_slice(b"1.0 ");
+    write_data_in(data_in, &buf, alloc_len)
+}
+
+struct ScsiEpollHandler<T: DiskFile> {
+    queues: Vec<Queue>,
+    mem: Arc<ArcSwap<GuestMemoryMmap>>,
+    target: Target<T>,
+    interrupt_cb: Arc<dyn VirtioInterrupt>,
+    queue_evts: Vec<EventFd>,
+    kill_evt: EventFd,
+    pause_evt: EventFd,
+}
+
+impl<T: DiskFile> ScsiEpollHandler<T> {
+    fn process_queue(&mut self, queue_index: usize) -> bool {
+        let queue = &mut self.queues[queue_index];
+
+        let mut used_desc_heads = Vec::new();
+        let mem = self.mem.load();
+        for avail_desc in queue.iter(&mem) {
+            let index = avail_desc.index;
+            let result = if queue_index == CONTROL_QUEUE {
+                self.target.handle_ctrl(avail_desc)
+            } else {
+                self.target.handle_cmd(avail_desc)
+            };
+            let len = result.unwrap_or_else(|e| {
+                error!("Failed to process SCSI request: {:?}", e);
+                0
+            });
+            used_desc_heads.push((index, len));
+        }
+
+        for &(desc_index, len) in used_desc_heads.iter() {
+            queue.add_used(&mem, desc_index, len);
+        }
+        !used_desc_heads.is_empty()
+    }
+
+    fn signal_used_queue(&self, queue_index: usize) -> result::Result<(), DeviceError> {
+        self.interrupt_cb
+            .trigger(&VirtioInterruptType::Queue, Some(&self.queues[queue_index]))
+            .map_err(|e| {
+                error!("Failed to signal used queue: {:?}", e);
+                DeviceError::FailedSignalingUsedQueue(e)
+            })
+    }
+
+    fn run(&mut self, paused: Arc<AtomicBool>) -> result::Result<(), DeviceError> {
+        // Create the epoll file descriptor
+        let epoll_fd = epoll::create(true).map_err(DeviceError::EpollCreateFd)?;
+
+        // Add events
+        for (queue_evt, event) in self.queue_evts.iter().zip(&[
+            CONTROL_QUEUE_EVENT,
+            EVENT_QUEUE_EVENT,
+            REQUEST_QUEUE_EVENT,
+        ]) {
+            epoll::ctl(
+                epoll_fd,
+                epoll::ControlOptions::EPOLL_CTL_ADD,
+                queue_evt.as_raw_fd(),
+                epoll::Event::new(epoll::Events::EPOLLIN, u64::from(*event)),
+            )
+            .map_err(DeviceError::EpollCtl)?;
+        }
+        epoll::ctl(
+            epoll_fd,
+            epoll::ControlOptions::EPOLL_CTL_ADD,
+            self.kill_evt.as_raw_fd(),
+            epoll::Event::new(epoll::Events::EPOLLIN, u64::from(KILL_EVENT)),
+        )
+        .map_err(DeviceError::EpollCtl)?;
+        epoll::ctl(
+            epoll_fd,
+            epoll::ControlOptions::EPOLL_CTL_ADD,
+            self.pause_evt.as_raw_fd(),
+            epoll::Event::new(epoll::Events::EPOLLIN, u64::from(PAUSE_EVENT)),
+        )
+        .map_err(DeviceError::EpollCtl)?;
+
+        const EPOLL_EVENTS_LEN: usize = 100;
+        let mut events = vec![epoll::Event::new(epoll::Events::empty(), 0); EPOLL_EVENTS_LEN];
+
+        'epoll: loop {
+            let num_events = match epoll::wait(epoll_fd, -1, &mut events[..]) {
+                Ok(res) => res,
+                Err(e) => {
+                    if e.kind() == io::ErrorKind::Interrupted {
+                        // It's well defined from the epoll_wait() syscall
+                        // documentation that the epoll loop can be interrupted
+                        // before any of the requested events occurred or the
+                        // timeout expired. In both those cases, epoll_wait()
+                        // returns an error of type EINTR, but this should not
+                        // be considered as a regular error. Instead it is more
+                        // appropriate to retry, by calling into epoll_wait().
+                        continue;
+                    }
+                    return Err(DeviceError::EpollWait(e));
+                }
+            };
+
+            for event in events.iter().take(num_events) {
+                let ev_type = event.data as u16;
+
+                match ev_type {
+                    CONTROL_QUEUE_EVENT | REQUEST_QUEUE_EVENT => {
+                        let queue_index = if ev_type == CONTROL_QUEUE_EVENT {
+                            CONTROL_QUEUE
+                        } else {
+                            REQUEST_QUEUE
+                        };
+                        if let Err(e) = self.queue_evts[queue_index].read() {
+                            error!("Failed to get queue event: {:?}", e);
+                            break 'epoll;
+                        } else if self.process_queue(queue_index) {
+                            if let Err(e) = self.signal_used_queue(queue_index) {
+                                error!("Failed to signal used queue: {:?}", e);
+                                break 'epoll;
+                            }
+                        }
+                    }
+                    EVENT_QUEUE_EVENT => {
+                        // The buffers are kept around, as no event is ever
+                        // reported to the driver.
+                        if let Err(e) = self.queue_evts[EVENT_QUEUE].read() {
+                            error!("Failed to get queue event: {:?}", e);
+                            break 'epoll;
+                        }
+                    }
+                    KILL_EVENT => {
+                        debug!("KILL_EVENT received, stopping epoll loop");
+                        break 'epoll;
+                    }
+                    PAUSE_EVENT => {
+                        debug!("PAUSE_EVENT received, pausing virtio-scsi epoll loop");
+                        // We loop here to handle spurious park() returns.
+                        // Until we have not resumed, the paused boolean will
+                        // be true.
+                        while paused.load(Ordering::SeqCst) {
+                            thread::park();
+                        }
+                    }
+                    _ => {
+                        error!("Unknown event for virtio-scsi");
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Virtio SCSI host bus adapter, exposing a set of disk files as the LUNs of
+/// a single target.
+pub struct Scsi<T: DiskFile> {
+    kill_evt: Option<EventFd>,
+    pause_evt: Option<EventFd>,
+    target: Target<T>,
+    avail_features: u64,
+    acked_features: u64,
+    config: VirtioScsiConfig,
+    queue_evts: Option<Vec<EventFd>>,
+    interrupt_cb: Option<Arc<dyn VirtioInterrupt>>,
+    epoll_threads: Option<Vec<thread::JoinHandle<result::Result<(), DeviceError>>>>,
+    paused: Arc<AtomicBool>,
+}
+
+impl<T: DiskFile> Scsi<T> {
+    /// Create a new virtio SCSI device exposing each disk file at the LUN it
+    /// is associated with.
+    pub fn new(disks: Vec<(u16, T)>, iommu: bool) -> io::Result<Scsi<T>> {
+        let mut luns = BTreeMap::new();
+        for (lun, mut disk) in disks {
+            if lun > MAX_LUN || luns.contains_key(&lun) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("Invalid or duplicate LUN {}", lun),
+                ));
+            }
+            let nsectors = disk.seek(SeekFrom::End(0))? / SECTOR_SIZE;
+            luns.insert(
+                lun,
+                Lun {
+                    disk: Arc::new(Mutex::new(disk)),
+                    nsectors,
+                },
+            );
+        }
+
+        let mut avail_features = 1u64 << VIRTIO_F_VERSION_1;
+
+        if iommu {
+            avail_features |= 1u64 << VIRTIO_F_IOMMU_PLATFORM;
+        }
+
+        let config = VirtioScsiConfig {
+            num_queues: (NUM_QUEUES - REQUEST_QUEUE) as u32,
+            seg_max: u32::from(QUEUE_SIZE) - 2,
+            max_sectors: 0xffff,
+            cmd_per_lun: u32::from(QUEUE_SIZE),
+            event_info_size: EVENT_INFO_SIZE,
+            sense_size: SENSE_SIZE as u32,
+            cdb_size: CDB_SIZE as u32,
+            max_channel: 0,
+            max_target: 0,
+            max_lun: u32::from(MAX_LUN),
+        };
+
+        Ok(Scsi {
+            kill_evt: None,
+            pause_evt: None,
+            target: Target { luns },
+            avail_features,
+            acked_features: 0u64,
+            config,
+            queue_evts: None,
+            interrupt_cb: None,
+            epoll_threads: None,
+            paused: Arc::new(AtomicBool::new(false)),
+        })
+    }
+}
+
+impl<T: DiskFile> Drop for Scsi<T> {
+    fn drop(&mut self) {
+        if let Some(kill_evt) = self.kill_evt.take() {
+            // Ignore the result because there is nothing we can do about it.
+            let _ = kill_evt.write(1);
+        }
+    }
+}
+
+impl<T: 'static + DiskFile + Send> VirtioDevice for Scsi<T> {
+    fn device_type(&self) -> u32 {
+        VirtioDeviceType::TYPE_SCSI as u32
+    }
+
+    fn queue_max_sizes(&self) -> &[u16] {
+        QUEUE_SIZES
+    }
+
+    fn features(&self) -> u64 {
+        self.avail_features
+    }
+
+    fn ack_features(&mut self, value: u64) {
+        let mut v = value;
+        // Check if the guest is ACK'ing a feature that we didn't claim to have.
+        let unrequested_features = v & !self.avail_features;
+        if unrequested_features != 0 {
+            warn!("Received acknowledge request for unknown feature.");
+
+            // Don't count these features as acked.
+            v &= !unrequested_features;
+        }
+        self.acked_features |= v;
+    }
+
+    fn read_config(&self, offset: u64, mut data: &mut [u8]) {
+        let config_slice = self.config.as_slice();
+        let config_len = config_slice.len() as u64;
+        if offset >= config_len {
+            error!("Failed to read config space");
+            return;
+        }
+        if let Some(end) = offset.checked_add(data.len() as u64) {
+            // This write can't fail, offset and end are checked against config_len.
+            data.write_all(&config_slice[offset as usize..cmp::min(end, config_len) as usize])
+                .unwrap();
+        }
+    }
+
+    fn write_config(&mut self, offset: u64, data: &[u8]) {
+        let mut config = self.config;
+        let config_slice = config.as_mut_slice();
+        let data_len = data.len() as u64;
+        let config_len = config_slice.len() as u64;
+        if offset + data_len > config_len {
+            error!("Failed to write config space");
+            return;
+        }
+        let (_, right) = config_slice.split_at_mut(offset as usize);
+        right[..data.len()].copy_from_slice(&data[..]);
+
+        // The driver may only write the sense and CDB sizes, which are
+        // hardcoded in the request layout.
+        if config.as_slice() != self.config.as_slice() {
+            warn!("Ignoring virtio-scsi config space change");
+        }
+    }
+
+    fn activate(
+        &mut self,
+        mem: Arc<ArcSwap<GuestMemoryMmap>>,
+        interrupt_cb: Arc<dyn VirtioInterrupt>,
+        queues: Vec<Queue>,
+        queue_evts: Vec<EventFd>,
+    ) -> ActivateResult {
+        if queues.len() != NUM_QUEUES || queue_evts.len() != NUM_QUEUES {
+            error!(
+                "Cannot perform activate. Expected {} queue(s), got {}",
+                NUM_QUEUES,
+                queues.len()
+            );
+            return Err(ActivateError::BadActivate);
+        }
+
+        let (self_kill_evt, kill_evt) = EventFd::new(EFD_NONBLOCK)
+            .and_then(|e| Ok((e.try_clone()?, e)))
+            .map_err(|e| {
+                error!("failed creating kill EventFd pair: {}", e);
+                ActivateError::BadActivate
+            })?;
+        self.kill_evt = Some(self_kill_evt);
+
+        let (self_pause_evt, pause_evt) = EventFd::new(EFD_NONBLOCK)
+            .and_then(|e| Ok((e.try_clone()?, e)))
+            .map_err(|e| {
+                error!("failed creating pause EventFd pair: {}", e);
+                ActivateError::BadActivate
+            })?;
+        self.pause_evt = Some(self_pause_evt);
+
+        // Save the interrupt EventFD as we need to return it on reset
+        // but clone it to pass into the thread.
+        self.interrupt_cb = Some(interrupt_cb.clone());
+
+        let mut tmp_queue_evts: Vec<EventFd> = Vec::new();
+        for queue_evt in queue_evts.iter() {
+            // Save the queue EventFD as we need to return it on reset
+            // but clone it to pass into the thread.
+            tmp_queue_evts.push(queue_evt.try_clone().map_err(|e| {
+                error!("failed to clone queue EventFd: {}", e);
+                ActivateError::BadActivate
+            })?);
+        }
+        self.queue_evts = Some(tmp_queue_evts);
+
+        let mut handler = ScsiEpollHandler {
+            queues,
+            mem,
+            target: self.target.clone(),
+            interrupt_cb,
+            queue_evts,
+            kill_evt,
+            pause_evt,
+        };
+
+        let paused = self.paused.clone();
+        let mut epoll_threads = Vec::new();
+        thread::Builder::new()
+            .name("virtio_scsi".to_string())
+            .spawn(move || handler.run(paused))
+            .map(|thread| epoll_threads.push(thread))
+            .map_err(|e| {
+                error!("failed to clone the virtio-scsi epoll thread: {}", e);
+                ActivateError::BadActivate
+            })?;
+
+        self.epoll_threads = Some(epoll_threads);
+
+        Ok(())
+    }
+
+    fn reset(&mut self) -> Option<(Arc<dyn VirtioInterrupt>, Vec<EventFd>)> {
+        // We first must resume the virtio thread if it was paused.
+        if self.pause_evt.take().is_some() {
+            self.resume().ok()?;
+        }
+
+        if let Some(kill_evt) = self.kill_evt.take() {
+            // Ignore the result because there is nothing we can do about it.
+            let _ = kill_evt.write(1);
+        }
+        join_epoll_threads(self.epoll_threads.take());
+
+        self.acked_features = 0;
+
+        // Return the interrupt and queue EventFDs
+        Some((
+            self.interrupt_cb.take().unwrap(),
+            self.queue_evts.take().unwrap(),
+        ))
+    }
+
+    fn ready_to_remove(&self) -> bool {
+        // The epoll thread holds a reference to each disk until it exits.
+        self.target
+            .luns
+            .values()
+            .all(|lun| Arc::strong_count(&lun.disk) == 1)
+    }
+}
+
+virtio_pausable!(Scsi, T: 'static + DiskFile + Send);
+impl<T: 'static + DiskFile + Send> Snapshotable for Scsi<T> {}
+impl<T: 'static + DiskFile + Send> Migratable for Scsi<T> {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{create_guest_memory, Buffer, CountingInterrupt, VirtqueueBuilder};
+    use std::io::Cursor;
+    use vm_memory::{Address, Bytes, GuestAddress};
+
+    const REQ_ADDR: GuestAddress = GuestAddress(0x8000);
+    const DATA_OUT_ADDR: GuestAddress = GuestAddress(0x9000);
+    const RESP_ADDR: GuestAddress = GuestAddress(0xa000);
+    const DATA_IN_ADDR: GuestAddress = GuestAddress(0xb000);
+
+    fn cmd_req(lun: u16, cdb: &[u8]) -> VirtioScsiCmdReq {
+        let mut req = VirtioScsiCmdReq::default();
+        req.lun = [1, 0, (lun >> 8) as u8 | 0x40, lun as u8, 0, 0, 0, 0];
+        req.cdb[..cdb.len()].copy_from_slice(cdb);
+        req
+    }
+
+    fn create_handler(
+        mem: &GuestMemoryMmap,
+        queue: Queue,
+        luns: &[(u16, usize)],
+    ) -> ScsiEpollHandler<Cursor<Vec<u8>>> {
+        let disks = luns
+            .iter()
+            .map(|(lun, size)| (*lun, Cursor::new(vec![0u8; *size])))
+            .collect();
+        let scsi = Scsi::new(disks, false).unwrap();
+
+        ScsiEpollHandler {
+            queues: vec![Queue::new(QUEUE_SIZE), Queue::new(QUEUE_SIZE), queue],
+            mem: Arc::new(ArcSwap::new(Arc::new(mem.clone()))),
+            target: scsi.target.clone(),
+            interrupt_cb: Arc::new(CountingInterrupt::default()),
+            queue_evts: Vec::new(),
+            kill_evt: EventFd::new(EFD_NONBLOCK).unwrap(),
+            pause_evt: EventFd::new(EFD_NONBLOCK).unwrap(),
+        }
+    }
+
+    #[test]
+    fn test_read_write() {
+        let mem = create_guest_memory(0x10000);
+        let mut vq = VirtqueueBuilder::new(&mem, QUEUE_SIZE).build();
+        let mut handler = create_handler(&mem, vq.create_queue(), &[(0, 0x1000), (3, 0x1000)]);
+
+        // WRITE(10) of two sectors at LBA 1 of LUN 3.
+        let data = [0xa5u8; 0x400];
+        mem.write_obj(cmd_req(3, &[WRITE_10, 0, 0, 0, 0, 1, 0, 0, 2]), REQ_ADDR)
+            .unwrap();
+        mem.write_slice(&data, DATA_OUT_ADDR).unwrap();
+        vq.add_chain(&[
+            Buffer::readable(REQ_ADDR, 51),
+            Buffer::readable(DATA_OUT_ADDR, 0x400),
+            Buffer::writable(RESP_ADDR, CMD_RESP_SIZE as u32),
+        ]);
+        assert!(handler.process_queue(REQUEST_QUEUE));
+        let resp: VirtioScsiCmdRespHeader = mem.read_obj(RESP_ADDR).unwrap();
+        assert_eq!(resp.response, VIRTIO_SCSI_S_OK);
+        assert_eq!(resp.status, SAM_STAT_GOOD);
+
+        // READ(16) of the same sectors, into a larger buffer.
+        let mut cdb = [0u8; 16];
+        cdb[0] = READ_16;
+        cdb[9] = 1;
+        cdb[13] = 2;
+        mem.write_obj(cmd_req(3, &cdb), REQ_ADDR).unwrap();
+        vq.add_chain(&[
+            Buffer::readable(REQ_ADDR, 51),
+            Buffer::writable(RESP_ADDR, CMD_RESP_SIZE as u32),
+            Buffer::writable(DATA_IN_ADDR, 0x600),
+        ]);
+        assert!(handler.process_queue(REQUEST_QUEUE));
+        assert_eq!(vq.used_elem(1).1, (CMD_RESP_SIZE + 0x400) as u32);
+        let resp: VirtioScsiCmdRespHeader = mem.read_obj(RESP_ADDR).unwrap();
+        assert_eq!(resp.status, SAM_STAT_GOOD);
+        assert_eq!({ resp.resid }, 0x200);
+        let mut buf = [0u8; 0x400];
+        mem.read_slice(&mut buf, DATA_IN_ADDR).unwrap();
+        assert_eq!(buf[..], data[..]);
+
+        // LUN 0 is backed by a different disk.
+        mem.write_obj(cmd_req(0, &cdb), REQ_ADDR).unwrap();
+        vq.add_chain(&[
+            Buffer::readable(REQ_ADDR, 51),
+            Buffer::writable(RESP_ADDR, CMD_RESP_SIZE as u32),
+            Buffer::writable(DATA_IN_ADDR, 0x400),
+        ]);
+        assert!(handler.process_queue(REQUEST_QUEUE));
+        mem.read_slice(&mut buf, DATA_IN_ADDR).unwrap();
+        assert_eq!(buf[..], [0u8; 0x400][..]);
+
+        // Reading past the end of the disk.
+        mem.write_obj(cmd_req(0, &[READ_10, 0, 0, 0, 0, 7, 0, 0, 2]), REQ_ADDR)
+            .unwrap();
+        vq.add_chain(&[
+            Buffer::readable(REQ_ADDR, 51),
+            Buffer::writable(RESP_ADDR, CMD_RESP_SIZE as u32),
+            Buffer::writable(DATA_IN_ADDR, 0x400),
+        ]);
+        assert!(handler.process_queue(REQUEST_QUEUE));
+        let resp: VirtioScsiCmdRespHeader = mem.read_obj(RESP_ADDR).unwrap();
+        assert_eq!(resp.status, SAM_STAT_CHECK_CONDITION);
+        let mut sense = [0u8; 18];
+        mem.read_slice(&mut sense, RESP_ADDR.unchecked_add(12))
+            .unwrap();
+        assert_eq!((sense[2], sense[12]), (ILLEGAL_REQUEST, 0x21));
+    }
+
+    #[test]
+    fn test_discovery() {
+        let mem = create_guest_memory(0x10000);
+        let mut vq = VirtqueueBuilder::new(&mem, QUEUE_SIZE).build();
+        let mut handler = create_handler(&mem, vq.create_queue(), &[(1, 0x1000), (300, 0x800)]);
+
+        // REPORT LUNS, addressed to a LUN which doesn't exist.
+        mem.write_obj(
+            cmd_req(0, &[REPORT_LUNS, 0, 0, 0, 0, 0, 0, 0, 1, 0]),
+            REQ_ADDR,
+        )
+        .unwrap();
+        vq.add_chain(&[
+            Buffer::readable(REQ_ADDR, 51),
+            Buffer::writable(RESP_ADDR, CMD_RESP_SIZE as u32),
+            Buffer::writable(DATA_IN_ADDR, 0x100),
+        ]);
+        assert!(handler.process_queue(REQUEST_QUEUE));
+        let mut buf = [0u8; 24];
+        mem.read_slice(&mut buf, DATA_IN_ADDR).unwrap();
+        assert_eq!(buf[..8], [0, 0, 0, 16, 0, 0, 0, 0]);
+        assert_eq!(buf[8..16], [0, 1, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(buf[16..], [0x41, 0x2c, 0, 0, 0, 0, 0, 0]);
+
+        // INQUIRY reports whether a device is present.
+        for (lun, peripheral) in &[(0, 0x7f), (300, 0x00)] {
+            mem.write_obj(cmd_req(*lun, &[INQUIRY, 0, 0, 0, 36, 0]), REQ_ADDR)
+                .unwrap();
+            vq.add_chain(&[
+                Buffer::readable(REQ_ADDR, 51),
+                Buffer::writable(RESP_ADDR, CMD_RESP_SIZE as u32),
+                Buffer::writable(DATA_IN_ADDR, 0x100),
+            ]);
+            assert!(handler.process_queue(REQUEST_QUEUE));
+            assert_eq!(mem.read_obj::<u8>(DATA_IN_ADDR).unwrap(), *peripheral);
+        }
+
+        // READ CAPACITY(10) reports the last LBA and the block size.
+        mem.write_obj(cmd_req(300, &[READ_CAPACITY_10]), REQ_ADDR)
+            .unwrap();
+        vq.add_chain(&[
+            Buffer::readable(REQ_ADDR, 51),
+            Buffer::writable(RESP_ADDR, CMD_RESP_SIZE as u32),
+            Buffer::writable(DATA_IN_ADDR, 8),
+        ]);
+        assert!(handler.process_queue(REQUEST_QUEUE));
+        let mut buf = [0u8; 8];
+        mem.read_slice(&mut buf, DATA_IN_ADDR).unwrap();
+        assert_eq!(buf, [0, 0, 0, 3, 0, 0, 2, 0]);
+
+        // Any other command fails on a LUN which doesn't exist.
+        mem.write_obj(cmd_req(2, &[TEST_UNIT_READY]), REQ_ADDR)
+            .unwrap();
+        vq.add_chain(&[
+            Buffer::readable(REQ_ADDR, 51),
+            Buffer::writable(RESP_ADDR, CMD_RESP_SIZE as u32),
+        ]);
+        assert!(handler.process_queue(REQUEST_QUEUE));
+        let resp: VirtioScsiCmdRespHeader = mem.read_obj(RESP_ADDR).unwrap();
+        assert_eq!(resp.status, SAM_STAT_CHECK_CONDITION);
+    }
+
+    #[test]
+    fn test_unsupported_cdb() {
+        let mem = create_guest_memory(0x10000);
+        let mut vq = VirtqueueBuilder::new(&mem, QUEUE_SIZE).build();
+        let mut handler = create_handler(&mem, vq.create_queue(), &[(0, 0x1000)]);
+
+        // MODE SENSE(6)
+        mem.write_obj(cmd_req(0, &[0x1a, 0, 0x3f, 0, 0xff, 0]), REQ_ADDR)
+            .unwrap();
+        vq.add_chain(&[
+            Buffer::readable(REQ_ADDR, 51),
+            Buffer::writable(RESP_ADDR, CMD_RESP_SIZE as u32),
+            Buffer::writable(DATA_IN_ADDR, 0xff),
+        ]);
+        assert!(handler.process_queue(REQUEST_QUEUE));
+        let resp: VirtioScsiCmdRespHeader = mem.read_obj(RESP_ADDR).unwrap();
+        assert_eq!(resp.response, VIRTIO_SCSI_S_OK);
+        assert_eq!(resp.status, SAM_STAT_CHECK_CONDITION);
+        assert_eq!({ resp.sense_len }, 18);
+        assert_eq!({ resp.resid }, 0xff);
+        let mut sense = [0u8; 18];
+        mem.read_slice(&mut sense, RESP_ADDR.unchecked_add(12))
+            .unwrap();
+        assert_eq!((sense[2], sense[12]), (ILLEGAL_REQUEST, 0x20));
+
+        // Addressing another target.
+        let mut req = cmd_req(0, &[TEST_UNIT_READY]);
+        req.lun[1] = 1;
+        mem.write_obj(req, REQ_ADDR).unwrap();
+        vq.add_chain(&[
+            Buffer::readable(REQ_ADDR, 51),
+            Buffer::writable(RESP_ADDR, CMD_RESP_SIZE as u32),
+        ]);
+        assert!(handler.process_queue(REQUEST_QUEUE));
+        let resp: VirtioScsiCmdRespHeader = mem.read_obj(RESP_ADDR).unwrap();
+        assert_eq!(resp.response, VIRTIO_SCSI_S_BAD_TARGET);
+    }
+
+    #[test]
+    fn test_invalid_luns() {
+        let disk = || Cursor::new(vec![0u8; 0x1000]);
+        assert!(Scsi::new(vec![(0, disk()), (0, disk())], false).is_err());
+        assert!(Scsi::new(vec![(MAX_LUN + 1, disk())], false).is_err());
+        assert!(Scsi::new(vec![(0, disk()), (MAX_LUN, disk())], false).is_ok());
+    }
+}
@@ -0,0 +1,364 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Helpers for device unit tests, laying virtqueues out in a fake guest memory
+//! the same way a driver would.
+
+use std::io;
+use std::mem::size_of;
+use std::sync::atomic::{fence, AtomicUsize, Ordering};
+
+use crate::queue::{Descriptor, Queue, VIRTQ_DESC_F_NEXT, VIRTQ_DESC_F_WRITE};
+use crate::{VirtioInterrupt, VirtioInterruptType};
+use vm_memory::{Address, Bytes, GuestAddress, GuestMemory, GuestMemoryMmap};
+
+const DESC_TABLE_ALIGN: u64 = 16;
+const AVAIL_RING_ALIGN: u64 = 2;
+const USED_RING_ALIGN: u64 = 4;
+
+/// Creates a guest memory made of a single region of `size` bytes, starting
+/// at guest address 0.
+pub fn create_guest_memory(size: usize) -> GuestMemoryMmap {
+    GuestMemoryMmap::from_ranges(&[(GuestAddress(0), size)]).unwrap()
+}
+
+fn align_up(addr: GuestAddress, align: u64) -> GuestAddress {
+    GuestAddress((addr.raw_value() + align - 1) & !(align - 1))
+}
+
+fn desc_table_size(size: u16) -> u64 {
+    size_of::<Descriptor>() as u64 * u64::from(size)
+}
+
+// flags, idx, ring[size] and used_event.
+fn avail_ring_size(size: u16) -> u64 {
+    6 + 2 * u64::from(size)
+}
+
+// flags, idx, ring[size] and avail_event.
+fn used_ring_size(size: u16) -> u64 {
+    6 + 8 * u64::from(size)
+}
+
+/// A buffer to be added to a descriptor chain through `Virtqueue::add_chain`.
+#[derive(Clone, Copy)]
+pub struct Buffer {
+    pub addr: GuestAddress,
+    pub len: u32,
+    pub writable: bool,
+}
+
+impl Buffer {
+    pub fn readable(addr: GuestAddress, len: u32) -> Self {
+        Buffer {
+            addr,
+            len,
+            writable: false,
+        }
+    }
+
+    pub fn writable(addr: GuestAddress, len: u32) -> Self {
+        Buffer {
+            addr,
+            len,
+            writable: true,
+        }
+    }
+}
+
+/// Builds a virtqueue in guest memory. Unless placed explicitly, the
+/// descriptor table is put at the start address and each ring follows the
+/// previous part, aligned as the split virtqueue layout requires.
+pub struct VirtqueueBuilder<'a> {
+    mem: &'a GuestMemoryMmap,
+    size: u16,
+    start: GuestAddress,
+    desc_table: Option<GuestAddress>,
+    avail_ring: Option<GuestAddress>,
+    used_ring: Option<GuestAddress>,
+}
+
+impl<'a> VirtqueueBuilder<'a> {
+    pub fn new(mem: &'a GuestMemoryMmap, size: u16) -> Self {
+        VirtqueueBuilder {
+            mem,
+            size,
+            start: GuestAddress(0),
+            desc_table: None,
+            avail_ring: None,
+            used_ring: None,
+        }
+    }
+
+    pub fn start(mut self, addr: GuestAddress) -> Self {
+        self.start = addr;
+        self
+    }
+
+    pub fn desc_table(mut self, addr: GuestAddress) -> Self {
+        self.desc_table = Some(addr);
+        self
+    }
+
+    pub fn avail_ring(mut self, addr: GuestAddress) -> Self {
+        self.avail_ring = Some(addr);
+        self
+    }
+
+    pub fn used_ring(mut self, addr: GuestAddress) -> Self {
+        self.used_ring = Some(addr);
+        self
+    }
+
+    /// Writes empty rings to guest memory. Panics if the layout is invalid.
+    pub fn build(self) -> Virtqueue<'a> {
+        assert!(self.size > 0 && self.size & (self.size - 1) == 0);
+
+        let desc_table = self
+            .desc_table
+            .unwrap_or_else(|| align_up(self.start, DESC_TABLE_ALIGN));
+        let avail_ring = self.avail_ring.unwrap_or_else(|| {
+            align_up(
+                desc_table.unchecked_add(desc_table_size(self.size)),
+                AVAIL_RING_ALIGN,
+            )
+        });
+        let used_ring = self.used_ring.unwrap_or_else(|| {
+            align_up(
+                avail_ring.unchecked_add(avail_ring_size(self.size)),
+                USED_RING_ALIGN,
+            )
+        });
+
+        for &(addr, len, align) in &[
+            (desc_table, desc_table_size(self.size), DESC_TABLE_ALIGN),
+            (avail_ring, avail_ring_size(self.size), AVAIL_RING_ALIGN),
+            (used_ring, used_ring_size(self.size), USED_RING_ALIGN),
+        ] {
+            assert_eq!(addr.raw_value() & (align - 1), 0);
+            assert!(self.mem.checked_offset(addr, len as usize - 1).is_some());
+            self.mem
+                .write_slice(&vec![0u8; len as usize], addr)
+                .unwrap();
+        }
+
+        Virtqueue {
+            mem: self.mem,
+            size: self.size,
+            desc_table,
+            avail_ring,
+            used_ring,
+            next_desc: 0,
+            avail_idx: 0,
+        }
+    }
+}
+
+/// A virtqueue laid out in guest memory, seen from the driver side.
+pub struct Virtqueue<'a> {
+    mem: &'a GuestMemoryMmap,
+    size: u16,
+    desc_table: GuestAddress,
+    avail_ring: GuestAddress,
+    used_ring: GuestAddress,
+    next_desc: u16,
+    avail_idx: u16,
+}
+
+impl<'a> Virtqueue<'a> {
+    pub fn desc_table(&self) -> GuestAddress {
+        self.desc_table
+    }
+
+    pub fn avail_ring(&self) -> GuestAddress {
+        self.avail_ring
+    }
+
+    pub fn used_ring(&self) -> GuestAddress {
+        self.used_ring
+    }
+
+    /// Creates a ready `Queue` using this virtqueue's rings.
+    pub fn create_queue(&self) -> Queue {
+        let mut queue = Queue::new(self.size);
+        queue.size = self.size;
+        queue.ready = true;
+        queue.desc_table = self.desc_table;
+        queue.avail_ring = self.avail_ring;
+        queue.used_ring = self.used_ring;
+        queue
+    }
+
+    /// Writes a descriptor to the table at `index`.
+    pub fn set_desc(&self, index: u16, addr: GuestAddress, len: u32, flags: u16, next: u16) {
+        let desc = self
+            .desc_table
+            .unchecked_add(u64::from(index) * size_of::<Descriptor>() as u64);
+        self.mem.write_obj(addr.raw_value(), desc).unwrap();
+        self.mem.write_obj(len, desc.unchecked_add(8)).unwrap();
+        self.mem.write_obj(flags, desc.unchecked_add(12)).unwrap();
+        self.mem.write_obj(next, desc.unchecked_add(14)).unwrap();
+    }
+
+    /// Chains `buffers` together using the next free descriptors, and makes
+    /// the chain available to the device. Returns the head index.
+    pub fn add_chain(&mut self, buffers: &[Buffer]) -> u16 {
+        assert!(!buffers.is_empty());
+
+        let head = self.next_desc;
+        for (i, buffer) in buffers.iter().enumerate() {
+            let index = (self.next_desc + i as u16) % self.size;
+            let mut flags = 0;
+            if buffer.writable {
+                flags |= VIRTQ_DESC_F_WRITE;
+            }
+            if i + 1 < buffers.len() {
+                flags |= VIRTQ_DESC_F_NEXT;
+            }
+            self.set_desc(
+                index,
+                buffer.addr,
+                buffer.len,
+                flags,
+                (index + 1) % self.size,
+            );
+        }
+        self.next_desc = (self.next_desc + buffers.len() as u16) % self.size;
+
+        let slot = u64::from(self.avail_idx % self.size);
+        self.mem
+            .write_obj(head, self.avail_ring.unchecked_add(4 + 2 * slot))
+            .unwrap();
+        self.avail_idx = self.avail_idx.wrapping_add(1);
+
+        // Make the ring entry visible before the index, as a driver would.
+        fence(Ordering::Release);
+        self.mem
+            .write_obj(self.avail_idx, self.avail_ring.unchecked_add(2))
+            .unwrap();
+
+        head
+    }
+
+    /// Returns the used ring index written by the device.
+    pub fn used_idx(&self) -> u16 {
+        self.mem.read_obj(self.used_ring.unchecked_add(2)).unwrap()
+    }
+
+    /// Returns the (head index, length) pair of a used ring entry.
+    pub fn used_elem(&self, slot: u16) -> (u32, u32) {
+        let elem = self
+            .used_ring
+            .unchecked_add(4 + 8 * u64::from(slot % self.size));
+        (
+            self.mem.read_obj(elem).unwrap(),
+            self.mem.read_obj(elem.unchecked_add(4)).unwrap(),
+        )
+    }
+}
+
+/// Interrupt callback counting how many times it has been triggered.
+#[derive(Default)]
+pub struct CountingInterrupt {
+    pub count: AtomicUsize,
+}
+
+impl VirtioInterrupt for CountingInterrupt {
+    fn trigger(
+        &self,
+        _int_type: &VirtioInterruptType,
+        _queue: Option<&Queue>,
+    ) -> std::result::Result<(), io::Error> {
+        self.count.fetch_add(1, Ordering::SeqCst);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_default_layout() {
+        let mem = create_guest_memory(0x10000);
+        let vq = VirtqueueBuilder::new(&mem, 256)
+            .start(GuestAddress(0x1000))
+            .build();
+
+        assert_eq!(vq.desc_table(), GuestAddress(0x1000));
+        assert_eq!(vq.avail_ring(), GuestAddress(0x2000));
+        // The available ring takes 0x206 bytes, rounded up to 4.
+        assert_eq!(vq.used_ring(), GuestAddress(0x2208));
+
+        let queue = vq.create_queue();
+        assert!(queue.is_valid(&mem));
+        assert_eq!(queue.actual_size(), 256);
+    }
+
+    #[test]
+    fn test_explicit_layout() {
+        let mem = create_guest_memory(0x10000);
+        let vq = VirtqueueBuilder::new(&mem, 16)
+            .desc_table(GuestAddress(0x4000))
+            .avail_ring(GuestAddress(0x3000))
+            .used_ring(GuestAddress(0x2000))
+            .build();
+
+        let queue = vq.create_queue();
+        assert!(queue.is_valid(&mem));
+        assert_eq!(queue.desc_table, GuestAddress(0x4000));
+        assert_eq!(queue.avail_ring, GuestAddress(0x3000));
+        assert_eq!(queue.used_ring, GuestAddress(0x2000));
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_layout_out_of_memory() {
+        let mem = create_guest_memory(0x1000);
+        VirtqueueBuilder::new(&mem, 256).build();
+    }
+
+    #[test]
+    fn test_add_chain_and_used() {
+        let mem = create_guest_memory(0x10000);
+        let mut vq = VirtqueueBuilder::new(&mem, 16).build();
+        let mut queue = vq.create_queue();
+
+        let first = vq.add_chain(&[
+            Buffer::readable(GuestAddress(0x8000), 0x10),
+            Buffer::writable(GuestAddress(0x9000), 0x100),
+        ]);
+        let second = vq.add_chain(&[Buffer::writable(GuestAddress(0xa000), 0x20)]);
+        assert_eq!((first, second), (0, 2));
+
+        let chains: Vec<(u16, Vec<(GuestAddress, u32, bool)>)> = queue
+            .iter(&mem)
+            .map(|head| {
+                let index = head.index;
+                let descs = head
+                    .into_iter()
+                    .map(|d| (d.addr, d.len, d.is_write_only()))
+                    .collect();
+                (index, descs)
+            })
+            .collect();
+        assert_eq!(
+            chains,
+            vec![
+                (
+                    0,
+                    vec![
+                        (GuestAddress(0x8000), 0x10, false),
+                        (GuestAddress(0x9000), 0x100, true)
+                    ]
+                ),
+                (2, vec![(GuestAddress(0xa000), 0x20, true)]),
+            ]
+        );
+
+        queue.add_used(&mem, second, 0x20);
+        assert_eq!(vq.used_idx(), 1);
+        assert_eq!(vq.used_elem(0), (2, 0x20));
+    }
+}
@@ -2,11 +2,15 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE file.
 
+use super::{restore_virtio_device, snapshot_virtio_device};
+use crate::device::{add_snapshot_state, snapshot_state};
 use crate::transport::{VirtioTransport, NOTIFY_REG_OFFSET};
 use crate::{
-    Queue, VirtioDevice, VirtioInterrupt, VirtioInterruptType, DEVICE_ACKNOWLEDGE, DEVICE_DRIVER,
+    check_driver_features, ActivateError, ActivateResult, Queue, QueueState, VirtioDevice,
+    VirtioDeviceType, VirtioInterrupt, VirtioInterruptType, DEVICE_ACKNOWLEDGE, DEVICE_DRIVER,
     DEVICE_DRIVER_OK, DEVICE_FAILED, DEVICE_FEATURES_OK, DEVICE_INIT,
-    INTERRUPT_STATUS_CONFIG_CHANGED, INTERRUPT_STATUS_USED_RING,
+    INTERRUPT_STATUS_CONFIG_CHANGED, INTERRUPT_STATUS_USED_RING, VIRTIO_F_IOMMU_PLATFORM,
+    VIRTIO_F_VERSION_1,
 };
 use arc_swap::ArcSwap;
 use byteorder::{ByteOrder, LittleEndian};
@@ -16,7 +20,7 @@ use std::result;
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex};
 use vm_device::interrupt::InterruptSourceGroup;
-use vm_device::{Migratable, MigratableError, Pausable, Snapshotable};
+use vm_device::{Migratable, MigratableError, Pausable, Snapshot, Snapshotable};
 use vm_memory::{GuestAddress, GuestMemoryMmap};
 use vmm_sys_util::{errno::Result, eventfd::EventFd};
 
@@ -74,11 +78,20 @@ impl VirtioInterrupt for VirtioInterruptIntx {
 /// Typically one page (4096 bytes) of MMIO address space is sufficient to handle this transport
 /// and inner virtio device.
 pub struct MmioDevice {
+    // Id of the device in the VM snapshot.
+    id: String,
     device: Arc<Mutex<dyn VirtioDevice>>,
     device_activated: bool,
+    // Set once restored from the snapshot of an activated device, which is
+    // activated again when the VM is resumed.
+    activate_on_resume: bool,
 
     features_select: u32,
     acked_features_select: u32,
+    driver_features: u64,
+    // Set once the driver touched a register of the legacy (version 1)
+    // layout, which is not supported.
+    legacy_layout: bool,
     queue_select: u32,
     interrupt_status: Arc<AtomicUsize>,
     interrupt_cb: Option<Arc<dyn VirtioInterrupt>>,
@@ -107,10 +120,14 @@ impl MmioDevice {
             .map(|&s| Queue::new(s))
             .collect();
         Ok(MmioDevice {
+            id: String::new(),
             device,
             device_activated: false,
+            activate_on_resume: false,
             features_select: 0,
             acked_features_select: 0,
+            driver_features: 0,
+            legacy_layout: false,
             queue_select: 0,
             interrupt_status: Arc::new(AtomicUsize::new(0)),
             interrupt_cb: None,
@@ -122,6 +139,12 @@ impl MmioDevice {
         })
     }
 
+    /// Sets the id of the device in the VM snapshot, which has to be unique
+    /// among the devices of the VM for it to be saved.
+    pub fn set_id(&mut self, id: String) {
+        self.id = id;
+    }
+
     /// Gets the list of queue events that must be triggered whenever the VM writes to
     /// `virtio::NOTIFY_REG_OFFSET` past the MMIO base. Each event must be triggered when the
     /// value being written equals the index of the event in this list.
@@ -129,6 +152,14 @@ impl MmioDevice {
         self.queue_evts.as_slice()
     }
 
+    fn check_driver(&self) -> ActivateResult {
+        if self.legacy_layout {
+            return Err(ActivateError::BadActivate);
+        }
+
+        check_driver_features(self.driver_features)
+    }
+
     fn is_driver_ready(&self) -> bool {
         let ready_bits = DEVICE_ACKNOWLEDGE | DEVICE_DRIVER | DEVICE_DRIVER_OK | DEVICE_FEATURES_OK;
         self.driver_status == ready_bits && self.driver_status & DEVICE_FAILED == 0
@@ -161,6 +192,50 @@ impl MmioDevice {
         }
     }
 
+    // Takes the interrupt and queue EventFDs back from the device. Returns
+    // false if the underlying device does not implement reset.
+    fn reset_device(&mut self) -> bool {
+        let mut device = self.device.lock().unwrap();
+        if let Some((interrupt_cb, mut queue_evts)) = device.reset() {
+            self.interrupt_cb = Some(interrupt_cb);
+            self.queue_evts.append(&mut queue_evts);
+
+            self.device_activated = false;
+
+            self.queues.iter_mut().for_each(Queue::reset);
+            self.queue_select = 0;
+            self.features_select = 0;
+            self.acked_features_select = 0;
+            self.driver_features = 0;
+            self.interrupt_status.store(0, Ordering::SeqCst);
+
+            true
+        } else {
+            false
+        }
+    }
+
+    // Hands the queues over to the device, which starts processing them.
+    fn activate(&mut self) {
+        if let Some(interrupt_cb) = self.interrupt_cb.take() {
+            if self.mem.is_some() {
+                let mem = self.mem.as_ref().unwrap().clone();
+                let mut device = self.device.lock().unwrap();
+                let device_type = VirtioDeviceType::from(device.device_type());
+                device
+                    .activate(
+                        mem,
+                        interrupt_cb,
+                        self.queues.clone(),
+                        self.queue_evts.split_off(0),
+                    )
+                    .unwrap_or_else(|e| panic!("Failed to activate {} device: {}", device_type, e));
+                self.device_activated = true;
+                event!("virtio-device", "activated", "type" => device_type);
+            }
+        }
+    }
+
     pub fn assign_interrupt(&mut self, interrupt: Arc<Box<dyn InterruptSourceGroup>>) {
         self.interrupt_cb = Some(Arc::new(VirtioInterruptIntx::new(
             self.interrupt_status.clone(),
@@ -189,9 +264,13 @@ impl BusDevice for MmioDevice {
                     0x08 => self.device.lock().unwrap().device_type(),
                     0x0c => VENDOR_ID, // vendor id
                     0x10 => {
+                        // Queues are never behind a DMA mapping on this
+                        // transport.
+                        let features = (self.device.lock().unwrap().features()
+                            | 1u64 << VIRTIO_F_VERSION_1)
+                            & !(1u64 << VIRTIO_F_IOMMU_PLATFORM);
                         if self.features_select < 2 {
-                            (self.device.lock().unwrap().features() >> (self.features_select * 32))
-                                as u32
+                            (features >> (self.features_select * 32)) as u32
                         } else {
                             0
                         }
@@ -240,10 +319,14 @@ impl BusDevice for MmioDevice {
                     0x14 => self.features_select = v,
                     0x20 => {
                         if self.acked_features_select < 2 {
+                            let shift = self.acked_features_select * 32;
+                            self.driver_features = (self.driver_features
+                                & !(0xffff_ffffu64 << shift))
+                                | u64::from(v) << shift;
                             self.device
                                 .lock()
                                 .unwrap()
-                                .ack_features(u64::from(v) << (self.acked_features_select * 32));
+                                .ack_features(u64::from(v) << shift);
                         } else {
                             warn!(
                                 "invalid ack_features (page {}, value 0x{:x})",
@@ -253,13 +336,42 @@ impl BusDevice for MmioDevice {
                     }
                     0x24 => self.acked_features_select = v,
                     0x30 => self.queue_select = v,
-                    0x38 => mut_q = self.with_queue_mut(|q| q.size = v as u16),
-                    0x44 => mut_q = self.with_queue_mut(|q| q.ready = v == 1),
+                    0x38 => {
+                        mut_q = self.with_queue_mut(|q| {
+                            if let Err(e) = q.set_size(v as u16) {
+                                warn!("invalid queue size set by the driver: {}", e);
+                            }
+                        })
+                    }
+                    0x44 => mut_q = self.with_queue_mut(|q| q.enable(v == 1)),
                     0x64 => {
                         self.interrupt_status
                             .fetch_and(!(v as usize), Ordering::SeqCst);
                     }
-                    0x70 => self.driver_status = v,
+                    // GuestPageSize, QueueAlign and QueuePFN only exist in the
+                    // legacy layout.
+                    0x28 | 0x3c | 0x40 => {
+                        warn!("legacy virtio mmio register write: 0x{:x}", offset);
+                        self.legacy_layout = true;
+                    }
+                    0x70 => {
+                        self.driver_status = v;
+                        // Writing zero resets the device.
+                        if v == DEVICE_INIT && self.device_activated && !self.reset_device() {
+                            error!(
+                                "Attempt to reset device when not implemented in underlying device"
+                            );
+                            self.driver_status = DEVICE_FAILED;
+                        }
+                        // Leaving FEATURES_OK unset tells the driver its
+                        // features were refused.
+                        if v & DEVICE_FEATURES_OK != 0 {
+                            if let Err(e) = self.check_driver() {
+                                warn!("refusing legacy driver: {:?}", e);
+                                self.driver_status &= !DEVICE_FEATURES_OK;
+                            }
+                        }
+                    }
                     0x80 => mut_q = self.with_queue_mut(|q| lo(&mut q.desc_table, v)),
                     0x84 => mut_q = self.with_queue_mut(|q| hi(&mut q.desc_table, v)),
                     0x90 => mut_q = self.with_queue_mut(|q| lo(&mut q.avail_ring, v)),
@@ -294,21 +406,11 @@ impl BusDevice for MmioDevice {
         }
 
         if !self.device_activated && self.is_driver_ready() && self.are_queues_valid() {
-            if let Some(interrupt_cb) = self.interrupt_cb.take() {
-                if self.mem.is_some() {
-                    let mem = self.mem.as_ref().unwrap().clone();
-                    self.device
-                        .lock()
-                        .unwrap()
-                        .activate(
-                            mem,
-                            interrupt_cb,
-                            self.queues.clone(),
-                            self.queue_evts.split_off(0),
-                        )
-                        .expect("Failed to activate device");
-                    self.device_activated = true;
-                }
+            if let Err(e) = self.check_driver() {
+                error!("Refusing to activate device: {:?}", e);
+                self.driver_status = DEVICE_FAILED;
+            } else {
+                self.activate();
             }
         }
     }
@@ -320,9 +422,107 @@ impl Pausable for MmioDevice {
     }
 
     fn resume(&mut self) -> result::Result<(), MigratableError> {
+        if self.activate_on_resume {
+            self.activate_on_resume = false;
+            // The guest notified the queues before the snapshot, the device
+            // has to look at them once activated.
+            for queue_evt in self.queue_evts.iter() {
+                queue_evt
+                    .write(1)
+                    .map_err(|e| MigratableError::Resume(e.into()))?;
+            }
+            self.activate();
+        }
+
         Ok(())
     }
 }
 
-impl Snapshotable for MmioDevice {}
+#[derive(Deserialize, Serialize)]
+struct MmioDeviceState {
+    device_activated: bool,
+    interrupt_status: usize,
+    features_select: u32,
+    acked_features_select: u32,
+    driver_features: u64,
+    queue_select: u32,
+    driver_status: u32,
+    config_generation: u32,
+    queues: Vec<QueueState>,
+}
+
+impl Snapshotable for MmioDevice {
+    fn id(&self) -> String {
+        self.id.clone()
+    }
+
+    fn snapshot(&self) -> result::Result<Snapshot, MigratableError> {
+        let mem = self
+            .mem
+            .as_ref()
+            .ok_or_else(|| MigratableError::Snapshot(anyhow!("No guest memory")))?
+            .load();
+
+        let mut snapshot = Snapshot::new(&self.id());
+        add_snapshot_state(
+            &mut snapshot,
+            &MmioDeviceState {
+                device_activated: self.device_activated || self.activate_on_resume,
+                interrupt_status: self.interrupt_status.load(Ordering::SeqCst),
+                features_select: self.features_select,
+                acked_features_select: self.acked_features_select,
+                driver_features: self.driver_features,
+                queue_select: self.queue_select,
+                driver_status: self.driver_status,
+                config_generation: self.config_generation,
+                queues: self.queues.iter().map(|q| q.state(&mem)).collect(),
+            },
+        )?;
+        snapshot.add_snapshot(snapshot_virtio_device(&self.device)?);
+
+        Ok(snapshot)
+    }
+
+    fn restore(&mut self, mut snapshot: Snapshot) -> result::Result<(), MigratableError> {
+        if self.device_activated {
+            return Err(MigratableError::Restore(anyhow!(
+                "Device already activated"
+            )));
+        }
+        let state: MmioDeviceState = snapshot_state(&snapshot)?
+            .ok_or_else(|| MigratableError::Restore(anyhow!("Missing virtio-mmio state")))?;
+        if state.queues.len() != self.queues.len() {
+            return Err(MigratableError::Restore(anyhow!(
+                "{} queues instead of {}",
+                state.queues.len(),
+                self.queues.len()
+            )));
+        }
+
+        restore_virtio_device(&self.device, &mut snapshot)?;
+        // For the devices not saving the features the driver acked.
+        self.device
+            .lock()
+            .unwrap()
+            .ack_features(state.driver_features);
+
+        for (queue, queue_state) in self.queues.iter_mut().zip(state.queues.iter()) {
+            queue
+                .set_state(queue_state)
+                .map_err(|e| MigratableError::Restore(e.into()))?;
+        }
+
+        self.features_select = state.features_select;
+        self.acked_features_select = state.acked_features_select;
+        self.driver_features = state.driver_features;
+        self.queue_select = state.queue_select;
+        self.driver_status = state.driver_status;
+        self.config_generation = state.config_generation;
+        self.interrupt_status
+            .store(state.interrupt_status, Ordering::SeqCst);
+        self.activate_on_resume = state.device_activated;
+
+        Ok(())
+    }
+}
 impl Migratable for MmioDevice {}
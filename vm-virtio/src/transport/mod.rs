@@ -2,6 +2,12 @@
 //
 // SPDX-License-Identifier: Apache-2.0
 
+#[cfg(any(feature = "pci_support", feature = "mmio_support"))]
+use crate::VirtioDevice;
+#[cfg(any(feature = "pci_support", feature = "mmio_support"))]
+use std::sync::{Arc, Mutex};
+#[cfg(any(feature = "pci_support", feature = "mmio_support"))]
+use vm_device::{MigratableError, Snapshot};
 use vmm_sys_util::eventfd::EventFd;
 #[cfg(feature = "pci_support")]
 mod pci_common_config;
@@ -22,3 +28,27 @@ pub const NOTIFY_REG_OFFSET: u32 = 0x50;
 pub trait VirtioTransport {
     fn ioeventfds(&self, base_addr: u64) -> Vec<(&EventFd, u64)>;
 }
+
+// The snapshot of the virtio device, among the ones of its transport.
+#[cfg(any(feature = "pci_support", feature = "mmio_support"))]
+const VIRTIO_DEVICE_SNAPSHOT_ID: &str = "virtio-device";
+
+#[cfg(any(feature = "pci_support", feature = "mmio_support"))]
+fn snapshot_virtio_device(
+    device: &Arc<Mutex<dyn VirtioDevice>>,
+) -> Result<Snapshot, MigratableError> {
+    let mut snapshot = device.lock().unwrap().snapshot()?;
+    snapshot.id = VIRTIO_DEVICE_SNAPSHOT_ID.to_string();
+    Ok(snapshot)
+}
+
+#[cfg(any(feature = "pci_support", feature = "mmio_support"))]
+fn restore_virtio_device(
+    device: &Arc<Mutex<dyn VirtioDevice>>,
+    snapshot: &mut Snapshot,
+) -> Result<(), MigratableError> {
+    match snapshot.snapshots.remove(VIRTIO_DEVICE_SNAPSHOT_ID) {
+        Some(device_snapshot) => device.lock().unwrap().restore(*device_snapshot),
+        None => Ok(()),
+    }
+}
@@ -7,11 +7,14 @@
 // SPDX-License-Identifier: Apache-2.0 AND BSD-3-Clause
 extern crate byteorder;
 
-use crate::{Queue, VirtioDevice};
+use crate::{
+    check_driver_features, Queue, VirtioDevice, DEVICE_FEATURES_OK, VIRTIO_F_IOMMU_PLATFORM,
+    VIRTIO_F_VERSION_1, VIRTIO_MSI_NO_VECTOR,
+};
 use byteorder::{ByteOrder, LittleEndian};
 use std::sync::atomic::{AtomicU16, Ordering};
 use std::sync::{Arc, Mutex};
-use vm_memory::GuestAddress;
+use vm_memory::{Address, GuestAddress};
 
 /// Contains the data for reading and writing the common configuration structure of a virtio PCI
 /// device.
@@ -42,6 +45,14 @@ pub struct VirtioPciCommonConfig {
     pub driver_feature_select: u32,
     pub queue_select: u16,
     pub msix_config: Arc<AtomicU16>,
+    /// Whether the queues translate addresses through a DMA mapping. The
+    /// device only advertises VIRTIO_F_IOMMU_PLATFORM if they do.
+    pub iommu_platform: bool,
+    /// Features accepted by the driver so far.
+    pub driver_features: u64,
+    /// Number of MSI-X vectors of the device. Vectors out of this range are
+    /// refused, which makes the driver fall back to sharing vectors.
+    pub msix_vectors: u16,
 }
 
 impl VirtioPciCommonConfig {
@@ -64,11 +75,11 @@ impl VirtioPciCommonConfig {
                 LittleEndian::write_u16(data, v);
             }
             4 => {
-                let v = self.read_common_config_dword(offset, device);
+                let v = self.read_common_config_dword(offset, queues, device);
                 LittleEndian::write_u32(data, v);
             }
             8 => {
-                let v = self.read_common_config_qword(offset);
+                let v = self.read_common_config_qword(offset, queues);
                 LittleEndian::write_u64(data, v);
             }
             _ => error!("invalid data length for virtio read: len {}", data.len()),
@@ -111,7 +122,20 @@ impl VirtioPciCommonConfig {
     fn write_common_config_byte(&mut self, offset: u64, value: u8) {
         debug!("write_common_config_byte: offset 0x{:x}", offset);
         match offset {
-            0x14 => self.driver_status = value,
+            0x14 => {
+                self.driver_status = value;
+                // Leaving FEATURES_OK unset tells the driver its features
+                // were refused.
+                if value & DEVICE_FEATURES_OK as u8 != 0
+                    && check_driver_features(self.driver_features).is_err()
+                {
+                    warn!(
+                        "refusing legacy driver features 0x{:x}",
+                        self.driver_features
+                    );
+                    self.driver_status &= !(DEVICE_FEATURES_OK as u8);
+                }
+            }
             _ => {
                 warn!("invalid virtio config byte write: 0x{:x}", offset);
             }
@@ -144,10 +168,19 @@ impl VirtioPciCommonConfig {
     fn write_common_config_word(&mut self, offset: u64, value: u16, queues: &mut Vec<Queue>) {
         debug!("write_common_config_word: offset 0x{:x}", offset);
         match offset {
-            0x10 => self.msix_config.store(value, Ordering::SeqCst),
+            0x10 => self
+                .msix_config
+                .store(self.checked_vector(value), Ordering::SeqCst),
             0x16 => self.queue_select = value,
-            0x18 => self.with_queue_mut(queues, |q| q.size = value),
-            0x1a => self.with_queue_mut(queues, |q| q.vector = value),
+            0x18 => self.with_queue_mut(queues, |q| {
+                if let Err(e) = q.set_size(value) {
+                    warn!("invalid queue size set by the driver: {}", e);
+                }
+            }),
+            0x1a => {
+                let vector = self.checked_vector(value);
+                self.with_queue_mut(queues, |q| q.vector = vector)
+            }
             0x1c => self.with_queue_mut(queues, |q| q.enable(value == 1)),
             _ => {
                 warn!("invalid virtio register word write: 0x{:x}", offset);
@@ -155,7 +188,12 @@ impl VirtioPciCommonConfig {
         }
     }
 
-    fn read_common_config_dword(&self, offset: u64, device: Arc<Mutex<dyn VirtioDevice>>) -> u32 {
+    fn read_common_config_dword(
+        &self,
+        offset: u64,
+        queues: &[Queue],
+        device: Arc<Mutex<dyn VirtioDevice>>,
+    ) -> u32 {
         debug!("read_common_config_dword: offset 0x{:x}", offset);
         match offset {
             0x00 => self.device_feature_select,
@@ -163,13 +201,25 @@ impl VirtioPciCommonConfig {
                 let locked_device = device.lock().unwrap();
                 // Only 64 bits of features (2 pages) are defined for now, so limit
                 // device_feature_select to avoid shifting by 64 or more bits.
+                let mut features = locked_device.features() | 1u64 << VIRTIO_F_VERSION_1;
+                if !self.iommu_platform {
+                    features &= !(1u64 << VIRTIO_F_IOMMU_PLATFORM);
+                }
                 if self.device_feature_select < 2 {
-                    (locked_device.features() >> (self.device_feature_select * 32)) as u32
+                    (features >> (self.device_feature_select * 32)) as u32
                 } else {
                     0
                 }
             }
             0x08 => self.driver_feature_select,
+            0x20..=0x34 if offset % 4 == 0 => {
+                let address = self.read_common_config_qword(offset & !0x7, queues);
+                if offset & 0x4 == 0 {
+                    address as u32
+                } else {
+                    (address >> 32) as u32
+                }
+            }
             _ => {
                 warn!("invalid virtio register dword read: 0x{:x}", offset);
                 0
@@ -198,9 +248,11 @@ impl VirtioPciCommonConfig {
             0x08 => self.driver_feature_select = value,
             0x0c => {
                 if self.driver_feature_select < 2 {
+                    let shift = self.driver_feature_select * 32;
+                    self.driver_features = (self.driver_features & !(0xffff_ffffu64 << shift))
+                        | u64::from(value) << shift;
                     let mut locked_device = device.lock().unwrap();
-                    locked_device
-                        .ack_features(u64::from(value) << (self.driver_feature_select * 32));
+                    locked_device.ack_features(u64::from(value) << shift);
                 } else {
                     warn!(
                         "invalid ack_features (page {}, value 0x{:x})",
@@ -220,9 +272,18 @@ impl VirtioPciCommonConfig {
         }
     }
 
-    fn read_common_config_qword(&self, _offset: u64) -> u64 {
-        debug!("read_common_config_qword: offset 0x{:x}", _offset);
-        0 // Assume the guest has no reason to read write-only registers.
+    fn read_common_config_qword(&self, offset: u64, queues: &[Queue]) -> u64 {
+        debug!("read_common_config_qword: offset 0x{:x}", offset);
+        let address = match offset {
+            0x20 => self.with_queue(queues, |q| q.desc_table),
+            0x28 => self.with_queue(queues, |q| q.avail_ring),
+            0x30 => self.with_queue(queues, |q| q.used_ring),
+            _ => {
+                warn!("invalid virtio register qword read: 0x{:x}", offset);
+                None
+            }
+        };
+        address.map_or(0, |a| a.raw_value())
     }
 
     fn write_common_config_qword(&mut self, offset: u64, value: u64, queues: &mut Vec<Queue>) {
@@ -249,21 +310,38 @@ impl VirtioPciCommonConfig {
             f(queue);
         }
     }
+
+    // The driver reads VIRTIO_MSI_NO_VECTOR back when the vector it asked
+    // for can't be used.
+    fn checked_vector(&self, vector: u16) -> u16 {
+        if vector < self.msix_vectors {
+            vector
+        } else {
+            if vector != VIRTIO_MSI_NO_VECTOR {
+                warn!(
+                    "MSI-X vector {} out of range, {} vectors available",
+                    vector, self.msix_vectors
+                );
+            }
+            VIRTIO_MSI_NO_VECTOR
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::{ActivateResult, VirtioInterrupt};
+    use crate::{ActivateResult, VirtioInterrupt, DEVICE_ACKNOWLEDGE, DEVICE_DRIVER};
     use arc_swap::ArcSwap;
     use std::sync::Arc;
+    use vm_device::Snapshotable;
     use vm_memory::GuestMemoryMmap;
     use vmm_sys_util::eventfd::EventFd;
 
     struct DummyDevice(u32);
     const QUEUE_SIZE: u16 = 256;
     const QUEUE_SIZES: &'static [u16] = &[QUEUE_SIZE];
-    const DUMMY_FEATURES: u64 = 0x5555_aaaa;
+    const DUMMY_FEATURES: u64 = 0x5555_aaaa | 1 << VIRTIO_F_IOMMU_PLATFORM;
     impl VirtioDevice for DummyDevice {
         fn device_type(&self) -> u32 {
             return self.0;
@@ -292,6 +370,8 @@ mod tests {
         fn write_config(&mut self, _offset: u64, _data: &[u8]) {}
     }
 
+    impl Snapshotable for DummyDevice {}
+
     #[test]
     fn write_base_regs() {
         let mut regs = VirtioPciCommonConfig {
@@ -301,6 +381,9 @@ mod tests {
             driver_feature_select: 0x0,
             queue_select: 0xff,
             msix_config: Arc::new(AtomicU16::new(0)),
+            iommu_platform: false,
+            driver_features: 0,
+            msix_vectors: 0,
         };
 
         let dev = Arc::new(Mutex::new(DummyDevice(0)));
@@ -341,4 +424,202 @@ mod tests {
         assert_eq!(read_back[0], 0xaa);
         assert_eq!(read_back[1], 0x55);
     }
+
+    #[test]
+    fn write_queue_size() {
+        let mut regs = VirtioPciCommonConfig {
+            driver_status: 0,
+            config_generation: 0,
+            device_feature_select: 0,
+            driver_feature_select: 0,
+            queue_select: 0,
+            msix_config: Arc::new(AtomicU16::new(0)),
+            iommu_platform: false,
+            driver_features: 0,
+            msix_vectors: 0,
+        };
+
+        let dev = Arc::new(Mutex::new(DummyDevice(0)));
+        let mut queues = vec![Queue::new(QUEUE_SIZE)];
+
+        // The queue size starts as the device maximum.
+        let mut read_back = vec![0, 0];
+        regs.read(0x18, &mut read_back, &mut queues, dev.clone());
+        assert_eq!(LittleEndian::read_u16(&read_back), QUEUE_SIZE);
+
+        // The driver can pick a smaller size, which is what the device uses.
+        regs.write(0x18, &[64, 0], &mut queues, dev.clone());
+        let mut read_back = vec![0, 0];
+        regs.read(0x18, &mut read_back, &mut queues, dev.clone());
+        assert_eq!(LittleEndian::read_u16(&read_back), 64);
+        assert_eq!(queues[0].get_max_size(), QUEUE_SIZE);
+        assert_eq!(queues[0].actual_size(), 64);
+
+        // A size that isn't a power of two is refused.
+        regs.write(0x18, &[63, 0], &mut queues, dev.clone());
+        let mut read_back = vec![0, 0];
+        regs.read(0x18, &mut read_back, &mut queues, dev);
+        assert_eq!(LittleEndian::read_u16(&read_back), 64);
+    }
+
+    #[test]
+    fn queue_addresses() {
+        let mut regs = VirtioPciCommonConfig {
+            driver_status: 0,
+            config_generation: 0,
+            device_feature_select: 0,
+            driver_feature_select: 0,
+            queue_select: 1,
+            msix_config: Arc::new(AtomicU16::new(0)),
+            iommu_platform: false,
+            driver_features: 0,
+            msix_vectors: 0,
+        };
+
+        let dev = Arc::new(Mutex::new(DummyDevice(0)));
+        let mut queues = vec![Queue::new(QUEUE_SIZE), Queue::new(QUEUE_SIZE)];
+
+        // The addresses can be set with a qword, or two dwords, and are read
+        // back the same way.
+        regs.write(
+            0x20,
+            &0x1_2345_6000u64.to_le_bytes(),
+            &mut queues,
+            dev.clone(),
+        );
+        regs.write(0x28, &0x7000u32.to_le_bytes(), &mut queues, dev.clone());
+        regs.write(0x2c, &0x1u32.to_le_bytes(), &mut queues, dev.clone());
+        regs.write(0x30, &0x8000u32.to_le_bytes(), &mut queues, dev.clone());
+        assert_eq!(queues[1].desc_table, GuestAddress(0x1_2345_6000));
+        assert_eq!(queues[1].avail_ring, GuestAddress(0x1_0000_7000));
+        assert_eq!(queues[1].used_ring, GuestAddress(0x8000));
+        assert_eq!(queues[0].desc_table, GuestAddress(0));
+
+        let mut read_back = vec![0; 8];
+        regs.read(0x28, &mut read_back, &mut queues, dev.clone());
+        assert_eq!(LittleEndian::read_u64(&read_back), 0x1_0000_7000);
+        let mut read_back = vec![0; 4];
+        regs.read(0x24, &mut read_back, &mut queues, dev.clone());
+        assert_eq!(LittleEndian::read_u32(&read_back), 0x1);
+        regs.read(0x20, &mut read_back, &mut queues, dev.clone());
+        assert_eq!(LittleEndian::read_u32(&read_back), 0x2345_6000);
+
+        // Enabling the queue.
+        regs.write(0x1c, &[1, 0], &mut queues, dev.clone());
+        assert!(queues[1].ready);
+        assert!(!queues[0].ready);
+
+        // Nothing to read for a queue the device doesn't have.
+        regs.write(0x16, &[2, 0], &mut queues, dev.clone());
+        let mut read_back = vec![0xff; 8];
+        regs.read(0x30, &mut read_back, &mut queues, dev);
+        assert_eq!(LittleEndian::read_u64(&read_back), 0);
+    }
+
+    #[test]
+    fn iommu_platform_feature() {
+        let mut regs = VirtioPciCommonConfig {
+            driver_status: 0,
+            config_generation: 0,
+            device_feature_select: 1,
+            driver_feature_select: 0,
+            queue_select: 0,
+            msix_config: Arc::new(AtomicU16::new(0)),
+            iommu_platform: false,
+            driver_features: 0,
+            msix_vectors: 0,
+        };
+
+        let dev = Arc::new(Mutex::new(DummyDevice(0)));
+        let mut queues = Vec::new();
+        let iommu_platform = 1 << (VIRTIO_F_IOMMU_PLATFORM - 32);
+
+        // Not advertised without a DMA mapping, even if the device offers it.
+        let mut read_back = vec![0, 0, 0, 0];
+        regs.read(0x04, &mut read_back, &mut queues, dev.clone());
+        assert_eq!(LittleEndian::read_u32(&read_back) & iommu_platform, 0);
+
+        regs.iommu_platform = true;
+        let mut read_back = vec![0, 0, 0, 0];
+        regs.read(0x04, &mut read_back, &mut queues, dev.clone());
+        assert_eq!(
+            LittleEndian::read_u32(&read_back) & iommu_platform,
+            iommu_platform
+        );
+    }
+
+    #[test]
+    fn version_1_handshake() {
+        let mut regs = VirtioPciCommonConfig {
+            driver_status: 0,
+            config_generation: 0,
+            device_feature_select: 1,
+            driver_feature_select: 1,
+            queue_select: 0,
+            msix_config: Arc::new(AtomicU16::new(0)),
+            iommu_platform: false,
+            driver_features: 0,
+            msix_vectors: 0,
+        };
+
+        let dev = Arc::new(Mutex::new(DummyDevice(0)));
+        let mut queues = Vec::new();
+        let status = (DEVICE_ACKNOWLEDGE | DEVICE_DRIVER | DEVICE_FEATURES_OK) as u8;
+
+        // VIRTIO_F_VERSION_1 is always offered.
+        let mut read_back = vec![0, 0, 0, 0];
+        regs.read(0x04, &mut read_back, &mut queues, dev.clone());
+        assert_eq!(LittleEndian::read_u32(&read_back) & 1, 1);
+
+        // A driver not accepting it doesn't get FEATURES_OK.
+        regs.write(0x0c, &[0, 0, 0, 0], &mut queues, dev.clone());
+        regs.write(0x14, &[status], &mut queues, dev.clone());
+        let mut read_back = vec![0];
+        regs.read(0x14, &mut read_back, &mut queues, dev.clone());
+        assert_eq!(read_back[0], status & !(DEVICE_FEATURES_OK as u8));
+
+        regs.write(0x0c, &[1, 0, 0, 0], &mut queues, dev.clone());
+        regs.write(0x14, &[status], &mut queues, dev.clone());
+        let mut read_back = vec![0];
+        regs.read(0x14, &mut read_back, &mut queues, dev.clone());
+        assert_eq!(read_back[0], status);
+    }
+
+    #[test]
+    fn msix_vector_range() {
+        let mut regs = VirtioPciCommonConfig {
+            driver_status: 0,
+            config_generation: 0,
+            device_feature_select: 0,
+            driver_feature_select: 0,
+            queue_select: 0,
+            msix_config: Arc::new(AtomicU16::new(0)),
+            iommu_platform: false,
+            driver_features: 0,
+            msix_vectors: 3,
+        };
+
+        let dev = Arc::new(Mutex::new(DummyDevice(0)));
+        let mut queues = vec![Queue::new(QUEUE_SIZE), Queue::new(QUEUE_SIZE)];
+
+        // Vectors can be shared between queues.
+        regs.write(0x1a, &[0x01, 0x00], &mut queues, dev.clone());
+        regs.write(0x16, &[0x01, 0x00], &mut queues, dev.clone());
+        regs.write(0x1a, &[0x01, 0x00], &mut queues, dev.clone());
+        assert_eq!(queues[0].vector, 1);
+        assert_eq!(queues[1].vector, 1);
+
+        // Out of range vectors are refused.
+        regs.write(0x1a, &[0x03, 0x00], &mut queues, dev.clone());
+        let mut read_back = vec![0x00, 0x00];
+        regs.read(0x1a, &mut read_back, &mut queues, dev.clone());
+        assert_eq!(LittleEndian::read_u16(&read_back), VIRTIO_MSI_NO_VECTOR);
+
+        regs.write(0x10, &[0x02, 0x00], &mut queues, dev.clone());
+        regs.read(0x10, &mut read_back, &mut queues, dev.clone());
+        assert_eq!(LittleEndian::read_u16(&read_back), 2);
+        regs.write(0x10, &[0x10, 0x00], &mut queues, dev.clone());
+        regs.read(0x10, &mut read_back, &mut queues, dev.clone());
+        assert_eq!(LittleEndian::read_u16(&read_back), VIRTIO_MSI_NO_VECTOR);
+    }
 }
@@ -13,20 +13,23 @@ extern crate vm_allocator;
 extern crate vm_memory;
 extern crate vmm_sys_util;
 
-use super::VirtioPciCommonConfig;
+use super::{restore_virtio_device, snapshot_virtio_device, VirtioPciCommonConfig};
+use crate::device::{add_snapshot_state, snapshot_state};
 use crate::transport::VirtioTransport;
 use crate::{
-    Queue, VirtioDevice, VirtioDeviceType, VirtioInterrupt, VirtioInterruptType,
-    VirtioIommuRemapping, DEVICE_ACKNOWLEDGE, DEVICE_DRIVER, DEVICE_DRIVER_OK, DEVICE_FAILED,
-    DEVICE_FEATURES_OK, DEVICE_INIT, VIRTIO_MSI_NO_VECTOR,
+    check_driver_features, DmaMapping, Queue, QueueState, VirtioDevice, VirtioDeviceType,
+    VirtioInterrupt, VirtioInterruptType, DEVICE_ACKNOWLEDGE, DEVICE_DRIVER, DEVICE_DRIVER_OK,
+    DEVICE_FAILED, DEVICE_FEATURES_OK, DEVICE_INIT, INTERRUPT_STATUS_CONFIG_CHANGED,
+    INTERRUPT_STATUS_USED_RING, VIRTIO_MSI_NO_VECTOR,
 };
 use arc_swap::ArcSwap;
 use devices::BusDevice;
 use libc::EFD_NONBLOCK;
 use pci::{
-    BarReprogrammingParams, MsixCap, MsixConfig, PciBarConfiguration, PciBarRegionType,
-    PciCapability, PciCapabilityID, PciClassCode, PciConfiguration, PciDevice, PciDeviceError,
-    PciHeaderType, PciMassStorageSubclass, PciNetworkControllerSubclass, PciSubclass,
+    BarReprogrammingParams, MsixCap, MsixConfig, MsixConfigState, PciBarConfiguration,
+    PciBarRegionType, PciCapability, PciCapabilityID, PciClassCode, PciConfiguration,
+    PciConfigurationState, PciDevice, PciDeviceError, PciHeaderType, PciMassStorageSubclass,
+    PciNetworkControllerSubclass, PciSubclass,
 };
 use std::any::Any;
 use std::cmp;
@@ -38,7 +41,7 @@ use vm_allocator::SystemAllocator;
 use vm_device::interrupt::{
     InterruptIndex, InterruptManager, InterruptSourceGroup, MsiIrqGroupConfig,
 };
-use vm_device::{Migratable, MigratableError, Pausable, Snapshotable};
+use vm_device::{Migratable, MigratableError, Pausable, Snapshot, Snapshotable};
 use vm_memory::{Address, ByteValued, GuestAddress, GuestMemoryMmap, GuestUsize, Le32};
 use vmm_sys_util::{errno::Result, eventfd::EventFd};
 
@@ -257,6 +260,9 @@ const VIRTIO_PCI_VENDOR_ID: u16 = 0x1af4;
 const VIRTIO_PCI_DEVICE_ID_BASE: u16 = 0x1040; // Add to device type to get device ID.
 
 pub struct VirtioPciDevice {
+    // Id of the device in the VM snapshot.
+    id: String,
+
     // PCI configuration registers.
     configuration: PciConfiguration,
 
@@ -272,6 +278,11 @@ pub struct VirtioPciDevice {
     // Virtio device reference and status
     device: Arc<Mutex<dyn VirtioDevice>>,
     device_activated: bool,
+    // Set once restored from the snapshot of an activated device, which is
+    // activated again when the VM is resumed.
+    activate_on_resume: bool,
+    // BARs the guest had moved before the restored snapshot was taken.
+    restored_bar_moves: Vec<BarReprogrammingParams>,
 
     // PCI interrupts.
     interrupt_status: Arc<AtomicUsize>,
@@ -281,6 +292,9 @@ pub struct VirtioPciDevice {
     // virtio queues
     queues: Vec<Queue>,
     queue_evts: Vec<EventFd>,
+    // Clones of the queue events, kept once they are handed to the device,
+    // for the notifications reaching the BAR instead of the ioeventfds.
+    notify_evts: Vec<EventFd>,
 
     // Guest memory
     memory: Option<Arc<ArcSwap<GuestMemoryMmap>>>,
@@ -306,21 +320,24 @@ impl VirtioPciDevice {
         memory: Arc<ArcSwap<GuestMemoryMmap>>,
         device: Arc<Mutex<dyn VirtioDevice>>,
         msix_num: u16,
-        iommu_mapping_cb: Option<Arc<VirtioIommuRemapping>>,
+        dma_mapping: Option<Arc<dyn DmaMapping>>,
         interrupt_manager: &Arc<dyn InterruptManager<GroupConfig = MsiIrqGroupConfig>>,
     ) -> Result<Self> {
         let device_clone = device.clone();
         let locked_device = device_clone.lock().unwrap();
         let mut queue_evts = Vec::new();
+        let mut notify_evts = Vec::new();
         for _ in locked_device.queue_max_sizes().iter() {
-            queue_evts.push(EventFd::new(EFD_NONBLOCK)?)
+            let queue_evt = EventFd::new(EFD_NONBLOCK)?;
+            notify_evts.push(queue_evt.try_clone()?);
+            queue_evts.push(queue_evt);
         }
         let queues = locked_device
             .queue_max_sizes()
             .iter()
             .map(|&s| {
                 let mut queue = Queue::new(s);
-                queue.iommu_mapping_cb = iommu_mapping_cb.clone();
+                queue.dma_mapping = dma_mapping.clone();
                 queue
             })
             .collect();
@@ -378,6 +395,7 @@ impl VirtioPciDevice {
         );
 
         let mut virtio_pci_device = VirtioPciDevice {
+            id: String::new(),
             configuration,
             common_config: VirtioPciCommonConfig {
                 driver_status: 0,
@@ -386,15 +404,21 @@ impl VirtioPciDevice {
                 driver_feature_select: 0,
                 queue_select: 0,
                 msix_config: Arc::new(AtomicU16::new(0)),
+                iommu_platform: dma_mapping.is_some(),
+                driver_features: 0,
+                msix_vectors: msix_num,
             },
             msix_config,
             msix_num,
             device,
             device_activated: false,
+            activate_on_resume: false,
+            restored_bar_moves: Vec::new(),
             interrupt_status: Arc::new(AtomicUsize::new(0)),
             virtio_interrupt: None,
             queues,
             queue_evts,
+            notify_evts,
             memory: Some(memory),
             settings_bar: 0,
             use_64bit_bar,
@@ -406,6 +430,7 @@ impl VirtioPciDevice {
             virtio_pci_device.virtio_interrupt = Some(Arc::new(VirtioInterruptMsix::new(
                 msix_config.clone(),
                 virtio_pci_device.common_config.msix_config.clone(),
+                virtio_pci_device.interrupt_status.clone(),
                 virtio_pci_device.interrupt_source_group.clone(),
             )));
         }
@@ -413,6 +438,12 @@ impl VirtioPciDevice {
         Ok(virtio_pci_device)
     }
 
+    /// Sets the id of the device in the VM snapshot, which has to be unique
+    /// among the devices of the VM for it to be saved.
+    pub fn set_id(&mut self, id: String) {
+        self.id = id;
+    }
+
     /// Gets the list of queue events that must be triggered whenever the VM writes to
     /// `virtio::NOTIFY_REG_OFFSET` past the MMIO base. Each event must be triggered when the
     /// value being written equals the index of the event in this list.
@@ -444,6 +475,97 @@ impl VirtioPciDevice {
         self.configuration.get_bar_addr(self.settings_bar as usize)
     }
 
+    /// Returns the BARs to move where they were when the restored snapshot
+    /// was taken.
+    pub fn take_restored_bar_moves(&mut self) -> Vec<BarReprogrammingParams> {
+        self.restored_bar_moves.drain(..).collect()
+    }
+
+    /// Returns the BARs mapped for this device, at their current address.
+    pub fn bars(&self) -> Vec<(GuestAddress, GuestUsize, PciBarRegionType)> {
+        let region_type = if self.use_64bit_bar {
+            PciBarRegionType::Memory64BitRegion
+        } else {
+            PciBarRegionType::Memory32BitRegion
+        };
+
+        vec![(
+            GuestAddress(self.config_bar_addr()),
+            CAPABILITY_BAR_SIZE,
+            region_type,
+        )]
+    }
+
+    pub fn virtio_device(&self) -> Arc<Mutex<dyn VirtioDevice>> {
+        self.device.clone()
+    }
+
+    /// The MSI-X interrupts of the device.
+    pub fn interrupt_group(&self) -> Arc<Box<dyn InterruptSourceGroup>> {
+        self.interrupt_source_group.clone()
+    }
+
+    /// Stops the queues of an activated device ahead of its removal. The
+    /// driver has to initialize the device again to keep using it.
+    pub fn quiesce(&mut self) {
+        if self.device_activated && !self.reset_device() {
+            error!("Attempt to quiesce device when reset is not implemented in underlying device");
+        }
+    }
+
+    /// Returns whether the device is inactive, with no request in flight,
+    /// and can be unplugged.
+    pub fn ready_to_remove(&self) -> bool {
+        !self.device_activated && self.device.lock().unwrap().ready_to_remove()
+    }
+
+    // Takes the interrupt and queue EventFDs back from the device. Returns
+    // false if the underlying device does not implement reset.
+    fn reset_device(&mut self) -> bool {
+        let mut device = self.device.lock().unwrap();
+        if let Some((virtio_interrupt, mut queue_evts)) = device.reset() {
+            // Upon reset the device returns its interrupt EventFD and it's queue EventFDs
+            self.virtio_interrupt = Some(virtio_interrupt);
+            self.queue_evts.append(&mut queue_evts);
+
+            self.device_activated = false;
+
+            // Reset queue readiness (changes queue_enable), queue sizes
+            // and selected_queue as per spec for reset
+            self.queues.iter_mut().for_each(Queue::reset);
+            self.common_config.queue_select = 0;
+            self.common_config.device_feature_select = 0;
+            self.common_config.driver_feature_select = 0;
+            self.common_config.driver_features = 0;
+            self.interrupt_status.store(0, Ordering::SeqCst);
+
+            true
+        } else {
+            false
+        }
+    }
+
+    // Hands the queues over to the device, which starts processing them.
+    fn activate(&mut self) {
+        if let Some(virtio_interrupt) = self.virtio_interrupt.take() {
+            if self.memory.is_some() {
+                let mem = self.memory.as_ref().unwrap().clone();
+                let mut device = self.device.lock().unwrap();
+                let device_type = VirtioDeviceType::from(device.device_type());
+                device
+                    .activate(
+                        mem,
+                        virtio_interrupt,
+                        self.queues.clone(),
+                        self.queue_evts.split_off(0),
+                    )
+                    .unwrap_or_else(|e| panic!("Failed to activate {} device: {}", device_type, e));
+                self.device_activated = true;
+                event!("virtio-device", "activated", "type" => device_type);
+            }
+        }
+    }
+
     fn add_pci_capabilities(
         &mut self,
         settings_bar: u8,
@@ -562,23 +684,25 @@ impl VirtioPciDevice {
 
 impl VirtioTransport for VirtioPciDevice {
     fn ioeventfds(&self, base_addr: u64) -> Vec<(&EventFd, u64)> {
-        let notify_base = base_addr + NOTIFICATION_BAR_OFFSET;
         self.queue_evts()
             .iter()
             .enumerate()
-            .map(|(i, event)| {
-                (
-                    event,
-                    notify_base + i as u64 * u64::from(NOTIFY_OFF_MULTIPLIER),
-                )
-            })
+            .map(|(i, event)| (event, base_addr + queue_notify_offset(i)))
             .collect()
     }
 }
 
+// The offset in the BAR the queue with the given index is notified at. The
+// queue_notify_off of a queue being its index, it is the index times the
+// multiplier from the notification capability.
+fn queue_notify_offset(index: usize) -> u64 {
+    NOTIFICATION_BAR_OFFSET + index as u64 * u64::from(NOTIFY_OFF_MULTIPLIER)
+}
+
 pub struct VirtioInterruptMsix {
     msix_config: Arc<Mutex<MsixConfig>>,
     config_vector: Arc<AtomicU16>,
+    interrupt_status: Arc<AtomicUsize>,
     interrupt_source_group: Arc<Box<dyn InterruptSourceGroup>>,
 }
 
@@ -586,11 +710,13 @@ impl VirtioInterruptMsix {
     pub fn new(
         msix_config: Arc<Mutex<MsixConfig>>,
         config_vector: Arc<AtomicU16>,
+        interrupt_status: Arc<AtomicUsize>,
         interrupt_source_group: Arc<Box<dyn InterruptSourceGroup>>,
     ) -> Self {
         VirtioInterruptMsix {
             msix_config,
             config_vector,
+            interrupt_status,
             interrupt_source_group,
         }
     }
@@ -602,6 +728,16 @@ impl VirtioInterrupt for VirtioInterruptMsix {
         int_type: &VirtioInterruptType,
         queue: Option<&Queue>,
     ) -> std::result::Result<(), std::io::Error> {
+        // Reflect the interrupt cause in the ISR status register, so that a
+        // driver reading it can tell a configuration change from a used ring
+        // update.
+        let status = match int_type {
+            VirtioInterruptType::Config => INTERRUPT_STATUS_CONFIG_CHANGED,
+            VirtioInterruptType::Queue => INTERRUPT_STATUS_USED_RING,
+        };
+        self.interrupt_status
+            .fetch_or(status as usize, Ordering::SeqCst);
+
         let vector = match int_type {
             VirtioInterruptType::Config => self.config_vector.load(Ordering::SeqCst),
             VirtioInterruptType::Queue => {
@@ -618,7 +754,13 @@ impl VirtioInterrupt for VirtioInterruptMsix {
         }
 
         let config = &mut self.msix_config.lock().unwrap();
-        let entry = &config.table_entries[vector as usize];
+        let entry = match config.table_entries.get(vector as usize) {
+            Some(entry) => entry,
+            None => {
+                error!("Invalid MSI-X vector {}", vector);
+                return Ok(());
+            }
+        };
         // In case the vector control register associated with the entry
         // has its first bit set, this means the vector is masked and the
         // device should not inject the interrupt.
@@ -707,7 +849,7 @@ impl PciDevice for VirtioPciDevice {
         let (virtio_pci_bar_addr, region_type) = if self.use_64bit_bar {
             let region_type = PciBarRegionType::Memory64BitRegion;
             let addr = allocator
-                .allocate_mmio_addresses(None, CAPABILITY_BAR_SIZE, None)
+                .allocate_high_mmio_addresses(None, CAPABILITY_BAR_SIZE, None)
                 .ok_or(PciDeviceError::IoAllocationFailed(CAPABILITY_BAR_SIZE))?;
             ranges.push((addr, CAPABILITY_BAR_SIZE, region_type));
             (addr, region_type)
@@ -829,7 +971,17 @@ impl PciDevice for VirtioPciDevice {
             o if NOTIFICATION_BAR_OFFSET <= o
                 && o < NOTIFICATION_BAR_OFFSET + NOTIFICATION_SIZE =>
             {
-                // Handled with ioeventfds.
+                // The ioeventfds catch the notifications written to the BAR,
+                // these ones come through VIRTIO_PCI_CAP_PCI_CFG.
+                let index = (o - NOTIFICATION_BAR_OFFSET) / u64::from(NOTIFY_OFF_MULTIPLIER);
+                match self.notify_evts.get(index as usize) {
+                    Some(notify_evt) => {
+                        if let Err(e) = notify_evt.write(1) {
+                            error!("Failed to notify queue {}: {}", index, e);
+                        }
+                    }
+                    None => warn!("Notification for a missing queue {}", index),
+                }
             }
             o if MSIX_TABLE_BAR_OFFSET <= o && o < MSIX_TABLE_BAR_OFFSET + MSIX_TABLE_SIZE => {
                 if let Some(msix_config) = &self.msix_config {
@@ -851,41 +1003,18 @@ impl PciDevice for VirtioPciDevice {
         };
 
         if !self.device_activated && self.is_driver_ready() && self.are_queues_valid() {
-            if let Some(virtio_interrupt) = self.virtio_interrupt.take() {
-                if self.memory.is_some() {
-                    let mem = self.memory.as_ref().unwrap().clone();
-                    let mut device = self.device.lock().unwrap();
-                    device
-                        .activate(
-                            mem,
-                            virtio_interrupt,
-                            self.queues.clone(),
-                            self.queue_evts.split_off(0),
-                        )
-                        .expect("Failed to activate device");
-                    self.device_activated = true;
-                }
+            if let Err(e) = check_driver_features(self.common_config.driver_features) {
+                error!("Refusing to activate device: {:?}", e);
+                self.common_config.driver_status = DEVICE_FAILED as u8;
+            } else {
+                self.activate();
             }
         }
 
         // Device has been reset by the driver
-        if self.device_activated && self.is_driver_init() {
-            let mut device = self.device.lock().unwrap();
-            if let Some((virtio_interrupt, mut queue_evts)) = device.reset() {
-                // Upon reset the device returns its interrupt EventFD and it's queue EventFDs
-                self.virtio_interrupt = Some(virtio_interrupt);
-                self.queue_evts.append(&mut queue_evts);
-
-                self.device_activated = false;
-
-                // Reset queue readiness (changes queue_enable), queue sizes
-                // and selected_queue as per spec for reset
-                self.queues.iter_mut().for_each(Queue::reset);
-                self.common_config.queue_select = 0;
-            } else {
-                error!("Attempt to reset device when not implemented in underlying device");
-                self.common_config.driver_status = crate::DEVICE_FAILED as u8;
-            }
+        if self.device_activated && self.is_driver_init() && !self.reset_device() {
+            error!("Attempt to reset device when not implemented in underlying device");
+            self.common_config.driver_status = crate::DEVICE_FAILED as u8;
         }
     }
 
@@ -910,9 +1039,431 @@ impl Pausable for VirtioPciDevice {
     }
 
     fn resume(&mut self) -> result::Result<(), MigratableError> {
+        if self.activate_on_resume {
+            self.activate_on_resume = false;
+            // The guest notified the queues before the snapshot, the device
+            // has to look at them once activated.
+            for queue_evt in self.queue_evts.iter() {
+                queue_evt
+                    .write(1)
+                    .map_err(|e| MigratableError::Resume(e.into()))?;
+            }
+            self.activate();
+        }
+
         Ok(())
     }
 }
 
-impl Snapshotable for VirtioPciDevice {}
+#[derive(Deserialize, Serialize)]
+struct VirtioPciDeviceState {
+    device_activated: bool,
+    interrupt_status: usize,
+    driver_status: u8,
+    config_generation: u8,
+    device_feature_select: u32,
+    driver_feature_select: u32,
+    queue_select: u16,
+    msix_config: u16,
+    driver_features: u64,
+    queues: Vec<QueueState>,
+    configuration: PciConfigurationState,
+    msix: Option<MsixConfigState>,
+}
+
+impl Snapshotable for VirtioPciDevice {
+    fn id(&self) -> String {
+        self.id.clone()
+    }
+
+    fn snapshot(&self) -> result::Result<Snapshot, MigratableError> {
+        let mem = self
+            .memory
+            .as_ref()
+            .ok_or_else(|| MigratableError::Snapshot(anyhow!("No guest memory")))?
+            .load();
+
+        let mut snapshot = Snapshot::new(&self.id());
+        add_snapshot_state(
+            &mut snapshot,
+            &VirtioPciDeviceState {
+                device_activated: self.device_activated || self.activate_on_resume,
+                interrupt_status: self.interrupt_status.load(Ordering::SeqCst),
+                driver_status: self.common_config.driver_status,
+                config_generation: self.common_config.config_generation,
+                device_feature_select: self.common_config.device_feature_select,
+                driver_feature_select: self.common_config.driver_feature_select,
+                queue_select: self.common_config.queue_select,
+                msix_config: self.common_config.msix_config.load(Ordering::SeqCst),
+                driver_features: self.common_config.driver_features,
+                queues: self.queues.iter().map(|q| q.state(&mem)).collect(),
+                configuration: self.configuration.state(),
+                msix: self
+                    .msix_config
+                    .as_ref()
+                    .map(|msix_config| msix_config.lock().unwrap().state()),
+            },
+        )?;
+        snapshot.add_snapshot(snapshot_virtio_device(&self.device)?);
+
+        Ok(snapshot)
+    }
+
+    fn restore(&mut self, mut snapshot: Snapshot) -> result::Result<(), MigratableError> {
+        if self.device_activated {
+            return Err(MigratableError::Restore(anyhow!(
+                "Device already activated"
+            )));
+        }
+        let state: VirtioPciDeviceState = snapshot_state(&snapshot)?
+            .ok_or_else(|| MigratableError::Restore(anyhow!("Missing virtio-pci state")))?;
+        if state.queues.len() != self.queues.len() {
+            return Err(MigratableError::Restore(anyhow!(
+                "{} queues instead of {}",
+                state.queues.len(),
+                self.queues.len()
+            )));
+        }
+
+        restore_virtio_device(&self.device, &mut snapshot)?;
+        // For the devices not saving the features the driver acked.
+        self.device
+            .lock()
+            .unwrap()
+            .ack_features(state.driver_features);
+
+        for (queue, queue_state) in self.queues.iter_mut().zip(state.queues.iter()) {
+            queue
+                .set_state(queue_state)
+                .map_err(|e| MigratableError::Restore(e.into()))?;
+        }
+        self.restored_bar_moves = self
+            .configuration
+            .set_state(&state.configuration)
+            .map_err(|e| MigratableError::Restore(e.into()))?;
+        // This sets the interrupt routes up again.
+        if let (Some(msix_config), Some(msix_state)) = (&self.msix_config, &state.msix) {
+            msix_config
+                .lock()
+                .unwrap()
+                .set_state(msix_state)
+                .map_err(|e| MigratableError::Restore(e.into()))?;
+        }
+
+        self.common_config.driver_status = state.driver_status;
+        self.common_config.config_generation = state.config_generation;
+        self.common_config.device_feature_select = state.device_feature_select;
+        self.common_config.driver_feature_select = state.driver_feature_select;
+        self.common_config.queue_select = state.queue_select;
+        self.common_config
+            .msix_config
+            .store(state.msix_config, Ordering::SeqCst);
+        self.common_config.driver_features = state.driver_features;
+        self.interrupt_status
+            .store(state.interrupt_status, Ordering::SeqCst);
+        self.activate_on_resume = state.device_activated;
+
+        Ok(())
+    }
+}
 impl Migratable for VirtioPciDevice {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{create_guest_memory, Buffer, VirtqueueBuilder};
+    use crate::{ActivateResult, Block, VIRTIO_F_VERSION_1};
+    use std::io::Cursor;
+    use std::path::PathBuf;
+    use std::thread;
+    use std::time::{Duration, Instant};
+    use virtio_bindings::bindings::virtio_blk::{VIRTIO_BLK_S_OK, VIRTIO_BLK_T_IN};
+    use vm_device::interrupt::InterruptSourceConfig;
+    use vm_memory::Bytes;
+
+    #[derive(Default)]
+    struct DummyDevice {
+        interrupt_cb: Option<Arc<dyn VirtioInterrupt>>,
+        queue_evts: Option<Vec<EventFd>>,
+    }
+    const QUEUE_SIZES: &[u16] = &[256];
+
+    impl VirtioDevice for DummyDevice {
+        fn device_type(&self) -> u32 {
+            0
+        }
+
+        fn queue_max_sizes(&self) -> &[u16] {
+            QUEUE_SIZES
+        }
+
+        fn activate(
+            &mut self,
+            _mem: Arc<ArcSwap<GuestMemoryMmap>>,
+            interrupt_evt: Arc<dyn VirtioInterrupt>,
+            _queues: Vec<Queue>,
+            queue_evts: Vec<EventFd>,
+        ) -> ActivateResult {
+            self.interrupt_cb = Some(interrupt_evt);
+            self.queue_evts = Some(queue_evts);
+            Ok(())
+        }
+
+        fn reset(&mut self) -> Option<(Arc<dyn VirtioInterrupt>, Vec<EventFd>)> {
+            Some((self.interrupt_cb.take()?, self.queue_evts.take()?))
+        }
+
+        fn read_config(&self, _offset: u64, _data: &mut [u8]) {}
+
+        fn write_config(&mut self, _offset: u64, _data: &[u8]) {}
+    }
+
+    impl Snapshotable for DummyDevice {}
+
+    struct DummyInterruptSourceGroup;
+
+    impl InterruptSourceGroup for DummyInterruptSourceGroup {
+        fn trigger(&self, _index: InterruptIndex) -> std::io::Result<()> {
+            Ok(())
+        }
+
+        fn update(
+            &self,
+            _index: InterruptIndex,
+            _config: InterruptSourceConfig,
+        ) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    struct DummyInterruptManager;
+
+    impl InterruptManager for DummyInterruptManager {
+        type GroupConfig = MsiIrqGroupConfig;
+
+        fn create_group(
+            &self,
+            _config: Self::GroupConfig,
+        ) -> std::io::Result<Arc<Box<dyn InterruptSourceGroup>>> {
+            Ok(Arc::new(Box::new(DummyInterruptSourceGroup)))
+        }
+
+        fn destroy_group(&self, _group: Arc<Box<dyn InterruptSourceGroup>>) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn create_virtio_pci_device() -> VirtioPciDevice {
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let interrupt_manager: Arc<dyn InterruptManager<GroupConfig = MsiIrqGroupConfig>> =
+            Arc::new(DummyInterruptManager);
+
+        VirtioPciDevice::new(
+            Arc::new(ArcSwap::from(Arc::new(mem))),
+            Arc::new(Mutex::new(DummyDevice::default())),
+            2,
+            None,
+            &interrupt_manager,
+        )
+        .unwrap()
+    }
+
+    fn read_isr(device: &mut VirtioPciDevice) -> u8 {
+        let mut data = [0u8];
+        device.read_bar(0, ISR_CONFIG_BAR_OFFSET, &mut data);
+        data[0]
+    }
+
+    #[test]
+    fn test_signal_config_change() {
+        let mut device = create_virtio_pci_device();
+        let virtio_interrupt = device.virtio_interrupt.clone().unwrap();
+
+        // Signaling a configuration change sets the status bit, and reading
+        // the ISR register acknowledges it.
+        virtio_interrupt.signal_config_change().unwrap();
+        assert_eq!(read_isr(&mut device), INTERRUPT_STATUS_CONFIG_CHANGED as u8);
+        assert_eq!(read_isr(&mut device), 0);
+
+        // The driver can also acknowledge a single bit by writing it back.
+        virtio_interrupt.signal_config_change().unwrap();
+        virtio_interrupt
+            .trigger(&VirtioInterruptType::Queue, None)
+            .unwrap();
+        device.write_bar(
+            0,
+            ISR_CONFIG_BAR_OFFSET,
+            &[INTERRUPT_STATUS_CONFIG_CHANGED as u8],
+        );
+        assert_eq!(read_isr(&mut device), INTERRUPT_STATUS_USED_RING as u8);
+    }
+
+    #[test]
+    fn test_queue_notification() {
+        let mut device = create_virtio_pci_device();
+        let base_addr = 0x1000_0000;
+
+        // Each queue is notified at its notify_off times the multiplier.
+        let ioeventfds: Vec<u64> = device
+            .ioeventfds(base_addr)
+            .iter()
+            .map(|(_, addr)| *addr)
+            .collect();
+        assert_eq!(ioeventfds, vec![base_addr + NOTIFICATION_BAR_OFFSET]);
+        let mut notify_off = [0u8; 2];
+        device.read_bar(0, COMMON_CONFIG_BAR_OFFSET + 0x1e, &mut notify_off);
+        assert_eq!(u16::from_le_bytes(notify_off), 0);
+
+        // A notification missing the ioeventfd still reaches the queue.
+        device.write_bar(0, NOTIFICATION_BAR_OFFSET, &[0, 0]);
+        assert_eq!(device.queue_evts[0].read().unwrap(), 1);
+
+        // The offset of a missing queue is ignored.
+        device.write_bar(
+            0,
+            NOTIFICATION_BAR_OFFSET + u64::from(NOTIFY_OFF_MULTIPLIER),
+            &[1, 0],
+        );
+        assert!(device.queue_evts[0].read().is_err());
+    }
+
+    fn write_common_config(device: &mut VirtioPciDevice, offset: u64, data: &[u8]) {
+        device.write_bar(0, COMMON_CONFIG_BAR_OFFSET + offset, data);
+    }
+
+    fn initialize_driver(device: &mut VirtioPciDevice) {
+        let status = (DEVICE_ACKNOWLEDGE | DEVICE_DRIVER) as u8;
+        write_common_config(device, 0x14, &[status]);
+        // VIRTIO_F_VERSION_1 is in the second page of features.
+        write_common_config(device, 0x08, &1u32.to_le_bytes());
+        write_common_config(
+            device,
+            0x0c,
+            &(1u32 << (VIRTIO_F_VERSION_1 - 32)).to_le_bytes(),
+        );
+        let status = status | DEVICE_FEATURES_OK as u8;
+        write_common_config(device, 0x14, &[status]);
+
+        write_common_config(device, 0x16, &[0, 0]);
+        write_common_config(device, 0x20, &0x1000u64.to_le_bytes());
+        write_common_config(device, 0x28, &0x3000u64.to_le_bytes());
+        write_common_config(device, 0x30, &0x4000u64.to_le_bytes());
+        write_common_config(device, 0x1c, &[1, 0]);
+        write_common_config(device, 0x14, &[status | DEVICE_DRIVER_OK as u8]);
+    }
+
+    #[test]
+    fn test_driver_reset() {
+        let mut device = create_virtio_pci_device();
+        initialize_driver(&mut device);
+        assert!(device.device_activated);
+        assert!(device.queue_evts.is_empty());
+        assert!(device.virtio_interrupt.is_none());
+
+        // Writing zero to the status stops the device, and takes the queue
+        // and interrupt EventFDs back from it.
+        write_common_config(&mut device, 0x14, &[DEVICE_INIT as u8]);
+        assert!(!device.device_activated);
+        assert_eq!(device.queue_evts.len(), 1);
+        assert!(device.virtio_interrupt.is_some());
+        assert!(!device.queues[0].ready);
+        assert_eq!(device.common_config.driver_features, 0);
+
+        // The driver can then initialize the device from scratch.
+        initialize_driver(&mut device);
+        assert!(device.device_activated);
+        assert_eq!(
+            device.common_config.driver_features,
+            1u64 << VIRTIO_F_VERSION_1
+        );
+    }
+
+    #[test]
+    fn test_snapshot_restore_with_requests_in_flight() {
+        let mem = create_guest_memory(0x10000);
+        let memory = Arc::new(ArcSwap::from(Arc::new(mem.clone())));
+        let interrupt_manager: Arc<dyn InterruptManager<GroupConfig = MsiIrqGroupConfig>> =
+            Arc::new(DummyInterruptManager);
+        let mut disk = vec![0u8; 0x1000];
+        disk[0x200..0x400].copy_from_slice(&[0x5a; 0x200]);
+        let create_device = |disk: Vec<u8>| {
+            let block = Block::new(
+                Cursor::new(disk),
+                PathBuf::from("/dev/null"),
+                false,
+                false,
+                1,
+                16,
+            )
+            .unwrap();
+            let block = Arc::new(Mutex::new(block));
+            let mut device =
+                VirtioPciDevice::new(memory.clone(), block.clone(), 2, None, &interrupt_manager)
+                    .unwrap();
+            device.set_id("virtio-pci-01".to_string());
+            (device, block)
+        };
+
+        // The rings where initialize_driver() puts them.
+        let mut vq = VirtqueueBuilder::new(&mem, 16)
+            .desc_table(GuestAddress(0x1000))
+            .avail_ring(GuestAddress(0x3000))
+            .used_ring(GuestAddress(0x4000))
+            .build();
+        let (mut device, block) = create_device(disk.clone());
+        initialize_driver(&mut device);
+        assert!(device.device_activated);
+
+        // Two reads of the second sector are made available, but the device
+        // is paused before being notified.
+        let mut heads = Vec::new();
+        for i in 0..2 {
+            let header = GuestAddress(0x8000 + i * 0x1000);
+            mem.write_obj(VIRTIO_BLK_T_IN, header).unwrap();
+            mem.write_obj(1u64, header.unchecked_add(8)).unwrap();
+            heads.push(vq.add_chain(&[
+                Buffer::readable(header, 16),
+                Buffer::writable(header.unchecked_add(0x100), 0x200),
+                Buffer::writable(header.unchecked_add(0x300), 1),
+            ]));
+            mem.write_obj(0xffu8, header.unchecked_add(0x300)).unwrap();
+        }
+        block.lock().unwrap().pause().unwrap();
+        let snapshot = device.snapshot().unwrap();
+        assert_eq!(snapshot.id, "virtio-pci-01");
+        assert_eq!(vq.used_idx(), 0);
+
+        // The snapshot goes through its serialized form, as in a VM one.
+        let snapshot: Snapshot =
+            serde_json::from_slice(&serde_json::to_vec(&snapshot).unwrap()).unwrap();
+        let (mut restored, _block) = create_device(disk);
+        restored.restore(snapshot).unwrap();
+        assert!(!restored.device_activated);
+        assert_eq!(
+            restored.common_config.driver_features,
+            1u64 << VIRTIO_F_VERSION_1
+        );
+        assert_eq!(restored.queues[0].desc_table, GuestAddress(0x1000));
+        assert_eq!(restored.queues[0].next_avail.0, 0);
+
+        // Resuming activates the device, which runs the requests it hadn't
+        // completed.
+        restored.resume().unwrap();
+        assert!(restored.device_activated);
+        let start = Instant::now();
+        while vq.used_idx() != 2 {
+            assert!(start.elapsed() < Duration::from_secs(5));
+            thread::sleep(Duration::from_millis(10));
+        }
+        for (slot, &head) in heads.iter().enumerate() {
+            assert_eq!(vq.used_elem(slot as u16), (u32::from(head), 0x201));
+            let header = GuestAddress(0x8000 + slot as u64 * 0x1000);
+            let status: u8 = mem.read_obj(header.unchecked_add(0x300)).unwrap();
+            assert_eq!(u32::from(status), VIRTIO_BLK_S_OK);
+            let mut data = [0u8; 0x200];
+            mem.read_slice(&mut data, header.unchecked_add(0x100))
+                .unwrap();
+            assert!(data.iter().all(|&b| b == 0x5a));
+        }
+    }
+}
@@ -7,7 +7,7 @@ use super::vu_common_ctrl::*;
 use super::Error as DeviceError;
 use super::{Error, Result};
 use crate::block::VirtioBlockConfig;
-use crate::VirtioInterrupt;
+use crate::{join_epoll_threads, VirtioInterrupt};
 use arc_swap::ArcSwap;
 use libc;
 use libc::EFD_NONBLOCK;
@@ -307,6 +307,10 @@ impl VirtioDevice for Blk {
             // Ignore the result because there is nothing we can do about it.
             let _ = kill_evt.write(1);
         }
+        join_epoll_threads(self.epoll_threads.take());
+
+        // The protocol features stay acked with the backend.
+        self.acked_features &= VhostUserVirtioFeatures::PROTOCOL_FEATURES.bits();
 
         // Return the interrupt and queue EventFDs
         Some((
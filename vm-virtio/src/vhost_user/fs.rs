@@ -6,8 +6,8 @@ use super::Error as DeviceError;
 use super::{Error, Result};
 use crate::vhost_user::handler::{VhostUserEpollConfig, VhostUserEpollHandler};
 use crate::{
-    ActivateError, ActivateResult, Queue, VirtioDevice, VirtioDeviceType, VirtioInterrupt,
-    VirtioSharedMemoryList, VIRTIO_F_VERSION_1,
+    join_epoll_threads, ActivateError, ActivateResult, Queue, VirtioDevice, VirtioDeviceType,
+    VirtioInterrupt, VirtioSharedMemoryList, VIRTIO_F_VERSION_1,
 };
 use arc_swap::ArcSwap;
 use libc::{self, EFD_NONBLOCK};
@@ -447,6 +447,10 @@ impl VirtioDevice for Fs {
             // Ignore the result because there is nothing we can do about it.
             let _ = kill_evt.write(1);
         }
+        join_epoll_threads(self.epoll_threads.take());
+
+        // The protocol features stay acked with the backend.
+        self.acked_features &= VhostUserVirtioFeatures::PROTOCOL_FEATURES.bits();
 
         // Return the interrupt and queue EventFDs
         Some((
@@ -8,6 +8,7 @@ extern crate virtio_bindings;
 extern crate vm_memory;
 
 use std;
+use std::fmt;
 use std::io;
 use vhost_rs::Error as VhostError;
 use vm_memory::Error as MmapError;
@@ -101,3 +102,83 @@ pub enum Error {
     InvalidFeatures,
 }
 type Result<T> = std::result::Result<T, Error>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::AvailAddress => write!(f, "invalid available address"),
+            Error::BadQueueNum => write!(f, "queue number is not correct"),
+            Error::CreateKillEventFd(_) => write!(f, "creating kill eventfd failed"),
+            Error::CloneKillEventFd(_) => write!(f, "cloning kill eventfd failed"),
+            Error::DescriptorTableAddress => write!(f, "invalid descriptor table address"),
+            Error::EpollCreateFd(_) => write!(f, "failed to create the epoll fd"),
+            Error::EpollCtl(_) => write!(f, "epoll ctl error"),
+            Error::EpollWait(_) => write!(f, "epoll wait error"),
+            Error::FailedReadingQueue(_) => write!(f, "read queue failed"),
+            Error::FailedSignalingUsedQueue(_) => write!(f, "signal used queue failed"),
+            Error::MemoryRegions(_) => write!(f, "failed to get the guest memory regions"),
+            Error::VhostUserCreateMaster(e) => write!(f, "failed to create master: {}", e),
+            Error::VhostUserOpen(e) => write!(f, "failed to open vhost device: {}", e),
+            Error::VhostUserConnect(e) => write!(f, "connection to socket failed: {}", e),
+            Error::VhostUserGetFeatures(e) => write!(f, "get features failed: {}", e),
+            Error::VhostUserGetQueueMaxNum(e) => write!(f, "get queue max number failed: {}", e),
+            Error::VhostUserGetProtocolFeatures(e) => {
+                write!(f, "get protocol features failed: {}", e)
+            }
+            Error::VhostUserProtocolNotSupport => write!(
+                f,
+                "the vhost-user backend does not support the vhost-user protocol features"
+            ),
+            Error::VhostUserSetOwner(e) => write!(f, "set owner failed: {}", e),
+            Error::VhostUserResetOwner(e) => write!(f, "reset owner failed: {}", e),
+            Error::VhostUserSetFeatures(e) => write!(f, "set features failed: {}", e),
+            Error::VhostUserSetProtocolFeatures(e) => {
+                write!(f, "set protocol features failed: {}", e)
+            }
+            Error::VhostUserSetMemTable(e) => write!(f, "set mem table failed: {}", e),
+            Error::VhostUserSetVringNum(e) => write!(f, "set vring num failed: {}", e),
+            Error::VhostUserSetVringAddr(e) => write!(f, "set vring addr failed: {}", e),
+            Error::VhostUserSetVringBase(e) => write!(f, "set vring base failed: {}", e),
+            Error::VhostUserSetVringCall(e) => write!(f, "set vring call failed: {}", e),
+            Error::VhostUserSetVringKick(e) => write!(f, "set vring kick failed: {}", e),
+            Error::VhostUserSetVringEnable(e) => write!(f, "set vring enable failed: {}", e),
+            Error::VhostIrqCreate(_) => write!(f, "failed to create vhost eventfd"),
+            Error::VhostIrqRead(_) => write!(f, "failed to read vhost eventfd"),
+            Error::VhostUserMemoryRegion(_) => {
+                write!(f, "failed to describe a vhost-user memory region")
+            }
+            Error::VhostUserSlaveRequest(e) => {
+                write!(f, "failed to handle vhost-user slave request: {}", e)
+            }
+            Error::MasterReqHandlerCreation(e) => write!(
+                f,
+                "failed to create the master request handler from slave: {}",
+                e
+            ),
+            Error::VhostUserSetSlaveRequestFd(e) => write!(f, "set slave request fd failed: {}", e),
+            Error::UsedAddress => write!(f, "invalid used address"),
+            Error::InvalidFeatures => {
+                write!(f, "invalid features provided from vhost-user backend")
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::CreateKillEventFd(e) => Some(e),
+            Error::CloneKillEventFd(e) => Some(e),
+            Error::EpollCreateFd(e) => Some(e),
+            Error::EpollCtl(e) => Some(e),
+            Error::EpollWait(e) => Some(e),
+            Error::FailedReadingQueue(e) => Some(e),
+            Error::FailedSignalingUsedQueue(e) => Some(e),
+            Error::MemoryRegions(e) => Some(e),
+            Error::VhostIrqCreate(e) => Some(e),
+            Error::VhostIrqRead(e) => Some(e),
+            Error::VhostUserMemoryRegion(e) => Some(e),
+            _ => None,
+        }
+    }
+}
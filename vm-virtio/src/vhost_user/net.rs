@@ -2,7 +2,7 @@
 // SPDX-License-Identifier: Apache-2.0
 
 use super::super::net_util::{
-    build_net_config_space, CtrlVirtio, NetCtrlEpollHandler, VirtioNetConfig,
+    build_net_config_space, CtrlVirtio, NetCtrlEpollHandler, RxFilter, VirtioNetConfig,
 };
 use super::super::Error as CtrlError;
 use super::super::{ActivateError, ActivateResult, Queue, VirtioDevice, VirtioDeviceType};
@@ -10,7 +10,7 @@ use super::handler::*;
 use super::vu_common_ctrl::*;
 use super::Error as DeviceError;
 use super::{Error, Result};
-use crate::VirtioInterrupt;
+use crate::{join_epoll_threads, VirtioInterrupt};
 use arc_swap::ArcSwap;
 use libc;
 use libc::EFD_NONBLOCK;
@@ -19,7 +19,7 @@ use std::cmp;
 use std::io::Write;
 use std::result;
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 use std::thread;
 use std::vec::Vec;
 use vhost_rs::vhost_user::message::{VhostUserProtocolFeatures, VhostUserVirtioFeatures};
@@ -273,11 +273,18 @@ impl VirtioDevice for Net {
             let cvq_queue = queues.remove(queue_num - 1);
             let cvq_queue_evt = queue_evts.remove(queue_num - 1);
 
+            // The backend does the receiving, RX filtering isn't offered.
             let mut ctrl_handler = NetCtrlEpollHandler {
                 mem: mem.clone(),
+                interrupt_cb: interrupt_cb.clone(),
                 kill_evt: kill_evt.try_clone().unwrap(),
                 pause_evt: pause_evt.try_clone().unwrap(),
-                ctrl_q: CtrlVirtio::new(cvq_queue, cvq_queue_evt),
+                ctrl_q: CtrlVirtio::new(
+                    cvq_queue,
+                    cvq_queue_evt,
+                    Arc::new(Mutex::new(RxFilter::new(None))),
+                    None,
+                ),
                 epoll_fd: 0,
             };
 
@@ -347,6 +354,15 @@ impl VirtioDevice for Net {
             // Ignore the result because there is nothing we can do about it.
             let _ = kill_evt.write(1);
         }
+        join_epoll_threads(self.epoll_threads.take());
+        join_epoll_threads(
+            self.ctrl_queue_epoll_thread
+                .take()
+                .map(|thread| vec![thread]),
+        );
+
+        // The protocol features stay acked with the backend.
+        self.acked_features &= VhostUserVirtioFeatures::PROTOCOL_FEATURES.bits();
 
         // Return the interrupt and queue EventFDs
         Some((
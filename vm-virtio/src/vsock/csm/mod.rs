@@ -9,6 +9,8 @@ mod txbuf;
 
 pub use connection::VsockConnection;
 
+use std::fmt;
+
 pub mod defs {
     /// Vsock connection TX buffer capacity.
     pub const CONN_TX_BUF_SIZE: usize = 64 * 1024;
@@ -34,6 +36,32 @@ pub enum Error {
     StreamWrite(std::io::Error),
 }
 
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::TxBufFull => write!(f, "attempted to push data to a full TX buffer"),
+            Error::TxBufFlush(_) => write!(
+                f,
+                "an I/O error occurred, when attempting to flush the connection TX buffer"
+            ),
+            Error::StreamWrite(_) => write!(
+                f,
+                "an I/O error occurred, when attempting to write data to the host-side stream"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::TxBufFlush(e) => Some(e),
+            Error::StreamWrite(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
 type Result<T> = std::result::Result<T, Error>;
 
 /// A vsock connection state.
@@ -10,7 +10,7 @@
 
 use super::{VsockBackend, VsockPacket};
 use crate::Error as DeviceError;
-use crate::VirtioInterrupt;
+use crate::{join_epoll_threads, VirtioInterrupt};
 use crate::{
     ActivateError, ActivateResult, DeviceEventT, Queue, VirtioDevice, VirtioDeviceType,
     VirtioInterruptType, VIRTIO_F_IN_ORDER, VIRTIO_F_IOMMU_PLATFORM, VIRTIO_F_VERSION_1,
@@ -562,6 +562,9 @@ where
             // Ignore the result because there is nothing we can do about it.
             let _ = kill_evt.write(1);
         }
+        join_epoll_threads(self.epoll_threads.take());
+
+        self.acked_features = 0;
 
         // Return the interrupt and queue EventFDs
         Some((
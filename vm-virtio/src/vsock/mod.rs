@@ -18,6 +18,7 @@ pub use self::unix::VsockUnixBackend;
 pub use self::unix::VsockUnixError;
 
 use packet::VsockPacket;
+use std::fmt;
 use std::os::unix::io::RawFd;
 
 mod defs {
@@ -88,6 +89,54 @@ pub enum VsockError {
 }
 type Result<T> = std::result::Result<T, VsockError>;
 
+impl fmt::Display for VsockError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VsockError::BufDescTooSmall => write!(
+                f,
+                "the vsock data/buffer virtio descriptor length is smaller than expected"
+            ),
+            VsockError::BufDescMissing => write!(
+                f,
+                "the vsock data/buffer virtio descriptor is expected, but missing"
+            ),
+            VsockError::GuestMemory => write!(f, "chained GuestMemory error"),
+            VsockError::GuestMemoryBounds => {
+                write!(f, "bounds check failed on guest memory pointer")
+            }
+            VsockError::HdrDescTooSmall(e) => {
+                write!(f, "the vsock header descriptor length is too small: {}", e)
+            }
+            VsockError::InvalidPktLen(e) => write!(
+                f,
+                "the vsock header `len` field holds an invalid value: {}",
+                e
+            ),
+            VsockError::NoData => {
+                write!(f, "a data fetch was attempted when no data was available")
+            }
+            VsockError::PktBufMissing => write!(
+                f,
+                "a data buffer was expected for the provided packet, but it is missing"
+            ),
+            VsockError::UnreadableDescriptor => {
+                write!(f, "encountered an unexpected write-only virtio descriptor")
+            }
+            VsockError::UnwritableDescriptor => {
+                write!(f, "encountered an unexpected read-only virtio descriptor")
+            }
+        }
+    }
+}
+
+impl std::error::Error for VsockError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            _ => None,
+        }
+    }
+}
+
 #[derive(Debug)]
 pub enum VsockEpollHandlerError {
     /// The vsock data/buffer virtio descriptor length is smaller than expected.
@@ -112,6 +161,54 @@ pub enum VsockEpollHandlerError {
     UnwritableDescriptor,
 }
 
+impl fmt::Display for VsockEpollHandlerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VsockEpollHandlerError::BufDescTooSmall => write!(
+                f,
+                "the vsock data/buffer virtio descriptor length is smaller than expected"
+            ),
+            VsockEpollHandlerError::BufDescMissing => write!(
+                f,
+                "the vsock data/buffer virtio descriptor is expected, but missing"
+            ),
+            VsockEpollHandlerError::GuestMemory => write!(f, "chained GuestMemory error"),
+            VsockEpollHandlerError::GuestMemoryBounds => {
+                write!(f, "bounds check failed on guest memory pointer")
+            }
+            VsockEpollHandlerError::HdrDescTooSmall(e) => {
+                write!(f, "the vsock header descriptor length is too small: {}", e)
+            }
+            VsockEpollHandlerError::InvalidPktLen(e) => write!(
+                f,
+                "the vsock header `len` field holds an invalid value: {}",
+                e
+            ),
+            VsockEpollHandlerError::NoData => {
+                write!(f, "a data fetch was attempted when no data was available")
+            }
+            VsockEpollHandlerError::PktBufMissing => write!(
+                f,
+                "a data buffer was expected for the provided packet, but it is missing"
+            ),
+            VsockEpollHandlerError::UnreadableDescriptor => {
+                write!(f, "encountered an unexpected write-only virtio descriptor")
+            }
+            VsockEpollHandlerError::UnwritableDescriptor => {
+                write!(f, "encountered an unexpected read-only virtio descriptor")
+            }
+        }
+    }
+}
+
+impl std::error::Error for VsockEpollHandlerError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            _ => None,
+        }
+    }
+}
+
 /// A passive, event-driven object, that needs to be notified whenever an epoll-able event occurs.
 /// An event-polling control loop will use `get_polled_fd()` and `get_polled_evset()` to query
 /// the listener for the file descriptor and the set of events it's interested in. When such an
@@ -15,6 +15,8 @@ mod muxer_rxq;
 pub use muxer::VsockMuxer as VsockUnixBackend;
 pub use Error as VsockUnixError;
 
+use std::fmt;
+
 mod defs {
     /// Maximum number of established connections that we can handle.
     pub const MAX_CONNECTIONS: usize = 1023;
@@ -52,5 +54,45 @@ pub enum Error {
     TooManyConnections,
 }
 
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::ConvertFromUTF8(_) => write!(f, "error converting from UTF-8"),
+            Error::EpollAdd(_) => write!(f, "error registering a new epoll-listening FD"),
+            Error::EpollFdCreate(_) => write!(f, "error creating an epoll FD"),
+            Error::InvalidPortRequest => {
+                write!(f, "the host made an invalid vsock port connection request")
+            }
+            Error::ParseInteger(_) => write!(f, "error parsing integer"),
+            Error::ReadStreamPort(_) => write!(f, "error reading stream port"),
+            Error::UnixAccept(_) => write!(
+                f,
+                "error accepting a new connection from the host-side Unix socket"
+            ),
+            Error::UnixBind(_) => write!(f, "error binding to the host-side Unix socket"),
+            Error::UnixConnect(_) => write!(f, "error connecting to a host-side Unix socket"),
+            Error::UnixRead(_) => write!(f, "error reading from host-side Unix socket"),
+            Error::TooManyConnections => write!(f, "muxer connection limit reached"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::ConvertFromUTF8(e) => Some(e),
+            Error::EpollAdd(e) => Some(e),
+            Error::EpollFdCreate(e) => Some(e),
+            Error::ParseInteger(e) => Some(e),
+            Error::ReadStreamPort(e) => Some(e),
+            Error::UnixAccept(e) => Some(e),
+            Error::UnixBind(e) => Some(e),
+            Error::UnixConnect(e) => Some(e),
+            Error::UnixRead(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
 type Result<T> = std::result::Result<T, Error>;
 type MuxerConnection = super::csm::VsockConnection<std::os::unix::net::UnixStream>;
@@ -24,7 +24,10 @@
 ///       the host is ready to issue a vsock connection request, informing us of the
 ///       destination port to which it wants to connect);
 ///    3. Some event was triggered for a connected Unix socket, that belongs to a
-///       `VsockConnection`.
+///       `VsockConnection`;
+///    4. A new host-initiated connection is ready to be accepted from a Unix socket listening
+///       on behalf of a given guest port. No "connect" command is expected on those, so that
+///       any AF_UNIX client can reach the guest port directly.
 ///    The muxer gets notified about all of these events, because, as a `VsockEpollListener`
 ///    implementor, it gets to register a nested epoll FD into the main VMM epolling loop. All
 ///    other pollable FDs are then registered under this nested epoll FD.
@@ -82,6 +85,8 @@ enum EpollListener {
     /// A listener interested in reading host "connect <port>" commands from a freshly
     /// connected host socket.
     LocalStream(UnixStream),
+    /// A listener interested in new host-initiated connections to the guest `port`.
+    PortSock { sock: UnixListener, port: u32 },
 }
 
 /// The vsock connection multiplexer.
@@ -114,6 +119,9 @@ pub struct VsockMuxer {
     local_port_set: HashSet<u32>,
     /// The last used host-side port.
     local_port_last: u32,
+    /// The host-side ports of the connections accepted from a port listener. The host end of
+    /// those doesn't expect the "OK <port>" acknowledgement.
+    port_sock_local_ports: HashSet<u32>,
 }
 
 impl VsockChannel for VsockMuxer {
@@ -345,12 +353,24 @@ impl VsockMuxer {
             killq: MuxerKillQ::new(),
             local_port_last: (1u32 << 30) - 1,
             local_port_set: HashSet::with_capacity(defs::MAX_CONNECTIONS),
+            port_sock_local_ports: HashSet::new(),
         };
 
         muxer.add_listener(muxer.host_sock.as_raw_fd(), EpollListener::HostSock)?;
         Ok(muxer)
     }
 
+    /// Listen on the Unix socket at `path`, forwarding every connection accepted from it to
+    /// the guest `port`.
+    ///
+    pub fn add_port_listener(&mut self, port: u32, path: &str) -> Result<()> {
+        let sock = UnixListener::bind(path)
+            .and_then(|sock| sock.set_nonblocking(true).map(|_| sock))
+            .map_err(Error::UnixBind)?;
+
+        self.add_listener(sock.as_raw_fd(), EpollListener::PortSock { sock, port })
+    }
+
     /// Handle/dispatch an epoll event to its listener.
     ///
     fn handle_event(&mut self, fd: RawFd, evset: epoll::Events) {
@@ -433,6 +453,52 @@ impl VsockMuxer {
                 }
             }
 
+            // A new host-initiated connection to a given guest port is ready to be accepted.
+            //
+            Some(EpollListener::PortSock { sock, port }) => {
+                let peer_port = *port;
+                let accepted = sock.accept();
+                if self.conn_map.len() == defs::MAX_CONNECTIONS {
+                    // Dropping the accepted stream closes it.
+                    warn!("vsock: connection limit reached; refusing new host connection");
+                    return;
+                }
+                accepted
+                    .map_err(Error::UnixAccept)
+                    .and_then(|(stream, _)| {
+                        stream
+                            .set_nonblocking(true)
+                            .map(|_| stream)
+                            .map_err(Error::UnixAccept)
+                    })
+                    .and_then(|stream| {
+                        let local_port = self.allocate_local_port();
+                        self.add_connection(
+                            ConnMapKey {
+                                local_port,
+                                peer_port,
+                            },
+                            MuxerConnection::new_local_init(
+                                stream,
+                                uapi::VSOCK_HOST_CID,
+                                self.cid,
+                                local_port,
+                                peer_port,
+                            ),
+                        )
+                        .map(|_| {
+                            self.port_sock_local_ports.insert(local_port);
+                        })
+                        .map_err(|err| {
+                            self.free_local_port(local_port);
+                            err
+                        })
+                    })
+                    .unwrap_or_else(|err| {
+                        info!("vsock: error adding port connection: {:?}", err);
+                    });
+            }
+
             _ => {
                 info!("vsock: unexpected event: fd={:?}, evset={:?}", fd, evset);
             }
@@ -556,6 +622,7 @@ impl VsockMuxer {
             EpollListener::Connection { evset, .. } => evset,
             EpollListener::LocalStream(_) => epoll::Events::EPOLLIN,
             EpollListener::HostSock => epoll::Events::EPOLLIN,
+            EpollListener::PortSock { .. } => epoll::Events::EPOLLIN,
         };
 
         epoll::ctl(
@@ -617,6 +684,7 @@ impl VsockMuxer {
     ///
     fn free_local_port(&mut self, port: u32) {
         self.local_port_set.remove(&port);
+        self.port_sock_local_ports.remove(&port);
     }
 
     /// Handle a new connection request comming from our peer (the guest vsock driver).
@@ -671,8 +739,11 @@ impl VsockMuxer {
             mut_fn(conn);
 
             // If this is a host-initiated connection that has just become established, we'll have
-            // to send an ack message to the host end.
-            if prev_state == ConnState::LocalInit && conn.state() == ConnState::Established {
+            // to send an ack message to the host end, unless it came through a port listener.
+            if prev_state == ConnState::LocalInit
+                && conn.state() == ConnState::Established
+                && !self.port_sock_local_ports.contains(&key.local_port)
+            {
                 conn.send_bytes(format!("OK {}\n", key.local_port).as_bytes())
                     .unwrap_or_else(|err| {
                         conn.kill();
@@ -1124,6 +1195,58 @@ mod tests {
         assert_eq!(ctx.pkt.buf().unwrap()[..data.len()], data);
     }
 
+    #[test]
+    fn test_port_listener_connection() {
+        let mut ctx = MuxerTestContext::new("port_listener_connection");
+        let peer_port = 22;
+        let path = format!("{}_listen_{}", ctx.muxer.host_sock_path, peer_port);
+        ctx.muxer.add_port_listener(peer_port, &path).unwrap();
+
+        // No "connect" command is needed, the connection request goes to the guest as soon as
+        // the host connection is accepted.
+        let mut stream = UnixStream::connect(&path).unwrap();
+        stream.set_nonblocking(true).unwrap();
+        ctx.notify_muxer();
+
+        let local_port = ctx.muxer.local_port_last;
+        let key = ConnMapKey {
+            local_port,
+            peer_port,
+        };
+        assert!(ctx.muxer.conn_map.contains_key(&key));
+        assert!(ctx.muxer.has_pending_rx());
+        ctx.recv();
+        assert_eq!(ctx.pkt.op(), uapi::VSOCK_OP_REQUEST);
+        assert_eq!(ctx.pkt.src_port(), local_port);
+        assert_eq!(ctx.pkt.dst_port(), peer_port);
+
+        // The host end doesn't get any acknowledgement once the connection is established.
+        ctx.init_pkt(local_port, peer_port, uapi::VSOCK_OP_RESPONSE);
+        ctx.send();
+        let mut buf = vec![0u8; 32];
+        assert_eq!(
+            stream.read(&mut buf[..]).unwrap_err().kind(),
+            io::ErrorKind::WouldBlock
+        );
+
+        // Test guest -> host data flow.
+        let data = [1, 2, 3, 4];
+        ctx.init_data_pkt(local_port, peer_port, &data);
+        ctx.send();
+        let mut buf = vec![0u8; data.len()];
+        stream.read_exact(buf.as_mut_slice()).unwrap();
+        assert_eq!(buf.as_slice(), &data);
+
+        // The local port is freed along with the connection.
+        ctx.init_pkt(local_port, peer_port, uapi::VSOCK_OP_RST);
+        ctx.send();
+        assert!(!ctx.muxer.conn_map.contains_key(&key));
+        assert!(!ctx.muxer.local_port_set.contains(&local_port));
+        assert!(!ctx.muxer.port_sock_local_ports.contains(&local_port));
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
     #[test]
     fn test_local_close() {
         let peer_port = 1025;
@@ -0,0 +1,85 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+
+// Port I/O dispatch as done on vCPU exits, and serial input. None of this
+// needs a KVM device.
+//
+// To spot regressions, record a baseline before a change and compare:
+//   cargo bench -p vmm -- --save-baseline before
+//   cargo bench -p vmm -- --baseline before
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use devices::legacy::Serial;
+use devices::{Bus, BusDevice};
+use std::sync::{Arc, Mutex};
+use vm_device::interrupt::{InterruptIndex, InterruptSourceConfig, InterruptSourceGroup};
+
+struct DummyDevice;
+
+impl BusDevice for DummyDevice {
+    fn read(&mut self, _base: u64, _offset: u64, data: &mut [u8]) {
+        for byte in data.iter_mut() {
+            *byte = 0xff;
+        }
+    }
+}
+
+struct NoopInterrupt;
+
+impl InterruptSourceGroup for NoopInterrupt {
+    fn trigger(&self, _index: InterruptIndex) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    fn update(
+        &self,
+        _index: InterruptIndex,
+        _config: InterruptSourceConfig,
+    ) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+// Registers `count` 8 bytes wide devices, and reads from the last one, which
+// is the worst case for the lookup.
+fn pio_dispatch(c: &mut Criterion) {
+    let mut group = c.benchmark_group("pio_dispatch");
+    for count in [2u64, 30].iter() {
+        let bus = Bus::new();
+        for i in 0..*count {
+            bus.insert(Arc::new(Mutex::new(DummyDevice)), 0x100 + i * 0x10, 0x8)
+                .unwrap();
+        }
+        let addr = 0x100 + (count - 1) * 0x10 + 4;
+
+        group.bench_with_input(BenchmarkId::from_parameter(count), &addr, |b, addr| {
+            let mut data = [0u8; 1];
+            b.iter(|| assert!(bus.read(*addr, &mut data)))
+        });
+    }
+    group.finish();
+}
+
+fn serial_input(c: &mut Criterion) {
+    let input = vec![b'a'; 4096];
+    let mut serial = Serial::new_sink(Arc::new(Box::new(NoopInterrupt)));
+
+    let mut group = c.benchmark_group("serial_input");
+    group.throughput(Throughput::Bytes(input.len() as u64));
+    group.bench_function("queue_input_bytes", |b| {
+        b.iter(|| {
+            serial.queue_input_bytes(&input).unwrap();
+            // Drain the receive buffer as the guest would, through the data
+            // register.
+            let mut data = [0u8; 1];
+            for _ in 0..input.len() {
+                serial.read(0, 0, &mut data);
+            }
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, pio_dispatch, serial_input);
+criterion_main!(benches);
@@ -13,6 +13,7 @@ use vm_memory::{Address, ByteValued, Bytes};
 
 use std::sync::{Arc, Mutex};
 
+use crate::config::NumaConfig;
 use crate::cpu::CpuManager;
 use crate::device_manager::DeviceManager;
 use crate::memory_manager::MemoryManager;
@@ -79,6 +80,98 @@ struct IortIdMapping {
     pub flags: u32,
 }
 
+#[repr(packed)]
+#[derive(Default)]
+struct ProcessorLocalApicAffinity {
+    pub type_: u8,
+    pub length: u8,
+    pub proximity_domain_lo: u8,
+    pub apic_id: u8,
+    pub flags: u32,
+    pub local_sapic_eid: u8,
+    pub proximity_domain_hi: [u8; 3],
+    pub clock_domain: u32,
+}
+
+#[repr(packed)]
+#[derive(Default)]
+struct MemoryAffinity {
+    pub type_: u8,
+    pub length: u8,
+    pub proximity_domain: u32,
+    _reserved1: u16,
+    pub base_addr_lo: u32,
+    pub base_addr_hi: u32,
+    pub length_lo: u32,
+    pub length_hi: u32,
+    _reserved2: u32,
+    pub flags: u32,
+    _reserved3: u64,
+}
+
+const SRAT_AFFINITY_ENABLED: u32 = 1;
+
+fn create_srat_table(numa_nodes: &[NumaConfig], memory_manager: &Arc<Mutex<MemoryManager>>) -> SDT {
+    let mut srat = SDT::new(*b"SRAT", 36, 3, *b"CLOUDH", *b"CHSRAT  ", 1);
+    // Reserved, the first field being 1 for backward compatibility
+    srat.append(1u32);
+    srat.append(0u64);
+
+    for node in numa_nodes.iter() {
+        for cpu in node.cpus.iter().flatten() {
+            srat.append(ProcessorLocalApicAffinity {
+                type_: 0,
+                length: 16,
+                proximity_domain_lo: node.id as u8,
+                apic_id: *cpu,
+                flags: SRAT_AFFINITY_ENABLED,
+                proximity_domain_hi: [
+                    (node.id >> 8) as u8,
+                    (node.id >> 16) as u8,
+                    (node.id >> 24) as u8,
+                ],
+                ..Default::default()
+            });
+        }
+    }
+
+    for range in memory_manager.lock().unwrap().numa_ranges().iter() {
+        let base = range.start.raw_value();
+        let length = range.size as u64;
+        srat.append(MemoryAffinity {
+            type_: 1,
+            length: 40,
+            proximity_domain: range.node,
+            base_addr_lo: base as u32,
+            base_addr_hi: (base >> 32) as u32,
+            length_lo: length as u32,
+            length_hi: (length >> 32) as u32,
+            flags: SRAT_AFFINITY_ENABLED,
+            ..Default::default()
+        });
+    }
+
+    srat
+}
+
+fn create_slit_table(numa_nodes: &[NumaConfig]) -> SDT {
+    let mut slit = SDT::new(*b"SLIT", 36, 1, *b"CLOUDH", *b"CHSLIT  ", 1);
+    slit.append(numa_nodes.len() as u64);
+
+    // The node ids cover 0..n, each row giving the distances from a node.
+    let mut distances = vec![20u8; numa_nodes.len() * numa_nodes.len()];
+    for node in numa_nodes.iter() {
+        let row = node.id as usize * numa_nodes.len();
+        distances[row + node.id as usize] = 10;
+        for distance in node.distances.iter().flatten() {
+            distances[row + distance.destination as usize] = distance.distance;
+        }
+    }
+    slit.append_slice(&distances);
+
+    slit
+}
+
 pub fn create_dsdt_table(
     device_manager: &DeviceManager,
     cpu_manager: &Arc<Mutex<CpuManager>>,
@@ -99,6 +192,7 @@ pub fn create_acpi_tables(
     device_manager: &DeviceManager,
     cpu_manager: &Arc<Mutex<CpuManager>>,
     memory_manager: &Arc<Mutex<MemoryManager>>,
+    numa_nodes: &Option<Vec<NumaConfig>>,
 ) -> GuestAddress {
     // RSDP is at the EBDA
     let rsdp_offset = layout::RSDP_POINTER;
@@ -169,6 +263,26 @@ pub fn create_acpi_tables(
         .write_slice(mcfg.as_slice(), mcfg_offset)
         .expect("Error writing MCFG table");
     tables.push(mcfg_offset.0);
+    let mut next_offset = mcfg_offset.checked_add(mcfg.len() as u64).unwrap();
+
+    // SRAT and SLIT, describing the NUMA topology
+    if let Some(numa_nodes) = numa_nodes {
+        let srat = create_srat_table(numa_nodes, memory_manager);
+        let srat_offset = next_offset;
+        guest_mem
+            .write_slice(srat.as_slice(), srat_offset)
+            .expect("Error writing SRAT table");
+        tables.push(srat_offset.0);
+
+        let slit = create_slit_table(numa_nodes);
+        let slit_offset = srat_offset.checked_add(srat.len() as u64).unwrap();
+        guest_mem
+            .write_slice(slit.as_slice(), slit_offset)
+            .expect("Error writing SLIT table");
+        tables.push(slit_offset.0);
+
+        next_offset = slit_offset.checked_add(slit.len() as u64).unwrap();
+    }
 
     // XSDT
     let mut xsdt = SDT::new(*b"XSDT", 36, 1, *b"CLOUDH", *b"CHXSDT  ", 1);
@@ -177,7 +291,13 @@ pub fn create_acpi_tables(
     }
     xsdt.update_checksum();
 
-    let xsdt_offset = mcfg_offset.checked_add(mcfg.len() as u64).unwrap();
+    let xsdt_offset = next_offset;
+    // The tables are written upwards from the RSDP, and must stop short of
+    // the VM generation ID.
+    assert!(
+        xsdt_offset.0 + xsdt.len() as u64 <= layout::VMGENID_START.0,
+        "The ACPI tables overlap the VM generation ID"
+    );
     guest_mem
         .write_slice(xsdt.as_slice(), xsdt_offset)
         .expect("Error writing XSDT table");
@@ -4,7 +4,9 @@
 //
 
 use crate::api::http_endpoint::{
-    VmActionHandler, VmCreate, VmInfo, VmResize, VmmPing, VmmShutdown,
+    VmActionHandler, VmAddDevice, VmBalloonStats, VmCreate, VmInfo, VmNmi, VmRemoveDevice,
+    VmResize, VmResizeDisk, VmRestore, VmScreenshot, VmSendKey, VmSetLink, VmSnapshot, VmmMetrics,
+    VmmPing, VmmShutdown,
 };
 use crate::api::{ApiRequest, VmAction};
 use crate::{Error, Result};
@@ -51,18 +53,64 @@ lazy_static! {
             routes: HashMap::new(),
         };
 
-        r.routes.insert(endpoint!("/vm.create"), Box::new(VmCreate {}));
-        r.routes.insert(endpoint!("/vm.boot"), Box::new(VmActionHandler::new(VmAction::Boot)));
-        r.routes.insert(endpoint!("/vm.delete"), Box::new(VmActionHandler::new(VmAction::Delete)));
+        r.routes
+            .insert(endpoint!("/vm.create"), Box::new(VmCreate {}));
+        r.routes.insert(
+            endpoint!("/vm.boot"),
+            Box::new(VmActionHandler::new(VmAction::Boot)),
+        );
+        r.routes.insert(
+            endpoint!("/vm.delete"),
+            Box::new(VmActionHandler::new(VmAction::Delete)),
+        );
         r.routes.insert(endpoint!("/vm.info"), Box::new(VmInfo {}));
-        r.routes.insert(endpoint!("/vm.pause"), Box::new(VmActionHandler::new(VmAction::Pause)));
-        r.routes.insert(endpoint!("/vm.resume"), Box::new(VmActionHandler::new(VmAction::Resume)));
-        r.routes.insert(endpoint!("/vm.shutdown"), Box::new(VmActionHandler::new(VmAction::Shutdown)));
-        r.routes.insert(endpoint!("/vm.reboot"), Box::new(VmActionHandler::new(VmAction::Reboot)));
-        r.routes.insert(endpoint!("/vmm.shutdown"), Box::new(VmmShutdown {}));
-        r.routes.insert(endpoint!("/vmm.ping"), Box::new(VmmPing {}));
-        r.routes.insert(endpoint!("/vm.resize"), Box::new(VmResize {}));
-
+        r.routes
+            .insert(endpoint!("/vm.balloon-stats"), Box::new(VmBalloonStats {}));
+        r.routes
+            .insert(endpoint!("/vm.screenshot"), Box::new(VmScreenshot {}));
+        r.routes.insert(
+            endpoint!("/vm.pause"),
+            Box::new(VmActionHandler::new(VmAction::Pause)),
+        );
+        r.routes.insert(
+            endpoint!("/vm.resume"),
+            Box::new(VmActionHandler::new(VmAction::Resume)),
+        );
+        r.routes.insert(
+            endpoint!("/vm.shutdown"),
+            Box::new(VmActionHandler::new(VmAction::Shutdown)),
+        );
+        r.routes.insert(
+            endpoint!("/vm.reboot"),
+            Box::new(VmActionHandler::new(VmAction::Reboot)),
+        );
+        r.routes
+            .insert(endpoint!("/vmm.shutdown"), Box::new(VmmShutdown {}));
+        r.routes
+            .insert(endpoint!("/vmm.ping"), Box::new(VmmPing {}));
+        r.routes
+            .insert(endpoint!("/vmm.metrics"), Box::new(VmmMetrics {}));
+        r.routes
+            .insert(endpoint!("/vm.resize"), Box::new(VmResize {}));
+        r.routes.insert(endpoint!("/vm.nmi"), Box::new(VmNmi {}));
+        r.routes.insert(
+            endpoint!("/vm.update-generation-id"),
+            Box::new(VmActionHandler::new(VmAction::UpdateGenerationId)),
+        );
+        r.routes
+            .insert(endpoint!("/vm.send-key"), Box::new(VmSendKey {}));
+        r.routes
+            .insert(endpoint!("/vm.resize-disk"), Box::new(VmResizeDisk {}));
+        r.routes
+            .insert(endpoint!("/vm.set-link"), Box::new(VmSetLink {}));
+        r.routes
+            .insert(endpoint!("/vm.add-device"), Box::new(VmAddDevice {}));
+        r.routes
+            .insert(endpoint!("/vm.remove-device"), Box::new(VmRemoveDevice {}));
+        r.routes
+            .insert(endpoint!("/vm.snapshot"), Box::new(VmSnapshot {}));
+        r.routes
+            .insert(endpoint!("/vm.restore"), Box::new(VmRestore {}));
         r
     };
 }
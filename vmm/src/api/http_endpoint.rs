@@ -5,11 +5,17 @@
 
 use crate::api::http::EndpointHandler;
 use crate::api::{
-    vm_boot, vm_create, vm_delete, vm_info, vm_pause, vm_reboot, vm_resize, vm_resume, vm_shutdown,
-    vmm_ping, vmm_shutdown, ApiError, ApiRequest, ApiResult, VmAction, VmConfig, VmResizeData,
+    vm_add_device, vm_balloon_stats, vm_boot, vm_create, vm_delete, vm_info, vm_nmi, vm_pause,
+    vm_reboot, vm_remove_device, vm_resize, vm_resize_disk, vm_restore, vm_resume, vm_screenshot,
+    vm_send_key, vm_set_link, vm_shutdown, vm_snapshot, vm_update_generation_id, vmm_metrics,
+    vmm_ping, vmm_shutdown, ApiError, ApiRequest, ApiResult, VmAction, VmAddDeviceData, VmConfig,
+    VmNmiData, VmRemoveDeviceData, VmResizeData, VmResizeDiskData, VmRestoreData, VmScreenshotData,
+    VmSendKeyData, VmSetLinkData, VmSnapshotData,
 };
+use crate::config::Error as ConfigError;
 use micro_http::{Body, Method, Request, Response, StatusCode, Version};
 use serde_json::Error as SerdeError;
+use std::fmt;
 use std::sync::mpsc::Sender;
 use std::sync::{Arc, Mutex};
 use vmm_sys_util::eventfd::EventFd;
@@ -20,6 +26,9 @@ pub enum HttpError {
     /// API request receive error
     SerdeJsonDeserialize(SerdeError),
 
+    /// The VM configuration is invalid
+    InvalidVmConfig(ConfigError),
+
     /// Could not create a VM
     VmCreate(ApiError),
 
@@ -29,6 +38,12 @@ pub enum HttpError {
     /// Could not get the VM information
     VmInfo(ApiError),
 
+    /// Could not get the VM balloon statistics
+    VmBalloonStats(ApiError),
+
+    /// Could not save the VM display
+    VmScreenshot(ApiError),
+
     /// Could not pause the VM
     VmPause(ApiError),
 
@@ -47,11 +62,103 @@ pub enum HttpError {
     /// Could not resize a VM
     VmResize(ApiError),
 
+    /// Could not inject an NMI into a VM
+    VmNmi(ApiError),
+
+    /// Could not type on the keyboard of a VM
+    VmSendKey(ApiError),
+
+    /// Could not resize a VM disk
+    VmResizeDisk(ApiError),
+
+    /// Could not change the link of a VM network device
+    VmSetLink(ApiError),
+
+    /// Could not add a device to a VM
+    VmAddDevice(ApiError),
+
+    /// Could not remove a device from a VM
+    VmRemoveDevice(ApiError),
+
+    /// Could not snapshot a VM
+    VmSnapshot(ApiError),
+
+    /// Could not restore a VM
+    VmRestore(ApiError),
+
     /// Could not shut the VMM down
     VmmShutdown(ApiError),
 
     /// Could not handle VMM ping
     VmmPing(ApiError),
+
+    /// Could not get the VMM metrics
+    VmmMetrics(ApiError),
+}
+
+impl fmt::Display for HttpError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HttpError::SerdeJsonDeserialize(_) => write!(f, "cannot deserialize the request body"),
+            HttpError::InvalidVmConfig(_) => write!(f, "the VM configuration is invalid"),
+            HttpError::VmCreate(_) => write!(f, "could not create a VM"),
+            HttpError::VmBoot(_) => write!(f, "could not boot a VM"),
+            HttpError::VmInfo(_) => write!(f, "could not get the VM information"),
+            HttpError::VmBalloonStats(_) => write!(f, "could not get the VM balloon statistics"),
+            HttpError::VmScreenshot(_) => write!(f, "could not save the VM display"),
+            HttpError::VmPause(_) => write!(f, "could not pause the VM"),
+            HttpError::VmResume(_) => write!(f, "could not resume the VM"),
+            HttpError::VmShutdown(_) => write!(f, "could not shut a VM down"),
+            HttpError::VmReboot(_) => write!(f, "could not reboot a VM"),
+            HttpError::VmAction(_) => write!(f, "could not act on a VM"),
+            HttpError::VmResize(_) => write!(f, "could not resize a VM"),
+            HttpError::VmNmi(_) => write!(f, "could not inject an NMI into a VM"),
+            HttpError::VmSendKey(_) => write!(f, "could not type on the keyboard of a VM"),
+            HttpError::VmResizeDisk(_) => write!(f, "could not resize a VM disk"),
+            HttpError::VmSetLink(_) => {
+                write!(f, "could not change the link of a VM network device")
+            }
+            HttpError::VmAddDevice(_) => write!(f, "could not add a device to a VM"),
+            HttpError::VmRemoveDevice(_) => write!(f, "could not remove a device from a VM"),
+            HttpError::VmSnapshot(_) => write!(f, "could not snapshot a VM"),
+            HttpError::VmRestore(_) => write!(f, "could not restore a VM"),
+            HttpError::VmmShutdown(_) => write!(f, "could not shut the VMM down"),
+            HttpError::VmmPing(_) => write!(f, "could not handle VMM ping"),
+            HttpError::VmmMetrics(_) => write!(f, "could not get the VMM metrics"),
+        }
+    }
+}
+
+impl std::error::Error for HttpError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            HttpError::SerdeJsonDeserialize(e) => Some(e),
+            HttpError::InvalidVmConfig(e) => Some(e),
+            HttpError::VmCreate(e) => Some(e),
+            HttpError::VmBoot(e) => Some(e),
+            HttpError::VmInfo(e) => Some(e),
+            HttpError::VmBalloonStats(e) => Some(e),
+            HttpError::VmScreenshot(e) => Some(e),
+            HttpError::VmPause(e) => Some(e),
+            HttpError::VmResume(e) => Some(e),
+            HttpError::VmShutdown(e) => Some(e),
+            HttpError::VmReboot(e) => Some(e),
+            HttpError::VmAction(e) => Some(e),
+            HttpError::VmResize(e) => Some(e),
+            HttpError::VmNmi(e) => Some(e),
+            HttpError::VmSendKey(e) => Some(e),
+            HttpError::VmResizeDisk(e) => Some(e),
+            HttpError::VmSetLink(e) => Some(e),
+            HttpError::VmAddDevice(e) => Some(e),
+            HttpError::VmRemoveDevice(e) => Some(e),
+            HttpError::VmSnapshot(e) => Some(e),
+            HttpError::VmRestore(e) => Some(e),
+            HttpError::VmmShutdown(e) => Some(e),
+            HttpError::VmmPing(e) => Some(e),
+            HttpError::VmmMetrics(e) => Some(e),
+            _ => None,
+        }
+    }
 }
 
 fn error_response(error: HttpError, status: StatusCode) -> Response {
@@ -75,13 +182,15 @@ impl EndpointHandler for VmCreate {
             Method::Put => {
                 match &req.body {
                     Some(body) => {
-                        // Deserialize into a VmConfig
-                        let vm_config: VmConfig = match serde_json::from_slice(body.raw())
-                            .map_err(HttpError::SerdeJsonDeserialize)
-                        {
-                            Ok(config) => config,
-                            Err(e) => return error_response(e, StatusCode::BadRequest),
-                        };
+                        // Deserialize into a VmConfig, validated as the command
+                        // line one is.
+                        let vm_config =
+                            match VmConfig::from_json(&String::from_utf8_lossy(body.raw()))
+                                .map_err(HttpError::InvalidVmConfig)
+                            {
+                                Ok(config) => config,
+                                Err(e) => return error_response(e, StatusCode::BadRequest),
+                            };
 
                         // Call vm_create()
                         match vm_create(api_notifier, api_sender, Arc::new(Mutex::new(vm_config)))
@@ -117,6 +226,7 @@ impl VmActionHandler {
             VmAction::Reboot => vm_reboot,
             VmAction::Pause => vm_pause,
             VmAction::Resume => vm_resume,
+            VmAction::UpdateGenerationId => vm_update_generation_id,
         });
 
         VmActionHandler { action_fn }
@@ -175,6 +285,34 @@ impl EndpointHandler for VmInfo {
     }
 }
 
+// /api/v1/vm.balloon-stats handler
+pub struct VmBalloonStats {}
+
+impl EndpointHandler for VmBalloonStats {
+    fn handle_request(
+        &self,
+        req: &Request,
+        api_notifier: EventFd,
+        api_sender: Sender<ApiRequest>,
+    ) -> Response {
+        match req.method() {
+            Method::Get => match vm_balloon_stats(api_notifier, api_sender)
+                .map_err(HttpError::VmBalloonStats)
+            {
+                Ok(stats) => {
+                    let mut response = Response::new(Version::Http11, StatusCode::OK);
+                    let stats_serialized = serde_json::to_string(&stats).unwrap();
+
+                    response.set_body(Body::new(stats_serialized));
+                    response
+                }
+                Err(e) => error_response(e, StatusCode::InternalServerError),
+            },
+            _ => Response::new(Version::Http11, StatusCode::BadRequest),
+        }
+    }
+}
+
 // /api/v1/vmm.info handler
 pub struct VmmPing {}
 
@@ -201,6 +339,34 @@ impl EndpointHandler for VmmPing {
     }
 }
 
+// /api/v1/vmm.metrics handler
+pub struct VmmMetrics {}
+
+impl EndpointHandler for VmmMetrics {
+    fn handle_request(
+        &self,
+        req: &Request,
+        api_notifier: EventFd,
+        api_sender: Sender<ApiRequest>,
+    ) -> Response {
+        match req.method() {
+            Method::Get => {
+                match vmm_metrics(api_notifier, api_sender).map_err(HttpError::VmmMetrics) {
+                    Ok(metrics) => {
+                        let mut response = Response::new(Version::Http11, StatusCode::OK);
+                        let metrics_serialized = serde_json::to_string(&metrics).unwrap();
+
+                        response.set_body(Body::new(metrics_serialized));
+                        response
+                    }
+                    Err(e) => error_response(e, StatusCode::InternalServerError),
+                }
+            }
+            _ => Response::new(Version::Http11, StatusCode::BadRequest),
+        }
+    }
+}
+
 // /api/v1/vmm.shutdown handler
 pub struct VmmShutdown {}
 
@@ -261,3 +427,365 @@ impl EndpointHandler for VmResize {
         }
     }
 }
+
+// /api/v1/vm.nmi handler
+pub struct VmNmi {}
+
+impl EndpointHandler for VmNmi {
+    fn handle_request(
+        &self,
+        req: &Request,
+        api_notifier: EventFd,
+        api_sender: Sender<ApiRequest>,
+    ) -> Response {
+        match req.method() {
+            Method::Put => {
+                // The body is optional, all vCPUs are targeted without it.
+                let vm_nmi_data: VmNmiData = match &req.body {
+                    Some(body) => match serde_json::from_slice(body.raw())
+                        .map_err(HttpError::SerdeJsonDeserialize)
+                    {
+                        Ok(data) => data,
+                        Err(e) => return error_response(e, StatusCode::BadRequest),
+                    },
+                    None => VmNmiData::default(),
+                };
+
+                // Call vm_nmi()
+                match vm_nmi(api_notifier, api_sender, Arc::new(vm_nmi_data))
+                    .map_err(HttpError::VmNmi)
+                {
+                    Ok(_) => Response::new(Version::Http11, StatusCode::NoContent),
+                    Err(e) => error_response(e, StatusCode::InternalServerError),
+                }
+            }
+            _ => Response::new(Version::Http11, StatusCode::BadRequest),
+        }
+    }
+}
+
+// /api/v1/vm.send-key handler
+pub struct VmSendKey {}
+
+impl EndpointHandler for VmSendKey {
+    fn handle_request(
+        &self,
+        req: &Request,
+        api_notifier: EventFd,
+        api_sender: Sender<ApiRequest>,
+    ) -> Response {
+        match req.method() {
+            Method::Put => {
+                match &req.body {
+                    Some(body) => {
+                        let vm_send_key_data: VmSendKeyData =
+                            match serde_json::from_slice(body.raw())
+                                .map_err(HttpError::SerdeJsonDeserialize)
+                            {
+                                Ok(data) => data,
+                                Err(e) => return error_response(e, StatusCode::BadRequest),
+                            };
+
+                        // Call vm_send_key()
+                        match vm_send_key(api_notifier, api_sender, Arc::new(vm_send_key_data))
+                            .map_err(HttpError::VmSendKey)
+                        {
+                            Ok(_) => Response::new(Version::Http11, StatusCode::NoContent),
+                            Err(e) => error_response(e, StatusCode::InternalServerError),
+                        }
+                    }
+
+                    None => Response::new(Version::Http11, StatusCode::BadRequest),
+                }
+            }
+            _ => Response::new(Version::Http11, StatusCode::BadRequest),
+        }
+    }
+}
+
+// /api/v1/vm.resize-disk handler
+pub struct VmResizeDisk {}
+
+impl EndpointHandler for VmResizeDisk {
+    fn handle_request(
+        &self,
+        req: &Request,
+        api_notifier: EventFd,
+        api_sender: Sender<ApiRequest>,
+    ) -> Response {
+        match req.method() {
+            Method::Put => {
+                match &req.body {
+                    Some(body) => {
+                        let vm_resize_disk_data: VmResizeDiskData =
+                            match serde_json::from_slice(body.raw())
+                                .map_err(HttpError::SerdeJsonDeserialize)
+                            {
+                                Ok(data) => data,
+                                Err(e) => return error_response(e, StatusCode::BadRequest),
+                            };
+
+                        // Call vm_resize_disk()
+                        match vm_resize_disk(
+                            api_notifier,
+                            api_sender,
+                            Arc::new(vm_resize_disk_data),
+                        )
+                        .map_err(HttpError::VmResizeDisk)
+                        {
+                            Ok(_) => Response::new(Version::Http11, StatusCode::NoContent),
+                            Err(e) => error_response(e, StatusCode::InternalServerError),
+                        }
+                    }
+
+                    None => Response::new(Version::Http11, StatusCode::BadRequest),
+                }
+            }
+            _ => Response::new(Version::Http11, StatusCode::BadRequest),
+        }
+    }
+}
+
+// /api/v1/vm.set-link handler
+pub struct VmSetLink {}
+
+impl EndpointHandler for VmSetLink {
+    fn handle_request(
+        &self,
+        req: &Request,
+        api_notifier: EventFd,
+        api_sender: Sender<ApiRequest>,
+    ) -> Response {
+        match req.method() {
+            Method::Put => {
+                match &req.body {
+                    Some(body) => {
+                        let vm_set_link_data: VmSetLinkData =
+                            match serde_json::from_slice(body.raw())
+                                .map_err(HttpError::SerdeJsonDeserialize)
+                            {
+                                Ok(data) => data,
+                                Err(e) => return error_response(e, StatusCode::BadRequest),
+                            };
+
+                        // Call vm_set_link()
+                        match vm_set_link(api_notifier, api_sender, Arc::new(vm_set_link_data))
+                            .map_err(HttpError::VmSetLink)
+                        {
+                            Ok(_) => Response::new(Version::Http11, StatusCode::NoContent),
+                            Err(e) => error_response(e, StatusCode::InternalServerError),
+                        }
+                    }
+
+                    None => Response::new(Version::Http11, StatusCode::BadRequest),
+                }
+            }
+            _ => Response::new(Version::Http11, StatusCode::BadRequest),
+        }
+    }
+}
+
+// /api/v1/vm.add-device handler
+pub struct VmAddDevice {}
+
+impl EndpointHandler for VmAddDevice {
+    fn handle_request(
+        &self,
+        req: &Request,
+        api_notifier: EventFd,
+        api_sender: Sender<ApiRequest>,
+    ) -> Response {
+        match req.method() {
+            Method::Put => {
+                match &req.body {
+                    Some(body) => {
+                        let vm_add_device_data: VmAddDeviceData =
+                            match serde_json::from_slice(body.raw())
+                                .map_err(HttpError::SerdeJsonDeserialize)
+                            {
+                                Ok(data) => data,
+                                Err(e) => return error_response(e, StatusCode::BadRequest),
+                            };
+
+                        // Call vm_add_device()
+                        match vm_add_device(api_notifier, api_sender, Arc::new(vm_add_device_data))
+                            .map_err(HttpError::VmAddDevice)
+                        {
+                            Ok(device) => {
+                                let mut response = Response::new(Version::Http11, StatusCode::OK);
+                                let device_serialized = serde_json::to_string(&device).unwrap();
+
+                                response.set_body(Body::new(device_serialized));
+                                response
+                            }
+                            Err(e) => error_response(e, StatusCode::InternalServerError),
+                        }
+                    }
+
+                    None => Response::new(Version::Http11, StatusCode::BadRequest),
+                }
+            }
+            _ => Response::new(Version::Http11, StatusCode::BadRequest),
+        }
+    }
+}
+
+// /api/v1/vm.remove-device handler
+pub struct VmRemoveDevice {}
+
+impl EndpointHandler for VmRemoveDevice {
+    fn handle_request(
+        &self,
+        req: &Request,
+        api_notifier: EventFd,
+        api_sender: Sender<ApiRequest>,
+    ) -> Response {
+        match req.method() {
+            Method::Put => {
+                match &req.body {
+                    Some(body) => {
+                        let vm_remove_device_data: VmRemoveDeviceData =
+                            match serde_json::from_slice(body.raw())
+                                .map_err(HttpError::SerdeJsonDeserialize)
+                            {
+                                Ok(data) => data,
+                                Err(e) => return error_response(e, StatusCode::BadRequest),
+                            };
+
+                        // Call vm_remove_device()
+                        match vm_remove_device(
+                            api_notifier,
+                            api_sender,
+                            Arc::new(vm_remove_device_data),
+                        )
+                        .map_err(HttpError::VmRemoveDevice)
+                        {
+                            Ok(_) => Response::new(Version::Http11, StatusCode::NoContent),
+                            Err(e) => error_response(e, StatusCode::InternalServerError),
+                        }
+                    }
+
+                    None => Response::new(Version::Http11, StatusCode::BadRequest),
+                }
+            }
+            _ => Response::new(Version::Http11, StatusCode::BadRequest),
+        }
+    }
+}
+
+// /api/v1/vm.snapshot handler
+pub struct VmSnapshot {}
+
+impl EndpointHandler for VmSnapshot {
+    fn handle_request(
+        &self,
+        req: &Request,
+        api_notifier: EventFd,
+        api_sender: Sender<ApiRequest>,
+    ) -> Response {
+        match req.method() {
+            Method::Put => {
+                match &req.body {
+                    Some(body) => {
+                        let vm_snapshot_data: VmSnapshotData =
+                            match serde_json::from_slice(body.raw())
+                                .map_err(HttpError::SerdeJsonDeserialize)
+                            {
+                                Ok(data) => data,
+                                Err(e) => return error_response(e, StatusCode::BadRequest),
+                            };
+
+                        // Call vm_snapshot()
+                        match vm_snapshot(api_notifier, api_sender, Arc::new(vm_snapshot_data))
+                            .map_err(HttpError::VmSnapshot)
+                        {
+                            Ok(_) => Response::new(Version::Http11, StatusCode::NoContent),
+                            Err(e) => error_response(e, StatusCode::InternalServerError),
+                        }
+                    }
+
+                    None => Response::new(Version::Http11, StatusCode::BadRequest),
+                }
+            }
+            _ => Response::new(Version::Http11, StatusCode::BadRequest),
+        }
+    }
+}
+
+// /api/v1/vm.screenshot handler
+pub struct VmScreenshot {}
+
+impl EndpointHandler for VmScreenshot {
+    fn handle_request(
+        &self,
+        req: &Request,
+        api_notifier: EventFd,
+        api_sender: Sender<ApiRequest>,
+    ) -> Response {
+        match req.method() {
+            Method::Put => {
+                match &req.body {
+                    Some(body) => {
+                        let vm_screenshot_data: VmScreenshotData =
+                            match serde_json::from_slice(body.raw())
+                                .map_err(HttpError::SerdeJsonDeserialize)
+                            {
+                                Ok(data) => data,
+                                Err(e) => return error_response(e, StatusCode::BadRequest),
+                            };
+
+                        // Call vm_screenshot()
+                        match vm_screenshot(api_notifier, api_sender, Arc::new(vm_screenshot_data))
+                            .map_err(HttpError::VmScreenshot)
+                        {
+                            Ok(_) => Response::new(Version::Http11, StatusCode::NoContent),
+                            Err(e) => error_response(e, StatusCode::InternalServerError),
+                        }
+                    }
+
+                    None => Response::new(Version::Http11, StatusCode::BadRequest),
+                }
+            }
+            _ => Response::new(Version::Http11, StatusCode::BadRequest),
+        }
+    }
+}
+
+// /api/v1/vm.restore handler
+pub struct VmRestore {}
+
+impl EndpointHandler for VmRestore {
+    fn handle_request(
+        &self,
+        req: &Request,
+        api_notifier: EventFd,
+        api_sender: Sender<ApiRequest>,
+    ) -> Response {
+        match req.method() {
+            Method::Put => {
+                match &req.body {
+                    Some(body) => {
+                        let vm_restore_data: VmRestoreData =
+                            match serde_json::from_slice(body.raw())
+                                .map_err(HttpError::SerdeJsonDeserialize)
+                            {
+                                Ok(data) => data,
+                                Err(e) => return error_response(e, StatusCode::BadRequest),
+                            };
+
+                        // Call vm_restore()
+                        match vm_restore(api_notifier, api_sender, Arc::new(vm_restore_data))
+                            .map_err(HttpError::VmRestore)
+                        {
+                            Ok(_) => Response::new(Version::Http11, StatusCode::NoContent),
+                            Err(e) => error_response(e, StatusCode::InternalServerError),
+                        }
+                    }
+
+                    None => Response::new(Version::Http11, StatusCode::BadRequest),
+                }
+            }
+            _ => Response::new(Version::Http11, StatusCode::BadRequest),
+        }
+    }
+}
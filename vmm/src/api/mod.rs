@@ -32,15 +32,24 @@ extern crate micro_http;
 extern crate vmm_sys_util;
 
 pub use self::http::start_http_thread;
+pub use self::prometheus::start_prometheus_thread;
 
 pub mod http;
 pub mod http_endpoint;
+pub mod prometheus;
 
-use crate::config::VmConfig;
+use crate::config::{DiskConfig, NetConfig, VmConfig};
+use crate::device_registry::DeviceResources;
+use crate::memory_manager::MergeableRegion;
 use crate::vm::{Error as VmError, VmState};
+use std::collections::BTreeMap;
+use std::fmt;
 use std::io;
+use std::path::PathBuf;
 use std::sync::mpsc::{channel, RecvError, SendError, Sender};
 use std::sync::{Arc, Mutex};
+use vm_device::metrics::MetricSample;
+use vm_virtio::BalloonStats;
 use vmm_sys_util::eventfd::EventFd;
 
 /// API errors are sent back from the VMM API server through the ApiResponse.
@@ -73,6 +82,12 @@ pub enum ApiError {
     /// The VM info is not available.
     VmInfo(VmError),
 
+    /// The VM balloon statistics are not available.
+    VmBalloonStats(VmError),
+
+    /// The VM display could not be saved.
+    VmScreenshot(VmError),
+
     /// The VM config is missing.
     VmMissingConfig,
 
@@ -99,6 +114,107 @@ pub enum ApiError {
 
     /// The VM could not be resized
     VmResize(VmError),
+
+    /// The NMI could not be injected into the VM
+    VmNmi(VmError),
+
+    /// The VM generation ID could not be changed
+    VmUpdateGenerationId(VmError),
+
+    /// The keys could not be typed on the VM keyboard
+    VmSendKey(VmError),
+
+    /// The VM disk could not be resized
+    VmResizeDisk(VmError),
+
+    /// The link of the VM network device could not be changed
+    VmSetLink(VmError),
+
+    /// The device could not be added to the VM
+    VmAddDevice(VmError),
+
+    /// The device could not be removed from the VM
+    VmRemoveDevice(VmError),
+
+    /// The VM could not be snapshotted
+    VmSnapshot(VmError),
+
+    /// The VM could not be restored
+    VmRestore(VmError),
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ApiError::EventFdWrite(_) => write!(f, "cannot write to EventFd"),
+            ApiError::RequestSend(_) => write!(f, "API request send error"),
+            ApiError::ResponsePayloadType => write!(f, "wrong response payload type"),
+            ApiError::ResponseRecv(_) => write!(f, "API response receive error"),
+            ApiError::VmBoot(_) => write!(f, "the VM could not boot"),
+            ApiError::VmAlreadyCreated => write!(f, "the VM is already created"),
+            ApiError::VmCreate(_) => write!(f, "the VM could not be created"),
+            ApiError::VmDelete(_) => write!(f, "the VM could not be deleted"),
+            ApiError::VmInfo(_) => write!(f, "the VM info is not available"),
+            ApiError::VmBalloonStats(_) => {
+                write!(f, "the VM balloon statistics are not available")
+            }
+            ApiError::VmScreenshot(_) => write!(f, "the VM display could not be saved"),
+            ApiError::VmMissingConfig => write!(f, "the VM config is missing"),
+            ApiError::VmPause(_) => write!(f, "the VM could not be paused"),
+            ApiError::VmResume(_) => write!(f, "the VM could not resume"),
+            ApiError::VmNotBooted => write!(f, "the VM is not booted"),
+            ApiError::VmNotCreated => write!(f, "the VM is not created"),
+            ApiError::VmShutdown(_) => write!(f, "the VM could not shutdown"),
+            ApiError::VmReboot(_) => write!(f, "the VM could not reboot"),
+            ApiError::VmmShutdown(_) => write!(f, "the VMM could not shutdown"),
+            ApiError::VmResize(_) => write!(f, "the VM could not be resized"),
+            ApiError::VmNmi(_) => write!(f, "the NMI could not be injected into the VM"),
+            ApiError::VmUpdateGenerationId(_) => {
+                write!(f, "the VM generation ID could not be changed")
+            }
+            ApiError::VmSendKey(_) => write!(f, "the keys could not be typed on the VM keyboard"),
+            ApiError::VmResizeDisk(_) => write!(f, "the VM disk could not be resized"),
+            ApiError::VmSetLink(_) => {
+                write!(f, "the link of the VM network device could not be changed")
+            }
+            ApiError::VmAddDevice(_) => write!(f, "the device could not be added to the VM"),
+            ApiError::VmRemoveDevice(_) => write!(f, "the device could not be removed from the VM"),
+            ApiError::VmSnapshot(_) => write!(f, "the VM could not be snapshotted"),
+            ApiError::VmRestore(_) => write!(f, "the VM could not be restored"),
+        }
+    }
+}
+
+impl std::error::Error for ApiError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ApiError::EventFdWrite(e) => Some(e),
+            ApiError::RequestSend(e) => Some(e),
+            ApiError::ResponseRecv(e) => Some(e),
+            ApiError::VmBoot(e) => Some(e),
+            ApiError::VmCreate(e) => Some(e),
+            ApiError::VmDelete(e) => Some(e),
+            ApiError::VmInfo(e) => Some(e),
+            ApiError::VmBalloonStats(e) => Some(e),
+            ApiError::VmScreenshot(e) => Some(e),
+            ApiError::VmPause(e) => Some(e),
+            ApiError::VmResume(e) => Some(e),
+            ApiError::VmShutdown(e) => Some(e),
+            ApiError::VmReboot(e) => Some(e),
+            ApiError::VmmShutdown(e) => Some(e),
+            ApiError::VmResize(e) => Some(e),
+            ApiError::VmNmi(e) => Some(e),
+            ApiError::VmUpdateGenerationId(e) => Some(e),
+            ApiError::VmSendKey(e) => Some(e),
+            ApiError::VmResizeDisk(e) => Some(e),
+            ApiError::VmSetLink(e) => Some(e),
+            ApiError::VmAddDevice(e) => Some(e),
+            ApiError::VmRemoveDevice(e) => Some(e),
+            ApiError::VmSnapshot(e) => Some(e),
+            ApiError::VmRestore(e) => Some(e),
+            _ => None,
+        }
+    }
 }
 pub type ApiResult<T> = std::result::Result<T, ApiError>;
 
@@ -106,6 +222,12 @@ pub type ApiResult<T> = std::result::Result<T, ApiError>;
 pub struct VmInfo {
     pub config: Arc<Mutex<VmConfig>>,
     pub state: VmState,
+    /// The advice KSM got about the guest RAM, once the VM is booted.
+    #[serde(default)]
+    pub mergeable_regions: Vec<MergeableRegion>,
+    /// The devices plugged into the VM, by id.
+    #[serde(default)]
+    pub devices: BTreeMap<String, DeviceResources>,
 }
 
 #[derive(Clone, Deserialize, Serialize)]
@@ -119,6 +241,86 @@ pub struct VmResizeData {
     pub desired_ram: Option<u64>,
 }
 
+#[derive(Clone, Default, Deserialize, Serialize)]
+pub struct VmNmiData {
+    /// The vCPU to inject the NMI into. All vCPUs are targeted when unset.
+    pub cpu_id: Option<u8>,
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct VmSendKeyData {
+    /// The scancode bytes to type, in order.
+    pub scancodes: Vec<u8>,
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct VmResizeDiskData {
+    /// The id of the disk, unless given by its index.
+    #[serde(default)]
+    pub id: Option<String>,
+    /// The index of the disk in the VM config.
+    #[serde(default)]
+    pub disk_index: Option<usize>,
+    /// Defaults to the current size of the backing file.
+    #[serde(default)]
+    pub desired_size: Option<u64>,
+    /// Allows shrinking the disk.
+    #[serde(default)]
+    pub force: bool,
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct VmSetLinkData {
+    /// The id of the virtio-net device.
+    pub id: String,
+    /// Whether the guest sees the link up.
+    pub up: bool,
+}
+
+/// The device to plug, e.g. `{"disk": {"path": "/path/to/disk"}}`.
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum VmAddDeviceData {
+    Disk(DiskConfig),
+    Net(NetConfig),
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct VmAddDeviceResponse {
+    /// The id of the new device.
+    pub id: String,
+    /// The PCI device id of the new device, as seen from the guest.
+    pub index: usize,
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct VmRemoveDeviceData {
+    /// The id of the device, unless given by its index.
+    #[serde(default)]
+    pub id: Option<String>,
+    /// The PCI device id of the device, as seen from the guest.
+    #[serde(default)]
+    pub index: Option<usize>,
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct VmSnapshotData {
+    /// The directory the snapshot is saved to.
+    pub destination: PathBuf,
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct VmScreenshotData {
+    /// The file the display of the VM is written to, as a PPM image.
+    pub destination: PathBuf,
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct VmRestoreData {
+    /// The directory holding a snapshot taken through vm.snapshot.
+    pub source: PathBuf,
+}
+
 pub enum ApiResponsePayload {
     /// No data is sent on the channel.
     Empty,
@@ -126,8 +328,17 @@ pub enum ApiResponsePayload {
     /// Virtual machine information
     VmInfo(VmInfo),
 
+    /// Guest memory statistics
+    VmBalloonStats(BalloonStats),
+
     /// Vmm ping response
     VmmPing(VmmPingResponse),
+
+    /// Current value of the VMM metrics
+    VmmMetrics(Vec<MetricSample>),
+
+    /// Device hotplug response
+    VmAddDevice(VmAddDeviceResponse),
 }
 
 /// This is the response sent by the VMM API server through the mpsc channel.
@@ -155,9 +366,18 @@ pub enum ApiRequest {
     /// Request the VM information.
     VmInfo(Sender<ApiResponse>),
 
+    /// Request the latest guest memory statistics from the balloon device.
+    VmBalloonStats(Sender<ApiResponse>),
+
+    /// Save what the VM displays through its GPU device to a file.
+    VmScreenshot(Arc<VmScreenshotData>, Sender<ApiResponse>),
+
     /// Request the VMM API server status
     VmmPing(Sender<ApiResponse>),
 
+    /// Request the current value of the VMM metrics.
+    VmmMetrics(Sender<ApiResponse>),
+
     /// Pause a VM.
     VmPause(Sender<ApiResponse>),
 
@@ -181,6 +401,35 @@ pub enum ApiRequest {
 
     //// Resuze the VMM
     VmResize(Arc<VmResizeData>, Sender<ApiResponse>),
+
+    /// Inject an NMI into one or all of the VM vCPUs.
+    VmNmi(Arc<VmNmiData>, Sender<ApiResponse>),
+
+    /// Change the VM generation ID, notifying the guest.
+    VmUpdateGenerationId(Sender<ApiResponse>),
+
+    /// Type scancodes on the VM PS/2 keyboard.
+    VmSendKey(Arc<VmSendKeyData>, Sender<ApiResponse>),
+
+    /// Resize one of the VM disks.
+    VmResizeDisk(Arc<VmResizeDiskData>, Sender<ApiResponse>),
+
+    /// Bring the link of one of the VM network devices up or down.
+    VmSetLink(Arc<VmSetLinkData>, Sender<ApiResponse>),
+
+    /// Plug a virtio device into the VM.
+    VmAddDevice(Arc<VmAddDeviceData>, Sender<ApiResponse>),
+
+    /// Unplug a virtio device from the VM.
+    VmRemoveDevice(Arc<VmRemoveDeviceData>, Sender<ApiResponse>),
+
+    /// Save the VM to a directory, pausing it while its state is saved.
+    VmSnapshot(Arc<VmSnapshotData>, Sender<ApiResponse>),
+
+    /// Create and run a VM from a snapshot.
+    /// If a VM was already created, the VMM API server will send a
+    /// VmAlreadyCreated error back.
+    VmRestore(Arc<VmRestoreData>, Sender<ApiResponse>),
 }
 
 pub fn vm_create(
@@ -222,6 +471,9 @@ pub enum VmAction {
 
     /// Resume a VM
     Resume,
+
+    /// Change the generation ID of a VM
+    UpdateGenerationId,
 }
 
 fn vm_action(api_evt: EventFd, api_sender: Sender<ApiRequest>, action: VmAction) -> ApiResult<()> {
@@ -234,6 +486,7 @@ fn vm_action(api_evt: EventFd, api_sender: Sender<ApiRequest>, action: VmAction)
         VmAction::Reboot => ApiRequest::VmReboot(response_sender),
         VmAction::Pause => ApiRequest::VmPause(response_sender),
         VmAction::Resume => ApiRequest::VmResume(response_sender),
+        VmAction::UpdateGenerationId => ApiRequest::VmUpdateGenerationId(response_sender),
     };
 
     // Send the VM request.
@@ -269,6 +522,10 @@ pub fn vm_resume(api_evt: EventFd, api_sender: Sender<ApiRequest>) -> ApiResult<
     vm_action(api_evt, api_sender, VmAction::Resume)
 }
 
+pub fn vm_update_generation_id(api_evt: EventFd, api_sender: Sender<ApiRequest>) -> ApiResult<()> {
+    vm_action(api_evt, api_sender, VmAction::UpdateGenerationId)
+}
+
 pub fn vm_info(api_evt: EventFd, api_sender: Sender<ApiRequest>) -> ApiResult<VmInfo> {
     let (response_sender, response_receiver) = channel();
 
@@ -286,6 +543,44 @@ pub fn vm_info(api_evt: EventFd, api_sender: Sender<ApiRequest>) -> ApiResult<Vm
     }
 }
 
+pub fn vm_balloon_stats(
+    api_evt: EventFd,
+    api_sender: Sender<ApiRequest>,
+) -> ApiResult<BalloonStats> {
+    let (response_sender, response_receiver) = channel();
+
+    // Send the balloon statistics request.
+    api_sender
+        .send(ApiRequest::VmBalloonStats(response_sender))
+        .map_err(ApiError::RequestSend)?;
+    api_evt.write(1).map_err(ApiError::EventFdWrite)?;
+
+    let response = response_receiver.recv().map_err(ApiError::ResponseRecv)??;
+
+    match response {
+        ApiResponsePayload::VmBalloonStats(stats) => Ok(stats),
+        _ => Err(ApiError::ResponsePayloadType),
+    }
+}
+
+pub fn vm_screenshot(
+    api_evt: EventFd,
+    api_sender: Sender<ApiRequest>,
+    data: Arc<VmScreenshotData>,
+) -> ApiResult<()> {
+    let (response_sender, response_receiver) = channel();
+
+    // Send the VM screenshot request.
+    api_sender
+        .send(ApiRequest::VmScreenshot(data, response_sender))
+        .map_err(ApiError::RequestSend)?;
+    api_evt.write(1).map_err(ApiError::EventFdWrite)?;
+
+    response_receiver.recv().map_err(ApiError::ResponseRecv)??;
+
+    Ok(())
+}
+
 pub fn vmm_ping(api_evt: EventFd, api_sender: Sender<ApiRequest>) -> ApiResult<VmmPingResponse> {
     let (response_sender, response_receiver) = channel();
 
@@ -302,6 +597,25 @@ pub fn vmm_ping(api_evt: EventFd, api_sender: Sender<ApiRequest>) -> ApiResult<V
     }
 }
 
+pub fn vmm_metrics(
+    api_evt: EventFd,
+    api_sender: Sender<ApiRequest>,
+) -> ApiResult<Vec<MetricSample>> {
+    let (response_sender, response_receiver) = channel();
+
+    api_sender
+        .send(ApiRequest::VmmMetrics(response_sender))
+        .map_err(ApiError::RequestSend)?;
+    api_evt.write(1).map_err(ApiError::EventFdWrite)?;
+
+    let metrics = response_receiver.recv().map_err(ApiError::ResponseRecv)??;
+
+    match metrics {
+        ApiResponsePayload::VmmMetrics(metrics) => Ok(metrics),
+        _ => Err(ApiError::ResponsePayloadType),
+    }
+}
+
 pub fn vmm_shutdown(api_evt: EventFd, api_sender: Sender<ApiRequest>) -> ApiResult<()> {
     let (response_sender, response_receiver) = channel();
 
@@ -333,3 +647,150 @@ pub fn vm_resize(
 
     Ok(())
 }
+
+pub fn vm_nmi(
+    api_evt: EventFd,
+    api_sender: Sender<ApiRequest>,
+    data: Arc<VmNmiData>,
+) -> ApiResult<()> {
+    let (response_sender, response_receiver) = channel();
+
+    // Send the VM NMI request.
+    api_sender
+        .send(ApiRequest::VmNmi(data, response_sender))
+        .map_err(ApiError::RequestSend)?;
+    api_evt.write(1).map_err(ApiError::EventFdWrite)?;
+
+    response_receiver.recv().map_err(ApiError::ResponseRecv)??;
+
+    Ok(())
+}
+
+pub fn vm_send_key(
+    api_evt: EventFd,
+    api_sender: Sender<ApiRequest>,
+    data: Arc<VmSendKeyData>,
+) -> ApiResult<()> {
+    let (response_sender, response_receiver) = channel();
+
+    // Send the VM key typing request.
+    api_sender
+        .send(ApiRequest::VmSendKey(data, response_sender))
+        .map_err(ApiError::RequestSend)?;
+    api_evt.write(1).map_err(ApiError::EventFdWrite)?;
+
+    response_receiver.recv().map_err(ApiError::ResponseRecv)??;
+
+    Ok(())
+}
+
+pub fn vm_resize_disk(
+    api_evt: EventFd,
+    api_sender: Sender<ApiRequest>,
+    data: Arc<VmResizeDiskData>,
+) -> ApiResult<()> {
+    let (response_sender, response_receiver) = channel();
+
+    // Send the VM disk resizing request.
+    api_sender
+        .send(ApiRequest::VmResizeDisk(data, response_sender))
+        .map_err(ApiError::RequestSend)?;
+    api_evt.write(1).map_err(ApiError::EventFdWrite)?;
+
+    response_receiver.recv().map_err(ApiError::ResponseRecv)??;
+
+    Ok(())
+}
+
+pub fn vm_set_link(
+    api_evt: EventFd,
+    api_sender: Sender<ApiRequest>,
+    data: Arc<VmSetLinkData>,
+) -> ApiResult<()> {
+    let (response_sender, response_receiver) = channel();
+
+    // Send the VM network link change request.
+    api_sender
+        .send(ApiRequest::VmSetLink(data, response_sender))
+        .map_err(ApiError::RequestSend)?;
+    api_evt.write(1).map_err(ApiError::EventFdWrite)?;
+
+    response_receiver.recv().map_err(ApiError::ResponseRecv)??;
+
+    Ok(())
+}
+
+pub fn vm_add_device(
+    api_evt: EventFd,
+    api_sender: Sender<ApiRequest>,
+    data: Arc<VmAddDeviceData>,
+) -> ApiResult<VmAddDeviceResponse> {
+    let (response_sender, response_receiver) = channel();
+
+    // Send the VM device addition request.
+    api_sender
+        .send(ApiRequest::VmAddDevice(data, response_sender))
+        .map_err(ApiError::RequestSend)?;
+    api_evt.write(1).map_err(ApiError::EventFdWrite)?;
+
+    let response = response_receiver.recv().map_err(ApiError::ResponseRecv)??;
+
+    match response {
+        ApiResponsePayload::VmAddDevice(device) => Ok(device),
+        _ => Err(ApiError::ResponsePayloadType),
+    }
+}
+
+pub fn vm_remove_device(
+    api_evt: EventFd,
+    api_sender: Sender<ApiRequest>,
+    data: Arc<VmRemoveDeviceData>,
+) -> ApiResult<()> {
+    let (response_sender, response_receiver) = channel();
+
+    // Send the VM device removal request.
+    api_sender
+        .send(ApiRequest::VmRemoveDevice(data, response_sender))
+        .map_err(ApiError::RequestSend)?;
+    api_evt.write(1).map_err(ApiError::EventFdWrite)?;
+
+    response_receiver.recv().map_err(ApiError::ResponseRecv)??;
+
+    Ok(())
+}
+
+pub fn vm_snapshot(
+    api_evt: EventFd,
+    api_sender: Sender<ApiRequest>,
+    data: Arc<VmSnapshotData>,
+) -> ApiResult<()> {
+    let (response_sender, response_receiver) = channel();
+
+    // Send the VM snapshot request.
+    api_sender
+        .send(ApiRequest::VmSnapshot(data, response_sender))
+        .map_err(ApiError::RequestSend)?;
+    api_evt.write(1).map_err(ApiError::EventFdWrite)?;
+
+    response_receiver.recv().map_err(ApiError::ResponseRecv)??;
+
+    Ok(())
+}
+
+pub fn vm_restore(
+    api_evt: EventFd,
+    api_sender: Sender<ApiRequest>,
+    data: Arc<VmRestoreData>,
+) -> ApiResult<()> {
+    let (response_sender, response_receiver) = channel();
+
+    // Send the VM restore request.
+    api_sender
+        .send(ApiRequest::VmRestore(data, response_sender))
+        .map_err(ApiError::RequestSend)?;
+    api_evt.write(1).map_err(ApiError::EventFdWrite)?;
+
+    response_receiver.recv().map_err(ApiError::ResponseRecv)??;
+
+    Ok(())
+}
@@ -0,0 +1,92 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Exposes the VMM metrics over TCP, in the Prometheus text format.
+//!
+//! Every connection gets the current value of the metrics, whatever it
+//! asked for, so that the listener can be scraped without any configuration
+//! beyond its address.
+
+use crate::{Error, Result};
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::thread;
+use std::time::Duration;
+use vm_device::metrics;
+
+// Scrapers not sending their request in time don't hold the thread forever.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+fn serve(stream: &mut TcpStream) -> io::Result<()> {
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+    // The request itself doesn't matter, it only has to be consumed before
+    // answering for the client not to get a reset.
+    let mut request = [0u8; 1024];
+    let _ = stream.read(&mut request)?;
+
+    let mut body = Vec::new();
+    metrics::registry().write_prometheus(&mut body)?;
+
+    write!(
+        stream,
+        "HTTP/1.1 200 OK\r\n\
+         Content-Type: text/plain; version=0.0.4\r\n\
+         Content-Length: {}\r\n\
+         Connection: close\r\n\r\n",
+        body.len()
+    )?;
+    stream.write_all(&body)?;
+    stream.flush()
+}
+
+/// Starts serving the metrics on `address`, e.g. "127.0.0.1:9100".
+pub fn start_prometheus_thread(address: &str) -> Result<thread::JoinHandle<Result<()>>> {
+    let listener = TcpListener::bind(address).map_err(Error::MetricsBind)?;
+
+    thread::Builder::new()
+        .name("prometheus".to_string())
+        .spawn(move || {
+            for stream in listener.incoming() {
+                match stream {
+                    Ok(mut stream) => {
+                        if let Err(e) = serve(&mut stream) {
+                            warn!("Failed to serve the metrics: {}", e);
+                        }
+                    }
+                    Err(e) => error!("Failed to accept a metrics connection: {}", e),
+                }
+            }
+            Ok(())
+        })
+        .map_err(Error::MetricsThreadSpawn)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_scrape() {
+        metrics::registry()
+            .counter("vcpu_exits", "test_scrape")
+            .add(42);
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        drop(listener);
+        start_prometheus_thread(&address.to_string()).unwrap();
+
+        let mut stream = TcpStream::connect(address).unwrap();
+        stream
+            .write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("# TYPE cloud_hypervisor_vcpu_exits counter\n"));
+        assert!(response.contains("cloud_hypervisor_vcpu_exits{id=\"test_scrape\"} 42\n"));
+    }
+}
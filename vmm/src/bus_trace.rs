@@ -0,0 +1,330 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Tracing of the I/O port and MMIO accesses of the guest, for debugging
+//! guests bringing their devices up.
+//!
+//! The vCPUs record each access once the bus has handled it, so that a read
+//! carries the value the guest got. The latest accesses are kept in a
+//! bounded ring, logged when a vCPU fails, and every access can also be
+//! streamed to a file, in a compact binary format the `bus_trace` binary
+//! converts to text.
+//!
+//! A vCPU only holds an optional tracer: with tracing disabled, an access
+//! costs a single branch.
+//!
+//! The trace file is created once per VMM, the VMs it boots again after a
+//! reboot appending their accesses to it, with timestamps counted from the
+//! first boot.
+
+use crate::config::{TraceBusConfig, TraceBusRange};
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufWriter, Read, Write};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Starts a binary trace, followed by the accesses.
+pub const TRACE_MAGIC: &[u8; 8] = b"CHBUSTR1";
+
+// Timestamp, address and value, then vCPU id, flags and size.
+const RECORD_SIZE: usize = 8 + 8 + 8 + 1 + 1 + 1;
+const FLAG_WRITE: u8 = 1 << 0;
+const FLAG_MMIO: u8 = 1 << 1;
+
+/// The bus an access went through.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BusKind {
+    Pio,
+    Mmio,
+}
+
+/// A single access of the guest.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BusAccess {
+    /// Time since tracing started.
+    pub timestamp: Duration,
+    pub vcpu: u8,
+    pub bus: BusKind,
+    pub write: bool,
+    pub addr: u64,
+    pub size: u8,
+    /// The data written or read, as a little endian value, truncated to
+    /// 8 bytes.
+    pub value: u64,
+}
+
+impl BusAccess {
+    fn encode(&self) -> [u8; RECORD_SIZE] {
+        let mut record = [0u8; RECORD_SIZE];
+        record[0..8].copy_from_slice(&(self.timestamp.as_nanos() as u64).to_le_bytes());
+        record[8..16].copy_from_slice(&self.addr.to_le_bytes());
+        record[16..24].copy_from_slice(&self.value.to_le_bytes());
+        record[24] = self.vcpu;
+        record[25] = match self.bus {
+            BusKind::Pio => 0,
+            BusKind::Mmio => FLAG_MMIO,
+        } | if self.write { FLAG_WRITE } else { 0 };
+        record[26] = self.size;
+        record
+    }
+
+    fn decode(record: &[u8; RECORD_SIZE]) -> io::Result<Self> {
+        let u64_at = |offset: usize| {
+            let mut bytes = [0u8; 8];
+            bytes.copy_from_slice(&record[offset..offset + 8]);
+            u64::from_le_bytes(bytes)
+        };
+        let flags = record[25];
+        if flags & !(FLAG_WRITE | FLAG_MMIO) != 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("invalid bus access flags {:#x}", flags),
+            ));
+        }
+
+        Ok(BusAccess {
+            timestamp: Duration::from_nanos(u64_at(0)),
+            vcpu: record[24],
+            bus: if flags & FLAG_MMIO != 0 {
+                BusKind::Mmio
+            } else {
+                BusKind::Pio
+            },
+            write: flags & FLAG_WRITE != 0,
+            addr: u64_at(8),
+            size: record[26],
+            value: u64_at(16),
+        })
+    }
+}
+
+impl fmt::Display for BusAccess {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:>6}.{:09} vcpu{} {} {} {:#x}/{} {:#x}",
+            self.timestamp.as_secs(),
+            self.timestamp.subsec_nanos(),
+            self.vcpu,
+            match self.bus {
+                BusKind::Pio => "pio ",
+                BusKind::Mmio => "mmio",
+            },
+            if self.write { "w" } else { "r" },
+            self.addr,
+            self.size,
+            self.value
+        )
+    }
+}
+
+// Dropped on the first error, not to fail every access after it.
+type TraceWriter = Arc<Mutex<Option<BufWriter<File>>>>;
+
+lazy_static! {
+    // The trace files opened by the VMM, along with when they were created.
+    static ref TRACE_FILES: Mutex<HashMap<PathBuf, (Instant, TraceWriter)>> =
+        Mutex::new(HashMap::new());
+}
+
+// Creates the trace file at `path` the first time it is asked for, and
+// returns the same writer afterwards.
+fn trace_file(path: &PathBuf) -> io::Result<(Instant, TraceWriter)> {
+    let mut files = TRACE_FILES.lock().unwrap();
+    if let Some((start, writer)) = files.get(path) {
+        return Ok((*start, Arc::clone(writer)));
+    }
+
+    let mut file = BufWriter::new(File::create(path)?);
+    file.write_all(TRACE_MAGIC)?;
+    let entry = (Instant::now(), Arc::new(Mutex::new(Some(file))));
+    files.insert(path.clone(), (entry.0, Arc::clone(&entry.1)));
+    Ok(entry)
+}
+
+/// Records the accesses of the guest, from all its vCPUs.
+pub struct BusTracer {
+    start: Instant,
+    capacity: usize,
+    ring: Mutex<VecDeque<BusAccess>>,
+    pio: Vec<TraceBusRange>,
+    mmio: Vec<TraceBusRange>,
+    filtered: bool,
+    file: TraceWriter,
+}
+
+impl BusTracer {
+    pub fn new(config: &TraceBusConfig) -> io::Result<Self> {
+        let (start, file) = match &config.file {
+            Some(path) => trace_file(path)?,
+            None => (Instant::now(), Arc::new(Mutex::new(None))),
+        };
+
+        Ok(BusTracer {
+            start,
+            capacity: config.entries,
+            ring: Mutex::new(VecDeque::with_capacity(config.entries)),
+            pio: config.pio.clone().unwrap_or_default(),
+            mmio: config.mmio.clone().unwrap_or_default(),
+            filtered: config.pio.is_some() || config.mmio.is_some(),
+            file,
+        })
+    }
+
+    fn traces(&self, bus: BusKind, addr: u64) -> bool {
+        if !self.filtered {
+            return true;
+        }
+        let ranges = match bus {
+            BusKind::Pio => &self.pio,
+            BusKind::Mmio => &self.mmio,
+        };
+        ranges.iter().any(|range| range.contains(addr))
+    }
+
+    /// Records an access of `vcpu` to `addr`, if it is traced.
+    pub fn record(&self, vcpu: u8, bus: BusKind, write: bool, addr: u64, data: &[u8]) {
+        if !self.traces(bus, addr) {
+            return;
+        }
+
+        let mut value = [0u8; 8];
+        let len = data.len().min(value.len());
+        value[..len].copy_from_slice(&data[..len]);
+        let access = BusAccess {
+            timestamp: self.start.elapsed(),
+            vcpu,
+            bus,
+            write,
+            addr,
+            size: data.len() as u8,
+            value: u64::from_le_bytes(value),
+        };
+
+        if self.capacity > 0 {
+            let mut ring = self.ring.lock().unwrap();
+            if ring.len() == self.capacity {
+                ring.pop_front();
+            }
+            ring.push_back(access);
+        }
+
+        let mut file = self.file.lock().unwrap();
+        if let Some(writer) = file.as_mut() {
+            if let Err(e) = writer.write_all(&access.encode()) {
+                warn!("Stopping to write the bus trace: {}", e);
+                file.take();
+            }
+        }
+    }
+
+    /// The latest accesses, oldest first.
+    pub fn recent(&self) -> Vec<BusAccess> {
+        self.ring.lock().unwrap().iter().cloned().collect()
+    }
+
+    /// Writes the accesses buffered so far to the trace file.
+    pub fn flush(&self) -> io::Result<()> {
+        match self.file.lock().unwrap().as_mut() {
+            Some(writer) => writer.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Converts a binary trace read from `reader` to text, one access per line.
+pub fn convert<R: Read, W: Write>(mut reader: R, mut writer: W) -> io::Result<()> {
+    let mut magic = [0u8; 8];
+    reader.read_exact(&mut magic)?;
+    if &magic != TRACE_MAGIC {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "not a bus trace",
+        ));
+    }
+
+    let mut record = [0u8; RECORD_SIZE];
+    loop {
+        match reader.read_exact(&mut record) {
+            Ok(()) => writeln!(writer, "{}", BusAccess::decode(&record)?)?,
+            // A trace cut short, e.g. by the VMM being killed, is converted
+            // up to its last complete access.
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(entries: usize) -> TraceBusConfig {
+        TraceBusConfig {
+            entries,
+            file: None,
+            pio: None,
+            mmio: None,
+        }
+    }
+
+    #[test]
+    fn test_ring_and_filters() {
+        let tracer = BusTracer::new(&config(2)).unwrap();
+        tracer.record(0, BusKind::Pio, true, 0x3f8, &[b'5']);
+        tracer.record(1, BusKind::Mmio, false, 0xd000_0000, &[1, 2, 3, 4]);
+        tracer.record(0, BusKind::Pio, true, 0x3f8, &[b'\n']);
+        let recent = tracer.recent();
+        assert_eq!(recent.len(), 2);
+        assert_eq!(recent[0].bus, BusKind::Mmio);
+        assert_eq!(recent[0].value, 0x0403_0201);
+        assert_eq!(recent[1].value, u64::from(b'\n'));
+
+        let mut config = config(8);
+        config.pio = Some(vec![TraceBusRange {
+            first: 0x3f8,
+            last: 0x3ff,
+        }]);
+        let tracer = BusTracer::new(&config).unwrap();
+        tracer.record(0, BusKind::Pio, false, 0x3fd, &[0x60]);
+        tracer.record(0, BusKind::Pio, true, 0x80, &[0x12]);
+        tracer.record(0, BusKind::Mmio, true, 0x3f8, &[0]);
+        let recent = tracer.recent();
+        assert_eq!(recent.len(), 1);
+        assert_eq!((recent[0].addr, recent[0].write), (0x3fd, false));
+    }
+
+    #[test]
+    fn test_binary_trace() {
+        let path = std::env::temp_dir().join(format!("ch-bus-trace-{}", std::process::id()));
+        let mut config = config(0);
+        config.file = Some(path.clone());
+        let tracer = BusTracer::new(&config).unwrap();
+        tracer.record(2, BusKind::Mmio, true, 0xfee0_00b0, &[0, 0, 0, 0]);
+        tracer.record(0, BusKind::Pio, false, 0x60, &[0xfa]);
+        tracer.flush().unwrap();
+        assert!(tracer.recent().is_empty());
+
+        // The VM booted again after a reboot appends to the same trace.
+        let tracer = BusTracer::new(&config).unwrap();
+        tracer.record(1, BusKind::Pio, true, 0x64, &[0xfe]);
+        tracer.flush().unwrap();
+
+        let mut text = Vec::new();
+        convert(File::open(&path).unwrap(), &mut text).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let text = String::from_utf8(text).unwrap();
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[0].ends_with(" vcpu2 mmio w 0xfee000b0/4 0x0"));
+        assert!(lines[1].ends_with(" vcpu0 pio  r 0x60/1 0xfa"));
+        assert!(lines[2].ends_with(" vcpu1 pio  w 0x64/1 0xfe"));
+
+        assert!(convert(&b"not a trace"[..], Vec::new()).is_err());
+    }
+}
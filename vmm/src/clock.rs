@@ -0,0 +1,134 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Guest clock adjustment across pause/resume and snapshot/restore.
+//!
+//! The kvmclock value is saved along with the host clocks when the VM stops
+//! running, and advanced by the time elapsed on the host once it runs again,
+//! so that the guest doesn't lag behind the host by the time it was stopped.
+
+use kvm_bindings::kvm_clock_data;
+use kvm_ioctls::VmFd;
+use std::io;
+use vmm_sys_util::ioctl::{ioctl_with_mut_ref, ioctl_with_ref};
+
+const KVMIO: u32 = 0xAE;
+
+// Not exposed by kvm-ioctls yet.
+ioctl_iow_nr!(KVM_SET_CLOCK, KVMIO, 0x7b, kvm_clock_data);
+ioctl_ior_nr!(KVM_GET_CLOCK, KVMIO, 0x7c, kvm_clock_data);
+
+/// Host clock used to compute the time during which the guest was stopped.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum HostClock {
+    /// Only valid as long as the host hasn't rebooted, used on resume.
+    Monotonic,
+    /// Also valid on another host, used on restore.
+    Realtime,
+}
+
+/// The guest clock, along with the host clocks at the time it was read.
+/// All values are in nanoseconds.
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+pub struct ClockState {
+    pub kvmclock: u64,
+    pub host_monotonic: u64,
+    pub host_realtime: u64,
+}
+
+fn host_clock_ns(clock: libc::clockid_t) -> io::Result<u64> {
+    let mut ts = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    // Safe because ts is a valid timespec and we check the return value.
+    let ret = unsafe { libc::clock_gettime(clock, &mut ts) };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(ts.tv_sec as u64 * 1_000_000_000 + ts.tv_nsec as u64)
+}
+
+impl ClockState {
+    /// Reads the guest clock of the VM `fd`.
+    pub fn save(fd: &VmFd) -> io::Result<Self> {
+        let mut clock = kvm_clock_data::default();
+        // Safe because clock is a valid kvm_clock_data and we check the
+        // return value.
+        let ret = unsafe { ioctl_with_mut_ref(fd, KVM_GET_CLOCK(), &mut clock) };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(ClockState {
+            kvmclock: clock.clock,
+            host_monotonic: host_clock_ns(libc::CLOCK_MONOTONIC)?,
+            host_realtime: host_clock_ns(libc::CLOCK_REALTIME)?,
+        })
+    }
+
+    /// Sets the guest clock of the VM `fd`, advanced by the time elapsed on
+    /// the `host_clock` since the state was saved.
+    pub fn restore(&self, fd: &VmFd, host_clock: HostClock) -> io::Result<()> {
+        let now = ClockState {
+            kvmclock: 0,
+            host_monotonic: host_clock_ns(libc::CLOCK_MONOTONIC)?,
+            host_realtime: host_clock_ns(libc::CLOCK_REALTIME)?,
+        };
+        let clock = kvm_clock_data {
+            clock: self.adjusted(&now, host_clock),
+            ..Default::default()
+        };
+        // Safe because clock is a valid kvm_clock_data and we check the
+        // return value.
+        let ret = unsafe { ioctl_with_ref(fd, KVM_SET_CLOCK(), &clock) };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(())
+    }
+
+    // The guest clock is never moved backwards, should the host clock have
+    // been.
+    fn adjusted(&self, now: &ClockState, host_clock: HostClock) -> u64 {
+        let elapsed = match host_clock {
+            HostClock::Monotonic => now.host_monotonic.saturating_sub(self.host_monotonic),
+            HostClock::Realtime => now.host_realtime.saturating_sub(self.host_realtime),
+        };
+        self.kvmclock.saturating_add(elapsed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_adjusted_clock() {
+        let saved = ClockState {
+            kvmclock: 5_000,
+            host_monotonic: 10_000,
+            host_realtime: 1_000_000,
+        };
+        let now = ClockState {
+            kvmclock: 0,
+            host_monotonic: 13_000,
+            host_realtime: 1_500_000,
+        };
+
+        assert_eq!(saved.adjusted(&now, HostClock::Monotonic), 8_000);
+        assert_eq!(saved.adjusted(&now, HostClock::Realtime), 505_000);
+
+        // The host clock went backwards.
+        let now = ClockState {
+            kvmclock: 0,
+            host_monotonic: 1_000,
+            host_realtime: 1_000,
+        };
+        assert_eq!(saved.adjusted(&now, HostClock::Monotonic), 5_000);
+        assert_eq!(saved.adjusted(&now, HostClock::Realtime), 5_000);
+    }
+}
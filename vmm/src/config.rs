@@ -5,22 +5,36 @@
 
 extern crate vm_virtio;
 
+use arch::{MmioHole, RegionType};
 use clap::ArgMatches;
 use net_util::MacAddr;
 use std::convert::From;
+use std::fmt;
 use std::io;
 use std::net::AddrParseError;
-use std::net::Ipv4Addr;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::os::unix::io::RawFd;
 use std::path::PathBuf;
 use std::result;
+use std::sync::Arc;
+use std::time::Duration;
+use vm_memory::{Address, GuestAddress};
 
 pub const DEFAULT_VCPUS: u8 = 1;
+/// The host CPU is passed through.
+pub const DEFAULT_CPU_MODEL: &str = "host";
 pub const DEFAULT_MEMORY_MB: u64 = 512;
 pub const DEFAULT_RNG_SOURCE: &str = "/dev/urandom";
 pub const DEFAULT_NUM_QUEUES_VUNET: usize = 2;
 pub const DEFAULT_QUEUE_SIZE_VUNET: u16 = 256;
 pub const DEFAULT_NUM_QUEUES_VUBLK: usize = 1;
 pub const DEFAULT_QUEUE_SIZE_VUBLK: u16 = 128;
+// The virtio specification limits queues to 32768 entries.
+pub const MAX_QUEUE_SIZE: u16 = 32768;
+// The explicit guest RAM regions are made of whole pages.
+const MEMORY_REGION_ALIGNMENT: u64 = 4 << 10;
+// The devices are plugged into the single PCI bus, which has 32 slots.
+const PCI_SLOTS: u32 = 32;
 
 /// Errors associated with VM configuration parameters.
 #[derive(Debug)]
@@ -31,10 +45,23 @@ pub enum Error {
     ParseCpusUnknownParam,
     /// Max is less than boot
     ParseCpusMaxLowerThanBoot,
+    /// Failed parsing vCPU internal error action.
+    ParseCpusInternalErrorParam,
+    /// Failed parsing vCPU MSR overrides parameter.
+    ParseCpusMsrsParam,
+    /// Unknown vCPU model.
+    ParseCpusModelParam,
     /// Failed parsing memory file parameter.
     ParseMemoryFileParam,
+    /// Failed parsing memory regions parameter.
+    ParseMemoryRegionsParam,
     /// Failed parsing kernel parameters.
     ParseKernelParams,
+    /// Failed parsing kernel file descriptor parameter.
+    ParseKernelFdParam(std::num::ParseIntError),
+    /// The kernel is given more or less than one of a path, a file
+    /// descriptor and an image in memory.
+    InvalidKernelSource,
     /// Failed parsing kernel command line parameters.
     ParseCmdlineParams,
     /// Failed parsing disks parameters.
@@ -49,8 +76,39 @@ pub enum Error {
     ParseDiskVhostSocketRequired,
     /// Failed parsing disk wce parameter.
     ParseDiskWceParam(std::str::ParseBoolError),
+    /// Failed parsing disk root partition parameter.
+    ParseDiskRootParam(std::num::ParseIntError),
+    /// Failed parsing disk request timeout parameter.
+    ParseDiskRequestTimeoutParam(std::num::ParseIntError),
+    /// Failed parsing disk file descriptor parameter.
+    ParseDiskFdParam(std::num::ParseIntError),
+    /// A disk is given both or neither of a path and a file descriptor, or a
+    /// file descriptor along with a vhost-user socket.
+    InvalidDiskSource,
+    /// The standard input, output and error can't be used as a file.
+    InvalidInheritedFd(i32),
+    /// The VMM didn't inherit the file descriptor.
+    ClosedInheritedFd(i32),
+    /// More than one disk is the root device.
+    MultipleRootDisks,
+    /// The root disk has no serial the guest can name it after.
+    InvalidRootDiskSerial,
     /// Failed parsing random number generator parameters.
     ParseRngParams,
+    /// Failed parsing balloon statistics polling interval parameter.
+    ParseBalloonStatsPollingIntervalParam(std::num::ParseIntError),
+    /// The balloon pressure step is null.
+    InvalidBalloonPressureStep,
+    /// Unexpected balloon parameter.
+    ParseBalloonUnknownParam,
+    /// Failed parsing GPU width parameter.
+    ParseGpuWidthParam(std::num::ParseIntError),
+    /// Failed parsing GPU height parameter.
+    ParseGpuHeightParam(std::num::ParseIntError),
+    /// Unexpected GPU parameter.
+    ParseGpuUnknownParam,
+    /// GPU resolution is null, or too large.
+    InvalidGpuResolution(u32, u32),
     /// Failed parsing network ip parameter.
     ParseNetIpParam(AddrParseError),
     /// Failed parsing network mask parameter.
@@ -65,6 +123,12 @@ pub enum Error {
     ParseNetVhostParam(std::str::ParseBoolError),
     /// Need a vhost socket
     ParseNetVhostSocketRequired,
+    /// vhost-net and vhost-user can't be both enabled
+    ParseNetVhostNetWithVhostUser,
+    /// Failed parsing network pcap snapshot length parameter.
+    ParseNetPcapSnaplenParam(std::num::ParseIntError),
+    /// The network pcap snapshot length is null.
+    InvalidNetPcapSnaplen,
     /// Failed parsing fs tag parameter.
     ParseFsTagParam,
     /// Failed parsing fs socket path parameter.
@@ -79,6 +143,38 @@ pub enum Error {
     InvalidCacheSizeWithDaxOff,
     /// Failed parsing persitent memory file parameter.
     ParsePmemFileParam,
+    /// Failed parsing SCSI LUN parameter.
+    ParseScsiLunParam(std::num::ParseIntError),
+    /// Failed parsing SCSI file parameter.
+    ParseScsiFileParam,
+    /// SCSI LUN is out of range, or used more than once.
+    InvalidScsiLun(u16),
+    /// Failed parsing platform UUID parameter.
+    ParsePlatformUuidParam,
+    /// Failed parsing NUMA node id parameter.
+    ParseNumaIdParam(std::num::ParseIntError),
+    /// Failed parsing NUMA node cpus parameter.
+    ParseNumaCpusParam,
+    /// Failed parsing NUMA node host_node parameter.
+    ParseNumaHostNodeParam(std::num::ParseIntError),
+    /// Failed parsing NUMA node distances parameter.
+    ParseNumaDistancesParam,
+    /// Unexpected NUMA node parameter.
+    ParseNumaUnknownParam,
+    /// NUMA node ids must be unique and numbered from 0.
+    InvalidNumaNodeId(u32),
+    /// A vCPU is out of range, or assigned to more than one NUMA node.
+    InvalidNumaNodeCpu(u8),
+    /// NUMA node sizes must be 2MiB aligned and add up to the memory size.
+    InvalidNumaMemorySize,
+    /// Unexpected SGX EPC section parameter.
+    ParseSgxEpcUnknownParam,
+    /// SGX EPC section sizes must be non-null and 4KiB aligned.
+    InvalidSgxEpcSize(u64),
+    /// The SGX EPC sections add up to more than the address space.
+    InvalidSgxEpcTotalSize,
+    /// Unexpected platform parameter.
+    ParsePlatformUnknownParam,
     /// Failed parsing size parameter.
     ParseSizeParam(std::num::ParseIntError),
     /// Failed parsing console parameter.
@@ -101,29 +197,429 @@ pub enum Error {
     ParseVsockCidParam(std::num::ParseIntError),
     /// Failed parsing vsock socket path parameter.
     ParseVsockSockParam,
+    /// Failed parsing vsock port listeners parameter.
+    ParseVsockListenParam,
     /// Missing kernel configuration
     ValidateMissingKernelConfig,
     /// Failed parsing generic on|off parameter.
     ParseOnOff,
+    /// Queue size is not a power of two, or is bigger than the virtio limit.
+    InvalidQueueSize(u16),
+    /// Failed parsing irqchip parameter.
+    ParseIrqChipParam,
+    /// The PIT can only be created along with a kernel irqchip.
+    PitWithoutKernelIrqChip,
+    /// The 32-bit MMIO hole is misaligned, or overlaps fixed areas.
+    InvalidMmioHole(u64, u64),
+    /// The memory region with the given index is empty, or wraps around the
+    /// address space.
+    InvalidMemoryRegion(usize),
+    /// The memory region with the given index isn't page aligned.
+    UnalignedMemoryRegion(usize),
+    /// The memory regions with the given indices overlap.
+    OverlappingMemoryRegions(usize, usize),
+    /// The memory region with the given index overlaps the 32-bit devices
+    /// area.
+    MemoryRegionInDeviceArea(usize),
+    /// The memory regions don't add up to the memory size.
+    MemoryRegionsSize(u64, u64),
+    /// Memory backed by a file is shared, and never merged by KSM.
+    MergeableMemoryFile,
+    /// Failed parsing stdin parameter.
+    ParseStdinParam,
+    /// Failed parsing the VNC listening address.
+    ParseVncParam(AddrParseError),
+    /// A VNC server needs a GPU to display.
+    VncWithoutGpu,
+    /// Failed parsing the number of bus accesses kept in memory.
+    ParseTraceBusEntriesParam(std::num::ParseIntError),
+    /// Failed parsing the traced I/O port or MMIO ranges.
+    ParseTraceBusRangeParam,
+    /// Failed parsing bus tracing parameters.
+    ParseTraceBusUnknownParam,
+    /// Failed parsing the watchdog timeout.
+    ParseWatchdogTimeoutParam(std::num::ParseIntError),
+    /// Failed parsing the watchdog expiry action.
+    ParseWatchdogActionParam,
+    /// Failed parsing watchdog parameters.
+    ParseWatchdogUnknownParam,
+    /// The watchdog timeout is null.
+    InvalidWatchdogTimeout,
+    /// Failed parsing the seconds of runtime budget.
+    ParseRuntimeBudgetSecondsParam(std::num::ParseIntError),
+    /// Failed parsing the runtime budget action.
+    ParseRuntimeBudgetActionParam,
+    /// Failed parsing runtime budget parameters.
+    ParseRuntimeBudgetUnknownParam,
+    /// The runtime budget is null.
+    InvalidRuntimeBudget,
+    /// Failed parsing the seed of the random number generator.
+    ParseRngSeed(std::num::ParseIntError),
+    /// The device id is empty, or reserved.
+    InvalidDeviceId(String),
+    /// Several devices have the same id.
+    DuplicateDeviceId(String),
+    /// Failed parsing the PCI slot of a device.
+    ParsePciSlotParam(std::num::ParseIntError),
+    /// The PCI slot is either the host bridge one or beyond the last one.
+    InvalidPciSlot(u32),
+    /// Several devices have the same PCI slot.
+    DuplicatePciSlot(u32),
+    /// The disk serial is longer than what the guest reads.
+    InvalidDiskSerial(String),
+    /// A vhost-user disk is given a serial, which only the backend sets.
+    InvalidVhostUserDiskSerial,
+    /// The disk request timeout is null.
+    InvalidDiskRequestTimeout,
+    /// Failed parsing the JSON VM configuration.
+    ParseJson(serde_json::Error),
 }
 pub type Result<T> = result::Result<T, Error>;
 
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::ParseCpusParams(_) => write!(f, "failed parsing cpus parameters"),
+            Error::ParseCpusUnknownParam => write!(f, "unexpected vCPU parameter"),
+            Error::ParseCpusMaxLowerThanBoot => write!(f, "max vCPUs is less than boot vCPUs"),
+            Error::ParseCpusMsrsParam => write!(f, "failed parsing vCPU MSR overrides parameter"),
+            Error::ParseCpusModelParam => write!(f, "unknown vCPU model"),
+            Error::ParseCpusInternalErrorParam => {
+                write!(f, "failed parsing vCPU internal error action")
+            }
+            Error::ParseMemoryFileParam => write!(f, "failed parsing memory file parameter"),
+            Error::ParseMemoryRegionsParam => {
+                write!(f, "failed parsing memory regions parameter")
+            }
+            Error::ParseKernelParams => write!(f, "failed parsing kernel parameters"),
+            Error::ParseKernelFdParam(_) => {
+                write!(f, "failed parsing kernel file descriptor parameter")
+            }
+            Error::InvalidKernelSource => write!(
+                f,
+                "the kernel must be given either a path, a file descriptor or an image"
+            ),
+            Error::ParseCmdlineParams => write!(f, "failed parsing kernel command line parameters"),
+            Error::ParseDisksParams => write!(f, "failed parsing disks parameters"),
+            Error::ParseDiskNumQueuesParam(_) => {
+                write!(f, "failed parsing disk queue number parameter")
+            }
+            Error::ParseDiskQueueSizeParam(_) => {
+                write!(f, "failed parsing disk queue size parameter")
+            }
+            Error::ParseDiskVhostParam(_) => write!(f, "failed to parse vhost parameters"),
+            Error::ParseDiskVhostSocketRequired => write!(f, "need a vhost socket"),
+            Error::ParseDiskWceParam(_) => write!(f, "failed parsing disk wce parameter"),
+            Error::ParseDiskRootParam(_) => {
+                write!(f, "failed parsing disk root partition parameter")
+            }
+            Error::ParseDiskRequestTimeoutParam(_) => {
+                write!(f, "failed parsing disk request timeout parameter")
+            }
+            Error::ParseDiskFdParam(_) => {
+                write!(f, "failed parsing disk file descriptor parameter")
+            }
+            Error::InvalidDiskSource => write!(
+                f,
+                "a disk must be given either a path, a file descriptor or a vhost-user socket"
+            ),
+            Error::InvalidInheritedFd(fd) => {
+                write!(f, "file descriptor {} can't be used as a file", fd)
+            }
+            Error::ClosedInheritedFd(fd) => write!(f, "file descriptor {} isn't open", fd),
+            Error::MultipleRootDisks => write!(f, "more than one disk is the root device"),
+            Error::InvalidRootDiskSerial => write!(
+                f,
+                "the root disk needs a serial made of letters, digits, '-', '_' and '.'"
+            ),
+            Error::ParseRngParams => write!(f, "failed parsing random number generator parameters"),
+            Error::ParseBalloonStatsPollingIntervalParam(_) => {
+                write!(
+                    f,
+                    "failed parsing balloon statistics polling interval parameter"
+                )
+            }
+            Error::InvalidBalloonPressureStep => write!(f, "balloon pressure step is null"),
+            Error::ParseBalloonUnknownParam => write!(f, "unexpected balloon parameter"),
+            Error::ParseGpuWidthParam(_) => write!(f, "failed parsing GPU width parameter"),
+            Error::ParseGpuHeightParam(_) => write!(f, "failed parsing GPU height parameter"),
+            Error::ParseGpuUnknownParam => write!(f, "unexpected GPU parameter"),
+            Error::InvalidGpuResolution(width, height) => {
+                write!(
+                    f,
+                    "GPU resolution {}x{} is null, or too large",
+                    width, height
+                )
+            }
+            Error::ParseNetIpParam(_) => write!(f, "failed parsing network ip parameter"),
+            Error::ParseNetMaskParam(_) => write!(f, "failed parsing network mask parameter"),
+            Error::ParseNetMacParam(_) => write!(f, "failed parsing network mac parameter"),
+            Error::ParseNetNumQueuesParam(_) => {
+                write!(f, "failed parsing network queue number parameter")
+            }
+            Error::ParseNetQueueSizeParam(_) => {
+                write!(f, "failed parsing network queue size parameter")
+            }
+            Error::ParseNetVhostParam(_) => write!(f, "failed to parse vhost parameters"),
+            Error::ParseNetVhostSocketRequired => write!(f, "need a vhost socket"),
+            Error::ParseNetVhostNetWithVhostUser => {
+                write!(f, "vhost-net and vhost-user can't be both enabled")
+            }
+            Error::ParseNetPcapSnaplenParam(_) => {
+                write!(f, "failed parsing network pcap snapshot length parameter")
+            }
+            Error::InvalidNetPcapSnaplen => write!(f, "network pcap snapshot length is null"),
+            Error::ParseFsTagParam => write!(f, "failed parsing fs tag parameter"),
+            Error::ParseFsSockParam => write!(f, "failed parsing fs socket path parameter"),
+            Error::ParseFsNumQueuesParam(_) => {
+                write!(f, "failed parsing fs number of queues parameter")
+            }
+            Error::ParseFsQueueSizeParam(_) => write!(f, "failed parsing fs queue size parameter"),
+            Error::ParseFsDax => write!(f, "failed parsing fs dax parameter"),
+            Error::InvalidCacheSizeWithDaxOff => {
+                write!(f, "cannot have dax=off along with cache_size parameter")
+            }
+            Error::ParsePmemFileParam => {
+                write!(f, "failed parsing persistent memory file parameter")
+            }
+            Error::ParseScsiLunParam(_) => write!(f, "failed parsing SCSI LUN parameter"),
+            Error::ParseScsiFileParam => write!(f, "failed parsing SCSI file parameter"),
+            Error::InvalidScsiLun(e) => {
+                write!(f, "SCSI LUN {} is out of range, or used more than once", e)
+            }
+            Error::ParsePlatformUuidParam => write!(f, "failed parsing platform UUID parameter"),
+            Error::ParseNumaIdParam(_) => write!(f, "failed parsing NUMA node id parameter"),
+            Error::ParseNumaCpusParam => write!(f, "failed parsing NUMA node cpus parameter"),
+            Error::ParseNumaHostNodeParam(_) => {
+                write!(f, "failed parsing NUMA node host_node parameter")
+            }
+            Error::ParseNumaDistancesParam => {
+                write!(f, "failed parsing NUMA node distances parameter")
+            }
+            Error::ParseNumaUnknownParam => write!(f, "unexpected NUMA node parameter"),
+            Error::InvalidNumaNodeId(e) => write!(
+                f,
+                "invalid NUMA node id {}, ids must be unique and numbered from 0",
+                e
+            ),
+            Error::InvalidNumaNodeCpu(e) => write!(
+                f,
+                "vCPU {} is out of range, or assigned to more than one NUMA node",
+                e
+            ),
+            Error::InvalidNumaMemorySize => write!(
+                f,
+                "NUMA node sizes must be 2MiB aligned and add up to the memory size"
+            ),
+            Error::ParseSgxEpcUnknownParam => write!(f, "unexpected SGX EPC section parameter"),
+            Error::InvalidSgxEpcSize(size) => write!(
+                f,
+                "invalid SGX EPC section size {:#x}, it must be non-null and 4KiB aligned",
+                size
+            ),
+            Error::InvalidSgxEpcTotalSize => {
+                write!(
+                    f,
+                    "the SGX EPC sections add up to more than the address space"
+                )
+            }
+            Error::ParsePlatformUnknownParam => write!(f, "unexpected platform parameter"),
+            Error::ParseSizeParam(_) => write!(f, "failed parsing size parameter"),
+            Error::ParseConsoleParam => write!(f, "failed parsing console parameter"),
+            Error::ParseTTYParam => write!(f, "both console and serial are tty"),
+            Error::ParseVuNetMacParam(_) => {
+                write!(f, "failed parsing vhost-user-net mac parameter")
+            }
+            Error::ParseVuSockParam => write!(f, "failed parsing vhost-user sock parameter"),
+            Error::ParseVuNumQueuesParam(_) => {
+                write!(f, "failed parsing vhost-user queue number parameter")
+            }
+            Error::ParseVuQueueSizeParam(_) => {
+                write!(f, "failed parsing vhost-user queue size parameter")
+            }
+            Error::ParseVuNetServerParam(_) => {
+                write!(f, "failed parsing vhost-user-net server parameter")
+            }
+            Error::ParseVuBlkWceParam(_) => {
+                write!(f, "failed parsing vhost-user-blk wce parameter")
+            }
+            Error::ParseVsockCidParam(_) => write!(f, "failed parsing vsock context ID parameter"),
+            Error::ParseVsockSockParam => write!(f, "failed parsing vsock socket path parameter"),
+            Error::ParseVsockListenParam => {
+                write!(f, "failed parsing vsock port listeners parameter")
+            }
+            Error::ValidateMissingKernelConfig => write!(f, "missing kernel configuration"),
+            Error::ParseOnOff => write!(f, "failed parsing generic on|off parameter"),
+            Error::InvalidQueueSize(e) => write!(
+                f,
+                "queue size {} is not a power of two, or is bigger than the virtio limit",
+                e
+            ),
+            Error::ParseIrqChipParam => write!(f, "failed parsing irqchip parameter"),
+            Error::PitWithoutKernelIrqChip => {
+                write!(f, "the PIT can only be created along with a kernel irqchip")
+            }
+            Error::InvalidMmioHole(base, size) => write!(
+                f,
+                "invalid 32-bit MMIO hole of {:#x} bytes at {:#x}",
+                size, base
+            ),
+            Error::InvalidMemoryRegion(index) => write!(
+                f,
+                "memory region {} is empty, or wraps around the address space",
+                index
+            ),
+            Error::UnalignedMemoryRegion(index) => {
+                write!(f, "memory region {} isn't page aligned", index)
+            }
+            Error::OverlappingMemoryRegions(first, second) => {
+                write!(f, "memory regions {} and {} overlap", first, second)
+            }
+            Error::MemoryRegionInDeviceArea(index) => write!(
+                f,
+                "memory region {} overlaps the 32-bit devices area",
+                index
+            ),
+            Error::MemoryRegionsSize(regions, size) => write!(
+                f,
+                "the memory regions add up to {:#x} bytes instead of {:#x}",
+                regions, size
+            ),
+            Error::MergeableMemoryFile => write!(
+                f,
+                "memory backed by a file cannot be mergeable, KSM only merges private pages"
+            ),
+            Error::ParseStdinParam => write!(f, "failed parsing stdin parameter"),
+            Error::ParseVncParam(_) => write!(f, "failed parsing the VNC listening address"),
+            Error::VncWithoutGpu => write!(f, "a VNC server needs a GPU to display"),
+            Error::ParseTraceBusEntriesParam(_) => {
+                write!(f, "failed parsing the number of traced bus accesses")
+            }
+            Error::ParseTraceBusRangeParam => write!(f, "failed parsing the traced bus ranges"),
+            Error::ParseTraceBusUnknownParam => write!(f, "unexpected bus tracing parameter"),
+            Error::ParseWatchdogTimeoutParam(_) => write!(f, "failed parsing the watchdog timeout"),
+            Error::ParseWatchdogActionParam => {
+                write!(f, "failed parsing the watchdog expiry action")
+            }
+            Error::ParseWatchdogUnknownParam => write!(f, "unexpected watchdog parameter"),
+            Error::InvalidWatchdogTimeout => write!(f, "the watchdog timeout is null"),
+            Error::ParseRuntimeBudgetSecondsParam(_) => {
+                write!(f, "failed parsing the seconds of runtime budget")
+            }
+            Error::ParseRuntimeBudgetActionParam => {
+                write!(f, "failed parsing the runtime budget action")
+            }
+            Error::ParseRuntimeBudgetUnknownParam => {
+                write!(f, "unexpected runtime budget parameter")
+            }
+            Error::InvalidRuntimeBudget => write!(f, "the runtime budget is null"),
+            Error::ParseRngSeed(_) => write!(f, "failed parsing the random number generator seed"),
+            Error::InvalidDeviceId(id) => write!(f, "invalid device id {:?}", id),
+            Error::DuplicateDeviceId(id) => write!(f, "several devices have the id {:?}", id),
+            Error::ParsePciSlotParam(_) => write!(f, "failed parsing the PCI slot"),
+            Error::InvalidPciSlot(slot) => write!(
+                f,
+                "invalid PCI slot {}, the slots go from 1 to {}",
+                slot,
+                PCI_SLOTS - 1
+            ),
+            Error::DuplicatePciSlot(slot) => {
+                write!(f, "several devices have the PCI slot {}", slot)
+            }
+            Error::InvalidDiskSerial(serial) => write!(
+                f,
+                "disk serial {:?} is longer than {} bytes",
+                serial,
+                vm_virtio::DISK_SERIAL_MAX_LEN
+            ),
+            Error::InvalidVhostUserDiskSerial => {
+                write!(f, "a vhost-user disk can't be given a serial")
+            }
+            Error::InvalidDiskRequestTimeout => write!(f, "the disk request timeout is null"),
+            Error::ParseJson(_) => write!(f, "failed parsing the JSON VM configuration"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::ParseCpusParams(e) => Some(e),
+            Error::ParseDiskNumQueuesParam(e) => Some(e),
+            Error::ParseDiskQueueSizeParam(e) => Some(e),
+            Error::ParseDiskVhostParam(e) => Some(e),
+            Error::ParseDiskWceParam(e) => Some(e),
+            Error::ParseDiskRootParam(e) => Some(e),
+            Error::ParseDiskRequestTimeoutParam(e) => Some(e),
+            Error::ParseDiskFdParam(e) => Some(e),
+            Error::ParseKernelFdParam(e) => Some(e),
+            Error::ParseNetIpParam(e) => Some(e),
+            Error::ParseVncParam(e) => Some(e),
+            Error::ParseTraceBusEntriesParam(e) => Some(e),
+            Error::ParseWatchdogTimeoutParam(e) => Some(e),
+            Error::ParseRuntimeBudgetSecondsParam(e) => Some(e),
+            Error::ParseNetMaskParam(e) => Some(e),
+            Error::ParseNetMacParam(e) => Some(e),
+            Error::ParseNetNumQueuesParam(e) => Some(e),
+            Error::ParseNetQueueSizeParam(e) => Some(e),
+            Error::ParseNetVhostParam(e) => Some(e),
+            Error::ParseNetPcapSnaplenParam(e) => Some(e),
+            Error::ParseFsNumQueuesParam(e) => Some(e),
+            Error::ParseFsQueueSizeParam(e) => Some(e),
+            Error::ParseScsiLunParam(e) => Some(e),
+            Error::ParseNumaIdParam(e) => Some(e),
+            Error::ParseNumaHostNodeParam(e) => Some(e),
+            Error::ParseSizeParam(e) => Some(e),
+            Error::ParseBalloonStatsPollingIntervalParam(e) => Some(e),
+            Error::ParseGpuWidthParam(e) => Some(e),
+            Error::ParseGpuHeightParam(e) => Some(e),
+            Error::ParseVuNetMacParam(e) => Some(e),
+            Error::ParseVuNumQueuesParam(e) => Some(e),
+            Error::ParseVuQueueSizeParam(e) => Some(e),
+            Error::ParseVuNetServerParam(e) => Some(e),
+            Error::ParseVuBlkWceParam(e) => Some(e),
+            Error::ParseVsockCidParam(e) => Some(e),
+            Error::ParsePciSlotParam(e) => Some(e),
+            Error::ParseRngSeed(e) => Some(e),
+            Error::ParseJson(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
 pub struct VmParams<'a> {
     pub cpus: &'a str,
     pub memory: &'a str,
     pub kernel: Option<&'a str>,
+    pub firmware: Option<&'a str>,
     pub cmdline: Option<&'a str>,
     pub disks: Option<Vec<&'a str>>,
     pub net: Option<Vec<&'a str>>,
     pub rng: &'a str,
+    pub rng_seed: Option<&'a str>,
+    pub balloon: Option<&'a str>,
+    pub gpu: Option<&'a str>,
     pub fs: Option<Vec<&'a str>>,
     pub pmem: Option<Vec<&'a str>>,
+    pub scsi: Option<Vec<&'a str>>,
+    pub platform: Option<&'a str>,
+    pub numa: Option<Vec<&'a str>>,
+    pub sgx_epc: Option<Vec<&'a str>>,
     pub serial: &'a str,
     pub console: &'a str,
     pub devices: Option<Vec<&'a str>>,
     pub vhost_user_net: Option<Vec<&'a str>>,
     pub vhost_user_blk: Option<Vec<&'a str>>,
     pub vsock: Option<Vec<&'a str>>,
+    pub irqchip: &'a str,
+    pub pit: bool,
+    pub stdin: &'a str,
+    pub vnc: Option<&'a str>,
+    pub trace_bus: Option<&'a str>,
+    pub metrics_socket: Option<&'a str>,
+    pub watchdog: Option<&'a str>,
+    pub runtime_budget: Option<&'a str>,
+    pub landlock: Option<&'a str>,
 }
 
 impl<'a> VmParams<'a> {
@@ -132,39 +628,88 @@ impl<'a> VmParams<'a> {
         let cpus = args.value_of("cpus").unwrap();
         let memory = args.value_of("memory").unwrap();
         let rng = args.value_of("rng").unwrap();
+        let rng_seed = args.value_of("rng-seed");
         let serial = args.value_of("serial").unwrap();
+        let irqchip = args.value_of("irqchip").unwrap();
+        let stdin = args.value_of("stdin").unwrap();
 
         let kernel = args.value_of("kernel");
+        let firmware = args.value_of("firmware");
         let cmdline = args.value_of("cmdline");
 
         let disks: Option<Vec<&str>> = args.values_of("disk").map(|x| x.collect());
         let net: Option<Vec<&str>> = args.values_of("net").map(|x| x.collect());
         let console = args.value_of("console").unwrap();
+        let balloon = args.value_of("balloon");
+        // All the GPU parameters are optional.
+        let gpu = if args.is_present("gpu") {
+            Some(args.value_of("gpu").unwrap_or(""))
+        } else {
+            None
+        };
         let fs: Option<Vec<&str>> = args.values_of("fs").map(|x| x.collect());
         let pmem: Option<Vec<&str>> = args.values_of("pmem").map(|x| x.collect());
+        let scsi: Option<Vec<&str>> = args.values_of("scsi").map(|x| x.collect());
+        let platform = args.value_of("platform");
+        let numa: Option<Vec<&str>> = args.values_of("numa").map(|x| x.collect());
+        let sgx_epc: Option<Vec<&str>> = args.values_of("sgx-epc").map(|x| x.collect());
         let devices: Option<Vec<&str>> = args.values_of("device").map(|x| x.collect());
         let vhost_user_net: Option<Vec<&str>> =
             args.values_of("vhost-user-net").map(|x| x.collect());
         let vhost_user_blk: Option<Vec<&str>> =
             args.values_of("vhost-user-blk").map(|x| x.collect());
         let vsock: Option<Vec<&str>> = args.values_of("vsock").map(|x| x.collect());
+        let pit = args.is_present("pit");
+        let vnc = args.value_of("vnc");
+        // The option can be given without any value, to trace everything.
+        let trace_bus = if args.is_present("trace-bus") {
+            Some(args.value_of("trace-bus").unwrap_or(""))
+        } else {
+            None
+        };
+        let metrics_socket = args.value_of("metrics-socket");
+        // All the watchdog parameters are optional.
+        let watchdog = if args.is_present("watchdog") {
+            Some(args.value_of("watchdog").unwrap_or(""))
+        } else {
+            None
+        };
+        let runtime_budget = args.value_of("runtime-budget");
+        let landlock = args.value_of("landlock");
 
         VmParams {
             cpus,
             memory,
             kernel,
+            firmware,
             cmdline,
             disks,
             net,
             rng,
+            rng_seed,
+            balloon,
+            gpu,
             fs,
             pmem,
+            scsi,
+            platform,
+            numa,
+            sgx_epc,
             serial,
             console,
             devices,
             vhost_user_net,
             vhost_user_blk,
             vsock,
+            irqchip,
+            pit,
+            stdin,
+            vnc,
+            trace_bus,
+            metrics_socket,
+            watchdog,
+            runtime_budget,
+            landlock,
         }
     }
 }
@@ -187,6 +732,14 @@ fn parse_size(size: &str) -> Result<u64> {
     Ok(res << shift)
 }
 
+fn validate_queue_size(queue_size: u16) -> Result<()> {
+    if !queue_size.is_power_of_two() || queue_size > MAX_QUEUE_SIZE {
+        return Err(Error::InvalidQueueSize(queue_size));
+    }
+
+    Ok(())
+}
+
 fn parse_on_off(param: &str) -> Result<bool> {
     if !param.is_empty() {
         let res = match param {
@@ -201,10 +754,306 @@ fn parse_on_off(param: &str) -> Result<bool> {
     }
 }
 
+fn parse_device_id(id: &str) -> Option<String> {
+    if id.is_empty() {
+        None
+    } else {
+        Some(id.to_string())
+    }
+}
+
+fn parse_pci_slot(slot: &str) -> Result<Option<u32>> {
+    if slot.is_empty() {
+        Ok(None)
+    } else {
+        Ok(Some(slot.parse().map_err(Error::ParsePciSlotParam)?))
+    }
+}
+
+fn validate_pci_slot(slot: u32) -> Result<()> {
+    // The host bridge takes the first slot.
+    if slot == 0 || slot >= PCI_SLOTS {
+        return Err(Error::InvalidPciSlot(slot));
+    }
+
+    Ok(())
+}
+
+// The ids starting with an underscore are reserved for the ones the VMM
+// generates.
+fn validate_device_id(id: &str) -> Result<()> {
+    if id.is_empty() || id.starts_with('_') {
+        return Err(Error::InvalidDeviceId(id.to_string()));
+    }
+
+    Ok(())
+}
+
+// The first id made of `prefix` and a number which isn't taken yet.
+fn next_device_id<S: AsRef<str>>(prefix: &str, taken: &[S]) -> String {
+    (0..)
+        .map(|index| format!("{}{}", prefix, index))
+        .find(|id| !taken.iter().any(|taken| taken.as_ref() == id))
+        .unwrap()
+}
+
+fn validate_inherited_fd(fd: RawFd) -> Result<()> {
+    if fd <= libc::STDERR_FILENO {
+        return Err(Error::InvalidInheritedFd(fd));
+    }
+
+    // Safe because the call only reads the flags of the fd.
+    if unsafe { libc::fcntl(fd, libc::F_GETFD) } < 0 {
+        return Err(Error::ClosedInheritedFd(fd));
+    }
+
+    Ok(())
+}
+
+fn validate_root_disk(disks: &[DiskConfig]) -> Result<()> {
+    if disks.iter().filter(|d| d.root.is_some()).count() > 1 {
+        return Err(Error::MultipleRootDisks);
+    }
+
+    Ok(())
+}
+
+// Checks the NUMA nodes describe the whole boot RAM, with each vCPU being
+// assigned to one node at most.
+fn validate_numa_config(
+    numa_nodes: &[NumaConfig],
+    cpus: &CpusConfig,
+    memory: &MemoryConfig,
+) -> Result<()> {
+    let mut assigned_cpus = Vec::new();
+    for (index, node) in numa_nodes.iter().enumerate() {
+        if node.id as usize >= numa_nodes.len()
+            || numa_nodes[..index].iter().any(|n| n.id == node.id)
+        {
+            return Err(Error::InvalidNumaNodeId(node.id));
+        }
+
+        if node.size == 0 || node.size % (2 << 20) != 0 {
+            return Err(Error::InvalidNumaMemorySize);
+        }
+
+        if let Some(distances) = &node.distances {
+            for distance in distances.iter() {
+                if distance.destination as usize >= numa_nodes.len() {
+                    return Err(Error::InvalidNumaNodeId(distance.destination));
+                }
+            }
+        }
+
+        for cpu in node.cpus.iter().flatten() {
+            if *cpu >= cpus.max_vcpus || assigned_cpus.contains(cpu) {
+                return Err(Error::InvalidNumaNodeCpu(*cpu));
+            }
+            assigned_cpus.push(*cpu);
+        }
+    }
+
+    if numa_nodes.iter().map(|n| n.size).sum::<u64>() != memory.size {
+        return Err(Error::InvalidNumaMemorySize);
+    }
+
+    Ok(())
+}
+
+/// What to do when KVM reports an internal error on a vCPU.
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+pub enum InternalErrorAction {
+    /// Abort the VMM process, leaving a core dump behind.
+    Abort,
+    /// Reset the VM.
+    Reset,
+    /// Log the vCPU registers and the guest code around the instruction
+    /// pointer, then shut the VMM down.
+    DumpAndExit,
+}
+
+impl InternalErrorAction {
+    pub fn parse(action: &str) -> Result<Self> {
+        match action {
+            "abort" => Ok(InternalErrorAction::Abort),
+            "reset" => Ok(InternalErrorAction::Reset),
+            "dump-and-exit" => Ok(InternalErrorAction::DumpAndExit),
+            _ => Err(Error::ParseCpusInternalErrorParam),
+        }
+    }
+}
+
+impl Default for InternalErrorAction {
+    fn default() -> Self {
+        InternalErrorAction::DumpAndExit
+    }
+}
+
+/// Interrupt controllers emulated by KVM.
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+pub enum IrqChipKind {
+    /// The PICs, the IOAPIC and the local APICs are all emulated in kernel.
+    Kernel,
+    /// Only the local APICs are emulated in kernel, the IOAPIC is emulated
+    /// by the VMM.
+    Split,
+    /// No interrupt controller at all. Only meant for experimenting, as
+    /// devices have no way to interrupt the guest.
+    None,
+}
+
+impl IrqChipKind {
+    pub fn parse(irqchip: &str) -> Result<Self> {
+        match irqchip {
+            "kernel" => Ok(IrqChipKind::Kernel),
+            "split" => Ok(IrqChipKind::Split),
+            "none" => Ok(IrqChipKind::None),
+            _ => Err(Error::ParseIrqChipParam),
+        }
+    }
+}
+
+/// What to do when the guest stops pinging its watchdog.
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+pub enum WatchdogAction {
+    /// Reset the VM.
+    Reset,
+    /// Shut the VM down.
+    Shutdown,
+    /// Only report the expiry on the event monitor.
+    Event,
+}
+
+impl WatchdogAction {
+    pub fn parse(action: &str) -> Result<Self> {
+        match action {
+            "reset" => Ok(WatchdogAction::Reset),
+            "shutdown" => Ok(WatchdogAction::Shutdown),
+            "event" => Ok(WatchdogAction::Event),
+            _ => Err(Error::ParseWatchdogActionParam),
+        }
+    }
+}
+
+impl Default for WatchdogAction {
+    fn default() -> Self {
+        WatchdogAction::Reset
+    }
+}
+
+/// What to do once the guest has used up its runtime budget.
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+pub enum RuntimeBudgetAction {
+    /// Pause the VM, which can only be resumed for as long as it takes the
+    /// budget to expire again.
+    Pause,
+    /// Shut the VM down, along with the VMM.
+    Kill,
+}
+
+impl RuntimeBudgetAction {
+    pub fn parse(action: &str) -> Result<Self> {
+        match action {
+            "pause" => Ok(RuntimeBudgetAction::Pause),
+            "kill" => Ok(RuntimeBudgetAction::Kill),
+            _ => Err(Error::ParseRuntimeBudgetActionParam),
+        }
+    }
+}
+
+impl Default for RuntimeBudgetAction {
+    fn default() -> Self {
+        RuntimeBudgetAction::Kill
+    }
+}
+
+impl Default for IrqChipKind {
+    fn default() -> Self {
+        IrqChipKind::Split
+    }
+}
+
+/// How the VMM reads its standard input to feed the guest console.
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+pub enum StdinMode {
+    /// Put the terminal in raw mode so that every key press reaches the
+    /// guest.
+    Raw,
+    /// Leave the terminal untouched, input is forwarded line by line.
+    Cooked,
+    /// Don't read from stdin at all.
+    None,
+}
+
+impl StdinMode {
+    pub fn parse(stdin: &str) -> Result<Self> {
+        match stdin {
+            "raw" => Ok(StdinMode::Raw),
+            "cooked" => Ok(StdinMode::Cooked),
+            "none" => Ok(StdinMode::None),
+            _ => Err(Error::ParseStdinParam),
+        }
+    }
+}
+
+impl Default for StdinMode {
+    fn default() -> Self {
+        StdinMode::Raw
+    }
+}
+
 #[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
 pub struct CpusConfig {
     pub boot_vcpus: u8,
     pub max_vcpus: u8,
+    #[serde(default)]
+    pub internal_error: InternalErrorAction,
+    #[serde(default)]
+    pub msrs: Option<Vec<MsrConfig>>,
+    #[serde(default = "default_cpusconfig_model")]
+    pub model: String,
+}
+
+fn default_cpusconfig_model() -> String {
+    String::from(DEFAULT_CPU_MODEL)
+}
+
+// Checks `model` names a CPU model, aarch64 supporting the host one only.
+#[cfg(target_arch = "x86_64")]
+fn check_cpu_model(model: &str) -> Result<()> {
+    crate::cpu_model::find(model)
+        .map(|_| ())
+        .map_err(|_| Error::ParseCpusModelParam)
+}
+
+#[cfg(target_arch = "aarch64")]
+fn check_cpu_model(model: &str) -> Result<()> {
+    if model != DEFAULT_CPU_MODEL {
+        return Err(Error::ParseCpusModelParam);
+    }
+
+    Ok(())
+}
+
+/// MSR value written to every vCPU before boot.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct MsrConfig {
+    pub index: u32,
+    pub value: u64,
+}
+
+fn parse_msr_number<T>(
+    s: &str,
+    from_str_radix: fn(&str, u32) -> std::result::Result<T, std::num::ParseIntError>,
+) -> Result<T> {
+    if s.starts_with("0x") || s.starts_with("0X") {
+        from_str_radix(&s[2..], 16)
+    } else {
+        from_str_radix(s, 10)
+    }
+    .map_err(|_| Error::ParseCpusMsrsParam)
 }
 
 impl CpusConfig {
@@ -214,6 +1063,9 @@ impl CpusConfig {
             Ok(CpusConfig {
                 boot_vcpus: legacy_vcpu_count,
                 max_vcpus: legacy_vcpu_count,
+                internal_error: InternalErrorAction::default(),
+                msrs: None,
+                model: default_cpusconfig_model(),
             })
         } else {
             // Split the parameters based on the comma delimiter
@@ -221,12 +1073,21 @@ impl CpusConfig {
 
             let mut boot_str: &str = "";
             let mut max_str: &str = "";
+            let mut internal_error_str: &str = "";
+            let mut msrs_str: &str = "";
+            let mut model_str: &str = "";
 
             for param in params_list.iter() {
                 if param.starts_with("boot=") {
                     boot_str = &param["boot=".len()..];
                 } else if param.starts_with("max=") {
                     max_str = &param["max=".len()..];
+                } else if param.starts_with("internal_error=") {
+                    internal_error_str = &param["internal_error=".len()..];
+                } else if param.starts_with("msrs=") {
+                    msrs_str = &param["msrs=".len()..];
+                } else if param.starts_with("model=") {
+                    model_str = &param["model=".len()..];
                 } else {
                     return Err(Error::ParseCpusUnknownParam);
                 }
@@ -243,9 +1104,41 @@ impl CpusConfig {
                 return Err(Error::ParseCpusMaxLowerThanBoot);
             }
 
+            let internal_error = if internal_error_str != "" {
+                InternalErrorAction::parse(internal_error_str)?
+            } else {
+                InternalErrorAction::default()
+            };
+
+            let msrs = if msrs_str.is_empty() {
+                None
+            } else {
+                let mut msrs = Vec::new();
+                for msr in msrs_str.split(':') {
+                    let mut fields = msr.splitn(2, '@');
+                    let index = parse_msr_number(fields.next().unwrap(), u32::from_str_radix)?;
+                    let value = parse_msr_number(
+                        fields.next().ok_or(Error::ParseCpusMsrsParam)?,
+                        u64::from_str_radix,
+                    )?;
+                    msrs.push(MsrConfig { index, value });
+                }
+                Some(msrs)
+            };
+
+            let model = if model_str.is_empty() {
+                default_cpusconfig_model()
+            } else {
+                check_cpu_model(model_str)?;
+                model_str.to_string()
+            };
+
             Ok(CpusConfig {
                 boot_vcpus,
                 max_vcpus,
+                internal_error,
+                msrs,
+                model,
             })
         }
     }
@@ -256,19 +1149,58 @@ impl Default for CpusConfig {
         CpusConfig {
             boot_vcpus: DEFAULT_VCPUS,
             max_vcpus: DEFAULT_VCPUS,
+            internal_error: InternalErrorAction::default(),
+            msrs: None,
+            model: default_cpusconfig_model(),
         }
     }
 }
 
 #[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
 pub struct MemoryConfig {
     pub size: u64,
     #[serde(default)]
     pub file: Option<PathBuf>,
+    /// Marks the guest RAM mergeable by KSM, or unmergeable when explicitly
+    /// disabled.
     #[serde(default)]
-    pub mergeable: bool,
+    pub mergeable: Option<bool>,
+    /// Backs the guest RAM with transparent huge pages, or prevents it when
+    /// explicitly disabled.
+    #[serde(default)]
+    pub transparent_hugepages: Option<bool>,
+    /// Faults all the boot RAM in before the vCPUs start, rather than on
+    /// its first accesses. The faults are taken from background threads as
+    /// soon as the RAM is mapped, the boot waiting for them. RAM hot-added
+    /// while the guest runs is faulted in the same way, without waiting,
+    /// the guest possibly touching some of it first.
+    #[serde(default)]
+    pub prefault: bool,
     #[serde(default)]
     pub hotplug_size: Option<u64>,
+    /// RAM hot-added since the VM booted, already accounted for in `size`.
+    #[serde(default)]
+    pub hotplugged_size: Option<u64>,
+    /// Overrides the start of the 32-bit MMIO hole, where the RAM below
+    /// 4GiB stops.
+    #[serde(default)]
+    pub mmio_hole_base: Option<u64>,
+    /// Overrides the size of the 32-bit MMIO hole.
+    #[serde(default)]
+    pub mmio_hole_size: Option<u64>,
+    /// Lays out the boot RAM as these regions, instead of the architecture
+    /// default layout.
+    #[serde(default)]
+    pub regions: Option<Vec<MemoryRegionConfig>>,
+}
+
+/// A range of guest RAM, at a guest physical address.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct MemoryRegionConfig {
+    pub gpa: u64,
+    pub size: u64,
 }
 
 impl MemoryConfig {
@@ -279,8 +1211,13 @@ impl MemoryConfig {
         let mut size_str: &str = "";
         let mut file_str: &str = "";
         let mut mergeable_str: &str = "";
+        let mut transparent_hugepages_str: &str = "";
+        let mut prefault_str: &str = "";
         let mut backed = false;
         let mut hotplug_str: &str = "";
+        let mut mmio_hole_base_str: &str = "";
+        let mut mmio_hole_size_str: &str = "";
+        let mut regions_str: &str = "";
 
         for param in params_list.iter() {
             if param.starts_with("size=") {
@@ -290,8 +1227,18 @@ impl MemoryConfig {
                 file_str = &param[5..];
             } else if param.starts_with("mergeable=") {
                 mergeable_str = &param[10..];
+            } else if param.starts_with("transparent_hugepages=") {
+                transparent_hugepages_str = &param["transparent_hugepages=".len()..];
+            } else if param.starts_with("prefault=") {
+                prefault_str = &param[9..];
             } else if param.starts_with("hotplug_size=") {
                 hotplug_str = &param[13..]
+            } else if param.starts_with("mmio_hole_base=") {
+                mmio_hole_base_str = &param[15..];
+            } else if param.starts_with("mmio_hole_size=") {
+                mmio_hole_size_str = &param[15..];
+            } else if param.starts_with("regions=") {
+                regions_str = &param["regions=".len()..];
             }
         }
 
@@ -305,16 +1252,162 @@ impl MemoryConfig {
             None
         };
 
-        Ok(MemoryConfig {
-            size: parse_size(size_str)?,
+        // The regions are given as a colon separated list of
+        // "<size>@<guest_physical_address>".
+        let regions = if regions_str.is_empty() {
+            None
+        } else {
+            let mut regions = Vec::new();
+            for item in regions_str.split(':') {
+                let mut fields = item.splitn(2, '@');
+                let size = parse_size(fields.next().unwrap())
+                    .map_err(|_| Error::ParseMemoryRegionsParam)?;
+                let gpa = parse_size(fields.next().ok_or(Error::ParseMemoryRegionsParam)?)
+                    .map_err(|_| Error::ParseMemoryRegionsParam)?;
+                regions.push(MemoryRegionConfig { gpa, size });
+            }
+            Some(regions)
+        };
+
+        // The size defaults to the one of the regions.
+        let size = match &regions {
+            Some(regions) if size_str.is_empty() => regions.iter().map(|r| r.size).sum(),
+            _ => parse_size(size_str)?,
+        };
+
+        let config = MemoryConfig {
+            size,
             file,
-            mergeable: parse_on_off(mergeable_str)?,
+            mergeable: if mergeable_str == "" {
+                None
+            } else {
+                Some(parse_on_off(mergeable_str)?)
+            },
+            transparent_hugepages: if transparent_hugepages_str == "" {
+                None
+            } else {
+                Some(parse_on_off(transparent_hugepages_str)?)
+            },
+            prefault: parse_on_off(prefault_str)?,
             hotplug_size: if hotplug_str == "" {
                 None
             } else {
                 Some(parse_size(hotplug_str)?)
             },
-        })
+            hotplugged_size: None,
+            mmio_hole_base: if mmio_hole_base_str == "" {
+                None
+            } else {
+                Some(parse_size(mmio_hole_base_str)?)
+            },
+            mmio_hole_size: if mmio_hole_size_str == "" {
+                None
+            } else {
+                Some(parse_size(mmio_hole_size_str)?)
+            },
+            regions,
+        };
+        config.validate_mmio_hole()?;
+        config.validate_regions()?;
+        config.validate_mergeable()?;
+
+        Ok(config)
+    }
+
+    /// The 32-bit MMIO hole, the architecture default one unless overridden.
+    pub fn mmio_hole(&self) -> MmioHole {
+        let default = MmioHole::default();
+        MmioHole {
+            start: self.mmio_hole_base.map_or(default.start, GuestAddress),
+            size: self.mmio_hole_size.unwrap_or(default.size),
+        }
+    }
+
+    fn validate_mmio_hole(&self) -> Result<()> {
+        let mmio_hole = self.mmio_hole();
+        if !arch::check_mmio_hole(&mmio_hole) {
+            return Err(Error::InvalidMmioHole(
+                mmio_hole.start.raw_value(),
+                mmio_hole.size,
+            ));
+        }
+
+        Ok(())
+    }
+
+    fn validate_mergeable(&self) -> Result<()> {
+        if self.mergeable == Some(true) && self.file.is_some() {
+            return Err(Error::MergeableMemoryFile);
+        }
+
+        Ok(())
+    }
+
+    // Checks the regions are page aligned, and don't overlap each other nor
+    // the 32-bit devices area. They only cover the boot RAM, the hot-added
+    // RAM being allocated by the VMM.
+    fn validate_regions(&self) -> Result<()> {
+        let regions = match &self.regions {
+            Some(regions) => regions,
+            None => return Ok(()),
+        };
+
+        let devices_area: Vec<(u64, u64)> = arch::arch_memory_regions(0, self.mmio_hole())
+            .iter()
+            .filter(|r| r.2 != RegionType::Ram)
+            .map(|r| (r.0.raw_value(), r.0.raw_value() + r.1 as u64))
+            .collect();
+        let overlap = |start: u64, end: u64, other_start: u64, other_end: u64| {
+            start < other_end && other_start < end
+        };
+
+        let mut ranges: Vec<(u64, u64)> = Vec::new();
+        for (index, region) in regions.iter().enumerate() {
+            let end = match region.gpa.checked_add(region.size) {
+                Some(end) if region.size != 0 => end,
+                _ => return Err(Error::InvalidMemoryRegion(index)),
+            };
+            if region.gpa % MEMORY_REGION_ALIGNMENT != 0
+                || region.size % MEMORY_REGION_ALIGNMENT != 0
+            {
+                return Err(Error::UnalignedMemoryRegion(index));
+            }
+            if let Some(other) = ranges
+                .iter()
+                .position(|r| overlap(region.gpa, end, r.0, r.1))
+            {
+                return Err(Error::OverlappingMemoryRegions(other, index));
+            }
+            if devices_area
+                .iter()
+                .any(|r| overlap(region.gpa, end, r.0, r.1))
+            {
+                return Err(Error::MemoryRegionInDeviceArea(index));
+            }
+            ranges.push((region.gpa, end));
+        }
+
+        let regions_size: u64 = regions.iter().map(|r| r.size).sum();
+        let boot_ram = self.size.saturating_sub(self.hotplugged_size.unwrap_or(0));
+        if regions_size != boot_ram {
+            return Err(Error::MemoryRegionsSize(regions_size, boot_ram));
+        }
+
+        Ok(())
+    }
+
+    /// Makes the RAM hot-added at `hotplugged` (address, size) part of the
+    /// boot RAM, as on a reboot. With explicit regions, each hot-added region
+    /// becomes one more region at the same address.
+    pub fn add_hotplugged_to_boot_ram(&mut self, hotplugged: &[(u64, u64)]) {
+        if let Some(regions) = self.regions.as_mut() {
+            regions.extend(
+                hotplugged
+                    .iter()
+                    .map(|&(gpa, size)| MemoryRegionConfig { gpa, size }),
+            );
+        }
+        self.hotplugged_size = None;
     }
 }
 
@@ -323,18 +1416,137 @@ impl Default for MemoryConfig {
         MemoryConfig {
             size: DEFAULT_MEMORY_MB << 20,
             file: None,
-            mergeable: false,
+            mergeable: None,
+            transparent_hugepages: None,
+            prefault: false,
             hotplug_size: None,
+            hotplugged_size: None,
+            mmio_hole_base: None,
+            mmio_hole_size: None,
+            regions: None,
         }
     }
 }
 
+/// Kernel image held in memory, e.g. downloaded by a program embedding the
+/// VMM, shared by the VMs booting it.
+#[derive(Clone, PartialEq)]
+pub struct KernelImage(pub Arc<[u8]>);
+
+// The image itself would flood the logs.
+impl fmt::Debug for KernelImage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "KernelImage({} bytes)", self.0.len())
+    }
+}
+
 #[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
 pub struct KernelConfig {
+    #[serde(default)]
+    pub path: PathBuf,
+    /// File descriptor inherited from the process starting the VMM, from
+    /// which the kernel is read instead of a path. It is never closed by the
+    /// VMM, which reads the kernel from a duplicate.
+    #[serde(default)]
+    pub fd: Option<RawFd>,
+    /// Kernel image in memory, used instead of a path. It can only be given
+    /// by a program embedding the VMM, and isn't saved along with the VM
+    /// snapshots.
+    #[serde(skip)]
+    pub image: Option<KernelImage>,
+    /// Keep the kernel read from a path or a file descriptor in memory, so
+    /// that the VM reboots without reading it again. Turning it off saves
+    /// the memory on hosts short of it.
+    #[serde(default = "default_kernelconfig_cache")]
+    pub cache: bool,
+}
+
+fn default_kernelconfig_cache() -> bool {
+    true
+}
+
+impl KernelConfig {
+    pub fn parse(kernel: &str) -> Result<Self> {
+        // The path comes first, followed by the options. Only the options
+        // are split off the end, for the path to be able to hold commas.
+        let mut source = kernel;
+        let mut cache_str: &str = "";
+
+        while let Some(index) = source.rfind(',') {
+            let param = &source[index + 1..];
+            if param.starts_with("cache=") {
+                // The last option given wins.
+                if cache_str.is_empty() {
+                    cache_str = &param[6..];
+                }
+            } else {
+                break;
+            }
+            source = &source[..index];
+        }
+
+        let mut cache = default_kernelconfig_cache();
+        if !cache_str.is_empty() {
+            cache = parse_on_off(cache_str)?;
+        }
+
+        let config = if source.starts_with("fd=") {
+            KernelConfig {
+                path: PathBuf::new(),
+                fd: Some(source[3..].parse().map_err(Error::ParseKernelFdParam)?),
+                image: None,
+                cache,
+            }
+        } else {
+            KernelConfig {
+                path: PathBuf::from(source),
+                fd: None,
+                image: None,
+                cache,
+            }
+        };
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    /// A kernel loaded from `image`, an ELF or bzImage kernel on x86_64, or
+    /// an arm64 Image.
+    pub fn from_image(image: Vec<u8>) -> Self {
+        KernelConfig {
+            path: PathBuf::new(),
+            fd: None,
+            image: Some(KernelImage(image.into())),
+            cache: default_kernelconfig_cache(),
+        }
+    }
+
+    fn validate(&self) -> Result<()> {
+        let sources = [
+            !self.path.as_os_str().is_empty(),
+            self.fd.is_some(),
+            self.image.is_some(),
+        ];
+        if sources.iter().filter(|s| **s).count() != 1 {
+            return Err(Error::InvalidKernelSource);
+        }
+        if let Some(fd) = self.fd {
+            validate_inherited_fd(fd)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct FirmwareConfig {
     pub path: PathBuf,
 }
 
 #[derive(Clone, Debug, Default, PartialEq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
 pub struct CmdlineConfig {
     pub args: String,
 }
@@ -350,8 +1562,15 @@ impl CmdlineConfig {
 }
 
 #[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
 pub struct DiskConfig {
+    #[serde(default)]
     pub path: PathBuf,
+    /// File descriptor inherited from the process starting the VMM, used
+    /// instead of a path. It is never closed by the VMM, which works on a
+    /// duplicate.
+    #[serde(default)]
+    pub fd: Option<RawFd>,
     #[serde(default)]
     pub readonly: bool,
     #[serde(default)]
@@ -367,6 +1586,27 @@ pub struct DiskConfig {
     pub vhost_socket: Option<String>,
     #[serde(default = "default_diskconfig_wce")]
     pub wce: bool,
+    /// The partition holding the root filesystem, 0 standing for the whole
+    /// disk, for the kernel to be told to mount it. The disk needs a serial,
+    /// the guest finding it under `/dev/disk/by-id`.
+    #[serde(default)]
+    pub root: Option<u32>,
+    /// Time in milliseconds after which a request the disk hasn't completed
+    /// is failed, for the guest to retry it.
+    #[serde(default)]
+    pub request_timeout: Option<u64>,
+    /// Names the disk in the API requests, the metrics and the events. The
+    /// VMM generates one when it isn't given, e.g. `_disk0`.
+    #[serde(default)]
+    pub id: Option<String>,
+    /// The device number of the disk on the PCI bus, the VMM picking the
+    /// first one free when it isn't given.
+    #[serde(default)]
+    pub pci_slot: Option<u32>,
+    /// The serial the guest reads for the disk, instead of one derived from
+    /// the disk image file. vhost-user disks can't be given one.
+    #[serde(default)]
+    pub serial: Option<String>,
 }
 
 fn default_diskconfig_num_queues() -> usize {
@@ -387,6 +1627,9 @@ impl DiskConfig {
         let params_list: Vec<&str> = disk.split(',').collect();
 
         let mut path_str: &str = "";
+        let mut id_str: &str = "";
+        let mut pci_slot_str: &str = "";
+        let mut fd_str: &str = "";
         let mut readonly_str: &str = "";
         let mut direct_str: &str = "";
         let mut iommu_str: &str = "";
@@ -395,10 +1638,15 @@ impl DiskConfig {
         let mut vhost_socket_str: &str = "";
         let mut vhost_user_str: &str = "";
         let mut wce_str: &str = "";
+        let mut root_str: &str = "";
+        let mut request_timeout_str: &str = "";
+        let mut serial_str: &str = "";
 
         for param in params_list.iter() {
             if param.starts_with("path=") {
                 path_str = &param[5..];
+            } else if param.starts_with("fd=") {
+                fd_str = &param[3..];
             } else if param.starts_with("readonly=") {
                 readonly_str = &param[9..];
             } else if param.starts_with("direct=") {
@@ -415,6 +1663,16 @@ impl DiskConfig {
                 vhost_socket_str = &param[7..];
             } else if param.starts_with("wce=") {
                 wce_str = &param[4..];
+            } else if param.starts_with("root=") {
+                root_str = &param[5..];
+            } else if param.starts_with("request_timeout=") {
+                request_timeout_str = &param["request_timeout=".len()..];
+            } else if param.starts_with("serial=") {
+                serial_str = &param[7..];
+            } else if param.starts_with("id=") {
+                id_str = &param[3..];
+            } else if param.starts_with("pci_slot=") {
+                pci_slot_str = &param["pci_slot=".len()..];
             }
         }
 
@@ -447,13 +1705,41 @@ impl DiskConfig {
             wce = wce_str.parse().map_err(Error::ParseDiskWceParam)?;
         }
 
+        let mut fd = None;
+        if !fd_str.is_empty() {
+            fd = Some(fd_str.parse().map_err(Error::ParseDiskFdParam)?);
+        }
+
+        let mut root = None;
+        if !root_str.is_empty() {
+            root = Some(root_str.parse().map_err(Error::ParseDiskRootParam)?);
+        }
+
+        let mut request_timeout = None;
+        if !request_timeout_str.is_empty() {
+            if vhost_user {
+                warn!("request_timeout parameter has no effect when used vhost_user=true");
+            }
+            request_timeout = Some(
+                request_timeout_str
+                    .parse()
+                    .map_err(Error::ParseDiskRequestTimeoutParam)?,
+            );
+        }
+
+        let mut serial = None;
+        if !serial_str.is_empty() {
+            serial = Some(serial_str.to_string());
+        }
+
         // For now we require a socket if vhost-user is turned on
         if vhost_user && vhost_socket.is_none() {
             return Err(Error::ParseDiskVhostSocketRequired);
         }
 
-        Ok(DiskConfig {
+        let config = DiskConfig {
             path: PathBuf::from(path_str),
+            fd,
             readonly: parse_on_off(readonly_str)?,
             direct: parse_on_off(direct_str)?,
             iommu: parse_on_off(iommu_str)?,
@@ -462,11 +1748,61 @@ impl DiskConfig {
             vhost_socket,
             vhost_user,
             wce,
-        })
+            root,
+            request_timeout,
+            id: parse_device_id(id_str),
+            pci_slot: parse_pci_slot(pci_slot_str)?,
+            serial,
+        };
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    // A vhost-user disk is only given a socket, the others either a path or
+    // a file descriptor.
+    pub fn validate(&self) -> Result<()> {
+        validate_queue_size(self.queue_size)?;
+
+        if let Some(serial) = &self.serial {
+            if serial.len() > vm_virtio::DISK_SERIAL_MAX_LEN {
+                return Err(Error::InvalidDiskSerial(serial.clone()));
+            }
+            if self.vhost_user {
+                return Err(Error::InvalidVhostUserDiskSerial);
+            }
+        }
+
+        // The root device is named after the serial, which udev only uses
+        // verbatim when it has no characters to escape.
+        if self.root.is_some() {
+            let valid = self.serial.as_ref().map_or(false, |serial| {
+                serial
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || "-_.".contains(c))
+            });
+            if !valid {
+                return Err(Error::InvalidRootDiskSerial);
+            }
+        }
+
+        // Every request would fail.
+        if self.request_timeout == Some(0) {
+            return Err(Error::InvalidDiskRequestTimeout);
+        }
+
+        let has_path = !self.path.as_os_str().is_empty();
+        match self.fd {
+            Some(_) if has_path || self.vhost_user => Err(Error::InvalidDiskSource),
+            Some(fd) => validate_inherited_fd(fd),
+            None if !has_path && !self.vhost_user => Err(Error::InvalidDiskSource),
+            None => Ok(()),
+        }
     }
 }
 
 #[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
 pub struct NetConfig {
     #[serde(default = "default_netconfig_tap")]
     pub tap: Option<String>,
@@ -485,6 +1821,16 @@ pub struct NetConfig {
     #[serde(default)]
     pub vhost_user: bool,
     pub vhost_socket: Option<String>,
+    #[serde(default)]
+    pub vhost_net: bool,
+    #[serde(default)]
+    pub pcap: Option<PathBuf>,
+    #[serde(default = "default_netconfig_pcap_snaplen")]
+    pub pcap_snaplen: u32,
+    #[serde(default)]
+    pub id: Option<String>,
+    #[serde(default)]
+    pub pci_slot: Option<u32>,
 }
 
 fn default_netconfig_tap() -> Option<String> {
@@ -507,6 +1853,10 @@ fn default_netconfig_num_queues() -> usize {
     DEFAULT_NUM_QUEUES_VUNET
 }
 
+fn default_netconfig_pcap_snaplen() -> u32 {
+    vm_virtio::PCAP_DEFAULT_SNAPLEN
+}
+
 fn default_netconfig_queue_size() -> u16 {
     DEFAULT_QUEUE_SIZE_VUNET
 }
@@ -517,6 +1867,8 @@ impl NetConfig {
         let params_list: Vec<&str> = net.split(',').collect();
 
         let mut tap_str: &str = "";
+        let mut id_str: &str = "";
+        let mut pci_slot_str: &str = "";
         let mut ip_str: &str = "";
         let mut mask_str: &str = "";
         let mut mac_str: &str = "";
@@ -525,6 +1877,9 @@ impl NetConfig {
         let mut queue_size_str: &str = "";
         let mut vhost_socket_str: &str = "";
         let mut vhost_user_str: &str = "";
+        let mut vhost_net_str: &str = "";
+        let mut pcap_str: &str = "";
+        let mut pcap_snaplen_str: &str = "";
 
         for param in params_list.iter() {
             if param.starts_with("tap=") {
@@ -545,6 +1900,16 @@ impl NetConfig {
                 vhost_user_str = &param[11..];
             } else if param.starts_with("socket=") {
                 vhost_socket_str = &param[7..];
+            } else if param.starts_with("vhost_net=") {
+                vhost_net_str = &param[10..];
+            } else if param.starts_with("pcap=") {
+                pcap_str = &param[5..];
+            } else if param.starts_with("pcap_snaplen=") {
+                pcap_snaplen_str = &param[13..];
+            } else if param.starts_with("id=") {
+                id_str = &param[3..];
+            } else if param.starts_with("pci_slot=") {
+                pci_slot_str = &param["pci_slot=".len()..];
             }
         }
 
@@ -557,6 +1922,9 @@ impl NetConfig {
         let mut queue_size: u16 = default_netconfig_queue_size();
         let mut vhost_user = false;
         let mut vhost_socket = None;
+        let vhost_net = parse_on_off(vhost_net_str)?;
+        let mut pcap = None;
+        let mut pcap_snaplen: u32 = default_netconfig_pcap_snaplen();
 
         if !tap_str.is_empty() {
             tap = Some(tap_str.to_string());
@@ -586,13 +1954,24 @@ impl NetConfig {
         if !vhost_socket_str.is_empty() {
             vhost_socket = Some(vhost_socket_str.to_owned());
         }
+        if !pcap_str.is_empty() {
+            pcap = Some(PathBuf::from(pcap_str));
+        }
+        if !pcap_snaplen_str.is_empty() {
+            pcap_snaplen = pcap_snaplen_str
+                .parse()
+                .map_err(Error::ParseNetPcapSnaplenParam)?;
+        }
 
         // For now we require a socket if vhost-user is turned on
         if vhost_user && vhost_socket.is_none() {
             return Err(Error::ParseNetVhostSocketRequired);
         }
+        if vhost_user && vhost_net {
+            return Err(Error::ParseNetVhostNetWithVhostUser);
+        }
 
-        Ok(NetConfig {
+        let config = NetConfig {
             tap,
             ip,
             mask,
@@ -602,11 +1981,30 @@ impl NetConfig {
             queue_size,
             vhost_user,
             vhost_socket,
-        })
+            vhost_net,
+            pcap,
+            pcap_snaplen,
+            id: parse_device_id(id_str),
+            pci_slot: parse_pci_slot(pci_slot_str)?,
+        };
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        validate_queue_size(self.queue_size)?;
+
+        if self.pcap_snaplen == 0 {
+            return Err(Error::InvalidNetPcapSnaplen);
+        }
+
+        Ok(())
     }
 }
 
 #[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
 pub struct RngConfig {
     pub src: PathBuf,
     #[serde(default)]
@@ -646,6 +2044,153 @@ impl Default for RngConfig {
 }
 
 #[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct BalloonConfig {
+    /// Memory the guest is asked to give back, in bytes.
+    #[serde(default)]
+    pub size: u64,
+    /// Seconds between two requests for the guest memory statistics, 0
+    /// disabling them.
+    #[serde(default)]
+    pub stats_polling_interval: u64,
+    /// Lets the guest report its free pages, given back to the host.
+    #[serde(default)]
+    pub free_page_reporting: bool,
+    /// Lets the guest take memory back from the balloon rather than running
+    /// out of it.
+    #[serde(default)]
+    pub deflate_on_oom: bool,
+    /// Memory available on the host, in bytes, below which the guest is
+    /// asked for more memory. The balloon deflates back to `size` as the
+    /// pressure eases.
+    #[serde(default)]
+    pub pressure_threshold: Option<u64>,
+    /// Memory the guest is asked for, or given back, at once under pressure.
+    #[serde(default = "default_balloonconfig_pressure_step")]
+    pub pressure_step: u64,
+}
+
+fn default_balloonconfig_pressure_step() -> u64 {
+    64 << 20
+}
+
+impl Default for BalloonConfig {
+    fn default() -> Self {
+        BalloonConfig {
+            size: 0,
+            stats_polling_interval: 0,
+            free_page_reporting: false,
+            deflate_on_oom: false,
+            pressure_threshold: None,
+            pressure_step: default_balloonconfig_pressure_step(),
+        }
+    }
+}
+
+impl BalloonConfig {
+    pub fn parse(balloon: &str) -> Result<Self> {
+        // Split the parameters based on the comma delimiter
+        let params_list: Vec<&str> = balloon.split(',').collect();
+
+        let mut config = BalloonConfig::default();
+        for param in params_list.iter().filter(|p| !p.is_empty()) {
+            if param.starts_with("size=") {
+                config.size = parse_size(&param["size=".len()..])?;
+            } else if param.starts_with("stats_polling_interval=") {
+                config.stats_polling_interval = param["stats_polling_interval=".len()..]
+                    .parse()
+                    .map_err(Error::ParseBalloonStatsPollingIntervalParam)?;
+            } else if param.starts_with("free_page_reporting=") {
+                config.free_page_reporting = parse_on_off(&param["free_page_reporting=".len()..])?;
+            } else if param.starts_with("deflate_on_oom=") {
+                config.deflate_on_oom = parse_on_off(&param["deflate_on_oom=".len()..])?;
+            } else if param.starts_with("pressure_threshold=") {
+                config.pressure_threshold =
+                    Some(parse_size(&param["pressure_threshold=".len()..])?);
+            } else if param.starts_with("pressure_step=") {
+                config.pressure_step = parse_size(&param["pressure_step=".len()..])?;
+            } else {
+                return Err(Error::ParseBalloonUnknownParam);
+            }
+        }
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    fn validate(&self) -> Result<()> {
+        if self.pressure_step == 0 {
+            return Err(Error::InvalidBalloonPressureStep);
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct GpuConfig {
+    /// Resolution of the single scanout, which doesn't change while the VM
+    /// runs.
+    #[serde(default = "default_gpuconfig_width")]
+    pub width: u32,
+    #[serde(default = "default_gpuconfig_height")]
+    pub height: u32,
+}
+
+fn default_gpuconfig_width() -> u32 {
+    vm_virtio::gpu::GPU_DEFAULT_WIDTH
+}
+
+fn default_gpuconfig_height() -> u32 {
+    vm_virtio::gpu::GPU_DEFAULT_HEIGHT
+}
+
+impl Default for GpuConfig {
+    fn default() -> Self {
+        GpuConfig {
+            width: default_gpuconfig_width(),
+            height: default_gpuconfig_height(),
+        }
+    }
+}
+
+impl GpuConfig {
+    pub fn parse(gpu: &str) -> Result<Self> {
+        // Split the parameters based on the comma delimiter
+        let params_list: Vec<&str> = gpu.split(',').collect();
+
+        let mut config = GpuConfig::default();
+        for param in params_list.iter().filter(|p| !p.is_empty()) {
+            if param.starts_with("width=") {
+                config.width = param["width=".len()..]
+                    .parse()
+                    .map_err(Error::ParseGpuWidthParam)?;
+            } else if param.starts_with("height=") {
+                config.height = param["height=".len()..]
+                    .parse()
+                    .map_err(Error::ParseGpuHeightParam)?;
+            } else {
+                return Err(Error::ParseGpuUnknownParam);
+            }
+        }
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    fn validate(&self) -> Result<()> {
+        let max = vm_virtio::gpu::GPU_MAX_DIMENSION;
+        if self.width == 0 || self.height == 0 || self.width > max || self.height > max {
+            return Err(Error::InvalidGpuResolution(self.width, self.height));
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
 pub struct FsConfig {
     pub tag: String,
     pub sock: PathBuf,
@@ -657,6 +2202,10 @@ pub struct FsConfig {
     pub dax: bool,
     #[serde(default = "default_fsconfig_cache_size")]
     pub cache_size: u64,
+    #[serde(default)]
+    pub id: Option<String>,
+    #[serde(default)]
+    pub pci_slot: Option<u32>,
 }
 
 fn default_fsconfig_num_queues() -> usize {
@@ -681,6 +2230,8 @@ impl FsConfig {
         let params_list: Vec<&str> = fs.split(',').collect();
 
         let mut tag: &str = "";
+        let mut id_str: &str = "";
+        let mut pci_slot_str: &str = "";
         let mut sock: &str = "";
         let mut num_queues_str: &str = "";
         let mut queue_size_str: &str = "";
@@ -700,6 +2251,10 @@ impl FsConfig {
                 dax_str = &param[4..];
             } else if param.starts_with("cache_size=") {
                 cache_size_str = &param[11..];
+            } else if param.starts_with("id=") {
+                id_str = &param[3..];
+            } else if param.starts_with("pci_slot=") {
+                pci_slot_str = &param["pci_slot=".len()..];
             }
         }
 
@@ -751,52 +2306,312 @@ impl FsConfig {
             queue_size,
             dax,
             cache_size,
+            id: parse_device_id(id_str),
+            pci_slot: parse_pci_slot(pci_slot_str)?,
+        })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct PmemConfig {
+    pub file: PathBuf,
+    pub size: u64,
+    #[serde(default)]
+    pub iommu: bool,
+    #[serde(default)]
+    pub mergeable: bool,
+    #[serde(default)]
+    pub id: Option<String>,
+    #[serde(default)]
+    pub pci_slot: Option<u32>,
+}
+
+impl PmemConfig {
+    pub fn parse(pmem: &str) -> Result<Self> {
+        // Split the parameters based on the comma delimiter
+        let params_list: Vec<&str> = pmem.split(',').collect();
+
+        let mut file_str: &str = "";
+        let mut id_str: &str = "";
+        let mut pci_slot_str: &str = "";
+        let mut size_str: &str = "";
+        let mut iommu_str: &str = "";
+        let mut mergeable_str: &str = "";
+
+        for param in params_list.iter() {
+            if param.starts_with("file=") {
+                file_str = &param[5..];
+            } else if param.starts_with("size=") {
+                size_str = &param[5..];
+            } else if param.starts_with("iommu=") {
+                iommu_str = &param[6..];
+            } else if param.starts_with("mergeable=") {
+                mergeable_str = &param[10..];
+            } else if param.starts_with("id=") {
+                id_str = &param[3..];
+            } else if param.starts_with("pci_slot=") {
+                pci_slot_str = &param["pci_slot=".len()..];
+            }
+        }
+
+        if file_str.is_empty() {
+            return Err(Error::ParsePmemFileParam);
+        }
+
+        Ok(PmemConfig {
+            file: PathBuf::from(file_str),
+            size: parse_size(size_str)?,
+            iommu: parse_on_off(iommu_str)?,
+            mergeable: parse_on_off(mergeable_str)?,
+            id: parse_device_id(id_str),
+            pci_slot: parse_pci_slot(pci_slot_str)?,
+        })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct ScsiConfig {
+    pub lun: u16,
+    pub file: PathBuf,
+}
+
+impl ScsiConfig {
+    pub fn parse(scsi: &str) -> Result<Self> {
+        // Split the parameters based on the comma delimiter
+        let params_list: Vec<&str> = scsi.split(',').collect();
+
+        let mut lun_str: &str = "";
+        let mut file_str: &str = "";
+
+        for param in params_list.iter() {
+            if param.starts_with("lun=") {
+                lun_str = &param[4..];
+            } else if param.starts_with("file=") {
+                file_str = &param[5..];
+            }
+        }
+
+        if file_str.is_empty() {
+            return Err(Error::ParseScsiFileParam);
+        }
+
+        Ok(ScsiConfig {
+            lun: lun_str.parse().map_err(Error::ParseScsiLunParam)?,
+            file: PathBuf::from(file_str),
+        })
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct PlatformConfig {
+    #[serde(default)]
+    pub uuid: Option<String>,
+    #[serde(default)]
+    pub serial_number: Option<String>,
+    #[serde(default)]
+    pub oem_strings: Option<Vec<String>>,
+}
+
+impl PlatformConfig {
+    pub fn parse(platform: &str) -> Result<Self> {
+        // Split the parameters based on the comma delimiter
+        let params_list: Vec<&str> = platform.split(',').collect();
+
+        let mut uuid: Option<String> = None;
+        let mut serial_number: Option<String> = None;
+        let mut oem_strings: Option<Vec<String>> = None;
+
+        for param in params_list.iter() {
+            if param.starts_with("uuid=") {
+                let uuid_str = &param["uuid=".len()..];
+                if arch::parse_uuid(uuid_str).is_none() {
+                    return Err(Error::ParsePlatformUuidParam);
+                }
+                uuid = Some(uuid_str.to_string());
+            } else if param.starts_with("serial_number=") {
+                serial_number = Some(param["serial_number=".len()..].to_string());
+            } else if param.starts_with("oem_string=") {
+                // Each occurrence adds a new OEM string.
+                oem_strings
+                    .get_or_insert_with(Vec::new)
+                    .push(param["oem_string=".len()..].to_string());
+            } else {
+                return Err(Error::ParsePlatformUnknownParam);
+            }
+        }
+
+        Ok(PlatformConfig {
+            uuid,
+            serial_number,
+            oem_strings,
+        })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct NumaDistance {
+    pub destination: u32,
+    pub distance: u8,
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct NumaConfig {
+    pub id: u32,
+    #[serde(default)]
+    pub cpus: Option<Vec<u8>>,
+    pub size: u64,
+    #[serde(default)]
+    pub host_node: Option<u32>,
+    #[serde(default)]
+    pub distances: Option<Vec<NumaDistance>>,
+}
+
+impl NumaConfig {
+    pub fn parse(numa: &str) -> Result<Self> {
+        // Split the parameters based on the comma delimiter
+        let params_list: Vec<&str> = numa.split(',').collect();
+
+        let mut id_str: &str = "";
+        let mut cpus_str: &str = "";
+        let mut size_str: &str = "";
+        let mut host_node_str: &str = "";
+        let mut distances_str: &str = "";
+
+        for param in params_list.iter() {
+            if param.starts_with("id=") {
+                id_str = &param["id=".len()..];
+            } else if param.starts_with("cpus=") {
+                cpus_str = &param["cpus=".len()..];
+            } else if param.starts_with("size=") {
+                size_str = &param["size=".len()..];
+            } else if param.starts_with("host_node=") {
+                host_node_str = &param["host_node=".len()..];
+            } else if param.starts_with("distances=") {
+                distances_str = &param["distances=".len()..];
+            } else {
+                return Err(Error::ParseNumaUnknownParam);
+            }
+        }
+
+        // The vCPUs are given as a colon separated list of ids or ranges,
+        // e.g. "0-3:6".
+        let cpus = if cpus_str.is_empty() {
+            None
+        } else {
+            let mut cpus = Vec::new();
+            for item in cpus_str.split(':') {
+                let mut bounds = item.splitn(2, '-');
+                let first: u8 = bounds
+                    .next()
+                    .unwrap()
+                    .parse()
+                    .map_err(|_| Error::ParseNumaCpusParam)?;
+                let last: u8 = match bounds.next() {
+                    Some(last) => last.parse().map_err(|_| Error::ParseNumaCpusParam)?,
+                    None => first,
+                };
+                if last < first {
+                    return Err(Error::ParseNumaCpusParam);
+                }
+                cpus.extend(first..=last);
+            }
+            Some(cpus)
+        };
+
+        // The distances are given as a colon separated list of
+        // "<destination>@<distance>".
+        let distances = if distances_str.is_empty() {
+            None
+        } else {
+            let mut distances = Vec::new();
+            for item in distances_str.split(':') {
+                let mut fields = item.splitn(2, '@');
+                let destination = fields
+                    .next()
+                    .unwrap()
+                    .parse()
+                    .map_err(|_| Error::ParseNumaDistancesParam)?;
+                let distance = fields
+                    .next()
+                    .ok_or(Error::ParseNumaDistancesParam)?
+                    .parse()
+                    .map_err(|_| Error::ParseNumaDistancesParam)?;
+                distances.push(NumaDistance {
+                    destination,
+                    distance,
+                });
+            }
+            Some(distances)
+        };
+
+        Ok(NumaConfig {
+            id: id_str.parse().map_err(Error::ParseNumaIdParam)?,
+            cpus,
+            size: parse_size(size_str)?,
+            host_node: if host_node_str.is_empty() {
+                None
+            } else {
+                Some(
+                    host_node_str
+                        .parse()
+                        .map_err(Error::ParseNumaHostNodeParam)?,
+                )
+            },
+            distances,
         })
     }
 }
 
+/// A section of SGX Enclave Page Cache exposed to the guest, backed by the
+/// host EPC through `/dev/sgx_vepc`.
 #[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
-pub struct PmemConfig {
-    pub file: PathBuf,
+#[serde(deny_unknown_fields)]
+pub struct SgxEpcConfig {
     pub size: u64,
+    /// Whether the host EPC is allocated upfront, rather than as the guest
+    /// first accesses it.
     #[serde(default)]
-    pub iommu: bool,
-    #[serde(default)]
-    pub mergeable: bool,
+    pub prefault: bool,
 }
 
-impl PmemConfig {
-    pub fn parse(pmem: &str) -> Result<Self> {
+impl SgxEpcConfig {
+    pub fn parse(sgx_epc: &str) -> Result<Self> {
         // Split the parameters based on the comma delimiter
-        let params_list: Vec<&str> = pmem.split(',').collect();
+        let params_list: Vec<&str> = sgx_epc.split(',').collect();
 
-        let mut file_str: &str = "";
         let mut size_str: &str = "";
-        let mut iommu_str: &str = "";
-        let mut mergeable_str: &str = "";
+        let mut prefault_str: &str = "";
 
         for param in params_list.iter() {
-            if param.starts_with("file=") {
-                file_str = &param[5..];
-            } else if param.starts_with("size=") {
-                size_str = &param[5..];
-            } else if param.starts_with("iommu=") {
-                iommu_str = &param[6..];
-            } else if param.starts_with("mergeable=") {
-                mergeable_str = &param[10..];
+            if param.starts_with("size=") {
+                size_str = &param["size=".len()..];
+            } else if param.starts_with("prefault=") {
+                prefault_str = &param["prefault=".len()..];
+            } else {
+                return Err(Error::ParseSgxEpcUnknownParam);
             }
         }
 
-        if file_str.is_empty() {
-            return Err(Error::ParsePmemFileParam);
+        let config = SgxEpcConfig {
+            size: parse_size(size_str)?,
+            prefault: parse_on_off(prefault_str)?,
+        };
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    fn validate(&self) -> Result<()> {
+        if self.size == 0 || self.size % (4 << 10) != 0 {
+            return Err(Error::InvalidSgxEpcSize(self.size));
         }
 
-        Ok(PmemConfig {
-            file: PathBuf::from(file_str),
-            size: parse_size(size_str)?,
-            iommu: parse_on_off(iommu_str)?,
-            mergeable: parse_on_off(mergeable_str)?,
-        })
+        Ok(())
     }
 }
 
@@ -818,18 +2633,28 @@ impl ConsoleOutputMode {
 }
 
 #[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
 pub struct ConsoleConfig {
     #[serde(default = "default_consoleconfig_file")]
     pub file: Option<PathBuf>,
     pub mode: ConsoleOutputMode,
     #[serde(default)]
     pub iommu: bool,
+    /// Output kept while the destination is not ready for it, in bytes, the
+    /// oldest output being dropped beyond. At least 4 KiB are kept. Only used
+    /// by the serial port.
+    #[serde(default = "default_consoleconfig_buffer_size")]
+    pub buffer_size: u64,
 }
 
 fn default_consoleconfig_file() -> Option<PathBuf> {
     None
 }
 
+fn default_consoleconfig_buffer_size() -> u64 {
+    64 << 10
+}
+
 impl ConsoleConfig {
     pub fn parse(console: &str) -> Result<Self> {
         // Split the parameters based on the comma delimiter
@@ -839,10 +2664,13 @@ impl ConsoleConfig {
         let mut file: Option<PathBuf> = default_consoleconfig_file();
         let mut mode: ConsoleOutputMode = ConsoleOutputMode::Off;
         let mut iommu_str: &str = "";
+        let mut buffer_size = default_consoleconfig_buffer_size();
 
         for param in params_list.iter() {
             if param.starts_with("iommu=") {
                 iommu_str = &param[6..];
+            } else if param.starts_with("buffer_size=") {
+                buffer_size = parse_size(&param["buffer_size=".len()..])?;
             } else {
                 if *param == "off" {
                     mode = ConsoleOutputMode::Off;
@@ -871,6 +2699,7 @@ impl ConsoleConfig {
             mode,
             file,
             iommu: parse_on_off(iommu_str)?,
+            buffer_size,
         })
     }
 
@@ -879,6 +2708,7 @@ impl ConsoleConfig {
             file: None,
             mode: ConsoleOutputMode::Null,
             iommu: false,
+            buffer_size: default_consoleconfig_buffer_size(),
         }
     }
 
@@ -887,15 +2717,21 @@ impl ConsoleConfig {
             file: None,
             mode: ConsoleOutputMode::Tty,
             iommu: false,
+            buffer_size: default_consoleconfig_buffer_size(),
         }
     }
 }
 
 #[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
 pub struct DeviceConfig {
     pub path: PathBuf,
     #[serde(default)]
     pub iommu: bool,
+    #[serde(default)]
+    pub id: Option<String>,
+    #[serde(default)]
+    pub pci_slot: Option<u32>,
 }
 
 impl DeviceConfig {
@@ -904,6 +2740,8 @@ impl DeviceConfig {
         let params_list: Vec<&str> = device.split(',').collect();
 
         let mut path_str: &str = "";
+        let mut id_str: &str = "";
+        let mut pci_slot_str: &str = "";
         let mut iommu_str: &str = "";
 
         for param in params_list.iter() {
@@ -911,17 +2749,24 @@ impl DeviceConfig {
                 path_str = &param[5..];
             } else if param.starts_with("iommu=") {
                 iommu_str = &param[6..];
+            } else if param.starts_with("id=") {
+                id_str = &param[3..];
+            } else if param.starts_with("pci_slot=") {
+                pci_slot_str = &param["pci_slot=".len()..];
             }
         }
 
         Ok(DeviceConfig {
             path: PathBuf::from(path_str),
             iommu: parse_on_off(iommu_str)?,
+            id: parse_device_id(id_str),
+            pci_slot: parse_pci_slot(pci_slot_str)?,
         })
     }
 }
 
 #[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
 pub struct VhostUserNetConfig {
     pub sock: String,
     #[serde(default = "default_vunetconfig_num_queues")]
@@ -930,6 +2775,10 @@ pub struct VhostUserNetConfig {
     pub queue_size: u16,
     #[serde(default = "default_vunetconfig_mac")]
     pub mac: MacAddr,
+    #[serde(default)]
+    pub id: Option<String>,
+    #[serde(default)]
+    pub pci_slot: Option<u32>,
 }
 
 fn default_vunetconfig_num_queues() -> usize {
@@ -951,6 +2800,8 @@ impl VhostUserNetConfig {
         let params_list: Vec<&str> = vhost_user_net.split(',').collect();
 
         let mut mac_str: &str = "";
+        let mut id_str: &str = "";
+        let mut pci_slot_str: &str = "";
         let mut sock: &str = "";
         let mut num_queues_str: &str = "";
         let mut queue_size_str: &str = "";
@@ -964,6 +2815,10 @@ impl VhostUserNetConfig {
                 num_queues_str = &param[11..];
             } else if param.starts_with("queue_size=") {
                 queue_size_str = &param[11..];
+            } else if param.starts_with("id=") {
+                id_str = &param[3..];
+            } else if param.starts_with("pci_slot=") {
+                pci_slot_str = &param["pci_slot=".len()..];
             }
         }
 
@@ -993,16 +2848,34 @@ impl VhostUserNetConfig {
             num_queues,
             queue_size,
             mac,
+            id: parse_device_id(id_str),
+            pci_slot: parse_pci_slot(pci_slot_str)?,
         })
     }
 }
 
 #[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
 pub struct VsockConfig {
     pub cid: u64,
     pub sock: PathBuf,
     #[serde(default)]
+    pub listen: Option<Vec<VsockListenConfig>>,
+    #[serde(default)]
     pub iommu: bool,
+    #[serde(default)]
+    pub id: Option<String>,
+    #[serde(default)]
+    pub pci_slot: Option<u32>,
+}
+
+/// Unix socket the VMM listens on, connecting everything accepted from it
+/// to a guest vsock port.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct VsockListenConfig {
+    pub port: u32,
+    pub uds: PathBuf,
 }
 
 impl VsockConfig {
@@ -1011,7 +2884,10 @@ impl VsockConfig {
         let params_list: Vec<&str> = vsock.split(',').collect();
 
         let mut cid_str: &str = "";
+        let mut id_str: &str = "";
+        let mut pci_slot_str: &str = "";
         let mut sock_str: &str = "";
+        let mut listen_str: &str = "";
         let mut iommu_str: &str = "";
 
         for param in params_list.iter() {
@@ -1019,8 +2895,14 @@ impl VsockConfig {
                 cid_str = &param[4..];
             } else if param.starts_with("sock=") {
                 sock_str = &param[5..];
+            } else if param.starts_with("listen=") {
+                listen_str = &param["listen=".len()..];
             } else if param.starts_with("iommu=") {
                 iommu_str = &param[6..];
+            } else if param.starts_with("id=") {
+                id_str = &param[3..];
+            } else if param.starts_with("pci_slot=") {
+                pci_slot_str = &param["pci_slot=".len()..];
             }
         }
 
@@ -1028,15 +2910,42 @@ impl VsockConfig {
             return Err(Error::ParseVsockSockParam);
         }
 
+        let listen = if listen_str.is_empty() {
+            None
+        } else {
+            let mut listen = Vec::new();
+            for item in listen_str.split(':') {
+                let mut fields = item.splitn(2, '@');
+                let port = fields
+                    .next()
+                    .unwrap()
+                    .parse::<u32>()
+                    .map_err(|_| Error::ParseVsockListenParam)?;
+                let uds = fields
+                    .next()
+                    .filter(|uds| !uds.is_empty())
+                    .ok_or(Error::ParseVsockListenParam)?;
+                listen.push(VsockListenConfig {
+                    port,
+                    uds: PathBuf::from(uds),
+                });
+            }
+            Some(listen)
+        };
+
         Ok(VsockConfig {
             cid: cid_str.parse::<u64>().map_err(Error::ParseVsockCidParam)?,
             sock: PathBuf::from(sock_str),
+            listen,
             iommu: parse_on_off(iommu_str)?,
+            id: parse_device_id(id_str),
+            pci_slot: parse_pci_slot(pci_slot_str)?,
         })
     }
 }
 
 #[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
 pub struct VhostUserBlkConfig {
     pub sock: String,
     #[serde(default = "default_vublkconfig_num_queues")]
@@ -1045,6 +2954,10 @@ pub struct VhostUserBlkConfig {
     pub queue_size: u16,
     #[serde(default = "default_vublkconfig_wce")]
     pub wce: bool,
+    #[serde(default)]
+    pub id: Option<String>,
+    #[serde(default)]
+    pub pci_slot: Option<u32>,
 }
 
 fn default_vublkconfig_num_queues() -> usize {
@@ -1066,6 +2979,8 @@ impl VhostUserBlkConfig {
         let params_list: Vec<&str> = vhost_user_blk.split(',').collect();
 
         let mut sock: &str = "";
+        let mut id_str: &str = "";
+        let mut pci_slot_str: &str = "";
         let mut num_queues_str: &str = "";
         let mut queue_size_str: &str = "";
         let mut wce_str: &str = "";
@@ -1079,6 +2994,10 @@ impl VhostUserBlkConfig {
                 queue_size_str = &param[11..];
             } else if param.starts_with("wce=") {
                 wce_str = &param[4..];
+            } else if param.starts_with("id=") {
+                id_str = &param[3..];
+            } else if param.starts_with("pci_slot=") {
+                pci_slot_str = &param["pci_slot=".len()..];
             }
         }
 
@@ -1105,25 +3024,262 @@ impl VhostUserBlkConfig {
             num_queues,
             queue_size,
             wce,
+            id: parse_device_id(id_str),
+            pci_slot: parse_pci_slot(pci_slot_str)?,
+        })
+    }
+}
+
+/// A range of I/O ports or MMIO addresses, bounds included.
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct TraceBusRange {
+    pub first: u64,
+    pub last: u64,
+}
+
+impl TraceBusRange {
+    pub fn contains(&self, addr: u64) -> bool {
+        self.first <= addr && addr <= self.last
+    }
+}
+
+pub const DEFAULT_TRACE_BUS_ENTRIES: usize = 4096;
+
+fn default_tracebusconfig_entries() -> usize {
+    DEFAULT_TRACE_BUS_ENTRIES
+}
+
+/// Tracing of the I/O port and MMIO accesses of the guest. Without any
+/// range, every access is traced, otherwise only the ones to the given
+/// ranges.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct TraceBusConfig {
+    /// How many of the latest accesses are kept in memory.
+    #[serde(default = "default_tracebusconfig_entries")]
+    pub entries: usize,
+    /// File every traced access is streamed to, in the binary format. It is
+    /// created once per VMM, the VMs booted after a reboot appending to it.
+    #[serde(default)]
+    pub file: Option<PathBuf>,
+    #[serde(default)]
+    pub pio: Option<Vec<TraceBusRange>>,
+    #[serde(default)]
+    pub mmio: Option<Vec<TraceBusRange>>,
+}
+
+// Parses a colon separated list of addresses or ranges of addresses, in
+// decimal or hexadecimal, e.g. "0x3f8-0x3ff:0x80".
+fn parse_trace_bus_ranges(ranges: &str) -> Result<Vec<TraceBusRange>> {
+    let parse_address = |s: &str| {
+        if s.starts_with("0x") || s.starts_with("0X") {
+            u64::from_str_radix(&s[2..], 16)
+        } else {
+            s.parse()
+        }
+        .map_err(|_| Error::ParseTraceBusRangeParam)
+    };
+
+    let mut parsed = Vec::new();
+    for item in ranges.split(':') {
+        let mut bounds = item.splitn(2, '-');
+        let first = parse_address(bounds.next().unwrap())?;
+        let last = match bounds.next() {
+            Some(last) => parse_address(last)?,
+            None => first,
+        };
+        if last < first {
+            return Err(Error::ParseTraceBusRangeParam);
+        }
+        parsed.push(TraceBusRange { first, last });
+    }
+    Ok(parsed)
+}
+
+impl TraceBusConfig {
+    pub fn parse(trace_bus: &str) -> Result<Self> {
+        let mut entries_str: &str = "";
+        let mut file_str: &str = "";
+        let mut pio_str: &str = "";
+        let mut mmio_str: &str = "";
+
+        // Tracing every access is merely asking for the option.
+        if !trace_bus.is_empty() {
+            for param in trace_bus.split(',') {
+                if param.starts_with("entries=") {
+                    entries_str = &param["entries=".len()..];
+                } else if param.starts_with("file=") {
+                    file_str = &param["file=".len()..];
+                } else if param.starts_with("pio=") {
+                    pio_str = &param["pio=".len()..];
+                } else if param.starts_with("mmio=") {
+                    mmio_str = &param["mmio=".len()..];
+                } else {
+                    return Err(Error::ParseTraceBusUnknownParam);
+                }
+            }
+        }
+
+        Ok(TraceBusConfig {
+            entries: if entries_str.is_empty() {
+                DEFAULT_TRACE_BUS_ENTRIES
+            } else {
+                entries_str
+                    .parse()
+                    .map_err(Error::ParseTraceBusEntriesParam)?
+            },
+            file: if file_str.is_empty() {
+                None
+            } else {
+                Some(PathBuf::from(file_str))
+            },
+            pio: if pio_str.is_empty() {
+                None
+            } else {
+                Some(parse_trace_bus_ranges(pio_str)?)
+            },
+            mmio: if mmio_str.is_empty() {
+                None
+            } else {
+                Some(parse_trace_bus_ranges(mmio_str)?)
+            },
         })
     }
 }
 
+/// Intel 6300ESB watchdog timer, expiring unless the guest pings it.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct WatchdogConfig {
+    /// Seconds the watchdog waits for a ping, until the guest driver
+    /// programs its own timeout.
+    #[serde(default = "default_watchdogconfig_timeout")]
+    pub timeout: u64,
+    #[serde(default)]
+    pub action: WatchdogAction,
+}
+
+fn default_watchdogconfig_timeout() -> u64 {
+    30
+}
+
+impl Default for WatchdogConfig {
+    fn default() -> Self {
+        WatchdogConfig {
+            timeout: default_watchdogconfig_timeout(),
+            action: WatchdogAction::default(),
+        }
+    }
+}
+
+impl WatchdogConfig {
+    pub fn parse(watchdog: &str) -> Result<Self> {
+        // Split the parameters based on the comma delimiter
+        let params_list: Vec<&str> = watchdog.split(',').collect();
+
+        let mut config = WatchdogConfig::default();
+        for param in params_list.iter().filter(|p| !p.is_empty()) {
+            if param.starts_with("timeout=") {
+                config.timeout = param["timeout=".len()..]
+                    .parse()
+                    .map_err(Error::ParseWatchdogTimeoutParam)?;
+            } else if param.starts_with("action=") {
+                config.action = WatchdogAction::parse(&param["action=".len()..])?;
+            } else {
+                return Err(Error::ParseWatchdogUnknownParam);
+            }
+        }
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    fn validate(&self) -> Result<()> {
+        if self.timeout == 0 {
+            return Err(Error::InvalidWatchdogTimeout);
+        }
+
+        Ok(())
+    }
+}
+
+/// Wall-clock time the guest may run for, across reboots, the time spent
+/// paused not counting.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct RuntimeBudgetConfig {
+    pub seconds: u64,
+    #[serde(default)]
+    pub action: RuntimeBudgetAction,
+}
+
+impl RuntimeBudgetConfig {
+    pub fn parse(runtime_budget: &str) -> Result<Self> {
+        // Split the parameters based on the comma delimiter
+        let params_list: Vec<&str> = runtime_budget.split(',').collect();
+
+        let mut seconds = 0;
+        let mut action = RuntimeBudgetAction::default();
+        for param in params_list.iter().filter(|p| !p.is_empty()) {
+            if param.starts_with("seconds=") {
+                seconds = param["seconds=".len()..]
+                    .parse()
+                    .map_err(Error::ParseRuntimeBudgetSecondsParam)?;
+            } else if param.starts_with("action=") {
+                action = RuntimeBudgetAction::parse(&param["action=".len()..])?;
+            } else {
+                return Err(Error::ParseRuntimeBudgetUnknownParam);
+            }
+        }
+        let config = RuntimeBudgetConfig { seconds, action };
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    fn validate(&self) -> Result<()> {
+        if self.seconds == 0 {
+            return Err(Error::InvalidRuntimeBudget);
+        }
+
+        Ok(())
+    }
+
+    /// The time the guest may run for.
+    pub fn budget(&self) -> Duration {
+        Duration::from_secs(self.seconds)
+    }
+}
+
 #[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
 pub struct VmConfig {
     #[serde(default)]
     pub cpus: CpusConfig,
     #[serde(default)]
     pub memory: MemoryConfig,
     pub kernel: Option<KernelConfig>,
+    pub firmware: Option<FirmwareConfig>,
     #[serde(default)]
     pub cmdline: CmdlineConfig,
     pub disks: Option<Vec<DiskConfig>>,
     pub net: Option<Vec<NetConfig>>,
     #[serde(default)]
     pub rng: RngConfig,
+    /// Seed of a pseudo random number generator replacing the entropy
+    /// source of the rng device. This is insecure, for testing only.
+    #[serde(default)]
+    pub rng_seed: Option<u64>,
+    pub balloon: Option<BalloonConfig>,
+    pub gpu: Option<GpuConfig>,
     pub fs: Option<Vec<FsConfig>>,
     pub pmem: Option<Vec<PmemConfig>>,
+    pub scsi: Option<Vec<ScsiConfig>>,
+    pub platform: Option<PlatformConfig>,
+    pub numa: Option<Vec<NumaConfig>>,
+    #[serde(default)]
+    pub sgx_epc: Option<Vec<SgxEpcConfig>>,
     #[serde(default = "ConsoleConfig::default_serial")]
     pub serial: ConsoleConfig,
     #[serde(default = "ConsoleConfig::default_console")]
@@ -1134,11 +3290,33 @@ pub struct VmConfig {
     pub vsock: Option<Vec<VsockConfig>>,
     #[serde(default)]
     pub iommu: bool,
+    #[serde(default)]
+    pub create_pit: bool,
+    #[serde(default)]
+    pub create_irqchip_kind: IrqChipKind,
+    #[serde(default)]
+    pub stdin: StdinMode,
+    #[serde(default)]
+    pub vnc_addr: Option<SocketAddr>,
+    #[serde(default)]
+    pub metrics_socket: Option<PathBuf>,
+    #[serde(default)]
+    pub trace_bus: Option<TraceBusConfig>,
+    #[serde(default)]
+    pub watchdog: Option<WatchdogConfig>,
+    #[serde(default)]
+    pub runtime_budget: Option<RuntimeBudgetConfig>,
+    /// Whether the filesystem access of the VMM is restricted to the paths
+    /// the VM needs, through Landlock.
+    #[serde(default)]
+    pub landlock: bool,
 }
 
 impl VmConfig {
+    /// A VM boots either a kernel or a firmware, the latter taking over
+    /// the kernel and command line when both are given.
     pub fn valid(&self) -> bool {
-        self.kernel.is_some()
+        self.kernel.is_some() || self.firmware.is_some()
     }
 
     pub fn parse(vm_params: VmParams) -> Result<Self> {
@@ -1154,6 +3332,7 @@ impl VmConfig {
                 }
                 disk_config_list.push(disk_config);
             }
+            validate_root_disk(&disk_config_list)?;
             disks = Some(disk_config_list);
         }
 
@@ -1175,6 +3354,16 @@ impl VmConfig {
             iommu = true;
         }
 
+        let mut balloon: Option<BalloonConfig> = None;
+        if let Some(balloon_str) = vm_params.balloon {
+            balloon = Some(BalloonConfig::parse(balloon_str)?);
+        }
+
+        let mut gpu: Option<GpuConfig> = None;
+        if let Some(gpu_str) = vm_params.gpu {
+            gpu = Some(GpuConfig::parse(gpu_str)?);
+        }
+
         let mut fs: Option<Vec<FsConfig>> = None;
         if let Some(fs_list) = &vm_params.fs {
             let mut fs_config_list = Vec::new();
@@ -1197,6 +3386,49 @@ impl VmConfig {
             pmem = Some(pmem_config_list);
         }
 
+        // All the LUNs are grouped behind a single host bus adapter.
+        let mut scsi: Option<Vec<ScsiConfig>> = None;
+        if let Some(scsi_list) = &vm_params.scsi {
+            let mut scsi_config_list: Vec<ScsiConfig> = Vec::new();
+            for item in scsi_list.iter() {
+                let scsi_config = ScsiConfig::parse(item)?;
+                if scsi_config.lun > vm_virtio::scsi::MAX_LUN
+                    || scsi_config_list.iter().any(|c| c.lun == scsi_config.lun)
+                {
+                    return Err(Error::InvalidScsiLun(scsi_config.lun));
+                }
+                scsi_config_list.push(scsi_config);
+            }
+            scsi = Some(scsi_config_list);
+        }
+
+        let mut platform: Option<PlatformConfig> = None;
+        if let Some(platform_str) = vm_params.platform {
+            platform = Some(PlatformConfig::parse(platform_str)?);
+        }
+
+        let cpus = CpusConfig::parse(vm_params.cpus)?;
+        let memory = MemoryConfig::parse(vm_params.memory)?;
+
+        let mut numa: Option<Vec<NumaConfig>> = None;
+        if let Some(numa_list) = &vm_params.numa {
+            let mut numa_config_list = Vec::new();
+            for item in numa_list.iter() {
+                numa_config_list.push(NumaConfig::parse(item)?);
+            }
+            validate_numa_config(&numa_config_list, &cpus, &memory)?;
+            numa = Some(numa_config_list);
+        }
+
+        let mut sgx_epc: Option<Vec<SgxEpcConfig>> = None;
+        if let Some(sgx_epc_list) = &vm_params.sgx_epc {
+            let mut sgx_epc_config_list = Vec::new();
+            for item in sgx_epc_list.iter() {
+                sgx_epc_config_list.push(SgxEpcConfig::parse(item)?);
+            }
+            sgx_epc = Some(sgx_epc_config_list);
+        }
+
         let console = ConsoleConfig::parse(vm_params.console)?;
         if console.iommu {
             iommu = true;
@@ -1252,21 +3484,50 @@ impl VmConfig {
 
         let mut kernel: Option<KernelConfig> = None;
         if let Some(k) = vm_params.kernel {
-            kernel = Some(KernelConfig {
-                path: PathBuf::from(k),
+            kernel = Some(KernelConfig::parse(k)?);
+        }
+
+        let mut firmware: Option<FirmwareConfig> = None;
+        if let Some(f) = vm_params.firmware {
+            firmware = Some(FirmwareConfig {
+                path: PathBuf::from(f),
             });
         }
 
-        Ok(VmConfig {
-            cpus: CpusConfig::parse(vm_params.cpus)?,
-            memory: MemoryConfig::parse(vm_params.memory)?,
+        let create_irqchip_kind = IrqChipKind::parse(vm_params.irqchip)?;
+        if vm_params.pit && create_irqchip_kind != IrqChipKind::Kernel {
+            return Err(Error::PitWithoutKernelIrqChip);
+        }
+
+        let mut vnc_addr: Option<SocketAddr> = None;
+        if let Some(vnc_str) = vm_params.vnc {
+            if gpu.is_none() {
+                return Err(Error::VncWithoutGpu);
+            }
+            vnc_addr = Some(vnc_str.parse().map_err(Error::ParseVncParam)?);
+        }
+
+        let config = VmConfig {
+            cpus,
+            memory,
             kernel,
+            firmware,
             cmdline: CmdlineConfig::parse(vm_params.cmdline)?,
             disks,
             net,
             rng,
+            rng_seed: vm_params
+                .rng_seed
+                .map(|s| s.parse().map_err(Error::ParseRngSeed))
+                .transpose()?,
+            balloon,
+            gpu,
             fs,
             pmem,
+            scsi,
+            platform,
+            numa,
+            sgx_epc,
             serial,
             console,
             devices,
@@ -1274,6 +3535,320 @@ impl VmConfig {
             vhost_user_blk,
             vsock,
             iommu,
-        })
+            create_pit: vm_params.pit,
+            create_irqchip_kind,
+            stdin: StdinMode::parse(vm_params.stdin)?,
+            vnc_addr,
+            metrics_socket: vm_params.metrics_socket.map(PathBuf::from),
+            trace_bus: vm_params.trace_bus.map(TraceBusConfig::parse).transpose()?,
+            watchdog: vm_params.watchdog.map(WatchdogConfig::parse).transpose()?,
+            runtime_budget: vm_params
+                .runtime_budget
+                .map(RuntimeBudgetConfig::parse)
+                .transpose()?,
+            landlock: parse_on_off(vm_params.landlock.unwrap_or(""))?,
+        };
+        config.validate_device_ids()?;
+        config.validate_pci_slots()?;
+
+        Ok(config)
+    }
+
+    // The ids of the devices which have one.
+    fn device_ids(&self) -> Vec<&str> {
+        let mut ids = Vec::new();
+        ids.extend(self.disks.iter().flatten().filter_map(|d| d.id.as_deref()));
+        ids.extend(self.net.iter().flatten().filter_map(|n| n.id.as_deref()));
+        ids.extend(self.fs.iter().flatten().filter_map(|f| f.id.as_deref()));
+        ids.extend(self.pmem.iter().flatten().filter_map(|p| p.id.as_deref()));
+        ids.extend(
+            self.devices
+                .iter()
+                .flatten()
+                .filter_map(|d| d.id.as_deref()),
+        );
+        ids.extend(
+            self.vhost_user_net
+                .iter()
+                .flatten()
+                .filter_map(|n| n.id.as_deref()),
+        );
+        ids.extend(
+            self.vhost_user_blk
+                .iter()
+                .flatten()
+                .filter_map(|b| b.id.as_deref()),
+        );
+        ids.extend(self.vsock.iter().flatten().filter_map(|v| v.id.as_deref()));
+        ids
+    }
+
+    fn validate_device_ids(&self) -> Result<()> {
+        let ids = self.device_ids();
+        for (index, id) in ids.iter().enumerate() {
+            validate_device_id(id)?;
+            if ids[..index].contains(id) {
+                return Err(Error::DuplicateDeviceId(id.to_string()));
+            }
+        }
+
+        Ok(())
+    }
+
+    // The devices which were given a PCI slot, by id.
+    fn device_pci_slots(&self) -> Vec<(Option<&str>, u32)> {
+        let mut slots = Vec::new();
+        slots.extend(
+            self.disks
+                .iter()
+                .flatten()
+                .filter_map(|d| Some((d.id.as_deref(), d.pci_slot?))),
+        );
+        slots.extend(
+            self.net
+                .iter()
+                .flatten()
+                .filter_map(|n| Some((n.id.as_deref(), n.pci_slot?))),
+        );
+        slots.extend(
+            self.fs
+                .iter()
+                .flatten()
+                .filter_map(|f| Some((f.id.as_deref(), f.pci_slot?))),
+        );
+        slots.extend(
+            self.pmem
+                .iter()
+                .flatten()
+                .filter_map(|p| Some((p.id.as_deref(), p.pci_slot?))),
+        );
+        slots.extend(
+            self.devices
+                .iter()
+                .flatten()
+                .filter_map(|d| Some((d.id.as_deref(), d.pci_slot?))),
+        );
+        slots.extend(
+            self.vhost_user_net
+                .iter()
+                .flatten()
+                .filter_map(|n| Some((n.id.as_deref(), n.pci_slot?))),
+        );
+        slots.extend(
+            self.vhost_user_blk
+                .iter()
+                .flatten()
+                .filter_map(|b| Some((b.id.as_deref(), b.pci_slot?))),
+        );
+        slots.extend(
+            self.vsock
+                .iter()
+                .flatten()
+                .filter_map(|v| Some((v.id.as_deref(), v.pci_slot?))),
+        );
+        slots
+    }
+
+    fn validate_pci_slots(&self) -> Result<()> {
+        let slots = self.pci_slots();
+        for (index, slot) in slots.iter().enumerate() {
+            validate_pci_slot(*slot)?;
+            if slots[..index].contains(slot) {
+                return Err(Error::DuplicatePciSlot(*slot));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The PCI slots the devices were given, to be kept for them.
+    pub fn pci_slots(&self) -> Vec<u32> {
+        self.device_pci_slots()
+            .iter()
+            .map(|(_, slot)| *slot)
+            .collect()
+    }
+
+    /// The PCI slot the device `id` was given, if any.
+    pub fn pci_slot(&self, id: &str) -> Option<u32> {
+        self.device_pci_slots()
+            .iter()
+            .find(|(device_id, _)| *device_id == Some(id))
+            .map(|(_, slot)| *slot)
+    }
+
+    /// Checks `slot` can be given to a device plugged into the VM.
+    pub fn validate_new_pci_slot(&self, slot: u32) -> Result<()> {
+        validate_pci_slot(slot)?;
+        if self.pci_slots().contains(&slot) {
+            return Err(Error::DuplicatePciSlot(slot));
+        }
+
+        Ok(())
+    }
+
+    /// Checks `id` can be given to a device plugged into the VM.
+    pub fn validate_new_device_id(&self, id: &str) -> Result<()> {
+        validate_device_id(id)?;
+        if self.device_ids().contains(&id) {
+            return Err(Error::DuplicateDeviceId(id.to_string()));
+        }
+
+        Ok(())
+    }
+
+    /// An id for a device plugged into the VM without one, made of `prefix`
+    /// and the first number not taken yet, e.g. `_disk0`.
+    pub fn new_device_id(&self, prefix: &str) -> String {
+        next_device_id(prefix, &self.device_ids())
+    }
+
+    /// Gives an id to each device which doesn't have one yet, e.g. `_disk0`
+    /// or `_net1`, so that they all keep the same id across reboots.
+    pub fn assign_device_ids(&mut self) {
+        let mut taken: Vec<String> = self.device_ids().iter().map(|id| id.to_string()).collect();
+
+        let mut slots: Vec<(&str, &mut Option<String>)> = Vec::new();
+        slots.extend(
+            self.disks
+                .iter_mut()
+                .flatten()
+                .map(|d| ("_disk", &mut d.id)),
+        );
+        slots.extend(self.net.iter_mut().flatten().map(|n| ("_net", &mut n.id)));
+        slots.extend(self.fs.iter_mut().flatten().map(|f| ("_fs", &mut f.id)));
+        slots.extend(self.pmem.iter_mut().flatten().map(|p| ("_pmem", &mut p.id)));
+        slots.extend(
+            self.devices
+                .iter_mut()
+                .flatten()
+                .map(|d| ("_vfio", &mut d.id)),
+        );
+        slots.extend(
+            self.vhost_user_net
+                .iter_mut()
+                .flatten()
+                .map(|n| ("_net", &mut n.id)),
+        );
+        slots.extend(
+            self.vhost_user_blk
+                .iter_mut()
+                .flatten()
+                .map(|b| ("_disk", &mut b.id)),
+        );
+        slots.extend(
+            self.vsock
+                .iter_mut()
+                .flatten()
+                .map(|v| ("_vsock", &mut v.id)),
+        );
+
+        for (prefix, id) in slots.into_iter().filter(|(_, id)| id.is_none()) {
+            let new_id = next_device_id(prefix, &taken);
+            taken.push(new_id.clone());
+            *id = Some(new_id);
+        }
+    }
+
+    /// Builds a VM configuration from its JSON description, as accepted by
+    /// the vm.create API endpoint. Unknown keys are rejected, and the result
+    /// goes through the same checks as the command line parameters.
+    pub fn from_json(json: &str) -> Result<Self> {
+        let mut config: VmConfig = serde_json::from_str(json).map_err(Error::ParseJson)?;
+        config.validate()?;
+
+        // As with the command line, a single device placed behind the
+        // IOMMU is enough to get one created.
+        config.iommu |= config.rng.iommu
+            || config.console.iommu
+            || config.disks.iter().flatten().any(|d| d.iommu)
+            || config.net.iter().flatten().any(|n| n.iommu)
+            || config.pmem.iter().flatten().any(|p| p.iommu)
+            || config.devices.iter().flatten().any(|d| d.iommu)
+            || config.vsock.iter().flatten().any(|v| v.iommu);
+
+        Ok(config)
+    }
+
+    fn validate(&self) -> Result<()> {
+        if self.cpus.max_vcpus < self.cpus.boot_vcpus {
+            return Err(Error::ParseCpusMaxLowerThanBoot);
+        }
+
+        self.validate_device_ids()?;
+        self.validate_pci_slots()?;
+
+        if let Some(kernel) = &self.kernel {
+            kernel.validate()?;
+        }
+
+        for disk in self.disks.iter().flatten() {
+            disk.validate()?;
+        }
+        if let Some(disks) = &self.disks {
+            validate_root_disk(disks)?;
+        }
+        for net in self.net.iter().flatten() {
+            net.validate()?;
+        }
+
+        if let Some(balloon) = &self.balloon {
+            balloon.validate()?;
+        }
+
+        if let Some(gpu) = &self.gpu {
+            gpu.validate()?;
+        }
+
+        if let Some(watchdog) = &self.watchdog {
+            watchdog.validate()?;
+        }
+
+        if let Some(runtime_budget) = &self.runtime_budget {
+            runtime_budget.validate()?;
+        }
+
+        if let Some(scsi) = &self.scsi {
+            for (index, config) in scsi.iter().enumerate() {
+                if config.lun > vm_virtio::scsi::MAX_LUN
+                    || scsi[..index].iter().any(|c| c.lun == config.lun)
+                {
+                    return Err(Error::InvalidScsiLun(config.lun));
+                }
+            }
+        }
+
+        if let Some(numa) = &self.numa {
+            validate_numa_config(numa, &self.cpus, &self.memory)?;
+        }
+
+        if let Some(sgx_epc) = &self.sgx_epc {
+            for section in sgx_epc.iter() {
+                section.validate()?;
+            }
+            sgx_epc
+                .iter()
+                .try_fold(0u64, |size, section| size.checked_add(section.size))
+                .ok_or(Error::InvalidSgxEpcTotalSize)?;
+        }
+
+        self.memory.validate_mmio_hole()?;
+        self.memory.validate_regions()?;
+        self.memory.validate_mergeable()?;
+
+        if self.console.mode == ConsoleOutputMode::Tty && self.serial.mode == ConsoleOutputMode::Tty
+        {
+            return Err(Error::ParseTTYParam);
+        }
+
+        if self.create_pit && self.create_irqchip_kind != IrqChipKind::Kernel {
+            return Err(Error::PitWithoutKernelIrqChip);
+        }
+
+        if self.vnc_addr.is_some() && self.gpu.is_none() {
+            return Err(Error::VncWithoutGpu);
+        }
+
+        Ok(())
     }
 }
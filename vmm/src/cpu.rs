@@ -8,32 +8,165 @@
 //
 // SPDX-License-Identifier: Apache-2.0 AND BSD-3-Clause
 //
+use crate::bus_trace::{BusKind, BusTracer};
+#[cfg(target_arch = "x86_64")]
+use crate::clock::{ClockState, HostClock};
+#[cfg(target_arch = "x86_64")]
+use crate::config::IrqChipKind;
+use crate::config::{CpusConfig, InternalErrorAction, MsrConfig, NumaConfig};
 use crate::device_manager::DeviceManager;
 #[cfg(feature = "acpi")]
 use acpi_tables::{aml, aml::Aml, sdt::SDT};
+use anyhow::anyhow;
 use arc_swap::ArcSwap;
 #[cfg(feature = "acpi")]
 use arch::layout;
 use devices::{ioapic, BusDevice};
-use kvm_bindings::CpuId;
+use kvm_bindings::kvm_run;
+#[cfg(target_arch = "x86_64")]
+use kvm_bindings::{
+    kvm_guest_debug, kvm_lapic_state, kvm_mp_state, kvm_msr_entry, kvm_regs, kvm_segment,
+    kvm_sregs, kvm_translation, kvm_vcpu_events, kvm_xcrs, kvm_xsave, CpuId, Msrs,
+};
 use kvm_ioctls::*;
 use libc::{c_void, siginfo_t};
 use std::cmp;
+use std::collections::{BTreeMap, HashMap};
+#[cfg(target_arch = "x86_64")]
+use std::mem::size_of;
+use std::os::unix::io::AsRawFd;
 use std::os::unix::thread::JoinHandleExt;
 use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Receiver, Sender};
 use std::sync::{Arc, Barrier, Mutex, Weak};
 use std::thread;
 use std::{fmt, io, result};
-use vm_device::{Migratable, MigratableError, Pausable, Snapshotable};
-use vm_memory::{Address, GuestAddress, GuestMemoryMmap};
+use vm_device::metrics::{self, Metric};
+use vm_device::{
+    Migratable, MigratableError, Pausable, Snapshot, SnapshotDataSection, Snapshotable,
+};
+use vm_memory::{Bytes, GuestAddress, GuestMemoryMmap};
 use vmm_sys_util::eventfd::EventFd;
+#[cfg(target_arch = "x86_64")]
+use vmm_sys_util::ioctl::{ioctl, ioctl_with_mut_ref, ioctl_with_ref};
 use vmm_sys_util::signal::{register_signal_handler, SIGRTMIN};
 
+#[cfg(target_arch = "x86_64")]
+const KVMIO: u32 = 0xAE;
+
+// Inject an NMI into the vCPU. Not exposed by kvm-ioctls yet.
+#[cfg(target_arch = "x86_64")]
+ioctl_io_nr!(KVM_NMI, KVMIO, 0x9a);
+// Translate a guest linear address. Not exposed by kvm-ioctls yet.
+#[cfg(target_arch = "x86_64")]
+ioctl_iowr_nr!(KVM_TRANSLATE, KVMIO, 0x85, kvm_translation);
+// Set the debugging controls of the vCPU. Not exposed by kvm-ioctls yet.
+#[cfg(target_arch = "x86_64")]
+ioctl_iow_nr!(KVM_SET_GUEST_DEBUG, KVMIO, 0x9b, kvm_guest_debug);
+
+// Controls of KVM_SET_GUEST_DEBUG.
+#[cfg(target_arch = "x86_64")]
+const KVM_GUESTDBG_ENABLE: u32 = 0x1;
+#[cfg(target_arch = "x86_64")]
+const KVM_GUESTDBG_SINGLESTEP: u32 = 0x2;
+#[cfg(target_arch = "x86_64")]
+const KVM_GUESTDBG_USE_HW_BP: u32 = 0x2_0000;
+
+// Number of hardware breakpoints, set in DR0 to DR3.
+#[cfg(target_arch = "x86_64")]
+const HW_BREAKPOINTS: usize = 4;
+// DR6 bits telling which breakpoint was hit, or if the exception comes
+// from single stepping.
+#[cfg(target_arch = "x86_64")]
+const DR6_BS: u64 = 1 << 14;
+// RFLAGS resume flag, not to hit the same instruction breakpoint again.
+#[cfg(target_arch = "x86_64")]
+const RFLAGS_RF: u64 = 1 << 16;
+
+// Guest code logged around the instruction pointer on internal errors.
+#[cfg(target_arch = "x86_64")]
+const INTERNAL_ERROR_DUMP_BEFORE: u64 = 16;
+#[cfg(target_arch = "x86_64")]
+const INTERNAL_ERROR_DUMP_AFTER: u64 = 48;
+
+// Types of the KVM_EXIT_SYSTEM_EVENT exits, raised by the in-kernel PSCI
+// implementation.
+#[cfg(target_arch = "aarch64")]
+const KVM_SYSTEM_EVENT_SHUTDOWN: u32 = 1;
+#[cfg(target_arch = "aarch64")]
+const KVM_SYSTEM_EVENT_RESET: u32 = 2;
+
 // Debug I/O port
 #[cfg(target_arch = "x86_64")]
 const DEBUG_IOPORT: u16 = 0x80;
 const DEBUG_IOPORT_PREFIX: &str = "Debug I/O port";
 
+// Accesses to an I/O port no device handles which get logged, the following
+// ones being ignored silently.
+const UNHANDLED_PIO_LOG_LIMIT: u32 = 8;
+
+/// Handles the guest accesses to the I/O ports no device handles, as the
+/// ports a guest probes for legacy hardware. Reads return all ones, as from a
+/// port nothing decodes on real hardware, and writes are dropped.
+///
+/// The first accesses to each port are logged, so that a guest polling a
+/// port can't flood the log.
+#[derive(Default)]
+pub struct UnhandledPio {
+    accesses: Mutex<HashMap<u16, u32>>,
+}
+
+impl UnhandledPio {
+    fn log(&self, port: u16, access: &str, len: usize) {
+        let count = {
+            let mut accesses = self.accesses.lock().unwrap();
+            let count = accesses.entry(port).or_insert(0);
+            *count = count.saturating_add(1);
+            *count
+        };
+
+        if count < UNHANDLED_PIO_LOG_LIMIT {
+            debug!(
+                "Unhandled {} of {} bytes at I/O port {:#x}",
+                access, len, port
+            );
+        } else if count == UNHANDLED_PIO_LOG_LIMIT {
+            debug!(
+                "Unhandled {} of {} bytes at I/O port {:#x}, not logging further accesses",
+                access, len, port
+            );
+        }
+    }
+
+    /// Reads `data` from `port` on `io_bus`, or fills it with all ones if no
+    /// device handles the port.
+    pub fn read(&self, io_bus: &devices::Bus, port: u16, data: &mut [u8]) {
+        if !io_bus.read(u64::from(port), data) {
+            for byte in data.iter_mut() {
+                *byte = 0xff;
+            }
+            self.log(port, "read", data.len());
+        }
+    }
+
+    /// Writes `data` to `port` on `io_bus`, if a device handles the port.
+    pub fn write(&self, io_bus: &devices::Bus, port: u16, data: &[u8]) {
+        if !io_bus.write(u64::from(port), data) {
+            self.log(port, "write", data.len());
+        }
+    }
+
+    #[cfg(test)]
+    fn accesses(&self, port: u16) -> u32 {
+        self.accesses
+            .lock()
+            .unwrap()
+            .get(&port)
+            .copied()
+            .unwrap_or(0)
+    }
+}
+
 /// Debug I/O port, see:
 /// https://www.intel.com/content/www/us/en/support/articles/000005500/boards-and-kits.html
 ///
@@ -76,34 +209,22 @@ pub enum Error {
     /// Cannot open the VCPU file descriptor.
     VcpuFd(kvm_ioctls::Error),
 
-    /// Cannot run the VCPUs.
+    /// KVM_RUN failed, the vCPU cannot go on running.
     VcpuRun(kvm_ioctls::Error),
 
     /// Cannot spawn a new vCPU thread.
     VcpuSpawn(io::Error),
 
-    #[cfg(target_arch = "x86_64")]
-    /// Error configuring the general purpose registers
-    REGSConfiguration(arch::x86_64::regs::Error),
-
-    #[cfg(target_arch = "x86_64")]
-    /// Error configuring the special registers
-    SREGSConfiguration(arch::x86_64::regs::Error),
-
-    #[cfg(target_arch = "x86_64")]
-    /// Error configuring the floating point related registers
-    FPUConfiguration(arch::x86_64::regs::Error),
+    /// Error configuring the vCPU registers, MSRs or CPUID.
+    VcpuConfiguration(arch::Error),
 
-    /// The call to KVM_SET_CPUID2 failed.
-    SetSupportedCpusFailed(kvm_ioctls::Error),
+    #[cfg(target_arch = "aarch64")]
+    /// Cannot create the GIC
+    CreateGic(arch::aarch64::gic::Error),
 
-    #[cfg(target_arch = "x86_64")]
-    /// Cannot set the local interruption due to bad configuration.
-    LocalIntConfiguration(arch::x86_64::interrupts::Error),
-
-    #[cfg(target_arch = "x86_64")]
-    /// Error configuring the MSR registers
-    MSRSConfiguration(arch::x86_64::regs::Error),
+    #[cfg(target_arch = "aarch64")]
+    /// Cannot signal the guest requested a shutdown
+    EventFdWrite(io::Error),
 
     /// Unexpected KVM_RUN exit reason
     VcpuUnhandledKvmExit,
@@ -119,81 +240,133 @@ pub enum Error {
 
     /// Asking for more vCPUs that we can have
     DesiredVCPUCountExceedsMax,
+
+    /// Cannot inject an NMI into the vCPU.
+    VcpuNmi(io::Error),
+
+    /// The targeted vCPU is not running.
+    VcpuNotPresent(u8),
+
+    /// Cannot read the vCPU state from KVM.
+    VcpuGetState(kvm_ioctls::Error),
+
+    /// Cannot load the vCPU state into KVM.
+    VcpuSetState(kvm_ioctls::Error),
+
+    /// KVM reported an internal error, with the given sub-error.
+    VcpuInternalError(u32),
+
+    /// Cannot set the debugging controls of the vCPU.
+    #[cfg(target_arch = "x86_64")]
+    VcpuSetGuestDebug(io::Error),
+
+    /// Asking for more hardware breakpoints than the vCPU has.
+    #[cfg(target_arch = "x86_64")]
+    TooManyHwBreakpoints(usize),
+
+    /// Cannot read the CPUs of a host NUMA node.
+    HostNodeCpus(io::Error),
+
+    /// The CPU list of a host NUMA node is malformed.
+    HostNodeCpuList(String),
+
+    /// Cannot enable SGX through the feature control MSR.
+    #[cfg(target_arch = "x86_64")]
+    SetSgxFeatureControl(kvm_ioctls::Error),
 }
 pub type Result<T> = result::Result<T, Error>;
 
-#[allow(dead_code)]
-#[derive(Copy, Clone)]
-enum CpuidReg {
-    EAX,
-    EBX,
-    ECX,
-    EDX,
+// Tells what the failure of KVM_RUN with `errno` means, for the errors that
+// are known to come from the guest or from the vCPU setup.
+fn kvm_run_error_reason(errno: i32) -> &'static str {
+    match errno {
+        libc::EFAULT => "the guest accessed memory that has no host mapping",
+        libc::ENOSYS => "KVM does not support the exit the vCPU made",
+        libc::ENOEXEC => "the vCPU is not initialized, or its state is invalid",
+        _ => "unexpected KVM error",
+    }
 }
 
-pub struct CpuidPatch {
-    pub function: u32,
-    pub index: u32,
-    pub flags_bit: Option<u8>,
-    pub eax_bit: Option<u8>,
-    pub ebx_bit: Option<u8>,
-    pub ecx_bit: Option<u8>,
-    pub edx_bit: Option<u8>,
+/// A vCPU which stopped running on an error, bringing the VM down.
+#[derive(Debug)]
+pub struct VcpuFailure {
+    pub id: u8,
+    pub error: Error,
 }
 
-impl CpuidPatch {
-    fn set_cpuid_reg(
-        cpuid: &mut CpuId,
-        function: u32,
-        index: Option<u32>,
-        reg: CpuidReg,
-        value: u32,
-    ) {
-        let entries = cpuid.as_mut_slice();
-
-        for entry in entries.iter_mut() {
-            if entry.function == function && (index == None || index.unwrap() == entry.index) {
-                match reg {
-                    CpuidReg::EAX => {
-                        entry.eax = value;
-                    }
-                    CpuidReg::EBX => {
-                        entry.ebx = value;
-                    }
-                    CpuidReg::ECX => {
-                        entry.ecx = value;
-                    }
-                    CpuidReg::EDX => {
-                        entry.edx = value;
-                    }
-                }
+impl fmt::Display for VcpuFailure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "vCPU {} failed: {}", self.id, self.error)
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::VcpuFd(_) => write!(f, "cannot open the VCPU file descriptor"),
+            Error::VcpuRun(e) => {
+                write!(
+                    f,
+                    "cannot run the vCPU: {}",
+                    kvm_run_error_reason(e.errno())
+                )
+            }
+            Error::VcpuSpawn(_) => write!(f, "cannot spawn a new vCPU thread"),
+            Error::VcpuConfiguration(e) => write!(f, "error configuring the vCPU: {:?}", e),
+            #[cfg(target_arch = "aarch64")]
+            Error::CreateGic(e) => write!(f, "cannot create the GIC: {:?}", e),
+            #[cfg(target_arch = "aarch64")]
+            Error::EventFdWrite(_) => write!(f, "cannot signal the guest requested a shutdown"),
+            Error::VcpuUnhandledKvmExit => write!(f, "unexpected KVM_RUN exit reason"),
+            Error::ThreadCleanup(e) => write!(f, "failed to join on vCPU threads: {:?}", e),
+            Error::BusError(_) => write!(f, "cannot add legacy device to Bus"),
+            Error::AllocateIOPort => write!(f, "failed to allocate IO port"),
+            Error::DesiredVCPUCountExceedsMax => {
+                write!(f, "asking for more vCPUs than we can have")
+            }
+            Error::VcpuNmi(_) => write!(f, "cannot inject an NMI into the vCPU"),
+            Error::VcpuNotPresent(e) => write!(f, "vCPU {} is not running", e),
+            Error::VcpuGetState(_) => write!(f, "cannot read the vCPU state from KVM"),
+            Error::VcpuSetState(_) => write!(f, "cannot load the vCPU state into KVM"),
+            Error::VcpuInternalError(e) => write!(f, "KVM internal error, sub-error {}", e),
+            #[cfg(target_arch = "x86_64")]
+            Error::VcpuSetGuestDebug(_) => write!(f, "cannot set the vCPU debugging controls"),
+            #[cfg(target_arch = "x86_64")]
+            Error::TooManyHwBreakpoints(n) => write!(
+                f,
+                "asking for {} hardware breakpoints, only {} are available",
+                n, HW_BREAKPOINTS
+            ),
+            Error::HostNodeCpus(_) => write!(f, "cannot read the CPUs of a host NUMA node"),
+            Error::HostNodeCpuList(e) => {
+                write!(f, "malformed CPU list for a host NUMA node: {:?}", e)
+            }
+            #[cfg(target_arch = "x86_64")]
+            Error::SetSgxFeatureControl(_) => {
+                write!(f, "cannot enable SGX through the feature control MSR")
             }
         }
     }
+}
 
-    pub fn patch_cpuid(cpuid: &mut CpuId, patches: Vec<CpuidPatch>) {
-        let entries = cpuid.as_mut_slice();
-
-        for entry in entries.iter_mut() {
-            for patch in patches.iter() {
-                if entry.function == patch.function && entry.index == patch.index {
-                    if let Some(flags_bit) = patch.flags_bit {
-                        entry.flags |= 1 << flags_bit;
-                    }
-                    if let Some(eax_bit) = patch.eax_bit {
-                        entry.eax |= 1 << eax_bit;
-                    }
-                    if let Some(ebx_bit) = patch.ebx_bit {
-                        entry.ebx |= 1 << ebx_bit;
-                    }
-                    if let Some(ecx_bit) = patch.ecx_bit {
-                        entry.ecx |= 1 << ecx_bit;
-                    }
-                    if let Some(edx_bit) = patch.edx_bit {
-                        entry.edx |= 1 << edx_bit;
-                    }
-                }
-            }
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::VcpuFd(e) => Some(e),
+            Error::VcpuRun(e) => Some(e),
+            Error::VcpuSpawn(e) => Some(e),
+            #[cfg(target_arch = "aarch64")]
+            Error::EventFdWrite(e) => Some(e),
+            Error::BusError(e) => Some(e),
+            Error::VcpuNmi(e) => Some(e),
+            Error::VcpuGetState(e) => Some(e),
+            Error::VcpuSetState(e) => Some(e),
+            #[cfg(target_arch = "x86_64")]
+            Error::VcpuSetGuestDebug(e) => Some(e),
+            Error::HostNodeCpus(e) => Some(e),
+            #[cfg(target_arch = "x86_64")]
+            Error::SetSgxFeatureControl(e) => Some(e),
+            _ => None,
         }
     }
 }
@@ -230,6 +403,272 @@ struct InterruptSourceOverride {
     pub flags: u16,
 }
 
+/// The KVM state of a vCPU, enough to resume it where it was stopped.
+#[cfg(target_arch = "x86_64")]
+pub struct VcpuKvmState {
+    regs: kvm_regs,
+    sregs: kvm_sregs,
+    xsave: kvm_xsave,
+    xcrs: kvm_xcrs,
+    lapic: kvm_lapic_state,
+    mp_state: kvm_mp_state,
+    vcpu_events: kvm_vcpu_events,
+    msrs: Vec<kvm_msr_entry>,
+}
+
+// The KVM structures are plain old data, so they can be saved as raw bytes.
+#[cfg(target_arch = "x86_64")]
+fn kvm_struct_to_bytes<T: Copy>(data: &[T]) -> Vec<u8> {
+    // Safe because the slice is valid for size_of_val(data) bytes.
+    unsafe {
+        std::slice::from_raw_parts(data.as_ptr() as *const u8, std::mem::size_of_val(data)).to_vec()
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+fn kvm_struct_from_bytes<T: Copy>(bytes: &[u8]) -> Option<Vec<T>> {
+    if bytes.len() % size_of::<T>() != 0 {
+        return None;
+    }
+
+    Some(
+        bytes
+            .chunks(size_of::<T>())
+            // Safe because the chunk is as large as T, and any bit pattern
+            // is a valid KVM structure.
+            .map(|chunk| unsafe { std::ptr::read_unaligned(chunk.as_ptr() as *const T) })
+            .collect(),
+    )
+}
+
+#[cfg(target_arch = "x86_64")]
+const VCPU_SNAPSHOT_REGS: &str = "regs";
+#[cfg(target_arch = "x86_64")]
+const VCPU_SNAPSHOT_SREGS: &str = "sregs";
+#[cfg(target_arch = "x86_64")]
+const VCPU_SNAPSHOT_XSAVE: &str = "xsave";
+#[cfg(target_arch = "x86_64")]
+const VCPU_SNAPSHOT_XCRS: &str = "xcrs";
+#[cfg(target_arch = "x86_64")]
+const VCPU_SNAPSHOT_LAPIC: &str = "lapic";
+#[cfg(target_arch = "x86_64")]
+const VCPU_SNAPSHOT_MP_STATE: &str = "mp_state";
+#[cfg(target_arch = "x86_64")]
+const VCPU_SNAPSHOT_VCPU_EVENTS: &str = "vcpu_events";
+#[cfg(target_arch = "x86_64")]
+const VCPU_SNAPSHOT_MSRS: &str = "msrs";
+
+#[cfg(target_arch = "x86_64")]
+impl VcpuKvmState {
+    fn add_to(&self, snapshot: &mut Snapshot) {
+        let mut add = |id: &str, snapshot_data: Vec<u8>| {
+            snapshot.add_data_section(SnapshotDataSection {
+                id: id.to_string(),
+                snapshot: snapshot_data,
+            })
+        };
+
+        add(VCPU_SNAPSHOT_REGS, kvm_struct_to_bytes(&[self.regs]));
+        add(VCPU_SNAPSHOT_SREGS, kvm_struct_to_bytes(&[self.sregs]));
+        add(VCPU_SNAPSHOT_XSAVE, kvm_struct_to_bytes(&[self.xsave]));
+        add(VCPU_SNAPSHOT_XCRS, kvm_struct_to_bytes(&[self.xcrs]));
+        add(VCPU_SNAPSHOT_LAPIC, kvm_struct_to_bytes(&[self.lapic]));
+        add(
+            VCPU_SNAPSHOT_MP_STATE,
+            kvm_struct_to_bytes(&[self.mp_state]),
+        );
+        add(
+            VCPU_SNAPSHOT_VCPU_EVENTS,
+            kvm_struct_to_bytes(&[self.vcpu_events]),
+        );
+        add(VCPU_SNAPSHOT_MSRS, kvm_struct_to_bytes(&self.msrs));
+    }
+
+    fn from_snapshot(snapshot: &Snapshot) -> result::Result<Self, MigratableError> {
+        fn section<T: Copy>(
+            snapshot: &Snapshot,
+            id: &str,
+        ) -> result::Result<Vec<T>, MigratableError> {
+            snapshot
+                .snapshot_data
+                .get(id)
+                .and_then(|section| kvm_struct_from_bytes(&section.snapshot))
+                .ok_or_else(|| {
+                    MigratableError::Restore(anyhow!(
+                        "Invalid {} section in {} snapshot",
+                        id,
+                        snapshot.id
+                    ))
+                })
+        }
+
+        fn single<T: Copy>(snapshot: &Snapshot, id: &str) -> result::Result<T, MigratableError> {
+            match section(snapshot, id)?.as_slice() {
+                [value] => Ok(*value),
+                _ => Err(MigratableError::Restore(anyhow!(
+                    "Invalid {} section in {} snapshot",
+                    id,
+                    snapshot.id
+                ))),
+            }
+        }
+
+        Ok(VcpuKvmState {
+            regs: single(snapshot, VCPU_SNAPSHOT_REGS)?,
+            sregs: single(snapshot, VCPU_SNAPSHOT_SREGS)?,
+            xsave: single(snapshot, VCPU_SNAPSHOT_XSAVE)?,
+            xcrs: single(snapshot, VCPU_SNAPSHOT_XCRS)?,
+            lapic: single(snapshot, VCPU_SNAPSHOT_LAPIC)?,
+            mp_state: single(snapshot, VCPU_SNAPSHOT_MP_STATE)?,
+            vcpu_events: single(snapshot, VCPU_SNAPSHOT_VCPU_EVENTS)?,
+            msrs: section(snapshot, VCPU_SNAPSHOT_MSRS)?,
+        })
+    }
+}
+
+// Formats the registers of a vCPU, one group per line.
+#[cfg(target_arch = "x86_64")]
+fn format_vcpu_state(id: u8, regs: &kvm_regs, sregs: &kvm_sregs) -> String {
+    let segment = |name: &str, s: &kvm_segment| {
+        format!(
+            "{}={:#06x} base={:#x} limit={:#x} type={:#x} dpl={} db={} l={} present={}",
+            name, s.selector, s.base, s.limit, s.type_, s.dpl, s.db, s.l, s.present
+        )
+    };
+
+    [
+        format!("vCPU {}", id),
+        format!("rip={:#018x} rflags={:#018x}", regs.rip, regs.rflags),
+        format!(
+            "rax={:#018x} rbx={:#018x} rcx={:#018x} rdx={:#018x}",
+            regs.rax, regs.rbx, regs.rcx, regs.rdx
+        ),
+        format!(
+            "rsi={:#018x} rdi={:#018x} rbp={:#018x} rsp={:#018x}",
+            regs.rsi, regs.rdi, regs.rbp, regs.rsp
+        ),
+        format!(
+            "r8={:#018x} r9={:#018x} r10={:#018x} r11={:#018x}",
+            regs.r8, regs.r9, regs.r10, regs.r11
+        ),
+        format!(
+            "r12={:#018x} r13={:#018x} r14={:#018x} r15={:#018x}",
+            regs.r12, regs.r13, regs.r14, regs.r15
+        ),
+        segment("cs", &sregs.cs),
+        segment("ds", &sregs.ds),
+        segment("es", &sregs.es),
+        segment("fs", &sregs.fs),
+        segment("gs", &sregs.gs),
+        segment("ss", &sregs.ss),
+        format!(
+            "cr0={:#018x} cr2={:#018x} cr3={:#018x} cr4={:#018x} efer={:#x}",
+            sregs.cr0, sregs.cr2, sregs.cr3, sregs.cr4, sregs.efer
+        ),
+    ]
+    .join("\n")
+}
+
+/// Handles the exits returned by running a vCPU.
+///
+/// `handle()` dispatches each exit reason to a dedicated method. Every method
+/// has a default implementation, so a handler only overrides the exits it
+/// cares about. The returned boolean tells whether the vCPU should keep
+/// running.
+pub trait VcpuExitHandler {
+    fn handle(&self, exit: VcpuExit) -> Result<bool> {
+        match exit {
+            VcpuExit::IoIn(addr, data) => self.io_in(addr, data),
+            VcpuExit::IoOut(addr, data) => self.io_out(addr, data),
+            VcpuExit::MmioRead(addr, data) => self.mmio_read(addr, data),
+            VcpuExit::MmioWrite(addr, data) => self.mmio_write(addr, data),
+            VcpuExit::IoapicEoi(vector) => self.ioapic_eoi(vector),
+            VcpuExit::Hlt => self.hlt(),
+            VcpuExit::Shutdown => self.shutdown(),
+            VcpuExit::SystemEvent(event_type, flags) => self.system_event(event_type, flags),
+            VcpuExit::InternalError => self.internal_error(),
+            VcpuExit::Debug => self.debug(),
+            exit => self.unhandled(exit),
+        }
+    }
+
+    fn io_in(&self, addr: u16, data: &mut [u8]) -> Result<bool> {
+        self.unhandled(VcpuExit::IoIn(addr, data))
+    }
+
+    fn io_out(&self, addr: u16, data: &[u8]) -> Result<bool> {
+        self.unhandled(VcpuExit::IoOut(addr, data))
+    }
+
+    fn mmio_read(&self, addr: u64, data: &mut [u8]) -> Result<bool> {
+        self.unhandled(VcpuExit::MmioRead(addr, data))
+    }
+
+    fn mmio_write(&self, addr: u64, data: &[u8]) -> Result<bool> {
+        self.unhandled(VcpuExit::MmioWrite(addr, data))
+    }
+
+    fn ioapic_eoi(&self, vector: u8) -> Result<bool> {
+        self.unhandled(VcpuExit::IoapicEoi(vector))
+    }
+
+    fn hlt(&self) -> Result<bool> {
+        self.unhandled(VcpuExit::Hlt)
+    }
+
+    fn shutdown(&self) -> Result<bool> {
+        // Triple fault to trigger a reboot
+        Ok(false)
+    }
+
+    fn system_event(&self, event_type: u32, flags: u64) -> Result<bool> {
+        self.unhandled(VcpuExit::SystemEvent(event_type, flags))
+    }
+
+    fn internal_error(&self) -> Result<bool> {
+        self.unhandled(VcpuExit::InternalError)
+    }
+
+    fn debug(&self) -> Result<bool> {
+        self.unhandled(VcpuExit::Debug)
+    }
+
+    fn unhandled(&self, exit: VcpuExit) -> Result<bool> {
+        error!("Unexpected exit reason on vcpu run: {:?}", exit);
+        Err(Error::VcpuUnhandledKvmExit)
+    }
+}
+
+// Counts the exits of a vCPU by type, the ones not worth a counter of their
+// own being counted together.
+struct ExitMetrics {
+    io: Arc<Metric>,
+    mmio: Arc<Metric>,
+    hlt: Arc<Metric>,
+    other: Arc<Metric>,
+}
+
+impl ExitMetrics {
+    fn new(id: &str) -> Self {
+        let registry = metrics::registry();
+        ExitMetrics {
+            io: registry.counter("vcpu_io_exits", id),
+            mmio: registry.counter("vcpu_mmio_exits", id),
+            hlt: registry.counter("vcpu_hlt_exits", id),
+            other: registry.counter("vcpu_other_exits", id),
+        }
+    }
+
+    fn count(&self, exit: &VcpuExit) {
+        match exit {
+            VcpuExit::IoIn(..) | VcpuExit::IoOut(..) => self.io.inc(),
+            VcpuExit::MmioRead(..) | VcpuExit::MmioWrite(..) => self.mmio.inc(),
+            VcpuExit::Hlt => self.hlt.inc(),
+            _ => self.other.inc(),
+        }
+    }
+}
+
 /// A wrapper around creating and using a kvm-based VCPU.
 pub struct Vcpu {
     fd: VcpuFd,
@@ -238,6 +677,46 @@ pub struct Vcpu {
     mmio_bus: Arc<devices::Bus>,
     ioapic: Option<Arc<Mutex<ioapic::Ioapic>>>,
     vm_ts: std::time::Instant,
+    msr_list: Arc<Vec<u32>>,
+    msr_overrides: Arc<Vec<MsrConfig>>,
+    unhandled_pio: Arc<UnhandledPio>,
+    #[cfg(target_arch = "aarch64")]
+    exit_evt: EventFd,
+    #[cfg(target_arch = "x86_64")]
+    guest_debug: kvm_guest_debug,
+    exits: Arc<Metric>,
+    exit_types: ExitMetrics,
+    bus_tracer: Option<Arc<BusTracer>>,
+}
+
+/// What made a vCPU exit with a debug exception.
+#[cfg(target_arch = "x86_64")]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DebugEvent {
+    /// The hardware breakpoint `index` was hit at `address`.
+    HwBreakpoint { index: usize, address: u64 },
+    /// A single instruction was executed, stopping at `pc`.
+    SingleStep { pc: u64 },
+    /// Any other debug exception, at `pc`.
+    Other { pc: u64, dr6: u64 },
+}
+
+#[cfg(target_arch = "x86_64")]
+impl DebugEvent {
+    // Decodes the debug status register, the breakpoint addresses coming
+    // from DR0 to DR3 as programmed.
+    fn from_dr6(dr6: u64, pc: u64, debugreg: &[u64]) -> Self {
+        if let Some(index) = (0..HW_BREAKPOINTS).find(|i| dr6 & (1 << i) != 0) {
+            DebugEvent::HwBreakpoint {
+                index,
+                address: debugreg[index],
+            }
+        } else if dr6 & DR6_BS != 0 {
+            DebugEvent::SingleStep { pc }
+        } else {
+            DebugEvent::Other { pc, dr6 }
+        }
+    }
 }
 
 impl Vcpu {
@@ -247,6 +726,7 @@ impl Vcpu {
     ///
     /// * `id` - Represents the CPU number between [0, max vcpus).
     /// * `vm` - The virtual machine this vcpu will get attached to.
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         id: u8,
         fd: &Arc<VmFd>,
@@ -254,6 +734,11 @@ impl Vcpu {
         mmio_bus: Arc<devices::Bus>,
         ioapic: Option<Arc<Mutex<ioapic::Ioapic>>>,
         creation_ts: std::time::Instant,
+        msr_list: Arc<Vec<u32>>,
+        msr_overrides: Arc<Vec<MsrConfig>>,
+        unhandled_pio: Arc<UnhandledPio>,
+        bus_tracer: Option<Arc<BusTracer>>,
+        #[cfg(target_arch = "aarch64")] exit_evt: EventFd,
     ) -> Result<Self> {
         let kvm_vcpu = fd.create_vcpu(id).map_err(Error::VcpuFd)?;
         // Initially the cpuid per vCPU is the one supported by this VM.
@@ -264,43 +749,78 @@ impl Vcpu {
             mmio_bus,
             ioapic,
             vm_ts: creation_ts,
+            msr_list,
+            msr_overrides,
+            unhandled_pio,
+            #[cfg(target_arch = "aarch64")]
+            exit_evt,
+            #[cfg(target_arch = "x86_64")]
+            guest_debug: kvm_guest_debug::default(),
+            exits: metrics::registry().counter("vcpu_exits", &id.to_string()),
+            exit_types: ExitMetrics::new(&id.to_string()),
+            bus_tracer,
         })
     }
 
-    /// Configures a x86_64 specific vcpu and should be called once per vcpu from the vcpu's thread.
+    /// Configures the vcpu for the VM, through the architecture specific
+    /// `configure_vcpu`. On x86_64 this should be called once per vcpu from the
+    /// vcpu's thread, while on aarch64 it must be done before the GIC is
+    /// created, so from the thread creating the vcpus.
     ///
     /// # Arguments
     ///
-    /// * `machine_config` - Specifies necessary info used for the CPUID configuration.
-    /// * `kernel_start_addr` - Offset from `guest_mem` at which the kernel starts.
-    /// * `vm` - The virtual machine this vcpu will get attached to.
+    /// * `vm_fd` - The virtual machine this vcpu is attached to.
+    /// * `kernel_start_addr` - Offset from `guest_mem` at which the kernel starts. Without it,
+    ///   the registers are left in the reset state KVM creates the vcpu in.
+    /// * `vm_memory` - The guest memory.
+    /// * `cpuid` - The CPUID of the VM.
+    /// * `lapic` - Whether the local APIC is emulated in kernel.
     pub fn configure(
         &mut self,
+        #[cfg(target_arch = "aarch64")] vm_fd: &VmFd,
         kernel_start_addr: Option<GuestAddress>,
         vm_memory: &Arc<ArcSwap<GuestMemoryMmap>>,
-        cpuid: CpuId,
+        #[cfg(target_arch = "x86_64")] cpuid: CpuId,
+        #[cfg(target_arch = "x86_64")] lapic: bool,
     ) -> Result<()> {
-        let mut cpuid = cpuid;
-        CpuidPatch::set_cpuid_reg(&mut cpuid, 0xb, None, CpuidReg::EDX, u32::from(self.id));
-        self.fd
-            .set_cpuid2(&cpuid)
-            .map_err(Error::SetSupportedCpusFailed)?;
-
-        arch::x86_64::regs::setup_msrs(&self.fd).map_err(Error::MSRSConfiguration)?;
-        if let Some(kernel_start_addr) = kernel_start_addr {
-            // Safe to unwrap because this method is called after the VM is configured
-            arch::x86_64::regs::setup_regs(
+        #[cfg(target_arch = "x86_64")]
+        {
+            let msr_overrides = self.msr_overrides();
+            let sgx_feature_control = arch::x86_64::sgx_feature_control(&cpuid);
+            arch::x86_64::configure_vcpu(
                 &self.fd,
-                kernel_start_addr.raw_value(),
-                arch::x86_64::layout::BOOT_STACK_POINTER.raw_value(),
-                arch::x86_64::layout::ZERO_PAGE_START.raw_value(),
+                self.id,
+                kernel_start_addr,
+                &vm_memory.load(),
+                cpuid,
+                &msr_overrides,
+                lapic,
             )
-            .map_err(Error::REGSConfiguration)?;
-            arch::x86_64::regs::setup_fpu(&self.fd).map_err(Error::FPUConfiguration)?;
-            arch::x86_64::regs::setup_sregs(&vm_memory.load(), &self.fd)
-                .map_err(Error::SREGSConfiguration)?;
+            .map_err(Error::VcpuConfiguration)?;
+            // The guest only gets to use SGX once the firmware enabled it,
+            // which is left to the VMM.
+            if let Some(entry) = sgx_feature_control {
+                let count = self
+                    .fd
+                    .set_msrs(&Msrs::from_entries(&[entry]))
+                    .map_err(Error::SetSgxFeatureControl)?;
+                if count != 1 {
+                    return Err(Error::SetSgxFeatureControl(kvm_ioctls::Error::new(
+                        libc::EINVAL,
+                    )));
+                }
+            }
         }
-        arch::x86_64::interrupts::set_lint(&self.fd).map_err(Error::LocalIntConfiguration)?;
+        #[cfg(target_arch = "aarch64")]
+        arch::aarch64::configure_vcpu(
+            vm_fd,
+            &self.fd,
+            self.id,
+            kernel_start_addr,
+            &vm_memory.load(),
+        )
+        .map_err(Error::VcpuConfiguration)?;
+
         Ok(())
     }
 
@@ -310,53 +830,329 @@ impl Vcpu {
     /// anything useful.
     pub fn run(&self) -> Result<bool> {
         match self.fd.run() {
-            Ok(run) => match run {
-                VcpuExit::IoIn(addr, data) => {
-                    self.io_bus.read(u64::from(addr), data);
-                    Ok(true)
-                }
-                VcpuExit::IoOut(addr, data) => {
-                    if addr == DEBUG_IOPORT && data.len() == 1 {
-                        self.log_debug_ioport(data[0]);
-                    }
-                    self.io_bus.write(u64::from(addr), data);
-                    Ok(true)
-                }
-                VcpuExit::MmioRead(addr, data) => {
-                    self.mmio_bus.read(addr as u64, data);
-                    Ok(true)
-                }
-                VcpuExit::MmioWrite(addr, data) => {
-                    self.mmio_bus.write(addr as u64, data);
-                    Ok(true)
+            Ok(exit) => {
+                self.exits.inc();
+                self.exit_types.count(&exit);
+                self.handle(exit)
+            }
+
+            Err(e) => match e.errno() {
+                libc::EAGAIN | libc::EINTR => Ok(true),
+                errno => {
+                    error!(
+                        "vCPU {} cannot run, {}: {}",
+                        self.id,
+                        kvm_run_error_reason(errno),
+                        e
+                    );
+                    Err(Error::VcpuRun(e))
                 }
-                VcpuExit::IoapicEoi(vector) => {
-                    if let Some(ioapic) = &self.ioapic {
-                        ioapic.lock().unwrap().end_of_interrupt(vector);
+            },
+        }
+    }
+
+    // Reads from the kvm_run structure what kvm-ioctls doesn't expose, from
+    // the exit reasons without data.
+    fn read_kvm_run<T>(&self, read: impl FnOnce(&kvm_run) -> T) -> Option<T> {
+        let size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+        // Safe because we map the start of the kvm_run structure read-only,
+        // and check the result.
+        let addr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                size,
+                libc::PROT_READ,
+                libc::MAP_SHARED,
+                self.fd.as_raw_fd(),
+                0,
+            )
+        };
+        if addr == libc::MAP_FAILED {
+            error!("Failed to map kvm_run: {}", io::Error::last_os_error());
+            return None;
+        }
+
+        // Safe because the mapping holds a kvm_run structure.
+        let result = read(unsafe { &*(addr as *const kvm_run) });
+
+        // Safe because addr and size describe the mapping created above.
+        unsafe { libc::munmap(addr, size) };
+
+        Some(result)
+    }
+
+    // Reads the sub-error and its data, for internal errors.
+    fn read_internal_error(&self) -> (u32, Vec<u64>) {
+        self.read_kvm_run(|run| {
+            // Safe because the exit reason tells the internal error member
+            // of the union is valid.
+            let internal = unsafe { run.__bindgen_anon_1.internal };
+            let ndata = cmp::min(internal.ndata as usize, internal.data.len());
+            (internal.suberror, internal.data[..ndata].to_vec())
+        })
+        .unwrap_or((0, Vec::new()))
+    }
+
+    /// Tells why the vCPU exited with a debug exception, from the kvm_run
+    /// structure, as kvm-ioctls doesn't expose it.
+    #[cfg(target_arch = "x86_64")]
+    pub fn debug_event(&self) -> Option<DebugEvent> {
+        // Safe because this is only called on debug exits, for which the
+        // debug member of the union is valid.
+        let arch = self.read_kvm_run(|run| unsafe { run.__bindgen_anon_1.debug.arch })?;
+        Some(DebugEvent::from_dr6(
+            arch.dr6,
+            arch.pc,
+            &self.guest_debug.arch.debugreg,
+        ))
+    }
+
+    /// Programs the hardware breakpoints on `addrs`, up to 4 instruction
+    /// breakpoints through DR0 to DR3. An empty list removes them all.
+    #[cfg(target_arch = "x86_64")]
+    pub fn set_hw_breakpoints(&mut self, addrs: &[u64]) -> Result<()> {
+        if addrs.len() > HW_BREAKPOINTS {
+            return Err(Error::TooManyHwBreakpoints(addrs.len()));
+        }
+
+        let arch = &mut self.guest_debug.arch;
+        arch.debugreg = Default::default();
+        // DR7 local enable bits, leaving the conditions and lengths cleared
+        // for execution breakpoints.
+        let mut dr7 = 0;
+        for (i, addr) in addrs.iter().enumerate() {
+            arch.debugreg[i] = *addr;
+            dr7 |= 1 << (2 * i);
+        }
+        arch.debugreg[7] = dr7;
+
+        if addrs.is_empty() {
+            self.guest_debug.control &= !KVM_GUESTDBG_USE_HW_BP;
+        } else {
+            self.guest_debug.control |= KVM_GUESTDBG_USE_HW_BP;
+        }
+        self.set_guest_debug()
+    }
+
+    /// Makes the vCPU exit after each instruction, or stop doing so.
+    #[cfg(target_arch = "x86_64")]
+    pub fn enable_singlestep(&mut self, enable: bool) -> Result<()> {
+        if enable {
+            self.guest_debug.control |= KVM_GUESTDBG_SINGLESTEP;
+        } else {
+            self.guest_debug.control &= !KVM_GUESTDBG_SINGLESTEP;
+        }
+        self.set_guest_debug()
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    fn set_guest_debug(&mut self) -> Result<()> {
+        if self.guest_debug.control & !KVM_GUESTDBG_ENABLE != 0 {
+            self.guest_debug.control |= KVM_GUESTDBG_ENABLE;
+        } else {
+            self.guest_debug.control = 0;
+        }
+
+        // Safe because we know the file descriptor is a valid vCPU one and
+        // the structure is the one KVM_SET_GUEST_DEBUG expects.
+        let ret = unsafe { ioctl_with_ref(&self.fd, KVM_SET_GUEST_DEBUG(), &self.guest_debug) };
+        if ret < 0 {
+            return Err(Error::VcpuSetGuestDebug(io::Error::last_os_error()));
+        }
+
+        Ok(())
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    fn translate_gva(&self, gva: u64) -> Option<u64> {
+        let mut translation = kvm_translation {
+            linear_address: gva,
+            ..Default::default()
+        };
+        // Safe because we know the file descriptor is a valid vCPU one and
+        // the structure is the one KVM_TRANSLATE expects.
+        let ret = unsafe { ioctl_with_mut_ref(&self.fd, KVM_TRANSLATE(), &mut translation) };
+        if ret < 0 || translation.valid == 0 {
+            return None;
+        }
+
+        Some(translation.physical_address)
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    /// Logs the registers and the guest code around the instruction pointer,
+    /// to help understanding why the vCPU failed.
+    pub fn dump(&self, vm_memory: &GuestMemoryMmap) {
+        match self.fd.get_regs() {
+            Ok(regs) => {
+                error!("vCPU {} registers: {:x?}", self.id, regs);
+
+                let rip_gpa = match self.translate_gva(regs.rip) {
+                    Some(gpa) => gpa,
+                    None => {
+                        error!("Cannot translate RIP 0x{:x}", regs.rip);
+                        return self.dump_sregs();
                     }
-                    Ok(true)
-                }
-                VcpuExit::Shutdown => {
-                    // Triple fault to trigger a reboot
-                    Ok(false)
-                }
-                r => {
-                    error!("Unexpected exit reason on vcpu run: {:?}", r);
-                    Err(Error::VcpuUnhandledKvmExit)
+                };
+
+                // Stay on the page of the instruction pointer, as the
+                // surrounding ones may not be contiguous.
+                let page = rip_gpa & !0xfff;
+                let start = cmp::max(rip_gpa.saturating_sub(INTERNAL_ERROR_DUMP_BEFORE), page);
+                let end = cmp::min(rip_gpa + INTERNAL_ERROR_DUMP_AFTER, page + 0x1000);
+                let mut code = vec![0u8; (end - start) as usize];
+                match vm_memory.read_slice(&mut code, GuestAddress(start)) {
+                    Ok(()) => error!(
+                        "Guest code at 0x{:x} (RIP 0x{:x} at GPA 0x{:x}): {:02x?}",
+                        start, regs.rip, rip_gpa, code
+                    ),
+                    Err(e) => error!("Cannot read guest code at 0x{:x}: {}", start, e),
                 }
-            },
+            }
+            Err(e) => error!("Cannot get vCPU {} registers: {}", self.id, e),
+        }
 
-            Err(ref e) => match e.errno() {
-                libc::EAGAIN | libc::EINTR => Ok(true),
-                _ => {
-                    error!("VCPU {:?} error {:?}", self.id, e);
-                    Err(Error::VcpuUnhandledKvmExit)
+        self.dump_sregs();
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    fn dump_sregs(&self) {
+        match self.fd.get_sregs() {
+            Ok(sregs) => error!("vCPU {} special registers: {:x?}", self.id, sregs),
+            Err(e) => error!("Cannot get vCPU {} special registers: {}", self.id, e),
+        }
+    }
+
+    /// Returns the general purpose, segment and control registers of the
+    /// VCPU, formatted for debugging. The VCPU must not be running.
+    #[cfg(target_arch = "x86_64")]
+    pub fn dump_state(&self) -> Result<String> {
+        let regs = self.fd.get_regs().map_err(Error::VcpuGetState)?;
+        let sregs = self.fd.get_sregs().map_err(Error::VcpuGetState)?;
+
+        Ok(format_vcpu_state(self.id, &regs, &sregs))
+    }
+
+    /// Injects a non-maskable interrupt into the VCPU.
+    ///
+    /// This must be called from the thread owning the VCPU, while the VCPU
+    /// is not running.
+    #[cfg(target_arch = "x86_64")]
+    pub fn inject_nmi(&self) -> Result<()> {
+        // Safe because we know the file descriptor is a valid vCPU one and
+        // KVM_NMI does not take any argument.
+        let ret = unsafe { ioctl(&self.fd, KVM_NMI()) };
+        if ret < 0 {
+            return Err(Error::VcpuNmi(io::Error::last_os_error()));
+        }
+
+        Ok(())
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    /// Reads the vCPU state from KVM. The vCPU must not be running.
+    pub fn state(&self) -> Result<VcpuKvmState> {
+        Ok(VcpuKvmState {
+            regs: self.fd.get_regs().map_err(Error::VcpuGetState)?,
+            sregs: self.fd.get_sregs().map_err(Error::VcpuGetState)?,
+            xsave: self.fd.get_xsave().map_err(Error::VcpuGetState)?,
+            xcrs: self.fd.get_xcrs().map_err(Error::VcpuGetState)?,
+            lapic: self.fd.get_lapic().map_err(Error::VcpuGetState)?,
+            mp_state: self.fd.get_mp_state().map_err(Error::VcpuGetState)?,
+            vcpu_events: self.fd.get_vcpu_events().map_err(Error::VcpuGetState)?,
+            msrs: self.get_msrs()?,
+        })
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    /// Loads a state returned by `state()` into KVM. The vCPU must have been
+    /// configured first, as the CPUID is not part of the state.
+    pub fn set_state(&self, state: &VcpuKvmState) -> Result<()> {
+        self.fd
+            .set_mp_state(state.mp_state)
+            .map_err(Error::VcpuSetState)?;
+        self.fd.set_regs(&state.regs).map_err(Error::VcpuSetState)?;
+        self.fd
+            .set_sregs(&state.sregs)
+            .map_err(Error::VcpuSetState)?;
+        self.fd
+            .set_xsave(&state.xsave)
+            .map_err(Error::VcpuSetState)?;
+        self.fd.set_xcrs(&state.xcrs).map_err(Error::VcpuSetState)?;
+        self.fd
+            .set_lapic(&state.lapic)
+            .map_err(Error::VcpuSetState)?;
+        self.fd
+            .set_msrs(&Msrs::from_entries(&state.msrs))
+            .map_err(Error::VcpuSetState)?;
+        self.fd
+            .set_vcpu_events(&state.vcpu_events)
+            .map_err(Error::VcpuSetState)?;
+
+        Ok(())
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    // The user provided MSR values, skipping the ones KVM can't set.
+    fn msr_overrides(&self) -> Vec<kvm_msr_entry> {
+        self.msr_overrides
+            .iter()
+            .filter(|msr| {
+                let allowed = self.msr_list.contains(&msr.index);
+                if !allowed {
+                    warn!(
+                        "Skipping MSR {:#x} override on vCPU {}: not supported by KVM",
+                        msr.index, self.id
+                    );
                 }
-            },
+                allowed
+            })
+            .map(|msr| kvm_msr_entry {
+                index: msr.index,
+                data: msr.value,
+                ..Default::default()
+            })
+            .collect()
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    fn get_msrs(&self) -> Result<Vec<kvm_msr_entry>> {
+        let mut entries = Vec::with_capacity(self.msr_list.len());
+        let mut indices = self.msr_list.as_slice();
+        while !indices.is_empty() {
+            let mut msrs = Msrs::from_entries(
+                &indices
+                    .iter()
+                    .map(|&index| kvm_msr_entry {
+                        index,
+                        ..Default::default()
+                    })
+                    .collect::<Vec<kvm_msr_entry>>(),
+            );
+            let count = self.fd.get_msrs(&mut msrs).map_err(Error::VcpuGetState)?;
+            entries.extend_from_slice(&msrs.as_slice()[..count]);
+
+            // KVM stops at the first MSR it can't read, skip it.
+            indices = &indices[cmp::min(count + 1, indices.len())..];
         }
+
+        Ok(entries)
+    }
+
+    /// Logs the core registers, on aarch64 the guest code is not dumped.
+    #[cfg(target_arch = "aarch64")]
+    pub fn dump(&self, _vm_memory: &GuestMemoryMmap) {
+        error!("vCPU {} state dump is not supported on aarch64", self.id);
+    }
+
+    /// NMIs can't be injected on aarch64.
+    #[cfg(target_arch = "aarch64")]
+    pub fn inject_nmi(&self) -> Result<()> {
+        Err(Error::VcpuNmi(io::Error::from_raw_os_error(libc::ENOSYS)))
     }
 
     // Log debug io port codes.
+    #[cfg(target_arch = "x86_64")]
     fn log_debug_ioport(&self, code: u8) {
         let ts = self.vm_ts.elapsed();
 
@@ -370,6 +1166,149 @@ impl Vcpu {
     }
 }
 
+impl VcpuExitHandler for Vcpu {
+    fn io_in(&self, addr: u16, data: &mut [u8]) -> Result<bool> {
+        self.unhandled_pio.read(&self.io_bus, addr, data);
+        if let Some(tracer) = &self.bus_tracer {
+            tracer.record(self.id, BusKind::Pio, false, u64::from(addr), data);
+        }
+        Ok(true)
+    }
+
+    fn io_out(&self, addr: u16, data: &[u8]) -> Result<bool> {
+        #[cfg(target_arch = "x86_64")]
+        {
+            if addr == DEBUG_IOPORT && data.len() == 1 {
+                self.log_debug_ioport(data[0]);
+            }
+        }
+        self.unhandled_pio.write(&self.io_bus, addr, data);
+        if let Some(tracer) = &self.bus_tracer {
+            tracer.record(self.id, BusKind::Pio, true, u64::from(addr), data);
+        }
+        Ok(true)
+    }
+
+    fn mmio_read(&self, addr: u64, data: &mut [u8]) -> Result<bool> {
+        self.mmio_bus.read(addr, data);
+        if let Some(tracer) = &self.bus_tracer {
+            tracer.record(self.id, BusKind::Mmio, false, addr, data);
+        }
+        Ok(true)
+    }
+
+    fn mmio_write(&self, addr: u64, data: &[u8]) -> Result<bool> {
+        self.mmio_bus.write(addr, data);
+        if let Some(tracer) = &self.bus_tracer {
+            tracer.record(self.id, BusKind::Mmio, true, addr, data);
+        }
+        Ok(true)
+    }
+
+    fn ioapic_eoi(&self, vector: u8) -> Result<bool> {
+        if let Some(ioapic) = &self.ioapic {
+            ioapic.lock().unwrap().end_of_interrupt(vector);
+        }
+        Ok(true)
+    }
+
+    // PSCI SYSTEM_OFF and SYSTEM_RESET calls from the guest.
+    #[cfg(target_arch = "aarch64")]
+    fn system_event(&self, event_type: u32, flags: u64) -> Result<bool> {
+        match event_type {
+            KVM_SYSTEM_EVENT_SHUTDOWN => {
+                self.exit_evt.write(1).map_err(Error::EventFdWrite)?;
+                Ok(true)
+            }
+            KVM_SYSTEM_EVENT_RESET => Ok(false),
+            _ => self.unhandled(VcpuExit::SystemEvent(event_type, flags)),
+        }
+    }
+
+    fn internal_error(&self) -> Result<bool> {
+        let (suberror, data) = self.read_internal_error();
+        error!(
+            "KVM internal error on vCPU {}: suberror {} data {:x?}",
+            self.id, suberror, data
+        );
+        Err(Error::VcpuInternalError(suberror))
+    }
+
+    // Reports the breakpoints hits and steps, leaving the vCPU running.
+    #[cfg(target_arch = "x86_64")]
+    fn debug(&self) -> Result<bool> {
+        let event = match self.debug_event() {
+            Some(event) => event,
+            None => return self.unhandled(VcpuExit::Debug),
+        };
+        info!("vCPU {} debug exception: {:x?}", self.id, event);
+
+        if let DebugEvent::HwBreakpoint { .. } = event {
+            // The breakpoint is a fault, resume without hitting it again.
+            let mut regs = self.fd.get_regs().map_err(Error::VcpuGetState)?;
+            regs.rflags |= RFLAGS_RF;
+            self.fd.set_regs(&regs).map_err(Error::VcpuSetState)?;
+        }
+
+        Ok(true)
+    }
+}
+
+// Logs the latest accesses of the guest, e.g. the ones which led a vCPU to
+// fail.
+fn log_bus_trace(tracer: &BusTracer) {
+    let accesses: Vec<String> = tracer
+        .recent()
+        .iter()
+        .map(|access| access.to_string())
+        .collect();
+    error!("Latest bus accesses:\n{}", accesses.join("\n"));
+    if let Err(e) = tracer.flush() {
+        warn!("Cannot write the bus trace: {}", e);
+    }
+}
+
+// Returns the host CPUs of `node`, as listed by sysfs, e.g. "0-3,8-11".
+fn host_node_cpus(node: u32) -> Result<Vec<usize>> {
+    let path = format!("/sys/devices/system/node/node{}/cpulist", node);
+    let cpulist = std::fs::read_to_string(path).map_err(Error::HostNodeCpus)?;
+    parse_cpu_list(cpulist.trim()).ok_or(Error::HostNodeCpuList(cpulist))
+}
+
+fn parse_cpu_list(cpulist: &str) -> Option<Vec<usize>> {
+    let mut cpus = Vec::new();
+    for item in cpulist.split(',').filter(|i| !i.is_empty()) {
+        let mut bounds = item.splitn(2, '-');
+        let first: usize = bounds.next()?.parse().ok()?;
+        let last: usize = match bounds.next() {
+            Some(last) => last.parse().ok()?,
+            None => first,
+        };
+        cpus.extend(first..=last);
+    }
+    Some(cpus)
+}
+
+// Restricts the calling thread to the given host CPUs.
+fn set_thread_affinity(host_cpus: &[usize]) {
+    // Safe because the CPU set is fully initialized before being passed
+    // to the kernel, along with its size.
+    let ret = unsafe {
+        let mut cpuset: libc::cpu_set_t = std::mem::zeroed();
+        libc::CPU_ZERO(&mut cpuset);
+        for cpu in host_cpus {
+            libc::CPU_SET(*cpu, &mut cpuset);
+        }
+        libc::sched_setaffinity(0, size_of::<libc::cpu_set_t>(), &cpuset)
+    };
+    if ret != 0 {
+        warn!(
+            "Failed to set vCPU affinity: {}",
+            io::Error::last_os_error()
+        );
+    }
+}
+
 pub struct CpuManager {
     boot_vcpus: u8,
     max_vcpus: u8,
@@ -377,13 +1316,32 @@ pub struct CpuManager {
     mmio_bus: Arc<devices::Bus>,
     ioapic: Option<Arc<Mutex<ioapic::Ioapic>>>,
     vm_memory: Arc<ArcSwap<GuestMemoryMmap>>,
+    #[cfg(target_arch = "x86_64")]
     cpuid: CpuId,
     fd: Arc<VmFd>,
     vcpus_kill_signalled: Arc<AtomicBool>,
     vcpus_pause_signalled: Arc<AtomicBool>,
     reset_evt: EventFd,
+    exit_evt: EventFd,
+    internal_error: InternalErrorAction,
+    #[cfg(target_arch = "x86_64")]
+    irqchip_kind: IrqChipKind,
     vcpu_states: Vec<VcpuState>,
     selected_cpu: u8,
+    msr_list: Arc<Vec<u32>>,
+    msr_overrides: Arc<Vec<MsrConfig>>,
+    unhandled_pio: Arc<UnhandledPio>,
+    bus_tracer: Option<Arc<BusTracer>>,
+    // The vCPU threads report the errors they stop on through this channel.
+    failure_sender: Sender<VcpuFailure>,
+    failures: Receiver<VcpuFailure>,
+    restored_vcpus: BTreeMap<String, Box<Snapshot>>,
+    vcpu_affinity: BTreeMap<u8, Vec<usize>>,
+    #[cfg(target_arch = "aarch64")]
+    gic: Option<arch::aarch64::gic::GicV3>,
+    // The guest clock when the vCPUs were paused.
+    #[cfg(target_arch = "x86_64")]
+    paused_clock: Option<ClockState>,
 }
 
 const CPU_ENABLE_FLAG: usize = 0;
@@ -461,6 +1419,9 @@ struct VcpuState {
     removing: bool,
     handle: Option<thread::JoinHandle<()>>,
     kill: Arc<AtomicBool>,
+    nmi: Arc<AtomicBool>,
+    // Only locked by the vCPU thread while the vCPU runs.
+    vcpu: Option<Arc<Mutex<Vcpu>>>,
 }
 
 impl VcpuState {
@@ -492,18 +1453,38 @@ impl VcpuState {
 }
 
 impl CpuManager {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
-        boot_vcpus: u8,
-        max_vcpus: u8,
+        config: &CpusConfig,
         device_manager: &DeviceManager,
         guest_memory: Arc<ArcSwap<GuestMemoryMmap>>,
         fd: Arc<VmFd>,
-        cpuid: CpuId,
+        #[cfg(target_arch = "x86_64")] cpuid: CpuId,
         reset_evt: EventFd,
+        exit_evt: EventFd,
+        msr_list: Vec<u32>,
+        #[cfg(target_arch = "x86_64")] irqchip_kind: IrqChipKind,
+        numa_nodes: &Option<Vec<NumaConfig>>,
+        bus_tracer: Option<Arc<BusTracer>>,
     ) -> Result<Arc<Mutex<CpuManager>>> {
+        let boot_vcpus = config.boot_vcpus;
+        let max_vcpus = config.max_vcpus;
         let mut vcpu_states = Vec::with_capacity(usize::from(max_vcpus));
         vcpu_states.resize_with(usize::from(max_vcpus), VcpuState::default);
 
+        // The vCPUs of a guest node bound to a host node run on the CPUs of
+        // that host node.
+        let mut vcpu_affinity = BTreeMap::new();
+        for node in numa_nodes.iter().flatten() {
+            if let Some(host_node) = node.host_node {
+                let host_cpus = host_node_cpus(host_node)?;
+                for cpu in node.cpus.iter().flatten() {
+                    vcpu_affinity.insert(*cpu, host_cpus.clone());
+                }
+            }
+        }
+
+        let (failure_sender, failures) = channel();
         let cpu_manager = Arc::new(Mutex::new(CpuManager {
             boot_vcpus,
             max_vcpus,
@@ -511,13 +1492,30 @@ impl CpuManager {
             mmio_bus: device_manager.mmio_bus().clone(),
             ioapic: device_manager.ioapic().clone(),
             vm_memory: guest_memory,
+            #[cfg(target_arch = "x86_64")]
             cpuid,
             fd,
             vcpus_kill_signalled: Arc::new(AtomicBool::new(false)),
             vcpus_pause_signalled: Arc::new(AtomicBool::new(false)),
             vcpu_states,
             reset_evt,
+            exit_evt,
+            internal_error: config.internal_error,
+            #[cfg(target_arch = "x86_64")]
+            irqchip_kind,
             selected_cpu: 0,
+            msr_list: Arc::new(msr_list),
+            msr_overrides: Arc::new(config.msrs.clone().unwrap_or_default()),
+            unhandled_pio: Arc::new(UnhandledPio::default()),
+            bus_tracer,
+            failure_sender,
+            failures,
+            restored_vcpus: BTreeMap::new(),
+            vcpu_affinity,
+            #[cfg(target_arch = "aarch64")]
+            gic: None,
+            #[cfg(target_arch = "x86_64")]
+            paused_clock: None,
         }));
 
         device_manager
@@ -543,12 +1541,15 @@ impl CpuManager {
         &mut self,
         desired_vcpus: u8,
         entry_addr: Option<GuestAddress>,
+        hotplug: bool,
     ) -> Result<()> {
         if desired_vcpus > self.max_vcpus {
             return Err(Error::DesiredVCPUCountExceedsMax);
         }
 
         let creation_ts = std::time::Instant::now();
+        // Only the vCPUs activated here, and this thread, wait on the
+        // barrier, the ones already running never getting back to it.
         let vcpu_thread_barrier = Arc::new(Barrier::new(
             (desired_vcpus - self.present_vcpus() + 1) as usize,
         ));
@@ -560,49 +1561,145 @@ impl CpuManager {
                 None
             };
 
-            let mut vcpu = Vcpu::new(
+            #[cfg(target_arch = "x86_64")]
+            let vcpu = Arc::new(Mutex::new(Vcpu::new(
                 cpu_id,
                 &self.fd,
                 self.io_bus.clone().upgrade().unwrap(),
                 self.mmio_bus.clone(),
                 ioapic,
                 creation_ts,
-            )?;
+                self.msr_list.clone(),
+                self.msr_overrides.clone(),
+                self.unhandled_pio.clone(),
+                self.bus_tracer.clone(),
+            )?));
+            #[cfg(target_arch = "aarch64")]
+            let vcpu = Arc::new(Mutex::new(Vcpu::new(
+                cpu_id,
+                &self.fd,
+                self.io_bus.clone().upgrade().unwrap(),
+                self.mmio_bus.clone(),
+                ioapic,
+                creation_ts,
+                self.msr_list.clone(),
+                self.msr_overrides.clone(),
+                self.unhandled_pio.clone(),
+                self.bus_tracer.clone(),
+                self.exit_evt.try_clone().unwrap(),
+            )?));
+            #[cfg(target_arch = "aarch64")]
+            vcpu.lock()
+                .unwrap()
+                .configure(&self.fd, entry_addr, &self.vm_memory)?;
+            let vcpu_clone = vcpu.clone();
+            let restored = self.restored_vcpus.remove(&format!("vcpu{}", cpu_id));
+            let inserting = hotplug && restored.is_none();
 
             let vcpu_thread_barrier = vcpu_thread_barrier.clone();
 
             let reset_evt = self.reset_evt.try_clone().unwrap();
+            let exit_evt = self.exit_evt.try_clone().unwrap();
+            let internal_error = self.internal_error;
+            let failure_sender = self.failure_sender.clone();
+            let bus_tracer = self.bus_tracer.clone();
+            #[cfg(target_arch = "x86_64")]
+            let lapic = self.irqchip_kind != IrqChipKind::None;
             let vcpu_kill_signalled = self.vcpus_kill_signalled.clone();
             let vcpu_pause_signalled = self.vcpus_pause_signalled.clone();
 
             let vcpu_kill = self.vcpu_states[usize::from(cpu_id)].kill.clone();
+            let vcpu_nmi = self.vcpu_states[usize::from(cpu_id)].nmi.clone();
             let vm_memory = self.vm_memory.clone();
+            #[cfg(target_arch = "x86_64")]
             let cpuid = self.cpuid.clone();
+            let affinity = self.vcpu_affinity.get(&cpu_id).cloned();
 
             let handle = Some(
                 thread::Builder::new()
-                    .name(format!("vcpu{}", vcpu.id))
+                    .name(format!("vcpu{}", cpu_id))
                     .spawn(move || {
+                        if let Some(host_cpus) = affinity {
+                            set_thread_affinity(&host_cpus);
+                        }
+
                         extern "C" fn handle_signal(_: i32, _: *mut siginfo_t, _: *mut c_void) {}
                         // This uses an async signal safe handler to kill the vcpu handles.
                         register_signal_handler(SIGRTMIN(), handle_signal)
                             .expect("Failed to register vcpu signal handler");
 
-                        vcpu.configure(entry_addr, &vm_memory, cpuid)
-                            .expect("Failed to configure vCPU");
+                        {
+                            let mut vcpu = vcpu_clone.lock().unwrap();
+                            #[cfg(target_arch = "x86_64")]
+                            vcpu.configure(entry_addr, &vm_memory, cpuid, lapic)
+                                .expect("Failed to configure vCPU");
+                            if let Some(snapshot) = restored {
+                                vcpu.restore(*snapshot).expect("Failed to restore vCPU");
+                            }
+                        }
 
                         // Block until all CPUs are ready.
                         vcpu_thread_barrier.wait();
 
+                        // A vCPU hot added while the VM is paused waits for
+                        // it to be resumed before running.
+                        while vcpu_pause_signalled.load(Ordering::SeqCst) {
+                            thread::park();
+                        }
+
                         loop {
+                            // An NMI has been requested for this vCPU, inject
+                            // it before going back to the guest.
+                            if vcpu_nmi.swap(false, Ordering::SeqCst) {
+                                if let Err(e) = vcpu_clone.lock().unwrap().inject_nmi() {
+                                    error!("Failed to inject NMI: {:?}", e);
+                                }
+                            }
+
                             // vcpu.run() returns false on a KVM_EXIT_SHUTDOWN (triple-fault) so trigger a reset
-                            match vcpu.run() {
+                            // The lock is released before parking, so that
+                            // the vCPU state can be read while paused.
+                            let run = vcpu_clone.lock().unwrap().run();
+                            match run {
+                                Err(Error::VcpuInternalError(_)) => {
+                                    event!("vcpu", "internal-error", "id" => cpu_id);
+                                    if let Some(tracer) = &bus_tracer {
+                                        log_bus_trace(tracer);
+                                    }
+                                    match internal_error {
+                                        InternalErrorAction::Abort => {
+                                            error!("Aborting on vCPU internal error");
+                                            std::process::abort();
+                                        }
+                                        InternalErrorAction::Reset => {
+                                            reset_evt.write(1).unwrap();
+                                        }
+                                        InternalErrorAction::DumpAndExit => {
+                                            vcpu_clone.lock().unwrap().dump(&vm_memory.load());
+                                            exit_evt.write(1).unwrap();
+                                        }
+                                    }
+                                    break;
+                                }
                                 Err(e) => {
-                                    error!("VCPU generated error: {:?}", e);
+                                    error!("vCPU {} failed: {}", cpu_id, e);
+                                    if let Some(tracer) = &bus_tracer {
+                                        log_bus_trace(tracer);
+                                    }
+                                    event!("vcpu", "error", "id" => cpu_id, "error" => e);
+                                    // The VM cannot run without this vCPU,
+                                    // it is shut down. The receiver only goes
+                                    // away with the CPU manager.
+                                    let _ = failure_sender.send(VcpuFailure {
+                                        id: cpu_id,
+                                        error: e,
+                                    });
+                                    exit_evt.write(1).unwrap();
                                     break;
                                 }
                                 Ok(true) => {}
                                 Ok(false) => {
+                                    event!("vcpu", "triple-fault", "id" => cpu_id);
                                     reset_evt.write(1).unwrap();
                                     break;
                                 }
@@ -630,10 +1727,23 @@ impl CpuManager {
                     .map_err(Error::VcpuSpawn)?,
             );
 
-            // On hot plug calls into this function entry_addr is None. It is for
-            // those hotplug CPU additions that we need to set the inserting flag.
+            // It is for the hotplug CPU additions that we need to set the
+            // inserting flag.
             self.vcpu_states[usize::from(cpu_id)].handle = handle;
-            self.vcpu_states[usize::from(cpu_id)].inserting = entry_addr.is_none();
+            self.vcpu_states[usize::from(cpu_id)].inserting = inserting;
+            self.vcpu_states[usize::from(cpu_id)].vcpu = Some(vcpu);
+        }
+
+        // The GIC can only be created once all the vCPUs are, and before
+        // any of them runs.
+        #[cfg(target_arch = "aarch64")]
+        {
+            if self.gic.is_none() {
+                self.gic = Some(
+                    arch::aarch64::gic::create_gic(&self.fd, u64::from(desired_vcpus), false)
+                        .map_err(Error::CreateGic)?,
+                );
+            }
         }
 
         // Unblock all CPU threads.
@@ -655,17 +1765,29 @@ impl CpuManager {
         state.signal_thread();
         state.join_thread()?;
         state.handle = None;
+        state.vcpu = None;
         Ok(())
     }
 
     // Starts all the vCPUs that the VM is booting with. Blocks until all vCPUs are running.
-    pub fn start_boot_vcpus(&mut self, entry_addr: GuestAddress) -> Result<()> {
-        self.activate_vcpus(self.boot_vcpus(), Some(entry_addr))
+    // Without an entry address, they start from the reset vector.
+    pub fn start_boot_vcpus(&mut self, entry_addr: Option<GuestAddress>) -> Result<()> {
+        self.activate_vcpus(self.boot_vcpus(), entry_addr, false)
+    }
+
+    /// Starts the vCPUs from the snapshot given to `restore()`, instead of
+    /// booting them.
+    pub fn start_restored_vcpus(&mut self) -> Result<()> {
+        self.activate_vcpus(self.restored_vcpus.len() as u8, None, false)
+    }
+
+    pub fn has_restored_vcpus(&self) -> bool {
+        !self.restored_vcpus.is_empty()
     }
 
     pub fn resize(&mut self, desired_vcpus: u8) -> Result<bool> {
         match desired_vcpus.cmp(&self.present_vcpus()) {
-            cmp::Ordering::Greater => self.activate_vcpus(desired_vcpus, None).and(Ok(true)),
+            cmp::Ordering::Greater => self.activate_vcpus(desired_vcpus, None, true).and(Ok(true)),
             cmp::Ordering::Less => self.mark_vcpus_for_removal(desired_vcpus).and(Ok(true)),
             _ => Ok(false),
         }
@@ -690,6 +1812,45 @@ impl CpuManager {
         Ok(())
     }
 
+    /// Injects an NMI into the given vCPU, or into all running vCPUs if
+    /// no vCPU is specified.
+    pub fn nmi(&self, cpu_id: Option<u8>) -> Result<()> {
+        let states: Vec<&VcpuState> = match cpu_id {
+            Some(cpu_id) => match self.vcpu_states.get(usize::from(cpu_id)) {
+                Some(state) if state.active() => vec![state],
+                _ => return Err(Error::VcpuNotPresent(cpu_id)),
+            },
+            None => self.vcpu_states.iter().filter(|s| s.active()).collect(),
+        };
+
+        // The vCPU thread injects the NMI itself, the signal only kicks it
+        // out of KVM_RUN so that it can notice the request.
+        for state in states {
+            state.nmi.store(true, Ordering::SeqCst);
+            state.signal_thread();
+        }
+
+        Ok(())
+    }
+
+    /// Returns the register dump of every running vCPU. The vCPUs must be
+    /// paused, otherwise this waits for each one of them to exit.
+    #[cfg(target_arch = "x86_64")]
+    pub fn dump_vcpus(&self) -> Result<Vec<String>> {
+        self.vcpu_states
+            .iter()
+            .filter_map(|s| s.vcpu.as_ref())
+            // Taking the lock waits for the vCPU to be out of KVM_RUN.
+            .map(|vcpu| vcpu.lock().unwrap().dump_state())
+            .collect()
+    }
+
+    /// Returns the vCPUs which stopped on an error since the last call, and
+    /// the errors.
+    pub fn vcpu_failures(&self) -> Vec<VcpuFailure> {
+        self.failures.try_iter().collect()
+    }
+
     pub fn boot_vcpus(&self) -> u8 {
         self.boot_vcpus
     }
@@ -718,11 +1879,7 @@ impl CpuManager {
                 length: 8,
                 processor_id: cpu,
                 apic_id: cpu,
-                flags: if cpu < self.boot_vcpus {
-                    1 << MADT_CPU_ENABLE_FLAG
-                } else {
-                    0
-                },
+                flags: madt_lapic_flags(cpu, self.boot_vcpus),
             };
             madt.append(lapic);
         }
@@ -757,6 +1914,22 @@ struct CPU {
 #[cfg(feature = "acpi")]
 const MADT_CPU_ENABLE_FLAG: usize = 0;
 
+// Since ACPI 6.3, i.e. MADT revision 5, the guest ignores the disabled CPUs
+// which do not have this flag, instead of expecting them to be hot added.
+#[cfg(feature = "acpi")]
+const MADT_CPU_ONLINE_CAPABLE_FLAG: usize = 1;
+
+// The boot vCPUs are enabled, the other ones can be hot added up to the
+// maximum.
+#[cfg(feature = "acpi")]
+fn madt_lapic_flags(cpu: u8, boot_vcpus: u8) -> u32 {
+    if cpu < boot_vcpus {
+        1 << MADT_CPU_ENABLE_FLAG
+    } else {
+        1 << MADT_CPU_ONLINE_CAPABLE_FLAG
+    }
+}
+
 #[cfg(feature = "acpi")]
 impl Aml for CPU {
     fn to_aml_bytes(&self) -> Vec<u8> {
@@ -1048,10 +2221,30 @@ impl Pausable for CpuManager {
             state.signal_thread();
         }
 
+        // The guest clock keeps running on the host, it is set back to the
+        // value it has now once the vCPUs resume.
+        #[cfg(target_arch = "x86_64")]
+        {
+            self.paused_clock = Some(ClockState::save(&self.fd).map_err(|e| {
+                MigratableError::Pause(anyhow!("Could not save the guest clock: {}", e))
+            })?);
+        }
+
         Ok(())
     }
 
     fn resume(&mut self) -> std::result::Result<(), MigratableError> {
+        // Advance the guest clock by the time the VM was paused, before the
+        // vCPUs get to run.
+        #[cfg(target_arch = "x86_64")]
+        {
+            if let Some(clock) = self.paused_clock.take() {
+                clock.restore(&self.fd, HostClock::Monotonic).map_err(|e| {
+                    MigratableError::Resume(anyhow!("Could not set the guest clock: {}", e))
+                })?;
+            }
+        }
+
         // Toggle the vCPUs pause boolean
         self.vcpus_pause_signalled.store(false, Ordering::SeqCst);
 
@@ -1066,5 +2259,356 @@ impl Pausable for CpuManager {
     }
 }
 
-impl Snapshotable for CpuManager {}
+#[cfg(target_arch = "x86_64")]
+impl Snapshotable for Vcpu {
+    fn id(&self) -> String {
+        format!("vcpu{}", self.id)
+    }
+
+    fn snapshot(&self) -> result::Result<Snapshot, MigratableError> {
+        let mut snapshot = Snapshot::new(&self.id());
+        self.state()
+            .map_err(|e| MigratableError::Snapshot(anyhow!("{:?}", e)))?
+            .add_to(&mut snapshot);
+
+        Ok(snapshot)
+    }
+
+    fn restore(&mut self, snapshot: Snapshot) -> result::Result<(), MigratableError> {
+        let state = VcpuKvmState::from_snapshot(&snapshot)?;
+        self.set_state(&state)
+            .map_err(|e| MigratableError::Restore(anyhow!("{:?}", e)))
+    }
+}
+
+#[cfg(target_arch = "aarch64")]
+impl Snapshotable for Vcpu {
+    fn id(&self) -> String {
+        format!("vcpu{}", self.id)
+    }
+
+    fn snapshot(&self) -> result::Result<Snapshot, MigratableError> {
+        Err(MigratableError::Snapshot(anyhow!(
+            "vCPU snapshot is not supported on aarch64"
+        )))
+    }
+
+    fn restore(&mut self, _snapshot: Snapshot) -> result::Result<(), MigratableError> {
+        Err(MigratableError::Restore(anyhow!(
+            "vCPU restore is not supported on aarch64"
+        )))
+    }
+}
+
+const CPU_MANAGER_SNAPSHOT_ID: &str = "cpu-manager";
+#[cfg(target_arch = "x86_64")]
+const CPU_MANAGER_SNAPSHOT_CLOCK: &str = "clock";
+
+impl Snapshotable for CpuManager {
+    fn id(&self) -> String {
+        CPU_MANAGER_SNAPSHOT_ID.to_string()
+    }
+
+    fn snapshot(&self) -> result::Result<Snapshot, MigratableError> {
+        let mut snapshot = Snapshot::new(&self.id());
+        for vcpu in self.vcpu_states.iter().filter_map(|s| s.vcpu.as_ref()) {
+            // Taking the lock waits for the vCPU to be out of KVM_RUN.
+            snapshot.add_snapshot(vcpu.lock().unwrap().snapshot()?);
+        }
+
+        #[cfg(target_arch = "x86_64")]
+        {
+            let clock = match self.paused_clock {
+                Some(clock) => clock,
+                None => ClockState::save(&self.fd).map_err(|e| {
+                    MigratableError::Snapshot(anyhow!("Could not save the guest clock: {}", e))
+                })?,
+            };
+            snapshot.add_data_section(SnapshotDataSection {
+                id: CPU_MANAGER_SNAPSHOT_CLOCK.to_string(),
+                snapshot: serde_json::to_vec(&clock)
+                    .map_err(|e| MigratableError::Snapshot(e.into()))?,
+            });
+        }
+
+        Ok(snapshot)
+    }
+
+    fn restore(&mut self, snapshot: Snapshot) -> result::Result<(), MigratableError> {
+        if self.present_vcpus() != 0 {
+            return Err(MigratableError::Restore(anyhow!("vCPUs already started")));
+        }
+
+        let vcpus = snapshot.snapshots.len();
+        if vcpus == 0 || vcpus > usize::from(self.max_vcpus) {
+            return Err(MigratableError::Restore(anyhow!(
+                "Invalid number of vCPUs: {}",
+                vcpus
+            )));
+        }
+
+        // The vCPUs are started in order, from the first one.
+        for cpu_id in 0..vcpus {
+            if !snapshot.snapshots.contains_key(&format!("vcpu{}", cpu_id)) {
+                return Err(MigratableError::Restore(anyhow!(
+                    "Missing vCPU {} snapshot",
+                    cpu_id
+                )));
+            }
+        }
+
+        // The guest clock is advanced by the wall-clock time elapsed since
+        // the snapshot, as the VM may be restored on another host.
+        #[cfg(target_arch = "x86_64")]
+        {
+            if let Some(section) = snapshot.snapshot_data.get(CPU_MANAGER_SNAPSHOT_CLOCK) {
+                let clock: ClockState = serde_json::from_slice(&section.snapshot)
+                    .map_err(|e| MigratableError::Restore(e.into()))?;
+                clock.restore(&self.fd, HostClock::Realtime).map_err(|e| {
+                    MigratableError::Restore(anyhow!("Could not set the guest clock: {}", e))
+                })?;
+            }
+        }
+
+        self.restored_vcpus = snapshot.snapshots;
+        Ok(())
+    }
+}
 impl Migratable for CpuManager {}
+
+#[cfg(all(test, target_arch = "x86_64"))]
+mod tests {
+    use super::*;
+    use crate::config::{TraceBusConfig, TraceBusRange};
+    use vm_memory::{Address, GuestMemory, GuestMemoryRegion};
+
+    #[test]
+    fn test_format_vcpu_state() {
+        let mut regs = kvm_regs::default();
+        regs.rip = 0x10_0000;
+        regs.r15 = 0xf;
+        let mut sregs = kvm_sregs::default();
+        sregs.cs.selector = 0x10;
+        sregs.cr0 = 0x8000_0011;
+        sregs.cr3 = 0x9000;
+
+        let dump = format_vcpu_state(1, &regs, &sregs);
+        let lines: Vec<&str> = dump.lines().collect();
+        assert_eq!(lines[0], "vCPU 1");
+        assert!(lines[1].starts_with("rip=0x0000000000100000 "));
+        assert!(lines[5].ends_with("r15=0x000000000000000f"));
+        assert!(lines[6].starts_with("cs=0x0010 "));
+        assert!(dump.contains("cr0=0x0000000080000011"));
+        assert!(dump.contains("cr3=0x0000000000009000"));
+    }
+
+    struct DummyDevice;
+    impl BusDevice for DummyDevice {
+        fn read(&mut self, _base: u64, offset: u64, data: &mut [u8]) {
+            data[0] = offset as u8;
+        }
+    }
+
+    #[test]
+    fn test_unhandled_pio() {
+        let io_bus = devices::Bus::new();
+        io_bus
+            .insert(Arc::new(Mutex::new(DummyDevice)), 0x60, 0x10)
+            .unwrap();
+        let unhandled_pio = UnhandledPio::default();
+
+        // Handled ports reach their device.
+        let mut data = [0u8; 2];
+        unhandled_pio.read(&io_bus, 0x64, &mut data);
+        assert_eq!(data, [4, 0]);
+        unhandled_pio.write(&io_bus, 0x64, &[1]);
+        assert_eq!(unhandled_pio.accesses(0x64), 0);
+
+        // Other ports float high, whatever was in the buffer.
+        let mut data = [0x12u8; 4];
+        unhandled_pio.read(&io_bus, 0x2e, &mut data);
+        assert_eq!(data, [0xff; 4]);
+        unhandled_pio.write(&io_bus, 0x2e, &[0x55]);
+        assert_eq!(unhandled_pio.accesses(0x2e), 2);
+
+        // The accesses are counted per port, far beyond the logged ones.
+        for _ in 0..2 * UNHANDLED_PIO_LOG_LIMIT {
+            unhandled_pio.read(&io_bus, 0x92, &mut data[..1]);
+        }
+        assert_eq!(unhandled_pio.accesses(0x92), 2 * UNHANDLED_PIO_LOG_LIMIT);
+        assert_eq!(unhandled_pio.accesses(0x2e), 2);
+    }
+
+    #[test]
+    fn test_vcpu_run_efault() {
+        let kvm = Kvm::new().unwrap();
+        let vm_fd = Arc::new(kvm.create_vm().unwrap());
+
+        // Guest memory whose host mapping is gone by the time the vCPU
+        // fetches its first instruction from it.
+        let size = 0x1000;
+        // Safe because we check the result, and only unmap what we mapped.
+        let addr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                size,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            )
+        };
+        assert_ne!(addr, libc::MAP_FAILED);
+        let region = kvm_bindings::kvm_userspace_memory_region {
+            slot: 0,
+            guest_phys_addr: 0x1000,
+            memory_size: size as u64,
+            userspace_addr: addr as u64,
+            flags: 0,
+        };
+        // Safe because KVM checks the host mapping on each guest access.
+        unsafe { vm_fd.set_user_memory_region(region).unwrap() };
+        unsafe { libc::munmap(addr, size) };
+
+        let vcpu = Vcpu::new(
+            0,
+            &vm_fd,
+            Arc::new(devices::Bus::new()),
+            Arc::new(devices::Bus::new()),
+            None,
+            std::time::Instant::now(),
+            Arc::new(Vec::new()),
+            Arc::new(Vec::new()),
+            Arc::new(UnhandledPio::default()),
+            None,
+        )
+        .unwrap();
+        let mut sregs = vcpu.fd.get_sregs().unwrap();
+        sregs.cs.base = 0;
+        sregs.cs.selector = 0;
+        vcpu.fd.set_sregs(&sregs).unwrap();
+        let mut regs = vcpu.fd.get_regs().unwrap();
+        regs.rip = 0x1000;
+        regs.rflags = 2;
+        vcpu.fd.set_regs(&regs).unwrap();
+
+        let e = vcpu.run().unwrap_err();
+        match e {
+            Error::VcpuRun(ref kvm_error) => assert_eq!(kvm_error.errno(), libc::EFAULT),
+            _ => panic!("unexpected error: {:?}", e),
+        }
+        assert_eq!(
+            e.to_string(),
+            "cannot run the vCPU: the guest accessed memory that has no host mapping"
+        );
+    }
+
+    #[test]
+    fn test_bus_trace() {
+        let kvm = Kvm::new().unwrap();
+        let vm_fd = Arc::new(kvm.create_vm().unwrap());
+        let load_addr = GuestAddress(0x1000);
+        let mem = GuestMemoryMmap::from_ranges(&[(load_addr, 0x1000)]).unwrap();
+        mem.with_regions(|index, region| {
+            let mem_region = kvm_bindings::kvm_userspace_memory_region {
+                slot: index as u32,
+                guest_phys_addr: region.start_addr().raw_value(),
+                memory_size: region.len() as u64,
+                userspace_addr: region.as_ptr() as u64,
+                flags: 0,
+            };
+            // Safe because the guest regions are guaranteed not to overlap.
+            unsafe { vm_fd.set_user_memory_region(mem_region) }
+        })
+        .unwrap();
+        mem.write_slice(&crate::vm::TEST_VM_CODE, load_addr)
+            .unwrap();
+
+        let tracer = Arc::new(
+            BusTracer::new(&TraceBusConfig {
+                entries: 16,
+                file: None,
+                pio: Some(vec![TraceBusRange {
+                    first: 0x3f8,
+                    last: 0x3ff,
+                }]),
+                mmio: None,
+            })
+            .unwrap(),
+        );
+        let vcpu = Vcpu::new(
+            0,
+            &vm_fd,
+            Arc::new(devices::Bus::new()),
+            Arc::new(devices::Bus::new()),
+            None,
+            std::time::Instant::now(),
+            Arc::new(Vec::new()),
+            Arc::new(Vec::new()),
+            Arc::new(UnhandledPio::default()),
+            Some(tracer.clone()),
+        )
+        .unwrap();
+        let mut sregs = vcpu.fd.get_sregs().unwrap();
+        sregs.cs.base = 0;
+        sregs.cs.selector = 0;
+        vcpu.fd.set_sregs(&sregs).unwrap();
+        let mut regs = vcpu.fd.get_regs().unwrap();
+        regs.rip = load_addr.raw_value();
+        regs.rax = 2;
+        regs.rbx = 3;
+        regs.rflags = 2;
+        vcpu.fd.set_regs(&regs).unwrap();
+
+        // Both writes are handled, then the vCPU stops on the hlt, which
+        // it has no handler for.
+        while vcpu.run().is_ok() {}
+
+        let writes: Vec<(BusKind, bool, u64, u8, u64)> = tracer
+            .recent()
+            .iter()
+            .map(|a| (a.bus, a.write, a.addr, a.size, a.value))
+            .collect();
+        assert_eq!(
+            writes,
+            vec![
+                (BusKind::Pio, true, 0x3f8, 1, u64::from(b'5')),
+                (BusKind::Pio, true, 0x3f8, 1, u64::from(b'\n')),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_debug_event_from_dr6() {
+        let debugreg = [0x1000, 0x2000, 0x3000, 0x4000, 0, 0, 0, 0x55];
+
+        assert_eq!(
+            DebugEvent::from_dr6(0xffff_0ff2, 0x2000, &debugreg),
+            DebugEvent::HwBreakpoint {
+                index: 1,
+                address: 0x2000
+            }
+        );
+        assert_eq!(
+            DebugEvent::from_dr6(0xffff_4ff0, 0x1234, &debugreg),
+            DebugEvent::SingleStep { pc: 0x1234 }
+        );
+        assert_eq!(
+            DebugEvent::from_dr6(0xffff_0ff0, 0x1234, &debugreg),
+            DebugEvent::Other {
+                pc: 0x1234,
+                dr6: 0xffff_0ff0
+            }
+        );
+    }
+
+    #[cfg(feature = "acpi")]
+    #[test]
+    fn test_madt_lapic_flags() {
+        // Boot vCPUs are enabled, the others are left for hot add.
+        assert_eq!(madt_lapic_flags(0, 2), 0x1);
+        assert_eq!(madt_lapic_flags(1, 2), 0x1);
+        assert_eq!(madt_lapic_flags(2, 2), 0x2);
+        assert_eq!(madt_lapic_flags(7, 2), 0x2);
+    }
+}
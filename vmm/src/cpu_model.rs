@@ -0,0 +1,630 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! CPU models.
+//!
+//! A named CPU model exposes the same vendor, signature, brand string and
+//! features to the guest whatever the host is, as long as the host supports
+//! all the features of the model, so that the guest can be migrated between
+//! hosts. Short of that, the host model exposes the host CPU without the
+//! features that can't be migrated, to hosts with the same CPU.
+
+use crate::config::DEFAULT_CPU_MODEL;
+use arch::x86_64::CpuidReg::{self, EAX, EBX, ECX, EDX};
+use kvm_bindings::{kvm_cpuid_entry2, CpuId};
+use std::fmt;
+
+const BRAND_STRING_LEAVES: [u32; 3] = [0x8000_0002, 0x8000_0003, 0x8000_0004];
+
+#[derive(Debug)]
+pub enum Error {
+    /// The CPU model is not known.
+    UnknownModel(String),
+    /// The host CPU lacks a feature of the CPU model.
+    MissingFeature {
+        model: &'static str,
+        feature: &'static str,
+    },
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::UnknownModel(name) => write!(f, "unknown CPU model {}", name),
+            Error::MissingFeature { model, feature } => write!(
+                f,
+                "the host CPU doesn't support the {} feature of the {} CPU model",
+                feature, model
+            ),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// A CPUID feature bit.
+pub struct CpuFeature {
+    name: &'static str,
+    function: u32,
+    index: u32,
+    reg: CpuidReg,
+    bit: u8,
+}
+
+const fn feature(
+    name: &'static str,
+    function: u32,
+    index: u32,
+    reg: CpuidReg,
+    bit: u8,
+) -> CpuFeature {
+    CpuFeature {
+        name,
+        function,
+        index,
+        reg,
+        bit,
+    }
+}
+
+// The registers defined by the models, along with the bits taken from the
+// host anyway. Those are either computed by KVM at runtime (OSXSAVE, OSPKE),
+// describing the topology (HTT), or patched later on (TSC deadline timer,
+// hypervisor).
+const MODEL_REGS: [(u32, u32, CpuidReg, u32); 7] = [
+    (1, 0, ECX, 1 << 24 | 1 << 27 | 1 << 31),
+    (1, 0, EDX, 1 << 28),
+    (7, 0, EBX, 0),
+    (7, 0, ECX, 1 << 4),
+    (0xd, 1, EAX, 0),
+    (0x8000_0001, 0, ECX, 0),
+    (0x8000_0001, 0, EDX, 0),
+];
+
+const FPU: CpuFeature = feature("fpu", 1, 0, EDX, 0);
+const VME: CpuFeature = feature("vme", 1, 0, EDX, 1);
+const DE: CpuFeature = feature("de", 1, 0, EDX, 2);
+const PSE: CpuFeature = feature("pse", 1, 0, EDX, 3);
+const TSC: CpuFeature = feature("tsc", 1, 0, EDX, 4);
+const MSR: CpuFeature = feature("msr", 1, 0, EDX, 5);
+const PAE: CpuFeature = feature("pae", 1, 0, EDX, 6);
+const MCE: CpuFeature = feature("mce", 1, 0, EDX, 7);
+const CX8: CpuFeature = feature("cx8", 1, 0, EDX, 8);
+const APIC: CpuFeature = feature("apic", 1, 0, EDX, 9);
+const SEP: CpuFeature = feature("sep", 1, 0, EDX, 11);
+const MTRR: CpuFeature = feature("mtrr", 1, 0, EDX, 12);
+const PGE: CpuFeature = feature("pge", 1, 0, EDX, 13);
+const MCA: CpuFeature = feature("mca", 1, 0, EDX, 14);
+const CMOV: CpuFeature = feature("cmov", 1, 0, EDX, 15);
+const PAT: CpuFeature = feature("pat", 1, 0, EDX, 16);
+const PSE36: CpuFeature = feature("pse36", 1, 0, EDX, 17);
+const CLFLUSH: CpuFeature = feature("clflush", 1, 0, EDX, 19);
+const MMX: CpuFeature = feature("mmx", 1, 0, EDX, 23);
+const FXSR: CpuFeature = feature("fxsr", 1, 0, EDX, 24);
+const SSE: CpuFeature = feature("sse", 1, 0, EDX, 25);
+const SSE2: CpuFeature = feature("sse2", 1, 0, EDX, 26);
+
+const SSE3: CpuFeature = feature("sse3", 1, 0, ECX, 0);
+const PCLMULQDQ: CpuFeature = feature("pclmulqdq", 1, 0, ECX, 1);
+const MONITOR: CpuFeature = feature("monitor", 1, 0, ECX, 3);
+const SSSE3: CpuFeature = feature("ssse3", 1, 0, ECX, 9);
+const FMA: CpuFeature = feature("fma", 1, 0, ECX, 12);
+const CX16: CpuFeature = feature("cx16", 1, 0, ECX, 13);
+const PCID: CpuFeature = feature("pcid", 1, 0, ECX, 17);
+const SSE4_1: CpuFeature = feature("sse4.1", 1, 0, ECX, 19);
+const SSE4_2: CpuFeature = feature("sse4.2", 1, 0, ECX, 20);
+const X2APIC: CpuFeature = feature("x2apic", 1, 0, ECX, 21);
+const MOVBE: CpuFeature = feature("movbe", 1, 0, ECX, 22);
+const POPCNT: CpuFeature = feature("popcnt", 1, 0, ECX, 23);
+const AES: CpuFeature = feature("aes", 1, 0, ECX, 25);
+const XSAVE: CpuFeature = feature("xsave", 1, 0, ECX, 26);
+const AVX: CpuFeature = feature("avx", 1, 0, ECX, 28);
+const F16C: CpuFeature = feature("f16c", 1, 0, ECX, 29);
+const RDRAND: CpuFeature = feature("rdrand", 1, 0, ECX, 30);
+
+const FSGSBASE: CpuFeature = feature("fsgsbase", 7, 0, EBX, 0);
+const BMI1: CpuFeature = feature("bmi1", 7, 0, EBX, 3);
+const AVX2: CpuFeature = feature("avx2", 7, 0, EBX, 5);
+const SMEP: CpuFeature = feature("smep", 7, 0, EBX, 7);
+const BMI2: CpuFeature = feature("bmi2", 7, 0, EBX, 8);
+const ERMS: CpuFeature = feature("erms", 7, 0, EBX, 9);
+const INVPCID: CpuFeature = feature("invpcid", 7, 0, EBX, 10);
+const AVX512F: CpuFeature = feature("avx512f", 7, 0, EBX, 16);
+const AVX512DQ: CpuFeature = feature("avx512dq", 7, 0, EBX, 17);
+const RDSEED: CpuFeature = feature("rdseed", 7, 0, EBX, 18);
+const ADX: CpuFeature = feature("adx", 7, 0, EBX, 19);
+const SMAP: CpuFeature = feature("smap", 7, 0, EBX, 20);
+const CLFLUSHOPT: CpuFeature = feature("clflushopt", 7, 0, EBX, 23);
+const CLWB: CpuFeature = feature("clwb", 7, 0, EBX, 24);
+const INTEL_PT: CpuFeature = feature("intel-pt", 7, 0, EBX, 25);
+const AVX512CD: CpuFeature = feature("avx512cd", 7, 0, EBX, 28);
+const SHA: CpuFeature = feature("sha", 7, 0, EBX, 29);
+const AVX512BW: CpuFeature = feature("avx512bw", 7, 0, EBX, 30);
+const AVX512VL: CpuFeature = feature("avx512vl", 7, 0, EBX, 31);
+const UMIP: CpuFeature = feature("umip", 7, 0, ECX, 2);
+const PKU: CpuFeature = feature("pku", 7, 0, ECX, 3);
+const WAITPKG: CpuFeature = feature("waitpkg", 7, 0, ECX, 5);
+
+const XSAVEOPT: CpuFeature = feature("xsaveopt", 0xd, 1, EAX, 0);
+const XSAVEC: CpuFeature = feature("xsavec", 0xd, 1, EAX, 1);
+
+const LAHF_LM: CpuFeature = feature("lahf_lm", 0x8000_0001, 0, ECX, 0);
+const ABM: CpuFeature = feature("abm", 0x8000_0001, 0, ECX, 5);
+const SSE4A: CpuFeature = feature("sse4a", 0x8000_0001, 0, ECX, 6);
+const MISALIGNSSE: CpuFeature = feature("misalignsse", 0x8000_0001, 0, ECX, 7);
+const PREFETCHW: CpuFeature = feature("3dnowprefetch", 0x8000_0001, 0, ECX, 8);
+const SYSCALL: CpuFeature = feature("syscall", 0x8000_0001, 0, EDX, 11);
+const NX: CpuFeature = feature("nx", 0x8000_0001, 0, EDX, 20);
+const MMXEXT: CpuFeature = feature("mmxext", 0x8000_0001, 0, EDX, 22);
+const FXSR_OPT: CpuFeature = feature("fxsr_opt", 0x8000_0001, 0, EDX, 25);
+const PDPE1GB: CpuFeature = feature("pdpe1gb", 0x8000_0001, 0, EDX, 26);
+const RDTSCP: CpuFeature = feature("rdtscp", 0x8000_0001, 0, EDX, 27);
+const LM: CpuFeature = feature("lm", 0x8000_0001, 0, EDX, 29);
+
+const INVTSC: CpuFeature = feature("invtsc", 0x8000_0007, 0, EDX, 8);
+
+// Host features the guest can't keep using once migrated, either because
+// their state isn't migrated (processor trace), or because they depend on
+// the exact host CPU (MWAIT power states, invariant TSC rate).
+const UNMIGRATABLE_FEATURES: &[CpuFeature] = &[MONITOR, INTEL_PT, WAITPKG, INVTSC];
+
+// Features of every x86_64 model.
+const X86_64_FEATURES: &[CpuFeature] = &[
+    FPU, VME, DE, PSE, TSC, MSR, PAE, MCE, CX8, APIC, SEP, MTRR, PGE, MCA, CMOV, PAT, PSE36,
+    CLFLUSH, MMX, FXSR, SSE, SSE2, SSE3, CX16, POPCNT, LAHF_LM, SYSCALL, NX, LM,
+];
+
+const SANDYBRIDGE_FEATURES: &[CpuFeature] = &[
+    PCLMULQDQ, SSSE3, SSE4_1, SSE4_2, X2APIC, AES, XSAVE, AVX, XSAVEOPT, RDTSCP,
+];
+
+// Without TSX, disabled by the microcode of most Haswell CPUs.
+const HASWELL_FEATURES: &[CpuFeature] = &[
+    FMA, PCID, MOVBE, F16C, RDRAND, FSGSBASE, BMI1, AVX2, SMEP, BMI2, ERMS, INVPCID, ABM,
+];
+
+const SKYLAKE_SERVER_FEATURES: &[CpuFeature] = &[
+    AVX512F, AVX512DQ, RDSEED, ADX, SMAP, CLFLUSHOPT, CLWB, AVX512CD, AVX512BW, AVX512VL, PKU,
+    XSAVEC, PREFETCHW, PDPE1GB,
+];
+
+const EPYC_FEATURES: &[CpuFeature] = &[
+    PCLMULQDQ,
+    SSSE3,
+    FMA,
+    SSE4_1,
+    SSE4_2,
+    X2APIC,
+    MOVBE,
+    AES,
+    XSAVE,
+    AVX,
+    F16C,
+    RDRAND,
+    FSGSBASE,
+    BMI1,
+    AVX2,
+    SMEP,
+    BMI2,
+    RDSEED,
+    ADX,
+    SMAP,
+    CLFLUSHOPT,
+    SHA,
+    XSAVEOPT,
+    XSAVEC,
+    ABM,
+    SSE4A,
+    MISALIGNSSE,
+    PREFETCHW,
+    MMXEXT,
+    FXSR_OPT,
+    PDPE1GB,
+    RDTSCP,
+];
+
+const EPYC_ROME_FEATURES: &[CpuFeature] = &[CLWB, UMIP];
+
+/// The name of the host model.
+pub const HOST_MODEL: &str = "host-model";
+
+/// The CPU exposed to the guest.
+pub enum CpuModel {
+    /// The host CPU, with all its features.
+    HostPassthrough,
+    /// The host CPU, without the features that can't be migrated.
+    HostModel,
+    /// A CPU model defined whatever the host.
+    Named(&'static NamedCpuModel),
+}
+
+/// A named CPU model.
+pub struct NamedCpuModel {
+    pub name: &'static str,
+    vendor: &'static [u8; 12],
+    family: u32,
+    model: u32,
+    stepping: u32,
+    brand: &'static str,
+    features: &'static [&'static [CpuFeature]],
+}
+
+const CPU_MODELS: &[NamedCpuModel] = &[
+    NamedCpuModel {
+        name: "SandyBridge",
+        vendor: b"GenuineIntel",
+        family: 6,
+        model: 42,
+        stepping: 1,
+        brand: "Intel Xeon E312xx (Sandy Bridge)",
+        features: &[X86_64_FEATURES, SANDYBRIDGE_FEATURES],
+    },
+    NamedCpuModel {
+        name: "Haswell",
+        vendor: b"GenuineIntel",
+        family: 6,
+        model: 60,
+        stepping: 4,
+        brand: "Intel Core Processor (Haswell, no TSX)",
+        features: &[X86_64_FEATURES, SANDYBRIDGE_FEATURES, HASWELL_FEATURES],
+    },
+    NamedCpuModel {
+        name: "Skylake-Server",
+        vendor: b"GenuineIntel",
+        family: 6,
+        model: 85,
+        stepping: 4,
+        brand: "Intel Xeon Processor (Skylake)",
+        features: &[
+            X86_64_FEATURES,
+            SANDYBRIDGE_FEATURES,
+            HASWELL_FEATURES,
+            SKYLAKE_SERVER_FEATURES,
+        ],
+    },
+    NamedCpuModel {
+        name: "EPYC",
+        vendor: b"AuthenticAMD",
+        family: 23,
+        model: 1,
+        stepping: 2,
+        brand: "AMD EPYC Processor",
+        features: &[X86_64_FEATURES, EPYC_FEATURES],
+    },
+    NamedCpuModel {
+        name: "EPYC-Rome",
+        vendor: b"AuthenticAMD",
+        family: 23,
+        model: 49,
+        stepping: 0,
+        brand: "AMD EPYC-Rome Processor",
+        features: &[X86_64_FEATURES, EPYC_FEATURES, EPYC_ROME_FEATURES],
+    },
+];
+
+/// Returns the CPU model named `name`.
+pub fn find(name: &str) -> Result<CpuModel> {
+    match name {
+        DEFAULT_CPU_MODEL => Ok(CpuModel::HostPassthrough),
+        HOST_MODEL => Ok(CpuModel::HostModel),
+        _ => CPU_MODELS
+            .iter()
+            .find(|m| m.name == name)
+            .map(CpuModel::Named)
+            .ok_or_else(|| Error::UnknownModel(name.to_string())),
+    }
+}
+
+fn entry_mut(cpuid: &mut CpuId, function: u32, index: u32) -> Option<&mut kvm_cpuid_entry2> {
+    cpuid
+        .as_mut_slice()
+        .iter_mut()
+        .find(|e| e.function == function && e.index == index)
+}
+
+fn reg_mut(entry: &mut kvm_cpuid_entry2, reg: CpuidReg) -> &mut u32 {
+    match reg {
+        EAX => &mut entry.eax,
+        EBX => &mut entry.ebx,
+        ECX => &mut entry.ecx,
+        EDX => &mut entry.edx,
+    }
+}
+
+/// Returns the processor signature, as found in EAX of the leaf 1.
+fn signature(family: u32, model: u32, stepping: u32) -> u32 {
+    let (family, extended_family) = if family > 0xf {
+        (0xf, family - 0xf)
+    } else {
+        (family, 0)
+    };
+
+    extended_family << 20 | (model >> 4) << 16 | family << 8 | (model & 0xf) << 4 | stepping
+}
+
+/// Returns the EAX, EBX, ECX and EDX values of the brand string leaves,
+/// 0x8000_0002 to 0x8000_0004, for `brand`.
+fn brand_string(brand: &str) -> [[u32; 4]; 3] {
+    // The string is NUL terminated, on 48 bytes at most.
+    let mut bytes = [0u8; 48];
+    let len = brand.len().min(47);
+    bytes[..len].copy_from_slice(&brand.as_bytes()[..len]);
+
+    let mut leaves = [[0u32; 4]; 3];
+    for (i, chunk) in bytes.chunks(4).enumerate() {
+        leaves[i / 4][i % 4] = u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+    }
+    leaves
+}
+
+fn remove_features(cpuid: &mut CpuId, features: &[CpuFeature]) {
+    for f in features {
+        if let Some(entry) = entry_mut(cpuid, f.function, f.index) {
+            *reg_mut(entry, f.reg) &= !(1 << f.bit);
+        }
+    }
+}
+
+impl CpuModel {
+    /// Turns the host `cpuid`, as returned by KVM_GET_SUPPORTED_CPUID, into
+    /// the one of the model.
+    pub fn apply(&self, cpuid: &mut CpuId) -> Result<()> {
+        match self {
+            CpuModel::HostPassthrough => {}
+            CpuModel::HostModel => remove_features(cpuid, UNMIGRATABLE_FEATURES),
+            CpuModel::Named(model) => {
+                model.apply(cpuid)?;
+                // Not all of them live in the registers defined by the
+                // model.
+                remove_features(cpuid, UNMIGRATABLE_FEATURES);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl NamedCpuModel {
+    fn features(&self) -> impl Iterator<Item = &CpuFeature> {
+        self.features.iter().flat_map(|f| f.iter())
+    }
+
+    /// Replaces the host `cpuid`, as returned by KVM_GET_SUPPORTED_CPUID,
+    /// with the one of the model. Fails if the host doesn't support one of
+    /// the model features.
+    pub fn apply(&self, cpuid: &mut CpuId) -> Result<()> {
+        let missing = |feature| Error::MissingFeature {
+            model: self.name,
+            feature,
+        };
+
+        for f in self.features() {
+            let supported = entry_mut(cpuid, f.function, f.index)
+                .map_or(false, |e| *reg_mut(e, f.reg) & (1 << f.bit) != 0);
+            if !supported {
+                return Err(missing(f.name));
+            }
+        }
+        for &function in BRAND_STRING_LEAVES.iter() {
+            if entry_mut(cpuid, function, 0).is_none() {
+                return Err(missing("brand string"));
+            }
+        }
+
+        for &(function, index, reg, host_bits) in MODEL_REGS.iter() {
+            let value = self
+                .features()
+                .filter(|f| f.function == function && f.index == index && f.reg == reg)
+                .fold(0, |value, f| value | 1 << f.bit);
+            if let Some(entry) = entry_mut(cpuid, function, index) {
+                let r = reg_mut(entry, reg);
+                *r = *r & host_bits | value;
+            }
+        }
+
+        if let Some(entry) = entry_mut(cpuid, 0, 0) {
+            let vendor = |i: usize| {
+                u32::from_le_bytes([
+                    self.vendor[i],
+                    self.vendor[i + 1],
+                    self.vendor[i + 2],
+                    self.vendor[i + 3],
+                ])
+            };
+            entry.ebx = vendor(0);
+            entry.edx = vendor(4);
+            entry.ecx = vendor(8);
+        }
+
+        if let Some(entry) = entry_mut(cpuid, 1, 0) {
+            entry.eax = signature(self.family, self.model, self.stepping);
+        }
+
+        for (&function, regs) in BRAND_STRING_LEAVES
+            .iter()
+            .zip(brand_string(self.brand).iter())
+        {
+            if let Some(entry) = entry_mut(cpuid, function, 0) {
+                entry.eax = regs[0];
+                entry.ebx = regs[1];
+                entry.ecx = regs[2];
+                entry.edx = regs[3];
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A host supporting every feature.
+    fn host_cpuid() -> CpuId {
+        let leaves = [
+            (0, 0),
+            (1, 0),
+            (7, 0),
+            (0xd, 0),
+            (0xd, 1),
+            (0x8000_0000, 0),
+            (0x8000_0001, 0),
+            (0x8000_0002, 0),
+            (0x8000_0003, 0),
+            (0x8000_0004, 0),
+            (0x8000_0007, 0),
+        ];
+        let entries: Vec<kvm_cpuid_entry2> = leaves
+            .iter()
+            .map(|&(function, index)| kvm_cpuid_entry2 {
+                function,
+                index,
+                eax: 0xffff_ffff,
+                ebx: 0xffff_ffff,
+                ecx: 0xffff_ffff,
+                edx: 0xffff_ffff,
+                ..Default::default()
+            })
+            .collect();
+        CpuId::from_entries(&entries)
+    }
+
+    fn leaf(cpuid: &mut CpuId, function: u32, index: u32) -> kvm_cpuid_entry2 {
+        *entry_mut(cpuid, function, index).unwrap()
+    }
+
+    fn named(name: &str) -> &'static NamedCpuModel {
+        match find(name).unwrap() {
+            CpuModel::Named(model) => model,
+            _ => panic!("{} is not a named CPU model", name),
+        }
+    }
+
+    fn has_feature(cpuid: &mut CpuId, f: &CpuFeature) -> bool {
+        *reg_mut(entry_mut(cpuid, f.function, f.index).unwrap(), f.reg) & (1 << f.bit) != 0
+    }
+
+    #[test]
+    fn test_find_cpu_model() {
+        match find(DEFAULT_CPU_MODEL).unwrap() {
+            CpuModel::HostPassthrough => {}
+            _ => panic!("expected the host CPU"),
+        }
+        match find(HOST_MODEL).unwrap() {
+            CpuModel::HostModel => {}
+            _ => panic!("expected the host model"),
+        }
+        assert_eq!(named("EPYC").name, "EPYC");
+        assert!(find("Pentium").is_err());
+    }
+
+    #[test]
+    fn test_host_model() {
+        let mut cpuid = host_cpuid();
+        find(DEFAULT_CPU_MODEL).unwrap().apply(&mut cpuid).unwrap();
+        assert!(has_feature(&mut cpuid, &INVTSC));
+
+        // Only the features that can't be migrated are removed.
+        find(HOST_MODEL).unwrap().apply(&mut cpuid).unwrap();
+        for f in UNMIGRATABLE_FEATURES {
+            assert!(!has_feature(&mut cpuid, f), "{} is left", f.name);
+        }
+        assert!(has_feature(&mut cpuid, &AVX512F));
+        assert!(has_feature(&mut cpuid, &RDTSCP));
+        assert_eq!(leaf(&mut cpuid, 0, 0).ebx, 0xffff_ffff);
+    }
+
+    #[test]
+    fn test_haswell() {
+        let mut cpuid = host_cpuid();
+        find("Haswell").unwrap().apply(&mut cpuid).unwrap();
+
+        assert_eq!(leaf(&mut cpuid, 1, 0).eax, 0x0003_06c4);
+        for f in X86_64_FEATURES
+            .iter()
+            .chain(SANDYBRIDGE_FEATURES)
+            .chain(HASWELL_FEATURES)
+        {
+            assert!(has_feature(&mut cpuid, f), "{} is missing", f.name);
+        }
+        // Nothing newer, and nothing that can't be migrated.
+        assert!(!has_feature(&mut cpuid, &AVX512F));
+        assert!(!has_feature(&mut cpuid, &RDSEED));
+        assert!(!has_feature(&mut cpuid, &INVTSC));
+    }
+
+    #[test]
+    fn test_signature() {
+        assert_eq!(signature(6, 85, 4), 0x0005_0654);
+        assert_eq!(signature(23, 1, 2), 0x0080_0f12);
+    }
+
+    #[test]
+    fn test_brand_string() {
+        let mut cpuid = host_cpuid();
+        named("Skylake-Server").apply(&mut cpuid).unwrap();
+
+        let mut brand = Vec::new();
+        for function in BRAND_STRING_LEAVES.iter() {
+            let e = leaf(&mut cpuid, *function, 0);
+            for reg in [e.eax, e.ebx, e.ecx, e.edx].iter() {
+                brand.extend_from_slice(&reg.to_le_bytes());
+            }
+        }
+        assert_eq!(&brand[..30], b"Intel Xeon Processor (Skylake)");
+        assert!(brand[30..].iter().all(|b| *b == 0));
+
+        // "Inte" and "l Xe"
+        let e = leaf(&mut cpuid, 0x8000_0002, 0);
+        assert_eq!(e.eax, 0x6574_6e49);
+        assert_eq!(e.ebx, 0x6558_206c);
+
+        // Brand strings are truncated, keeping the NUL terminator.
+        let long = brand_string(&"x".repeat(64));
+        assert_eq!(long[2][3], 0x0078_7878);
+    }
+
+    #[test]
+    fn test_apply_cpu_model() {
+        let mut cpuid = host_cpuid();
+        named("EPYC").apply(&mut cpuid).unwrap();
+
+        let e = leaf(&mut cpuid, 0, 0);
+        let mut vendor = Vec::new();
+        for reg in [e.ebx, e.edx, e.ecx].iter() {
+            vendor.extend_from_slice(&reg.to_le_bytes());
+        }
+        assert_eq!(&vendor, b"AuthenticAMD");
+
+        let e = leaf(&mut cpuid, 1, 0);
+        assert_eq!(e.eax, 0x0080_0f12);
+        // Only the model features, and the host bits, are left.
+        assert_eq!(e.ecx & (1 << SSE4_2.bit), 1 << SSE4_2.bit);
+        assert_eq!(e.ecx & (1 << PCID.bit), 0);
+        assert_eq!(e.ecx & (1 << 31), 1 << 31);
+        // The other leaves aren't modified.
+        assert_eq!(leaf(&mut cpuid, 0xd, 0).eax, 0xffff_ffff);
+        // AVX-512 isn't part of the model.
+        assert_eq!(leaf(&mut cpuid, 7, 0).ebx & (1 << AVX512F.bit), 0);
+    }
+
+    #[test]
+    fn test_apply_cpu_model_missing_feature() {
+        let mut cpuid = host_cpuid();
+        *reg_mut(entry_mut(&mut cpuid, 7, 0).unwrap(), EBX) &= !(1 << AVX512F.bit);
+
+        // The host can't be a Skylake server, but still an EPYC.
+        match named("Skylake-Server").apply(&mut cpuid) {
+            Err(Error::MissingFeature { model, feature }) => {
+                assert_eq!(model, "Skylake-Server");
+                assert_eq!(feature, "avx512f");
+            }
+            _ => panic!("expected a missing feature error"),
+        }
+        assert!(named("EPYC").apply(&mut cpuid).is_ok());
+    }
+}
@@ -12,54 +12,99 @@
 extern crate vm_device;
 
 use crate::config::ConsoleOutputMode;
-use crate::config::VmConfig;
-use crate::interrupt::{
-    KvmLegacyUserspaceInterruptManager, KvmMsiInterruptManager, KvmRoutingEntry,
+#[cfg(target_arch = "x86_64")]
+use crate::config::IrqChipKind;
+#[cfg(feature = "pci_support")]
+use crate::config::WatchdogAction;
+use crate::config::{DiskConfig, Error as ConfigError, MemoryConfig, NetConfig, VmConfig};
+use crate::device_registry::{
+    BusRange, BusRangeKind, DeviceRegistry, DeviceResources, Error as DeviceRegistryError,
 };
+use crate::inherited_fd;
+#[cfg(target_arch = "x86_64")]
+use crate::interrupt::KvmLegacyUserspaceInterruptManager;
+use crate::interrupt::{KvmLegacyInterruptManager, KvmMsiInterruptManager, KvmRoutingEntry};
 use crate::memory_manager::{Error as MemoryManagerError, MemoryManager};
+use crate::serial_buffer::{self, SerialBuffer};
+#[cfg(feature = "acpi")]
+use crate::vmgenid::{self, VmGenIdDevice};
 #[cfg(feature = "acpi")]
 use acpi_tables::{aml, aml::Aml};
+use anyhow::anyhow;
+#[cfg(target_arch = "aarch64")]
+use arch::aarch64::MmioDeviceInfo;
 #[cfg(feature = "acpi")]
 use arch::layout;
+#[cfg(target_arch = "x86_64")]
 use arch::layout::{APIC_START, IOAPIC_SIZE, IOAPIC_START};
+use devices::legacy::SerialOutput;
+#[cfg(feature = "pci_support")]
+use devices::BusDevice;
 use devices::{ioapic, HotPlugNotificationFlags};
 use kvm_ioctls::*;
 use libc::O_TMPFILE;
 use libc::TIOCGWINSZ;
 #[cfg(feature = "pci_support")]
 use pci::{
-    DeviceRelocation, PciBarRegionType, PciBus, PciConfigIo, PciConfigMmio, PciDevice, PciRoot,
+    DeviceRelocation, I6300esbWatchdog, PciBarRegionType, PciBus, PciConfigIo, PciConfigMmio,
+    PciDevice, PciRoot,
 };
 use qcow::{self, ImageType, QcowFile};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
 use std::fs::{File, OpenOptions};
-use std::io::{self, sink, stdout};
+use std::io::{self, sink, stdout, BufWriter};
 use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::{Path, PathBuf};
 use std::result;
 #[cfg(feature = "pci_support")]
 use std::sync::Weak;
 use std::sync::{Arc, Mutex};
 #[cfg(feature = "pci_support")]
-use vfio::{VfioDevice, VfioDmaMapping, VfioPciDevice, VfioPciError};
+use std::time::{Duration, Instant};
+#[cfg(feature = "pci_support")]
+use vfio::{VfioContainer, VfioDevice, VfioDmaMapping, VfioPciDevice, VfioPciError};
 use vm_allocator::SystemAllocator;
 use vm_device::interrupt::{
     InterruptIndex, InterruptManager, LegacyIrqGroupConfig, MsiIrqGroupConfig,
 };
-use vm_device::{Migratable, MigratableError, Pausable, Snapshotable};
+use vm_device::{Migratable, MigratableError, Pausable, Snapshot, Snapshotable};
 use vm_memory::guest_memory::FileOffset;
-use vm_memory::{Address, GuestAddress, GuestUsize, MmapRegion};
+#[cfg(feature = "pci_support")]
+use vm_memory::GuestMemoryRegion;
+use vm_memory::{Address, GuestAddress, GuestRegionMmap, GuestUsize, MmapRegion};
 #[cfg(feature = "pci_support")]
 use vm_virtio::transport::VirtioPciDevice;
 use vm_virtio::transport::VirtioTransport;
 use vm_virtio::vhost_user::VhostUserConfig;
 #[cfg(feature = "pci_support")]
-use vm_virtio::{DmaRemapping, IommuMapping, VirtioIommuRemapping};
+use vm_virtio::{DmaAccess, DmaMapping, DmaRemapping, IommuMapping};
 use vm_virtio::{VirtioSharedMemory, VirtioSharedMemoryList};
 use vmm_sys_util::eventfd::EventFd;
+use vmm_sys_util::timerfd::TimerFd;
 
 #[cfg(feature = "mmio_support")]
 const MMIO_LEN: u64 = 0x1000;
 
+// Time given to a device being unplugged to complete its requests in flight,
+// and to the guest to release it.
+#[cfg(feature = "pci_support")]
+const DEVICE_REMOVAL_TIMEOUT: Duration = Duration::from_secs(5);
+
+// How often the device removals in progress check on the device and the
+// guest.
+#[cfg(feature = "pci_support")]
+const DEVICE_REMOVAL_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+// PCI hotplug controller registers
+#[cfg(feature = "pci_support")]
+const PCI_SLOTS_PENDING_OFFSET: u64 = 0;
+#[cfg(feature = "pci_support")]
+const PCI_SLOTS_EJECTED_OFFSET: u64 = 4;
+#[cfg(feature = "pci_support")]
+const PCI_SLOTS_INSERTED_OFFSET: u64 = 8;
+
 /// Errors associated with device manager
 #[derive(Debug)]
 pub enum DeviceManagerError {
@@ -67,7 +112,7 @@ pub enum DeviceManagerError {
     EventFd(io::Error),
 
     /// Cannot open disk path
-    Disk(io::Error),
+    Disk(PathBuf, io::Error),
 
     /// Cannot create vhost-user-net device
     CreateVhostUserNet(vm_virtio::vhost_user::Error),
@@ -78,21 +123,66 @@ pub enum DeviceManagerError {
     /// Cannot create virtio-net device
     CreateVirtioNet(vm_virtio::net::Error),
 
+    /// Cannot hand the hotplugged memory over to a virtio-net device
+    UpdateVirtioNetMemory(vm_virtio::net::Error),
+
+    /// Cannot change the link status of a virtio-net device
+    SetNetLink(io::Error),
+
+    /// Cannot create the virtio-net packet capture file
+    CreateNetPcap(io::Error),
+
     /// Cannot create virtio-console device
     CreateVirtioConsole(io::Error),
 
     /// Cannot create virtio-rng device
     CreateVirtioRng(io::Error),
 
+    /// Cannot create virtio-balloon device
+    CreateVirtioBalloon(io::Error),
+
+    /// Cannot create the watchdog device.
+    CreateWatchdog(io::Error),
+
+    /// The VM has no balloon device.
+    NoBalloon,
+
+    /// Cannot ask the guest for memory through the balloon device.
+    SetBalloonTarget(io::Error),
+
+    /// The VM has no GPU device.
+    NoGpu,
+
+    /// Cannot create virtio-input device
+    CreateVirtioInput(io::Error),
+
+    /// The VM has no input device.
+    NoInput,
+
+    /// Cannot write the framebuffer to a file.
+    Screenshot(io::Error),
+
+    /// The VM has no PS/2 keyboard.
+    NoKeyboard,
+
+    /// Cannot type on the PS/2 keyboard.
+    SendKey(io::Error),
+
     /// Cannot create virtio-fs device
     CreateVirtioFs(vm_virtio::vhost_user::Error),
 
     /// Cannot create vhost-user-blk device
     CreateVhostUserBlk(vm_virtio::vhost_user::Error),
 
+    /// vhost-user devices need the guest RAM to be file backed
+    VhostUserMemoryNotShared,
+
     /// Cannot create virtio-pmem device
     CreateVirtioPmem(io::Error),
 
+    /// Cannot create virtio-scsi device
+    CreateVirtioScsi(io::Error),
+
     /// Cannot create virtio-vsock device
     CreateVirtioVsock(io::Error),
 
@@ -135,7 +225,7 @@ pub enum DeviceManagerError {
     AddPciDevice(pci::PciRootError),
 
     /// Cannot open persistent memory file
-    PmemFileOpen(io::Error),
+    PmemFileOpen(PathBuf, io::Error),
 
     /// Cannot set persistent memory file size
     PmemFileSetLen(io::Error),
@@ -147,10 +237,13 @@ pub enum DeviceManagerError {
     FsRangeAllocation,
 
     /// Error creating serial output file
-    SerialOutputFileOpen(io::Error),
+    SerialOutputFileOpen(PathBuf, io::Error),
 
     /// Error creating console output file
-    ConsoleOutputFileOpen(io::Error),
+    ConsoleOutputFileOpen(PathBuf, io::Error),
+
+    /// Cannot set up the serial output buffer
+    SerialBuffer(io::Error),
 
     /// Cannot create a VFIO device
     #[cfg(feature = "pci_support")]
@@ -164,6 +257,10 @@ pub enum DeviceManagerError {
     #[cfg(feature = "pci_support")]
     VfioMapRegion(VfioPciError),
 
+    /// Failed to map guest memory for VFIO DMA.
+    #[cfg(feature = "pci_support")]
+    VfioDmaMap(vfio::VfioError),
+
     /// Failed to create the KVM device.
     CreateKvmDevice(kvm_ioctls::Error),
 
@@ -196,6 +293,360 @@ pub enum DeviceManagerError {
 
     /// Failed cloning a File.
     CloneFile(io::Error),
+
+    /// No disk exists at the given index.
+    InvalidDiskIndex(usize),
+
+    /// The disk at the given index cannot be resized.
+    DiskResizeUnsupported(usize),
+
+    /// Failed resizing a virtio-blk device.
+    ResizeVirtioBlock(io::Error),
+
+    /// Cannot unregister ioevent.
+    UnregisterIoevent(kvm_ioctls::Error),
+
+    /// No free PCI device id is left.
+    #[cfg(feature = "pci_support")]
+    NextPciDeviceId(pci::PciRootError),
+
+    /// The PCI slot a device asked for is out of range or in use.
+    #[cfg(feature = "pci_support")]
+    PciSlot(pci::PciRootError),
+
+    /// Cannot remove PCI device
+    #[cfg(feature = "pci_support")]
+    RemovePciDevice(pci::PciRootError),
+
+    /// No device exists at the given index.
+    InvalidDeviceIndex(usize),
+
+    /// No disk has the given id.
+    InvalidDiskId(String),
+
+    /// No virtio-net device has the given id.
+    InvalidNetId(String),
+
+    /// The id of a device plugged at runtime is invalid or taken.
+    InvalidDeviceId(ConfigError),
+
+    /// The PCI slot of a device plugged at runtime is invalid or taken.
+    InvalidPciSlot(ConfigError),
+
+    /// The configuration of a device plugged at runtime is invalid.
+    InvalidDeviceConfig(ConfigError),
+
+    /// Failed looking a device up, or registering it.
+    DeviceRegistry(DeviceRegistryError),
+
+    /// The device cannot be unplugged.
+    DeviceRemovalUnsupported(String),
+
+    /// The device did not stop processing its queues.
+    DeviceNotQuiesced(String),
+
+    /// The guest did not release the device in time.
+    DeviceEjectTimeout(String),
+
+    /// The device is already being unplugged.
+    DeviceRemovalInProgress(String),
+
+    /// Cannot create or arm the device removal timer.
+    DeviceRemovalTimer(io::Error),
+
+    /// Failed to destroy interrupt source group.
+    DestroyInterruptGroup(io::Error),
+
+    /// Devices plugged at runtime cannot be attached to the virtio-iommu.
+    HotplugIommuUnsupported,
+
+    /// Devices cannot be plugged at runtime without PCI support.
+    HotplugUnsupported,
+
+    /// Cannot generate a VM generation ID.
+    GenerationId(io::Error),
+
+    /// Cannot write the VM generation ID to the guest memory.
+    WriteGenerationId(vm_memory::GuestMemoryError),
+
+    /// The VM generation ID cannot be changed without ACPI support.
+    GenerationIdUnsupported,
+}
+
+impl fmt::Display for DeviceManagerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DeviceManagerError::EventFd(_) => write!(f, "cannot create EventFd"),
+            DeviceManagerError::Disk(path, _) => write!(f, "cannot open disk {}", path.display()),
+            DeviceManagerError::CreateVhostUserNet(_) => {
+                write!(f, "cannot create vhost-user-net device")
+            }
+            DeviceManagerError::CreateVirtioBlock(_) => {
+                write!(f, "cannot create virtio-blk device")
+            }
+            DeviceManagerError::CreateVirtioNet(_) => write!(f, "cannot create virtio-net device"),
+            DeviceManagerError::UpdateVirtioNetMemory(_) => {
+                write!(
+                    f,
+                    "cannot hand the hotplugged memory over to a virtio-net device"
+                )
+            }
+            DeviceManagerError::SetNetLink(_) => {
+                write!(f, "cannot change the link status of a virtio-net device")
+            }
+            DeviceManagerError::CreateNetPcap(_) => {
+                write!(f, "cannot create virtio-net packet capture file")
+            }
+            DeviceManagerError::CreateVirtioConsole(_) => {
+                write!(f, "cannot create virtio-console device")
+            }
+            DeviceManagerError::CreateVirtioRng(_) => write!(f, "cannot create virtio-rng device"),
+            DeviceManagerError::CreateVirtioBalloon(_) => {
+                write!(f, "cannot create virtio-balloon device")
+            }
+            DeviceManagerError::CreateWatchdog(_) => write!(f, "cannot create the watchdog device"),
+            DeviceManagerError::NoBalloon => write!(f, "the VM has no balloon device"),
+            DeviceManagerError::SetBalloonTarget(_) => {
+                write!(
+                    f,
+                    "cannot ask the guest for memory through the balloon device"
+                )
+            }
+            DeviceManagerError::NoGpu => write!(f, "the VM has no GPU device"),
+            DeviceManagerError::CreateVirtioInput(_) => {
+                write!(f, "cannot create virtio-input device")
+            }
+            DeviceManagerError::NoInput => write!(f, "the VM has no input device"),
+            DeviceManagerError::NoKeyboard => write!(f, "the VM has no PS/2 keyboard"),
+            DeviceManagerError::SendKey(_) => write!(f, "cannot type on the PS/2 keyboard"),
+            DeviceManagerError::Screenshot(_) => {
+                write!(f, "cannot write the framebuffer to a file")
+            }
+            DeviceManagerError::CreateVirtioFs(_) => write!(f, "cannot create virtio-fs device"),
+            DeviceManagerError::CreateVhostUserBlk(_) => {
+                write!(f, "cannot create vhost-user-blk device")
+            }
+            DeviceManagerError::VhostUserMemoryNotShared => {
+                write!(f, "vhost-user devices need the guest RAM to be file backed")
+            }
+            DeviceManagerError::CreateVirtioPmem(_) => {
+                write!(f, "cannot create virtio-pmem device")
+            }
+            DeviceManagerError::CreateVirtioScsi(_) => {
+                write!(f, "cannot create virtio-scsi device")
+            }
+            DeviceManagerError::CreateVirtioVsock(_) => {
+                write!(f, "cannot create virtio-vsock device")
+            }
+            DeviceManagerError::CreateVsockConvertPath => write!(
+                f,
+                "failed converting Path to &str for the virtio-vsock device"
+            ),
+            DeviceManagerError::CreateVsockBackend(_) => {
+                write!(f, "cannot create virtio-vsock backend")
+            }
+            DeviceManagerError::CreateVirtioIommu(_) => {
+                write!(f, "cannot create virtio-iommu device")
+            }
+            DeviceManagerError::DetectImageType(e) => {
+                write!(f, "failed parsing disk image format: {}", e)
+            }
+            DeviceManagerError::QcowDeviceCreate(e) => {
+                write!(f, "cannot open qcow disk path: {}", e)
+            }
+            DeviceManagerError::OpenTap(e) => write!(f, "cannot open tap interface: {:?}", e),
+            DeviceManagerError::AllocateIrq => write!(f, "cannot allocate IRQ"),
+            DeviceManagerError::Irq(_) => write!(f, "cannot configure the IRQ"),
+            #[cfg(feature = "pci_support")]
+            DeviceManagerError::AllocateBars(e) => write!(f, "cannot allocate PCI BARs: {}", e),
+            DeviceManagerError::RegisterIoevent(_) => write!(f, "cannot register ioevent"),
+            DeviceManagerError::VirtioDevice(_) => write!(f, "cannot create virtio device"),
+            #[cfg(feature = "pci_support")]
+            DeviceManagerError::AddPciDevice(e) => write!(f, "cannot add PCI device: {:?}", e),
+            DeviceManagerError::PmemFileOpen(path, _) => {
+                write!(f, "cannot open persistent memory file {}", path.display())
+            }
+            DeviceManagerError::PmemFileSetLen(_) => {
+                write!(f, "cannot set persistent memory file size")
+            }
+            DeviceManagerError::PmemRangeAllocation => {
+                write!(f, "cannot find a memory range for persistent memory")
+            }
+            DeviceManagerError::FsRangeAllocation => {
+                write!(f, "cannot find a memory range for virtio-fs")
+            }
+            DeviceManagerError::SerialOutputFileOpen(path, _) => {
+                write!(f, "error creating serial output file {}", path.display())
+            }
+            DeviceManagerError::ConsoleOutputFileOpen(path, _) => {
+                write!(f, "error creating console output file {}", path.display())
+            }
+            DeviceManagerError::SerialBuffer(_) => {
+                write!(f, "cannot set up the serial output buffer")
+            }
+            #[cfg(feature = "pci_support")]
+            DeviceManagerError::VfioCreate(e) => write!(f, "cannot create a VFIO device: {}", e),
+            #[cfg(feature = "pci_support")]
+            DeviceManagerError::VfioPciCreate(e) => {
+                write!(f, "cannot create a VFIO PCI device: {}", e)
+            }
+            #[cfg(feature = "pci_support")]
+            DeviceManagerError::VfioMapRegion(e) => {
+                write!(f, "failed to map VFIO MMIO region: {}", e)
+            }
+            #[cfg(feature = "pci_support")]
+            DeviceManagerError::VfioDmaMap(e) => {
+                write!(f, "failed to map guest memory for VFIO DMA: {}", e)
+            }
+            DeviceManagerError::CreateKvmDevice(_) => write!(f, "failed to create the KVM device"),
+            DeviceManagerError::Mmap(_) => write!(f, "failed to memory map"),
+            DeviceManagerError::BusError(_) => write!(f, "cannot add legacy device to Bus"),
+            DeviceManagerError::AllocateIOPort => write!(f, "failed to allocate IO port"),
+            DeviceManagerError::HotPlugNotification(_) => {
+                write!(f, "failed to make hotplug notification")
+            }
+            DeviceManagerError::MemoryManager(_) => {
+                write!(f, "error from a memory manager operation")
+            }
+            DeviceManagerError::CreateInterruptGroup(_) => {
+                write!(f, "failed to create new interrupt source group")
+            }
+            DeviceManagerError::UpdateInterruptGroup(_) => {
+                write!(f, "failed to update interrupt source group")
+            }
+            DeviceManagerError::CreateIoapic(_) => write!(f, "failed creating IOAPIC"),
+            DeviceManagerError::NewMmapRegion(_) => {
+                write!(f, "failed creating a new MmapRegion instance")
+            }
+            DeviceManagerError::CloneFile(_) => write!(f, "failed cloning a File"),
+            DeviceManagerError::InvalidDiskIndex(e) => write!(f, "no disk exists at index {}", e),
+            DeviceManagerError::DiskResizeUnsupported(e) => {
+                write!(f, "the disk at index {} cannot be resized", e)
+            }
+            DeviceManagerError::ResizeVirtioBlock(_) => {
+                write!(f, "failed resizing a virtio-blk device")
+            }
+            DeviceManagerError::UnregisterIoevent(_) => write!(f, "cannot unregister ioevent"),
+            #[cfg(feature = "pci_support")]
+            DeviceManagerError::NextPciDeviceId(e) => {
+                write!(f, "no free PCI device id is left: {:?}", e)
+            }
+            #[cfg(feature = "pci_support")]
+            DeviceManagerError::PciSlot(e) => {
+                write!(f, "cannot give the device its PCI slot: {:?}", e)
+            }
+            #[cfg(feature = "pci_support")]
+            DeviceManagerError::RemovePciDevice(e) => {
+                write!(f, "cannot remove PCI device: {:?}", e)
+            }
+            DeviceManagerError::InvalidDeviceIndex(e) => {
+                write!(f, "no device exists at index {}", e)
+            }
+            DeviceManagerError::InvalidDiskId(id) => write!(f, "no disk has the id {:?}", id),
+            DeviceManagerError::InvalidNetId(id) => {
+                write!(f, "no virtio-net device has the id {:?}", id)
+            }
+            DeviceManagerError::InvalidDeviceId(e) => write!(f, "invalid device id: {}", e),
+            DeviceManagerError::InvalidPciSlot(e) => write!(f, "invalid PCI slot: {}", e),
+            DeviceManagerError::InvalidDeviceConfig(e) => {
+                write!(f, "invalid device configuration: {}", e)
+            }
+            DeviceManagerError::DeviceRegistry(e) => write!(f, "device registry error: {}", e),
+            DeviceManagerError::DeviceRemovalUnsupported(id) => {
+                write!(f, "the device {:?} cannot be unplugged", id)
+            }
+            DeviceManagerError::DeviceNotQuiesced(id) => {
+                write!(f, "the device {:?} did not stop processing its queues", id)
+            }
+            DeviceManagerError::DeviceEjectTimeout(id) => {
+                write!(f, "the guest did not release the device {:?} in time", id)
+            }
+            DeviceManagerError::DeviceRemovalInProgress(id) => {
+                write!(f, "the device {:?} is already being unplugged", id)
+            }
+            DeviceManagerError::DeviceRemovalTimer(_) => {
+                write!(f, "cannot create or arm the device removal timer")
+            }
+            DeviceManagerError::DestroyInterruptGroup(_) => {
+                write!(f, "failed to destroy interrupt source group")
+            }
+            DeviceManagerError::HotplugIommuUnsupported => write!(
+                f,
+                "devices plugged at runtime cannot be attached to the virtio-iommu"
+            ),
+            DeviceManagerError::HotplugUnsupported => write!(
+                f,
+                "devices cannot be plugged at runtime without PCI support"
+            ),
+            DeviceManagerError::GenerationId(_) => write!(f, "cannot generate a VM generation ID"),
+            DeviceManagerError::WriteGenerationId(_) => {
+                write!(f, "cannot write the VM generation ID to the guest memory")
+            }
+            DeviceManagerError::GenerationIdUnsupported => write!(
+                f,
+                "the VM generation ID cannot be changed without ACPI support"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for DeviceManagerError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            DeviceManagerError::EventFd(e) => Some(e),
+            DeviceManagerError::CreateVhostUserNet(e) => Some(e),
+            DeviceManagerError::CreateVirtioBlock(e) => Some(e),
+            DeviceManagerError::CreateVirtioNet(e) => Some(e),
+            DeviceManagerError::UpdateVirtioNetMemory(e) => Some(e),
+            DeviceManagerError::SetNetLink(e) => Some(e),
+            DeviceManagerError::CreateNetPcap(e) => Some(e),
+            DeviceManagerError::CreateVirtioConsole(e) => Some(e),
+            DeviceManagerError::CreateVirtioRng(e) => Some(e),
+            DeviceManagerError::CreateVirtioBalloon(e) => Some(e),
+            DeviceManagerError::CreateWatchdog(e) => Some(e),
+            DeviceManagerError::SetBalloonTarget(e) => Some(e),
+            DeviceManagerError::CreateVirtioInput(e) => Some(e),
+            DeviceManagerError::Screenshot(e) => Some(e),
+            DeviceManagerError::SendKey(e) => Some(e),
+            DeviceManagerError::GenerationId(e) => Some(e),
+            DeviceManagerError::SerialBuffer(e) => Some(e),
+            DeviceManagerError::WriteGenerationId(e) => Some(e),
+            DeviceManagerError::CreateVirtioFs(e) => Some(e),
+            DeviceManagerError::CreateVhostUserBlk(e) => Some(e),
+            DeviceManagerError::CreateVirtioPmem(e) => Some(e),
+            DeviceManagerError::CreateVirtioScsi(e) => Some(e),
+            DeviceManagerError::CreateVirtioVsock(e) => Some(e),
+            DeviceManagerError::CreateVsockBackend(e) => Some(e),
+            DeviceManagerError::CreateVirtioIommu(e) => Some(e),
+            DeviceManagerError::Irq(e) => Some(e),
+            DeviceManagerError::RegisterIoevent(e) => Some(e),
+            DeviceManagerError::VirtioDevice(e) => Some(e),
+            DeviceManagerError::PmemFileSetLen(e) => Some(e),
+            DeviceManagerError::CreateKvmDevice(e) => Some(e),
+            DeviceManagerError::Mmap(e) => Some(e),
+            DeviceManagerError::BusError(e) => Some(e),
+            DeviceManagerError::HotPlugNotification(e) => Some(e),
+            DeviceManagerError::MemoryManager(e) => Some(e),
+            DeviceManagerError::CreateInterruptGroup(e) => Some(e),
+            DeviceManagerError::UpdateInterruptGroup(e) => Some(e),
+            DeviceManagerError::DeviceRemovalTimer(e) => Some(e),
+            DeviceManagerError::DestroyInterruptGroup(e) => Some(e),
+            DeviceManagerError::CreateIoapic(e) => Some(e),
+            DeviceManagerError::NewMmapRegion(e) => Some(e),
+            DeviceManagerError::CloneFile(e) => Some(e),
+            DeviceManagerError::ResizeVirtioBlock(e) => Some(e),
+            DeviceManagerError::UnregisterIoevent(e) => Some(e),
+            DeviceManagerError::InvalidDeviceId(e) => Some(e),
+            DeviceManagerError::InvalidPciSlot(e) => Some(e),
+            DeviceManagerError::InvalidDeviceConfig(e) => Some(e),
+            DeviceManagerError::DeviceRegistry(e) => Some(e),
+            DeviceManagerError::Disk(_, e)
+            | DeviceManagerError::PmemFileOpen(_, e)
+            | DeviceManagerError::SerialOutputFileOpen(_, e)
+            | DeviceManagerError::ConsoleOutputFileOpen(_, e) => Some(e),
+            _ => None,
+        }
+    }
 }
 pub type DeviceManagerResult<T> = result::Result<T, DeviceManagerError>;
 
@@ -221,7 +672,11 @@ pub fn get_win_size() -> (u16, u16) {
 #[derive(Default)]
 pub struct Console {
     // Serial port on 0x3f8
+    #[cfg(target_arch = "x86_64")]
     serial: Option<Arc<Mutex<devices::legacy::Serial>>>,
+    // PL011 UART, described in the device tree
+    #[cfg(target_arch = "aarch64")]
+    serial: Option<Arc<Mutex<devices::legacy::Pl011>>>,
     console_input: Option<Arc<vm_virtio::ConsoleInput>>,
     input_enabled: bool,
 }
@@ -258,6 +713,57 @@ impl Console {
     }
 }
 
+// Translates the addresses of a virtio PCI device placed behind the
+// virtio-iommu, knowing the device ID.
+#[cfg(feature = "pci_support")]
+struct VirtioDmaMapping {
+    mapping: Arc<IommuMapping>,
+    dev_id: u32,
+}
+
+#[cfg(feature = "pci_support")]
+impl VirtioDmaMapping {
+    fn translate_addr(&self, addr: u64) -> io::Result<u64> {
+        self.mapping.translate(self.dev_id, addr).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::Other,
+                format!(
+                    "failed to translate addr 0x{:x} for device 00:{:02x}.0 {}",
+                    addr, self.dev_id, e
+                ),
+            )
+        })
+    }
+}
+
+#[cfg(feature = "pci_support")]
+impl DmaMapping for VirtioDmaMapping {
+    fn translate(&self, addr: u64, len: u64, _access: DmaAccess) -> io::Result<u64> {
+        let start = self.translate_addr(addr)?;
+        if len > 1 {
+            // The whole range must be mapped contiguously, as the device
+            // accesses it through a single guest address.
+            let last = addr.checked_add(len - 1).ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("range 0x{:x}+0x{:x} overflows", addr, len),
+                )
+            })?;
+            if start.checked_add(len - 1) != Some(self.translate_addr(last)?) {
+                return Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    format!(
+                        "range 0x{:x}+0x{:x} for device 00:{:02x}.0 is not contiguous",
+                        addr, len, self.dev_id
+                    ),
+                ));
+            }
+        }
+
+        Ok(start)
+    }
+}
+
 struct AddressManager {
     allocator: Arc<Mutex<SystemAllocator>>,
     io_bus: Arc<devices::Bus>,
@@ -265,6 +771,137 @@ struct AddressManager {
     vm_fd: Arc<VmFd>,
 }
 
+#[cfg(feature = "pci_support")]
+impl AddressManager {
+    // Moves the allocation of a BAR from `from` to `to`, along with its range
+    // on the bus. Nothing is changed on failure.
+    fn move_bar_range(
+        &self,
+        from: u64,
+        to: u64,
+        len: u64,
+        region_type: PciBarRegionType,
+    ) -> io::Result<()> {
+        type Free = fn(&mut SystemAllocator, GuestAddress, GuestUsize);
+        type Allocate = fn(
+            &mut SystemAllocator,
+            Option<GuestAddress>,
+            GuestUsize,
+            Option<GuestUsize>,
+        ) -> Option<GuestAddress>;
+        let (free, allocate, bus, window): (Free, Allocate, _, _) = match region_type {
+            PciBarRegionType::IORegion => (
+                SystemAllocator::free_io_addresses,
+                SystemAllocator::allocate_io_addresses,
+                &self.io_bus,
+                "IO",
+            ),
+            PciBarRegionType::Memory32BitRegion => (
+                SystemAllocator::free_mmio_hole_addresses,
+                SystemAllocator::allocate_mmio_hole_addresses,
+                &self.mmio_bus,
+                "32 bits MMIO",
+            ),
+            PciBarRegionType::Memory64BitRegion => (
+                SystemAllocator::free_high_mmio_addresses,
+                SystemAllocator::allocate_high_mmio_addresses,
+                &self.mmio_bus,
+                "64 bits MMIO",
+            ),
+        };
+
+        // Update system allocator
+        let size = len as GuestUsize;
+        let mut allocator = self.allocator.lock().unwrap();
+        free(&mut allocator, GuestAddress(from), size);
+        if allocate(&mut allocator, Some(GuestAddress(to)), size, None).is_none() {
+            allocate(&mut allocator, Some(GuestAddress(from)), size, None);
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!("failed allocating new {} range", window),
+            ));
+        }
+
+        // Update the bus, giving the new range back if the device can't be
+        // moved there.
+        if let Err(e) = bus.update_range(from, len, to, len) {
+            free(&mut allocator, GuestAddress(to), size);
+            allocate(&mut allocator, Some(GuestAddress(from)), size, None);
+            return Err(io::Error::new(io::ErrorKind::Other, e));
+        }
+
+        Ok(())
+    }
+
+    // Moves the queue notification ioeventfds of a virtio-pci device from the
+    // BAR at `from` to the one at `to`. Nothing is changed on failure.
+    fn move_ioeventfds(&self, pci_dev: &mut dyn PciDevice, from: u64, to: u64) -> io::Result<()> {
+        let virtio_pci_dev = match pci_dev.as_any().downcast_ref::<VirtioPciDevice>() {
+            Some(virtio_pci_dev) => virtio_pci_dev,
+            None => return Ok(()),
+        };
+        let old_ioeventfds = virtio_pci_dev.ioeventfds(from);
+        let new_ioeventfds = virtio_pci_dev.ioeventfds(to);
+
+        let register = |ioeventfds: &[(&EventFd, u64)]| {
+            for (event, addr) in ioeventfds.iter() {
+                if let Err(e) =
+                    self.vm_fd
+                        .register_ioevent(*event, &IoEventAddress::Mmio(*addr), NoDatamatch)
+                {
+                    error!("Failed registering ioeventfd at 0x{:x}: {}", addr, e);
+                }
+            }
+        };
+        let unregister = |ioeventfds: &[(&EventFd, u64)]| {
+            for (event, addr) in ioeventfds.iter() {
+                if let Err(e) = self
+                    .vm_fd
+                    .unregister_ioevent(*event, &IoEventAddress::Mmio(*addr))
+                {
+                    error!("Failed unregistering ioeventfd at 0x{:x}: {}", addr, e);
+                }
+            }
+        };
+
+        for (i, (event, addr)) in old_ioeventfds.iter().enumerate() {
+            if let Err(e) = self
+                .vm_fd
+                .unregister_ioevent(*event, &IoEventAddress::Mmio(*addr))
+            {
+                register(&old_ioeventfds[..i]);
+                return Err(io::Error::from_raw_os_error(e.errno()));
+            }
+        }
+        for (i, (event, addr)) in new_ioeventfds.iter().enumerate() {
+            if let Err(e) =
+                self.vm_fd
+                    .register_ioevent(*event, &IoEventAddress::Mmio(*addr), NoDatamatch)
+            {
+                unregister(&new_ioeventfds[..i]);
+                register(&old_ioeventfds);
+                return Err(io::Error::from_raw_os_error(e.errno()));
+            }
+        }
+
+        Ok(())
+    }
+
+    // Puts back a BAR moved by move_bar_range(), the move past it having
+    // failed.
+    fn undo_bar_range_move(
+        &self,
+        old_base: u64,
+        new_base: u64,
+        len: u64,
+        region_type: PciBarRegionType,
+    ) {
+        if let Err(e) = self.move_bar_range(new_base, old_base, len, region_type) {
+            error!("Failed moving the BAR back to 0x{:x}: {}", old_base, e);
+        }
+    }
+}
+
 #[cfg(feature = "pci_support")]
 impl DeviceRelocation for AddressManager {
     fn move_bar(
@@ -275,93 +912,112 @@ impl DeviceRelocation for AddressManager {
         pci_dev: &mut dyn PciDevice,
         region_type: PciBarRegionType,
     ) -> std::result::Result<(), std::io::Error> {
-        match region_type {
-            PciBarRegionType::IORegion => {
-                // Update system allocator
-                self.allocator
-                    .lock()
-                    .unwrap()
-                    .free_io_addresses(GuestAddress(old_base), len as GuestUsize);
-
-                self.allocator
-                    .lock()
-                    .unwrap()
-                    .allocate_io_addresses(Some(GuestAddress(new_base)), len as GuestUsize, None)
-                    .ok_or_else(|| {
-                        io::Error::new(io::ErrorKind::Other, "failed allocating new IO range")
-                    })?;
-
-                // Update PIO bus
-                self.io_bus
-                    .update_range(old_base, len, new_base, len)
-                    .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
-            }
-            PciBarRegionType::Memory32BitRegion | PciBarRegionType::Memory64BitRegion => {
-                // Update system allocator
-                self.allocator
-                    .lock()
-                    .unwrap()
-                    .free_mmio_addresses(GuestAddress(old_base), len as GuestUsize);
+        self.move_bar_range(old_base, new_base, len, region_type)?;
+
+        // The queues of a virtio-pci device are notified in its settings BAR.
+        let settings_bar = pci_dev
+            .as_any()
+            .downcast_ref::<VirtioPciDevice>()
+            .map_or(false, |virtio_pci_dev| {
+                virtio_pci_dev.config_bar_addr() == new_base
+            });
+        if settings_bar {
+            if let Err(e) = self.move_ioeventfds(pci_dev, old_base, new_base) {
+                self.undo_bar_range_move(old_base, new_base, len, region_type);
+                return Err(e);
+            }
+        }
 
-                if region_type == PciBarRegionType::Memory32BitRegion {
-                    self.allocator
-                        .lock()
-                        .unwrap()
-                        .allocate_mmio_hole_addresses(
-                            Some(GuestAddress(new_base)),
-                            len as GuestUsize,
-                            None,
-                        )
-                        .ok_or_else(|| {
-                            io::Error::new(
-                                io::ErrorKind::Other,
-                                "failed allocating new 32 bits MMIO range",
-                            )
-                        })?;
-                } else {
-                    self.allocator
-                        .lock()
-                        .unwrap()
-                        .allocate_mmio_addresses(
-                            Some(GuestAddress(new_base)),
-                            len as GuestUsize,
-                            None,
-                        )
-                        .ok_or_else(|| {
-                            io::Error::new(
-                                io::ErrorKind::Other,
-                                "failed allocating new 64 bits MMIO range",
-                            )
-                        })?;
+        if let Err(e) = pci_dev.move_bar(old_base, new_base) {
+            if settings_bar {
+                if let Err(e) = self.move_ioeventfds(pci_dev, new_base, old_base) {
+                    error!("Failed moving the ioeventfds back: {}", e);
                 }
-
-                // Update MMIO bus
-                self.mmio_bus
-                    .update_range(old_base, len, new_base, len)
-                    .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
             }
+            self.undo_bar_range_move(old_base, new_base, len, region_type);
+            return Err(e);
         }
 
-        let any_dev = pci_dev.as_any();
-        if let Some(virtio_pci_dev) = any_dev.downcast_ref::<VirtioPciDevice>() {
-            let bar_addr = virtio_pci_dev.config_bar_addr();
-            if bar_addr == new_base {
-                for (event, addr) in virtio_pci_dev.ioeventfds(old_base) {
-                    let io_addr = IoEventAddress::Mmio(addr);
-                    self.vm_fd
-                        .unregister_ioevent(event, &io_addr)
-                        .map_err(|e| io::Error::from_raw_os_error(e.errno()))?;
-                }
-                for (event, addr) in virtio_pci_dev.ioeventfds(new_base) {
-                    let io_addr = IoEventAddress::Mmio(addr);
-                    self.vm_fd
-                        .register_ioevent(event, &io_addr, NoDatamatch)
-                        .map_err(|e| io::Error::from_raw_os_error(e.errno()))?;
-                }
+        Ok(())
+    }
+}
+
+/// Reports the PCI slots being plugged and unplugged to the guest, and
+/// records the ones it has released.
+#[cfg(feature = "pci_support")]
+#[derive(Default)]
+struct PciHotplugController {
+    // Slots the guest has been asked to eject.
+    pending: u32,
+    // Slots the guest has released.
+    ejected: u32,
+    // Slots the guest has not been told about yet.
+    inserted: u32,
+}
+
+#[cfg(feature = "pci_support")]
+impl PciHotplugController {
+    fn request_insert(&mut self, slot: u32) {
+        self.inserted |= 1 << slot;
+    }
+
+    fn request_eject(&mut self, slot: u32) {
+        self.pending |= 1 << slot;
+        self.ejected &= !(1 << slot);
+    }
+
+    fn cancel_eject(&mut self, slot: u32) {
+        self.pending &= !(1 << slot);
+    }
+
+    /// Returns whether the guest released the slot, clearing its state.
+    fn take_ejected(&mut self, slot: u32) -> bool {
+        let ejected = self.ejected & (1 << slot) != 0;
+        self.ejected &= !(1 << slot);
+        ejected
+    }
+}
+
+#[cfg(feature = "pci_support")]
+impl BusDevice for PciHotplugController {
+    fn read(&mut self, _base: u64, offset: u64, data: &mut [u8]) {
+        match offset {
+            PCI_SLOTS_PENDING_OFFSET if data.len() == 4 => {
+                data.copy_from_slice(&self.pending.to_le_bytes());
+            }
+            // The guest only needs to check the new slots once.
+            PCI_SLOTS_INSERTED_OFFSET if data.len() == 4 => {
+                data.copy_from_slice(&self.inserted.to_le_bytes());
+                self.inserted = 0;
+            }
+            _ => {
+                warn!(
+                    "Unexpected access to PCI hotplug controller: offset {:#x}, length {}",
+                    offset,
+                    data.len()
+                );
             }
         }
+    }
 
-        pci_dev.move_bar(old_base, new_base)
+    fn write(&mut self, _base: u64, offset: u64, data: &[u8]) {
+        match offset {
+            PCI_SLOTS_EJECTED_OFFSET if data.len() == 4 => {
+                let mut bytes = [0u8; 4];
+                bytes.copy_from_slice(data);
+                // The guest can only release slots it was asked to eject.
+                let slots = u32::from_le_bytes(bytes) & self.pending;
+                self.pending &= !slots;
+                self.ejected |= slots;
+            }
+            _ => {
+                warn!(
+                    "Unexpected access to PCI hotplug controller: offset {:#x}, length {}",
+                    offset,
+                    data.len()
+                );
+            }
+        }
     }
 }
 
@@ -372,6 +1028,9 @@ pub struct DeviceManager {
     // Console abstraction
     console: Arc<Console>,
 
+    // Output of the serial port, flushed when its destination is writable
+    serial_buffer: Option<SerialBuffer>,
+
     // IOAPIC
     ioapic: Option<Arc<Mutex<ioapic::Ioapic>>>,
 
@@ -384,6 +1043,18 @@ pub struct DeviceManager {
     // Things to be added to the commandline (i.e. for virtio-mmio)
     cmdline_additions: Vec<String>,
 
+    // Devices described in the device tree, rather than on the commandline
+    #[cfg(target_arch = "aarch64")]
+    mmio_device_info: Vec<MmioDeviceInfo>,
+
+    // PL011 UART described in the device tree
+    #[cfg(target_arch = "aarch64")]
+    serial_device_info: Option<MmioDeviceInfo>,
+
+    // PL031 RTC described in the device tree
+    #[cfg(target_arch = "aarch64")]
+    rtc_device_info: Option<MmioDeviceInfo>,
+
     // ACPI GED notification device
     #[cfg(feature = "acpi")]
     ged_notification_device: Option<Arc<Mutex<devices::AcpiGEDDevice>>>,
@@ -394,30 +1065,83 @@ pub struct DeviceManager {
     // Migratable devices
     migratable_devices: Vec<Arc<Mutex<dyn Migratable>>>,
 
+    // Devices of the VM, by id
+    device_registry: DeviceRegistry,
+
     // Memory Manager
     memory_manager: Arc<Mutex<MemoryManager>>,
-}
 
-impl DeviceManager {
-    pub fn new(
-        vm_fd: Arc<VmFd>,
-        config: Arc<Mutex<VmConfig>>,
-        allocator: Arc<Mutex<SystemAllocator>>,
-        memory_manager: Arc<Mutex<MemoryManager>>,
-        _exit_evt: &EventFd,
-        reset_evt: &EventFd,
-    ) -> DeviceManagerResult<Self> {
-        let io_bus = devices::Bus::new();
-        let mmio_bus = devices::Bus::new();
+    // Resizable block devices, indexed like the disks from the VM config.
+    // vhost-user-blk devices are not resizable and have no entry.
+    block_devices: Vec<Option<Arc<Mutex<dyn vm_virtio::BlockResize>>>>,
 
-        let mut virtio_devices: Vec<(Arc<Mutex<dyn vm_virtio::VirtioDevice>>, bool)> = Vec::new();
-        let migratable_devices: Vec<Arc<Mutex<dyn Migratable>>> = Vec::new();
-        let mut _mmap_regions = Vec::new();
+    // virtio-net devices, which may run their datapath in vhost-net.
+    net_devices: Vec<(String, Arc<Mutex<vm_virtio::Net>>)>,
 
-        #[allow(unused_mut)]
-        let mut cmdline_additions = Vec::new();
+    // Balloon device, reporting the guest memory statistics.
+    balloon: Option<Arc<Mutex<vm_virtio::Balloon>>>,
 
-        let address_manager = Arc::new(AddressManager {
+    // GPU device, holding the framebuffer of the guest.
+    gpu: Option<Arc<Mutex<vm_virtio::gpu::Gpu>>>,
+
+    // Events sent to the input device, which only exists along with a VNC
+    // server.
+    input_events: Option<vm_virtio::input::InputEvents>,
+
+    // PS/2 keyboard controller, x86 only.
+    i8042: Option<Arc<Mutex<devices::legacy::I8042Device>>>,
+
+    // PCI bus, shared with the configuration space access mechanisms
+    #[cfg(feature = "pci_support")]
+    pci_bus: Option<Arc<Mutex<PciBus>>>,
+
+    // virtio-pci devices, indexed by their PCI device id
+    #[cfg(feature = "pci_support")]
+    virtio_pci_devices: HashMap<u32, Arc<Mutex<VirtioPciDevice>>>,
+
+    // PCI hotplug controller
+    #[cfg(feature = "pci_support")]
+    pci_hotplug_controller: Arc<Mutex<PciHotplugController>>,
+
+    // MSI interrupt manager, for the devices plugged at runtime
+    #[cfg(feature = "pci_support")]
+    msi_interrupt_manager: Arc<dyn InterruptManager<GroupConfig = MsiIrqGroupConfig>>,
+
+    // VFIO containers with the whole guest memory mapped for DMA
+    #[cfg(feature = "pci_support")]
+    vfio_containers: Vec<Arc<VfioContainer>>,
+
+    // Devices being unplugged, moved forward on the ticks of the removal
+    // timer, which is only armed while there are some.
+    #[cfg(feature = "pci_support")]
+    device_removals: Vec<DeviceRemoval>,
+    removal_timer: TimerFd,
+}
+
+impl DeviceManager {
+    pub fn new(
+        vm_fd: Arc<VmFd>,
+        config: Arc<Mutex<VmConfig>>,
+        allocator: Arc<Mutex<SystemAllocator>>,
+        memory_manager: Arc<Mutex<MemoryManager>>,
+        _exit_evt: &EventFd,
+        reset_evt: &EventFd,
+    ) -> DeviceManagerResult<Self> {
+        let io_bus = devices::Bus::new();
+        let mmio_bus = devices::Bus::new();
+
+        // The devices keep the ids they are given across reboots.
+        config.lock().unwrap().assign_device_ids();
+
+        let mut virtio_devices: Vec<(VirtioDeviceArc, bool, String)> = Vec::new();
+        #[allow(unused_mut)]
+        let mut migratable_devices: Vec<Arc<Mutex<dyn Migratable>>> = Vec::new();
+        let mut _mmap_regions = Vec::new();
+
+        #[allow(unused_mut)]
+        let mut cmdline_additions = Vec::new();
+
+        let address_manager = Arc::new(AddressManager {
             allocator,
             io_bus: Arc::new(io_bus),
             mmio_bus: Arc::new(mmio_bus),
@@ -431,6 +1155,24 @@ impl DeviceManager {
         let kvm_gsi_msi_routes: Arc<Mutex<HashMap<u32, KvmRoutingEntry>>> =
             Arc::new(Mutex::new(HashMap::new()));
 
+        // Without an irqchip, no interrupt gets routed nor injected.
+        #[cfg(target_arch = "x86_64")]
+        let irqchip_kind = config.lock().unwrap().create_irqchip_kind;
+        #[cfg(target_arch = "x86_64")]
+        let irqchip = irqchip_kind != IrqChipKind::None;
+        #[cfg(target_arch = "aarch64")]
+        let irqchip = true;
+
+        // With a kernel irqchip, the legacy GSIs must keep reaching the
+        // in-kernel IOAPIC once the MSI routes get set.
+        #[cfg(target_arch = "x86_64")]
+        if irqchip_kind == IrqChipKind::Kernel {
+            let mut routes = kvm_gsi_msi_routes.lock().unwrap();
+            for gsi in 0..ioapic::NUM_IOAPIC_PINS as u32 {
+                routes.insert(gsi, KvmRoutingEntry::ioapic_pin(gsi));
+            }
+        }
+
         // First we create the MSI interrupt manager, the legacy one is created
         // later, after the IOAPIC device creation.
         // The reason we create the MSI one first is because the IOAPIC needs it,
@@ -442,16 +1184,47 @@ impl DeviceManager {
                 Arc::clone(&address_manager.allocator),
                 vm_fd,
                 Arc::clone(&kvm_gsi_msi_routes),
+                irqchip,
             ));
 
-        let ioapic =
-            DeviceManager::add_ioapic(&address_manager, Arc::clone(&msi_interrupt_manager))?;
-
         // Now we can create the legacy interrupt manager, which needs the freshly
-        // formed IOAPIC device.
-        let legacy_interrupt_manager: Arc<
-            dyn InterruptManager<GroupConfig = LegacyIrqGroupConfig>,
-        > = Arc::new(KvmLegacyUserspaceInterruptManager::new(ioapic.clone()));
+        // formed IOAPIC device. The IOAPIC is only emulated here with a split
+        // irqchip, otherwise the lines of the in-kernel irqchip are raised
+        // directly.
+        #[cfg(target_arch = "x86_64")]
+        let (ioapic, legacy_interrupt_manager): (
+            _,
+            Arc<dyn InterruptManager<GroupConfig = LegacyIrqGroupConfig>>,
+        ) = if irqchip_kind == IrqChipKind::Split {
+            let ioapic =
+                DeviceManager::add_ioapic(&address_manager, Arc::clone(&msi_interrupt_manager))?;
+            migratable_devices.push(Arc::clone(&ioapic) as Arc<Mutex<dyn Migratable>>);
+            (
+                Some(ioapic.clone()),
+                Arc::new(KvmLegacyUserspaceInterruptManager::new(ioapic)),
+            )
+        } else {
+            (
+                None,
+                Arc::new(KvmLegacyInterruptManager::new(
+                    address_manager.vm_fd.clone(),
+                    irqchip,
+                )),
+            )
+        };
+
+        // On aarch64 the devices raise the SPIs of the in-kernel GIC.
+        #[cfg(target_arch = "aarch64")]
+        let (ioapic, legacy_interrupt_manager): (
+            _,
+            Arc<dyn InterruptManager<GroupConfig = LegacyIrqGroupConfig>>,
+        ) = (
+            None,
+            Arc::new(KvmLegacyInterruptManager::new(
+                address_manager.vm_fd.clone(),
+                irqchip,
+            )),
+        );
 
         #[cfg(feature = "acpi")]
         address_manager
@@ -470,18 +1243,51 @@ impl DeviceManager {
         let mut device_manager = DeviceManager {
             address_manager,
             console: Arc::new(Console::default()),
-            ioapic: Some(ioapic),
+            serial_buffer: None,
+            ioapic,
             _mmap_regions,
             cmdline_additions,
+            #[cfg(target_arch = "aarch64")]
+            mmio_device_info: Vec::new(),
+            #[cfg(target_arch = "aarch64")]
+            serial_device_info: None,
+            #[cfg(target_arch = "aarch64")]
+            rtc_device_info: None,
             #[cfg(feature = "acpi")]
             ged_notification_device: None,
             config,
             migratable_devices,
+            device_registry: DeviceRegistry::new(),
             memory_manager,
+            block_devices: Vec::new(),
+            net_devices: Vec::new(),
+            balloon: None,
+            gpu: None,
+            input_events: None,
+            i8042: None,
+            #[cfg(feature = "pci_support")]
+            pci_bus: None,
+            #[cfg(feature = "pci_support")]
+            virtio_pci_devices: HashMap::new(),
+            #[cfg(feature = "pci_support")]
+            pci_hotplug_controller: Arc::new(Mutex::new(PciHotplugController::default())),
+            #[cfg(feature = "pci_support")]
+            msi_interrupt_manager: Arc::clone(&msi_interrupt_manager),
+            #[cfg(feature = "pci_support")]
+            vfio_containers: Vec::new(),
+            #[cfg(feature = "pci_support")]
+            device_removals: Vec::new(),
+            removal_timer: TimerFd::new().map_err(DeviceManagerError::DeviceRemovalTimer)?,
         };
 
-        device_manager
-            .add_legacy_devices(reset_evt.try_clone().map_err(DeviceManagerError::EventFd)?)?;
+        #[cfg(target_arch = "x86_64")]
+        device_manager.add_legacy_devices(
+            reset_evt.try_clone().map_err(DeviceManagerError::EventFd)?,
+            &legacy_interrupt_manager,
+        )?;
+
+        #[cfg(target_arch = "aarch64")]
+        device_manager.add_legacy_devices(&legacy_interrupt_manager)?;
 
         #[cfg(feature = "acpi")]
         {
@@ -490,6 +1296,7 @@ impl DeviceManager {
                 reset_evt.try_clone().map_err(DeviceManagerError::EventFd)?,
                 _exit_evt.try_clone().map_err(DeviceManagerError::EventFd)?,
             )?;
+            device_manager.write_generation_id()?;
         }
 
         device_manager.console =
@@ -499,7 +1306,12 @@ impl DeviceManager {
         virtio_devices.append(&mut device_manager.make_virtio_devices()?);
 
         if cfg!(feature = "pci_support") {
-            device_manager.add_pci_devices(virtio_devices, &msi_interrupt_manager)?;
+            device_manager.add_pci_devices(
+                virtio_devices,
+                &msi_interrupt_manager,
+                reset_evt,
+                _exit_evt,
+            )?;
         } else if cfg!(feature = "mmio_support") {
             device_manager.add_mmio_devices(virtio_devices, &legacy_interrupt_manager)?;
         }
@@ -510,8 +1322,10 @@ impl DeviceManager {
     #[allow(unused_variables)]
     fn add_pci_devices(
         &mut self,
-        virtio_devices: Vec<(Arc<Mutex<dyn vm_virtio::VirtioDevice>>, bool)>,
+        virtio_devices: Vec<(VirtioDeviceArc, bool, String)>,
         interrupt_manager: &Arc<dyn InterruptManager<GroupConfig = MsiIrqGroupConfig>>,
+        reset_evt: &EventFd,
+        exit_evt: &EventFd,
     ) -> DeviceManagerResult<()> {
         #[cfg(feature = "pci_support")]
         {
@@ -521,6 +1335,14 @@ impl DeviceManager {
                 Arc::downgrade(&self.address_manager) as Weak<dyn DeviceRelocation>,
             );
 
+            // Keep the slots the devices asked for out of the ones the others
+            // are given.
+            for slot in self.config.lock().unwrap().pci_slots() {
+                pci_bus
+                    .reserve_device_id(slot)
+                    .map_err(DeviceManagerError::PciSlot)?;
+            }
+
             let (mut iommu_device, iommu_mapping) = if self.config.lock().unwrap().iommu {
                 let (device, mapping) =
                     vm_virtio::Iommu::new().map_err(DeviceManagerError::CreateVirtioIommu)?;
@@ -531,18 +1353,25 @@ impl DeviceManager {
 
             let mut iommu_attached_devices = Vec::new();
 
-            for (device, iommu_attached) in virtio_devices {
+            for (device, iommu_attached, id) in virtio_devices {
                 let mapping: &Option<Arc<IommuMapping>> = if iommu_attached {
                     &iommu_mapping
                 } else {
                     &None
                 };
 
-                let virtio_iommu_attach_dev =
-                    self.add_virtio_pci_device(device, &mut pci_bus, mapping, interrupt_manager)?;
+                let pci_slot = self.config.lock().unwrap().pci_slot(&id);
+                let pci_device_id = self.add_virtio_pci_device(
+                    device,
+                    &mut pci_bus,
+                    mapping,
+                    interrupt_manager,
+                    &id,
+                    pci_slot,
+                )?;
 
-                if let Some(dev_id) = virtio_iommu_attach_dev {
-                    iommu_attached_devices.push(dev_id);
+                if mapping.is_some() {
+                    iommu_attached_devices.push(pci_device_id << 3);
                 }
             }
 
@@ -562,16 +1391,20 @@ impl DeviceManager {
                     &mut pci_bus,
                     &None,
                     interrupt_manager,
+                    "_iommu0",
+                    None,
                 )?;
             }
 
+            self.add_watchdog(&mut pci_bus, reset_evt, exit_evt)?;
+
             let pci_bus = Arc::new(Mutex::new(pci_bus));
             let pci_config_io = Arc::new(Mutex::new(PciConfigIo::new(pci_bus.clone())));
             self.address_manager
                 .io_bus
                 .insert(pci_config_io, 0xcf8, 0x8)
                 .map_err(DeviceManagerError::BusError)?;
-            let pci_config_mmio = Arc::new(Mutex::new(PciConfigMmio::new(pci_bus)));
+            let pci_config_mmio = Arc::new(Mutex::new(PciConfigMmio::new(pci_bus.clone())));
             self.address_manager
                 .mmio_bus
                 .insert(
@@ -580,6 +1413,20 @@ impl DeviceManager {
                     arch::layout::PCI_MMCONFIG_SIZE,
                 )
                 .map_err(DeviceManagerError::BusError)?;
+
+            self.address_manager
+                .allocator
+                .lock()
+                .unwrap()
+                .allocate_io_addresses(Some(GuestAddress(0xae00)), 0xc, None)
+                .ok_or(DeviceManagerError::AllocateIOPort)?;
+
+            self.address_manager
+                .io_bus
+                .insert(self.pci_hotplug_controller.clone(), 0xae00, 0xc)
+                .map_err(DeviceManagerError::BusError)?;
+
+            self.pci_bus = Some(pci_bus);
         }
 
         Ok(())
@@ -588,20 +1435,24 @@ impl DeviceManager {
     #[allow(unused_variables, unused_mut)]
     fn add_mmio_devices(
         &mut self,
-        virtio_devices: Vec<(Arc<Mutex<dyn vm_virtio::VirtioDevice>>, bool)>,
+        virtio_devices: Vec<(VirtioDeviceArc, bool, String)>,
         interrupt_manager: &Arc<dyn InterruptManager<GroupConfig = LegacyIrqGroupConfig>>,
     ) -> DeviceManagerResult<()> {
         #[cfg(feature = "mmio_support")]
         {
-            for (device, _) in virtio_devices {
+            if self.config.lock().unwrap().watchdog.is_some() {
+                warn!("The watchdog is a PCI device, it is not created without PCI support");
+            }
+
+            for (device, _, id) in virtio_devices {
                 let mmio_addr = self
                     .address_manager
                     .allocator
                     .lock()
                     .unwrap()
-                    .allocate_mmio_addresses(None, MMIO_LEN, Some(MMIO_LEN));
+                    .allocate_high_mmio_addresses(None, MMIO_LEN, Some(MMIO_LEN));
                 if let Some(addr) = mmio_addr {
-                    self.add_virtio_mmio_device(device, interrupt_manager, addr)?;
+                    self.add_virtio_mmio_device(device, interrupt_manager, addr, &id)?;
                 } else {
                     error!("Unable to allocate MMIO address!");
                 }
@@ -611,6 +1462,7 @@ impl DeviceManager {
         Ok(())
     }
 
+    #[cfg(target_arch = "x86_64")]
     fn add_ioapic(
         address_manager: &Arc<AddressManager>,
         interrupt_manager: Arc<dyn InterruptManager<GroupConfig = MsiIrqGroupConfig>>,
@@ -685,14 +1537,41 @@ impl DeviceManager {
         Ok(Some(ged_device))
     }
 
-    fn add_legacy_devices(&mut self, reset_evt: EventFd) -> DeviceManagerResult<()> {
-        // Add a shutdown device (i8042)
-        let i8042 = Arc::new(Mutex::new(devices::legacy::I8042Device::new(reset_evt)));
+    #[cfg(target_arch = "x86_64")]
+    fn add_legacy_devices(
+        &mut self,
+        reset_evt: EventFd,
+        interrupt_manager: &Arc<dyn InterruptManager<GroupConfig = LegacyIrqGroupConfig>>,
+    ) -> DeviceManagerResult<()> {
+        // Add a shutdown device and keyboard (i8042), the keyboard being
+        // tied to IRQ #1
+        let interrupt_group = interrupt_manager
+            .create_group(LegacyIrqGroupConfig { irq: 1 })
+            .map_err(DeviceManagerError::CreateInterruptGroup)?;
+        let i8042 = Arc::new(Mutex::new(devices::legacy::I8042Device::new(
+            reset_evt,
+            interrupt_group,
+        )));
 
         self.address_manager
             .io_bus
-            .insert(i8042, 0x61, 0x4)
+            .insert(i8042.clone(), 0x60, 0x5)
             .map_err(DeviceManagerError::BusError)?;
+        self.migratable_devices
+            .push(Arc::clone(&i8042) as Arc<Mutex<dyn Migratable>>);
+        self.i8042 = Some(i8042);
+
+        // KVM only emulates the PICs along with the whole irqchip. Otherwise,
+        // the guest finds PICs it can't unmask and relies on the IOAPIC.
+        if self.config.lock().unwrap().create_irqchip_kind == IrqChipKind::Split {
+            for base in [0x20, 0xa0].iter() {
+                let pic = Arc::new(Mutex::new(devices::legacy::Pic::new()));
+                self.address_manager
+                    .io_bus
+                    .insert(pic, *base, 0x2)
+                    .map_err(DeviceManagerError::BusError)?;
+            }
+        }
         #[cfg(feature = "cmos")]
         {
             // Add a CMOS emulated device
@@ -706,7 +1585,8 @@ impl DeviceManager {
                 .last_addr()
                 .0
                 + 1;
-            let mem_below_4g = std::cmp::min(arch::layout::MEM_32BIT_RESERVED_START.0, mem_size);
+            let mmio_hole = self.memory_manager.lock().unwrap().mmio_hole();
+            let mem_below_4g = std::cmp::min(mmio_hole.start.raw_value(), mem_size);
             let mem_above_4g = mem_size.saturating_sub(arch::layout::RAM_64BIT_START.0);
 
             let cmos = Arc::new(Mutex::new(devices::legacy::Cmos::new(
@@ -716,27 +1596,87 @@ impl DeviceManager {
 
             self.address_manager
                 .io_bus
-                .insert(cmos, 0x70, 0x2)
+                .insert(cmos.clone(), 0x70, 0x2)
                 .map_err(DeviceManagerError::BusError)?;
+            self.migratable_devices
+                .push(cmos as Arc<Mutex<dyn Migratable>>);
         }
 
         Ok(())
     }
 
+    #[cfg(target_arch = "aarch64")]
+    fn add_legacy_devices(
+        &mut self,
+        interrupt_manager: &Arc<dyn InterruptManager<GroupConfig = LegacyIrqGroupConfig>>,
+    ) -> DeviceManagerResult<()> {
+        // Add a PL031 RTC, for the guest to get the wall clock time
+        let rtc_irq = self
+            .address_manager
+            .allocator
+            .lock()
+            .unwrap()
+            .allocate_irq()
+            .ok_or(DeviceManagerError::AllocateIrq)?;
+
+        let interrupt_group = interrupt_manager
+            .create_group(LegacyIrqGroupConfig {
+                irq: rtc_irq as InterruptIndex,
+            })
+            .map_err(DeviceManagerError::CreateInterruptGroup)?;
+
+        let rtc = Arc::new(Mutex::new(devices::legacy::Rtc::new(interrupt_group)));
+
+        let rtc_addr = arch::layout::LEGACY_RTC_MAPPED_IO_START.0;
+        self.address_manager
+            .mmio_bus
+            .insert(rtc, rtc_addr, devices::legacy::PL031_SIZE)
+            .map_err(DeviceManagerError::BusError)?;
+
+        self.rtc_device_info = Some(MmioDeviceInfo {
+            addr: rtc_addr,
+            len: devices::legacy::PL031_SIZE,
+            irq: rtc_irq,
+        });
+
+        Ok(())
+    }
+
     fn add_console_device(
         &mut self,
         interrupt_manager: &Arc<dyn InterruptManager<GroupConfig = LegacyIrqGroupConfig>>,
-        virtio_devices: &mut Vec<(Arc<Mutex<dyn vm_virtio::VirtioDevice>>, bool)>,
+        virtio_devices: &mut Vec<(VirtioDeviceArc, bool, String)>,
     ) -> DeviceManagerResult<Arc<Console>> {
         let serial_config = self.config.lock().unwrap().serial.clone();
-        let serial_writer: Option<Box<dyn io::Write + Send>> = match serial_config.mode {
-            ConsoleOutputMode::File => Some(Box::new(
-                File::create(serial_config.file.as_ref().unwrap())
-                    .map_err(DeviceManagerError::SerialOutputFileOpen)?,
-            )),
-            ConsoleOutputMode::Tty => Some(Box::new(stdout())),
+        let buffer_size = serial_config.buffer_size as usize;
+        let serial_writer: Option<Box<dyn SerialOutput>> = match serial_config.mode {
+            ConsoleOutputMode::File => {
+                let file = File::create(serial_config.file.as_ref().unwrap()).map_err(|e| {
+                    DeviceManagerError::SerialOutputFileOpen(serial_config.file.clone().unwrap(), e)
+                })?;
+                let serial_buffer = SerialBuffer::new(file, buffer_size)
+                    .map_err(DeviceManagerError::SerialBuffer)?;
+                self.serial_buffer = Some(serial_buffer.clone());
+                Some(Box::new(serial_buffer))
+            }
+            ConsoleOutputMode::Tty => {
+                match serial_buffer::reopen_stdout()
+                    .and_then(|out| SerialBuffer::new(out, buffer_size))
+                {
+                    Ok(serial_buffer) => {
+                        self.serial_buffer = Some(serial_buffer.clone());
+                        Some(Box::new(serial_buffer))
+                    }
+                    Err(e) => {
+                        // Blocking on a busy stdout beats losing the output.
+                        warn!("Cannot buffer the serial output: {}", e);
+                        Some(Box::new(stdout()))
+                    }
+                }
+            }
             ConsoleOutputMode::Off | ConsoleOutputMode::Null => None,
         };
+        #[cfg(target_arch = "x86_64")]
         let serial = if serial_config.mode != ConsoleOutputMode::Off {
             // Serial is tied to IRQ #4
             let serial_irq = 4;
@@ -763,6 +1703,46 @@ impl DeviceManager {
                 .io_bus
                 .insert(serial.clone(), 0x3f8, 0x8)
                 .map_err(DeviceManagerError::BusError)?;
+            self.migratable_devices
+                .push(Arc::clone(&serial) as Arc<Mutex<dyn Migratable>>);
+
+            Some(serial)
+        } else {
+            None
+        };
+
+        #[cfg(target_arch = "aarch64")]
+        let serial = if serial_config.mode != ConsoleOutputMode::Off {
+            let serial_irq = self
+                .address_manager
+                .allocator
+                .lock()
+                .unwrap()
+                .allocate_irq()
+                .ok_or(DeviceManagerError::AllocateIrq)?;
+
+            let interrupt_group = interrupt_manager
+                .create_group(LegacyIrqGroupConfig {
+                    irq: serial_irq as InterruptIndex,
+                })
+                .map_err(DeviceManagerError::CreateInterruptGroup)?;
+
+            let serial = Arc::new(Mutex::new(devices::legacy::Pl011::new(
+                interrupt_group,
+                serial_writer,
+            )));
+
+            let serial_addr = arch::layout::LEGACY_SERIAL_MAPPED_IO_START.0;
+            self.address_manager
+                .mmio_bus
+                .insert(serial.clone(), serial_addr, devices::legacy::PL011_SIZE)
+                .map_err(DeviceManagerError::BusError)?;
+
+            self.serial_device_info = Some(MmioDeviceInfo {
+                addr: serial_addr,
+                len: devices::legacy::PL011_SIZE,
+                irq: serial_irq,
+            });
 
             Some(serial)
         } else {
@@ -773,8 +1753,12 @@ impl DeviceManager {
         let console_config = self.config.lock().unwrap().console.clone();
         let console_writer: Option<Box<dyn io::Write + Send + Sync>> = match console_config.mode {
             ConsoleOutputMode::File => Some(Box::new(
-                File::create(console_config.file.as_ref().unwrap())
-                    .map_err(DeviceManagerError::ConsoleOutputFileOpen)?,
+                File::create(console_config.file.as_ref().unwrap()).map_err(|e| {
+                    DeviceManagerError::ConsoleOutputFileOpen(
+                        console_config.file.clone().unwrap(),
+                        e,
+                    )
+                })?,
             )),
             ConsoleOutputMode::Tty => Some(Box::new(stdout())),
             ConsoleOutputMode::Null => Some(Box::new(sink())),
@@ -789,6 +1773,7 @@ impl DeviceManager {
                 Arc::new(Mutex::new(virtio_console_device))
                     as Arc<Mutex<dyn vm_virtio::VirtioDevice>>,
                 false,
+                "_console0".to_string(),
             ));
             Some(console_input)
         } else {
@@ -803,20 +1788,32 @@ impl DeviceManager {
         }))
     }
 
-    fn make_virtio_devices(&mut self) -> DeviceManagerResult<Vec<(VirtioDeviceArc, bool)>> {
-        let mut devices: Vec<(Arc<Mutex<dyn vm_virtio::VirtioDevice>>, bool)> = Vec::new();
+    fn make_virtio_devices(&mut self) -> DeviceManagerResult<Vec<(VirtioDeviceArc, bool, String)>> {
+        let mut devices: Vec<(VirtioDeviceArc, bool, String)> = Vec::new();
 
         // Create "standard" virtio devices (net/block/rng)
         devices.append(&mut self.make_virtio_block_devices()?);
         devices.append(&mut self.make_virtio_net_devices()?);
         devices.append(&mut self.make_virtio_rng_devices()?);
 
+        // Add virtio-balloon if required
+        devices.append(&mut self.make_virtio_balloon_devices()?);
+
+        // Add virtio-gpu if required
+        devices.append(&mut self.make_virtio_gpu_devices()?);
+
+        // Add virtio-input if required
+        devices.append(&mut self.make_virtio_input_devices()?);
+
         // Add virtio-fs if required
         devices.append(&mut self.make_virtio_fs_devices()?);
 
         // Add virtio-pmem if required
         devices.append(&mut self.make_virtio_pmem_devices()?);
 
+        // Add virtio-scsi if required
+        devices.append(&mut self.make_virtio_scsi_devices()?);
+
         // Add virtio-vhost-user-net if required
         devices.append(&mut self.make_virtio_vhost_user_net_devices()?);
 
@@ -829,174 +1826,255 @@ impl DeviceManager {
         Ok(devices)
     }
 
-    fn make_virtio_block_devices(&mut self) -> DeviceManagerResult<Vec<(VirtioDeviceArc, bool)>> {
+    fn make_virtio_block_devices(
+        &mut self,
+    ) -> DeviceManagerResult<Vec<(VirtioDeviceArc, bool, String)>> {
         let mut devices = Vec::new();
 
-        if let Some(disk_list_cfg) = &self.config.lock().unwrap().disks {
+        let disks = self.config.lock().unwrap().disks.clone();
+        if let Some(disk_list_cfg) = &disks {
             for disk_cfg in disk_list_cfg.iter() {
-                if disk_cfg.vhost_user {
-                    let vu_cfg = VhostUserConfig {
-                        sock: disk_cfg.vhost_socket.clone().unwrap(),
-                        num_queues: disk_cfg.num_queues,
-                        queue_size: disk_cfg.queue_size,
-                    };
-                    let vhost_user_block_device = Arc::new(Mutex::new(
-                        vm_virtio::vhost_user::Blk::new(disk_cfg.wce, vu_cfg)
-                            .map_err(DeviceManagerError::CreateVhostUserBlk)?,
-                    ));
+                devices.push(self.make_virtio_block_device(disk_cfg)?);
+            }
+        }
 
-                    devices.push((
-                        Arc::clone(&vhost_user_block_device)
-                            as Arc<Mutex<dyn vm_virtio::VirtioDevice>>,
-                        false,
-                    ));
+        Ok(devices)
+    }
 
-                    self.migratable_devices
-                        .push(Arc::clone(&vhost_user_block_device) as Arc<Mutex<dyn Migratable>>);
-                } else {
-                    let mut options = OpenOptions::new();
-                    options.read(true);
-                    options.write(!disk_cfg.readonly);
-                    if disk_cfg.direct {
-                        options.custom_flags(libc::O_DIRECT);
-                    }
-                    // Open block device path
-                    let image: File = options
-                        .open(&disk_cfg.path)
-                        .map_err(DeviceManagerError::Disk)?;
-
-                    let mut raw_img = vm_virtio::RawFile::new(image, disk_cfg.direct);
-
-                    let image_type = qcow::detect_image_type(&mut raw_img)
-                        .map_err(DeviceManagerError::DetectImageType)?;
-                    match image_type {
-                        ImageType::Raw => {
-                            let dev = vm_virtio::Block::new(
-                                raw_img,
-                                disk_cfg.path.clone(),
-                                disk_cfg.readonly,
-                                disk_cfg.iommu,
-                                disk_cfg.num_queues,
-                                disk_cfg.queue_size,
-                            )
-                            .map_err(DeviceManagerError::CreateVirtioBlock)?;
+    fn make_virtio_block_device(
+        &mut self,
+        disk_cfg: &DiskConfig,
+    ) -> DeviceManagerResult<(VirtioDeviceArc, bool, String)> {
+        let id = assigned_id(&disk_cfg.id);
+        if disk_cfg.vhost_user {
+            check_vhost_user_memory(&self.config.lock().unwrap().memory)?;
+            let vu_cfg = VhostUserConfig {
+                sock: disk_cfg.vhost_socket.clone().unwrap(),
+                num_queues: disk_cfg.num_queues,
+                queue_size: disk_cfg.queue_size,
+            };
+            let vhost_user_block_device = Arc::new(Mutex::new(
+                vm_virtio::vhost_user::Blk::new(disk_cfg.wce, vu_cfg)
+                    .map_err(DeviceManagerError::CreateVhostUserBlk)?,
+            ));
 
-                            let block = Arc::new(Mutex::new(dev));
+            self.migratable_devices
+                .push(Arc::clone(&vhost_user_block_device) as Arc<Mutex<dyn Migratable>>);
+            self.block_devices.push(None);
 
-                            devices.push((
-                                Arc::clone(&block) as Arc<Mutex<dyn vm_virtio::VirtioDevice>>,
-                                disk_cfg.iommu,
-                            ));
-                            self.migratable_devices
-                                .push(Arc::clone(&block) as Arc<Mutex<dyn Migratable>>);
+            Ok((
+                Arc::clone(&vhost_user_block_device) as Arc<Mutex<dyn vm_virtio::VirtioDevice>>,
+                false,
+                id,
+            ))
+        } else {
+            // A disk inherited as a file descriptor is named after it.
+            let disk_path = match disk_cfg.fd {
+                Some(fd) => inherited_fd::path(fd),
+                None => disk_cfg.path.clone(),
+            };
+            let custom_flags = if disk_cfg.direct { libc::O_DIRECT } else { 0 };
+            let image: File = match disk_cfg.fd {
+                Some(fd) => inherited_fd::open(fd, true, !disk_cfg.readonly, custom_flags),
+                // Open block device path
+                None => OpenOptions::new()
+                    .read(true)
+                    .write(!disk_cfg.readonly)
+                    .custom_flags(custom_flags)
+                    .open(&disk_cfg.path),
+            }
+            .map_err(|e| DeviceManagerError::Disk(disk_path.clone(), e))?;
+
+            let mut raw_img = vm_virtio::RawFile::new(image, disk_cfg.direct);
+
+            let image_type = qcow::detect_image_type(&mut raw_img)
+                .map_err(DeviceManagerError::DetectImageType)?;
+            match image_type {
+                ImageType::Raw => {
+                    let mut dev = vm_virtio::Block::new(
+                        raw_img,
+                        disk_path.clone(),
+                        disk_cfg.readonly,
+                        disk_cfg.iommu,
+                        disk_cfg.num_queues,
+                        disk_cfg.queue_size,
+                    )
+                    .map_err(DeviceManagerError::CreateVirtioBlock)?;
+                    dev.set_metrics_id(&id);
+                    dev.set_request_timeout(disk_cfg.request_timeout.map(Duration::from_millis));
+                    dev.set_serial(disk_cfg.serial.clone());
+                    // Requests submitted through io_uring can't be bounded.
+                    #[cfg(feature = "io_uring")]
+                    {
+                        if disk_cfg.request_timeout.is_none() && dev.enable_io_uring() {
+                            info!("Using io_uring for disk {:?}", disk_path);
                         }
-                        ImageType::Qcow2 => {
-                            let qcow_img = QcowFile::from(raw_img)
-                                .map_err(DeviceManagerError::QcowDeviceCreate)?;
-                            let dev = vm_virtio::Block::new(
-                                qcow_img,
-                                disk_cfg.path.clone(),
-                                disk_cfg.readonly,
-                                disk_cfg.iommu,
-                                disk_cfg.num_queues,
-                                disk_cfg.queue_size,
-                            )
-                            .map_err(DeviceManagerError::CreateVirtioBlock)?;
+                    }
 
-                            let block = Arc::new(Mutex::new(dev));
+                    let block = Arc::new(Mutex::new(dev));
 
-                            devices.push((
-                                Arc::clone(&block) as Arc<Mutex<dyn vm_virtio::VirtioDevice>>,
-                                disk_cfg.iommu,
-                            ));
-                            self.migratable_devices
-                                .push(Arc::clone(&block) as Arc<Mutex<dyn Migratable>>);
-                        }
-                    };
+                    self.migratable_devices
+                        .push(Arc::clone(&block) as Arc<Mutex<dyn Migratable>>);
+                    self.block_devices.push(Some(
+                        Arc::clone(&block) as Arc<Mutex<dyn vm_virtio::BlockResize>>
+                    ));
+
+                    Ok((
+                        Arc::clone(&block) as Arc<Mutex<dyn vm_virtio::VirtioDevice>>,
+                        disk_cfg.iommu,
+                        id,
+                    ))
+                }
+                ImageType::Qcow2 => {
+                    let qcow_img =
+                        QcowFile::from(raw_img).map_err(DeviceManagerError::QcowDeviceCreate)?;
+                    let mut dev = vm_virtio::Block::new(
+                        qcow_img,
+                        disk_path.clone(),
+                        disk_cfg.readonly,
+                        disk_cfg.iommu,
+                        disk_cfg.num_queues,
+                        disk_cfg.queue_size,
+                    )
+                    .map_err(DeviceManagerError::CreateVirtioBlock)?;
+                    dev.set_metrics_id(&id);
+                    dev.set_request_timeout(disk_cfg.request_timeout.map(Duration::from_millis));
+                    dev.set_serial(disk_cfg.serial.clone());
+
+                    let block = Arc::new(Mutex::new(dev));
+
+                    self.migratable_devices
+                        .push(Arc::clone(&block) as Arc<Mutex<dyn Migratable>>);
+                    self.block_devices.push(Some(
+                        Arc::clone(&block) as Arc<Mutex<dyn vm_virtio::BlockResize>>
+                    ));
+
+                    Ok((
+                        Arc::clone(&block) as Arc<Mutex<dyn vm_virtio::VirtioDevice>>,
+                        disk_cfg.iommu,
+                        id,
+                    ))
                 }
             }
         }
-
-        Ok(devices)
     }
 
     /// Add virto-net and vhost-user-net devices
-    fn make_virtio_net_devices(&mut self) -> DeviceManagerResult<Vec<(VirtioDeviceArc, bool)>> {
+    fn make_virtio_net_devices(
+        &mut self,
+    ) -> DeviceManagerResult<Vec<(VirtioDeviceArc, bool, String)>> {
         let mut devices = Vec::new();
 
-        if let Some(net_list_cfg) = &self.config.lock().unwrap().net {
+        let net = self.config.lock().unwrap().net.clone();
+        if let Some(net_list_cfg) = &net {
             for net_cfg in net_list_cfg.iter() {
-                if net_cfg.vhost_user {
-                    let vu_cfg = VhostUserConfig {
-                        sock: net_cfg.vhost_socket.clone().unwrap(),
-                        num_queues: net_cfg.num_queues,
-                        queue_size: net_cfg.queue_size,
-                    };
-                    let vhost_user_net_device = Arc::new(Mutex::new(
-                        vm_virtio::vhost_user::Net::new(net_cfg.mac, vu_cfg)
-                            .map_err(DeviceManagerError::CreateVhostUserNet)?,
-                    ));
-                    devices.push((
-                        Arc::clone(&vhost_user_net_device)
-                            as Arc<Mutex<dyn vm_virtio::VirtioDevice>>,
-                        net_cfg.iommu,
-                    ));
-                    self.migratable_devices
-                        .push(Arc::clone(&vhost_user_net_device) as Arc<Mutex<dyn Migratable>>);
-                } else {
-                    let virtio_net_device = if let Some(ref tap_if_name) = net_cfg.tap {
-                        Arc::new(Mutex::new(
-                            vm_virtio::Net::new(
-                                Some(tap_if_name),
-                                None,
-                                None,
-                                Some(net_cfg.mac),
-                                net_cfg.iommu,
-                                net_cfg.num_queues,
-                                net_cfg.queue_size,
-                            )
-                            .map_err(DeviceManagerError::CreateVirtioNet)?,
-                        ))
-                    } else {
-                        Arc::new(Mutex::new(
-                            vm_virtio::Net::new(
-                                None,
-                                Some(net_cfg.ip),
-                                Some(net_cfg.mask),
-                                Some(net_cfg.mac),
-                                net_cfg.iommu,
-                                net_cfg.num_queues,
-                                net_cfg.queue_size,
-                            )
-                            .map_err(DeviceManagerError::CreateVirtioNet)?,
-                        ))
-                    };
-                    devices.push((
-                        Arc::clone(&virtio_net_device) as Arc<Mutex<dyn vm_virtio::VirtioDevice>>,
-                        net_cfg.iommu,
-                    ));
-                    self.migratable_devices
-                        .push(Arc::clone(&virtio_net_device) as Arc<Mutex<dyn Migratable>>);
-                }
+                devices.push(self.make_virtio_net_device(net_cfg)?);
             }
         }
 
         Ok(devices)
     }
 
-    fn make_virtio_rng_devices(&mut self) -> DeviceManagerResult<Vec<(VirtioDeviceArc, bool)>> {
+    fn make_virtio_net_device(
+        &mut self,
+        net_cfg: &NetConfig,
+    ) -> DeviceManagerResult<(VirtioDeviceArc, bool, String)> {
+        let id = assigned_id(&net_cfg.id);
+        if net_cfg.vhost_user {
+            check_vhost_user_memory(&self.config.lock().unwrap().memory)?;
+            if net_cfg.pcap.is_some() {
+                warn!("Packet capture is not supported with vhost-user-net, ignoring it");
+            }
+            let vu_cfg = VhostUserConfig {
+                sock: net_cfg.vhost_socket.clone().unwrap(),
+                num_queues: net_cfg.num_queues,
+                queue_size: net_cfg.queue_size,
+            };
+            let vhost_user_net_device = Arc::new(Mutex::new(
+                vm_virtio::vhost_user::Net::new(net_cfg.mac, vu_cfg)
+                    .map_err(DeviceManagerError::CreateVhostUserNet)?,
+            ));
+            self.migratable_devices
+                .push(Arc::clone(&vhost_user_net_device) as Arc<Mutex<dyn Migratable>>);
+
+            Ok((
+                Arc::clone(&vhost_user_net_device) as Arc<Mutex<dyn vm_virtio::VirtioDevice>>,
+                net_cfg.iommu,
+                id,
+            ))
+        } else {
+            let mut net = if let Some(ref tap_if_name) = net_cfg.tap {
+                vm_virtio::Net::new(
+                    Some(tap_if_name),
+                    None,
+                    None,
+                    Some(net_cfg.mac),
+                    net_cfg.iommu,
+                    net_cfg.num_queues,
+                    net_cfg.queue_size,
+                )
+                .map_err(DeviceManagerError::CreateVirtioNet)?
+            } else {
+                vm_virtio::Net::new(
+                    None,
+                    Some(net_cfg.ip),
+                    Some(net_cfg.mask),
+                    Some(net_cfg.mac),
+                    net_cfg.iommu,
+                    net_cfg.num_queues,
+                    net_cfg.queue_size,
+                )
+                .map_err(DeviceManagerError::CreateVirtioNet)?
+            };
+            net.set_metrics_id(&id);
+            if let Some(pcap_path) = &net_cfg.pcap {
+                net.set_pcap(
+                    vm_virtio::PcapWriter::new(pcap_path, net_cfg.pcap_snaplen)
+                        .map_err(DeviceManagerError::CreateNetPcap)?,
+                );
+            }
+            if net_cfg.vhost_net {
+                let guest_memory = self.memory_manager.lock().unwrap().guest_memory();
+                net.enable_vhost_net(&guest_memory.load());
+            }
+            let virtio_net_device = Arc::new(Mutex::new(net));
+            self.migratable_devices
+                .push(Arc::clone(&virtio_net_device) as Arc<Mutex<dyn Migratable>>);
+            self.net_devices
+                .push((id.clone(), Arc::clone(&virtio_net_device)));
+
+            Ok((
+                Arc::clone(&virtio_net_device) as Arc<Mutex<dyn vm_virtio::VirtioDevice>>,
+                net_cfg.iommu,
+                id,
+            ))
+        }
+    }
+
+    fn make_virtio_rng_devices(
+        &mut self,
+    ) -> DeviceManagerResult<Vec<(VirtioDeviceArc, bool, String)>> {
         let mut devices = Vec::new();
 
         // Add virtio-rng if required
         let rng_config = self.config.lock().unwrap().rng.clone();
+        let rng_seed = self.config.lock().unwrap().rng_seed;
         if let Some(rng_path) = rng_config.src.to_str() {
-            let virtio_rng_device = Arc::new(Mutex::new(
+            let rng = if let Some(seed) = rng_seed {
+                warn!(
+                    "virtio-rng seeded with {}: the guest entropy is predictable",
+                    seed
+                );
+                vm_virtio::Rng::new_seeded(seed, rng_config.iommu)
+            } else {
                 vm_virtio::Rng::new(rng_path, rng_config.iommu)
-                    .map_err(DeviceManagerError::CreateVirtioRng)?,
-            ));
+                    .map_err(DeviceManagerError::CreateVirtioRng)?
+            };
+            let virtio_rng_device = Arc::new(Mutex::new(rng));
             devices.push((
                 Arc::clone(&virtio_rng_device) as Arc<Mutex<dyn vm_virtio::VirtioDevice>>,
                 false,
+                "_rng0".to_string(),
             ));
 
             self.migratable_devices
@@ -1006,48 +2084,127 @@ impl DeviceManager {
         Ok(devices)
     }
 
-    fn make_virtio_fs_devices(&mut self) -> DeviceManagerResult<Vec<(VirtioDeviceArc, bool)>> {
+    fn make_virtio_balloon_devices(
+        &mut self,
+    ) -> DeviceManagerResult<Vec<(VirtioDeviceArc, bool, String)>> {
         let mut devices = Vec::new();
-        // Add virtio-fs if required
-        if let Some(fs_list_cfg) = &self.config.lock().unwrap().fs {
-            for fs_cfg in fs_list_cfg.iter() {
-                if let Some(fs_sock) = fs_cfg.sock.to_str() {
-                    let cache: Option<(VirtioSharedMemoryList, u64)> = if fs_cfg.dax {
-                        let fs_cache = fs_cfg.cache_size;
-                        // The memory needs to be 2MiB aligned in order to support
-                        // hugepages.
-                        let fs_guest_addr = self
-                            .address_manager
-                            .allocator
-                            .lock()
-                            .unwrap()
-                            .allocate_mmio_addresses(
-                                None,
-                                fs_cache as GuestUsize,
-                                Some(0x0020_0000),
-                            )
-                            .ok_or(DeviceManagerError::FsRangeAllocation)?;
-
-                        let mmap_region = MmapRegion::build(
-                            None,
-                            fs_cache as usize,
-                            libc::PROT_NONE,
-                            libc::MAP_ANONYMOUS | libc::MAP_PRIVATE,
-                        )
-                        .map_err(DeviceManagerError::NewMmapRegion)?;
-                        let addr: u64 = mmap_region.as_ptr() as u64;
 
-                        self._mmap_regions.push(mmap_region);
+        let balloon_config = self.config.lock().unwrap().balloon.clone();
+        if let Some(balloon_config) = balloon_config {
+            let mut balloon = vm_virtio::Balloon::new(
+                balloon_config.size,
+                balloon_config.stats_polling_interval,
+                balloon_config.free_page_reporting,
+                balloon_config.deflate_on_oom,
+            )
+            .map_err(DeviceManagerError::CreateVirtioBalloon)?;
+            balloon.set_metrics_id("_balloon0");
+            let virtio_balloon_device = Arc::new(Mutex::new(balloon));
+            devices.push((
+                Arc::clone(&virtio_balloon_device) as Arc<Mutex<dyn vm_virtio::VirtioDevice>>,
+                false,
+                "_balloon0".to_string(),
+            ));
 
-                        self.memory_manager
+            self.migratable_devices
+                .push(Arc::clone(&virtio_balloon_device) as Arc<Mutex<dyn Migratable>>);
+            self.balloon = Some(virtio_balloon_device);
+        }
+
+        Ok(devices)
+    }
+
+    fn make_virtio_gpu_devices(
+        &mut self,
+    ) -> DeviceManagerResult<Vec<(VirtioDeviceArc, bool, String)>> {
+        let mut devices = Vec::new();
+
+        let gpu_config = self.config.lock().unwrap().gpu.clone();
+        if let Some(gpu_config) = gpu_config {
+            let virtio_gpu_device = Arc::new(Mutex::new(vm_virtio::gpu::Gpu::new(
+                gpu_config.width,
+                gpu_config.height,
+            )));
+            devices.push((
+                Arc::clone(&virtio_gpu_device) as Arc<Mutex<dyn vm_virtio::VirtioDevice>>,
+                false,
+                "_gpu0".to_string(),
+            ));
+
+            self.migratable_devices
+                .push(Arc::clone(&virtio_gpu_device) as Arc<Mutex<dyn Migratable>>);
+            self.gpu = Some(virtio_gpu_device);
+        }
+
+        Ok(devices)
+    }
+
+    // The keyboard and pointer of VNC clients are the only input the guest
+    // gets, hence a device only when a VNC server is configured.
+    fn make_virtio_input_devices(
+        &mut self,
+    ) -> DeviceManagerResult<Vec<(VirtioDeviceArc, bool, String)>> {
+        let mut devices = Vec::new();
+
+        if self.config.lock().unwrap().vnc_addr.is_some() {
+            let input =
+                vm_virtio::input::Input::new().map_err(DeviceManagerError::CreateVirtioInput)?;
+            self.input_events = Some(input.events());
+            let virtio_input_device = Arc::new(Mutex::new(input));
+            devices.push((
+                Arc::clone(&virtio_input_device) as Arc<Mutex<dyn vm_virtio::VirtioDevice>>,
+                false,
+                "_input0".to_string(),
+            ));
+
+            self.migratable_devices
+                .push(Arc::clone(&virtio_input_device) as Arc<Mutex<dyn Migratable>>);
+        }
+
+        Ok(devices)
+    }
+
+    fn make_virtio_fs_devices(
+        &mut self,
+    ) -> DeviceManagerResult<Vec<(VirtioDeviceArc, bool, String)>> {
+        let mut devices = Vec::new();
+        // Add virtio-fs if required
+        let config = self.config.lock().unwrap();
+        if let Some(fs_list_cfg) = &config.fs {
+            check_vhost_user_memory(&config.memory)?;
+            for fs_cfg in fs_list_cfg.iter() {
+                if let Some(fs_sock) = fs_cfg.sock.to_str() {
+                    let cache: Option<(VirtioSharedMemoryList, u64)> = if fs_cfg.dax {
+                        let fs_cache = fs_cfg.cache_size;
+                        // The memory needs to be 2MiB aligned in order to support
+                        // hugepages.
+                        let fs_guest_addr = self
+                            .address_manager
+                            .allocator
                             .lock()
                             .unwrap()
-                            .create_userspace_mapping(
-                                fs_guest_addr.raw_value(),
-                                fs_cache,
-                                addr,
-                                false,
+                            .allocate_high_mmio_addresses(
+                                None,
+                                fs_cache as GuestUsize,
+                                Some(0x0020_0000),
                             )
+                            .ok_or(DeviceManagerError::FsRangeAllocation)?;
+
+                        let mmap_region = MmapRegion::build(
+                            None,
+                            fs_cache as usize,
+                            libc::PROT_NONE,
+                            libc::MAP_ANONYMOUS | libc::MAP_PRIVATE,
+                        )
+                        .map_err(DeviceManagerError::NewMmapRegion)?;
+                        let addr: u64 = mmap_region.as_ptr() as u64;
+
+                        self._mmap_regions.push(mmap_region);
+
+                        self.memory_manager
+                            .lock()
+                            .unwrap()
+                            .create_userspace_mapping(fs_guest_addr.raw_value(), fs_cache, addr)
                             .map_err(DeviceManagerError::MemoryManager)?;
 
                         let mut region_list = Vec::new();
@@ -1082,6 +2239,7 @@ impl DeviceManager {
                     devices.push((
                         Arc::clone(&virtio_fs_device) as Arc<Mutex<dyn vm_virtio::VirtioDevice>>,
                         false,
+                        assigned_id(&fs_cfg.id),
                     ));
 
                     self.migratable_devices
@@ -1093,7 +2251,43 @@ impl DeviceManager {
         Ok(devices)
     }
 
-    fn make_virtio_pmem_devices(&mut self) -> DeviceManagerResult<Vec<(VirtioDeviceArc, bool)>> {
+    fn make_virtio_scsi_devices(
+        &mut self,
+    ) -> DeviceManagerResult<Vec<(VirtioDeviceArc, bool, String)>> {
+        let mut devices = Vec::new();
+        // All the LUNs are exposed through a single virtio-scsi device.
+        if let Some(scsi_list_cfg) = &self.config.lock().unwrap().scsi {
+            let mut disks = Vec::new();
+            for scsi_cfg in scsi_list_cfg.iter() {
+                let image = OpenOptions::new()
+                    .read(true)
+                    .write(true)
+                    .open(&scsi_cfg.file)
+                    .map_err(|e| DeviceManagerError::Disk(scsi_cfg.file.clone(), e))?;
+                disks.push((scsi_cfg.lun, vm_virtio::RawFile::new(image, false)));
+            }
+
+            let virtio_scsi_device = Arc::new(Mutex::new(
+                vm_virtio::scsi::Scsi::new(disks, false)
+                    .map_err(DeviceManagerError::CreateVirtioScsi)?,
+            ));
+
+            devices.push((
+                Arc::clone(&virtio_scsi_device) as Arc<Mutex<dyn vm_virtio::VirtioDevice>>,
+                false,
+                "_scsi0".to_string(),
+            ));
+
+            self.migratable_devices
+                .push(Arc::clone(&virtio_scsi_device) as Arc<Mutex<dyn Migratable>>);
+        }
+
+        Ok(devices)
+    }
+
+    fn make_virtio_pmem_devices(
+        &mut self,
+    ) -> DeviceManagerResult<Vec<(VirtioDeviceArc, bool, String)>> {
         let mut devices = Vec::new();
         // Add virtio-pmem if required
         if let Some(pmem_list_cfg) = &self.config.lock().unwrap().pmem {
@@ -1107,7 +2301,7 @@ impl DeviceManager {
                     .allocator
                     .lock()
                     .unwrap()
-                    .allocate_mmio_addresses(None, size as GuestUsize, Some(0x0020_0000))
+                    .allocate_high_mmio_addresses(None, size as GuestUsize, Some(0x0020_0000))
                     .ok_or(DeviceManagerError::PmemRangeAllocation)?;
 
                 let (custom_flags, set_len) = if pmem_cfg.file.is_dir() {
@@ -1121,7 +2315,7 @@ impl DeviceManager {
                     .write(true)
                     .custom_flags(custom_flags)
                     .open(&pmem_cfg.file)
-                    .map_err(DeviceManagerError::PmemFileOpen)?;
+                    .map_err(|e| DeviceManagerError::PmemFileOpen(pmem_cfg.file.clone(), e))?;
 
                 if set_len {
                     file.set_len(size)
@@ -1139,13 +2333,14 @@ impl DeviceManager {
                 self.memory_manager
                     .lock()
                     .unwrap()
-                    .create_userspace_mapping(
-                        pmem_guest_addr.raw_value(),
-                        size,
-                        addr,
-                        pmem_cfg.mergeable,
-                    )
+                    .create_userspace_mapping(pmem_guest_addr.raw_value(), size, addr)
                     .map_err(DeviceManagerError::MemoryManager)?;
+                if pmem_cfg.mergeable {
+                    self.memory_manager
+                        .lock()
+                        .unwrap()
+                        .advise_mergeable_mapping(pmem_guest_addr.raw_value(), addr, size);
+                }
 
                 let virtio_pmem_device = Arc::new(Mutex::new(
                     vm_virtio::Pmem::new(file, pmem_guest_addr, size as GuestUsize, pmem_cfg.iommu)
@@ -1155,6 +2350,7 @@ impl DeviceManager {
                 devices.push((
                     Arc::clone(&virtio_pmem_device) as Arc<Mutex<dyn vm_virtio::VirtioDevice>>,
                     false,
+                    assigned_id(&pmem_cfg.id),
                 ));
 
                 self.migratable_devices
@@ -1167,10 +2363,12 @@ impl DeviceManager {
 
     fn make_virtio_vhost_user_net_devices(
         &mut self,
-    ) -> DeviceManagerResult<Vec<(VirtioDeviceArc, bool)>> {
+    ) -> DeviceManagerResult<Vec<(VirtioDeviceArc, bool, String)>> {
         let mut devices = Vec::new();
         // Add vhost-user-net if required
-        if let Some(vhost_user_net_list_cfg) = &self.config.lock().unwrap().vhost_user_net {
+        let config = self.config.lock().unwrap();
+        if let Some(vhost_user_net_list_cfg) = &config.vhost_user_net {
+            check_vhost_user_memory(&config.memory)?;
             for vhost_user_net_cfg in vhost_user_net_list_cfg.iter() {
                 let vu_cfg = VhostUserConfig {
                     sock: vhost_user_net_cfg.sock.clone(),
@@ -1185,6 +2383,7 @@ impl DeviceManager {
                 devices.push((
                     Arc::clone(&vhost_user_net_device) as Arc<Mutex<dyn vm_virtio::VirtioDevice>>,
                     false,
+                    assigned_id(&vhost_user_net_cfg.id),
                 ));
 
                 self.migratable_devices
@@ -1197,10 +2396,12 @@ impl DeviceManager {
 
     fn make_virtio_vhost_user_blk_devices(
         &mut self,
-    ) -> DeviceManagerResult<Vec<(VirtioDeviceArc, bool)>> {
+    ) -> DeviceManagerResult<Vec<(VirtioDeviceArc, bool, String)>> {
         let mut devices = Vec::new();
         // Add vhost-user-blk if required
-        if let Some(vhost_user_blk_list_cfg) = &self.config.lock().unwrap().vhost_user_blk {
+        let config = self.config.lock().unwrap();
+        if let Some(vhost_user_blk_list_cfg) = &config.vhost_user_blk {
+            check_vhost_user_memory(&config.memory)?;
             for vhost_user_blk_cfg in vhost_user_blk_list_cfg.iter() {
                 let vu_cfg = VhostUserConfig {
                     sock: vhost_user_blk_cfg.sock.clone(),
@@ -1215,6 +2416,7 @@ impl DeviceManager {
                 devices.push((
                     Arc::clone(&vhost_user_blk_device) as Arc<Mutex<dyn vm_virtio::VirtioDevice>>,
                     false,
+                    assigned_id(&vhost_user_blk_cfg.id),
                 ));
 
                 self.migratable_devices
@@ -1225,7 +2427,9 @@ impl DeviceManager {
         Ok(devices)
     }
 
-    fn make_virtio_vsock_devices(&mut self) -> DeviceManagerResult<Vec<(VirtioDeviceArc, bool)>> {
+    fn make_virtio_vsock_devices(
+        &mut self,
+    ) -> DeviceManagerResult<Vec<(VirtioDeviceArc, bool, String)>> {
         let mut devices = Vec::new();
         // Add vsock if required
         if let Some(vsock_list_cfg) = &self.config.lock().unwrap().vsock {
@@ -1234,9 +2438,18 @@ impl DeviceManager {
                     .sock
                     .to_str()
                     .ok_or(DeviceManagerError::CreateVsockConvertPath)?;
-                let backend =
+                let mut backend =
                     vm_virtio::vsock::VsockUnixBackend::new(vsock_cfg.cid, socket_path.to_string())
                         .map_err(DeviceManagerError::CreateVsockBackend)?;
+                for listen_cfg in vsock_cfg.listen.iter().flatten() {
+                    let uds_path = listen_cfg
+                        .uds
+                        .to_str()
+                        .ok_or(DeviceManagerError::CreateVsockConvertPath)?;
+                    backend
+                        .add_port_listener(listen_cfg.port, uds_path)
+                        .map_err(DeviceManagerError::CreateVsockBackend)?;
+                }
 
                 let vsock_device = Arc::new(Mutex::new(
                     vm_virtio::Vsock::new(vsock_cfg.cid, backend, vsock_cfg.iommu)
@@ -1246,6 +2459,7 @@ impl DeviceManager {
                 devices.push((
                     Arc::clone(&vsock_device) as Arc<Mutex<dyn vm_virtio::VirtioDevice>>,
                     false,
+                    assigned_id(&vsock_cfg.id),
                 ));
 
                 self.migratable_devices
@@ -1275,11 +2489,6 @@ impl DeviceManager {
         iommu_device: &mut Option<vm_virtio::Iommu>,
         interrupt_manager: &Arc<dyn InterruptManager<GroupConfig = MsiIrqGroupConfig>>,
     ) -> DeviceManagerResult<Vec<u32>> {
-        let mut mem_slot = self
-            .memory_manager
-            .lock()
-            .unwrap()
-            .allocate_kvm_memory_slot();
         let mut iommu_attached_device_ids = Vec::new();
 
         if let Some(device_list_cfg) = &self.config.lock().unwrap().devices {
@@ -1293,7 +2502,16 @@ impl DeviceManager {
                 // do multifunction. Also, because we only support one PCI
                 // bus, the bus 0, we don't need to add anything to the
                 // global device ID.
-                let device_id = pci.next_device_id() << 3;
+                let device_id = match device_cfg.pci_slot {
+                    Some(slot) => {
+                        pci.check_device_id(slot)
+                            .map_err(DeviceManagerError::PciSlot)?;
+                        slot
+                    }
+                    None => pci
+                        .next_device_id()
+                        .map_err(DeviceManagerError::NextPciDeviceId)?,
+                } << 3;
 
                 let memory = self.memory_manager.lock().unwrap().guest_memory();
                 let vfio_device = VfioDevice::new(
@@ -1314,23 +2532,38 @@ impl DeviceManager {
                         iommu_attached_device_ids.push(device_id);
                         iommu.add_external_mapping(device_id, vfio_mapping);
                     }
+                } else {
+                    self.vfio_containers.push(vfio_device.get_container());
                 }
 
+                let memory_slots = self.memory_manager.lock().unwrap().memory_slots();
                 let mut vfio_pci_device =
-                    VfioPciDevice::new(&self.address_manager.vm_fd, vfio_device, interrupt_manager)
+                    VfioPciDevice::new(memory_slots, vfio_device, interrupt_manager)
                         .map_err(DeviceManagerError::VfioPciCreate)?;
 
                 let bars = vfio_pci_device
                     .allocate_bars(&mut self.address_manager.allocator.lock().unwrap())
                     .map_err(DeviceManagerError::AllocateBars)?;
 
-                mem_slot = vfio_pci_device
-                    .map_mmio_regions(&self.address_manager.vm_fd, mem_slot)
+                self.device_registry
+                    .register(
+                        &assigned_id(&device_cfg.id),
+                        DeviceResources {
+                            pci_device_id: Some(device_id >> 3),
+                            bus_ranges: bar_ranges(&bars),
+                            irqs: Vec::new(),
+                            ioeventfds: Vec::new(),
+                        },
+                    )
+                    .map_err(DeviceManagerError::DeviceRegistry)?;
+
+                vfio_pci_device
+                    .map_mmio_regions()
                     .map_err(DeviceManagerError::VfioMapRegion)?;
 
                 let vfio_pci_device = Arc::new(Mutex::new(vfio_pci_device));
 
-                pci.add_device(vfio_pci_device.clone())
+                pci.add_device_with_id(device_id >> 3, vfio_pci_device.clone())
                     .map_err(DeviceManagerError::AddPciDevice)?;
 
                 pci.register_mapping(
@@ -1345,6 +2578,9 @@ impl DeviceManager {
         Ok(iommu_attached_device_ids)
     }
 
+    /// Plugs the virtio device onto the PCI bus, registering it as `id`, and
+    /// returns its PCI device id. The device gets `pci_slot` if given, which
+    /// must not be in use, or the first free slot otherwise.
     #[cfg(feature = "pci_support")]
     fn add_virtio_pci_device(
         &mut self,
@@ -1352,7 +2588,9 @@ impl DeviceManager {
         pci: &mut PciBus,
         iommu_mapping: &Option<Arc<IommuMapping>>,
         interrupt_manager: &Arc<dyn InterruptManager<GroupConfig = MsiIrqGroupConfig>>,
-    ) -> DeviceManagerResult<Option<u32>> {
+        id: &str,
+        pci_slot: Option<u32>,
+    ) -> DeviceManagerResult<u32> {
         // Allows support for one MSI-X vector per queue. It also adds 1
         // as we need to take into account the dedicated vector to notify
         // about a virtio config change.
@@ -1362,38 +2600,37 @@ impl DeviceManager {
         // to the PCI function, and we know we don't do multifunction.
         // Also, because we only support one PCI bus, the bus 0, we don't need
         // to add anything to the global device ID.
-        let dev_id = pci.next_device_id() << 3;
-
-        // Create the callback from the implementation of the DmaRemapping
-        // trait. The point with the callback is to simplify the code as we
-        // know about the device ID from this point.
-        let iommu_mapping_cb: Option<Arc<VirtioIommuRemapping>> =
-            if let Some(mapping) = iommu_mapping {
-                let mapping_clone = mapping.clone();
-                Some(Arc::new(Box::new(move |addr: u64| {
-                    mapping_clone.translate(dev_id, addr).map_err(|e| {
-                        std::io::Error::new(
-                            std::io::ErrorKind::Other,
-                            format!(
-                                "failed to translate addr 0x{:x} for device 00:{:02x}.0 {}",
-                                addr, dev_id, e
-                            ),
-                        )
-                    })
-                }) as VirtioIommuRemapping))
-            } else {
-                None
-            };
+        let pci_device_id = match pci_slot {
+            Some(slot) => {
+                pci.check_device_id(slot)
+                    .map_err(DeviceManagerError::PciSlot)?;
+                slot
+            }
+            None => pci
+                .next_device_id()
+                .map_err(DeviceManagerError::NextPciDeviceId)?,
+        };
+        let dev_id = pci_device_id << 3;
+
+        let dma_mapping: Option<Arc<dyn DmaMapping>> = if let Some(mapping) = iommu_mapping {
+            Some(Arc::new(VirtioDmaMapping {
+                mapping: mapping.clone(),
+                dev_id,
+            }))
+        } else {
+            None
+        };
 
         let memory = self.memory_manager.lock().unwrap().guest_memory();
         let mut virtio_pci_device = VirtioPciDevice::new(
             memory,
             virtio_device,
             msix_num,
-            iommu_mapping_cb,
+            dma_mapping,
             interrupt_manager,
         )
         .map_err(DeviceManagerError::VirtioDevice)?;
+        virtio_pci_device.set_id(format!("virtio-pci-{:02x}", pci_device_id));
 
         let mut allocator = self.address_manager.allocator.lock().unwrap();
         let bars = virtio_pci_device
@@ -1401,17 +2638,20 @@ impl DeviceManager {
             .map_err(DeviceManagerError::AllocateBars)?;
 
         let bar_addr = virtio_pci_device.config_bar_addr();
+        let mut ioeventfds = Vec::new();
         for (event, addr) in virtio_pci_device.ioeventfds(bar_addr) {
             let io_addr = IoEventAddress::Mmio(addr);
             self.address_manager
                 .vm_fd
                 .register_ioevent(event, &io_addr, NoDatamatch)
                 .map_err(DeviceManagerError::RegisterIoevent)?;
+            ioeventfds.push(addr);
         }
 
+        let bus_ranges = bar_ranges(&bars);
         let virtio_pci_device = Arc::new(Mutex::new(virtio_pci_device));
 
-        pci.add_device(virtio_pci_device.clone())
+        pci.add_device_with_id(pci_device_id, virtio_pci_device.clone())
             .map_err(DeviceManagerError::AddPciDevice)?;
 
         pci.register_mapping(
@@ -1422,16 +2662,71 @@ impl DeviceManager {
         )
         .map_err(DeviceManagerError::AddPciDevice)?;
 
+        self.device_registry
+            .register(
+                id,
+                DeviceResources {
+                    pci_device_id: Some(pci_device_id),
+                    bus_ranges,
+                    irqs: Vec::new(),
+                    ioeventfds,
+                },
+            )
+            .map_err(DeviceManagerError::DeviceRegistry)?;
+
         self.migratable_devices
             .push(Arc::clone(&virtio_pci_device) as Arc<Mutex<dyn Migratable>>);
 
-        let ret = if iommu_mapping.is_some() {
-            Some(dev_id)
-        } else {
-            None
+        self.virtio_pci_devices
+            .insert(pci_device_id, virtio_pci_device);
+
+        Ok(pci_device_id)
+    }
+
+    /// Plugs the watchdog onto the PCI bus, if the VM config asks for it.
+    #[cfg(feature = "pci_support")]
+    fn add_watchdog(
+        &mut self,
+        pci: &mut PciBus,
+        reset_evt: &EventFd,
+        exit_evt: &EventFd,
+    ) -> DeviceManagerResult<()> {
+        let watchdog_config = match self.config.lock().unwrap().watchdog.clone() {
+            Some(watchdog_config) => watchdog_config,
+            None => return Ok(()),
         };
 
-        Ok(ret)
+        let expiry_evt = match watchdog_config.action {
+            WatchdogAction::Reset => Some(reset_evt),
+            WatchdogAction::Shutdown => Some(exit_evt),
+            WatchdogAction::Event => None,
+        }
+        .map(|evt| evt.try_clone())
+        .transpose()
+        .map_err(DeviceManagerError::EventFd)?;
+        let mut watchdog =
+            I6300esbWatchdog::new(Duration::from_secs(watchdog_config.timeout), expiry_evt)
+                .map_err(DeviceManagerError::CreateWatchdog)?;
+
+        let bars = watchdog
+            .allocate_bars(&mut self.address_manager.allocator.lock().unwrap())
+            .map_err(DeviceManagerError::AllocateBars)?;
+
+        let watchdog = Arc::new(Mutex::new(watchdog));
+        pci.add_device(watchdog.clone())
+            .map_err(DeviceManagerError::AddPciDevice)?;
+        pci.register_mapping(
+            watchdog.clone(),
+            self.address_manager.io_bus.as_ref(),
+            self.address_manager.mmio_bus.as_ref(),
+            bars,
+        )
+        .map_err(DeviceManagerError::AddPciDevice)?;
+
+        self.migratable_devices
+            .push(watchdog as Arc<Mutex<dyn Migratable>>);
+
+        Ok(())
     }
 
     #[cfg(feature = "mmio_support")]
@@ -1440,17 +2735,21 @@ impl DeviceManager {
         virtio_device: Arc<Mutex<dyn vm_virtio::VirtioDevice>>,
         interrupt_manager: &Arc<dyn InterruptManager<GroupConfig = LegacyIrqGroupConfig>>,
         mmio_base: GuestAddress,
+        id: &str,
     ) -> DeviceManagerResult<()> {
         let memory = self.memory_manager.lock().unwrap().guest_memory();
         let mut mmio_device = vm_virtio::transport::MmioDevice::new(memory, virtio_device)
             .map_err(DeviceManagerError::VirtioDevice)?;
+        mmio_device.set_id(format!("virtio-mmio-{:x}", mmio_base.0));
 
+        let mut ioeventfds = Vec::new();
         for (i, (event, addr)) in mmio_device.ioeventfds(mmio_base.0).iter().enumerate() {
             let io_addr = IoEventAddress::Mmio(*addr);
             self.address_manager
                 .vm_fd
                 .register_ioevent(event, &io_addr, i as u32)
                 .map_err(DeviceManagerError::RegisterIoevent)?;
+            ioeventfds.push(*addr);
         }
 
         let irq_num = self
@@ -1469,12 +2768,29 @@ impl DeviceManager {
 
         mmio_device.assign_interrupt(interrupt_group);
 
+        self.device_registry
+            .register(
+                id,
+                DeviceResources {
+                    pci_device_id: None,
+                    bus_ranges: vec![BusRange {
+                        kind: BusRangeKind::Mmio64,
+                        base: mmio_base.0,
+                        size: MMIO_LEN,
+                    }],
+                    irqs: vec![irq_num],
+                    ioeventfds,
+                },
+            )
+            .map_err(DeviceManagerError::DeviceRegistry)?;
+
         let mmio_device_arc = Arc::new(Mutex::new(mmio_device));
         self.address_manager
             .mmio_bus
             .insert(mmio_device_arc.clone(), mmio_base.0, MMIO_LEN)
             .map_err(DeviceManagerError::BusError)?;
 
+        #[cfg(target_arch = "x86_64")]
         self.cmdline_additions.push(format!(
             "virtio_mmio.device={}K@0x{:08x}:{}",
             MMIO_LEN / 1024,
@@ -1482,6 +2798,13 @@ impl DeviceManager {
             irq_num
         ));
 
+        #[cfg(target_arch = "aarch64")]
+        self.mmio_device_info.push(MmioDeviceInfo {
+            addr: mmio_base.0,
+            len: MMIO_LEN,
+            irq: irq_num,
+        });
+
         self.migratable_devices
             .push(Arc::clone(&mmio_device_arc) as Arc<Mutex<dyn Migratable>>);
 
@@ -1508,8 +2831,596 @@ impl DeviceManager {
         &self.console
     }
 
-    pub fn cmdline_additions(&self) -> &[String] {
-        self.cmdline_additions.as_slice()
+    pub fn serial_buffer(&self) -> Option<&SerialBuffer> {
+        self.serial_buffer.as_ref()
+    }
+
+    /// Maps a hotplugged RAM region for DMA from the VFIO devices, unless
+    /// they sit behind the virtio-iommu and the guest handles their mappings,
+    /// and hands the new memory table over to vhost-net.
+    pub fn update_memory(&self, _new_region: &Arc<GuestRegionMmap>) -> DeviceManagerResult<()> {
+        let guest_memory = self.memory_manager.lock().unwrap().guest_memory();
+        for (_, net) in self.net_devices.iter() {
+            net.lock()
+                .unwrap()
+                .update_memory(&guest_memory.load())
+                .map_err(DeviceManagerError::UpdateVirtioNetMemory)?;
+        }
+
+        #[cfg(feature = "pci_support")]
+        for container in self.vfio_containers.iter() {
+            container
+                .vfio_dma_map(
+                    _new_region.start_addr().raw_value(),
+                    _new_region.len() as u64,
+                    _new_region.as_ptr() as u64,
+                )
+                .map_err(DeviceManagerError::VfioDmaMap)?;
+        }
+
+        Ok(())
+    }
+
+    /// The index in the VM config of the disk `id`.
+    pub fn disk_index(&self, id: &str) -> DeviceManagerResult<usize> {
+        self.config
+            .lock()
+            .unwrap()
+            .disks
+            .iter()
+            .flatten()
+            .position(|disk| disk.id.as_deref() == Some(id))
+            .ok_or_else(|| DeviceManagerError::InvalidDiskId(id.to_string()))
+    }
+
+    /// The id of the disk at `index` in the VM config.
+    pub fn disk_id(&self, index: usize) -> DeviceManagerResult<String> {
+        self.config
+            .lock()
+            .unwrap()
+            .disks
+            .as_ref()
+            .and_then(|disks| disks.get(index))
+            .and_then(|disk| disk.id.clone())
+            .ok_or(DeviceManagerError::InvalidDiskIndex(index))
+    }
+
+    /// The id of the device attached to the PCI device id `index`.
+    pub fn device_at(&self, index: usize) -> DeviceManagerResult<String> {
+        self.device_registry
+            .pci_device(index as u32)
+            .map(str::to_string)
+            .ok_or(DeviceManagerError::InvalidDeviceIndex(index))
+    }
+
+    pub fn devices(&self) -> &BTreeMap<String, DeviceResources> {
+        self.device_registry.devices()
+    }
+
+    /// Brings the link of the virtio-net device `id` up or down, as the
+    /// guest sees it.
+    pub fn set_net_link(&self, id: &str, up: bool) -> DeviceManagerResult<()> {
+        let (_, net) = self
+            .net_devices
+            .iter()
+            .find(|(net_id, _)| net_id == id)
+            .ok_or_else(|| DeviceManagerError::InvalidNetId(id.to_string()))?;
+        net.lock()
+            .unwrap()
+            .set_link_status(up)
+            .map_err(DeviceManagerError::SetNetLink)
+    }
+
+    pub fn resize_disk(
+        &self,
+        index: usize,
+        new_size: Option<u64>,
+        force: bool,
+    ) -> DeviceManagerResult<()> {
+        let block = self
+            .block_devices
+            .get(index)
+            .ok_or(DeviceManagerError::InvalidDiskIndex(index))?
+            .as_ref()
+            .ok_or(DeviceManagerError::DiskResizeUnsupported(index))?;
+
+        block
+            .lock()
+            .unwrap()
+            .resize(new_size, force)
+            .map_err(DeviceManagerError::ResizeVirtioBlock)
+    }
+
+    /// The latest memory statistics reported by the guest through the
+    /// balloon device.
+    pub fn balloon_stats(&self) -> DeviceManagerResult<vm_virtio::BalloonStats> {
+        let balloon = self.balloon.as_ref().ok_or(DeviceManagerError::NoBalloon)?;
+        Ok(balloon.lock().unwrap().stats())
+    }
+
+    /// Asks the guest for `size` bytes through the balloon device.
+    pub fn set_balloon_target(&self, size: u64) -> DeviceManagerResult<()> {
+        let balloon = self.balloon.as_ref().ok_or(DeviceManagerError::NoBalloon)?;
+        balloon
+            .lock()
+            .unwrap()
+            .set_target(size)
+            .map_err(DeviceManagerError::SetBalloonTarget)
+    }
+
+    /// Types scancodes on the PS/2 keyboard, for the guest to read. Fails
+    /// when the guest doesn't read the keyboard fast enough, the keys left
+    /// pressed being released.
+    pub fn send_keys(&self, scancodes: &[u8]) -> DeviceManagerResult<()> {
+        let i8042 = self.i8042.as_ref().ok_or(DeviceManagerError::NoKeyboard)?;
+        i8042
+            .lock()
+            .unwrap()
+            .queue_keystrokes(scancodes)
+            .map_err(DeviceManagerError::SendKey)
+    }
+
+    /// Writes what the guest displays through the GPU device to
+    /// `destination`, as a PPM image.
+    pub fn screenshot(&self, destination: &Path) -> DeviceManagerResult<()> {
+        let gpu = self.gpu.as_ref().ok_or(DeviceManagerError::NoGpu)?;
+        let framebuffer = gpu.lock().unwrap().framebuffer();
+
+        let file = File::create(destination).map_err(DeviceManagerError::Screenshot)?;
+        let mut writer = BufWriter::new(file);
+        framebuffer
+            .lock()
+            .unwrap()
+            .write_ppm(&mut writer)
+            .map_err(DeviceManagerError::Screenshot)
+    }
+
+    /// What a VNC server needs to display the guest and to forward it the
+    /// input of its clients.
+    pub fn vnc_display(
+        &self,
+    ) -> DeviceManagerResult<(
+        Arc<Mutex<vm_virtio::gpu::Framebuffer>>,
+        vm_virtio::input::InputEvents,
+    )> {
+        let gpu = self.gpu.as_ref().ok_or(DeviceManagerError::NoGpu)?;
+        let input_events = self
+            .input_events
+            .clone()
+            .ok_or(DeviceManagerError::NoInput)?;
+        let framebuffer = gpu.lock().unwrap().framebuffer();
+        Ok((framebuffer, input_events))
+    }
+
+    /// Plugs a new disk into the running VM, and returns its id and PCI
+    /// device id. The disk is added to the VM configuration, so that it can be resized
+    /// and is kept across reboots.
+    #[cfg(feature = "pci_support")]
+    pub fn add_disk(&mut self, disk_cfg: &DiskConfig) -> DeviceManagerResult<(String, u32)> {
+        if disk_cfg.iommu {
+            return Err(DeviceManagerError::HotplugIommuUnsupported);
+        }
+        disk_cfg
+            .validate()
+            .map_err(DeviceManagerError::InvalidDeviceConfig)?;
+
+        let mut disk_cfg = disk_cfg.clone();
+        let id = self.hotplug_device_id(&disk_cfg.id, "_disk")?;
+        disk_cfg.id = Some(id.clone());
+        self.validate_hotplug_pci_slot(disk_cfg.pci_slot)?;
+
+        let pci_device_id = self.hotplug_virtio_device(disk_cfg.pci_slot, |dm| {
+            dm.make_virtio_block_device(&disk_cfg)
+        })?;
+        self.config
+            .lock()
+            .unwrap()
+            .disks
+            .get_or_insert_with(Vec::new)
+            .push(disk_cfg);
+
+        Ok((id, pci_device_id))
+    }
+
+    /// Plugs a new network interface into the running VM, and returns its id
+    /// and PCI device id. The interface is added to the VM configuration.
+    #[cfg(feature = "pci_support")]
+    pub fn add_net(&mut self, net_cfg: &NetConfig) -> DeviceManagerResult<(String, u32)> {
+        if net_cfg.iommu {
+            return Err(DeviceManagerError::HotplugIommuUnsupported);
+        }
+        net_cfg
+            .validate()
+            .map_err(DeviceManagerError::InvalidDeviceConfig)?;
+
+        let mut net_cfg = net_cfg.clone();
+        let id = self.hotplug_device_id(&net_cfg.id, "_net")?;
+        net_cfg.id = Some(id.clone());
+        self.validate_hotplug_pci_slot(net_cfg.pci_slot)?;
+
+        let pci_device_id =
+            self.hotplug_virtio_device(net_cfg.pci_slot, |dm| dm.make_virtio_net_device(&net_cfg))?;
+        self.config
+            .lock()
+            .unwrap()
+            .net
+            .get_or_insert_with(Vec::new)
+            .push(net_cfg);
+
+        Ok((id, pci_device_id))
+    }
+
+    #[cfg(not(feature = "pci_support"))]
+    pub fn add_disk(&mut self, _disk_cfg: &DiskConfig) -> DeviceManagerResult<(String, u32)> {
+        Err(DeviceManagerError::HotplugUnsupported)
+    }
+
+    #[cfg(not(feature = "pci_support"))]
+    pub fn add_net(&mut self, _net_cfg: &NetConfig) -> DeviceManagerResult<(String, u32)> {
+        Err(DeviceManagerError::HotplugUnsupported)
+    }
+
+    // The id of a device being hotplugged: the one it was given, if no other
+    // device of the VM config has it, or a new one otherwise.
+    #[cfg(feature = "pci_support")]
+    fn hotplug_device_id(&self, id: &Option<String>, prefix: &str) -> DeviceManagerResult<String> {
+        let config = self.config.lock().unwrap();
+        match id {
+            Some(id) => {
+                config
+                    .validate_new_device_id(id)
+                    .map_err(DeviceManagerError::InvalidDeviceId)?;
+                Ok(id.clone())
+            }
+            None => Ok(config.new_device_id(prefix)),
+        }
+    }
+
+    // The PCI slot a device being hotplugged asked for must not be taken by
+    // another device of the VM config, even an unplugged one.
+    #[cfg(feature = "pci_support")]
+    fn validate_hotplug_pci_slot(&self, pci_slot: Option<u32>) -> DeviceManagerResult<()> {
+        if let Some(slot) = pci_slot {
+            self.config
+                .lock()
+                .unwrap()
+                .validate_new_pci_slot(slot)
+                .map_err(DeviceManagerError::InvalidPciSlot)?;
+        }
+
+        Ok(())
+    }
+
+    // Creates a virtio device and plugs it onto the PCI bus, in `pci_slot` if
+    // given, before asking the guest to scan its slot. The devices registered
+    // along the way are forgotten if it fails.
+    #[cfg(feature = "pci_support")]
+    fn hotplug_virtio_device<F>(
+        &mut self,
+        pci_slot: Option<u32>,
+        make_device: F,
+    ) -> DeviceManagerResult<u32>
+    where
+        F: FnOnce(&mut Self) -> DeviceManagerResult<(VirtioDeviceArc, bool, String)>,
+    {
+        let block_devices_len = self.block_devices.len();
+        let migratable_devices_len = self.migratable_devices.len();
+
+        let result = make_device(self).and_then(|(device, _, id)| {
+            let pci_bus = self.pci_bus.as_ref().unwrap().clone();
+            let interrupt_manager = Arc::clone(&self.msi_interrupt_manager);
+            let mut pci_bus = pci_bus.lock().unwrap();
+            self.add_virtio_pci_device(
+                device,
+                &mut pci_bus,
+                &None,
+                &interrupt_manager,
+                &id,
+                pci_slot,
+            )
+        });
+        let pci_device_id = match result {
+            Ok(pci_device_id) => pci_device_id,
+            Err(e) => {
+                self.block_devices.truncate(block_devices_len);
+                self.migratable_devices.truncate(migratable_devices_len);
+                return Err(e);
+            }
+        };
+
+        self.pci_hotplug_controller
+            .lock()
+            .unwrap()
+            .request_insert(pci_device_id);
+        self.notify_hotplug(HotPlugNotificationFlags::PCI_DEVICES_CHANGED)?;
+
+        Ok(pci_device_id)
+    }
+
+    /// Starts unplugging the virtio device `id`. The device is quiesced,
+    /// then the guest is asked to eject it, and its resources are released
+    /// once the guest is done with it. The removal moves forward through
+    /// `poll_device_removals()`, on the ticks of the removal timer, not to
+    /// block the VMM while waiting for the device and the guest.
+    #[cfg(feature = "pci_support")]
+    pub fn remove_device(&mut self, id: &str) -> DeviceManagerResult<()> {
+        let pci_device_id = self
+            .device_registry
+            .get(id)
+            .map_err(DeviceManagerError::DeviceRegistry)?
+            .pci_device_id
+            .ok_or_else(|| DeviceManagerError::DeviceRemovalUnsupported(id.to_string()))?;
+        // Only the virtio devices can be unplugged.
+        let device = self
+            .virtio_pci_devices
+            .get(&pci_device_id)
+            .cloned()
+            .ok_or_else(|| DeviceManagerError::DeviceRemovalUnsupported(id.to_string()))?;
+        if self.device_removals.iter().any(|removal| removal.id == id) {
+            return Err(DeviceManagerError::DeviceRemovalInProgress(id.to_string()));
+        }
+
+        if self.device_removals.is_empty() {
+            self.removal_timer
+                .reset(
+                    DEVICE_REMOVAL_POLL_INTERVAL,
+                    Some(DEVICE_REMOVAL_POLL_INTERVAL),
+                )
+                .map_err(DeviceManagerError::DeviceRemovalTimer)?;
+        }
+
+        // Stop the queues and let the requests in flight complete.
+        device.lock().unwrap().quiesce();
+        self.device_removals.push(DeviceRemoval {
+            id: id.to_string(),
+            pci_device_id,
+            device,
+            step: RemovalStep::Quiesce,
+            deadline: Instant::now() + DEVICE_REMOVAL_TIMEOUT,
+        });
+
+        Ok(())
+    }
+
+    /// Moves the device removals in progress forward, on a tick of the
+    /// removal timer. Returns the ids of the devices done with, along with
+    /// whether they could be removed.
+    #[cfg(feature = "pci_support")]
+    pub fn poll_device_removals(&mut self) -> Vec<(String, DeviceManagerResult<()>)> {
+        if let Err(e) = self.removal_timer.wait() {
+            warn!("Cannot read the device removal timer: {}", e);
+        }
+
+        let mut done = Vec::new();
+        let removals: Vec<DeviceRemoval> = self.device_removals.drain(..).collect();
+        for mut removal in removals {
+            match self.advance_device_removal(&mut removal) {
+                Ok(false) => self.device_removals.push(removal),
+                Ok(true) => done.push((removal.id, Ok(()))),
+                Err(e) => done.push((removal.id, Err(e))),
+            }
+        }
+
+        if self.device_removals.is_empty() {
+            if let Err(e) = self.removal_timer.clear() {
+                warn!("Cannot stop the device removal timer: {}", e);
+            }
+        }
+
+        done
+    }
+
+    #[cfg(not(feature = "pci_support"))]
+    pub fn poll_device_removals(&mut self) -> Vec<(String, DeviceManagerResult<()>)> {
+        Vec::new()
+    }
+
+    /// The timer moving the device removals forward.
+    pub fn removal_timer_fd(&self) -> RawFd {
+        self.removal_timer.as_raw_fd()
+    }
+
+    // Moves a device removal to its next step once the device or the guest
+    // is done with the current one. Returns whether the device is removed.
+    #[cfg(feature = "pci_support")]
+    fn advance_device_removal(&mut self, removal: &mut DeviceRemoval) -> DeviceManagerResult<bool> {
+        let timed_out = Instant::now() >= removal.deadline;
+        match removal.step {
+            RemovalStep::Quiesce => {
+                if !removal.device.lock().unwrap().ready_to_remove() {
+                    return if timed_out {
+                        Err(DeviceManagerError::DeviceNotQuiesced(removal.id.clone()))
+                    } else {
+                        Ok(false)
+                    };
+                }
+
+                // Ask the guest to eject the device and wait for it to
+                // release it.
+                self.pci_hotplug_controller
+                    .lock()
+                    .unwrap()
+                    .request_eject(removal.pci_device_id);
+                self.notify_hotplug(HotPlugNotificationFlags::PCI_DEVICES_CHANGED)?;
+                removal.step = RemovalStep::Eject;
+                removal.deadline = Instant::now() + DEVICE_REMOVAL_TIMEOUT;
+                Ok(false)
+            }
+            RemovalStep::Eject => {
+                let ejected = self
+                    .pci_hotplug_controller
+                    .lock()
+                    .unwrap()
+                    .take_ejected(removal.pci_device_id);
+                if !ejected {
+                    if timed_out {
+                        self.pci_hotplug_controller
+                            .lock()
+                            .unwrap()
+                            .cancel_eject(removal.pci_device_id);
+                        return Err(DeviceManagerError::DeviceEjectTimeout(removal.id.clone()));
+                    }
+                    return Ok(false);
+                }
+
+                // The driver may have initialized the device again in the
+                // meantime.
+                removal.device.lock().unwrap().quiesce();
+                removal.step = RemovalStep::Release;
+                removal.deadline = Instant::now() + DEVICE_REMOVAL_TIMEOUT;
+                Ok(false)
+            }
+            RemovalStep::Release => {
+                if !removal.device.lock().unwrap().ready_to_remove() {
+                    return if timed_out {
+                        Err(DeviceManagerError::DeviceNotQuiesced(removal.id.clone()))
+                    } else {
+                        Ok(false)
+                    };
+                }
+
+                self.release_device(&removal.id, removal.pci_device_id, &removal.device)?;
+                Ok(true)
+            }
+        }
+    }
+
+    // Releases the resources of a device the guest ejected.
+    #[cfg(feature = "pci_support")]
+    fn release_device(
+        &mut self,
+        id: &str,
+        pci_device_id: u32,
+        device: &Arc<Mutex<VirtioPciDevice>>,
+    ) -> DeviceManagerResult<()> {
+        let bars = {
+            let device = device.lock().unwrap();
+            for (event, addr) in device.ioeventfds(device.config_bar_addr()) {
+                let io_addr = IoEventAddress::Mmio(addr);
+                self.address_manager
+                    .vm_fd
+                    .unregister_ioevent(event, &io_addr)
+                    .map_err(DeviceManagerError::UnregisterIoevent)?;
+            }
+            device.bars()
+        };
+
+        {
+            let mut pci_bus = self.pci_bus.as_ref().unwrap().lock().unwrap();
+            pci_bus
+                .unregister_mapping(
+                    self.address_manager.io_bus.as_ref(),
+                    self.address_manager.mmio_bus.as_ref(),
+                    bars.clone(),
+                )
+                .map_err(DeviceManagerError::RemovePciDevice)?;
+
+            let mut allocator = self.address_manager.allocator.lock().unwrap();
+            for (addr, size, region_type) in bars {
+                match region_type {
+                    PciBarRegionType::IORegion => allocator.free_io_addresses(addr, size),
+                    PciBarRegionType::Memory32BitRegion => {
+                        allocator.free_mmio_hole_addresses(addr, size)
+                    }
+                    PciBarRegionType::Memory64BitRegion => {
+                        allocator.free_high_mmio_addresses(addr, size)
+                    }
+                }
+            }
+
+            pci_bus
+                .remove_device(pci_device_id)
+                .map_err(DeviceManagerError::RemovePciDevice)?;
+        }
+
+        self.virtio_pci_devices.remove(&pci_device_id);
+        self.device_registry
+            .unregister(id)
+            .map_err(DeviceManagerError::DeviceRegistry)?;
+
+        let virtio_device = device.lock().unwrap().virtio_device();
+        self.migratable_devices
+            .retain(|dev| !same_object(dev, device) && !same_object(dev, &virtio_device));
+        self.net_devices
+            .retain(|(_, net)| !same_object(net, &virtio_device));
+        for block in self.block_devices.iter_mut() {
+            if block
+                .as_ref()
+                .map_or(false, |block| same_object(block, &virtio_device))
+            {
+                *block = None;
+            }
+        }
+
+        // The routes and GSIs go along with the last reference to the
+        // interrupt group, held by the device.
+        let interrupt_group = device.lock().unwrap().interrupt_group();
+        self.msi_interrupt_manager
+            .destroy_group(interrupt_group)
+            .map_err(DeviceManagerError::DestroyInterruptGroup)?;
+
+        Ok(())
+    }
+
+    #[cfg(not(feature = "pci_support"))]
+    pub fn remove_device(&mut self, id: &str) -> DeviceManagerResult<()> {
+        Err(DeviceManagerError::DeviceRemovalUnsupported(id.to_string()))
+    }
+
+    /// The kernel parameters the devices need, to be appended to the
+    /// command line: the virtio-mmio devices on x86_64, and the disk marked
+    /// as the root device.
+    pub fn extra_cmdline(&self) -> String {
+        let mut params = self.cmdline_additions.clone();
+
+        // The configuration validation made sure the root disk has a serial.
+        let disks = self.config.lock().unwrap().disks.clone();
+        let root = disks.iter().flatten().find_map(|disk| {
+            disk.root
+                .and_then(|partition| disk.serial.as_ref().map(|serial| (serial, partition)))
+        });
+        if let Some((serial, partition)) = root {
+            params.push(root_param(serial, partition));
+        }
+
+        params.join(" ")
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    pub fn mmio_device_info(&self) -> &[MmioDeviceInfo] {
+        self.mmio_device_info.as_slice()
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    pub fn serial_device_info(&self) -> Option<&MmioDeviceInfo> {
+        self.serial_device_info.as_ref()
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    pub fn rtc_device_info(&self) -> Option<&MmioDeviceInfo> {
+        self.rtc_device_info.as_ref()
+    }
+
+    // Writes a new VM generation ID to the guest memory.
+    #[cfg(feature = "acpi")]
+    fn write_generation_id(&self) -> DeviceManagerResult<()> {
+        let id = vmgenid::new_generation_id().map_err(DeviceManagerError::GenerationId)?;
+        let memory = self.memory_manager.lock().unwrap().guest_memory();
+        vmgenid::write_generation_id(&memory.load(), &id)
+            .map_err(DeviceManagerError::WriteGenerationId)
+    }
+
+    /// Changes the VM generation ID, notifying the guest that it now runs
+    /// from a copy of its memory.
+    #[cfg(feature = "acpi")]
+    pub fn update_generation_id(&self) -> DeviceManagerResult<()> {
+        self.write_generation_id()?;
+        self.notify_hotplug(HotPlugNotificationFlags::GENERATION_ID_CHANGED)
+    }
+
+    #[cfg(not(feature = "acpi"))]
+    pub fn update_generation_id(&self) -> DeviceManagerResult<()> {
+        Err(DeviceManagerError::GenerationIdUnsupported)
     }
 
     pub fn notify_hotplug(
@@ -1530,6 +3441,66 @@ impl DeviceManager {
     }
 }
 
+// The vhost-user backend maps the guest RAM through the file descriptors
+// passed along with VHOST_USER_SET_MEM_TABLE, which anonymous memory lacks.
+fn check_vhost_user_memory(memory: &MemoryConfig) -> DeviceManagerResult<()> {
+    if memory.file.is_none() {
+        return Err(DeviceManagerError::VhostUserMemoryNotShared);
+    }
+
+    Ok(())
+}
+
+// The steps of a device removal, each waited for on a tick of the removal
+// timer, for at most DEVICE_REMOVAL_TIMEOUT.
+#[cfg(feature = "pci_support")]
+enum RemovalStep {
+    // The requests in flight complete.
+    Quiesce,
+    // The guest ejects the device.
+    Eject,
+    // The device stops again, if the driver initialized it meanwhile.
+    Release,
+}
+
+#[cfg(feature = "pci_support")]
+struct DeviceRemoval {
+    id: String,
+    pci_device_id: u32,
+    device: Arc<Mutex<VirtioPciDevice>>,
+    step: RemovalStep,
+    deadline: Instant,
+}
+
+// The id of a device from the VM config, assigned when the device manager
+// was created or the device plugged.
+fn assigned_id(id: &Option<String>) -> String {
+    id.clone().unwrap()
+}
+
+// The guest addresses the BARs of a PCI device were given.
+#[cfg(feature = "pci_support")]
+fn bar_ranges(bars: &[(GuestAddress, GuestUsize, PciBarRegionType)]) -> Vec<BusRange> {
+    bars.iter()
+        .map(|(addr, size, region_type)| BusRange {
+            kind: match region_type {
+                PciBarRegionType::IORegion => BusRangeKind::Pio,
+                PciBarRegionType::Memory32BitRegion => BusRangeKind::Mmio32,
+                PciBarRegionType::Memory64BitRegion => BusRangeKind::Mmio64,
+            },
+            base: addr.raw_value(),
+            size: *size,
+        })
+        .collect()
+}
+
+// Whether both references point to the same object, whatever the trait they
+// are seen through.
+#[cfg(feature = "pci_support")]
+fn same_object<T: ?Sized, U: ?Sized>(a: &Arc<T>, b: &Arc<U>) -> bool {
+    &**a as *const T as *const u8 == &**b as *const U as *const u8
+}
+
 #[cfg(feature = "acpi")]
 fn create_ged_device(ged_irq: u32) -> Vec<u8> {
     aml::Device::new(
@@ -1566,6 +3537,19 @@ fn create_ged_device(ged_irq: u32) -> Vec<u8> {
                         &aml::Equal::new(&aml::Local(1), &2usize),
                         vec![&aml::MethodCall::new("\\_SB_.MHPC.MSCN".into(), vec![])],
                     ),
+                    &aml::And::new(&aml::Local(1), &aml::Local(0), &4usize),
+                    &aml::If::new(
+                        &aml::Equal::new(&aml::Local(1), &4usize),
+                        vec![&aml::MethodCall::new("\\_SB_.PCI0.PCNT".into(), vec![])],
+                    ),
+                    &aml::And::new(&aml::Local(1), &aml::Local(0), &8usize),
+                    &aml::If::new(
+                        &aml::Equal::new(&aml::Local(1), &8usize),
+                        vec![&aml::Notify::new(
+                            &aml::Path::new("\\_SB_.VGEN"),
+                            &vmgenid::GENERATION_ID_NOTIFY,
+                        )],
+                    ),
                 ],
             ),
         ],
@@ -1573,12 +3557,127 @@ fn create_ged_device(ged_irq: u32) -> Vec<u8> {
     .to_aml_bytes()
 }
 
+#[cfg(feature = "acpi")]
+struct PciSlot {
+    slot: usize,
+}
+
+#[cfg(feature = "acpi")]
+impl Aml for PciSlot {
+    fn to_aml_bytes(&self) -> Vec<u8> {
+        let adr = self.slot << 16;
+        aml::Device::new(
+            format!("S{:03}", self.slot).as_str().into(),
+            vec![
+                &aml::Name::new("_ADR".into(), &adr),
+                &aml::Name::new("_SUN".into(), &self.slot),
+                // Release the slot once the guest is done with the device
+                &aml::Method::new(
+                    "_EJ0".into(),
+                    1,
+                    false,
+                    vec![&aml::Store::new(
+                        &aml::Path::new("\\_SB_.PCI0.B0EJ"),
+                        &(1usize << self.slot),
+                    )],
+                ),
+            ],
+        )
+        .to_aml_bytes()
+    }
+}
+
+// Notification values for the slot devices
+#[cfg(feature = "acpi")]
+const ACPI_DEVICE_CHECK: u8 = 0x1;
+#[cfg(feature = "acpi")]
+const ACPI_EJECT_REQUEST: u8 = 0x3;
+
+// Notifies the slot device if its bit is set in Local0.
+#[cfg(feature = "acpi")]
+struct PciSlotNotify {
+    slot: usize,
+    value: u8,
+}
+
+#[cfg(feature = "acpi")]
+impl Aml for PciSlotNotify {
+    fn to_aml_bytes(&self) -> Vec<u8> {
+        let mask = 1usize << self.slot;
+        let object = aml::Path::new(&format!("S{:03}", self.slot));
+        let mut bytes = aml::And::new(&aml::Local(1), &aml::Local(0), &mask).to_aml_bytes();
+        bytes.extend_from_slice(
+            &aml::If::new(
+                &aml::Equal::new(&aml::Local(1), &mask),
+                vec![&aml::Notify::new(&object, &self.value)],
+            )
+            .to_aml_bytes(),
+        );
+        bytes
+    }
+}
+
+// Slot 0 holds the host bridge, which cannot be unplugged.
+#[cfg(feature = "acpi")]
+const PCI_HOTPLUG_SLOTS: std::ops::Range<usize> = 1..32;
+
+#[cfg(feature = "acpi")]
+fn create_pci_hotplug_aml() -> Vec<u8> {
+    let slots: Vec<PciSlot> = PCI_HOTPLUG_SLOTS.map(|slot| PciSlot { slot }).collect();
+    let device_checks: Vec<PciSlotNotify> = PCI_HOTPLUG_SLOTS
+        .map(|slot| PciSlotNotify {
+            slot,
+            value: ACPI_DEVICE_CHECK,
+        })
+        .collect();
+    let eject_requests: Vec<PciSlotNotify> = PCI_HOTPLUG_SLOTS
+        .map(|slot| PciSlotNotify {
+            slot,
+            value: ACPI_EJECT_REQUEST,
+        })
+        .collect();
+
+    // Let the guest scan the new slots, then release the removed ones.
+    let load_pciu = aml::Store::new(&aml::Local(0), &aml::Path::new("PCIU"));
+    let load_pcid = aml::Store::new(&aml::Local(0), &aml::Path::new("PCID"));
+    let mut pcnt_children: Vec<&dyn aml::Aml> = vec![&load_pciu];
+    for notify in device_checks.iter() {
+        pcnt_children.push(notify);
+    }
+    pcnt_children.push(&load_pcid);
+    for notify in eject_requests.iter() {
+        pcnt_children.push(notify);
+    }
+    let pcnt = aml::Method::new("PCNT".into(), 0, true, pcnt_children);
+
+    let region = aml::OpRegion::new("PCST".into(), aml::OpRegionSpace::SystemIO, 0xae00, 0xc);
+    let fields = aml::Field::new(
+        "PCST".into(),
+        aml::FieldAccessType::DWord,
+        aml::FieldUpdateRule::WriteAsZeroes,
+        vec![
+            aml::FieldEntry::Named(*b"PCID", 32),
+            aml::FieldEntry::Named(*b"B0EJ", 32),
+            aml::FieldEntry::Named(*b"PCIU", 32),
+        ],
+    );
+
+    let mut children: Vec<&dyn aml::Aml> = vec![&region, &fields];
+    for slot in slots.iter() {
+        children.push(slot);
+    }
+    children.push(&pcnt);
+
+    aml::Scope::new("_SB_.PCI0".into(), children).to_aml_bytes()
+}
+
 #[cfg(feature = "acpi")]
 impl Aml for DeviceManager {
     fn to_aml_bytes(&self) -> Vec<u8> {
         let mut bytes = Vec::new();
         let start_of_device_area = self.memory_manager.lock().unwrap().start_of_device_area().0;
         let end_of_device_area = self.memory_manager.lock().unwrap().end_of_device_area().0;
+        let mmio_hole = self.memory_manager.lock().unwrap().mmio_hole();
         let pci_dsdt_data = aml::Device::new(
             "_SB_.PCI0".into(),
             vec![
@@ -1598,10 +3697,10 @@ impl Aml for DeviceManager {
                         &aml::AddressSpace::new_memory(
                             aml::AddressSpaceCachable::NotCacheable,
                             true,
-                            layout::MEM_32BIT_DEVICES_START.0 as u32,
-                            (layout::MEM_32BIT_DEVICES_START.0 + layout::MEM_32BIT_DEVICES_SIZE - 1)
-                                as u32,
+                            mmio_hole.start.raw_value() as u32,
+                            (mmio_hole.start.raw_value() + mmio_hole.size - 1) as u32,
                         ),
+                        // The device area, holding the 64-bit BARs.
                         &aml::AddressSpace::new_memory(
                             aml::AddressSpaceCachable::NotCacheable,
                             true,
@@ -1647,6 +3746,24 @@ impl Aml for DeviceManager {
         )
         .to_aml_bytes();
 
+        // Lets the guest find the keyboard without probing for it.
+        let ps2k_dsdt_data = aml::Device::new(
+            "_SB_.PS2K".into(),
+            vec![
+                &aml::Name::new("_HID".into(), &aml::EISAName::new("PNP0303")),
+                &aml::Name::new("_UID".into(), &aml::ZERO),
+                &aml::Name::new(
+                    "_CRS".into(),
+                    &aml::ResourceTemplate::new(vec![
+                        &aml::IO::new(0x60, 0x60, 0, 0x1),
+                        &aml::IO::new(0x64, 0x64, 0, 0x1),
+                        &aml::Interrupt::new(true, true, false, false, 1),
+                    ]),
+                ),
+            ],
+        )
+        .to_aml_bytes();
+
         let s5_sleep_data =
             aml::Name::new("_S5_".into(), &aml::Package::new(vec![&5u8])).to_aml_bytes();
 
@@ -1660,11 +3777,16 @@ impl Aml for DeviceManager {
         );
 
         bytes.extend_from_slice(pci_dsdt_data.as_slice());
+        bytes.extend_from_slice(create_pci_hotplug_aml().as_slice());
         bytes.extend_from_slice(mbrd_dsdt_data.as_slice());
         if self.config.lock().unwrap().serial.mode != ConsoleOutputMode::Off {
             bytes.extend_from_slice(com1_dsdt_data.as_slice());
         }
+        if self.i8042.is_some() {
+            bytes.extend_from_slice(ps2k_dsdt_data.as_slice());
+        }
         bytes.extend_from_slice(s5_sleep_data.as_slice());
+        bytes.extend_from_slice(VmGenIdDevice {}.to_aml_bytes().as_slice());
         bytes.extend_from_slice(ged_data.as_slice());
         bytes
     }
@@ -1688,5 +3810,142 @@ impl Pausable for DeviceManager {
     }
 }
 
-impl Snapshotable for DeviceManager {}
+const DEVICE_MANAGER_SNAPSHOT_ID: &str = "device-manager";
+
+impl Snapshotable for DeviceManager {
+    fn id(&self) -> String {
+        DEVICE_MANAGER_SNAPSHOT_ID.to_string()
+    }
+
+    // The virtio devices have no id, being saved along with their transport.
+    fn snapshot(&self) -> result::Result<Snapshot, MigratableError> {
+        let mut snapshot = Snapshot::new(&self.id());
+        for dev in &self.migratable_devices {
+            let dev = dev.lock().unwrap();
+            if !dev.id().is_empty() {
+                snapshot.add_snapshot(dev.snapshot()?);
+            }
+        }
+
+        Ok(snapshot)
+    }
+
+    fn restore(&mut self, snapshot: Snapshot) -> result::Result<(), MigratableError> {
+        let mut snapshots = snapshot.snapshots;
+        for dev in &self.migratable_devices {
+            let mut dev = dev.lock().unwrap();
+            if let Some(snapshot) = snapshots.remove(&dev.id()) {
+                dev.restore(*snapshot)?;
+            }
+        }
+
+        if let Some(id) = snapshots.keys().next() {
+            return Err(MigratableError::Restore(anyhow!(
+                "Unknown device in snapshot: {}",
+                id
+            )));
+        }
+
+        // The BARs the guest moved are mapped where they were first allocated
+        // until relocated.
+        #[cfg(feature = "pci_support")]
+        {
+            for virtio_pci_device in self.virtio_pci_devices.values() {
+                let mut virtio_pci_device = virtio_pci_device.lock().unwrap();
+                for bar in virtio_pci_device.take_restored_bar_moves() {
+                    self.address_manager
+                        .move_bar(
+                            bar.old_base,
+                            bar.new_base,
+                            bar.len,
+                            &mut *virtio_pci_device,
+                            bar.region_type,
+                        )
+                        .map_err(|e| MigratableError::Restore(e.into()))?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
 impl Migratable for DeviceManager {}
+
+// The kernel parameter naming the root device after the serial of the disk,
+// through the link udev creates for it, the guest naming the disks in the
+// order it finds them rather than the order they were given in.
+fn root_param(serial: &str, partition: u32) -> String {
+    if partition == 0 {
+        format!("root=/dev/disk/by-id/virtio-{}", serial)
+    } else {
+        format!("root=/dev/disk/by-id/virtio-{}-part{}", serial, partition)
+    }
+}
+
+#[cfg(all(test, feature = "pci_support"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_root_param() {
+        assert_eq!(root_param("os", 1), "root=/dev/disk/by-id/virtio-os-part1");
+        assert_eq!(root_param("os", 0), "root=/dev/disk/by-id/virtio-os");
+    }
+
+    #[test]
+    fn test_pci_hotplug_controller() {
+        let mut controller = PciHotplugController::default();
+        let mut data = [0u8; 4];
+
+        controller.request_eject(3);
+        controller.read(0, PCI_SLOTS_PENDING_OFFSET, &mut data);
+        assert_eq!(u32::from_le_bytes(data), 1 << 3);
+
+        // Slots which are not being unplugged cannot be released.
+        controller.write(0, PCI_SLOTS_EJECTED_OFFSET, &(1u32 << 4).to_le_bytes());
+        assert!(!controller.take_ejected(4));
+
+        controller.write(0, PCI_SLOTS_EJECTED_OFFSET, &(1u32 << 3).to_le_bytes());
+        controller.read(0, PCI_SLOTS_PENDING_OFFSET, &mut data);
+        assert_eq!(u32::from_le_bytes(data), 0);
+        assert!(controller.take_ejected(3));
+        assert!(!controller.take_ejected(3));
+    }
+
+    #[test]
+    fn test_pci_hotplug_controller_insert() {
+        let mut controller = PciHotplugController::default();
+        let mut data = [0u8; 4];
+
+        controller.request_insert(3);
+        controller.request_insert(5);
+        controller.read(0, PCI_SLOTS_INSERTED_OFFSET, &mut data);
+        assert_eq!(u32::from_le_bytes(data), (1 << 3) | (1 << 5));
+
+        // The new slots are only reported once.
+        controller.read(0, PCI_SLOTS_INSERTED_OFFSET, &mut data);
+        assert_eq!(u32::from_le_bytes(data), 0);
+        controller.read(0, PCI_SLOTS_PENDING_OFFSET, &mut data);
+        assert_eq!(u32::from_le_bytes(data), 0);
+    }
+
+    #[test]
+    fn test_check_vhost_user_memory() {
+        let mut memory = MemoryConfig {
+            size: 512 << 20,
+            file: None,
+            mergeable: None,
+            transparent_hugepages: None,
+            prefault: false,
+            hotplug_size: None,
+            hotplugged_size: None,
+            mmio_hole_base: None,
+            mmio_hole_size: None,
+            regions: None,
+        };
+        assert!(check_vhost_user_memory(&memory).is_err());
+
+        memory.file = Some(std::path::PathBuf::from("/dev/shm"));
+        assert!(check_vhost_user_memory(&memory).is_ok());
+    }
+}
@@ -0,0 +1,184 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! The devices of the VM, by the id they were given in the VM config or the
+//! one the VMM generated for them, e.g. `_disk0`.
+//!
+//! Each device is registered along with what it was given when plugged: the
+//! guest address ranges it answers on, its interrupt lines and the guest
+//! addresses of its ioeventfds. The API requests name the devices by their
+//! id, and unplugging a device releases what the registry holds for it.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::result;
+
+#[derive(Debug, PartialEq)]
+pub enum Error {
+    /// Another device has the same id.
+    DuplicateId(String),
+    /// No device has this id.
+    UnknownId(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::DuplicateId(id) => write!(f, "another device has the id {:?}", id),
+            Error::UnknownId(id) => write!(f, "no device has the id {:?}", id),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+pub type Result<T> = result::Result<T, Error>;
+
+/// The kind of guest addresses a range covers.
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BusRangeKind {
+    /// I/O ports.
+    Pio,
+    /// MMIO addresses below 4GiB.
+    Mmio32,
+    /// MMIO addresses above 4GiB.
+    Mmio64,
+}
+
+/// Guest addresses a device answers on.
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+pub struct BusRange {
+    pub kind: BusRangeKind,
+    pub base: u64,
+    pub size: u64,
+}
+
+/// What a device was given when plugged into the VM.
+#[derive(Clone, Debug, Default, PartialEq, Deserialize, Serialize)]
+pub struct DeviceResources {
+    /// The slot of the device on the PCI bus, 0 being the host bridge.
+    #[serde(default)]
+    pub pci_device_id: Option<u32>,
+    #[serde(default)]
+    pub bus_ranges: Vec<BusRange>,
+    /// The legacy interrupt lines. The MSI-X vectors are left out, as the
+    /// guest sets them up.
+    #[serde(default)]
+    pub irqs: Vec<u32>,
+    /// The guest addresses the guest writes to, to notify the device.
+    #[serde(default)]
+    pub ioeventfds: Vec<u64>,
+}
+
+/// The devices of the VM, by id.
+#[derive(Default)]
+pub struct DeviceRegistry {
+    devices: BTreeMap<String, DeviceResources>,
+}
+
+impl DeviceRegistry {
+    pub fn new() -> Self {
+        DeviceRegistry::default()
+    }
+
+    /// Records the device `id` was given `resources`. The id must not be
+    /// taken by another device yet.
+    pub fn register(&mut self, id: &str, resources: DeviceResources) -> Result<()> {
+        if self.devices.contains_key(id) {
+            return Err(Error::DuplicateId(id.to_string()));
+        }
+        self.devices.insert(id.to_string(), resources);
+
+        Ok(())
+    }
+
+    /// Forgets the device `id`, returning what it was given.
+    pub fn unregister(&mut self, id: &str) -> Result<DeviceResources> {
+        self.devices
+            .remove(id)
+            .ok_or_else(|| Error::UnknownId(id.to_string()))
+    }
+
+    pub fn get(&self, id: &str) -> Result<&DeviceResources> {
+        self.devices
+            .get(id)
+            .ok_or_else(|| Error::UnknownId(id.to_string()))
+    }
+
+    /// The id of the device in the PCI slot `pci_device_id`.
+    pub fn pci_device(&self, pci_device_id: u32) -> Option<&str> {
+        self.devices
+            .iter()
+            .find(|(_, resources)| resources.pci_device_id == Some(pci_device_id))
+            .map(|(id, _)| id.as_str())
+    }
+
+    /// All the devices, sorted by id.
+    pub fn devices(&self) -> &BTreeMap<String, DeviceResources> {
+        &self.devices
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pci_device(pci_device_id: u32, base: u64) -> DeviceResources {
+        DeviceResources {
+            pci_device_id: Some(pci_device_id),
+            bus_ranges: vec![BusRange {
+                kind: BusRangeKind::Mmio64,
+                base,
+                size: 0x1000,
+            }],
+            irqs: Vec::new(),
+            ioeventfds: vec![base + 0x100],
+        }
+    }
+
+    #[test]
+    fn test_device_registry() {
+        let mut registry = DeviceRegistry::new();
+        registry
+            .register("disk0", pci_device(2, 0x1_0000_0000))
+            .unwrap();
+        registry
+            .register("_net0", pci_device(3, 0x1_0000_1000))
+            .unwrap();
+
+        // The ids are unique.
+        assert_eq!(
+            registry.register("disk0", pci_device(4, 0x1_0000_2000)),
+            Err(Error::DuplicateId("disk0".to_string()))
+        );
+        assert_eq!(
+            registry.get("disk0").unwrap(),
+            &pci_device(2, 0x1_0000_0000)
+        );
+
+        assert_eq!(registry.pci_device(3), Some("_net0"));
+        assert_eq!(registry.pci_device(4), None);
+        assert_eq!(
+            registry.get("disk1"),
+            Err(Error::UnknownId("disk1".to_string()))
+        );
+
+        // Once unregistered, the id can be taken again.
+        assert_eq!(
+            registry.unregister("disk0").unwrap(),
+            pci_device(2, 0x1_0000_0000)
+        );
+        assert!(registry.get("disk0").is_err());
+        assert!(registry.unregister("disk0").is_err());
+        registry
+            .register("disk0", pci_device(2, 0x1_0000_2000))
+            .unwrap();
+        assert_eq!(
+            registry.devices().keys().collect::<Vec<_>>(),
+            vec!["_net0", "disk0"]
+        );
+    }
+}
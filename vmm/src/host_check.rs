@@ -0,0 +1,637 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Checks the host can run a VM, before anything is created.
+//!
+//! The host is probed first, into a `HostProbe`: access to `/dev/kvm`, the
+//! KVM API version and capabilities, the TAP device, the SGX virtual EPC
+//! device and the huge pages.
+//! `check_host()` then compares it with what a VM configuration needs, so
+//! that a missing capability is reported as such rather than as an ioctl
+//! failing with EINVAL. This is what `cloud-hypervisor --check-host` prints,
+//! and what `Vm::new()` relies on to refuse a VM the host can't run.
+
+use crate::config::VmConfig;
+use kvm_ioctls::{Cap, Kvm};
+use std::ffi::CString;
+use std::fmt;
+use std::fs::{self, OpenOptions};
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+
+/// The only KVM API version there has ever been.
+pub const KVM_API_VERSION: i32 = 12;
+
+const KVM_PATH: &str = "/dev/kvm";
+const TUN_PATH: &str = "/dev/net/tun";
+const SGX_VEPC_PATH: &str = "/dev/sgx_vepc";
+const MEMINFO_PATH: &str = "/proc/meminfo";
+const THP_ENABLED_PATH: &str = "/sys/kernel/mm/transparent_hugepage/enabled";
+const HUGETLBFS_MAGIC: i64 = 0x9584_58f6;
+
+// Capabilities the VMM does without, at a cost.
+const OPTIONAL_CAPABILITIES: [Cap; 1] = [Cap::ImmediateExit];
+
+/// What is known of the host, as probed, or made up for tests.
+#[derive(Clone, Debug, Default)]
+pub struct HostProbe {
+    /// The errno of opening `/dev/kvm`, if it failed.
+    pub kvm_error: Option<i32>,
+    pub api_version: Option<i32>,
+    /// The capabilities KVM supports, among the ones checked.
+    pub capabilities: Vec<Cap>,
+    /// The errno of opening `/dev/net/tun`, if it failed.
+    pub tun_error: Option<i32>,
+    /// The errno of opening `/dev/sgx_vepc`, if it failed.
+    pub sgx_vepc_error: Option<i32>,
+    /// Whether the file backing the guest RAM is on hugetlbfs.
+    pub memory_on_hugetlbfs: bool,
+    /// Size of the free huge pages, if known.
+    pub hugepages_free: Option<u64>,
+    /// The transparent huge pages mode, e.g. "madvise", if supported.
+    pub transparent_hugepages: Option<String>,
+}
+
+impl HostProbe {
+    /// Probes everything the configuration may need from the host.
+    pub fn probe(config: &VmConfig) -> Self {
+        let mut probe = match Kvm::new() {
+            Ok(kvm) => HostProbe::from_kvm(&kvm),
+            Err(e) => HostProbe {
+                kvm_error: Some(e.errno()),
+                ..Default::default()
+            },
+        };
+
+        probe.tun_error = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(TUN_PATH)
+            .err()
+            .map(|e| e.raw_os_error().unwrap_or(libc::EIO));
+        probe.sgx_vepc_error = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(SGX_VEPC_PATH)
+            .err()
+            .map(|e| e.raw_os_error().unwrap_or(libc::EIO));
+        probe.memory_on_hugetlbfs = config
+            .memory
+            .file
+            .as_ref()
+            .map(|path| on_hugetlbfs(path))
+            .unwrap_or(false);
+        probe.hugepages_free = fs::read_to_string(MEMINFO_PATH)
+            .ok()
+            .and_then(|meminfo| hugepages_free(&meminfo));
+        probe.transparent_hugepages = fs::read_to_string(THP_ENABLED_PATH)
+            .ok()
+            .and_then(|enabled| selected_mode(&enabled));
+
+        probe
+    }
+
+    /// Probes the KVM API version and capabilities only.
+    pub fn from_kvm(kvm: &Kvm) -> Self {
+        let mut checked = required_capabilities(None);
+        checked.extend(OPTIONAL_CAPABILITIES.iter());
+
+        HostProbe {
+            api_version: Some(kvm.get_api_version()),
+            capabilities: checked
+                .into_iter()
+                .filter(|cap| kvm.check_extension(*cap))
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    /// The capabilities required by the configuration the host lacks.
+    pub fn missing_capabilities(&self, config: &VmConfig) -> Vec<Cap> {
+        required_capabilities(Some(config))
+            .into_iter()
+            .filter(|cap| !self.capabilities.contains(cap))
+            .collect()
+    }
+}
+
+/// The KVM capabilities needed to run a VM with `config`, or with any
+/// configuration when none is given.
+#[cfg(target_arch = "x86_64")]
+pub fn required_capabilities(config: Option<&VmConfig>) -> Vec<Cap> {
+    use crate::config::IrqChipKind;
+
+    let mut required = vec![
+        Cap::UserMemory,
+        Cap::SetTssAddr,
+        Cap::ExtCpuid,
+        Cap::Irqfd,
+        Cap::Ioeventfd,
+        Cap::IrqRouting,
+        Cap::SignalMsi,
+    ];
+
+    let (irqchip_kind, create_pit) = match config {
+        Some(config) => (Some(config.create_irqchip_kind), config.create_pit),
+        None => (None, true),
+    };
+    if irqchip_kind.is_none() || irqchip_kind == Some(IrqChipKind::Kernel) {
+        required.push(Cap::Irqchip);
+    }
+    if irqchip_kind.is_none() || irqchip_kind == Some(IrqChipKind::Split) {
+        required.push(Cap::SplitIrqchip);
+    }
+    if irqchip_kind != Some(IrqChipKind::None) {
+        required.push(Cap::TscDeadlineTimer);
+    }
+    if create_pit {
+        required.push(Cap::Pit2);
+    }
+
+    required
+}
+
+/// The KVM capabilities needed to run a VM, the GIC and PSCI being always
+/// emulated in kernel on aarch64.
+#[cfg(target_arch = "aarch64")]
+pub fn required_capabilities(_config: Option<&VmConfig>) -> Vec<Cap> {
+    vec![
+        Cap::UserMemory,
+        Cap::Irqfd,
+        Cap::Ioeventfd,
+        Cap::OneReg,
+        Cap::ArmPsci02,
+        Cap::DeviceCtrl,
+    ]
+}
+
+/// The name the KVM API gives to a capability.
+pub fn capability_name(cap: Cap) -> &'static str {
+    match cap {
+        Cap::UserMemory => "KVM_CAP_USER_MEMORY",
+        Cap::Irqfd => "KVM_CAP_IRQFD",
+        Cap::Ioeventfd => "KVM_CAP_IOEVENTFD",
+        Cap::ImmediateExit => "KVM_CAP_IMMEDIATE_EXIT",
+        #[cfg(target_arch = "x86_64")]
+        Cap::SetTssAddr => "KVM_CAP_SET_TSS_ADDR",
+        #[cfg(target_arch = "x86_64")]
+        Cap::ExtCpuid => "KVM_CAP_EXT_CPUID",
+        #[cfg(target_arch = "x86_64")]
+        Cap::IrqRouting => "KVM_CAP_IRQ_ROUTING",
+        #[cfg(target_arch = "x86_64")]
+        Cap::SignalMsi => "KVM_CAP_SIGNAL_MSI",
+        #[cfg(target_arch = "x86_64")]
+        Cap::Irqchip => "KVM_CAP_IRQCHIP",
+        #[cfg(target_arch = "x86_64")]
+        Cap::SplitIrqchip => "KVM_CAP_SPLIT_IRQCHIP",
+        #[cfg(target_arch = "x86_64")]
+        Cap::TscDeadlineTimer => "KVM_CAP_TSC_DEADLINE_TIMER",
+        #[cfg(target_arch = "x86_64")]
+        Cap::Pit2 => "KVM_CAP_PIT2",
+        #[cfg(target_arch = "aarch64")]
+        Cap::OneReg => "KVM_CAP_ONE_REG",
+        #[cfg(target_arch = "aarch64")]
+        Cap::ArmPsci02 => "KVM_CAP_ARM_PSCI_0_2",
+        #[cfg(target_arch = "aarch64")]
+        Cap::DeviceCtrl => "KVM_CAP_DEVICE_CTRL",
+        _ => "KVM_CAP_UNKNOWN",
+    }
+}
+
+/// Explains why `/dev/kvm` couldn't be opened, from the errno.
+pub fn kvm_error_reason(errno: i32) -> String {
+    match errno {
+        libc::ENOENT => format!("{} not found, is the KVM module loaded?", KVM_PATH),
+        libc::EACCES | libc::EPERM => format!(
+            "no permission to open {}, is the user in the kvm group?",
+            KVM_PATH
+        ),
+        _ => format!(
+            "cannot open {}: {}",
+            KVM_PATH,
+            io::Error::from_raw_os_error(errno)
+        ),
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CheckStatus {
+    Pass,
+    /// The VM can run, but not as well as it could.
+    Warn,
+    Fail,
+}
+
+/// The result of checking one thing the VM needs from the host.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct HostCheck {
+    pub name: String,
+    pub status: CheckStatus,
+    pub detail: String,
+}
+
+/// The results of the checks, in the order they were made.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct HostReport {
+    pub checks: Vec<HostCheck>,
+}
+
+impl HostReport {
+    fn add(&mut self, name: &str, status: CheckStatus, detail: String) {
+        self.checks.push(HostCheck {
+            name: name.to_string(),
+            status,
+            detail,
+        });
+    }
+
+    /// Whether the host can run the VM, warnings aside.
+    pub fn passed(&self) -> bool {
+        self.checks.iter().all(|c| c.status != CheckStatus::Fail)
+    }
+}
+
+impl fmt::Display for HostReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for check in self.checks.iter() {
+            let status = match check.status {
+                CheckStatus::Pass => "PASS",
+                CheckStatus::Warn => "WARN",
+                CheckStatus::Fail => "FAIL",
+            };
+            writeln!(f, "[{}] {}: {}", status, check.name, check.detail)?;
+        }
+        write!(
+            f,
+            "{}",
+            if self.passed() {
+                "The host can run the VM."
+            } else {
+                "The host cannot run the VM."
+            }
+        )
+    }
+}
+
+/// Checks the host, as probed, has what the VM needs.
+pub fn check_host(probe: &HostProbe, config: &VmConfig) -> HostReport {
+    let mut report = HostReport::default();
+
+    // Nothing else about KVM can be known without access to it.
+    if let Some(errno) = probe.kvm_error {
+        report.add("kvm", CheckStatus::Fail, kvm_error_reason(errno));
+    } else {
+        report.add("kvm", CheckStatus::Pass, format!("{} accessible", KVM_PATH));
+        match probe.api_version {
+            Some(KVM_API_VERSION) => report.add(
+                "kvm_api_version",
+                CheckStatus::Pass,
+                format!("version {}", KVM_API_VERSION),
+            ),
+            version => report.add(
+                "kvm_api_version",
+                CheckStatus::Fail,
+                format!(
+                    "version {} instead of {}",
+                    version.unwrap_or_default(),
+                    KVM_API_VERSION
+                ),
+            ),
+        }
+
+        for cap in required_capabilities(Some(config)) {
+            if probe.capabilities.contains(&cap) {
+                report.add(capability_name(cap), CheckStatus::Pass, "supported".into());
+            } else {
+                report.add(
+                    capability_name(cap),
+                    CheckStatus::Fail,
+                    format!("host kernel lacks {}", capability_name(cap)),
+                );
+            }
+        }
+        for cap in OPTIONAL_CAPABILITIES.iter() {
+            if probe.capabilities.contains(cap) {
+                report.add(capability_name(*cap), CheckStatus::Pass, "supported".into());
+            } else {
+                report.add(
+                    capability_name(*cap),
+                    CheckStatus::Warn,
+                    format!("host kernel lacks {}", capability_name(*cap)),
+                );
+            }
+        }
+    }
+
+    // The vhost-user interfaces are set up by their backend.
+    let tap_needed = config
+        .net
+        .as_ref()
+        .map(|net| net.iter().any(|n| !n.vhost_user))
+        .unwrap_or(false);
+    if tap_needed {
+        match probe.tun_error {
+            None => report.add("tap", CheckStatus::Pass, format!("{} accessible", TUN_PATH)),
+            Some(errno) => report.add(
+                "tap",
+                CheckStatus::Fail,
+                format!(
+                    "cannot open {}: {}",
+                    TUN_PATH,
+                    io::Error::from_raw_os_error(errno)
+                ),
+            ),
+        }
+    }
+
+    if config.sgx_epc.is_some() {
+        match probe.sgx_vepc_error {
+            None => report.add(
+                "sgx_epc",
+                CheckStatus::Pass,
+                format!("{} accessible", SGX_VEPC_PATH),
+            ),
+            Some(libc::ENOENT) => report.add(
+                "sgx_epc",
+                CheckStatus::Fail,
+                format!(
+                    "no {}, the host doesn't support SGX virtualization",
+                    SGX_VEPC_PATH
+                ),
+            ),
+            Some(errno) => report.add(
+                "sgx_epc",
+                CheckStatus::Fail,
+                format!(
+                    "cannot open {}: {}",
+                    SGX_VEPC_PATH,
+                    io::Error::from_raw_os_error(errno)
+                ),
+            ),
+        }
+    }
+
+    if probe.memory_on_hugetlbfs {
+        let needed = config.memory.size;
+        match probe.hugepages_free {
+            Some(free) if free >= needed => report.add(
+                "hugepages",
+                CheckStatus::Pass,
+                format!("{} MiB free, {} MiB needed", free >> 20, needed >> 20),
+            ),
+            free => report.add(
+                "hugepages",
+                CheckStatus::Fail,
+                format!(
+                    "{} MiB free, {} MiB needed",
+                    free.unwrap_or_default() >> 20,
+                    needed >> 20
+                ),
+            ),
+        }
+    }
+
+    // The VM runs without them, they only help.
+    if config.memory.transparent_hugepages == Some(true) {
+        match probe.transparent_hugepages.as_deref() {
+            Some("never") => report.add(
+                "transparent_hugepages",
+                CheckStatus::Warn,
+                "disabled on the host".into(),
+            ),
+            Some(mode) => report.add(
+                "transparent_hugepages",
+                CheckStatus::Pass,
+                format!("{} mode", mode),
+            ),
+            None => report.add(
+                "transparent_hugepages",
+                CheckStatus::Warn,
+                "not supported by the host kernel".into(),
+            ),
+        }
+    }
+
+    report
+}
+
+fn on_hugetlbfs(path: &Path) -> bool {
+    let path = match CString::new(path.as_os_str().as_bytes()) {
+        Ok(path) => path,
+        Err(_) => return false,
+    };
+    // Safe because the path is a valid C string, and the kernel only fills
+    // the structure in.
+    let mut stat: libc::statfs = unsafe { std::mem::zeroed() };
+    let ret = unsafe { libc::statfs(path.as_ptr(), &mut stat) };
+    ret == 0 && stat.f_type as i64 == HUGETLBFS_MAGIC
+}
+
+// The size of the free huge pages, of the default size, in /proc/meminfo.
+fn hugepages_free(meminfo: &str) -> Option<u64> {
+    let field = |name: &str| {
+        meminfo
+            .lines()
+            .find(|line| line.starts_with(name))
+            .and_then(|line| line[name.len()..].split_whitespace().next())
+            .and_then(|value| value.parse::<u64>().ok())
+    };
+    Some(field("HugePages_Free:")? * (field("Hugepagesize:")? << 10))
+}
+
+// The mode selected among the ones of a sysfs file, e.g.
+// "always [madvise] never".
+fn selected_mode(modes: &str) -> Option<String> {
+    modes
+        .split_whitespace()
+        .find(|mode| mode.starts_with('['))
+        .map(|mode| mode.trim_matches(|c| c == '[' || c == ']').to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{MemoryConfig, NetConfig, SgxEpcConfig, VmConfig};
+
+    fn vm_config() -> VmConfig {
+        serde_json::from_str(r#"{"kernel": {"path": "/path/to/kernel"}}"#).unwrap()
+    }
+
+    fn probe(capabilities: Vec<Cap>) -> HostProbe {
+        HostProbe {
+            api_version: Some(KVM_API_VERSION),
+            capabilities,
+            ..Default::default()
+        }
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn test_check_capabilities() {
+        let mut config = vm_config();
+        let mut capabilities = required_capabilities(Some(&config));
+        capabilities.push(Cap::ImmediateExit);
+        let report = check_host(&probe(capabilities.clone()), &config);
+        assert!(report.passed());
+        assert!(report.checks.iter().all(|c| c.status == CheckStatus::Pass));
+        assert_eq!(report.checks[0].detail, "/dev/kvm accessible");
+
+        // A missing optional capability is only a warning.
+        capabilities.retain(|cap| *cap != Cap::ImmediateExit && *cap != Cap::SplitIrqchip);
+        let report = check_host(&probe(capabilities.clone()), &config);
+        assert!(!report.passed());
+        assert_eq!(
+            report.to_string().lines().collect::<Vec<&str>>()[2..],
+            [
+                "[PASS] KVM_CAP_USER_MEMORY: supported",
+                "[PASS] KVM_CAP_SET_TSS_ADDR: supported",
+                "[PASS] KVM_CAP_EXT_CPUID: supported",
+                "[PASS] KVM_CAP_IRQFD: supported",
+                "[PASS] KVM_CAP_IOEVENTFD: supported",
+                "[PASS] KVM_CAP_IRQ_ROUTING: supported",
+                "[PASS] KVM_CAP_SIGNAL_MSI: supported",
+                "[FAIL] KVM_CAP_SPLIT_IRQCHIP: host kernel lacks KVM_CAP_SPLIT_IRQCHIP",
+                "[PASS] KVM_CAP_TSC_DEADLINE_TIMER: supported",
+                "[WARN] KVM_CAP_IMMEDIATE_EXIT: host kernel lacks KVM_CAP_IMMEDIATE_EXIT",
+                "The host cannot run the VM.",
+            ]
+        );
+        assert_eq!(
+            probe(capabilities.clone()).missing_capabilities(&config),
+            vec![Cap::SplitIrqchip]
+        );
+
+        // Without a split irqchip, the capability isn't needed.
+        config.create_irqchip_kind = crate::config::IrqChipKind::None;
+        assert!(check_host(&probe(capabilities), &config).passed());
+    }
+
+    #[test]
+    fn test_check_kvm_access() {
+        let config = vm_config();
+        let host = HostProbe {
+            kvm_error: Some(libc::EACCES),
+            ..Default::default()
+        };
+        let report = check_host(&host, &config);
+        assert_eq!(
+            report.to_string(),
+            "[FAIL] kvm: no permission to open /dev/kvm, is the user in the kvm group?\n\
+             The host cannot run the VM."
+        );
+
+        let host = HostProbe {
+            api_version: Some(11),
+            ..probe(required_capabilities(Some(&config)))
+        };
+        let report = check_host(&host, &config);
+        assert_eq!(report.checks[1].status, CheckStatus::Fail);
+        assert_eq!(report.checks[1].detail, "version 11 instead of 12");
+    }
+
+    #[test]
+    fn test_check_tap_and_hugepages() {
+        let mut config = vm_config();
+        let net: NetConfig = serde_json::from_str("{}").unwrap();
+        config.net = Some(vec![net]);
+        config.memory = MemoryConfig {
+            size: 1 << 30,
+            file: Some("/dev/hugepages".into()),
+            transparent_hugepages: Some(true),
+            ..Default::default()
+        };
+        let host = HostProbe {
+            tun_error: Some(libc::ENOENT),
+            memory_on_hugetlbfs: true,
+            hugepages_free: Some(512 << 20),
+            transparent_hugepages: Some("never".to_string()),
+            ..probe(required_capabilities(Some(&config)))
+        };
+        let report = check_host(&host, &config);
+        let checks: Vec<(&str, CheckStatus, &str)> = report
+            .checks
+            .iter()
+            .filter(|c| !c.name.starts_with("kvm") && !c.name.starts_with("KVM_CAP"))
+            .map(|c| (c.name.as_str(), c.status, c.detail.as_str()))
+            .collect();
+        assert_eq!(
+            checks,
+            vec![
+                (
+                    "tap",
+                    CheckStatus::Fail,
+                    "cannot open /dev/net/tun: No such file or directory (os error 2)"
+                ),
+                (
+                    "hugepages",
+                    CheckStatus::Fail,
+                    "512 MiB free, 1024 MiB needed"
+                ),
+                (
+                    "transparent_hugepages",
+                    CheckStatus::Warn,
+                    "disabled on the host"
+                ),
+            ]
+        );
+
+        let json = serde_json::to_value(&report).unwrap();
+        assert_eq!(json["checks"][0]["name"], "kvm");
+        assert_eq!(json["checks"][0]["status"], "pass");
+    }
+
+    #[test]
+    fn test_check_sgx_epc() {
+        let mut config = vm_config();
+        let host = HostProbe {
+            sgx_vepc_error: Some(libc::ENOENT),
+            ..probe(required_capabilities(Some(&config)))
+        };
+        // The device is only needed for EPC sections.
+        assert!(check_host(&host, &config).passed());
+
+        config.sgx_epc = Some(vec![SgxEpcConfig {
+            size: 64 << 20,
+            prefault: false,
+        }]);
+        let report = check_host(&host, &config);
+        assert!(!report.passed());
+        let check = report.checks.last().unwrap();
+        assert_eq!(check.name, "sgx_epc");
+        assert_eq!(
+            check.detail,
+            "no /dev/sgx_vepc, the host doesn't support SGX virtualization"
+        );
+
+        let host = HostProbe {
+            sgx_vepc_error: None,
+            ..host
+        };
+        let report = check_host(&host, &config);
+        assert!(report.passed());
+        assert_eq!(
+            report.checks.last().unwrap().detail,
+            "/dev/sgx_vepc accessible"
+        );
+    }
+
+    #[test]
+    fn test_parse_host_files() {
+        let meminfo = "MemTotal:       16318412 kB\n\
+                       HugePages_Total:     512\n\
+                       HugePages_Free:      256\n\
+                       Hugepagesize:       2048 kB\n";
+        assert_eq!(hugepages_free(meminfo), Some(512 << 20));
+        assert_eq!(hugepages_free("MemTotal:       16318412 kB\n"), None);
+
+        assert_eq!(
+            selected_mode("always [madvise] never\n"),
+            Some("madvise".to_string())
+        );
+        assert_eq!(selected_mode("always madvise never"), None);
+    }
+}
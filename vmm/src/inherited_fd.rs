@@ -0,0 +1,116 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Files inherited from the process starting the VMM, given by their file
+//! descriptor instead of a path, e.g. a kernel unpacked in a memfd.
+//!
+//! The inherited file descriptor is never closed, as the VM config may need
+//! it again, e.g. to load the kernel once more when the VM reboots. Each user
+//! opens the file again through `/proc/self/fd`, getting a file description
+//! of its own, closed along with the `File` owning it. Its offset and status
+//! flags, e.g. `O_DIRECT`, are not shared with the inherited file descriptor.
+
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::RawFd;
+use std::path::PathBuf;
+
+fn invalid(fd: RawFd, reason: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("file descriptor {} {}", fd, reason),
+    )
+}
+
+/// The path the file behind the inherited `fd` is opened again through.
+pub fn path(fd: RawFd) -> PathBuf {
+    PathBuf::from(format!("/proc/self/fd/{}", fd))
+}
+
+/// Opens the file behind the inherited `fd` again, from its start, with the
+/// `custom_flags` given to open(2). The file must be a regular file, or a
+/// block device if `block_device` is set, and be open for writing if
+/// `writable` is set.
+pub fn open(
+    fd: RawFd,
+    block_device: bool,
+    writable: bool,
+    custom_flags: libc::c_int,
+) -> io::Result<File> {
+    // Safe because the return value is checked.
+    let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+    if flags < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    if writable && flags & libc::O_ACCMODE == libc::O_RDONLY {
+        return Err(invalid(fd, "is not open for writing"));
+    }
+
+    // Safe because stat only holds integers.
+    let mut stat: libc::stat = unsafe { std::mem::zeroed() };
+    // Safe because the return value is checked.
+    if unsafe { libc::fstat(fd, &mut stat) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    match stat.st_mode & libc::S_IFMT {
+        libc::S_IFREG => {}
+        libc::S_IFBLK if block_device => {}
+        _ if block_device => return Err(invalid(fd, "is neither a file nor a block device")),
+        _ => return Err(invalid(fd, "is not a regular file")),
+    }
+
+    OpenOptions::new()
+        .read(true)
+        .write(writable)
+        .custom_flags(custom_flags)
+        .open(path(fd))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::CString;
+    use std::io::{Read, Seek, SeekFrom, Write};
+    use std::os::unix::io::{AsRawFd, FromRawFd};
+
+    fn memfd() -> File {
+        let name = CString::new("inherited_fd").unwrap();
+        // Safe because the return value is checked.
+        let fd = unsafe { libc::memfd_create(name.as_ptr(), 0) };
+        assert!(fd >= 0);
+        // Safe because the fd was just created.
+        unsafe { File::from_raw_fd(fd) }
+    }
+
+    #[test]
+    fn test_open_inherited_fd() {
+        let mut memfd = memfd();
+        memfd.write_all(b"kernel").unwrap();
+
+        // The file reads from the start, and can be opened again, leaving
+        // the offset of the inherited file descriptor alone.
+        for _ in 0..2 {
+            let mut file = open(memfd.as_raw_fd(), false, true, 0).unwrap();
+            let mut data = String::new();
+            file.read_to_string(&mut data).unwrap();
+            assert_eq!(data, "kernel");
+        }
+        assert_eq!(memfd.seek(SeekFrom::Current(0)).unwrap(), 6);
+
+        let mut fds = [0; 2];
+        // Safe because the return value is checked.
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+        // Safe because the fds were just created.
+        let (reader, _writer) = unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) };
+        assert!(open(reader.as_raw_fd(), true, false, 0).is_err());
+
+        let read_only = File::open(format!("/proc/self/fd/{}", memfd.as_raw_fd())).unwrap();
+        assert!(open(read_only.as_raw_fd(), false, false, 0).is_ok());
+        assert!(open(read_only.as_raw_fd(), false, true, 0).is_err());
+
+        assert!(open(-1, false, false, 0).is_err());
+    }
+}
@@ -5,6 +5,8 @@
 
 use devices::ioapic;
 use kvm_bindings::{kvm_irq_routing, kvm_irq_routing_entry, KVM_IRQ_ROUTING_MSI};
+#[cfg(target_arch = "x86_64")]
+use kvm_bindings::{KVM_IRQCHIP_IOAPIC, KVM_IRQ_ROUTING_IRQCHIP};
 use kvm_ioctls::VmFd;
 use std::collections::HashMap;
 use std::io;
@@ -88,26 +90,59 @@ pub struct KvmRoutingEntry {
     masked: bool,
 }
 
+impl KvmRoutingEntry {
+    /// Routes `gsi` to the pin with the same number on the in-kernel IOAPIC.
+    /// Needed with a kernel irqchip, since setting the MSI routes replaces
+    /// the default routing table KVM sets up.
+    #[cfg(target_arch = "x86_64")]
+    pub fn ioapic_pin(gsi: u32) -> Self {
+        let mut kvm_route = kvm_irq_routing_entry {
+            gsi,
+            type_: KVM_IRQ_ROUTING_IRQCHIP,
+            ..Default::default()
+        };
+
+        kvm_route.u.irqchip.irqchip = KVM_IRQCHIP_IOAPIC;
+        kvm_route.u.irqchip.pin = gsi;
+
+        KvmRoutingEntry {
+            kvm_route,
+            masked: false,
+        }
+    }
+}
+
 pub struct MsiInterruptGroup {
+    allocator: Arc<Mutex<SystemAllocator>>,
     vm_fd: Arc<VmFd>,
     gsi_msi_routes: Arc<Mutex<HashMap<u32, KvmRoutingEntry>>>,
     irq_routes: HashMap<InterruptIndex, InterruptRoute>,
+    irqchip: bool,
 }
 
 impl MsiInterruptGroup {
     fn new(
+        allocator: Arc<Mutex<SystemAllocator>>,
         vm_fd: Arc<VmFd>,
         gsi_msi_routes: Arc<Mutex<HashMap<u32, KvmRoutingEntry>>>,
         irq_routes: HashMap<InterruptIndex, InterruptRoute>,
+        irqchip: bool,
     ) -> Self {
         MsiInterruptGroup {
+            allocator,
             vm_fd,
             gsi_msi_routes,
             irq_routes,
+            irqchip,
         }
     }
 
     fn set_kvm_gsi_routes(&self) -> Result<()> {
+        // Without an irqchip, KVM has nothing to route the GSIs to.
+        if !self.irqchip {
+            return Ok(());
+        }
+
         let gsi_msi_routes = self.gsi_msi_routes.lock().unwrap();
         let mut entry_vec: Vec<kvm_irq_routing_entry> = Vec::new();
         for (_, entry) in gsi_msi_routes.iter() {
@@ -161,6 +196,10 @@ impl MsiInterruptGroup {
 
 impl InterruptSourceGroup for MsiInterruptGroup {
     fn enable(&self) -> Result<()> {
+        if !self.irqchip {
+            return Ok(());
+        }
+
         for (_, route) in self.irq_routes.iter() {
             route.enable(&self.vm_fd)?;
         }
@@ -169,6 +208,10 @@ impl InterruptSourceGroup for MsiInterruptGroup {
     }
 
     fn disable(&self) -> Result<()> {
+        if !self.irqchip {
+            return Ok(());
+        }
+
         for (_, route) in self.irq_routes.iter() {
             route.disable(&self.vm_fd)?;
         }
@@ -242,6 +285,27 @@ impl InterruptSourceGroup for MsiInterruptGroup {
     }
 }
 
+// Removes the routes of the group and gives its GSIs back, once the device
+// owning it is gone, e.g. unplugged.
+impl Drop for MsiInterruptGroup {
+    fn drop(&mut self) {
+        {
+            let mut gsi_msi_routes = self.gsi_msi_routes.lock().unwrap();
+            for route in self.irq_routes.values() {
+                gsi_msi_routes.remove(&route.gsi);
+            }
+        }
+        if let Err(e) = self.set_kvm_gsi_routes() {
+            warn!("Failed removing the MSI routes: {}", e);
+        }
+
+        let mut allocator = self.allocator.lock().unwrap();
+        for route in self.irq_routes.values() {
+            allocator.free_gsi(route.gsi);
+        }
+    }
+}
+
 pub struct LegacyUserspaceInterruptGroup {
     ioapic: Arc<Mutex<ioapic::Ioapic>>,
     irq: u32,
@@ -272,14 +336,86 @@ impl InterruptSourceGroup for LegacyUserspaceInterruptGroup {
     }
 }
 
+// On aarch64, KVM_IRQ_LINE takes the type of the interrupt in bits 24-31
+// and, for an SPI, its GIC interrupt ID, which follows the 32 private ones.
+#[cfg(target_arch = "aarch64")]
+const KVM_ARM_IRQ_TYPE_SPI: u32 = 1;
+#[cfg(target_arch = "aarch64")]
+const KVM_ARM_IRQ_TYPE_SHIFT: u32 = 24;
+#[cfg(target_arch = "aarch64")]
+const GIC_SPI_BASE: u32 = 32;
+
+pub struct KvmLegacyInterruptGroup {
+    vm_fd: Arc<VmFd>,
+    irq: u32,
+    irqchip: bool,
+}
+
+impl KvmLegacyInterruptGroup {
+    fn new(vm_fd: Arc<VmFd>, irq: u32, irqchip: bool) -> Self {
+        KvmLegacyInterruptGroup {
+            vm_fd,
+            irq,
+            irqchip,
+        }
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    fn irq_line(&self) -> u32 {
+        self.irq
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    fn irq_line(&self) -> u32 {
+        (KVM_ARM_IRQ_TYPE_SPI << KVM_ARM_IRQ_TYPE_SHIFT) | (GIC_SPI_BASE + self.irq)
+    }
+
+    fn set_irq_line(&self, active: bool) -> Result<()> {
+        self.vm_fd
+            .set_irq_line(self.irq_line(), active)
+            .map_err(|e| {
+                io::Error::new(
+                    io::ErrorKind::Other,
+                    format!("failed to inject IRQ #{}: {}", self.irq, e),
+                )
+            })
+    }
+}
+
+impl InterruptSourceGroup for KvmLegacyInterruptGroup {
+    fn trigger(&self, _index: InterruptIndex) -> Result<()> {
+        // Without an irqchip, there is no line to raise.
+        if !self.irqchip {
+            return Ok(());
+        }
+
+        self.set_irq_line(true)?;
+        self.set_irq_line(false)
+    }
+
+    fn update(&self, _index: InterruptIndex, _config: InterruptSourceConfig) -> Result<()> {
+        Ok(())
+    }
+}
+
 pub struct KvmLegacyUserspaceInterruptManager {
     ioapic: Arc<Mutex<ioapic::Ioapic>>,
 }
 
+/// Legacy interrupt manager raising the lines of the in-kernel irqchip.
+/// Without one, the interrupts are dropped.
+pub struct KvmLegacyInterruptManager {
+    vm_fd: Arc<VmFd>,
+    irqchip: bool,
+}
+
+/// MSI interrupt manager routing the GSIs through KVM. Without an irqchip,
+/// neither the irqfds nor the GSI routes are set up.
 pub struct KvmMsiInterruptManager {
     allocator: Arc<Mutex<SystemAllocator>>,
     vm_fd: Arc<VmFd>,
     gsi_msi_routes: Arc<Mutex<HashMap<u32, KvmRoutingEntry>>>,
+    irqchip: bool,
 }
 
 impl KvmLegacyUserspaceInterruptManager {
@@ -288,16 +424,24 @@ impl KvmLegacyUserspaceInterruptManager {
     }
 }
 
+impl KvmLegacyInterruptManager {
+    pub fn new(vm_fd: Arc<VmFd>, irqchip: bool) -> Self {
+        KvmLegacyInterruptManager { vm_fd, irqchip }
+    }
+}
+
 impl KvmMsiInterruptManager {
     pub fn new(
         allocator: Arc<Mutex<SystemAllocator>>,
         vm_fd: Arc<VmFd>,
         gsi_msi_routes: Arc<Mutex<HashMap<u32, KvmRoutingEntry>>>,
+        irqchip: bool,
     ) -> Self {
         KvmMsiInterruptManager {
             allocator,
             vm_fd,
             gsi_msi_routes,
+            irqchip,
         }
     }
 }
@@ -320,6 +464,25 @@ impl InterruptManager for KvmLegacyUserspaceInterruptManager {
     }
 }
 
+impl InterruptManager for KvmLegacyInterruptManager {
+    type GroupConfig = LegacyIrqGroupConfig;
+
+    fn create_group(
+        &self,
+        config: Self::GroupConfig,
+    ) -> Result<Arc<Box<dyn InterruptSourceGroup>>> {
+        Ok(Arc::new(Box::new(KvmLegacyInterruptGroup::new(
+            self.vm_fd.clone(),
+            config.irq as u32,
+            self.irqchip,
+        ))))
+    }
+
+    fn destroy_group(&self, _group: Arc<Box<dyn InterruptSourceGroup>>) -> Result<()> {
+        Ok(())
+    }
+}
+
 impl InterruptManager for KvmMsiInterruptManager {
     type GroupConfig = MsiIrqGroupConfig;
 
@@ -335,13 +498,17 @@ impl InterruptManager for KvmMsiInterruptManager {
         }
 
         Ok(Arc::new(Box::new(MsiInterruptGroup::new(
+            self.allocator.clone(),
             self.vm_fd.clone(),
             self.gsi_msi_routes.clone(),
             irq_routes,
+            self.irqchip,
         ))))
     }
 
-    fn destroy_group(&self, _group: Arc<Box<dyn InterruptSourceGroup>>) -> Result<()> {
-        Ok(())
+    // The routes and GSIs of the group are released along with its last
+    // reference, which the device owning it may still hold.
+    fn destroy_group(&self, group: Arc<Box<dyn InterruptSourceGroup>>) -> Result<()> {
+        group.disable()
     }
 }
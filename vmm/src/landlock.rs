@@ -0,0 +1,490 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Filesystem access of the VMM restricted with Landlock, to the files the
+//! VM config names.
+//!
+//! The rules are built from the VM config, and applied once the VM is
+//! created, before it boots. Landlock restricts the thread applying the rules
+//! and the ones it spawns from then on, which covers the VMM thread, the vCPUs
+//! and the device threads, the VMM thread creating the VM again on reboot.
+//! The main thread and the HTTP API thread, started before, are not
+//! restricted. The restriction can't be lifted, and is not applied when the
+//! host kernel lacks Landlock.
+
+use crate::config::VmConfig;
+use crate::inherited_fd;
+use crate::vmgenid::RANDOM_SOURCE;
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::mem;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::path::{Path, PathBuf};
+use std::ptr::null;
+use std::result;
+
+// The syscall numbers are the same on x86_64 and aarch64.
+const SYS_LANDLOCK_CREATE_RULESET: libc::c_long = 444;
+const SYS_LANDLOCK_ADD_RULE: libc::c_long = 445;
+const SYS_LANDLOCK_RESTRICT_SELF: libc::c_long = 446;
+
+const LANDLOCK_CREATE_RULESET_VERSION: u32 = 1 << 0;
+const LANDLOCK_RULE_PATH_BENEATH: libc::c_int = 1;
+
+const ACCESS_FS_EXECUTE: u64 = 1 << 0;
+const ACCESS_FS_WRITE_FILE: u64 = 1 << 1;
+const ACCESS_FS_READ_FILE: u64 = 1 << 2;
+const ACCESS_FS_READ_DIR: u64 = 1 << 3;
+const ACCESS_FS_REMOVE_FILE: u64 = 1 << 5;
+const ACCESS_FS_MAKE_REG: u64 = 1 << 8;
+const ACCESS_FS_MAKE_SOCK: u64 = 1 << 9;
+// The accesses of the first Landlock ABI, which the later ones all handle.
+const ACCESS_FS_ABI_1: u64 = (1 << 13) - 1;
+// The accesses a rule can allow on a file, rather than on a directory.
+const ACCESS_FS_FILE: u64 = ACCESS_FS_EXECUTE | ACCESS_FS_WRITE_FILE | ACCESS_FS_READ_FILE;
+
+const KVM_PATH: &str = "/dev/kvm";
+const TUN_PATH: &str = "/dev/net/tun";
+const VHOST_NET_PATH: &str = "/dev/vhost-net";
+const VFIO_PATH: &str = "/dev/vfio";
+const SGX_VEPC_PATH: &str = "/dev/sgx_vepc";
+const NODE_PATH: &str = "/sys/devices/system/node";
+const MEMINFO_PATH: &str = "/proc/meminfo";
+const STATM_PATH: &str = "/proc/self/statm";
+
+#[repr(C)]
+struct RulesetAttr {
+    handled_access_fs: u64,
+}
+
+#[repr(C, packed)]
+struct PathBeneathAttr {
+    allowed_access: u64,
+    parent_fd: i32,
+}
+
+#[derive(Debug)]
+pub enum Error {
+    /// Cannot create the Landlock ruleset.
+    CreateRuleset(io::Error),
+    /// Cannot allow the access to a path.
+    AddRule(PathBuf, io::Error),
+    /// Cannot forbid the thread from gaining privileges.
+    NoNewPrivs(io::Error),
+    /// Cannot apply the Landlock ruleset.
+    RestrictSelf(io::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::CreateRuleset(_) => write!(f, "cannot create the Landlock ruleset"),
+            Error::AddRule(path, _) => write!(f, "cannot allow the access to {:?}", path),
+            Error::NoNewPrivs(_) => write!(f, "cannot forbid the VMM from gaining privileges"),
+            Error::RestrictSelf(_) => write!(f, "cannot apply the Landlock ruleset"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::CreateRuleset(e) => Some(e),
+            Error::AddRule(_, e) => Some(e),
+            Error::NoNewPrivs(e) => Some(e),
+            Error::RestrictSelf(e) => Some(e),
+        }
+    }
+}
+
+pub type Result<T> = result::Result<T, Error>;
+
+/// The access allowed to a file, or to the files beneath a directory.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Access {
+    Read,
+    ReadWrite,
+    /// Reading, writing, creating and removing files, e.g. the Unix sockets
+    /// the VMM listens on. Only applies to directories.
+    Create,
+}
+
+impl Access {
+    fn flags(self) -> u64 {
+        match self {
+            Access::Read => ACCESS_FS_READ_FILE | ACCESS_FS_READ_DIR,
+            Access::ReadWrite => ACCESS_FS_READ_FILE | ACCESS_FS_READ_DIR | ACCESS_FS_WRITE_FILE,
+            Access::Create => {
+                ACCESS_FS_READ_FILE
+                    | ACCESS_FS_READ_DIR
+                    | ACCESS_FS_WRITE_FILE
+                    | ACCESS_FS_MAKE_REG
+                    | ACCESS_FS_MAKE_SOCK
+                    | ACCESS_FS_REMOVE_FILE
+            }
+        }
+    }
+}
+
+impl fmt::Display for Access {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Access::Read => write!(f, "read"),
+            Access::ReadWrite => write!(f, "read-write"),
+            Access::Create => write!(f, "create"),
+        }
+    }
+}
+
+/// A path the VMM is allowed to access.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Rule {
+    pub path: PathBuf,
+    pub access: Access,
+}
+
+impl Rule {
+    pub fn new<P: AsRef<Path>>(path: P, access: Access) -> Self {
+        Rule {
+            path: path.as_ref().to_path_buf(),
+            access,
+        }
+    }
+}
+
+// Unix sockets are created by binding them, in the directory holding them.
+fn socket_dir(path: &Path) -> Rule {
+    let dir = match path.parent() {
+        Some(dir) if dir != Path::new("") => dir,
+        _ => Path::new("."),
+    };
+    Rule::new(dir, Access::Create)
+}
+
+/// The paths the VM `config` needs access to, from its creation until it is
+/// deleted, reboots included. The VMM always reads the host memory usage,
+/// for the metrics and the memory pressure monitor, and the random source of
+/// the VM generation ID.
+pub fn rules(config: &VmConfig) -> Vec<Rule> {
+    let mut rules = vec![
+        Rule::new(KVM_PATH, Access::ReadWrite),
+        Rule::new(MEMINFO_PATH, Access::Read),
+        Rule::new(STATM_PATH, Access::Read),
+        Rule::new(RANDOM_SOURCE, Access::Read),
+    ];
+    let mut add = |path: &Path, access: Access| {
+        let rule = Rule::new(path, access);
+        if !path.as_os_str().is_empty() && !rules.contains(&rule) {
+            rules.push(rule);
+        }
+    };
+
+    if let Some(firmware) = &config.firmware {
+        add(&firmware.path, Access::Read);
+    } else if let Some(kernel) = &config.kernel {
+        if let Some(fd) = kernel.fd {
+            add(&inherited_fd::path(fd), Access::Read);
+        } else if kernel.image.is_none() {
+            add(&kernel.path, Access::Read);
+        }
+    }
+
+    if let Some(file) = &config.memory.file {
+        add(file, Access::Create);
+    }
+
+    for disk in config.disks.iter().flatten() {
+        if disk.vhost_user {
+            if let Some(socket) = &disk.vhost_socket {
+                add(Path::new(socket), Access::ReadWrite);
+            }
+        } else {
+            let access = if disk.readonly {
+                Access::Read
+            } else {
+                Access::ReadWrite
+            };
+            match disk.fd {
+                Some(fd) => add(&inherited_fd::path(fd), access),
+                None => add(&disk.path, access),
+            }
+        }
+    }
+
+    let mut tap = false;
+    let mut vhost_net = false;
+    for net in config.net.iter().flatten() {
+        if net.vhost_user {
+            if let Some(socket) = &net.vhost_socket {
+                add(Path::new(socket), Access::ReadWrite);
+            }
+        } else {
+            tap = true;
+            vhost_net |= net.vhost_net;
+        }
+        if let Some(pcap) = &net.pcap {
+            add(pcap, Access::ReadWrite);
+        }
+    }
+    if tap {
+        add(Path::new(TUN_PATH), Access::ReadWrite);
+    }
+    if vhost_net {
+        add(Path::new(VHOST_NET_PATH), Access::ReadWrite);
+    }
+
+    // A seeded rng device does not read its entropy source.
+    if config.rng_seed.is_none() {
+        add(&config.rng.src, Access::Read);
+    }
+
+    for fs in config.fs.iter().flatten() {
+        add(&fs.sock, Access::ReadWrite);
+    }
+    for pmem in config.pmem.iter().flatten() {
+        add(&pmem.file, Access::ReadWrite);
+    }
+    for scsi in config.scsi.iter().flatten() {
+        add(&scsi.file, Access::ReadWrite);
+    }
+
+    for console in [&config.serial, &config.console].iter() {
+        if let Some(file) = &console.file {
+            add(file, Access::ReadWrite);
+        }
+    }
+
+    if config.devices.is_some() {
+        add(Path::new(VFIO_PATH), Access::ReadWrite);
+    }
+    for device in config.devices.iter().flatten() {
+        add(&device.path, Access::Read);
+    }
+
+    for vhost_user_net in config.vhost_user_net.iter().flatten() {
+        add(Path::new(&vhost_user_net.sock), Access::ReadWrite);
+    }
+    for vhost_user_blk in config.vhost_user_blk.iter().flatten() {
+        add(Path::new(&vhost_user_blk.sock), Access::ReadWrite);
+    }
+
+    let mut socket_dirs = Vec::new();
+    for vsock in config.vsock.iter().flatten() {
+        socket_dirs.push(socket_dir(&vsock.sock));
+        for listen in vsock.listen.iter().flatten() {
+            socket_dirs.push(socket_dir(&listen.uds));
+        }
+    }
+    if let Some(metrics_socket) = &config.metrics_socket {
+        socket_dirs.push(socket_dir(metrics_socket));
+    }
+
+    if config.numa.iter().flatten().any(|n| n.host_node.is_some()) {
+        add(Path::new(NODE_PATH), Access::Read);
+    }
+    if config.sgx_epc.is_some() {
+        add(Path::new(SGX_VEPC_PATH), Access::ReadWrite);
+    }
+    if let Some(file) = config.trace_bus.as_ref().and_then(|t| t.file.as_ref()) {
+        add(file, Access::ReadWrite);
+    }
+
+    for rule in socket_dirs {
+        if !rules.contains(&rule) {
+            rules.push(rule);
+        }
+    }
+
+    rules
+}
+
+/// The Landlock ABI version of the host kernel, if it supports Landlock.
+pub fn abi_version() -> Option<i32> {
+    // Safe because the kernel only returns the version, and the return
+    // value is checked.
+    let ret = unsafe {
+        libc::syscall(
+            SYS_LANDLOCK_CREATE_RULESET,
+            null::<RulesetAttr>(),
+            0usize,
+            LANDLOCK_CREATE_RULESET_VERSION,
+        )
+    };
+    if ret < 0 {
+        None
+    } else {
+        Some(ret as i32)
+    }
+}
+
+fn add_rule(ruleset: &File, rule: &Rule) -> io::Result<()> {
+    let file = OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_PATH | libc::O_CLOEXEC)
+        .open(&rule.path)?;
+    let mut allowed_access = rule.access.flags();
+    if !file.metadata()?.is_dir() {
+        allowed_access &= ACCESS_FS_FILE;
+    }
+
+    let attr = PathBeneathAttr {
+        allowed_access,
+        parent_fd: file.as_raw_fd(),
+    };
+    // Safe because the attributes are valid for the duration of the call,
+    // and the return value is checked.
+    let ret = unsafe {
+        libc::syscall(
+            SYS_LANDLOCK_ADD_RULE,
+            ruleset.as_raw_fd(),
+            LANDLOCK_RULE_PATH_BENEATH,
+            &attr as *const PathBeneathAttr,
+            0u32,
+        )
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Restricts the filesystem access of the calling thread, and of the threads
+/// it spawns from now on, to the paths of `rules`. The paths which don't
+/// exist are left out.
+pub fn restrict(rules: &[Rule]) -> Result<()> {
+    let attr = RulesetAttr {
+        handled_access_fs: ACCESS_FS_ABI_1,
+    };
+    // Safe because the attributes are valid for the duration of the call,
+    // and the return value is checked.
+    let fd = unsafe {
+        libc::syscall(
+            SYS_LANDLOCK_CREATE_RULESET,
+            &attr as *const RulesetAttr,
+            mem::size_of::<RulesetAttr>(),
+            0u32,
+        )
+    };
+    if fd < 0 {
+        return Err(Error::CreateRuleset(io::Error::last_os_error()));
+    }
+    // Safe because the fd was just created, nothing else owns it.
+    let ruleset = unsafe { File::from_raw_fd(fd as RawFd) };
+
+    for rule in rules.iter() {
+        match add_rule(&ruleset, rule) {
+            Ok(()) => info!("Landlock: {} access to {:?}", rule.access, rule.path),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                warn!("Landlock: no access to missing {:?}", rule.path)
+            }
+            Err(e) => return Err(Error::AddRule(rule.path.clone(), e)),
+        }
+    }
+
+    // Safe because the return value is checked.
+    if unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) } < 0 {
+        return Err(Error::NoNewPrivs(io::Error::last_os_error()));
+    }
+    // Safe because the return value is checked.
+    if unsafe { libc::syscall(SYS_LANDLOCK_RESTRICT_SELF, ruleset.as_raw_fd(), 0u32) } < 0 {
+        return Err(Error::RestrictSelf(io::Error::last_os_error()));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_landlock_rules() {
+        let config: VmConfig = serde_json::from_str(
+            r#"{
+                "kernel": {"path": "/path/to/kernel"},
+                "memory": {"size": 1073741824, "file": "/dev/hugepages"},
+                "disks": [
+                    {"path": "/path/to/rootfs", "readonly": true},
+                    {"path": "/path/to/data"},
+                    {"vhost_user": true, "vhost_socket": "/tmp/blk.sock"}
+                ],
+                "net": [{"pcap": "/tmp/net.pcap"}],
+                "pmem": [{"file": "/path/to/pmem", "size": 1048576}],
+                "serial": {"mode": "File", "file": "/tmp/serial.log"},
+                "vsock": [{"cid": 3, "sock": "/tmp/vsock/vm.sock",
+                           "listen": [{"port": 22, "uds": "/tmp/vsock/ssh.sock"}]}],
+                "metrics_socket": "metrics.sock"
+            }"#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            rules(&config),
+            vec![
+                Rule::new("/dev/kvm", Access::ReadWrite),
+                Rule::new("/proc/meminfo", Access::Read),
+                Rule::new("/proc/self/statm", Access::Read),
+                Rule::new("/dev/urandom", Access::Read),
+                Rule::new("/path/to/kernel", Access::Read),
+                Rule::new("/dev/hugepages", Access::Create),
+                Rule::new("/path/to/rootfs", Access::Read),
+                Rule::new("/path/to/data", Access::ReadWrite),
+                Rule::new("/tmp/blk.sock", Access::ReadWrite),
+                Rule::new("/tmp/net.pcap", Access::ReadWrite),
+                Rule::new("/dev/net/tun", Access::ReadWrite),
+                Rule::new("/path/to/pmem", Access::ReadWrite),
+                Rule::new("/tmp/serial.log", Access::ReadWrite),
+                Rule::new("/tmp/vsock", Access::Create),
+                Rule::new(".", Access::Create),
+            ]
+        );
+
+        // A file given as a file descriptor is opened again through it, the
+        // rule applying to the file it points to.
+        let config: VmConfig =
+            serde_json::from_str(r#"{"kernel": {"fd": 3}, "rng": {"src": "/dev/random"}}"#)
+                .unwrap();
+        assert_eq!(
+            rules(&config),
+            vec![
+                Rule::new("/dev/kvm", Access::ReadWrite),
+                Rule::new("/proc/meminfo", Access::Read),
+                Rule::new("/proc/self/statm", Access::Read),
+                Rule::new("/dev/urandom", Access::Read),
+                Rule::new("/proc/self/fd/3", Access::Read),
+                Rule::new("/dev/random", Access::Read),
+            ]
+        );
+
+        // A seeded rng device leaves its source out, but the VM generation ID
+        // still reads the random source.
+        let config: VmConfig = serde_json::from_str(
+            r#"{"kernel": {"fd": 3}, "rng": {"src": "/dev/random"}, "rng_seed": 42}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            rules(&config),
+            vec![
+                Rule::new("/dev/kvm", Access::ReadWrite),
+                Rule::new("/proc/meminfo", Access::Read),
+                Rule::new("/proc/self/statm", Access::Read),
+                Rule::new("/dev/urandom", Access::Read),
+                Rule::new("/proc/self/fd/3", Access::Read),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_access_flags() {
+        assert_eq!(Access::Read.flags() & ACCESS_FS_FILE, ACCESS_FS_READ_FILE);
+        assert_eq!(
+            Access::Create.flags() & ACCESS_FS_FILE,
+            ACCESS_FS_READ_FILE | ACCESS_FS_WRITE_FILE
+        );
+        assert_eq!(Access::Create.flags() & !ACCESS_FS_ABI_1, 0);
+    }
+}
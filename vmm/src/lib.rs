@@ -12,30 +12,66 @@ extern crate serde;
 #[macro_use]
 extern crate serde_derive;
 extern crate serde_json;
+#[macro_use]
+extern crate vm_device;
+#[macro_use]
 extern crate vmm_sys_util;
 
-use crate::api::{ApiError, ApiRequest, ApiResponse, ApiResponsePayload, VmInfo, VmmPingResponse};
-use crate::config::VmConfig;
-use crate::vm::{Error as VmError, Vm, VmState};
+use crate::api::{
+    ApiError, ApiRequest, ApiResponse, ApiResponsePayload, VmAddDeviceData, VmAddDeviceResponse,
+    VmInfo, VmRemoveDeviceData, VmResizeDiskData, VmSetLinkData, VmmPingResponse,
+};
+use crate::config::{RuntimeBudgetAction, StdinMode, VmConfig};
+use crate::memory_pressure::MemoryPressureMonitor;
+use crate::metrics_socket::{MetricsReport, MetricsServer};
+use crate::runtime_budget::RuntimeBudget;
+use crate::serial_buffer::SerialBuffer;
+use crate::shutdown_signals::ShutdownSignals;
+use crate::vm::{Error as VmError, Vm, VmState, SNAPSHOT_CONFIG_FILE};
+use crate::vnc::VncServer;
 use libc::EFD_NONBLOCK;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::File;
 use std::io;
 use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::Path;
 use std::sync::mpsc::{Receiver, RecvError, SendError, Sender};
 use std::sync::{Arc, Mutex};
+use std::time::Instant;
 use std::{result, thread};
+use vm_device::metrics::{self, MetricSample};
 use vm_device::Pausable;
 use vmm_sys_util::eventfd::EventFd;
 
 pub mod api;
+pub mod bus_trace;
+#[cfg(target_arch = "x86_64")]
+pub mod clock;
 pub mod config;
 pub mod cpu;
+#[cfg(target_arch = "x86_64")]
+pub mod cpu_model;
 pub mod device_manager;
+pub mod device_registry;
+pub mod host_check;
+pub mod inherited_fd;
 pub mod interrupt;
+pub mod landlock;
 pub mod memory_manager;
+pub mod memory_pressure;
+pub mod memory_slots;
+pub mod metrics_socket;
+pub mod runtime_budget;
+pub mod serial_buffer;
+pub mod shutdown_signals;
 pub mod vm;
+pub mod vnc;
 
 #[cfg(feature = "acpi")]
 mod acpi;
+#[cfg(feature = "acpi")]
+mod vmgenid;
 
 /// Errors associated with VMM management
 #[derive(Debug)]
@@ -59,15 +95,27 @@ pub enum Error {
     /// Cannot read from EventFd.
     EventFdRead(io::Error),
 
+    /// Cannot write to EventFd.
+    EventFdWrite(io::Error),
+
     /// Cannot create epoll context.
     Epoll(io::Error),
 
     /// Cannot create HTTP thread
     HttpThreadSpawn(io::Error),
 
+    /// Cannot listen for metrics scrapers
+    MetricsBind(io::Error),
+
+    /// Cannot create the metrics thread
+    MetricsThreadSpawn(io::Error),
+
     /// Cannot handle the VM STDIN stream
     Stdin(VmError),
 
+    /// Cannot pause the VM
+    VmPause(VmError),
+
     /// Cannot reboot the VM
     VmReboot(VmError),
 
@@ -79,20 +127,88 @@ pub enum Error {
 
     /// Cannot shut the VMM down
     VmmShutdown(VmError),
+
+    /// Cannot watch the signals shutting the VMM down
+    ShutdownSignals(io::Error),
 }
 pub type Result<T> = result::Result<T, Error>;
 
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::ApiRequestRecv(_) => write!(f, "API request receive error"),
+            Error::ApiResponseSend(_) => write!(f, "API response send error"),
+            Error::Bind(_) => write!(f, "cannot bind to the UNIX domain socket path"),
+            Error::EventFdClone(_) => write!(f, "cannot clone EventFd"),
+            Error::EventFdCreate(_) => write!(f, "cannot create EventFd"),
+            Error::EventFdRead(_) => write!(f, "cannot read from EventFd"),
+            Error::EventFdWrite(_) => write!(f, "cannot write to EventFd"),
+            Error::Epoll(_) => write!(f, "cannot create epoll context"),
+            Error::HttpThreadSpawn(_) => write!(f, "cannot create HTTP thread"),
+            Error::MetricsBind(_) => write!(f, "cannot listen for metrics scrapers"),
+            Error::MetricsThreadSpawn(_) => write!(f, "cannot create the metrics thread"),
+            Error::Stdin(_) => write!(f, "cannot handle the VM STDIN stream"),
+            Error::VmPause(_) => write!(f, "cannot pause the VM"),
+            Error::VmReboot(_) => write!(f, "cannot reboot the VM"),
+            Error::VmShutdown(_) => write!(f, "cannot shut a VM down"),
+            Error::VmmThreadSpawn(_) => write!(f, "cannot create VMM thread"),
+            Error::VmmShutdown(_) => write!(f, "cannot shut the VMM down"),
+            Error::ShutdownSignals(_) => {
+                write!(f, "cannot watch the signals shutting the VMM down")
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::ApiRequestRecv(e) => Some(e),
+            Error::ApiResponseSend(e) => Some(e),
+            Error::Bind(e) => Some(e),
+            Error::EventFdClone(e) => Some(e),
+            Error::EventFdCreate(e) => Some(e),
+            Error::EventFdRead(e) => Some(e),
+            Error::EventFdWrite(e) => Some(e),
+            Error::Epoll(e) => Some(e),
+            Error::HttpThreadSpawn(e) => Some(e),
+            Error::MetricsBind(e) => Some(e),
+            Error::MetricsThreadSpawn(e) => Some(e),
+            Error::Stdin(e) => Some(e),
+            Error::VmPause(e) => Some(e),
+            Error::VmReboot(e) => Some(e),
+            Error::VmShutdown(e) => Some(e),
+            Error::VmmThreadSpawn(e) => Some(e),
+            Error::VmmShutdown(e) => Some(e),
+            Error::ShutdownSignals(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub enum EpollDispatch {
     Exit,
     Reset,
     Stdin,
     Api,
+    VncListener,
+    VncClient,
+    VncTimer,
+    MetricsListener,
+    MetricsClient,
+    MemoryPressure,
+    SerialOutput,
+    SerialOutputQueued,
+    RuntimeBudget,
+    ShutdownSignal,
+    DeviceRemoval,
 }
 
 pub struct EpollContext {
     raw_fd: RawFd,
     dispatch_table: Vec<Option<EpollDispatch>>,
+    stdin_index: Option<usize>,
 }
 
 impl EpollContext {
@@ -104,25 +220,53 @@ impl EpollContext {
         // * 1 reset event
         // * 1 stdin event
         // * 1 API event
-        let mut dispatch_table = Vec::with_capacity(5);
+        // * 1 shutdown signal event
+        let mut dispatch_table = Vec::with_capacity(6);
         dispatch_table.push(None);
 
         Ok(EpollContext {
             raw_fd,
             dispatch_table,
+            stdin_index: None,
         })
     }
 
     pub fn add_stdin(&mut self) -> result::Result<(), io::Error> {
-        let dispatch_index = self.dispatch_table.len() as u64;
+        // Reuse the slot of a previous registration, so that the dispatch
+        // table doesn't grow each time a VM is created.
+        let dispatch_index = match self.stdin_index {
+            Some(index) if self.dispatch_table[index].is_some() => return Ok(()),
+            Some(index) => index,
+            None => {
+                self.dispatch_table.push(None);
+                self.dispatch_table.len() - 1
+            }
+        };
+        self.stdin_index = Some(dispatch_index);
+
         epoll::ctl(
             self.raw_fd,
             epoll::ControlOptions::EPOLL_CTL_ADD,
             libc::STDIN_FILENO,
-            epoll::Event::new(epoll::Events::EPOLLIN, dispatch_index),
+            epoll::Event::new(epoll::Events::EPOLLIN, dispatch_index as u64),
         )?;
 
-        self.dispatch_table.push(Some(EpollDispatch::Stdin));
+        self.dispatch_table[dispatch_index] = Some(EpollDispatch::Stdin);
+
+        Ok(())
+    }
+
+    pub fn remove_stdin(&mut self) -> result::Result<(), io::Error> {
+        if let Some(index) = self.stdin_index {
+            if self.dispatch_table[index].take().is_some() {
+                epoll::ctl(
+                    self.raw_fd,
+                    epoll::ControlOptions::EPOLL_CTL_DEL,
+                    libc::STDIN_FILENO,
+                    epoll::Event::new(epoll::Events::empty(), 0),
+                )?;
+            }
+        }
 
         Ok(())
     }
@@ -131,14 +275,59 @@ impl EpollContext {
     where
         T: AsRawFd,
     {
-        let dispatch_index = self.dispatch_table.len() as u64;
+        self.add_fd(fd.as_raw_fd(), token)
+    }
+
+    fn add_fd(&mut self, fd: RawFd, token: EpollDispatch) -> result::Result<(), io::Error> {
+        self.add_fd_with_events(fd, epoll::Events::EPOLLIN, token)
+    }
+
+    fn add_fd_with_events(
+        &mut self,
+        fd: RawFd,
+        events: epoll::Events,
+        token: EpollDispatch,
+    ) -> result::Result<(), io::Error> {
+        // Reuse the slot of a removed event, so that the dispatch table
+        // doesn't grow each time a VNC client connects. The stdin slot is
+        // kept for stdin.
+        let free_index = (1..self.dispatch_table.len())
+            .find(|&index| self.dispatch_table[index].is_none() && Some(index) != self.stdin_index);
+        let dispatch_index = match free_index {
+            Some(index) => index,
+            None => {
+                self.dispatch_table.push(None);
+                self.dispatch_table.len() - 1
+            }
+        };
+
         epoll::ctl(
             self.raw_fd,
             epoll::ControlOptions::EPOLL_CTL_ADD,
-            fd.as_raw_fd(),
-            epoll::Event::new(epoll::Events::EPOLLIN, dispatch_index),
+            fd,
+            epoll::Event::new(events, dispatch_index as u64),
         )?;
-        self.dispatch_table.push(Some(token));
+        self.dispatch_table[dispatch_index] = Some(token);
+
+        Ok(())
+    }
+
+    // Stops watching `fd`, which has been registered as the only event of
+    // type `token`.
+    fn remove_fd(&mut self, fd: RawFd, token: EpollDispatch) -> result::Result<(), io::Error> {
+        if let Some(slot) = self
+            .dispatch_table
+            .iter_mut()
+            .find(|slot| **slot == Some(token))
+        {
+            *slot = None;
+            epoll::ctl(
+                self.raw_fd,
+                epoll::ControlOptions::EPOLL_CTL_DEL,
+                fd,
+                epoll::Event::new(epoll::Events::empty(), 0),
+            )?;
+        }
 
         Ok(())
     }
@@ -179,21 +368,37 @@ pub struct Vmm {
     exit_evt: EventFd,
     reset_evt: EventFd,
     api_evt: EventFd,
+    shutdown_signals: ShutdownSignals,
     version: String,
     vm: Option<Vm>,
     vm_config: Option<Arc<Mutex<VmConfig>>>,
+    vnc: Option<VncServer>,
+    metrics_server: Option<MetricsServer>,
+    memory_pressure: Option<MemoryPressureMonitor>,
+    serial_output: Option<SerialBuffer>,
+    runtime_budget: Option<RuntimeBudget>,
+    // Whether the device removal timer of the VM is watched, from the first
+    // removal on.
+    device_removals_watched: bool,
+    // The API requests waiting for the removal of a device to be over, along
+    // with the id of the device.
+    pending_device_removals: Vec<(String, Sender<ApiResponse>)>,
+    // Whether the filesystem access of the VMM thread is restricted, which
+    // can't be undone.
+    landlocked: bool,
+    // When the VM last rebooted, until the guest writes to the serial port.
+    reboot_ts: Option<Instant>,
 }
 
 impl Vmm {
-    fn new(vmm_version: String, api_evt: EventFd) -> Result<Self> {
+    /// Creates a VMM serving the API requests signaled through `api_evt`.
+    /// SIGTERM and SIGINT shut it down, once blocked by all the threads of
+    /// the process through `shutdown_signals::block()`.
+    pub fn new(vmm_version: String, api_evt: EventFd) -> Result<Self> {
         let mut epoll = EpollContext::new().map_err(Error::Epoll)?;
         let exit_evt = EventFd::new(EFD_NONBLOCK).map_err(Error::EventFdCreate)?;
         let reset_evt = EventFd::new(EFD_NONBLOCK).map_err(Error::EventFdCreate)?;
 
-        if unsafe { libc::isatty(libc::STDIN_FILENO as i32) } != 0 {
-            epoll.add_stdin().map_err(Error::Epoll)?;
-        }
-
         epoll
             .add_event(&exit_evt, EpollDispatch::Exit)
             .map_err(Error::Epoll)?;
@@ -206,17 +411,48 @@ impl Vmm {
             .add_event(&api_evt, EpollDispatch::Api)
             .map_err(Error::Epoll)?;
 
+        let shutdown_signals = ShutdownSignals::new().map_err(Error::ShutdownSignals)?;
+        epoll
+            .add_event(&shutdown_signals, EpollDispatch::ShutdownSignal)
+            .map_err(Error::Epoll)?;
+
+        event!("vmm", "starting");
+
         Ok(Vmm {
             epoll,
             exit_evt,
             reset_evt,
             api_evt,
+            shutdown_signals,
             version: vmm_version,
             vm: None,
             vm_config: None,
+            vnc: None,
+            metrics_server: None,
+            memory_pressure: None,
+            serial_output: None,
+            runtime_budget: None,
+            device_removals_watched: false,
+            pending_device_removals: Vec::new(),
+            landlocked: false,
+            reboot_ts: None,
         })
     }
 
+    // Starts forwarding stdin to the guest console, unless the VM config
+    // opts out. Not being able to watch stdin, because it is closed or
+    // redirected from a regular file, only leaves the guest without console
+    // input.
+    fn add_stdin(&mut self, config: &Arc<Mutex<VmConfig>>) {
+        if config.lock().unwrap().stdin == StdinMode::None {
+            return;
+        }
+
+        if let Err(e) = self.epoll.add_stdin() {
+            warn!("Cannot read console input from stdin: {}", e);
+        }
+    }
+
     fn vm_boot(&mut self) -> result::Result<(), VmError> {
         // Create a new VM is we don't have one yet.
         if self.vm.is_none() {
@@ -229,17 +465,454 @@ impl Vmm {
             }
         }
 
+        self.apply_landlock()?;
+
         // Now we can boot the VM.
         if let Some(ref mut vm) = self.vm {
-            vm.boot()
+            event!("vm", "booting");
+            vm.boot()?;
+            event!("vm", "booted");
         } else {
-            Err(VmError::VmNotCreated)
+            return Err(VmError::VmNotCreated);
+        }
+
+        self.start_vnc();
+        self.start_metrics_server();
+        self.start_memory_pressure_monitor();
+        self.start_serial_output();
+        self.start_runtime_budget();
+        Ok(())
+    }
+
+    // Restricts the filesystem access of the VMM to the paths the VM needs,
+    // if its config asks for it, once the VM is created and before its
+    // vCPUs and devices threads get spawned. The rules of the first VM
+    // stay, a VM created later is limited to them.
+    fn apply_landlock(&mut self) -> result::Result<(), VmError> {
+        let vm = match self.vm.as_ref() {
+            Some(vm) if !self.landlocked && vm.get_config().lock().unwrap().landlock => vm,
+            _ => return Ok(()),
+        };
+
+        match landlock::abi_version() {
+            Some(version) => {
+                info!(
+                    "Restricting the VMM filesystem access, Landlock ABI {}",
+                    version
+                );
+                landlock::restrict(&vm.landlock_rules()).map_err(VmError::Landlock)?;
+                self.landlocked = true;
+            }
+            None => warn!(
+                "The host kernel doesn't support Landlock, the VMM filesystem access \
+                 is not restricted"
+            ),
+        }
+
+        Ok(())
+    }
+
+    // Serves the display of the VM over VNC if its config asks for it. The
+    // server outlives the VM, e.g. across reboots, only its display being
+    // replaced, and runs until the VM config is deleted. Not being able to
+    // start it only leaves the VM without a remote display.
+    fn start_vnc(&mut self) {
+        let addr = match self
+            .vm_config
+            .as_ref()
+            .and_then(|config| config.lock().unwrap().vnc_addr)
+        {
+            Some(addr) => addr,
+            None => return,
+        };
+        let (framebuffer, input) = match self.vm.as_ref().map(|vm| vm.vnc_display()) {
+            Some(Ok(display)) => display,
+            Some(Err(e)) => {
+                warn!("Cannot display the VM over VNC: {}", e);
+                return;
+            }
+            None => return,
+        };
+
+        if let Some(vnc) = self.vnc.as_mut() {
+            vnc.set_display(framebuffer, input);
+            return;
+        }
+
+        let vnc = match VncServer::new(addr, framebuffer, input) {
+            Ok(vnc) => vnc,
+            Err(e) => {
+                warn!("Cannot listen for VNC clients on {}: {}", addr, e);
+                return;
+            }
+        };
+        if let Err(e) = self
+            .epoll
+            .add_fd(vnc.listener_fd(), EpollDispatch::VncListener)
+            .and_then(|_| self.epoll.add_fd(vnc.timer_fd(), EpollDispatch::VncTimer))
+        {
+            warn!("Cannot listen for VNC clients: {}", e);
+            self.vnc = Some(vnc);
+            self.stop_vnc();
+            return;
+        }
+        self.vnc = Some(vnc);
+    }
+
+    fn stop_vnc(&mut self) {
+        if let Some(vnc) = self.vnc.take() {
+            let mut fds = vec![
+                (vnc.listener_fd(), EpollDispatch::VncListener),
+                (vnc.timer_fd(), EpollDispatch::VncTimer),
+            ];
+            if let Some(fd) = vnc.client_fd() {
+                fds.push((fd, EpollDispatch::VncClient));
+            }
+            for (fd, token) in fds {
+                if let Err(e) = self.epoll.remove_fd(fd, token) {
+                    warn!("Cannot stop the VNC server: {}", e);
+                }
+            }
+        }
+    }
+
+    fn vnc_accept(&mut self) {
+        if let Some(vnc) = self.vnc.as_mut() {
+            if let Some(fd) = vnc.accept() {
+                if let Err(e) = self.epoll.add_fd(fd, EpollDispatch::VncClient) {
+                    warn!("Cannot watch the VNC client: {}", e);
+                    vnc.disconnect();
+                }
+            }
+        }
+    }
+
+    // Handles the client connection becoming readable, or the refresh timer
+    // ticking, and disconnects the client when it fails.
+    fn vnc_client_event(&mut self, timer: bool) {
+        if let Some(vnc) = self.vnc.as_mut() {
+            let fd = match vnc.client_fd() {
+                Some(fd) => fd,
+                None => return,
+            };
+            let connected = if timer {
+                vnc.handle_timer()
+            } else {
+                vnc.handle_client()
+            };
+            if !connected {
+                if let Err(e) = self.epoll.remove_fd(fd, EpollDispatch::VncClient) {
+                    warn!("Cannot stop watching the VNC client: {}", e);
+                }
+                vnc.disconnect();
+            }
+        }
+    }
+
+    // Serves the statistics of the VM on a Unix socket if its config asks
+    // for it. As the VNC server, it outlives the VM and runs until the VM
+    // config is deleted, and not being able to start it is not fatal.
+    fn start_metrics_server(&mut self) {
+        if self.metrics_server.is_some() {
+            return;
+        }
+        let path = match self
+            .vm_config
+            .as_ref()
+            .and_then(|config| config.lock().unwrap().metrics_socket.clone())
+        {
+            Some(path) => path,
+            None => return,
+        };
+
+        let server = match MetricsServer::new(&path) {
+            Ok(server) => server,
+            Err(e) => {
+                warn!("Cannot serve the metrics on {:?}: {}", path, e);
+                return;
+            }
+        };
+        if let Err(e) = self
+            .epoll
+            .add_fd(server.listener_fd(), EpollDispatch::MetricsListener)
+        {
+            warn!("Cannot listen for metrics clients: {}", e);
+            return;
+        }
+        self.metrics_server = Some(server);
+    }
+
+    fn stop_metrics_server(&mut self) {
+        if let Some(server) = self.metrics_server.take() {
+            let mut fds = vec![(server.listener_fd(), EpollDispatch::MetricsListener)];
+            if let Some(fd) = server.client_fd() {
+                fds.push((fd, EpollDispatch::MetricsClient));
+            }
+            for (fd, token) in fds {
+                if let Err(e) = self.epoll.remove_fd(fd, token) {
+                    warn!("Cannot stop the metrics server: {}", e);
+                }
+            }
+        }
+    }
+
+    fn metrics_accept(&mut self) {
+        if let Some(server) = self.metrics_server.as_mut() {
+            if let Some(fd) = server.accept() {
+                if let Err(e) = self.epoll.add_fd_with_events(
+                    fd,
+                    epoll::Events::EPOLLIN | epoll::Events::EPOLLOUT | epoll::Events::EPOLLET,
+                    EpollDispatch::MetricsClient,
+                ) {
+                    warn!("Cannot watch the metrics client: {}", e);
+                    server.disconnect();
+                }
+            }
+        }
+    }
+
+    fn metrics_client_event(&mut self) {
+        let vm = &self.vm;
+        let report = || MetricsReport {
+            uptime_ms: vm
+                .as_ref()
+                .and_then(|vm| vm.uptime())
+                .map(|uptime| uptime.as_millis() as u64),
+            guest_memory_bytes: vm.as_ref().map_or(0, |vm| vm.ram_size()),
+            rss_bytes: metrics_socket::rss_bytes(),
+            metrics: metrics::registry().samples(),
+        };
+
+        if let Some(server) = self.metrics_server.as_mut() {
+            let fd = match server.client_fd() {
+                Some(fd) => fd,
+                None => return,
+            };
+            if !server.handle_client(report) {
+                if let Err(e) = self.epoll.remove_fd(fd, EpollDispatch::MetricsClient) {
+                    warn!("Cannot stop watching the metrics client: {}", e);
+                }
+                server.disconnect();
+            }
+        }
+    }
+
+    // Reclaims memory from the guest through the balloon when the host runs
+    // low, if the balloon config asks for it. Unlike the metrics server, it
+    // only lives as long as the VM, its balloon starting at the configured
+    // size again after a reboot.
+    fn start_memory_pressure_monitor(&mut self) {
+        if self.memory_pressure.is_some() {
+            return;
+        }
+        let balloon = match self
+            .vm_config
+            .as_ref()
+            .and_then(|config| config.lock().unwrap().balloon.clone())
+        {
+            Some(balloon) => balloon,
+            None => return,
+        };
+        let threshold = match balloon.pressure_threshold {
+            Some(threshold) => threshold,
+            None => return,
+        };
+
+        let monitor =
+            match MemoryPressureMonitor::new(threshold, balloon.pressure_step, balloon.size) {
+                Ok(monitor) => monitor,
+                Err(e) => {
+                    warn!("Cannot watch the host memory pressure: {}", e);
+                    return;
+                }
+            };
+        if let Err(e) = self
+            .epoll
+            .add_fd(monitor.timer_fd(), EpollDispatch::MemoryPressure)
+        {
+            warn!("Cannot watch the host memory pressure: {}", e);
+            return;
+        }
+        self.memory_pressure = Some(monitor);
+    }
+
+    fn stop_memory_pressure_monitor(&mut self) {
+        if let Some(monitor) = self.memory_pressure.take() {
+            if let Err(e) = self
+                .epoll
+                .remove_fd(monitor.timer_fd(), EpollDispatch::MemoryPressure)
+            {
+                warn!("Cannot stop watching the host memory pressure: {}", e);
+            }
+        }
+    }
+
+    fn memory_pressure_event(&mut self) {
+        let (monitor, vm) = match (self.memory_pressure.as_mut(), self.vm.as_ref()) {
+            (Some(monitor), Some(vm)) => (monitor, vm),
+            _ => return,
+        };
+        if let Some(target) = monitor.handle_timer(vm.ram_size()) {
+            info!("Asking the guest for {} bytes of memory", target);
+            if let Err(e) = vm.set_balloon_target(target) {
+                warn!("Cannot reclaim memory from the guest: {}", e);
+            }
+        }
+    }
+
+    // Writes the buffered serial output to its destination, whenever the
+    // guest queues some or the destination becomes writable again. Regular
+    // files can't be watched for writability, but they are always writable.
+    fn start_serial_output(&mut self) {
+        if self.serial_output.is_some() {
+            return;
+        }
+        let serial_buffer = match self.vm.as_ref().and_then(|vm| vm.serial_buffer()) {
+            Some(serial_buffer) => serial_buffer.clone(),
+            None => return,
+        };
+
+        if let Err(e) = self
+            .epoll
+            .add_fd(serial_buffer.queued_fd(), EpollDispatch::SerialOutputQueued)
+        {
+            warn!("Cannot watch the serial output: {}", e);
+            return;
+        }
+        match self.epoll.add_fd_with_events(
+            serial_buffer.out_fd(),
+            epoll::Events::EPOLLOUT | epoll::Events::EPOLLET,
+            EpollDispatch::SerialOutput,
+        ) {
+            Ok(()) => {}
+            Err(e) if e.raw_os_error() == Some(libc::EPERM) => {}
+            Err(e) => warn!("Cannot watch the serial output: {}", e),
+        }
+        self.serial_output = Some(serial_buffer);
+    }
+
+    // Reports how long the rebooted guest took to write to the serial port,
+    // the first sign of life of the kernel booted again.
+    fn serial_output_queued(&mut self) {
+        if let Some(reboot_ts) = self.reboot_ts.take() {
+            let elapsed = reboot_ts.elapsed();
+            info!("Serial output {:?} after the VM reboot", elapsed);
+            event!("vm", "reboot-serial-output", "duration_ms" => elapsed.as_millis());
+        }
+    }
+
+    fn stop_serial_output(&mut self) {
+        if let Some(serial_buffer) = self.serial_output.take() {
+            // Keeps the last words of the guest.
+            serial_buffer.flush_buffer();
+            for (fd, token) in &[
+                (serial_buffer.queued_fd(), EpollDispatch::SerialOutputQueued),
+                (serial_buffer.out_fd(), EpollDispatch::SerialOutput),
+            ] {
+                if let Err(e) = self.epoll.remove_fd(*fd, *token) {
+                    warn!("Cannot stop watching the serial output: {}", e);
+                }
+            }
+        }
+    }
+
+    // Lets the guest run for as long as the VM config allows, pausing or
+    // killing the VM once the time is up. The budget is consumed across
+    // reboots, and only comes back with a new VM config. Not being able to
+    // arm the timer leaves the guest running without a limit.
+    fn start_runtime_budget(&mut self) {
+        if self.runtime_budget.is_none() {
+            let config = match self
+                .vm_config
+                .as_ref()
+                .and_then(|config| config.lock().unwrap().runtime_budget.clone())
+            {
+                Some(config) => config,
+                None => return,
+            };
+            let runtime_budget = match RuntimeBudget::new(config.budget()) {
+                Ok(runtime_budget) => runtime_budget,
+                Err(e) => {
+                    warn!("Cannot enforce the runtime budget: {}", e);
+                    return;
+                }
+            };
+            if let Err(e) = self
+                .epoll
+                .add_fd(runtime_budget.timer_fd(), EpollDispatch::RuntimeBudget)
+            {
+                warn!("Cannot enforce the runtime budget: {}", e);
+                return;
+            }
+            self.runtime_budget = Some(runtime_budget);
+        }
+
+        if let Some(ref mut runtime_budget) = self.runtime_budget {
+            if let Err(e) = runtime_budget.start() {
+                warn!("Cannot enforce the runtime budget: {}", e);
+            }
+        }
+    }
+
+    // The time the VM doesn't run for is not taken from the budget.
+    fn pause_runtime_budget(&mut self) {
+        if let Some(ref mut runtime_budget) = self.runtime_budget {
+            if let Err(e) = runtime_budget.stop() {
+                warn!("Cannot stop the runtime budget timer: {}", e);
+            }
+        }
+    }
+
+    fn stop_runtime_budget(&mut self) {
+        if let Some(runtime_budget) = self.runtime_budget.take() {
+            if let Err(e) = self
+                .epoll
+                .remove_fd(runtime_budget.timer_fd(), EpollDispatch::RuntimeBudget)
+            {
+                warn!("Cannot stop watching the runtime budget: {}", e);
+            }
+        }
+    }
+
+    fn runtime_budget_event(&mut self) -> Result<()> {
+        let used = match self.runtime_budget.as_mut() {
+            Some(runtime_budget) if runtime_budget.handle_timer() => runtime_budget.used(),
+            _ => return Ok(()),
+        };
+        let action = match self.vm_config.as_ref().and_then(|config| {
+            config
+                .lock()
+                .unwrap()
+                .runtime_budget
+                .as_ref()
+                .map(|config| config.action)
+        }) {
+            Some(action) => action,
+            None => return Ok(()),
+        };
+
+        warn!(
+            "The guest ran for {:?}, exhausting its runtime budget",
+            used
+        );
+        match action {
+            RuntimeBudgetAction::Pause => {
+                event!("vm", "runtime-budget-exhausted", "action" => "pause");
+                self.vm_pause().map_err(Error::VmPause)
+            }
+            RuntimeBudgetAction::Kill => {
+                event!("vm", "runtime-budget-exhausted", "action" => "kill");
+                self.exit_evt.write(1).map_err(Error::EventFdWrite)
+            }
         }
     }
 
     fn vm_pause(&mut self) -> result::Result<(), VmError> {
         if let Some(ref mut vm) = self.vm {
-            vm.pause().map_err(VmError::Pause)
+            vm.pause().map_err(VmError::Pause)?;
+            self.pause_runtime_budget();
+            event!("vm", "paused");
+            Ok(())
         } else {
             Err(VmError::VmNotRunning)
         }
@@ -247,20 +920,58 @@ impl Vmm {
 
     fn vm_resume(&mut self) -> result::Result<(), VmError> {
         if let Some(ref mut vm) = self.vm {
-            vm.resume().map_err(VmError::Resume)
+            vm.resume().map_err(VmError::Resume)?;
+            self.start_runtime_budget();
+            event!("vm", "resumed");
+            Ok(())
         } else {
             Err(VmError::VmNotRunning)
         }
     }
 
     fn vm_shutdown(&mut self) -> result::Result<(), VmError> {
+        self.stop_memory_pressure_monitor();
+        self.stop_serial_output();
+        self.stop_device_removals();
+        self.pause_runtime_budget();
+        self.reboot_ts = None;
         if let Some(ref mut vm) = self.vm.take() {
-            vm.shutdown()
+            event!("vm", "shutting-down");
+            vm.shutdown()?;
+            event!("vm", "shutdown");
+            Ok(())
         } else {
             Err(VmError::VmNotRunning)
         }
     }
 
+    fn vm_snapshot(&mut self, destination: &Path) -> result::Result<(), VmError> {
+        if let Some(ref mut vm) = self.vm {
+            vm.snapshot(destination)
+        } else {
+            Err(VmError::VmNotRunning)
+        }
+    }
+
+    fn vm_restore(&mut self, source: &Path) -> result::Result<(), VmError> {
+        let config_file =
+            File::open(source.join(SNAPSHOT_CONFIG_FILE)).map_err(VmError::SnapshotIo)?;
+        let config: VmConfig =
+            serde_json::from_reader(config_file).map_err(VmError::SnapshotSerialization)?;
+        let config = Arc::new(Mutex::new(config));
+
+        let exit_evt = self.exit_evt.try_clone().map_err(VmError::EventFdClone)?;
+        let reset_evt = self.reset_evt.try_clone().map_err(VmError::EventFdClone)?;
+        let vm = Vm::restore(source, Arc::clone(&config), exit_evt, reset_evt)?;
+
+        self.add_stdin(&config);
+        self.vm_config = Some(config);
+        self.vm = Some(vm);
+
+        // Booting a restored VM resumes it.
+        self.vm_boot()
+    }
+
     fn vm_reboot(&mut self) -> result::Result<(), VmError> {
         // Without ACPI, a reset is equivalent to a shutdown
         #[cfg(not(feature = "acpi"))]
@@ -272,8 +983,15 @@ impl Vmm {
 
         // First we stop the current VM and create a new one.
         if let Some(ref mut vm) = self.vm {
+            event!("vm", "rebooting");
+            let reboot_ts = Instant::now();
             let config = vm.get_config();
+            let hotplugged_ram_regions = vm.hotplugged_ram_regions();
+            // The kernel kept in memory is booted again, the files it was
+            // read from may be gone by now.
+            let kernel_image = vm.kernel_image();
             self.vm_shutdown()?;
+            self.reboot_ts = Some(reboot_ts);
 
             let exit_evt = self.exit_evt.try_clone().map_err(VmError::EventFdClone)?;
             let reset_evt = self.reset_evt.try_clone().map_err(VmError::EventFdClone)?;
@@ -284,42 +1002,82 @@ impl Vmm {
             if self.reset_evt.read().is_ok() {
                 warn!("Spurious second reset event received. Ignoring.");
             }
-            self.vm = Some(Vm::new(config, exit_evt, reset_evt)?);
+
+            // The RAM hot-added so far becomes boot RAM.
+            config
+                .lock()
+                .unwrap()
+                .memory
+                .add_hotplugged_to_boot_ram(&hotplugged_ram_regions);
+            self.vm = Some(Vm::new_with_kernel_image(
+                config,
+                exit_evt,
+                reset_evt,
+                kernel_image,
+            )?);
         }
 
         // Then we start the new VM.
         if let Some(ref mut vm) = self.vm {
             vm.boot()?;
+            event!("vm", "rebooted");
         } else {
             return Err(VmError::VmNotCreated);
         }
 
+        self.start_vnc();
+        self.start_metrics_server();
+        self.start_memory_pressure_monitor();
+        self.start_serial_output();
+        self.start_runtime_budget();
         Ok(())
     }
 
     fn vm_info(&self) -> result::Result<VmInfo, VmError> {
         match &self.vm_config {
             Some(config) => {
-                let state = match &self.vm {
-                    Some(vm) => vm.get_state()?,
-                    None => VmState::Created,
+                let (state, mergeable_regions, devices) = match &self.vm {
+                    Some(vm) => (vm.get_state()?, vm.mergeable_regions(), vm.devices()),
+                    None => (VmState::Created, Vec::new(), BTreeMap::new()),
                 };
 
                 Ok(VmInfo {
                     config: Arc::clone(config),
                     state,
+                    mergeable_regions,
+                    devices,
                 })
             }
             None => Err(VmError::VmNotCreated),
         }
     }
 
+    fn vm_balloon_stats(&self) -> result::Result<vm_virtio::BalloonStats, VmError> {
+        if let Some(ref vm) = self.vm {
+            vm.balloon_stats()
+        } else {
+            Err(VmError::VmNotRunning)
+        }
+    }
+
+    fn vm_screenshot(&self, destination: &Path) -> result::Result<(), VmError> {
+        if let Some(ref vm) = self.vm {
+            vm.screenshot(destination)
+        } else {
+            Err(VmError::VmNotRunning)
+        }
+    }
+
     fn vmm_ping(&self) -> result::Result<VmmPingResponse, ApiError> {
         Ok(VmmPingResponse {
             version: self.version.clone(),
         })
     }
 
+    fn vmm_metrics(&self) -> Vec<MetricSample> {
+        metrics::registry().samples()
+    }
+
     fn vm_delete(&mut self) -> result::Result<(), VmError> {
         if self.vm_config.is_none() {
             return Ok(());
@@ -328,13 +1086,22 @@ impl Vmm {
         // First we try to shut the current VM down.
         self.vm_shutdown()?;
 
+        if let Err(e) = self.epoll.remove_stdin() {
+            warn!("Cannot stop reading stdin: {}", e);
+        }
+        self.stop_vnc();
+        self.stop_metrics_server();
+        self.stop_runtime_budget();
         self.vm_config = None;
+        event!("vm", "deleted");
 
         Ok(())
     }
 
     fn vmm_shutdown(&mut self) -> result::Result<(), VmError> {
-        self.vm_delete()
+        self.vm_delete()?;
+        event!("vmm", "shutdown");
+        Ok(())
     }
 
     fn vm_resize(
@@ -354,15 +1121,194 @@ impl Vmm {
         }
     }
 
-    fn control_loop(&mut self, api_receiver: Arc<Receiver<ApiRequest>>) -> Result<()> {
+    fn vm_nmi(&mut self, cpu_id: Option<u8>) -> result::Result<(), VmError> {
+        if let Some(ref vm) = self.vm {
+            match cpu_id {
+                Some(cpu_id) => vm.send_nmi(cpu_id),
+                None => vm.send_nmi_to_all(),
+            }
+        } else {
+            Err(VmError::VmNotRunning)
+        }
+    }
+
+    fn vm_update_generation_id(&mut self) -> result::Result<(), VmError> {
+        if let Some(ref vm) = self.vm {
+            vm.update_generation_id()
+        } else {
+            Err(VmError::VmNotRunning)
+        }
+    }
+
+    fn vm_send_key(&mut self, scancodes: &[u8]) -> result::Result<(), VmError> {
+        if let Some(ref vm) = self.vm {
+            vm.send_keys(scancodes)
+        } else {
+            Err(VmError::VmNotRunning)
+        }
+    }
+
+    fn vm_resize_disk(&mut self, data: &VmResizeDiskData) -> result::Result<(), VmError> {
+        if let Some(ref vm) = self.vm {
+            let (id, index) = match (&data.id, data.disk_index) {
+                (Some(id), None) => (id.clone(), vm.disk_index(id)?),
+                (None, Some(index)) => (vm.disk_id(index)?, index),
+                _ => return Err(VmError::DeviceSelection),
+            };
+            vm.resize_disk(index, data.desired_size, data.force)?;
+            event!("vm", "disk-resized", "id" => id);
+            Ok(())
+        } else {
+            Err(VmError::VmNotRunning)
+        }
+    }
+
+    fn vm_set_link(&mut self, data: &VmSetLinkData) -> result::Result<(), VmError> {
+        if let Some(ref vm) = self.vm {
+            vm.set_link(&data.id, data.up)
+        } else {
+            Err(VmError::VmNotRunning)
+        }
+    }
+
+    fn vm_add_device(
+        &mut self,
+        device: &VmAddDeviceData,
+    ) -> result::Result<VmAddDeviceResponse, VmError> {
+        if let Some(ref mut vm) = self.vm {
+            let (id, index) = match device {
+                VmAddDeviceData::Disk(disk_cfg) => vm.add_disk(disk_cfg)?,
+                VmAddDeviceData::Net(net_cfg) => vm.add_net(net_cfg)?,
+            };
+            event!("vm", "device-added", "id" => id);
+            Ok(VmAddDeviceResponse {
+                id,
+                index: index as usize,
+            })
+        } else {
+            Err(VmError::VmNotRunning)
+        }
+    }
+
+    // Starts unplugging a device, the guest being waited for on the ticks of
+    // the device removal timer. Returns the id of the device.
+    fn vm_remove_device(&mut self, data: &VmRemoveDeviceData) -> result::Result<String, VmError> {
+        if let Some(ref mut vm) = self.vm {
+            let id = match (&data.id, data.index) {
+                (Some(id), None) => id.clone(),
+                (None, Some(index)) => vm.device_at(index)?,
+                _ => return Err(VmError::DeviceSelection),
+            };
+            if !self.device_removals_watched {
+                self.epoll
+                    .add_fd(vm.device_removal_timer_fd(), EpollDispatch::DeviceRemoval)
+                    .map_err(VmError::WatchDeviceRemovals)?;
+                self.device_removals_watched = true;
+            }
+            vm.remove_device(&id)?;
+            event!("vm", "device-removal-started", "id" => id);
+            Ok(id)
+        } else {
+            Err(VmError::VmNotRunning)
+        }
+    }
+
+    // Reports the devices the removal of which is over, answering the API
+    // requests which asked for it.
+    fn device_removal_event(&mut self) {
+        let removals = match self.vm {
+            Some(ref mut vm) => vm.poll_device_removals(),
+            None => return,
+        };
+
+        for (id, result) in removals {
+            match &result {
+                Ok(()) => event!("vm", "device-removed", "id" => id),
+                Err(e) => {
+                    error!("Cannot unplug the device {}: {}", id, e);
+                    event!("vm", "device-removal-failed", "id" => id, "error" => e);
+                }
+            }
+            let response = result
+                .map_err(ApiError::VmRemoveDevice)
+                .map(|_| ApiResponsePayload::Empty);
+            self.respond_device_removal(&id, response);
+        }
+    }
+
+    fn respond_device_removal(&mut self, id: &str, response: ApiResponse) {
+        if let Some(index) = self
+            .pending_device_removals
+            .iter()
+            .position(|(pending, _)| pending == id)
+        {
+            let (_, sender) = self.pending_device_removals.remove(index);
+            // The client may have gone away meanwhile.
+            if sender.send(response).is_err() {
+                warn!("Cannot answer the removal request of the device {}", id);
+            }
+        }
+    }
+
+    fn stop_device_removals(&mut self) {
+        // The VM going away stops the removals in progress.
+        for (id, sender) in self.pending_device_removals.drain(..) {
+            if sender
+                .send(Err(ApiError::VmRemoveDevice(VmError::VmNotRunning)))
+                .is_err()
+            {
+                warn!("Cannot answer the removal request of the device {}", id);
+            }
+        }
+
+        if !self.device_removals_watched {
+            return;
+        }
+        self.device_removals_watched = false;
+        if let Some(vm) = self.vm.as_ref() {
+            if let Err(e) = self
+                .epoll
+                .remove_fd(vm.device_removal_timer_fd(), EpollDispatch::DeviceRemoval)
+            {
+                warn!("Cannot stop watching the device removals: {}", e);
+            }
+        }
+    }
+
+    /// Runs the VMM event loop until the VMM is shut down.
+    ///
+    /// This is a convenience wrapper around the same dispatching code as
+    /// `dispatch_ready_events()`, blocking until some events are ready.
+    pub fn control_loop(&mut self, api_receiver: Arc<Receiver<ApiRequest>>) -> Result<()> {
+        while self.dispatch_events(-1, &api_receiver)? {}
+
+        Ok(())
+    }
+
+    /// Handles the events currently pending on the VMM epoll context,
+    /// without blocking.
+    ///
+    /// This lets an external reactor drive the VMM: it watches the fd
+    /// returned by `as_raw_fd()` for readability and calls into this
+    /// function whenever it triggers. Returns `false` once the VMM has been
+    /// shut down, after which no more events should be dispatched.
+    pub fn dispatch_ready_events(&mut self, api_receiver: &Receiver<ApiRequest>) -> Result<bool> {
+        self.dispatch_events(0, api_receiver)
+    }
+
+    fn dispatch_events(
+        &mut self,
+        timeout: i32,
+        api_receiver: &Receiver<ApiRequest>,
+    ) -> Result<bool> {
         const EPOLL_EVENTS_LEN: usize = 100;
 
         let mut events = vec![epoll::Event::new(epoll::Events::empty(), 0); EPOLL_EVENTS_LEN];
         let epoll_fd = self.epoll.as_raw_fd();
 
-        'outer: loop {
-            let num_events = match epoll::wait(epoll_fd, -1, &mut events[..]) {
-                Ok(res) => res,
+        let num_events = loop {
+            match epoll::wait(epoll_fd, timeout, &mut events[..]) {
+                Ok(res) => break res,
                 Err(e) => {
                     if e.kind() == io::ErrorKind::Interrupted {
                         // It's well defined from the epoll_wait() syscall
@@ -376,146 +1322,323 @@ impl Vmm {
                     }
                     return Err(Error::Epoll(e));
                 }
-            };
-
-            for event in events.iter().take(num_events) {
-                let dispatch_idx = event.data as usize;
+            }
+        };
+
+        for event in events.iter().take(num_events) {
+            let dispatch_idx = event.data as usize;
+
+            if let Some(dispatch_type) = self.epoll.dispatch_table[dispatch_idx] {
+                match dispatch_type {
+                    EpollDispatch::Exit => {
+                        // Consume the event.
+                        self.exit_evt.read().map_err(Error::EventFdRead)?;
+                        event!("vm", "exit-requested");
+                        if let Some(ref vm) = self.vm {
+                            for failure in vm.vcpu_failures() {
+                                error!("Shutting down the VM: {}", failure);
+                            }
+                        }
+                        self.vmm_shutdown().map_err(Error::VmmShutdown)?;
 
-                if let Some(dispatch_type) = self.epoll.dispatch_table[dispatch_idx] {
-                    match dispatch_type {
-                        EpollDispatch::Exit => {
-                            // Consume the event.
-                            self.exit_evt.read().map_err(Error::EventFdRead)?;
-                            self.vmm_shutdown().map_err(Error::VmmShutdown)?;
+                        return Ok(false);
+                    }
+                    EpollDispatch::ShutdownSignal => {
+                        let signal = self
+                            .shutdown_signals
+                            .read()
+                            .map_err(Error::ShutdownSignals)?;
+                        if let Some(signal) = signal {
+                            info!("Shutting down the VMM on signal {}", signal);
+                            event!("vmm", "shutdown-signal");
+                            // A VM created but not booted yet has nothing
+                            // to shut down.
+                            match self.vmm_shutdown() {
+                                Ok(()) | Err(VmError::VmNotRunning) => {}
+                                Err(e) => return Err(Error::VmmShutdown(e)),
+                            }
 
-                            break 'outer;
-                        }
-                        EpollDispatch::Reset => {
-                            // Consume the event.
-                            self.reset_evt.read().map_err(Error::EventFdRead)?;
-                            self.vm_reboot().map_err(Error::VmReboot)?;
+                            return Ok(false);
                         }
-                        EpollDispatch::Stdin => {
-                            if let Some(ref vm) = self.vm {
-                                vm.handle_stdin().map_err(Error::Stdin)?;
+                    }
+                    EpollDispatch::Reset => {
+                        // Consume the event.
+                        self.reset_evt.read().map_err(Error::EventFdRead)?;
+                        event!("vm", "reset-requested");
+                        self.vm_reboot().map_err(Error::VmReboot)?;
+                    }
+                    EpollDispatch::Stdin => {
+                        if let Some(ref vm) = self.vm {
+                            // A closed stdin stays readable forever, stop
+                            // watching it once we reach its end.
+                            if vm.handle_stdin().map_err(Error::Stdin)? == 0 {
+                                self.epoll.remove_stdin().map_err(Error::Epoll)?;
                             }
                         }
-                        EpollDispatch::Api => {
-                            // Consume the event.
-                            self.api_evt.read().map_err(Error::EventFdRead)?;
-
-                            // Read from the API receiver channel
-                            let api_request = api_receiver.recv().map_err(Error::ApiRequestRecv)?;
-
-                            match api_request {
-                                ApiRequest::VmCreate(config, sender) => {
-                                    // We only store the passed VM config.
-                                    // The VM will be created when being asked to boot it.
-                                    let response = if self.vm_config.is_none() {
-                                        self.vm_config = Some(config);
-                                        Ok(ApiResponsePayload::Empty)
-                                    } else {
-                                        Err(ApiError::VmAlreadyCreated)
-                                    };
-
-                                    sender.send(response).map_err(Error::ApiResponseSend)?;
-                                }
-                                ApiRequest::VmDelete(sender) => {
-                                    let response = self
-                                        .vm_delete()
-                                        .map_err(ApiError::VmDelete)
-                                        .map(|_| ApiResponsePayload::Empty);
+                    }
+                    EpollDispatch::VncListener => self.vnc_accept(),
+                    EpollDispatch::VncClient => self.vnc_client_event(false),
+                    EpollDispatch::VncTimer => self.vnc_client_event(true),
+                    EpollDispatch::MetricsListener => self.metrics_accept(),
+                    EpollDispatch::MetricsClient => self.metrics_client_event(),
+                    EpollDispatch::MemoryPressure => self.memory_pressure_event(),
+                    EpollDispatch::RuntimeBudget => self.runtime_budget_event()?,
+                    EpollDispatch::DeviceRemoval => self.device_removal_event(),
+                    EpollDispatch::SerialOutput | EpollDispatch::SerialOutputQueued => {
+                        if let Some(ref serial_buffer) = self.serial_output {
+                            serial_buffer.flush_buffer();
+                        }
+                        if dispatch_type == EpollDispatch::SerialOutputQueued {
+                            self.serial_output_queued();
+                        }
+                    }
+                    EpollDispatch::Api => {
+                        // Consume the event.
+                        self.api_evt.read().map_err(Error::EventFdRead)?;
+
+                        // Read from the API receiver channel
+                        let api_request = api_receiver.recv().map_err(Error::ApiRequestRecv)?;
+
+                        match api_request {
+                            ApiRequest::VmCreate(config, sender) => {
+                                // We only store the passed VM config.
+                                // The VM will be created when being asked to boot it.
+                                let response = if self.vm_config.is_none() {
+                                    self.add_stdin(&config);
+                                    self.vm_config = Some(config);
+                                    Ok(ApiResponsePayload::Empty)
+                                } else {
+                                    Err(ApiError::VmAlreadyCreated)
+                                };
+
+                                sender.send(response).map_err(Error::ApiResponseSend)?;
+                            }
+                            ApiRequest::VmDelete(sender) => {
+                                let response = self
+                                    .vm_delete()
+                                    .map_err(ApiError::VmDelete)
+                                    .map(|_| ApiResponsePayload::Empty);
 
-                                    sender.send(response).map_err(Error::ApiResponseSend)?;
-                                }
-                                ApiRequest::VmBoot(sender) => {
-                                    // If we don't have a config, we can not boot a VM.
-                                    if self.vm_config.is_none() {
-                                        sender
-                                            .send(Err(ApiError::VmMissingConfig))
-                                            .map_err(Error::ApiResponseSend)?;
-                                        continue;
-                                    }
-
-                                    let response = self
-                                        .vm_boot()
-                                        .map_err(ApiError::VmBoot)
-                                        .map(|_| ApiResponsePayload::Empty);
-
-                                    sender.send(response).map_err(Error::ApiResponseSend)?;
+                                sender.send(response).map_err(Error::ApiResponseSend)?;
+                            }
+                            ApiRequest::VmBoot(sender) => {
+                                // If we don't have a config, we can not boot a VM.
+                                if self.vm_config.is_none() {
+                                    sender
+                                        .send(Err(ApiError::VmMissingConfig))
+                                        .map_err(Error::ApiResponseSend)?;
+                                    continue;
                                 }
-                                ApiRequest::VmShutdown(sender) => {
-                                    let response = self
-                                        .vm_shutdown()
-                                        .map_err(ApiError::VmShutdown)
-                                        .map(|_| ApiResponsePayload::Empty);
 
-                                    sender.send(response).map_err(Error::ApiResponseSend)?;
-                                }
-                                ApiRequest::VmReboot(sender) => {
-                                    let response = self
-                                        .vm_reboot()
-                                        .map_err(ApiError::VmReboot)
-                                        .map(|_| ApiResponsePayload::Empty);
+                                let response = self
+                                    .vm_boot()
+                                    .map_err(ApiError::VmBoot)
+                                    .map(|_| ApiResponsePayload::Empty);
 
-                                    sender.send(response).map_err(Error::ApiResponseSend)?;
-                                }
-                                ApiRequest::VmInfo(sender) => {
-                                    let response = self
-                                        .vm_info()
-                                        .map_err(ApiError::VmInfo)
-                                        .map(ApiResponsePayload::VmInfo);
+                                sender.send(response).map_err(Error::ApiResponseSend)?;
+                            }
+                            ApiRequest::VmShutdown(sender) => {
+                                let response = self
+                                    .vm_shutdown()
+                                    .map_err(ApiError::VmShutdown)
+                                    .map(|_| ApiResponsePayload::Empty);
 
-                                    sender.send(response).map_err(Error::ApiResponseSend)?;
-                                }
-                                ApiRequest::VmmPing(sender) => {
-                                    let response = self.vmm_ping().map(ApiResponsePayload::VmmPing);
+                                sender.send(response).map_err(Error::ApiResponseSend)?;
+                            }
+                            ApiRequest::VmReboot(sender) => {
+                                let response = self
+                                    .vm_reboot()
+                                    .map_err(ApiError::VmReboot)
+                                    .map(|_| ApiResponsePayload::Empty);
 
-                                    sender.send(response).map_err(Error::ApiResponseSend)?;
-                                }
-                                ApiRequest::VmPause(sender) => {
-                                    let response = self
-                                        .vm_pause()
-                                        .map_err(ApiError::VmPause)
-                                        .map(|_| ApiResponsePayload::Empty);
+                                sender.send(response).map_err(Error::ApiResponseSend)?;
+                            }
+                            ApiRequest::VmInfo(sender) => {
+                                let response = self
+                                    .vm_info()
+                                    .map_err(ApiError::VmInfo)
+                                    .map(ApiResponsePayload::VmInfo);
 
-                                    sender.send(response).map_err(Error::ApiResponseSend)?;
-                                }
-                                ApiRequest::VmResume(sender) => {
-                                    let response = self
-                                        .vm_resume()
-                                        .map_err(ApiError::VmResume)
-                                        .map(|_| ApiResponsePayload::Empty);
+                                sender.send(response).map_err(Error::ApiResponseSend)?;
+                            }
+                            ApiRequest::VmBalloonStats(sender) => {
+                                let response = self
+                                    .vm_balloon_stats()
+                                    .map_err(ApiError::VmBalloonStats)
+                                    .map(ApiResponsePayload::VmBalloonStats);
 
-                                    sender.send(response).map_err(Error::ApiResponseSend)?;
-                                }
-                                ApiRequest::VmmShutdown(sender) => {
-                                    let response = self
-                                        .vmm_shutdown()
-                                        .map_err(ApiError::VmmShutdown)
-                                        .map(|_| ApiResponsePayload::Empty);
+                                sender.send(response).map_err(Error::ApiResponseSend)?;
+                            }
+                            ApiRequest::VmScreenshot(screenshot_data, sender) => {
+                                let response = self
+                                    .vm_screenshot(&screenshot_data.destination)
+                                    .map_err(ApiError::VmScreenshot)
+                                    .map(|_| ApiResponsePayload::Empty);
+
+                                sender.send(response).map_err(Error::ApiResponseSend)?;
+                            }
+                            ApiRequest::VmmPing(sender) => {
+                                let response = self.vmm_ping().map(ApiResponsePayload::VmmPing);
 
-                                    sender.send(response).map_err(Error::ApiResponseSend)?;
+                                sender.send(response).map_err(Error::ApiResponseSend)?;
+                            }
+                            ApiRequest::VmmMetrics(sender) => {
+                                let response =
+                                    Ok(ApiResponsePayload::VmmMetrics(self.vmm_metrics()));
 
-                                    break 'outer;
-                                }
-                                ApiRequest::VmResize(resize_data, sender) => {
-                                    let response = self
-                                        .vm_resize(
-                                            resize_data.desired_vcpus,
-                                            resize_data.desired_ram,
-                                        )
-                                        .map_err(ApiError::VmResize)
-                                        .map(|_| ApiResponsePayload::Empty);
-                                    sender.send(response).map_err(Error::ApiResponseSend)?;
+                                sender.send(response).map_err(Error::ApiResponseSend)?;
+                            }
+                            ApiRequest::VmPause(sender) => {
+                                let response = self
+                                    .vm_pause()
+                                    .map_err(ApiError::VmPause)
+                                    .map(|_| ApiResponsePayload::Empty);
+
+                                sender.send(response).map_err(Error::ApiResponseSend)?;
+                            }
+                            ApiRequest::VmResume(sender) => {
+                                let response = self
+                                    .vm_resume()
+                                    .map_err(ApiError::VmResume)
+                                    .map(|_| ApiResponsePayload::Empty);
+
+                                sender.send(response).map_err(Error::ApiResponseSend)?;
+                            }
+                            ApiRequest::VmmShutdown(sender) => {
+                                let response = self
+                                    .vmm_shutdown()
+                                    .map_err(ApiError::VmmShutdown)
+                                    .map(|_| ApiResponsePayload::Empty);
+
+                                sender.send(response).map_err(Error::ApiResponseSend)?;
+
+                                return Ok(false);
+                            }
+                            ApiRequest::VmResize(resize_data, sender) => {
+                                let response = self
+                                    .vm_resize(resize_data.desired_vcpus, resize_data.desired_ram)
+                                    .map_err(ApiError::VmResize)
+                                    .map(|_| ApiResponsePayload::Empty);
+                                sender.send(response).map_err(Error::ApiResponseSend)?;
+                            }
+                            ApiRequest::VmNmi(nmi_data, sender) => {
+                                let response = self
+                                    .vm_nmi(nmi_data.cpu_id)
+                                    .map_err(ApiError::VmNmi)
+                                    .map(|_| ApiResponsePayload::Empty);
+                                sender.send(response).map_err(Error::ApiResponseSend)?;
+                            }
+                            ApiRequest::VmUpdateGenerationId(sender) => {
+                                let response = self
+                                    .vm_update_generation_id()
+                                    .map_err(ApiError::VmUpdateGenerationId)
+                                    .map(|_| ApiResponsePayload::Empty);
+                                sender.send(response).map_err(Error::ApiResponseSend)?;
+                            }
+                            ApiRequest::VmSendKey(send_key_data, sender) => {
+                                let response = self
+                                    .vm_send_key(&send_key_data.scancodes)
+                                    .map_err(ApiError::VmSendKey)
+                                    .map(|_| ApiResponsePayload::Empty);
+                                sender.send(response).map_err(Error::ApiResponseSend)?;
+                            }
+                            ApiRequest::VmResizeDisk(resize_disk_data, sender) => {
+                                let response = self
+                                    .vm_resize_disk(&resize_disk_data)
+                                    .map_err(ApiError::VmResizeDisk)
+                                    .map(|_| ApiResponsePayload::Empty);
+                                sender.send(response).map_err(Error::ApiResponseSend)?;
+                            }
+                            ApiRequest::VmSetLink(set_link_data, sender) => {
+                                let response = self
+                                    .vm_set_link(&set_link_data)
+                                    .map_err(ApiError::VmSetLink)
+                                    .map(|_| ApiResponsePayload::Empty);
+                                sender.send(response).map_err(Error::ApiResponseSend)?;
+                            }
+                            ApiRequest::VmAddDevice(add_device_data, sender) => {
+                                let response = self
+                                    .vm_add_device(&add_device_data)
+                                    .map_err(ApiError::VmAddDevice)
+                                    .map(ApiResponsePayload::VmAddDevice);
+                                sender.send(response).map_err(Error::ApiResponseSend)?;
+                            }
+                            // Answered once the removal is over, from
+                            // device_removal_event().
+                            ApiRequest::VmRemoveDevice(remove_device_data, sender) => {
+                                match self.vm_remove_device(&remove_device_data) {
+                                    Ok(id) => self.pending_device_removals.push((id, sender)),
+                                    Err(e) => sender
+                                        .send(Err(ApiError::VmRemoveDevice(e)))
+                                        .map_err(Error::ApiResponseSend)?,
                                 }
                             }
+                            ApiRequest::VmSnapshot(snapshot_data, sender) => {
+                                let response = self
+                                    .vm_snapshot(&snapshot_data.destination)
+                                    .map_err(ApiError::VmSnapshot)
+                                    .map(|_| ApiResponsePayload::Empty);
+                                sender.send(response).map_err(Error::ApiResponseSend)?;
+                            }
+                            ApiRequest::VmRestore(restore_data, sender) => {
+                                let response = if self.vm_config.is_none() {
+                                    self.vm_restore(&restore_data.source)
+                                        .map_err(ApiError::VmRestore)
+                                        .map(|_| ApiResponsePayload::Empty)
+                                } else {
+                                    Err(ApiError::VmAlreadyCreated)
+                                };
+                                sender.send(response).map_err(Error::ApiResponseSend)?;
+                            }
                         }
                     }
                 }
             }
         }
 
-        Ok(())
+        Ok(true)
+    }
+}
+
+impl AsRawFd for Vmm {
+    /// The VMM epoll context, which becomes readable whenever
+    /// `dispatch_ready_events()` has some work to do.
+    fn as_raw_fd(&self) -> RawFd {
+        self.epoll.as_raw_fd()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc::channel;
+
+    #[test]
+    fn test_dispatch_ready_events() {
+        let api_evt = EventFd::new(EFD_NONBLOCK).unwrap();
+        let mut vmm = Vmm::new("test".to_string(), api_evt.try_clone().unwrap()).unwrap();
+        let (api_sender, api_receiver) = channel();
+
+        // Nothing is pending, this must not block.
+        assert!(vmm.dispatch_ready_events(&api_receiver).unwrap());
+
+        let (response_sender, response_receiver) = channel();
+        api_sender
+            .send(ApiRequest::VmmPing(response_sender))
+            .unwrap();
+        api_evt.write(1).unwrap();
+        assert!(vmm.dispatch_ready_events(&api_receiver).unwrap());
+        match response_receiver.try_recv().unwrap() {
+            Ok(ApiResponsePayload::VmmPing(ping)) => assert_eq!(ping.version, "test"),
+            _ => panic!("unexpected ping response"),
+        }
+
+        let (response_sender, response_receiver) = channel();
+        api_sender
+            .send(ApiRequest::VmmShutdown(response_sender))
+            .unwrap();
+        api_evt.write(1).unwrap();
+        assert!(!vmm.dispatch_ready_events(&api_receiver).unwrap());
+        assert!(response_receiver.try_recv().unwrap().is_ok());
     }
 }
@@ -3,28 +3,40 @@
 // SPDX-License-Identifier: Apache-2.0
 //
 
+use crate::config::{MemoryConfig, NumaConfig};
+use crate::memory_slots::{Error as MemorySlotError, MemorySlot, MemorySlots};
 #[cfg(feature = "acpi")]
 use acpi_tables::{aml, aml::Aml};
+use anyhow::anyhow;
 use arc_swap::ArcSwap;
-use arch::RegionType;
+use arch::{MmioHole, RegionType};
 use devices::BusDevice;
-use kvm_bindings::kvm_userspace_memory_region;
-use kvm_ioctls::*;
 use std::convert::TryInto;
+use std::fmt;
 use std::fs::{File, OpenOptions};
 use std::io;
 use std::os::unix::io::FromRawFd;
 use std::path::PathBuf;
+use std::result;
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
 use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Instant;
 use vm_allocator::SystemAllocator;
+use vm_device::{MigratableError, Snapshot, SnapshotDataSection, Snapshotable};
 use vm_memory::guest_memory::FileOffset;
 use vm_memory::{
-    mmap::MmapRegionError, Address, Error as MmapError, GuestAddress, GuestMemory, GuestMemoryMmap,
-    GuestMemoryRegion, GuestRegionMmap, GuestUsize, MmapRegion,
+    mmap::MmapRegionError, Address, Bytes, Error as MmapError, GuestAddress, GuestMemory,
+    GuestMemoryMmap, GuestMemoryRegion, GuestRegionMmap, GuestUsize, MemoryRegionAddress,
+    MmapRegion,
 };
 
 const HOTPLUG_COUNT: usize = 8;
 
+// The host EPC is handed out to VMs through this device, each mapping of it
+// getting its own EPC pages.
+const SGX_VEPC_DEVICE: &str = "/dev/sgx_vepc";
+
 #[derive(Default)]
 struct HotPlugState {
     base: u64,
@@ -36,18 +48,51 @@ struct HotPlugState {
 
 pub struct MemoryManager {
     guest_memory: Arc<ArcSwap<GuestMemoryMmap>>,
-    next_kvm_memory_slot: u32,
     start_of_device_area: GuestAddress,
     end_of_device_area: GuestAddress,
-    fd: Arc<VmFd>,
+    // Where the RAM can be hotplugged up to, the device area being moved
+    // past the EPC sections.
+    end_of_hotplug_area: GuestAddress,
+    memory_slots: Arc<MemorySlots>,
     mem_regions: Vec<Arc<GuestRegionMmap>>,
     hotplug_slots: Vec<HotPlugState>,
     selected_slot: usize,
     backing_file: Option<PathBuf>,
-    mergeable: bool,
+    mergeable: Option<bool>,
+    mergeable_regions: Vec<MergeableRegion>,
+    transparent_hugepages: Option<bool>,
+    prefault: bool,
+    // Threads faulting the RAM regions in, in the background.
+    prefault_threads: Vec<thread::JoinHandle<()>>,
+    // Tells the prefault threads to give up, the VM being gone.
+    prefault_stop: Arc<AtomicBool>,
     allocator: Arc<Mutex<SystemAllocator>>,
     current_ram: u64,
     next_hotplug_slot: usize,
+    numa_ranges: Vec<NumaRange>,
+    firmware_region: Option<Arc<GuestRegionMmap>>,
+    mmio_hole: MmioHole,
+    // The host EPC mapped into the guest, kept mapped along with the VM.
+    sgx_epc_regions: Vec<MmapRegion>,
+}
+
+/// The advice given to KSM about merging the pages of a guest RAM region.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct MergeableRegion {
+    pub gpa: u64,
+    pub size: u64,
+    /// Whether the pages were marked mergeable, rather than unmergeable.
+    pub mergeable: bool,
+    /// Whether the kernel took the advice.
+    pub advised: bool,
+}
+
+/// A boot RAM range belonging to a guest NUMA node.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct NumaRange {
+    pub node: u32,
+    pub start: GuestAddress,
+    pub size: usize,
 }
 
 #[derive(Debug)]
@@ -79,10 +124,122 @@ pub enum Error {
     /// The requested hotplug memory addition is not a valid size
     InvalidSize,
 
-    /// Failed to set the user memory region.
-    SetUserMemoryRegion(kvm_ioctls::Error),
+    /// Failed to map memory into the guest through a KVM memory slot.
+    MemorySlot(MemorySlotError),
+
+    /// Failed to write the guest memory to a file.
+    DumpMemory(MmapError),
+
+    /// Failed to read the guest memory from a file.
+    LoadMemory(MmapError),
+
+    /// Failed to bind guest RAM to a host NUMA node.
+    Mbind(io::Error),
+
+    /// Failed to access the firmware file.
+    FirmwareFile(io::Error),
+
+    /// The firmware is empty or doesn't fit below 4GiB.
+    InvalidFirmwareSize(u64),
+
+    /// Failed to read the firmware from its file.
+    LoadFirmware(MmapError),
+
+    /// A guest memory region isn't within the RAM layout.
+    InvalidGuestMemory(u64, u64),
+
+    /// The guest memory doesn't match the size of the RAM.
+    GuestMemorySize(u64, u64),
+
+    /// A guest memory range isn't backed by a single memory region.
+    AddressNotBacked(GuestAddress, usize),
+
+    /// Failed to open the SGX virtual EPC device.
+    SgxEpcDevice(io::Error),
+
+    /// Failed to map the host EPC.
+    SgxEpcMap(MmapRegionError),
+
+    /// Failed to allocate the guest range of the EPC sections.
+    SgxEpcRangeAllocation,
 }
 
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::SharedFileCreate(_) => write!(f, "failed to create shared file"),
+            Error::SharedFileSetLen(_) => write!(f, "failed to set shared file length"),
+            Error::GuestMemory(_) => write!(f, "mmap backed guest memory error"),
+            Error::MemoryRangeAllocation => write!(f, "failed to allocate a memory range"),
+            Error::MmapRegion() => write!(f, "failed to create map region"),
+            Error::GuestMemoryRegion(_) => write!(f, "error from region creation"),
+            Error::NoSlotAvailable => write!(f, "no ACPI slot available"),
+            Error::InsufficientHotplugRAM => {
+                write!(f, "not enough space in the hotplug RAM region")
+            }
+            Error::InvalidSize => write!(
+                f,
+                "the requested hotplug memory addition is not a valid size"
+            ),
+            Error::MemorySlot(e) => write!(f, "failed to map the guest memory: {}", e),
+            Error::DumpMemory(_) => write!(f, "failed to write the guest memory to a file"),
+            Error::LoadMemory(_) => write!(f, "failed to read the guest memory from a file"),
+            Error::Mbind(_) => write!(f, "failed to bind guest RAM to a host NUMA node"),
+            Error::FirmwareFile(_) => write!(f, "failed to access the firmware file"),
+            Error::InvalidFirmwareSize(size) => {
+                write!(f, "invalid firmware size {:#x}", size)
+            }
+            Error::LoadFirmware(_) => write!(f, "failed to read the firmware from its file"),
+            Error::InvalidGuestMemory(start, size) => write!(
+                f,
+                "guest memory region at {:#x} of size {:#x} is outside the RAM layout",
+                start, size
+            ),
+            Error::GuestMemorySize(size, ram_size) => write!(
+                f,
+                "guest memory size {:#x} doesn't match the RAM size {:#x}",
+                size, ram_size
+            ),
+            Error::AddressNotBacked(addr, len) => write!(
+                f,
+                "guest memory range at {:#x} of size {:#x} isn't backed by a single region",
+                addr.raw_value(),
+                len
+            ),
+            Error::SgxEpcDevice(e) => write!(
+                f,
+                "cannot open {}, the host doesn't support SGX virtualization: {}",
+                SGX_VEPC_DEVICE, e
+            ),
+            Error::SgxEpcMap(_) => write!(f, "failed to map the host EPC"),
+            Error::SgxEpcRangeAllocation => {
+                write!(f, "failed to allocate the guest range of the EPC sections")
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::SharedFileCreate(e) => Some(e),
+            Error::SharedFileSetLen(e) => Some(e),
+            Error::GuestMemory(e) => Some(e),
+            Error::GuestMemoryRegion(e) => Some(e),
+            Error::DumpMemory(e) => Some(e),
+            Error::LoadMemory(e) => Some(e),
+            Error::Mbind(e) => Some(e),
+            Error::FirmwareFile(e) => Some(e),
+            Error::LoadFirmware(e) => Some(e),
+            Error::MemorySlot(e) => Some(e),
+            Error::SgxEpcDevice(e) => Some(e),
+            Error::SgxEpcMap(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
 pub fn get_host_cpu_phys_bits() -> u8 {
     use core::arch::x86_64;
     unsafe {
@@ -111,6 +268,13 @@ pub fn get_host_cpu_phys_bits() -> u8 {
     }
 }
 
+// KVM gives arm64 guests a 40 bits IPA space, unless asked otherwise when
+// creating the VM.
+#[cfg(target_arch = "aarch64")]
+pub fn get_host_cpu_phys_bits() -> u8 {
+    40
+}
+
 const ENABLE_FLAG: usize = 0;
 const INSERTING_FLAG: usize = 1;
 const REMOVING_FLAG: usize = 2;
@@ -192,46 +356,79 @@ impl BusDevice for MemoryManager {
 }
 
 impl MemoryManager {
-    pub fn new(
+    /// Allocates the boot RAM over `ram_regions`, as returned by
+    /// `boot_ram_regions()`, with a region per guest NUMA node if any.
+    pub fn create_boot_ram(
+        ram_regions: &[(GuestAddress, usize)],
+        backing_file: &Option<PathBuf>,
+        numa_nodes: &Option<Vec<NumaConfig>>,
+    ) -> Result<Vec<Arc<GuestRegionMmap>>, Error> {
+        let mut mem_regions = Vec::new();
+        if let Some(numa_nodes) = numa_nodes {
+            // Each node gets its own regions, so that they can be bound to
+            // a host node independently.
+            let nodes = sorted_nodes(numa_nodes);
+            let node_sizes: Vec<u64> = nodes.iter().map(|n| n.size).collect();
+            for (index, start, size) in split_ram_regions(ram_regions, &node_sizes) {
+                let region = MemoryManager::create_ram_region(backing_file, start, size)?;
+                if let Some(host_node) = nodes[index].host_node {
+                    mbind_region(&region, host_node)?;
+                }
+                mem_regions.push(region);
+            }
+        } else {
+            for region in ram_regions.iter() {
+                mem_regions.push(MemoryManager::create_ram_region(
+                    backing_file,
+                    region.0,
+                    region.1,
+                )?);
+            }
+        }
+
+        Ok(mem_regions)
+    }
+
+    /// Creates the memory manager for boot RAM allocated by the caller, e.g.
+    /// shared with an external backend, or preloaded from a snapshot. The
+    /// regions must cover `ram_regions`, and are only registered with KVM.
+    #[allow(clippy::too_many_arguments)]
+    pub fn with_memory(
         allocator: Arc<Mutex<SystemAllocator>>,
-        fd: Arc<VmFd>,
-        boot_ram: u64,
+        memory_slots: Arc<MemorySlots>,
+        mem_regions: Vec<Arc<GuestRegionMmap>>,
+        ram_regions: &[(GuestAddress, usize)],
         hotplug_size: Option<u64>,
         backing_file: &Option<PathBuf>,
-        mergeable: bool,
+        mergeable: Option<bool>,
+        transparent_hugepages: Option<bool>,
+        prefault: bool,
+        numa_nodes: &Option<Vec<NumaConfig>>,
+        mmio_hole: MmioHole,
     ) -> Result<Arc<Mutex<MemoryManager>>, Error> {
-        // Init guest memory
-        let arch_mem_regions = arch::arch_memory_regions(boot_ram);
-
-        let ram_regions: Vec<(GuestAddress, usize)> = arch_mem_regions
-            .iter()
-            .filter(|r| r.2 == RegionType::Ram)
-            .map(|r| (r.0, r.1))
-            .collect();
-
-        let mut mem_regions = Vec::new();
-        for region in ram_regions.iter() {
-            mem_regions.push(MemoryManager::create_ram_region(
-                backing_file,
-                region.0,
-                region.1,
-            )?);
-        }
+        check_ram_regions(ram_regions, &mem_regions)?;
+
+        let numa_ranges = match numa_nodes {
+            Some(numa_nodes) => {
+                let nodes = sorted_nodes(numa_nodes);
+                let node_sizes: Vec<u64> = nodes.iter().map(|n| n.size).collect();
+                split_ram_regions(ram_regions, &node_sizes)
+                    .into_iter()
+                    .map(|(index, start, size)| NumaRange {
+                        node: nodes[index].id,
+                        start,
+                        size,
+                    })
+                    .collect()
+            }
+            None => Vec::new(),
+        };
 
         let guest_memory =
             GuestMemoryMmap::from_arc_regions(mem_regions.clone()).map_err(Error::GuestMemory)?;
 
-        let end_of_device_area = GuestAddress((1 << get_host_cpu_phys_bits()) - 1);
-        let mem_end = guest_memory.last_addr();
-        let mut start_of_device_area = if mem_end < arch::layout::MEM_32BIT_RESERVED_START {
-            arch::layout::RAM_64BIT_START
-        } else {
-            mem_end.unchecked_add(1)
-        };
-
-        if let Some(size) = hotplug_size {
-            start_of_device_area = start_of_device_area.unchecked_add(size);
-        }
+        let (start_of_device_area, end_of_device_area) =
+            device_area(ram_regions, hotplug_size, mmio_hole);
 
         let guest_memory = Arc::new(ArcSwap::new(Arc::new(guest_memory)));
 
@@ -240,32 +437,47 @@ impl MemoryManager {
 
         let memory_manager = Arc::new(Mutex::new(MemoryManager {
             guest_memory: guest_memory.clone(),
-            next_kvm_memory_slot: 0,
             start_of_device_area,
             end_of_device_area,
-            fd,
+            end_of_hotplug_area: start_of_device_area,
+            memory_slots,
             mem_regions,
             hotplug_slots,
             selected_slot: 0,
             backing_file: backing_file.clone(),
             mergeable,
+            mergeable_regions: Vec::new(),
+            transparent_hugepages,
+            prefault,
+            prefault_threads: Vec::new(),
+            prefault_stop: Arc::new(AtomicBool::new(false)),
             allocator: allocator.clone(),
-            current_ram: boot_ram,
+            current_ram: ram_regions.iter().map(|r| r.1 as u64).sum(),
             next_hotplug_slot: 0,
+            numa_ranges,
+            firmware_region: None,
+            mmio_hole,
+            sgx_epc_regions: Vec::new(),
         }));
 
-        guest_memory.load().with_regions(|_, region| {
-            let _ = memory_manager.lock().unwrap().create_userspace_mapping(
-                region.start_addr().raw_value(),
-                region.len() as u64,
-                region.as_ptr() as u64,
-                mergeable,
-            )?;
-            Ok(())
-        })?;
+        {
+            let mut memory_manager = memory_manager.lock().unwrap();
+            for region in memory_manager.mem_regions.clone().iter() {
+                memory_manager.create_userspace_mapping(
+                    region.start_addr().raw_value(),
+                    region.len() as u64,
+                    region.as_ptr() as u64,
+                )?;
+                memory_manager.advise_ram_region(region);
+            }
+        }
 
         // Allocate RAM and Reserved address ranges.
-        for region in arch_mem_regions.iter() {
+        let reserved_regions = arch::arch_memory_regions(0, mmio_hole)
+            .into_iter()
+            .filter(|r| r.2 != RegionType::Ram)
+            .map(|r| (r.0, r.1));
+        for region in ram_regions.iter().cloned().chain(reserved_regions) {
             allocator
                 .lock()
                 .unwrap()
@@ -316,7 +528,7 @@ impl MemoryManager {
         }))
     }
 
-    fn hotplug_ram_region(&mut self, size: usize) -> Result<(), Error> {
+    fn hotplug_ram_region(&mut self, size: usize) -> Result<Arc<GuestRegionMmap>, Error> {
         info!("Hotplugging new RAM: {}", size);
 
         // Check that there is a free slot
@@ -332,14 +544,13 @@ impl MemoryManager {
         // Start address needs to be non-contiguous with last memory added (leaving a gap of 256MiB)
         // and also aligned to 128MiB boundary. It must also start at the 64bit start.
         let mem_end = self.guest_memory.load().last_addr();
-        let start_addr = if mem_end < arch::layout::MEM_32BIT_RESERVED_START {
+        let start_addr = if mem_end < self.mmio_hole.start {
             arch::layout::RAM_64BIT_START
         } else {
             GuestAddress((mem_end.0 + 1 + (256 << 20)) & !((128 << 20) - 1))
         };
 
-        if start_addr.checked_add(size.try_into().unwrap()).unwrap() >= self.start_of_device_area()
-        {
+        if start_addr.checked_add(size.try_into().unwrap()).unwrap() >= self.end_of_hotplug_area {
             return Err(Error::InsufficientHotplugRAM);
         }
 
@@ -351,8 +562,8 @@ impl MemoryManager {
             region.start_addr().0,
             region.len() as u64,
             region.as_ptr() as u64,
-            self.mergeable,
         )?;
+        self.advise_ram_region(&region);
 
         // Tell the allocator
         self.allocator
@@ -371,18 +582,65 @@ impl MemoryManager {
         self.next_hotplug_slot += 1;
 
         // Update the GuestMemoryMmap with the new range
-        self.mem_regions.push(region);
+        self.mem_regions.push(region.clone());
         let guest_memory = GuestMemoryMmap::from_arc_regions(self.mem_regions.clone())
             .map_err(Error::GuestMemory)?;
         self.guest_memory.store(Arc::new(guest_memory));
 
-        Ok(())
+        Ok(region)
     }
 
     pub fn guest_memory(&self) -> Arc<ArcSwap<GuestMemoryMmap>> {
         self.guest_memory.clone()
     }
 
+    /// Returns the host address the guest memory at `addr` is mapped at.
+    pub fn translate(&self, addr: GuestAddress) -> Result<*const u8, Error> {
+        self.translate_range(addr, 1)
+    }
+
+    /// Same as `translate()`, checking that the `len` bytes from `addr` are
+    /// all mapped, within a single region.
+    pub fn translate_range(&self, addr: GuestAddress, len: usize) -> Result<*const u8, Error> {
+        translate_range(&self.guest_memory.load(), addr, len)
+    }
+
+    /// Returns the guest RAM ranges, hotplugged memory included.
+    pub fn ram_regions(&self) -> Vec<(GuestAddress, usize)> {
+        self.mem_regions
+            .iter()
+            .map(|region| (region.start_addr(), region.len() as usize))
+            .collect()
+    }
+
+    /// The size of the guest RAM, hotplugged memory included.
+    pub fn current_ram(&self) -> u64 {
+        self.current_ram
+    }
+
+    /// The 32-bit MMIO hole the RAM below 4GiB stops at.
+    pub fn mmio_hole(&self) -> MmioHole {
+        self.mmio_hole
+    }
+
+    /// Returns the RAM regions hot-added to the guest.
+    pub fn hotplugged_regions(&self) -> Vec<Arc<GuestRegionMmap>> {
+        self.mem_regions
+            .iter()
+            .filter(|region| {
+                self.hotplug_slots
+                    .iter()
+                    .any(|slot| slot.active && slot.base == region.start_addr().raw_value())
+            })
+            .cloned()
+            .collect()
+    }
+
+    /// Returns the boot RAM ranges of each guest NUMA node, if any.
+    pub fn numa_ranges(&self) -> &[NumaRange] {
+        &self.numa_ranges
+    }
+
     pub fn start_of_device_area(&self) -> GuestAddress {
         self.start_of_device_area
     }
@@ -391,10 +649,10 @@ impl MemoryManager {
         self.end_of_device_area
     }
 
-    pub fn allocate_kvm_memory_slot(&mut self) -> u32 {
-        let slot_id = self.next_kvm_memory_slot;
-        self.next_kvm_memory_slot += 1;
-        slot_id
+    /// The KVM memory slots all the memory mapped into the guest goes
+    /// through.
+    pub fn memory_slots(&self) -> Arc<MemorySlots> {
+        self.memory_slots.clone()
     }
 
     pub fn create_userspace_mapping(
@@ -402,45 +660,16 @@ impl MemoryManager {
         guest_phys_addr: u64,
         memory_size: u64,
         userspace_addr: u64,
-        mergeable: bool,
     ) -> Result<u32, Error> {
-        let slot = self.allocate_kvm_memory_slot();
-        let mem_region = kvm_userspace_memory_region {
-            slot,
-            guest_phys_addr,
-            memory_size,
-            userspace_addr,
-            flags: 0,
-        };
-
-        // Safe because the guest regions are guaranteed not to overlap.
-        unsafe { self.fd.set_user_memory_region(mem_region) }
-            .map_err(Error::SetUserMemoryRegion)?;
-
-        // Mark the pages as mergeable if explicitly asked for.
-        if mergeable {
-            // Safe because the address and size are valid since the
-            // mmap succeeded.
-            let ret = unsafe {
-                libc::madvise(
-                    userspace_addr as *mut libc::c_void,
-                    memory_size as libc::size_t,
-                    libc::MADV_MERGEABLE,
-                )
-            };
-            if ret != 0 {
-                let err = io::Error::last_os_error();
-                // Safe to unwrap because the error is constructed with
-                // last_os_error(), which ensures the output will be Some().
-                let errno = err.raw_os_error().unwrap();
-                if errno == libc::EINVAL {
-                    warn!("kernel not configured with CONFIG_KSM");
-                } else {
-                    warn!("madvise error: {}", err);
-                }
-                warn!("failed to mark pages as mergeable");
-            }
-        }
+        let slot = self
+            .memory_slots
+            .add(MemorySlot {
+                guest_phys_addr,
+                memory_size,
+                userspace_addr,
+                flags: 0,
+            })
+            .map_err(Error::MemorySlot)?;
 
         info!(
             "Created userspace mapping: {:x} -> {:x} {:x}",
@@ -450,17 +679,601 @@ impl MemoryManager {
         Ok(slot)
     }
 
-    pub fn resize(&mut self, desired_ram: u64) -> Result<bool, Error> {
+    /// Marks the pages of a device memory mapped into the guest, such as a
+    /// pmem one, mergeable. The RAM gets its advice when it is created.
+    pub fn advise_mergeable_mapping(&self, guest_phys_addr: u64, userspace_addr: u64, size: u64) {
+        advise_mergeable(&[(guest_phys_addr, userspace_addr, size)], true, madvise);
+    }
+
+    /// Maps host EPC into the guest for each of the `sections`, contiguously
+    /// from the start of the device area, which is moved past them. Returns
+    /// where the sections landed in the guest, for the CPUID to tell.
+    #[cfg(target_arch = "x86_64")]
+    pub fn setup_sgx(
+        &mut self,
+        sections: &[crate::config::SgxEpcConfig],
+    ) -> Result<Vec<arch::x86_64::SgxEpcSection>, Error> {
+        let start = GuestAddress((self.start_of_device_area.raw_value() + 0xfff) & !0xfff);
+        let size = sections
+            .iter()
+            .try_fold(0u64, |size, section| size.checked_add(section.size))
+            .ok_or(Error::SgxEpcRangeAllocation)?;
+        self.allocator
+            .lock()
+            .unwrap()
+            .allocate_high_mmio_addresses(Some(start), size as GuestUsize, None)
+            .ok_or(Error::SgxEpcRangeAllocation)?;
+
+        let mut epc_sections = Vec::new();
+        let mut section_start = start;
+        for section in sections.iter() {
+            let file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open(SGX_VEPC_DEVICE)
+                .map_err(Error::SgxEpcDevice)?;
+            let mut flags = libc::MAP_SHARED;
+            if section.prefault {
+                flags |= libc::MAP_POPULATE;
+            }
+            let region = MmapRegion::build(
+                Some(FileOffset::new(file, 0)),
+                section.size as usize,
+                libc::PROT_READ | libc::PROT_WRITE,
+                flags,
+            )
+            .map_err(Error::SgxEpcMap)?;
+
+            self.create_userspace_mapping(
+                section_start.raw_value(),
+                section.size,
+                region.as_ptr() as u64,
+            )?;
+            self.sgx_epc_regions.push(region);
+
+            epc_sections.push(arch::x86_64::SgxEpcSection {
+                start: section_start,
+                size: section.size,
+            });
+            section_start = section_start.unchecked_add(section.size);
+        }
+
+        // The devices are allocated past the EPC, which isn't theirs to
+        // decode.
+        self.start_of_device_area = section_start;
+
+        Ok(epc_sections)
+    }
+
+    // Gives the kernel the advice asked for about a RAM region, recording
+    // whether KSM took it, then starts faulting the region in if asked to.
+    // The huge pages advice has to come first for the faults to get huge
+    // pages.
+    //
+    // The faults are taken from a thread of their own, which can take
+    // seconds for large regions, rather than from the VMM control loop. The
+    // boot waits for them through wait_for_prefault(), while loading the
+    // kernel and setting the devices up meanwhile.
+    fn advise_ram_region(&mut self, region: &Arc<GuestRegionMmap>) {
+        let regions = [(
+            region.start_addr().raw_value(),
+            region.as_ptr() as u64,
+            region.len() as u64,
+        )];
+        if let Some(mergeable) = self.mergeable {
+            self.mergeable_regions
+                .extend(advise_mergeable(&regions, mergeable, madvise));
+        }
+        if let Some(hugepages) = self.transparent_hugepages {
+            advise_hugepages(&regions, hugepages, madvise);
+        }
+        if self.prefault {
+            // The thread keeps the region mapped until it is done with it.
+            let thread_region = Arc::clone(region);
+            let stop = Arc::clone(&self.prefault_stop);
+            let thread = thread::Builder::new()
+                .name("prefault".to_string())
+                .spawn(move || {
+                    let region = thread_region;
+                    let start = Instant::now();
+                    if prefault(region.as_ptr(), region.len() as usize, page_size(), &stop) {
+                        info!(
+                            "Prefaulted RAM at 0x{:x}, {} bytes, in {:?}",
+                            region.start_addr().raw_value(),
+                            region.len(),
+                            start.elapsed()
+                        );
+                    }
+                });
+            match thread {
+                Ok(thread) => self.prefault_threads.push(thread),
+                Err(e) => {
+                    warn!(
+                        "Failed to start prefaulting RAM at 0x{:x}, prefaulting it now: {}",
+                        region.start_addr().raw_value(),
+                        e
+                    );
+                    prefault(
+                        region.as_ptr(),
+                        region.len() as usize,
+                        page_size(),
+                        &self.prefault_stop,
+                    );
+                }
+            }
+        }
+    }
+
+    /// Waits for the RAM mapped so far to be faulted in, for the vCPUs not
+    /// to take the first faults once started.
+    pub fn wait_for_prefault(&mut self) {
+        if self.prefault_threads.is_empty() {
+            return;
+        }
+
+        let start = Instant::now();
+        for thread in self.prefault_threads.drain(..) {
+            if thread.join().is_err() {
+                error!("Failed to join the prefault thread");
+            }
+        }
+        info!("Waited {:?} for the RAM to be prefaulted", start.elapsed());
+    }
+
+    /// The advice KSM got about each RAM region, when the configuration
+    /// asked for merging or not.
+    pub fn mergeable_regions(&self) -> Vec<MergeableRegion> {
+        self.mergeable_regions.clone()
+    }
+
+    /// Maps the firmware so that it ends at 4GiB, covering the reset vector,
+    /// and copies its last 128KiB to the legacy BIOS area below 1MiB, where
+    /// a BIOS expects to be able to jump to in real mode.
+    #[cfg(target_arch = "x86_64")]
+    pub fn load_firmware(&mut self, firmware: &mut File) -> Result<(), Error> {
+        let size = firmware.metadata().map_err(Error::FirmwareFile)?.len();
+        if size == 0 || size > arch::layout::FIRMWARE_MAX_SIZE || size % 0x1000 != 0 {
+            return Err(Error::InvalidFirmwareSize(size));
+        }
+
+        // The firmware area ends at 4GiB.
+        let start_addr =
+            arch::layout::FIRMWARE_START.unchecked_add(arch::layout::FIRMWARE_MAX_SIZE - size);
+        let region = MemoryManager::create_ram_region(&None, start_addr, size as usize)?;
+        region
+            .read_exact_from(MemoryRegionAddress(0), firmware, size as usize)
+            .map_err(Error::LoadFirmware)?;
+
+        let bios_size = std::cmp::min(size, arch::layout::LEGACY_BIOS_SIZE);
+        let mut bios = vec![0u8; bios_size as usize];
+        region
+            .read_slice(&mut bios, MemoryRegionAddress(size - bios_size))
+            .map_err(Error::LoadFirmware)?;
+        self.guest_memory
+            .load()
+            .write_slice(
+                &bios,
+                arch::layout::LEGACY_BIOS_START
+                    .unchecked_add(arch::layout::LEGACY_BIOS_SIZE - bios_size),
+            )
+            .map_err(Error::LoadFirmware)?;
+
+        self.create_userspace_mapping(start_addr.raw_value(), size, region.as_ptr() as u64)?;
+        self.firmware_region = Some(region);
+
+        Ok(())
+    }
+
+    /// Returns the RAM region added to the guest, if any.
+    pub fn resize(&mut self, desired_ram: u64) -> Result<Option<Arc<GuestRegionMmap>>, Error> {
         if desired_ram > self.current_ram {
-            self.hotplug_ram_region((desired_ram - self.current_ram) as usize)?;
+            let region = self.hotplug_ram_region((desired_ram - self.current_ram) as usize)?;
             self.current_ram = desired_ram;
-            Ok(true)
+            Ok(Some(region))
         } else {
-            Ok(false)
+            Ok(None)
+        }
+    }
+
+    fn memory_ranges(&self) -> Vec<MemoryRange> {
+        let mut ranges = Vec::new();
+        let _: result::Result<(), ()> = self.guest_memory.load().with_regions_mut(|_, region| {
+            ranges.push(MemoryRange {
+                gpa: region.start_addr().raw_value(),
+                length: region.len(),
+            });
+            Ok(())
+        });
+        ranges
+    }
+
+    /// Writes the whole guest RAM to `file`, one region after the other.
+    pub fn dump(&self, file: &File) -> Result<(), Error> {
+        self.guest_memory
+            .load()
+            .with_regions(|_, region| {
+                let mut file = file;
+                region.write_all_to(MemoryRegionAddress(0), &mut file, region.len() as usize)
+            })
+            .map_err(Error::DumpMemory)
+    }
+
+    /// Fills the guest RAM from a file written by `dump()`. The memory layout
+    /// must have been checked against the snapshot by `restore()` first.
+    pub fn load(&self, file: &File) -> Result<(), Error> {
+        self.guest_memory
+            .load()
+            .with_regions(|_, region| {
+                let mut file = file;
+                region.read_exact_from(MemoryRegionAddress(0), &mut file, region.len() as usize)
+            })
+            .map_err(Error::LoadMemory)
+    }
+}
+
+impl Drop for MemoryManager {
+    fn drop(&mut self) {
+        self.prefault_stop.store(true, Ordering::Relaxed);
+        for thread in self.prefault_threads.drain(..) {
+            if thread.join().is_err() {
+                error!("Failed to join the prefault thread");
+            }
         }
     }
 }
 
+// Returns where the `len` bytes of guest memory at `addr` are mapped in the
+// VMM. The pointer is only computed here, dereferencing it being up to the
+// caller, while the region holding it is still mapped.
+fn translate_range(
+    guest_memory: &GuestMemoryMmap,
+    addr: GuestAddress,
+    len: usize,
+) -> Result<*const u8, Error> {
+    if len == 0 {
+        return Err(Error::AddressNotBacked(addr, len));
+    }
+    vm_device::get_host_address_range(guest_memory, addr, len)
+        .map(|ptr| ptr as *const u8)
+        .ok_or(Error::AddressNotBacked(addr, len))
+}
+
+/// Returns the first and last addresses of the device area, where the 64-bit
+/// PCI BARs and the device memory live: from above the boot RAM regions and
+/// the hotpluggable RAM, up to the end of the guest physical address space.
+pub fn device_area(
+    ram_regions: &[(GuestAddress, usize)],
+    hotplug_size: Option<u64>,
+    mmio_hole: MmioHole,
+) -> (GuestAddress, GuestAddress) {
+    let mem_end = ram_regions
+        .iter()
+        .filter(|r| r.1 != 0)
+        .map(|r| r.0.unchecked_add(r.1 as u64 - 1))
+        .max()
+        .unwrap_or(arch::layout::RAM_64BIT_START);
+    let mut start = if mem_end < mmio_hole.start {
+        arch::layout::RAM_64BIT_START
+    } else {
+        mem_end.unchecked_add(1)
+    };
+
+    if let Some(size) = hotplug_size {
+        start = start.unchecked_add(size);
+    }
+
+    (start, GuestAddress((1 << get_host_cpu_phys_bits()) - 1))
+}
+
+/// Returns the boot RAM regions, sorted by address: the ones from the memory
+/// configuration if any, or else the ones laid out by `arch_memory_regions()`
+/// for the boot RAM size. RAM hot-added before a snapshot is left out, to be
+/// added back at the same addresses when restoring the memory manager.
+pub fn boot_ram_regions(memory_config: &MemoryConfig) -> Vec<(GuestAddress, usize)> {
+    match &memory_config.regions {
+        Some(regions) => {
+            let mut ram_regions: Vec<(GuestAddress, usize)> = regions
+                .iter()
+                .map(|r| (GuestAddress(r.gpa), r.size as usize))
+                .collect();
+            ram_regions.sort_by_key(|r| r.0);
+            ram_regions
+        }
+        None => {
+            let boot_ram = memory_config
+                .size
+                .saturating_sub(memory_config.hotplugged_size.unwrap_or(0));
+            arch_ram_regions(boot_ram, memory_config.mmio_hole())
+        }
+    }
+}
+
+fn arch_ram_regions(boot_ram: u64, mmio_hole: MmioHole) -> Vec<(GuestAddress, usize)> {
+    arch::arch_memory_regions(boot_ram, mmio_hole)
+        .iter()
+        .filter(|r| r.2 == RegionType::Ram)
+        .map(|r| (r.0, r.1))
+        .collect()
+}
+
+fn sorted_nodes(numa_nodes: &[NumaConfig]) -> Vec<&NumaConfig> {
+    let mut nodes: Vec<&NumaConfig> = numa_nodes.iter().collect();
+    nodes.sort_by_key(|n| n.id);
+    nodes
+}
+
+/// Checks the memory regions cover exactly the RAM regions, each memory
+/// region lying within a RAM region.
+fn check_ram_regions(
+    ram_regions: &[(GuestAddress, usize)],
+    mem_regions: &[Arc<GuestRegionMmap>],
+) -> Result<(), Error> {
+    for region in mem_regions.iter() {
+        let start = region.start_addr().raw_value();
+        let end = start.checked_add(region.len());
+        let contained = ram_regions.iter().any(|(ram_start, ram_size)| {
+            start >= ram_start.raw_value()
+                && end.map_or(false, |end| end <= ram_start.raw_value() + *ram_size as u64)
+        });
+        if !contained {
+            return Err(Error::InvalidGuestMemory(start, region.len()));
+        }
+    }
+
+    // The regions of a GuestMemoryMmap don't overlap, which leaves no gap
+    // once they add up to the size of the RAM.
+    let ram_size: u64 = ram_regions.iter().map(|r| r.1 as u64).sum();
+    let mem_size: u64 = mem_regions.iter().map(|r| r.len()).sum();
+    if mem_size != ram_size {
+        return Err(Error::GuestMemorySize(mem_size, ram_size));
+    }
+
+    Ok(())
+}
+
+/// Splits the RAM regions into consecutive chunks of `node_sizes` bytes,
+/// returning the index of the node each resulting region belongs to. The RAM
+/// beyond the sum of the node sizes, hot-added before a reboot, goes to the
+/// first node.
+fn split_ram_regions(
+    ram_regions: &[(GuestAddress, usize)],
+    node_sizes: &[u64],
+) -> Vec<(usize, GuestAddress, usize)> {
+    let mut regions = Vec::new();
+    let mut ram = ram_regions.iter().map(|r| (r.0, r.1 as u64));
+    let mut current = ram.next();
+
+    for (index, node_size) in node_sizes.iter().enumerate() {
+        let mut remaining = *node_size;
+        while remaining > 0 {
+            let (start, size) = match current {
+                Some(region) => region,
+                None => return regions,
+            };
+            let chunk = std::cmp::min(remaining, size);
+            regions.push((index, start, chunk as usize));
+            remaining -= chunk;
+            current = if chunk < size {
+                Some((start.unchecked_add(chunk), size - chunk))
+            } else {
+                ram.next()
+            };
+        }
+    }
+
+    for (start, size) in current.into_iter().chain(ram) {
+        regions.push((0, start, size as usize));
+    }
+
+    regions
+}
+
+// Binds the memory of a region to `host_node`, the pages being allocated
+// from that node when the guest first touches them.
+fn mbind_region(region: &GuestRegionMmap, host_node: u32) -> Result<(), Error> {
+    const MPOL_BIND: libc::c_long = 2;
+
+    let mut nodemask = vec![0u64; host_node as usize / 64 + 1];
+    nodemask[host_node as usize / 64] |= 1 << (host_node % 64);
+    // The kernel ignores the last bit of the mask.
+    let maxnode = nodemask.len() as u64 * 64 + 1;
+
+    // Safe because the region is a valid mapping and the node mask outlives
+    // the syscall.
+    let ret = unsafe {
+        libc::syscall(
+            libc::SYS_mbind,
+            region.as_ptr() as u64,
+            region.len() as u64,
+            MPOL_BIND,
+            nodemask.as_ptr(),
+            maxnode,
+            0,
+        )
+    };
+    if ret != 0 {
+        return Err(Error::Mbind(io::Error::last_os_error()));
+    }
+
+    Ok(())
+}
+
+fn madvise(addr: u64, len: u64, advice: libc::c_int) -> io::Result<()> {
+    // Safe because the callers only give the address and size of a mapping
+    // that succeeded.
+    let ret = unsafe { libc::madvise(addr as *mut libc::c_void, len as libc::size_t, advice) };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+// Marks the pages of the regions, as (guest address, host address, size)
+// tuples, mergeable or unmergeable through `madvise`.
+fn advise_mergeable<F>(
+    regions: &[(u64, u64, u64)],
+    mergeable: bool,
+    mut madvise: F,
+) -> Vec<MergeableRegion>
+where
+    F: FnMut(u64, u64, libc::c_int) -> io::Result<()>,
+{
+    let (advice, name) = if mergeable {
+        (libc::MADV_MERGEABLE, "mergeable")
+    } else {
+        (libc::MADV_UNMERGEABLE, "unmergeable")
+    };
+
+    regions
+        .iter()
+        .map(|&(gpa, host_addr, size)| {
+            let advised = match madvise(host_addr, size, advice) {
+                Ok(()) => true,
+                Err(e) => {
+                    if e.raw_os_error() == Some(libc::EINVAL) {
+                        warn!("kernel not configured with CONFIG_KSM");
+                    } else {
+                        warn!("madvise error: {}", e);
+                    }
+                    warn!("failed to mark pages at 0x{:x} as {}", gpa, name);
+                    false
+                }
+            };
+            MergeableRegion {
+                gpa,
+                size,
+                mergeable,
+                advised,
+            }
+        })
+        .collect()
+}
+
+// Asks for transparent huge pages to back the regions, as (guest address,
+// host address, size) tuples, or for none through `madvise`. The kernel not
+// taking the advice only costs performance.
+fn advise_hugepages<F>(regions: &[(u64, u64, u64)], hugepages: bool, mut madvise: F)
+where
+    F: FnMut(u64, u64, libc::c_int) -> io::Result<()>,
+{
+    let advice = if hugepages {
+        libc::MADV_HUGEPAGE
+    } else {
+        libc::MADV_NOHUGEPAGE
+    };
+
+    for &(gpa, host_addr, size) in regions {
+        if let Err(e) = madvise(host_addr, size, advice) {
+            if e.raw_os_error() == Some(libc::EINVAL) {
+                // Also returned for the RAM backed by a file not on tmpfs.
+                warn!("kernel not configured with CONFIG_TRANSPARENT_HUGEPAGE");
+            } else {
+                warn!("madvise error: {}", e);
+            }
+            warn!(
+                "failed to {} transparent huge pages at 0x{:x}",
+                if hugepages { "enable" } else { "disable" },
+                gpa
+            );
+        }
+    }
+}
+
+fn page_size() -> usize {
+    // Safe because sysconf() has no side effect.
+    match unsafe { libc::sysconf(libc::_SC_PAGESIZE) } {
+        size if size > 0 => size as usize,
+        _ => 4096,
+    }
+}
+
+// Touches each page of `len` bytes at `addr`, for the kernel to allocate
+// them now rather than on the first access of the guest. A byte is written
+// back as read, which faults the page in for writing without changing the
+// content of a file backing the RAM. Returns false if `stop` got set before
+// all the pages were touched.
+//
+// The guest may be running already, hence the byte is only written back
+// through a compare-and-exchange, which doesn't undo a write of the guest.
+fn prefault(addr: *mut u8, len: usize, page_size: usize, stop: &AtomicBool) -> bool {
+    for offset in (0..len).step_by(page_size) {
+        if stop.load(Ordering::Relaxed) {
+            return false;
+        }
+        // Safe because the offset is within the mapping, and the byte is
+        // only accessed atomically.
+        let byte = unsafe { &*(addr.add(offset) as *const AtomicU8) };
+        let value = byte.load(Ordering::Relaxed);
+        let _ = byte.compare_exchange(value, value, Ordering::Relaxed, Ordering::Relaxed);
+    }
+    true
+}
+
+/// A guest RAM region, as saved in a snapshot.
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+struct MemoryRange {
+    gpa: u64,
+    length: u64,
+}
+
+const MEMORY_MANAGER_SNAPSHOT_ID: &str = "memory-manager";
+const MEMORY_RANGES_SNAPSHOT_ID: &str = "memory-ranges";
+
+impl Snapshotable for MemoryManager {
+    fn id(&self) -> String {
+        MEMORY_MANAGER_SNAPSHOT_ID.to_string()
+    }
+
+    // The RAM content is too large for the snapshot, which only describes
+    // the layout of the file written by `dump()`.
+    fn snapshot(&self) -> result::Result<Snapshot, MigratableError> {
+        let ranges = serde_json::to_vec(&self.memory_ranges())
+            .map_err(|e| MigratableError::Snapshot(e.into()))?;
+
+        let mut snapshot = Snapshot::new(&self.id());
+        snapshot.add_data_section(SnapshotDataSection {
+            id: MEMORY_RANGES_SNAPSHOT_ID.to_string(),
+            snapshot: ranges,
+        });
+
+        Ok(snapshot)
+    }
+
+    fn restore(&mut self, snapshot: Snapshot) -> result::Result<(), MigratableError> {
+        let section = snapshot
+            .snapshot_data
+            .get(MEMORY_RANGES_SNAPSHOT_ID)
+            .ok_or_else(|| MigratableError::Restore(anyhow!("Missing memory ranges")))?;
+        let ranges: Vec<MemoryRange> = serde_json::from_slice(&section.snapshot)
+            .map_err(|e| MigratableError::Restore(e.into()))?;
+
+        // Hot-added RAM follows the boot RAM. Adding it back in the same
+        // order places each region at the address it had.
+        let current = self.memory_ranges();
+        if ranges.len() > current.len() && ranges.starts_with(&current) {
+            for range in ranges[current.len()..].iter() {
+                self.hotplug_ram_region(range.length as usize)
+                    .map_err(|e| {
+                        MigratableError::Restore(anyhow!("Could not add back RAM: {:?}", e))
+                    })?;
+                // The guest already knows about this memory.
+                self.hotplug_slots[self.next_hotplug_slot - 1].inserting = false;
+                self.current_ram += range.length;
+            }
+        }
+
+        if ranges != self.memory_ranges() {
+            return Err(MigratableError::Restore(anyhow!(
+                "Memory layout differs from the snapshot: {:?}",
+                ranges
+            )));
+        }
+
+        Ok(())
+    }
+}
+
 #[cfg(feature = "acpi")]
 struct MemoryNotify {
     slot_id: usize,
@@ -794,3 +1607,307 @@ impl Aml for MemoryManager {
         bytes
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_advise_mergeable() {
+        let regions = [
+            (0, 0x7f00_0000_0000, 1 << 30),
+            (1 << 32, 0x7f80_0000_0000, 1 << 20),
+        ];
+
+        let mut calls = Vec::new();
+        let advised = advise_mergeable(&regions, true, |addr, len, advice| {
+            calls.push((addr, len, advice));
+            Ok(())
+        });
+        assert_eq!(
+            calls,
+            vec![
+                (0x7f00_0000_0000, 1 << 30, libc::MADV_MERGEABLE),
+                (0x7f80_0000_0000, 1 << 20, libc::MADV_MERGEABLE),
+            ]
+        );
+        assert!(advised.iter().all(|r| r.mergeable && r.advised));
+        assert_eq!(advised[1].gpa, 1 << 32);
+        assert_eq!(advised[1].size, 1 << 20);
+
+        // A failure is recorded for the region, without stopping the advice
+        // for the other ones.
+        let mut calls = Vec::new();
+        let advised = advise_mergeable(&regions, false, |addr, _, advice| {
+            calls.push(advice);
+            if addr == 0x7f00_0000_0000 {
+                Err(io::Error::from_raw_os_error(libc::EINVAL))
+            } else {
+                Ok(())
+            }
+        });
+        assert_eq!(calls, vec![libc::MADV_UNMERGEABLE; 2]);
+        assert_eq!(
+            advised.iter().map(|r| r.advised).collect::<Vec<bool>>(),
+            vec![false, true]
+        );
+        assert!(advised.iter().all(|r| !r.mergeable));
+    }
+
+    #[test]
+    fn test_advise_hugepages() {
+        let regions = [(0, 0x7f00_0000_0000, 1 << 30)];
+        let mut calls = Vec::new();
+        advise_hugepages(&regions, true, |addr, len, advice| {
+            calls.push((addr, len, advice));
+            Err(io::Error::from_raw_os_error(libc::EINVAL))
+        });
+        advise_hugepages(&regions, false, |addr, len, advice| {
+            calls.push((addr, len, advice));
+            Ok(())
+        });
+        assert_eq!(
+            calls,
+            vec![
+                (0x7f00_0000_0000, 1 << 30, libc::MADV_HUGEPAGE),
+                (0x7f00_0000_0000, 1 << 30, libc::MADV_NOHUGEPAGE),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_translate_range() {
+        let guest_memory = GuestMemoryMmap::from_ranges(&[
+            (GuestAddress(0), 0x1000),
+            (GuestAddress(0x2000), 0x1000),
+        ])
+        .unwrap();
+        let region = guest_memory.find_region(GuestAddress(0x2000)).unwrap();
+
+        let ptr = translate_range(&guest_memory, GuestAddress(0x2800), 0x800).unwrap();
+        assert_eq!(ptr, region.as_ptr().wrapping_add(0x800) as *const u8);
+
+        // Not backed, or crossing the end of a region.
+        assert!(translate_range(&guest_memory, GuestAddress(0x1000), 1).is_err());
+        assert!(translate_range(&guest_memory, GuestAddress(0x2800), 0x801).is_err());
+        assert!(translate_range(&guest_memory, GuestAddress(0x800), 0x2000).is_err());
+        assert!(translate_range(&guest_memory, GuestAddress(0x3000), 1).is_err());
+        assert!(translate_range(&guest_memory, GuestAddress(0), 0).is_err());
+    }
+
+    #[test]
+    fn test_prefault() {
+        let page_size = page_size();
+        let region = MmapRegion::new(4 * page_size).unwrap();
+        // Safe because the offset is within the mapping.
+        unsafe { *region.as_ptr().add(page_size) = 0x5a };
+
+        assert!(prefault(
+            region.as_ptr(),
+            region.size(),
+            page_size,
+            &AtomicBool::new(false)
+        ));
+
+        let mut resident = vec![0u8; 4];
+        // Safe because the vector has an entry for each page of the mapping.
+        let ret = unsafe {
+            libc::mincore(
+                region.as_ptr() as *mut libc::c_void,
+                region.size(),
+                resident.as_mut_ptr(),
+            )
+        };
+        assert_eq!(ret, 0);
+        assert!(resident.iter().all(|r| r & 1 == 1));
+        // Safe because the offsets are within the mapping.
+        unsafe {
+            assert_eq!(*region.as_ptr().add(page_size), 0x5a);
+            assert_eq!(*region.as_ptr().add(2 * page_size), 0);
+        }
+        assert!(!prefault(
+            region.as_ptr(),
+            region.size(),
+            page_size,
+            &AtomicBool::new(true)
+        ));
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn test_device_area() {
+        // RAM below the 32-bit memory hole.
+        let mmio_hole = MmioHole::default();
+        let (start, end) = device_area(&arch_ram_regions(1 << 30, mmio_hole), None, mmio_hole);
+        assert_eq!(start, GuestAddress(0x1_0000_0000));
+        assert_eq!(end.raw_value() + 1, 1 << get_host_cpu_phys_bits());
+
+        // RAM above 4 GiB, followed by the hotpluggable RAM.
+        let (start, _) = device_area(
+            &arch_ram_regions(4 << 30, mmio_hole),
+            Some(1 << 30),
+            mmio_hole,
+        );
+        assert_eq!(start, GuestAddress(0x1_8000_0000));
+
+        // Less RAM below 4 GiB, as the 32-bit memory hole was moved down.
+        let mmio_hole = MmioHole {
+            start: GuestAddress(1 << 30),
+            size: 1 << 30,
+        };
+        let (start, _) = device_area(&arch_ram_regions(2 << 30, mmio_hole), None, mmio_hole);
+        assert_eq!(start, GuestAddress(0x1_4000_0000));
+
+        // Explicit RAM regions, the device area starting above the highest.
+        let ram_regions = vec![
+            (GuestAddress(0x1_0000_0000), 1 << 30),
+            (GuestAddress(0), 1 << 30),
+        ];
+        let (start, _) = device_area(&ram_regions, None, MmioHole::default());
+        assert_eq!(start, GuestAddress(0x1_4000_0000));
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn test_boot_ram_regions() {
+        use crate::config::MemoryRegionConfig;
+
+        let mut memory_config = MemoryConfig {
+            size: 3 << 30,
+            ..Default::default()
+        };
+        assert_eq!(
+            boot_ram_regions(&memory_config),
+            vec![(GuestAddress(0), 3 << 30)]
+        );
+
+        // The explicit regions are sorted, and nothing is added in between.
+        memory_config.regions = Some(vec![
+            MemoryRegionConfig {
+                gpa: 0x1_0000_0000,
+                size: 2 << 30,
+            },
+            MemoryRegionConfig {
+                gpa: 0,
+                size: 1 << 30,
+            },
+        ]);
+        assert_eq!(
+            boot_ram_regions(&memory_config),
+            vec![
+                (GuestAddress(0), 1 << 30),
+                (GuestAddress(0x1_0000_0000), 2 << 30)
+            ]
+        );
+
+        // The RAM hot-added before a reboot is kept at the same address.
+        memory_config.size = 4 << 30;
+        memory_config.hotplugged_size = Some(1 << 30);
+        memory_config.add_hotplugged_to_boot_ram(&[(0x2_0000_0000, 1 << 30)]);
+        assert_eq!(memory_config.hotplugged_size, None);
+        assert_eq!(
+            boot_ram_regions(&memory_config),
+            vec![
+                (GuestAddress(0), 1 << 30),
+                (GuestAddress(0x1_0000_0000), 2 << 30),
+                (GuestAddress(0x2_0000_0000), 1 << 30)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_split_ram_regions() {
+        // A single region shared by two nodes.
+        let ram_regions = vec![(GuestAddress(0), 1 << 30)];
+        assert_eq!(
+            split_ram_regions(&ram_regions, &[512 << 20, 512 << 20]),
+            vec![
+                (0, GuestAddress(0), 512 << 20),
+                (1, GuestAddress(512 << 20), 512 << 20),
+            ]
+        );
+
+        // A node spanning the 32-bit memory hole.
+        let ram_regions = vec![
+            (GuestAddress(0), 0xc000_0000),
+            (GuestAddress(0x1_0000_0000), 0x1_0000_0000),
+        ];
+        assert_eq!(
+            split_ram_regions(&ram_regions, &[2 << 30, 2 << 30, 1 << 30]),
+            vec![
+                (0, GuestAddress(0), 2 << 30),
+                (1, GuestAddress(2 << 30), 1 << 30),
+                (1, GuestAddress(0x1_0000_0000), 1 << 30),
+                (2, GuestAddress(0x1_4000_0000), 1 << 30),
+            ]
+        );
+
+        // RAM hot-added before a reboot, beyond the node sizes.
+        assert_eq!(
+            split_ram_regions(&ram_regions, &[1 << 30, 1 << 30]),
+            vec![
+                (0, GuestAddress(0), 1 << 30),
+                (1, GuestAddress(1 << 30), 1 << 30),
+                (0, GuestAddress(2 << 30), 1 << 30),
+                (0, GuestAddress(0x1_0000_0000), 0x1_0000_0000),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_check_ram_regions() {
+        let region = |start: u64, size: usize| {
+            Arc::new(
+                GuestRegionMmap::new(MmapRegion::new(size).unwrap(), GuestAddress(start)).unwrap(),
+            )
+        };
+        let ram_regions = vec![
+            (GuestAddress(0), 0x10_0000),
+            (GuestAddress(0x20_0000), 0x10_0000),
+        ];
+
+        // The RAM regions, or some smaller regions covering them.
+        assert!(check_ram_regions(
+            &ram_regions,
+            &[region(0, 0x10_0000), region(0x20_0000, 0x10_0000)]
+        )
+        .is_ok());
+        assert!(check_ram_regions(
+            &ram_regions,
+            &[
+                region(0, 0x8_0000),
+                region(0x8_0000, 0x8_0000),
+                region(0x20_0000, 0x10_0000)
+            ]
+        )
+        .is_ok());
+
+        // A region spanning the gap between the RAM regions.
+        match check_ram_regions(&ram_regions, &[region(0x8_0000, 0x10_0000)]) {
+            Err(Error::InvalidGuestMemory(0x8_0000, 0x10_0000)) => {}
+            r => panic!("unexpected result {:?}", r),
+        }
+
+        // Some RAM left without memory.
+        match check_ram_regions(&ram_regions, &[region(0, 0x10_0000)]) {
+            Err(Error::GuestMemorySize(0x10_0000, 0x20_0000)) => {}
+            r => panic!("unexpected result {:?}", r),
+        }
+    }
+
+    #[test]
+    fn test_set_user_memory_region_error() {
+        let e = Error::MemorySlot(MemorySlotError::SetUserMemoryRegion {
+            slot: 3,
+            guest_phys_addr: 0x1_0000_0000,
+            memory_size: 0x4000_0000,
+            error: kvm_ioctls::Error::new(libc::EEXIST),
+        });
+        assert_eq!(
+            e.to_string(),
+            "failed to map the guest memory: \
+             failed to set the user memory region 3 at 0x100000000 of size 0x40000000"
+        );
+        assert!(std::error::Error::source(&e).is_some());
+    }
+}
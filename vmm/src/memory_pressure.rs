@@ -0,0 +1,143 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Reclaims guest memory through the balloon device when the host runs low.
+//!
+//! The memory available on the host, as read from `/proc/meminfo`, is polled
+//! on a timer registered with the VMM epoll context. Each time it falls below
+//! the configured threshold, the guest is asked for one more step of memory.
+//! Once enough memory is available again, the balloon deflates one step at a
+//! time, down to the size the VM was configured with.
+//!
+//! A step is only given back once the host has a whole step above the
+//! threshold to spare, so that the balloon doesn't inflate and deflate in
+//! turns around the threshold.
+
+use std::fs;
+use std::io;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::time::Duration;
+use vmm_sys_util::timerfd::TimerFd;
+
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+const MEMINFO_PATH: &str = "/proc/meminfo";
+
+// Parses the memory available on the host, in bytes, out of the content of
+// /proc/meminfo.
+fn parse_mem_available(meminfo: &str) -> Option<u64> {
+    let line = meminfo
+        .lines()
+        .find(|line| line.starts_with("MemAvailable:"))?;
+    let mut fields = line["MemAvailable:".len()..].split_whitespace();
+    let kib: u64 = fields.next()?.parse().ok()?;
+    match fields.next() {
+        Some("kB") => Some(kib << 10),
+        _ => None,
+    }
+}
+
+/// The memory available on the host, if it can be read.
+pub fn mem_available() -> Option<u64> {
+    parse_mem_available(&fs::read_to_string(MEMINFO_PATH).ok()?)
+}
+
+/// Watches the memory available on the host, deciding how much memory the
+/// balloon should reclaim from the guest.
+pub struct MemoryPressureMonitor {
+    timer: TimerFd,
+    threshold: u64,
+    step: u64,
+    min_target: u64,
+    target: u64,
+}
+
+impl MemoryPressureMonitor {
+    /// Starts polling the host, the guest being asked for more memory than
+    /// `min_target` by `step` bytes each time less than `threshold` bytes are
+    /// available.
+    pub fn new(threshold: u64, step: u64, min_target: u64) -> io::Result<Self> {
+        let mut timer = TimerFd::new()?;
+        timer.reset(POLL_INTERVAL, Some(POLL_INTERVAL))?;
+
+        Ok(MemoryPressureMonitor {
+            timer,
+            threshold,
+            step,
+            min_target,
+            target: min_target,
+        })
+    }
+
+    /// The polling timer.
+    pub fn timer_fd(&self) -> RawFd {
+        self.timer.as_raw_fd()
+    }
+
+    /// Handles a polling timer tick. Returns the memory the balloon should
+    /// now reclaim, if that changed. The balloon never grows past
+    /// `max_target`, the size of the guest RAM.
+    pub fn handle_timer(&mut self, max_target: u64) -> Option<u64> {
+        if let Err(e) = self.timer.wait() {
+            warn!("Cannot read the memory pressure timer: {}", e);
+        }
+        let available = mem_available()?;
+        self.update(available, max_target)
+    }
+
+    fn update(&mut self, available: u64, max_target: u64) -> Option<u64> {
+        let target = if available < self.threshold {
+            self.target.saturating_add(self.step).min(max_target)
+        } else if available.saturating_sub(self.step) >= self.threshold {
+            self.target.saturating_sub(self.step).max(self.min_target)
+        } else {
+            self.target
+        };
+        if target == self.target {
+            return None;
+        }
+
+        self.target = target;
+        Some(target)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_mem_available() {
+        let meminfo = "MemTotal:       16315128 kB\n\
+                       MemFree:         1073252 kB\n\
+                       MemAvailable:    8157564 kB\n\
+                       Buffers:          520196 kB\n";
+        assert_eq!(parse_mem_available(meminfo), Some(8_157_564 << 10));
+        assert_eq!(parse_mem_available("MemTotal: 16315128 kB\n"), None);
+        assert_eq!(parse_mem_available("MemAvailable: 8157564\n"), None);
+    }
+
+    #[test]
+    fn test_memory_pressure() {
+        let mut monitor = MemoryPressureMonitor::new(1 << 30, 256 << 20, 128 << 20).unwrap();
+        let max_target = 640 << 20;
+
+        // Nothing changes while the host has enough memory.
+        assert_eq!(monitor.update(2 << 30, max_target), None);
+
+        // The balloon grows one step at a time, up to the guest RAM.
+        assert_eq!(monitor.update(900 << 20, max_target), Some(384 << 20));
+        assert_eq!(monitor.update(900 << 20, max_target), Some(640 << 20));
+        assert_eq!(monitor.update(900 << 20, max_target), None);
+
+        // Just above the threshold, the balloon keeps its size.
+        assert_eq!(monitor.update(1100 << 20, max_target), None);
+
+        // Then it deflates back to its configured size.
+        assert_eq!(monitor.update(1280 << 20, max_target), Some(384 << 20));
+        assert_eq!(monitor.update(2 << 30, max_target), Some(128 << 20));
+        assert_eq!(monitor.update(2 << 30, max_target), None);
+    }
+}
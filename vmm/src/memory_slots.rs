@@ -0,0 +1,450 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! KVM memory slots, through which all the memory mapped into the guest
+//! physical address space gets registered with KVM: guest RAM, hotplugged
+//! RAM, and memory owned by devices such as the pmem and virtio-fs cache
+//! regions or the BARs of assigned devices.
+//!
+//! Each slot records the guest physical range it maps, the host address
+//! backing it and its flags. Slots can be removed, freeing them for later
+//! mappings, and moved, as KVM can't move a slot in place: it is deleted by
+//! setting its size to 0, then added again at its new address. The number
+//! of slots is bounded by what KVM supports, as reported through
+//! `KVM_CAP_NR_MEMSLOTS`.
+
+use kvm_bindings::kvm_userspace_memory_region;
+use kvm_ioctls::VmFd;
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::{fmt, io, result};
+use vm_device::ExternalMemoryMapping;
+
+#[derive(Debug)]
+pub enum Error {
+    /// Failed to set the user memory region, e.g. because it overlaps
+    /// another one (EEXIST).
+    SetUserMemoryRegion {
+        slot: u32,
+        guest_phys_addr: u64,
+        memory_size: u64,
+        error: kvm_ioctls::Error,
+    },
+
+    /// All the memory slots KVM supports are taken.
+    NoMemorySlot(usize),
+
+    /// The guest physical range overlaps the one of another slot.
+    Overlap {
+        guest_phys_addr: u64,
+        memory_size: u64,
+        slot: u32,
+    },
+
+    /// No memory is mapped in the slot.
+    UnknownSlot(u32),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::SetUserMemoryRegion {
+                slot,
+                guest_phys_addr,
+                memory_size,
+                ..
+            } => write!(
+                f,
+                "failed to set the user memory region {} at {:#x} of size {:#x}",
+                slot, guest_phys_addr, memory_size
+            ),
+            Error::NoMemorySlot(max) => write!(f, "all the {} KVM memory slots are taken", max),
+            Error::Overlap {
+                guest_phys_addr,
+                memory_size,
+                slot,
+            } => write!(
+                f,
+                "memory at {:#x} of size {:#x} overlaps memory slot {}",
+                guest_phys_addr, memory_size, slot
+            ),
+            Error::UnknownSlot(slot) => write!(f, "no memory mapped in slot {}", slot),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::SetUserMemoryRegion { error, .. } => Some(error),
+            _ => None,
+        }
+    }
+}
+
+pub type Result<T> = result::Result<T, Error>;
+
+/// Sets the KVM user memory regions, the VM file descriptor being replaced
+/// in the tests.
+pub trait UserMemoryRegions: Send + Sync {
+    /// Sets the memory region of `region.slot`, deleting it if its size is 0.
+    fn set_user_memory_region(
+        &self,
+        region: kvm_userspace_memory_region,
+    ) -> result::Result<(), kvm_ioctls::Error>;
+}
+
+impl UserMemoryRegions for VmFd {
+    fn set_user_memory_region(
+        &self,
+        region: kvm_userspace_memory_region,
+    ) -> result::Result<(), kvm_ioctls::Error> {
+        // Safe because the host memory of each slot is kept mapped by its
+        // owner for as long as the slot exists, and the slots never overlap.
+        unsafe { VmFd::set_user_memory_region(self, region) }
+    }
+}
+
+/// The memory mapped in a slot.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MemorySlot {
+    pub guest_phys_addr: u64,
+    pub memory_size: u64,
+    pub userspace_addr: u64,
+    pub flags: u32,
+}
+
+impl MemorySlot {
+    fn region(&self, slot: u32) -> kvm_userspace_memory_region {
+        kvm_userspace_memory_region {
+            slot,
+            guest_phys_addr: self.guest_phys_addr,
+            memory_size: self.memory_size,
+            userspace_addr: self.userspace_addr,
+            flags: self.flags,
+        }
+    }
+
+    fn overlaps(&self, guest_phys_addr: u64, memory_size: u64) -> bool {
+        guest_phys_addr < self.guest_phys_addr + self.memory_size
+            && self.guest_phys_addr < guest_phys_addr + memory_size
+    }
+}
+
+/// The KVM memory slots of a VM.
+pub struct MemorySlots {
+    vm: Arc<dyn UserMemoryRegions>,
+    max_slots: usize,
+    slots: Mutex<BTreeMap<u32, MemorySlot>>,
+}
+
+impl MemorySlots {
+    /// Tracks the memory slots of `vm`, up to `max_slots` of them, as
+    /// returned by `Kvm::get_nr_memslots()`.
+    pub fn new(vm: Arc<dyn UserMemoryRegions>, max_slots: usize) -> Self {
+        MemorySlots {
+            vm,
+            max_slots,
+            slots: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    fn set(&self, slot: u32, memory: &MemorySlot) -> Result<()> {
+        self.vm
+            .set_user_memory_region(memory.region(slot))
+            .map_err(|error| Error::SetUserMemoryRegion {
+                slot,
+                guest_phys_addr: memory.guest_phys_addr,
+                memory_size: memory.memory_size,
+                error,
+            })
+    }
+
+    fn delete(&self, slot: u32, memory: &MemorySlot) -> Result<()> {
+        self.set(
+            slot,
+            &MemorySlot {
+                memory_size: 0,
+                ..*memory
+            },
+        )
+    }
+
+    // Checks that the range doesn't overlap any slot other than `slot`.
+    fn check_overlap(
+        slots: &BTreeMap<u32, MemorySlot>,
+        guest_phys_addr: u64,
+        memory_size: u64,
+        slot: Option<u32>,
+    ) -> Result<()> {
+        match slots
+            .iter()
+            .find(|(id, m)| Some(**id) != slot && m.overlaps(guest_phys_addr, memory_size))
+        {
+            Some((id, _)) => Err(Error::Overlap {
+                guest_phys_addr,
+                memory_size,
+                slot: *id,
+            }),
+            None => Ok(()),
+        }
+    }
+
+    /// Maps `memory` into the guest, in the lowest free slot, which is
+    /// returned.
+    pub fn add(&self, memory: MemorySlot) -> Result<u32> {
+        let mut slots = self.slots.lock().unwrap();
+        Self::check_overlap(&slots, memory.guest_phys_addr, memory.memory_size, None)?;
+
+        // The slots are sorted, the first gap being the lowest free slot.
+        let slot = slots
+            .keys()
+            .enumerate()
+            .find(|(index, slot)| *index as u32 != **slot)
+            .map(|(index, _)| index)
+            .unwrap_or_else(|| slots.len());
+        if slot >= self.max_slots {
+            return Err(Error::NoMemorySlot(self.max_slots));
+        }
+        let slot = slot as u32;
+
+        self.set(slot, &memory)?;
+        slots.insert(slot, memory);
+
+        Ok(slot)
+    }
+
+    /// Unmaps the memory mapped in `slot`, freeing it.
+    pub fn remove(&self, slot: u32) -> Result<MemorySlot> {
+        let mut slots = self.slots.lock().unwrap();
+        let memory = *slots.get(&slot).ok_or(Error::UnknownSlot(slot))?;
+
+        self.delete(slot, &memory)?;
+        slots.remove(&slot);
+
+        Ok(memory)
+    }
+
+    /// Moves the memory mapped in `slot` to `guest_phys_addr`, with `flags`.
+    /// The slot is freed if the memory can't be mapped at its new address.
+    pub fn update(&self, slot: u32, guest_phys_addr: u64, flags: u32) -> Result<()> {
+        let mut slots = self.slots.lock().unwrap();
+        let memory = *slots.get(&slot).ok_or(Error::UnknownSlot(slot))?;
+        Self::check_overlap(&slots, guest_phys_addr, memory.memory_size, Some(slot))?;
+
+        let updated = MemorySlot {
+            guest_phys_addr,
+            flags,
+            ..memory
+        };
+        // Only the flags of a slot can be changed in place.
+        if guest_phys_addr != memory.guest_phys_addr {
+            self.delete(slot, &memory)?;
+        }
+        if let Err(e) = self.set(slot, &updated) {
+            if guest_phys_addr != memory.guest_phys_addr {
+                slots.remove(&slot);
+            }
+            return Err(e);
+        }
+        slots.insert(slot, updated);
+
+        Ok(())
+    }
+
+    /// The memory mapped in `slot`, if any.
+    pub fn get(&self, slot: u32) -> Option<MemorySlot> {
+        self.slots.lock().unwrap().get(&slot).copied()
+    }
+
+    /// How many slots are taken.
+    pub fn len(&self) -> usize {
+        self.slots.lock().unwrap().len()
+    }
+
+    /// Whether no slot is taken.
+    pub fn is_empty(&self) -> bool {
+        self.slots.lock().unwrap().is_empty()
+    }
+}
+
+fn to_io_error(e: Error) -> io::Error {
+    match e {
+        Error::SetUserMemoryRegion { error, .. } => io::Error::from_raw_os_error(error.errno()),
+        e => io::Error::new(io::ErrorKind::InvalidInput, e.to_string()),
+    }
+}
+
+impl ExternalMemoryMapping for MemorySlots {
+    fn map(&self, gpa: u64, size: u64, host_addr: u64) -> result::Result<u32, io::Error> {
+        self.add(MemorySlot {
+            guest_phys_addr: gpa,
+            memory_size: size,
+            userspace_addr: host_addr,
+            flags: 0,
+        })
+        .map_err(to_io_error)
+    }
+
+    fn remap(&self, slot: u32, gpa: u64) -> result::Result<(), io::Error> {
+        let flags = self
+            .get(slot)
+            .ok_or_else(|| to_io_error(Error::UnknownSlot(slot)))?
+            .flags;
+        self.update(slot, gpa, flags).map_err(to_io_error)
+    }
+
+    fn unmap(&self, slot: u32) -> result::Result<(), io::Error> {
+        self.remove(slot).map(|_| ()).map_err(to_io_error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Records the regions set, failing with EEXIST when asked to.
+    #[derive(Default)]
+    struct MockVm {
+        regions: Mutex<Vec<kvm_userspace_memory_region>>,
+        fail: Mutex<bool>,
+    }
+
+    impl UserMemoryRegions for MockVm {
+        fn set_user_memory_region(
+            &self,
+            region: kvm_userspace_memory_region,
+        ) -> result::Result<(), kvm_ioctls::Error> {
+            if *self.fail.lock().unwrap() {
+                return Err(kvm_ioctls::Error::new(libc::EEXIST));
+            }
+            self.regions.lock().unwrap().push(region);
+            Ok(())
+        }
+    }
+
+    impl MockVm {
+        fn calls(&self) -> Vec<(u32, u64, u64)> {
+            self.regions
+                .lock()
+                .unwrap()
+                .drain(..)
+                .map(|r| (r.slot, r.guest_phys_addr, r.memory_size))
+                .collect()
+        }
+    }
+
+    fn memory(guest_phys_addr: u64, memory_size: u64) -> MemorySlot {
+        MemorySlot {
+            guest_phys_addr,
+            memory_size,
+            userspace_addr: 0x7f00_0000_0000 + guest_phys_addr,
+            flags: 0,
+        }
+    }
+
+    #[test]
+    fn test_add_remove_readd() {
+        let vm = Arc::new(MockVm::default());
+        let slots = MemorySlots::new(vm.clone(), 3);
+
+        assert_eq!(slots.add(memory(0, 0x1000)).unwrap(), 0);
+        assert_eq!(slots.add(memory(0x1000, 0x1000)).unwrap(), 1);
+        assert_eq!(vm.calls(), vec![(0, 0, 0x1000), (1, 0x1000, 0x1000)]);
+
+        // KVM deletes a slot given a size of 0.
+        assert_eq!(slots.remove(0).unwrap(), memory(0, 0x1000));
+        assert_eq!(vm.calls(), vec![(0, 0, 0)]);
+        assert!(slots.get(0).is_none());
+        assert_eq!(slots.len(), 1);
+
+        // The freed slot is taken again, the range being free too.
+        assert_eq!(slots.add(memory(0, 0x1000)).unwrap(), 0);
+        assert_eq!(slots.add(memory(0x2000, 0x1000)).unwrap(), 2);
+        assert_eq!(vm.calls(), vec![(0, 0, 0x1000), (2, 0x2000, 0x1000)]);
+
+        match slots.add(memory(0x3000, 0x1000)) {
+            Err(Error::NoMemorySlot(3)) => {}
+            r => panic!("unexpected result {:?}", r),
+        }
+        match slots.remove(3) {
+            Err(Error::UnknownSlot(3)) => {}
+            r => panic!("unexpected result {:?}", r),
+        }
+        assert!(vm.calls().is_empty());
+    }
+
+    #[test]
+    fn test_overlap() {
+        let vm = Arc::new(MockVm::default());
+        let slots = MemorySlots::new(vm.clone(), 8);
+        slots.add(memory(0x1000, 0x2000)).unwrap();
+        vm.calls();
+
+        for (addr, size) in &[(0, 0x1001), (0x2000, 0x100), (0x2fff, 0x1000)] {
+            match slots.add(memory(*addr, *size)) {
+                Err(Error::Overlap { slot: 0, .. }) => {}
+                r => panic!("unexpected result {:?}", r),
+            }
+        }
+        assert_eq!(slots.add(memory(0, 0x1000)).unwrap(), 1);
+        assert_eq!(slots.add(memory(0x3000, 0x1000)).unwrap(), 2);
+        assert_eq!(slots.len(), 3);
+    }
+
+    #[test]
+    fn test_update() {
+        let vm = Arc::new(MockVm::default());
+        let slots = MemorySlots::new(vm.clone(), 8);
+        slots.add(memory(0x1000, 0x1000)).unwrap();
+        slots.add(memory(0x4000, 0x1000)).unwrap();
+        vm.calls();
+
+        // Moving a slot deletes it first, changing its flags doesn't.
+        slots.update(0, 0x8000, 0).unwrap();
+        assert_eq!(vm.calls(), vec![(0, 0x1000, 0), (0, 0x8000, 0x1000)]);
+        slots.update(0, 0x8000, 1).unwrap();
+        assert_eq!(vm.calls(), vec![(0, 0x8000, 0x1000)]);
+        assert_eq!(slots.get(0).unwrap().flags, 1);
+
+        match slots.update(0, 0x4800, 0) {
+            Err(Error::Overlap { slot: 1, .. }) => {}
+            r => panic!("unexpected result {:?}", r),
+        }
+        assert!(vm.calls().is_empty());
+
+        // The old range is free once the slot has moved.
+        assert_eq!(slots.add(memory(0x1000, 0x1000)).unwrap(), 2);
+
+        // A slot failing to be set is left untouched.
+        *vm.fail.lock().unwrap() = true;
+        let e = slots.remove(1).unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "failed to set the user memory region 1 at 0x4000 of size 0x0"
+        );
+        assert!(std::error::Error::source(&e).is_some());
+        assert_eq!(slots.get(1), Some(memory(0x4000, 0x1000)));
+    }
+
+    #[test]
+    fn test_external_memory_mapping() {
+        let vm = Arc::new(MockVm::default());
+        let slots: Arc<dyn ExternalMemoryMapping> = Arc::new(MemorySlots::new(vm.clone(), 1));
+
+        let slot = slots.map(0x1000, 0x1000, 0x7f00_0000_0000).unwrap();
+        slots.remap(slot, 0x2000).unwrap();
+        assert_eq!(
+            vm.calls(),
+            vec![(0, 0x1000, 0x1000), (0, 0x1000, 0), (0, 0x2000, 0x1000)]
+        );
+        assert_eq!(
+            slots.map(0x4000, 0x1000, 0).unwrap_err().kind(),
+            io::ErrorKind::InvalidInput
+        );
+        slots.unmap(slot).unwrap();
+        assert!(slots.unmap(slot).is_err());
+    }
+}
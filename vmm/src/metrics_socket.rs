@@ -0,0 +1,297 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Serves the statistics of a VM as JSON over a Unix socket.
+//!
+//! Each time the client writes to the socket, whatever it writes, it gets a
+//! report back as a single line of JSON: the uptime of the VM, the size of
+//! its RAM, the memory used by the VMM, and every metric of the registry,
+//! e.g. the vCPU exits by type and the I/O performed by each device queue.
+//!
+//! Everything runs from the VMM event loop, the listening socket and the
+//! client connection being registered with the VMM epoll context. A single
+//! client is served at a time: while one is connected, new connections are
+//! closed right away.
+//!
+//! The client connection is non-blocking, and watched for both readability
+//! and writability. What the socket doesn't take right away is sent as it
+//! drains, a client asking for a report while still receiving the previous
+//! one only getting that one.
+
+use std::fs;
+use std::io::{self, Read, Write};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use vm_device::metrics::MetricSample;
+
+/// The statistics of a VM, as served over the socket.
+#[derive(Clone, Debug, Default, PartialEq, Deserialize, Serialize)]
+pub struct MetricsReport {
+    /// Time since the VM was booted, absent until it is.
+    pub uptime_ms: Option<u64>,
+    /// Size of the guest RAM, hotplugged memory included.
+    pub guest_memory_bytes: u64,
+    /// Memory resident for the whole VMM process, guest RAM included.
+    pub rss_bytes: Option<u64>,
+    pub metrics: Vec<MetricSample>,
+}
+
+/// The memory resident for the VMM process, if it can be read.
+pub fn rss_bytes() -> Option<u64> {
+    let statm = fs::read_to_string("/proc/self/statm").ok()?;
+    let pages: u64 = statm.split_whitespace().nth(1)?.parse().ok()?;
+    // Safe because sysconf() has no side effect.
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) };
+    if page_size <= 0 {
+        return None;
+    }
+    Some(pages * page_size as u64)
+}
+
+// Removes the socket a VMM which is gone left at `path`. Anything else, e.g.
+// a regular file or the socket of a running VMM, is left in place.
+fn remove_stale_socket(path: &Path) -> io::Result<()> {
+    let metadata = match fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    if !metadata.file_type().is_socket() {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("{:?} exists and is not a socket", path),
+        ));
+    }
+
+    match UnixStream::connect(path) {
+        Ok(_) => Err(io::Error::new(
+            io::ErrorKind::AddrInUse,
+            format!("{:?} is in use", path),
+        )),
+        Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => fs::remove_file(path),
+        Err(e) => Err(e),
+    }
+}
+
+/// Server answering the requests for the statistics of a VM.
+pub struct MetricsServer {
+    path: PathBuf,
+    listener: UnixListener,
+    client: Option<UnixStream>,
+    // Part of the last report the client hasn't been sent yet.
+    out: Vec<u8>,
+}
+
+impl MetricsServer {
+    /// Listens for clients on `path`, replacing a stale socket left there.
+    pub fn new(path: &Path) -> io::Result<Self> {
+        remove_stale_socket(path)?;
+        let listener = UnixListener::bind(path)?;
+        listener.set_nonblocking(true)?;
+
+        Ok(MetricsServer {
+            path: path.to_path_buf(),
+            listener,
+            client: None,
+            out: Vec::new(),
+        })
+    }
+
+    /// The listening socket, readable when a client connects.
+    pub fn listener_fd(&self) -> RawFd {
+        self.listener.as_raw_fd()
+    }
+
+    /// The connection of the current client, if any.
+    pub fn client_fd(&self) -> Option<RawFd> {
+        self.client.as_ref().map(|client| client.as_raw_fd())
+    }
+
+    /// Accepts a pending connection, and returns its fd if it became the
+    /// current client.
+    pub fn accept(&mut self) -> Option<RawFd> {
+        let stream = match self.listener.accept() {
+            Ok((stream, _)) => {
+                if self.client.is_some() {
+                    warn!("Refusing metrics client, another one is connected");
+                    return None;
+                }
+                stream
+            }
+            Err(e) => {
+                if e.kind() != io::ErrorKind::WouldBlock {
+                    warn!("Cannot accept metrics client: {}", e);
+                }
+                return None;
+            }
+        };
+
+        match stream.set_nonblocking(true) {
+            Ok(()) => {
+                self.client = Some(stream);
+                self.out.clear();
+                self.client_fd()
+            }
+            Err(e) => {
+                warn!("Cannot set the metrics client up: {}", e);
+                None
+            }
+        }
+    }
+
+    /// Answers what the client sent with a report built by `report`, and
+    /// sends what the socket takes of the pending report. Returns false once
+    /// the client has to be disconnected.
+    pub fn handle_client<F>(&mut self, report: F) -> bool
+    where
+        F: FnOnce() -> MetricsReport,
+    {
+        let client = match self.client.as_mut() {
+            Some(client) => client,
+            None => return false,
+        };
+
+        let out = &mut self.out;
+
+        let result = read_requests(client).and_then(|requested| {
+            match requested {
+                None => return Ok(false),
+                Some(true) if out.is_empty() => {
+                    *out = serde_json::to_vec(&report())?;
+                    out.push(b'\n');
+                }
+                Some(_) => {}
+            }
+            write_pending(client, out)?;
+            Ok(true)
+        });
+
+        match result {
+            Ok(connected) => connected,
+            Err(e) => {
+                warn!("Disconnecting metrics client: {}", e);
+                false
+            }
+        }
+    }
+
+    /// Closes the connection of the current client.
+    pub fn disconnect(&mut self) {
+        self.client = None;
+    }
+}
+
+// Consumes what the client sent, the requests themselves not mattering.
+// Returns whether the client sent anything, or None once it closed the
+// connection.
+fn read_requests(client: &mut UnixStream) -> io::Result<Option<bool>> {
+    let mut requested = false;
+    let mut request = [0u8; 256];
+    loop {
+        match client.read(&mut request) {
+            Ok(0) => return Ok(None),
+            Ok(_) => requested = true,
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(Some(requested)),
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+// Sends as much of `out` as the socket takes, without blocking.
+fn write_pending(client: &mut UnixStream, out: &mut Vec<u8>) -> io::Result<()> {
+    let mut sent = 0;
+    while sent < out.len() {
+        match client.write(&out[sent..]) {
+            Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+            Ok(len) => sent += len,
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    out.drain(..sent);
+    Ok(())
+}
+
+impl Drop for MetricsServer {
+    fn drop(&mut self) {
+        fs::remove_file(&self.path).unwrap_or_default();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader};
+    use vm_device::metrics::MetricKind;
+
+    #[test]
+    fn test_metrics_server() {
+        let path = std::env::temp_dir().join(format!("ch-metrics-{}.sock", std::process::id()));
+        let mut server = MetricsServer::new(&path).unwrap();
+
+        let mut stream = UnixStream::connect(&path).unwrap();
+        assert!(server.accept().is_some());
+
+        // Another client is refused while this one is connected.
+        let _other = UnixStream::connect(&path).unwrap();
+        assert!(server.accept().is_none());
+
+        let report = MetricsReport {
+            uptime_ms: Some(1500),
+            guest_memory_bytes: 512 << 20,
+            rss_bytes: rss_bytes(),
+            metrics: vec![MetricSample {
+                name: "vcpu_hlt_exits".to_string(),
+                id: "0".to_string(),
+                kind: MetricKind::Counter,
+                value: 3,
+            }],
+        };
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        for _ in 0..2 {
+            stream.write_all(b"metrics\n").unwrap();
+            assert!(server.handle_client(|| report.clone()));
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            let received: MetricsReport = serde_json::from_str(&line).unwrap();
+            assert_eq!(received, report);
+        }
+
+        drop(reader);
+        drop(stream);
+        assert!(!server.handle_client(MetricsReport::default));
+        server.disconnect();
+        assert!(server.client_fd().is_none());
+
+        drop(server);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_metrics_server_path_in_use() {
+        let path = std::env::temp_dir().join(format!("ch-metrics-{}.file", std::process::id()));
+        fs::write(&path, b"").unwrap();
+        assert!(MetricsServer::new(&path).is_err());
+        assert!(path.exists());
+        fs::remove_file(&path).unwrap();
+
+        let path =
+            std::env::temp_dir().join(format!("ch-metrics-{}-busy.sock", std::process::id()));
+        let server = MetricsServer::new(&path).unwrap();
+        assert!(MetricsServer::new(&path).is_err());
+        drop(server);
+
+        // The socket of a VMM which is gone is replaced.
+        let listener = UnixListener::bind(&path).unwrap();
+        drop(listener);
+        let server = MetricsServer::new(&path).unwrap();
+        drop(server);
+        assert!(!path.exists());
+    }
+}
@@ -0,0 +1,193 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Wall-clock time budget of the guest, for VMs which must not run away.
+//!
+//! The budget is only consumed while the VM runs. A timer registered with
+//! the VMM epoll context is armed with what is left of the budget each time
+//! the VM boots, reboots or resumes, and disarmed when it gets paused or shut
+//! down, so that the time spent paused doesn't count. The budget lasts as
+//! long as the VM config, reboots included.
+
+use std::io;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::time::{Duration, Instant};
+use vmm_sys_util::timerfd::TimerFd;
+
+/// The time left for the guest to run.
+pub struct RuntimeBudget {
+    timer: TimerFd,
+    budget: Duration,
+    // Time consumed until the VM last stopped running.
+    used: Duration,
+    // When the VM started running, if it is.
+    running_since: Option<Instant>,
+}
+
+impl RuntimeBudget {
+    /// Gives the guest `budget` to run for, starting once the VM runs.
+    pub fn new(budget: Duration) -> io::Result<Self> {
+        // The timer is read from the control loop, which may have cleared
+        // or rearmed it while handling the previous events of the batch.
+        // Safe because the return value is checked.
+        let fd = unsafe {
+            libc::timerfd_create(
+                libc::CLOCK_MONOTONIC,
+                libc::TFD_CLOEXEC | libc::TFD_NONBLOCK,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(RuntimeBudget {
+            // Safe because the fd was just created, nothing else owns it.
+            timer: unsafe { TimerFd::from_raw_fd(fd) },
+            budget,
+            used: Duration::from_secs(0),
+            running_since: None,
+        })
+    }
+
+    /// The timer expiring with the budget.
+    pub fn timer_fd(&self) -> RawFd {
+        self.timer.as_raw_fd()
+    }
+
+    /// The time the guest has run for so far.
+    pub fn used(&self) -> Duration {
+        self.used
+            + self
+                .running_since
+                .map_or(Duration::from_secs(0), |ts| ts.elapsed())
+    }
+
+    /// The time the guest may still run for.
+    pub fn remaining(&self) -> Duration {
+        self.budget
+            .checked_sub(self.used())
+            .unwrap_or_else(|| Duration::from_secs(0))
+    }
+
+    /// Starts consuming the budget, the VM running from now on. The timer
+    /// expires right away if the budget is used up already.
+    pub fn start(&mut self) -> io::Result<()> {
+        if self.running_since.is_none() {
+            self.running_since = Some(Instant::now());
+            self.arm()?;
+        }
+        Ok(())
+    }
+
+    /// Stops consuming the budget, the VM not running anymore.
+    pub fn stop(&mut self) -> io::Result<()> {
+        if let Some(ts) = self.running_since.take() {
+            self.used += ts.elapsed();
+            self.timer.clear()?;
+        }
+        Ok(())
+    }
+
+    // A null timeout would disarm the timer instead.
+    fn arm(&mut self) -> io::Result<()> {
+        let timeout = self.remaining().max(Duration::from_nanos(1));
+        self.timer.reset(timeout, None)
+    }
+
+    /// Handles a timer expiry, returning whether the running guest has used
+    /// up its budget.
+    pub fn handle_timer(&mut self) -> bool {
+        let mut expirations = 0u64;
+        // Safe because the buffer is the size of the expiration count the
+        // kernel writes.
+        let ret = unsafe {
+            libc::read(
+                self.timer.as_raw_fd(),
+                &mut expirations as *mut u64 as *mut libc::c_void,
+                std::mem::size_of::<u64>(),
+            )
+        };
+        if ret < 0 {
+            let e = io::Error::last_os_error();
+            // The timer was cleared or rearmed since it expired.
+            if e.kind() == io::ErrorKind::WouldBlock {
+                return false;
+            }
+            warn!("Cannot read the runtime budget timer: {}", e);
+        }
+        if self.running_since.is_none() {
+            return false;
+        }
+        if self.remaining() > Duration::from_secs(0) {
+            // Expired early, waits for the rest.
+            if let Err(e) = self.arm() {
+                warn!("Cannot rearm the runtime budget timer: {}", e);
+            }
+            return false;
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    // Waits for the timer to expire, as the VMM epoll context would.
+    fn wait_expiry(budget: &RuntimeBudget) {
+        let mut pollfd = libc::pollfd {
+            fd: budget.timer_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        // Safe because the pollfd structure outlives the call.
+        assert_eq!(unsafe { libc::poll(&mut pollfd, 1, 10_000) }, 1);
+    }
+
+    #[test]
+    fn test_runtime_budget() {
+        let mut budget = RuntimeBudget::new(Duration::from_millis(100)).unwrap();
+        assert!(!budget.timer.is_armed().unwrap());
+
+        // The time spent stopped doesn't count.
+        budget.start().unwrap();
+        thread::sleep(Duration::from_millis(40));
+        budget.stop().unwrap();
+        assert!(!budget.timer.is_armed().unwrap());
+        let used = budget.used();
+        assert!(used >= Duration::from_millis(40));
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(budget.used(), used);
+
+        // The timer expires with what is left of the budget.
+        let start = Instant::now();
+        budget.start().unwrap();
+        budget.start().unwrap();
+        assert!(budget.timer.is_armed().unwrap());
+        wait_expiry(&budget);
+        assert!(budget.handle_timer());
+        assert!(start.elapsed() >= Duration::from_millis(100) - used);
+        assert_eq!(budget.remaining(), Duration::from_secs(0));
+
+        // Once used up, the budget expires as soon as the VM runs again.
+        budget.stop().unwrap();
+        budget.start().unwrap();
+        wait_expiry(&budget);
+        assert!(budget.handle_timer());
+    }
+
+    #[test]
+    fn test_runtime_budget_cleared() {
+        let mut budget = RuntimeBudget::new(Duration::from_millis(10)).unwrap();
+        budget.start().unwrap();
+        wait_expiry(&budget);
+
+        // The VM got paused in the same batch of events, before the expiry
+        // was handled.
+        budget.stop().unwrap();
+        assert!(!budget.handle_timer());
+    }
+}
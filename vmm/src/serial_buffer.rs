@@ -0,0 +1,336 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Output buffer of the serial port.
+//!
+//! The guest output is queued in a bounded buffer, the vCPU writing to the
+//! port only holding its lock for as long as it takes to copy the bytes. The
+//! VMM control loop is notified through an event and writes the buffer to its
+//! destination without blocking, then carries on once epoll reports the
+//! destination as writable again. When the buffer is full, the oldest output
+//! is dropped, and counted by the `serial_output_dropped_bytes` metric.
+//!
+//! The serial port reports its transmitter as busy while the buffer is nearly
+//! full, so that a guest polling it waits for room instead of losing output.
+
+use devices::legacy::SerialOutput;
+use std::collections::VecDeque;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::sync::{Arc, Mutex};
+use vm_device::metrics::{self, Metric};
+use vmm_sys_util::eventfd::EventFd;
+
+// Output handed to the destination at once, which bounds the time the
+// buffer lock is held for by the control loop.
+const FLUSH_CHUNK_SIZE: usize = 4096;
+
+struct Ring {
+    buffer: VecDeque<u8>,
+    capacity: usize,
+    // Bytes removed from the front of the buffer so far, either written or
+    // dropped, locating the buffer in the whole output.
+    removed: u64,
+    // Bytes dropped so far.
+    dropped: u64,
+    // The same count, exported as a metric, which carries on across the
+    // buffers of the successive boots of the VM.
+    dropped_metric: Arc<Metric>,
+    // Set once the loss of some output has been reported, until the buffer
+    // is flushed.
+    overflowed: bool,
+}
+
+impl Ring {
+    // Drops `count` bytes from the front of the buffer.
+    fn drop_front(&mut self, count: usize) {
+        self.buffer.drain(..count);
+        self.removed += count as u64;
+        self.dropped += count as u64;
+        self.dropped_metric.add(count as u64);
+    }
+
+    // Removes the bytes written up to `end`, relative to the whole output.
+    // Some of them may have been dropped in the meantime.
+    fn consume(&mut self, end: u64) {
+        if end > self.removed {
+            let count = ((end - self.removed) as usize).min(self.buffer.len());
+            self.buffer.drain(..count);
+            self.removed += count as u64;
+        }
+    }
+}
+
+struct Output {
+    out: File,
+    // Set once a write error has been reported, until a write succeeds.
+    failed: bool,
+}
+
+impl Output {
+    // Writes as much of `data` as the destination takes without blocking,
+    // returning how much was written.
+    fn write_out(&mut self, data: &[u8]) -> io::Result<usize> {
+        let mut written = 0;
+        while written < data.len() {
+            match self.out.write(&data[written..]) {
+                Ok(count) => {
+                    written += count;
+                    self.failed = false;
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(written)
+    }
+}
+
+/// Serial port output, buffered until the control loop writes it to its
+/// destination.
+#[derive(Clone)]
+pub struct SerialBuffer {
+    fd: RawFd,
+    ring: Arc<Mutex<Ring>>,
+    output: Arc<Mutex<Output>>,
+    queued_evt: Arc<EventFd>,
+}
+
+impl SerialBuffer {
+    /// Writes to `out`, switched to non-blocking mode, keeping up to
+    /// `capacity` bytes of output while it is busy. The buffer holds at
+    /// least one chunk of output, whatever `capacity` is.
+    ///
+    /// As the non-blocking mode is shared by all the file descriptors
+    /// referring to the same open file, `out` shouldn't be used by anything
+    /// else.
+    pub fn new(out: File, capacity: usize) -> io::Result<Self> {
+        let fd = out.as_raw_fd();
+        // Safe because the fd is valid and the return value is checked.
+        let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+        if flags < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // Safe because the fd is valid and the return value is checked.
+        if unsafe { libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(SerialBuffer {
+            fd,
+            ring: Arc::new(Mutex::new(Ring {
+                buffer: VecDeque::new(),
+                capacity: capacity.max(FLUSH_CHUNK_SIZE),
+                removed: 0,
+                dropped: 0,
+                dropped_metric: metrics::registry()
+                    .counter("serial_output_dropped_bytes", "serial"),
+                overflowed: false,
+            })),
+            output: Arc::new(Mutex::new(Output { out, failed: false })),
+            queued_evt: Arc::new(EventFd::new(libc::EFD_NONBLOCK)?),
+        })
+    }
+
+    /// The destination, to be watched for writability.
+    pub fn out_fd(&self) -> RawFd {
+        self.fd
+    }
+
+    /// Signaled when output gets queued in an empty buffer.
+    pub fn queued_fd(&self) -> RawFd {
+        self.queued_evt.as_raw_fd()
+    }
+
+    /// How many bytes of output were dropped so far.
+    pub fn dropped(&self) -> u64 {
+        self.ring.lock().unwrap().dropped
+    }
+
+    /// Writes the buffered output the destination takes without blocking.
+    /// Called from the control loop, when the destination is writable or
+    /// output got queued.
+    pub fn flush_buffer(&self) {
+        // Consumes the queuing notification, the whole buffer being written.
+        let _ = self.queued_evt.read();
+
+        let mut output = self.output.lock().unwrap();
+        loop {
+            // The destination is written without holding the buffer lock,
+            // the vCPU may queue or drop output in the meantime.
+            let (start, chunk) = {
+                let ring = self.ring.lock().unwrap();
+                if ring.buffer.is_empty() {
+                    break;
+                }
+                let (data, _) = ring.buffer.as_slices();
+                let len = data.len().min(FLUSH_CHUNK_SIZE);
+                (ring.removed, data[..len].to_vec())
+            };
+
+            match output.write_out(&chunk) {
+                Ok(count) => {
+                    self.ring.lock().unwrap().consume(start + count as u64);
+                    if count < chunk.len() {
+                        return;
+                    }
+                }
+                Err(e) => {
+                    if !output.failed {
+                        warn!("Cannot write the serial output: {}", e);
+                        output.failed = true;
+                    }
+                    // Nothing tells when the destination recovers, retry
+                    // with the next output.
+                    let mut ring = self.ring.lock().unwrap();
+                    let len = ring.buffer.len();
+                    ring.drop_front(len);
+                    break;
+                }
+            }
+        }
+        self.ring.lock().unwrap().overflowed = false;
+    }
+}
+
+impl Write for SerialBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut ring = self.ring.lock().unwrap();
+        let was_empty = ring.buffer.is_empty();
+        ring.buffer.extend(buf);
+        if ring.buffer.len() > ring.capacity {
+            let excess = ring.buffer.len() - ring.capacity;
+            ring.drop_front(excess);
+            if !ring.overflowed {
+                warn!("Serial output buffer full, dropping the oldest output");
+                ring.overflowed = true;
+            }
+        }
+        drop(ring);
+
+        if was_empty && !buf.is_empty() {
+            self.queued_evt.write(1)?;
+        }
+        Ok(buf.len())
+    }
+
+    // The output is flushed by the control loop.
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl SerialOutput for SerialBuffer {
+    fn has_room(&self, len: usize) -> bool {
+        let ring = self.ring.lock().unwrap();
+        ring.buffer.len() + len <= ring.capacity
+    }
+}
+
+/// Opens the standard output again, so that it can be switched to
+/// non-blocking mode without affecting the standard input sharing the same
+/// terminal. A regular file is duplicated instead, to keep writing at the
+/// current offset. This fails for the destinations which can't be opened
+/// again, such as sockets.
+pub fn reopen_stdout() -> io::Result<File> {
+    let fd = io::stdout().as_raw_fd();
+    // Safe because stat only holds integers.
+    let mut stat: libc::stat = unsafe { std::mem::zeroed() };
+    // Safe because the fd is valid and the return value is checked.
+    if unsafe { libc::fstat(fd, &mut stat) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    if stat.st_mode & libc::S_IFMT == libc::S_IFREG {
+        // Safe because the fd is valid and the return value is checked.
+        let dup_fd = unsafe { libc::dup(fd) };
+        if dup_fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // Safe because the fd was just duplicated, nothing else owns it.
+        return Ok(unsafe { File::from_raw_fd(dup_fd) });
+    }
+
+    OpenOptions::new()
+        .write(true)
+        .open(format!("/proc/self/fd/{}", fd))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use std::time::{Duration, Instant};
+
+    fn pipe() -> (File, File) {
+        let mut fds = [0; 2];
+        // Safe because the return value is checked.
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+        // Safe because the fds were just created.
+        unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) }
+    }
+
+    #[test]
+    fn test_serial_buffer() {
+        let (mut reader, writer) = pipe();
+        let mut serial_buffer = SerialBuffer::new(writer, 8192).unwrap();
+
+        // The output waits for the control loop, which is notified once.
+        serial_buffer.write_all(b"abc").unwrap();
+        serial_buffer.write_all(b"def").unwrap();
+        assert_eq!(serial_buffer.queued_evt.read().unwrap(), 1);
+        serial_buffer.flush_buffer();
+
+        let mut data = [0; 6];
+        reader.read_exact(&mut data).unwrap();
+        assert_eq!(&data, b"abcdef");
+        assert!(serial_buffer.ring.lock().unwrap().buffer.is_empty());
+    }
+
+    #[test]
+    fn test_serial_buffer_blocked() {
+        let (mut reader, writer) = pipe();
+        // Safe because the fd is valid.
+        let pipe_size = unsafe { libc::fcntl(writer.as_raw_fd(), libc::F_SETPIPE_SZ, 4096) };
+        assert!(pipe_size > 0);
+        let pipe_size = pipe_size as usize;
+
+        let mut serial_buffer = SerialBuffer::new(writer, 8192).unwrap();
+        serial_buffer.write_all(&vec![0xff; pipe_size]).unwrap();
+        serial_buffer.flush_buffer();
+
+        // With nobody reading the pipe, writing one byte at a time as the
+        // vCPU does still returns right away, the oldest output being
+        // dropped once the buffer is full.
+        let output: Vec<u8> = (0..10_000).map(|i| i as u8).collect();
+        let start = Instant::now();
+        for b in output.iter() {
+            serial_buffer.write_all(&[*b]).unwrap();
+            serial_buffer.flush().unwrap();
+        }
+        assert!(start.elapsed() < Duration::from_secs(1));
+        assert!(!serial_buffer.has_room(1));
+        assert_eq!(serial_buffer.dropped(), 10_000 - 8192);
+
+        // The control loop writes nothing more until the pipe has room.
+        serial_buffer.flush_buffer();
+        assert_eq!(serial_buffer.ring.lock().unwrap().buffer.len(), 8192);
+
+        let mut data = vec![0; pipe_size];
+        reader.read_exact(&mut data).unwrap();
+        assert!(data.iter().all(|&b| b == 0xff));
+
+        serial_buffer.flush_buffer();
+        assert!(serial_buffer.has_room(1));
+        let mut data = vec![0; pipe_size];
+        let count = reader.read(&mut data).unwrap();
+        assert!(count > 0);
+        assert_eq!(
+            &data[..count],
+            &output[10_000 - 8192..10_000 - 8192 + count]
+        );
+    }
+}
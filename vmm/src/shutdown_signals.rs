@@ -0,0 +1,121 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! The signals asking the VMM process to terminate, SIGTERM and SIGINT.
+//!
+//! They are read from a signalfd watched by the VMM epoll context, rather
+//! than handled asynchronously, so that they shut the VM down the same way
+//! an exit request does: the vCPU threads are joined and the terminal gets
+//! its settings back. The signalfd only gets the signals blocked by every
+//! thread of the process, any other thread taking them with their default
+//! action, i.e. killing the process. `block()` must hence be called before
+//! the process spawns any thread, the threads inheriting the signal mask of
+//! the thread creating them.
+
+use std::fs::File;
+use std::io::{self, Read};
+use std::mem;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::ptr::null_mut;
+
+/// The signals shutting the VMM down.
+pub const SHUTDOWN_SIGNALS: [libc::c_int; 2] = [libc::SIGTERM, libc::SIGINT];
+
+fn shutdown_sigset() -> libc::sigset_t {
+    // Safe because the set is initialized by sigemptyset() before use, and
+    // the signals added are valid ones.
+    unsafe {
+        let mut set: libc::sigset_t = mem::zeroed();
+        libc::sigemptyset(&mut set);
+        for signal in SHUTDOWN_SIGNALS.iter() {
+            libc::sigaddset(&mut set, *signal);
+        }
+        set
+    }
+}
+
+/// Blocks the shutdown signals in the calling thread, and in the threads it
+/// spawns from now on, leaving them to the signalfd.
+pub fn block() -> io::Result<()> {
+    let set = shutdown_sigset();
+    // Safe because the set is initialized, and the return value is checked.
+    let ret = unsafe { libc::pthread_sigmask(libc::SIG_BLOCK, &set, null_mut()) };
+    if ret != 0 {
+        return Err(io::Error::from_raw_os_error(ret));
+    }
+    Ok(())
+}
+
+/// The shutdown signals sent to the process, as a file descriptor becoming
+/// readable when one is pending.
+pub struct ShutdownSignals {
+    fd: File,
+}
+
+impl ShutdownSignals {
+    pub fn new() -> io::Result<Self> {
+        let set = shutdown_sigset();
+        // Safe because the set is initialized, and the return value is
+        // checked.
+        let fd = unsafe { libc::signalfd(-1, &set, libc::SFD_NONBLOCK | libc::SFD_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        // Safe because the fd was just created, nothing else owns it.
+        Ok(ShutdownSignals {
+            fd: unsafe { File::from_raw_fd(fd) },
+        })
+    }
+
+    /// Consumes a pending shutdown signal, returning its number, or `None`
+    /// if none is pending.
+    pub fn read(&mut self) -> io::Result<Option<libc::c_int>> {
+        // Safe because signalfd_siginfo only holds integers.
+        let mut info: libc::signalfd_siginfo = unsafe { mem::zeroed() };
+        // Safe because the buffer covers exactly the structure, which the
+        // kernel fills in whole.
+        let buf = unsafe {
+            std::slice::from_raw_parts_mut(
+                &mut info as *mut libc::signalfd_siginfo as *mut u8,
+                mem::size_of::<libc::signalfd_siginfo>(),
+            )
+        };
+        match self.fd.read(buf) {
+            Ok(_) => Ok(Some(info.ssi_signo as libc::c_int)),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+impl AsRawFd for ShutdownSignals {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd.as_raw_fd()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_shutdown_signals() {
+        // The signal is only sent to this thread, which blocks it, rather
+        // than to the whole test process.
+        block().unwrap();
+        let mut signals = ShutdownSignals::new().unwrap();
+        assert_eq!(signals.read().unwrap(), None);
+
+        // Safe because the signal is blocked, left pending for the
+        // signalfd.
+        assert_eq!(
+            unsafe { libc::pthread_kill(libc::pthread_self(), libc::SIGTERM) },
+            0
+        );
+        assert_eq!(signals.read().unwrap(), Some(libc::SIGTERM));
+        assert_eq!(signals.read().unwrap(), None);
+    }
+}
@@ -24,40 +24,79 @@ extern crate vm_allocator;
 extern crate vm_memory;
 extern crate vm_virtio;
 
-use crate::config::VmConfig;
-use crate::cpu;
+use crate::bus_trace::BusTracer;
+#[cfg(target_arch = "x86_64")]
+use crate::config::IrqChipKind;
+use crate::config::{DiskConfig, KernelConfig, KernelImage, NetConfig, StdinMode, VmConfig};
+use crate::cpu::{self, VcpuExitHandler};
+#[cfg(target_arch = "x86_64")]
+use crate::cpu_model;
 use crate::device_manager::{get_win_size, Console, DeviceManager, DeviceManagerError};
-use crate::memory_manager::{get_host_cpu_phys_bits, Error as MemoryManagerError, MemoryManager};
+use crate::device_registry::DeviceResources;
+use crate::host_check::{self, HostProbe};
+use crate::inherited_fd;
+use crate::landlock;
+use crate::memory_manager::{
+    boot_ram_regions, device_area, Error as MemoryManagerError, MemoryManager, MergeableRegion,
+};
+use crate::memory_slots::MemorySlots;
+use crate::serial_buffer::SerialBuffer;
 use anyhow::anyhow;
+#[cfg(target_arch = "aarch64")]
 use arch::layout;
-use devices::{ioapic, HotPlugNotificationFlags};
-use kvm_bindings::{kvm_enable_cap, kvm_userspace_memory_region, KVM_CAP_SPLIT_IRQCHIP};
+#[cfg(target_arch = "x86_64")]
+use devices::ioapic;
+use devices::HotPlugNotificationFlags;
+use kvm_bindings::kvm_userspace_memory_region;
+#[cfg(target_arch = "x86_64")]
+use kvm_bindings::{kvm_enable_cap, kvm_pit_config, KVM_CAP_SPLIT_IRQCHIP, KVM_PIT_SPEAKER_DUMMY};
 use kvm_ioctls::*;
 use linux_loader::cmdline::Cmdline;
 use linux_loader::loader::KernelLoader;
-use signal_hook::{iterator::Signals, SIGINT, SIGTERM, SIGWINCH};
+use signal_hook::{iterator::Signals, SIGWINCH};
+use std::collections::BTreeMap;
 use std::ffi::CString;
-use std::fs::File;
-use std::io;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, Cursor, Read, Seek};
+use std::os::unix::io::RawFd;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
 use std::{result, str, thread};
 use vm_allocator::{GsiApic, SystemAllocator};
-use vm_device::{Migratable, MigratableError, Pausable, Snapshotable};
+use vm_device::{
+    add_snapshot_state, snapshot_state, Migratable, MigratableError, Pausable, Snapshot,
+    Snapshotable,
+};
 use vm_memory::{
-    Address, Bytes, GuestAddress, GuestMemory, GuestMemoryMmap, GuestMemoryRegion, GuestUsize,
+    Address, Bytes, GuestAddress, GuestMemory, GuestMemoryMmap, GuestMemoryRegion, GuestRegionMmap,
+    GuestUsize,
 };
+use vm_virtio::gpu::Framebuffer;
+use vm_virtio::input::InputEvents;
 use vmm_sys_util::eventfd::EventFd;
 use vmm_sys_util::terminal::Terminal;
 
+#[cfg(target_arch = "x86_64")]
 const X86_64_IRQ_BASE: u32 = 5;
 
 // CPUID feature bits
+#[cfg(target_arch = "x86_64")]
 const TSC_DEADLINE_TIMER_ECX_BIT: u8 = 24; // tsc deadline timer ecx bit.
+#[cfg(target_arch = "x86_64")]
 const HYPERVISOR_ECX_BIT: u8 = 31; // Hypervisor ecx bit.
 
 // 64 bit direct boot entry offset for bzImage
+#[cfg(target_arch = "x86_64")]
 const KERNEL_64BIT_ENTRY_OFFSET: u64 = 0x200;
 
+// Files making a VM snapshot.
+pub const SNAPSHOT_CONFIG_FILE: &str = "config.json";
+const SNAPSHOT_STATE_FILE: &str = "state.json";
+const SNAPSHOT_MEMORY_FILE: &str = "memory";
+
 /// Errors associated with VM management
 #[derive(Debug)]
 pub enum Error {
@@ -76,6 +115,16 @@ pub enum Error {
     /// Cannot load the kernel in memory
     KernelLoad(linux_loader::loader::Error),
 
+    /// Cannot open the firmware image
+    FirmwareFile(io::Error),
+
+    /// Cannot create the file the bus accesses are traced to
+    BusTraceFile(io::Error),
+
+    #[cfg(target_arch = "aarch64")]
+    /// Booting a firmware is only supported on x86_64
+    FirmwareUnsupported,
+
     /// Cannot load the command line in memory
     LoadCmdLine(linux_loader::loader::Error),
 
@@ -85,9 +134,30 @@ pub enum Error {
     /// Cannot convert command line into CString
     CmdLineCString(std::ffi::NulError),
 
+    /// The command line, with the parameters the devices need, is too long
+    CmdlineTooLong(usize),
+
     /// Cannot configure system
     ConfigureSystem(arch::Error),
 
+    #[cfg(target_arch = "x86_64")]
+    /// Cannot write the SMBIOS tables
+    SmbiosSetup(arch::smbios::Error),
+
+    #[cfg(target_arch = "x86_64")]
+    /// Cannot apply the CPU model
+    CpuModel(cpu_model::Error),
+
+    /// SGX EPC sections are configured, but the vCPUs can't have SGX
+    SgxUnsupported,
+
+    /// Cannot restrict the filesystem access of the VMM
+    Landlock(landlock::Error),
+
+    #[cfg(target_arch = "aarch64")]
+    /// Cannot load the kernel Image in memory
+    KernelImageLoad(arch::Error),
+
     PoisonedState,
 
     /// Cannot create a device manager.
@@ -129,17 +199,26 @@ pub enum Error {
     /// VM is not running
     VmNotRunning,
 
+    /// The device is given by both its id and its index, or by none.
+    DeviceSelection,
+
     /// Cannot clone EventFd.
     EventFdClone(io::Error),
 
+    /// Cannot watch the timer moving the device removals forward.
+    WatchDeviceRemovals(io::Error),
+
     /// Invalid VM state transition
     InvalidStateTransition(VmState, VmState),
 
     /// Error from CPU handling
     CpuManager(cpu::Error),
 
-    /// Capability missing
-    CapabilityMissing(Cap),
+    /// KVM doesn't support some of the capabilities the VM needs
+    MissingKvmCapability(Vec<Cap>),
+
+    /// KVM API version isn't the one the VMM was written for
+    KvmApiVersion(i32),
 
     /// Cannot pause devices
     PauseDevices(MigratableError),
@@ -161,9 +240,162 @@ pub enum Error {
 
     /// Memory manager error
     MemoryManager(MemoryManagerError),
+
+    /// Cannot snapshot the VM
+    Snapshot(MigratableError),
+
+    /// Cannot restore the VM
+    Restore(MigratableError),
+
+    /// Cannot access the snapshot files
+    SnapshotIo(io::Error),
+
+    /// Cannot serialize or deserialize the snapshot
+    SnapshotSerialization(serde_json::Error),
 }
 pub type Result<T> = result::Result<T, Error>;
 
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::VmFd(_) => write!(f, "cannot open the VM file descriptor"),
+            Error::VmCreate(_) => write!(f, "cannot create the KVM instance"),
+            Error::VmSetup(_) => write!(f, "cannot set the VM up"),
+            Error::KernelFile(_) => write!(f, "cannot open the kernel image"),
+            Error::BusTraceFile(_) => write!(f, "cannot create the bus trace file"),
+            Error::KernelLoad(e) => write!(f, "cannot load the kernel in memory: {:?}", e),
+            Error::FirmwareFile(_) => write!(f, "cannot open the firmware image"),
+            #[cfg(target_arch = "aarch64")]
+            Error::FirmwareUnsupported => {
+                write!(f, "booting a firmware is only supported on x86_64")
+            }
+            Error::LoadCmdLine(e) => write!(f, "cannot load the command line in memory: {:?}", e),
+            Error::CmdLineInsertStr(e) => write!(f, "cannot modify the command line: {:?}", e),
+            Error::CmdLineCString(_) => write!(f, "cannot convert command line into CString"),
+            Error::CmdlineTooLong(len) => write!(
+                f,
+                "the command line, with the parameters the devices need, is {} bytes long, \
+                 more than the {} bytes allowed",
+                len,
+                arch::CMDLINE_MAX_SIZE
+            ),
+            Error::ConfigureSystem(e) => write!(f, "cannot configure system: {:?}", e),
+            #[cfg(target_arch = "x86_64")]
+            Error::SmbiosSetup(e) => write!(f, "cannot write the SMBIOS tables: {:?}", e),
+            #[cfg(target_arch = "x86_64")]
+            Error::CpuModel(e) => write!(f, "cannot apply the CPU model: {}", e),
+            Error::Landlock(e) => {
+                write!(f, "cannot restrict the filesystem access of the VMM: {}", e)
+            }
+            Error::SgxUnsupported => write!(
+                f,
+                "SGX EPC sections are configured, but SGX isn't supported by KVM on this host, \
+                 or is hidden by the CPU model"
+            ),
+            #[cfg(target_arch = "aarch64")]
+            Error::KernelImageLoad(e) => {
+                write!(f, "cannot load the kernel Image in memory: {:?}", e)
+            }
+            Error::PoisonedState => write!(f, "the VM state is poisoned"),
+            Error::DeviceManager(_) => write!(f, "cannot create a device manager"),
+            Error::Console(_) => write!(f, "write to the console failed"),
+            Error::SetTerminalRaw(_) => write!(f, "cannot setup terminal in raw mode"),
+            Error::SetTerminalCanon(_) => write!(f, "cannot setup terminal in canonical mode"),
+            Error::CreateSystemAllocator => write!(f, "cannot create the system allocator"),
+            Error::ParseNetworkParameters => write!(f, "failed parsing network parameters"),
+            Error::MemOverflow => write!(f, "memory overflow"),
+            Error::IoapicRangeAllocation => write!(f, "failed to allocate the IOAPIC memory range"),
+            Error::SignalHandlerSpawn(_) => write!(f, "cannot spawn a signal handler thread"),
+            Error::ThreadCleanup(e) => write!(f, "failed to join on vCPU threads: {:?}", e),
+            Error::KvmNew(e) => write!(
+                f,
+                "failed to create a new KVM instance: {}",
+                host_check::kvm_error_reason(e.errno())
+            ),
+            Error::VmNotCreated => write!(f, "VM is not created"),
+            Error::VmNotRunning => write!(f, "VM is not running"),
+            Error::DeviceSelection => {
+                write!(f, "either the id or the index of the device must be given")
+            }
+            Error::EventFdClone(_) => write!(f, "cannot clone EventFd"),
+            Error::WatchDeviceRemovals(_) => {
+                write!(
+                    f,
+                    "cannot watch the timer moving the device removals forward"
+                )
+            }
+            Error::InvalidStateTransition(from, to) => {
+                write!(f, "invalid VM state transition from {:?} to {:?}", from, to)
+            }
+            Error::CpuManager(_) => write!(f, "error from CPU handling"),
+            Error::MissingKvmCapability(caps) => {
+                let names: Vec<&str> = caps
+                    .iter()
+                    .map(|cap| host_check::capability_name(*cap))
+                    .collect();
+                write!(f, "host kernel lacks {}", names.join(", "))
+            }
+            Error::KvmApiVersion(version) => write!(
+                f,
+                "unsupported KVM API version {}, {} expected",
+                version,
+                host_check::KVM_API_VERSION
+            ),
+            Error::PauseDevices(_) => write!(f, "cannot pause devices"),
+            Error::ResumeDevices(_) => write!(f, "cannot resume devices"),
+            Error::PauseCpus(_) => write!(f, "cannot pause CPUs"),
+            Error::ResumeCpus(_) => write!(f, "cannot resume cpus"),
+            Error::Pause(_) => write!(f, "cannot pause VM"),
+            Error::Resume(_) => write!(f, "cannot resume VM"),
+            Error::MemoryManager(_) => write!(f, "memory manager error"),
+            Error::Snapshot(_) => write!(f, "cannot snapshot the VM"),
+            Error::Restore(_) => write!(f, "cannot restore the VM"),
+            Error::SnapshotIo(_) => write!(f, "cannot access the snapshot files"),
+            Error::SnapshotSerialization(_) => {
+                write!(f, "cannot serialize or deserialize the snapshot")
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::VmFd(e) => Some(e),
+            Error::VmCreate(e) => Some(e),
+            Error::VmSetup(e) => Some(e),
+            Error::KernelFile(e) => Some(e),
+            Error::BusTraceFile(e) => Some(e),
+            Error::FirmwareFile(e) => Some(e),
+            Error::CmdLineCString(e) => Some(e),
+            Error::DeviceManager(e) => Some(e),
+            Error::Console(e) => Some(e),
+            Error::SetTerminalRaw(e) => Some(e),
+            Error::SetTerminalCanon(e) => Some(e),
+            Error::SignalHandlerSpawn(e) => Some(e),
+            Error::KvmNew(e) => Some(e),
+            Error::EventFdClone(e) => Some(e),
+            Error::WatchDeviceRemovals(e) => Some(e),
+            Error::CpuManager(e) => Some(e),
+            Error::PauseDevices(e) => Some(e),
+            Error::ResumeDevices(e) => Some(e),
+            Error::PauseCpus(e) => Some(e),
+            Error::ResumeCpus(e) => Some(e),
+            Error::Pause(e) => Some(e),
+            Error::Resume(e) => Some(e),
+            Error::MemoryManager(e) => Some(e),
+            Error::Snapshot(e) => Some(e),
+            Error::Restore(e) => Some(e),
+            Error::SnapshotIo(e) => Some(e),
+            Error::SnapshotSerialization(e) => Some(e),
+            #[cfg(target_arch = "x86_64")]
+            Error::CpuModel(e) => Some(e),
+            Error::Landlock(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
 #[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq)]
 pub enum VmState {
     Created,
@@ -206,41 +438,179 @@ impl VmState {
     }
 }
 
+// Set while stdin is in raw mode, so that every exit path knows whether
+// the terminal has to be put back in canonical mode.
+static TERMINAL_RAW: AtomicBool = AtomicBool::new(false);
+
+fn set_terminal_raw() -> Result<()> {
+    io::stdin()
+        .lock()
+        .set_raw_mode()
+        .map_err(Error::SetTerminalRaw)?;
+    TERMINAL_RAW.store(true, Ordering::SeqCst);
+    Ok(())
+}
+
+/// Puts stdin back in canonical mode if the VMM switched it to raw mode.
+///
+/// This is safe to call from any thread, any number of times, and is
+/// meant to be called before the process exits.
+pub fn restore_terminal() -> Result<()> {
+    if TERMINAL_RAW.swap(false, Ordering::SeqCst) {
+        io::stdin()
+            .lock()
+            .set_canon_mode()
+            .map_err(Error::SetTerminalCanon)?;
+    }
+    Ok(())
+}
+
+// Kernel image, read from a file or from memory.
+trait KernelSource: Read + Seek + Send {}
+
+impl<T: Read + Seek + Send> KernelSource for T {}
+
+// Opens the kernel to boot, `cached` being the image kept by the VM rebooting,
+// if any, booted again without reading anything. Unless the config opts out,
+// a kernel read from a path or a file descriptor is kept in memory to be
+// handed over to the next VM on reboot.
+fn open_kernel(
+    kernel: &KernelConfig,
+    cached: Option<KernelImage>,
+) -> Result<(Box<dyn KernelSource>, Option<KernelImage>)> {
+    if let Some(image) = cached.or_else(|| kernel.image.clone()) {
+        return Ok((Box::new(Cursor::new(image.0.clone())), Some(image)));
+    }
+
+    let mut file = match kernel.fd {
+        Some(fd) => inherited_fd::open(fd, false, false, 0),
+        None => File::open(&kernel.path),
+    }
+    .map_err(Error::KernelFile)?;
+    if !kernel.cache {
+        return Ok((Box::new(file), None));
+    }
+
+    let mut image = Vec::new();
+    file.read_to_end(&mut image).map_err(Error::KernelFile)?;
+    let image = KernelImage(image.into());
+    Ok((Box::new(Cursor::new(image.0.clone())), Some(image)))
+}
+
 pub struct Vm {
-    kernel: File,
+    kernel: Option<Box<dyn KernelSource>>,
+    // The kernel kept in memory, booted again on reboot.
+    kernel_image: Option<KernelImage>,
+    firmware: Option<File>,
     threads: Vec<thread::JoinHandle<()>>,
     devices: DeviceManager,
     config: Arc<Mutex<VmConfig>>,
-    on_tty: bool,
+    raw_tty: bool,
     signals: Option<Signals>,
     state: RwLock<VmState>,
     cpu_manager: Arc<Mutex<cpu::CpuManager>>,
     memory_manager: Arc<Mutex<MemoryManager>>,
+    // When the VM was booted, resuming it from a pause not counting as a
+    // boot.
+    boot_ts: Option<Instant>,
 }
 
 impl Vm {
+    /// Checks KVM supports everything needed to run a VM with the given
+    /// configuration, reporting all the missing capabilities at once.
+    pub fn check_capabilities(kvm: &Kvm, config: &VmConfig) -> Result<()> {
+        let probe = HostProbe::from_kvm(kvm);
+        match probe.api_version {
+            Some(host_check::KVM_API_VERSION) => {}
+            version => return Err(Error::KvmApiVersion(version.unwrap_or_default())),
+        }
+
+        let missing = probe.missing_capabilities(config);
+        if !missing.is_empty() {
+            return Err(Error::MissingKvmCapability(missing));
+        }
+
+        Ok(())
+    }
+
     pub fn new(
         config: Arc<Mutex<VmConfig>>,
         exit_evt: EventFd,
         reset_evt: EventFd,
+    ) -> Result<Self> {
+        Vm::new_with_kernel_image(config, exit_evt, reset_evt, None)
+    }
+
+    /// Same as `new()`, booting `kernel_image` if given, as kept by the VM
+    /// being rebooted, rather than reading the kernel again.
+    pub fn new_with_kernel_image(
+        config: Arc<Mutex<VmConfig>>,
+        exit_evt: EventFd,
+        reset_evt: EventFd,
+        kernel_image: Option<KernelImage>,
     ) -> Result<Self> {
         let kvm = Kvm::new().map_err(Error::KvmNew)?;
 
-        // Check required capabilities:
-        if !kvm.check_extension(Cap::SignalMsi) {
-            return Err(Error::CapabilityMissing(Cap::SignalMsi));
-        }
+        let memory = {
+            let config = config.lock().unwrap();
+            MemoryManager::create_boot_ram(
+                &boot_ram_regions(&config.memory),
+                &config.memory.file,
+                &config.numa,
+            )
+            .map_err(Error::MemoryManager)?
+        };
 
-        if !kvm.check_extension(Cap::TscDeadlineTimer) {
-            return Err(Error::CapabilityMissing(Cap::TscDeadlineTimer));
-        }
+        Vm::create(kvm, config, exit_evt, reset_evt, memory, kernel_image)
+    }
 
-        if !kvm.check_extension(Cap::SplitIrqchip) {
-            return Err(Error::CapabilityMissing(Cap::SplitIrqchip));
+    /// Creates a VM whose boot RAM is `memory`, allocated by the caller
+    /// rather than by the VM, e.g. to share it with an external backend or
+    /// to preload it. The regions must cover the RAM layout of the memory
+    /// configuration, and are only registered with KVM.
+    pub fn with_memory(
+        kvm: Kvm,
+        config: Arc<Mutex<VmConfig>>,
+        exit_evt: EventFd,
+        reset_evt: EventFd,
+        memory: Vec<Arc<GuestRegionMmap>>,
+    ) -> Result<Self> {
+        Vm::create(kvm, config, exit_evt, reset_evt, memory, None)
+    }
+
+    fn create(
+        kvm: Kvm,
+        config: Arc<Mutex<VmConfig>>,
+        exit_evt: EventFd,
+        reset_evt: EventFd,
+        memory: Vec<Arc<GuestRegionMmap>>,
+        kernel_image: Option<KernelImage>,
+    ) -> Result<Self> {
+        Vm::check_capabilities(&kvm, &config.lock().unwrap())?;
+
+        #[cfg(target_arch = "aarch64")]
+        {
+            if config.lock().unwrap().sgx_epc.is_some() {
+                return Err(Error::SgxUnsupported);
+            }
         }
 
-        let kernel = File::open(&config.lock().unwrap().kernel.as_ref().unwrap().path)
-            .map_err(Error::KernelFile)?;
+        // The kernel is ignored when booting a firmware.
+        let (kernel, kernel_image, firmware) = {
+            let config = config.lock().unwrap();
+            match &config.firmware {
+                Some(firmware) => (
+                    None,
+                    None,
+                    Some(File::open(&firmware.path).map_err(Error::FirmwareFile)?),
+                ),
+                None => {
+                    let (source, image) =
+                        open_kernel(config.kernel.as_ref().unwrap(), kernel_image)?;
+                    (Some(source), image, None)
+                }
+            }
+        };
 
         let fd: VmFd;
         loop {
@@ -261,79 +631,163 @@ impl Vm {
         }
         let fd = Arc::new(fd);
 
-        // Set TSS
-        fd.set_tss_address(arch::x86_64::layout::KVM_TSS_ADDRESS.raw_value() as usize)
-            .map_err(Error::VmSetup)?;
-
-        let mut cpuid_patches = Vec::new();
-        // Create split irqchip
-        // Only the local APIC is emulated in kernel, both PICs and IOAPIC
-        // are not.
-        let mut cap: kvm_enable_cap = Default::default();
-        cap.cap = KVM_CAP_SPLIT_IRQCHIP;
-        cap.args[0] = ioapic::NUM_IOAPIC_PINS as u64;
-        fd.enable_cap(&cap).map_err(Error::VmSetup)?;
-
-        // Patch tsc deadline timer bit
-        cpuid_patches.push(cpu::CpuidPatch {
-            function: 1,
-            index: 0,
-            flags_bit: None,
-            eax_bit: None,
-            ebx_bit: None,
-            ecx_bit: Some(TSC_DEADLINE_TIMER_ECX_BIT),
-            edx_bit: None,
-        });
-
-        // Patch hypervisor bit
-        cpuid_patches.push(cpu::CpuidPatch {
-            function: 1,
-            index: 0,
-            flags_bit: None,
-            eax_bit: None,
-            ebx_bit: None,
-            ecx_bit: Some(HYPERVISOR_ECX_BIT),
-            edx_bit: None,
-        });
-
-        // Supported CPUID
-        let mut cpuid = kvm
-            .get_supported_cpuid(kvm_bindings::KVM_MAX_CPUID_ENTRIES)
-            .map_err(Error::VmSetup)?;
-
-        cpu::CpuidPatch::patch_cpuid(&mut cpuid, cpuid_patches);
+        #[cfg(target_arch = "x86_64")]
+        let (cpuid, msr_list, irqchip_kind) = {
+            // Set TSS
+            fd.set_tss_address(arch::x86_64::layout::KVM_TSS_ADDRESS.raw_value() as usize)
+                .map_err(Error::VmSetup)?;
+
+            let mut cpuid_patches = Vec::new();
+            let (irqchip_kind, create_pit) = {
+                let config = config.lock().unwrap();
+                (config.create_irqchip_kind, config.create_pit)
+            };
+            match irqchip_kind {
+                IrqChipKind::Kernel => {
+                    // PICs, IOAPIC and local APIC are all emulated in kernel.
+                    fd.create_irq_chip().map_err(Error::VmSetup)?;
+                }
+                IrqChipKind::Split => {
+                    // Create split irqchip
+                    // Only the local APIC is emulated in kernel, both PICs and IOAPIC
+                    // are not.
+                    let mut cap: kvm_enable_cap = Default::default();
+                    cap.cap = KVM_CAP_SPLIT_IRQCHIP;
+                    cap.args[0] = ioapic::NUM_IOAPIC_PINS as u64;
+                    fd.enable_cap(&cap).map_err(Error::VmSetup)?;
+                }
+                IrqChipKind::None => {}
+            }
+
+            if create_pit {
+                // The speaker port is left to a dummy handler rather than
+                // exiting to userspace.
+                let pit_config = kvm_pit_config {
+                    flags: KVM_PIT_SPEAKER_DUMMY,
+                    ..Default::default()
+                };
+                fd.create_pit2(pit_config).map_err(Error::VmSetup)?;
+            }
+
+            // Patch tsc deadline timer bit, which relies on the local APIC.
+            if irqchip_kind != IrqChipKind::None {
+                cpuid_patches.push(arch::x86_64::CpuidPatch {
+                    function: 1,
+                    index: 0,
+                    flags_bit: None,
+                    eax_bit: None,
+                    ebx_bit: None,
+                    ecx_bit: Some(TSC_DEADLINE_TIMER_ECX_BIT),
+                    edx_bit: None,
+                });
+            }
+
+            // Patch hypervisor bit
+            cpuid_patches.push(arch::x86_64::CpuidPatch {
+                function: 1,
+                index: 0,
+                flags_bit: None,
+                eax_bit: None,
+                ebx_bit: None,
+                ecx_bit: Some(HYPERVISOR_ECX_BIT),
+                edx_bit: None,
+            });
+
+            // Supported CPUID
+            let mut cpuid = kvm
+                .get_supported_cpuid(kvm_bindings::KVM_MAX_CPUID_ENTRIES)
+                .map_err(Error::VmSetup)?;
+
+            // The CPU model comes first, the patches relying on KVM rather
+            // than on the host.
+            cpu_model::find(&config.lock().unwrap().cpus.model)
+                .and_then(|cpu_model| cpu_model.apply(&mut cpuid))
+                .map_err(Error::CpuModel)?;
+
+            arch::x86_64::CpuidPatch::patch_cpuid(&mut cpuid, cpuid_patches);
+
+            if config.lock().unwrap().sgx_epc.is_some() && !arch::x86_64::sgx_supported(&cpuid) {
+                return Err(Error::SgxUnsupported);
+            }
+
+            // MSRs saved along with the vCPU state
+            let msr_list = kvm
+                .get_msr_index_list()
+                .map_err(Error::VmSetup)?
+                .as_slice()
+                .to_vec();
 
+            (cpuid, msr_list, irqchip_kind)
+        };
+
+        #[cfg(target_arch = "x86_64")]
         let ioapic = GsiApic::new(
             X86_64_IRQ_BASE,
             ioapic::NUM_IOAPIC_PINS as u32 - X86_64_IRQ_BASE,
         );
 
-        // Let's allocate 64 GiB of addressable MMIO space, starting at 0.
+        // The devices interrupts are GIC SPIs on aarch64.
+        #[cfg(target_arch = "aarch64")]
+        let ioapic = GsiApic::new(layout::IRQ_BASE, layout::IRQ_NUM);
+
+        let memory_config = config.lock().unwrap().memory.clone();
+        let mmio_hole = memory_config.mmio_hole();
+        let numa_nodes = config.lock().unwrap().numa.clone();
+
+        let ram_regions = boot_ram_regions(&memory_config);
+
+        // The RAM, hotpluggable RAM included, lives below the device area,
+        // which 64-bit BARs and device memory are allocated from.
+        let (start_of_device_area, end_of_device_area) =
+            device_area(&ram_regions, memory_config.hotplug_size, mmio_hole);
         let allocator = Arc::new(Mutex::new(
             SystemAllocator::new(
                 GuestAddress(0),
                 1 << 16 as GuestUsize,
                 GuestAddress(0),
-                1 << get_host_cpu_phys_bits(),
-                layout::MEM_32BIT_RESERVED_START,
-                layout::MEM_32BIT_DEVICES_SIZE,
+                start_of_device_area.raw_value(),
+                mmio_hole.start,
+                mmio_hole.size,
+                start_of_device_area,
+                end_of_device_area.unchecked_offset_from(start_of_device_area) + 1,
                 vec![ioapic],
             )
             .ok_or(Error::CreateSystemAllocator)?,
         ));
 
-        let memory_config = config.lock().unwrap().memory.clone();
-
-        let memory_manager = MemoryManager::new(
+        let memory_slots = Arc::new(MemorySlots::new(fd.clone(), kvm.get_nr_memslots()));
+        let memory_manager = MemoryManager::with_memory(
             allocator.clone(),
-            fd.clone(),
-            memory_config.size,
+            memory_slots,
+            memory,
+            &ram_regions,
             memory_config.hotplug_size,
             &memory_config.file,
             memory_config.mergeable,
+            memory_config.transparent_hugepages,
+            memory_config.prefault,
+            &numa_nodes,
+            mmio_hole,
         )
         .map_err(Error::MemoryManager)?;
 
+        // The EPC sections are laid out ahead of the devices, at the start
+        // of the device area.
+        #[cfg(target_arch = "x86_64")]
+        let cpuid = {
+            let sgx_epc = config.lock().unwrap().sgx_epc.clone();
+            let mut cpuid = cpuid;
+            if let Some(sgx_epc) = sgx_epc {
+                let sections = memory_manager
+                    .lock()
+                    .unwrap()
+                    .setup_sgx(&sgx_epc)
+                    .map_err(Error::MemoryManager)?;
+                arch::x86_64::set_sgx_epc_sections(&mut cpuid, &sections);
+            }
+            cpuid
+        };
+
         let guest_memory = memory_manager.lock().unwrap().guest_memory();
 
         let device_manager = DeviceManager::new(
@@ -346,50 +800,94 @@ impl Vm {
         )
         .map_err(Error::DeviceManager)?;
 
-        let on_tty = unsafe { libc::isatty(libc::STDIN_FILENO as i32) } != 0;
+        // Only a terminal read in raw mode needs its settings changed, and
+        // restored on exit.
+        let raw_tty = config.lock().unwrap().stdin == StdinMode::Raw
+            && unsafe { libc::isatty(libc::STDIN_FILENO as i32) } != 0;
+
+        let bus_tracer = match &config.lock().unwrap().trace_bus {
+            Some(trace_bus) => Some(Arc::new(
+                BusTracer::new(trace_bus).map_err(Error::BusTraceFile)?,
+            )),
+            None => None,
+        };
 
-        let boot_vcpus = config.lock().unwrap().cpus.boot_vcpus;
-        let max_vcpus = config.lock().unwrap().cpus.max_vcpus;
+        let cpus_config = config.lock().unwrap().cpus.clone();
+        #[cfg(target_arch = "x86_64")]
         let cpu_manager = cpu::CpuManager::new(
-            boot_vcpus,
-            max_vcpus,
+            &cpus_config,
             &device_manager,
             guest_memory,
             fd,
             cpuid,
             reset_evt,
+            exit_evt.try_clone().map_err(Error::EventFdClone)?,
+            msr_list,
+            irqchip_kind,
+            &numa_nodes,
+            bus_tracer,
+        )
+        .map_err(Error::CpuManager)?;
+        #[cfg(target_arch = "aarch64")]
+        let cpu_manager = cpu::CpuManager::new(
+            &cpus_config,
+            &device_manager,
+            guest_memory,
+            fd,
+            reset_evt,
+            exit_evt.try_clone().map_err(Error::EventFdClone)?,
+            Vec::new(),
+            &numa_nodes,
+            bus_tracer,
         )
         .map_err(Error::CpuManager)?;
 
         Ok(Vm {
             kernel,
+            kernel_image,
+            firmware,
             devices: device_manager,
             config,
-            on_tty,
+            raw_tty,
             threads: Vec::with_capacity(1),
             signals: None,
             state: RwLock::new(VmState::Created),
             cpu_manager,
             memory_manager,
+            boot_ts: None,
         })
     }
 
-    fn load_kernel(&mut self) -> Result<GuestAddress> {
+    // The command line given by the user, followed by the parameters the
+    // devices need.
+    fn cmdline(&self) -> Result<CString> {
+        let args = self.config.lock().unwrap().cmdline.args.clone();
+        let extra = self.devices.extra_cmdline();
+
+        // Along with the separating space and the terminating null byte.
+        let len = args.len() + extra.len() + 2;
+        if len > arch::CMDLINE_MAX_SIZE {
+            return Err(Error::CmdlineTooLong(len));
+        }
+
         let mut cmdline = Cmdline::new(arch::CMDLINE_MAX_SIZE);
-        cmdline
-            .insert_str(self.config.lock().unwrap().cmdline.args.clone())
-            .map_err(Error::CmdLineInsertStr)?;
-        for entry in self.devices.cmdline_additions() {
-            cmdline.insert_str(entry).map_err(Error::CmdLineInsertStr)?;
+        cmdline.insert_str(args).map_err(Error::CmdLineInsertStr)?;
+        if !extra.is_empty() {
+            cmdline.insert_str(extra).map_err(Error::CmdLineInsertStr)?;
         }
 
-        let cmdline_cstring = CString::new(cmdline).map_err(Error::CmdLineCString)?;
+        CString::new(cmdline).map_err(Error::CmdLineCString)
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    fn load_kernel(&mut self) -> Result<GuestAddress> {
+        let cmdline_cstring = self.cmdline()?;
         let guest_memory = self.memory_manager.lock().as_ref().unwrap().guest_memory();
         let mem = guest_memory.load_full();
         let entry_addr = match linux_loader::loader::Elf::load(
             mem.as_ref(),
             None,
-            &mut self.kernel,
+            self.kernel.as_mut().unwrap(),
             Some(arch::layout::HIGH_RAM_START),
         ) {
             Ok(entry_addr) => entry_addr,
@@ -397,7 +895,7 @@ impl Vm {
                 linux_loader::loader::BzImage::load(
                     mem.as_ref(),
                     None,
-                    &mut self.kernel,
+                    self.kernel.as_mut().unwrap(),
                     Some(arch::layout::HIGH_RAM_START),
                 )
                 .map_err(Error::KernelLoad)?
@@ -413,24 +911,48 @@ impl Vm {
         .map_err(Error::LoadCmdLine)?;
         let boot_vcpus = self.cpu_manager.lock().unwrap().boot_vcpus();
         let _max_vcpus = self.cpu_manager.lock().unwrap().max_vcpus();
+        let ram_regions = self.memory_manager.lock().unwrap().ram_regions();
 
         #[allow(unused_mut, unused_assignments)]
         let mut rsdp_addr: Option<GuestAddress> = None;
 
         #[cfg(feature = "acpi")]
         {
+            let numa_nodes = self.config.lock().unwrap().numa.clone();
             rsdp_addr = Some(crate::acpi::create_acpi_tables(
                 &mem,
                 &self.devices,
                 &self.cpu_manager,
                 &self.memory_manager,
+                &numa_nodes,
             ));
         }
 
+        let platform = self
+            .config
+            .lock()
+            .unwrap()
+            .platform
+            .clone()
+            .unwrap_or_default();
+        let smbios_config = arch::smbios::SmbiosConfig {
+            uuid: platform.uuid.as_ref().map(String::as_str),
+            serial_number: platform.serial_number.as_ref().map(String::as_str),
+            oem_strings: platform
+                .oem_strings
+                .as_ref()
+                .map(Vec::as_slice)
+                .unwrap_or(&[]),
+        };
+        let ram_size: u64 = ram_regions.iter().map(|(_, size)| *size as u64).sum();
+        arch::smbios::setup_smbios(&mem, &smbios_config, boot_vcpus, ram_size)
+            .map_err(Error::SmbiosSetup)?;
+
         match entry_addr.setup_header {
             Some(hdr) => {
                 arch::configure_system(
                     &mem,
+                    &ram_regions,
                     arch::layout::CMDLINE_START,
                     cmdline_cstring.to_bytes().len() + 1,
                     boot_vcpus,
@@ -450,6 +972,7 @@ impl Vm {
             None => {
                 arch::configure_system(
                     &mem,
+                    &ram_regions,
                     arch::layout::CMDLINE_START,
                     cmdline_cstring.to_bytes().len() + 1,
                     boot_vcpus,
@@ -463,20 +986,57 @@ impl Vm {
         }
     }
 
+    // The arm64 Image is loaded as is, the command line and the devices are
+    // passed to the kernel through the device tree.
+    #[cfg(target_arch = "aarch64")]
+    fn load_kernel(&mut self) -> Result<GuestAddress> {
+        let cmdline_cstring = self.cmdline()?;
+        let guest_memory = self.memory_manager.lock().as_ref().unwrap().guest_memory();
+        let mem = guest_memory.load_full();
+        let entry_addr = arch::aarch64::load_kernel(mem.as_ref(), self.kernel.as_mut().unwrap())
+            .map_err(Error::KernelImageLoad)?;
+
+        let boot_vcpus = self.cpu_manager.lock().unwrap().boot_vcpus();
+        let ram_regions = self.memory_manager.lock().unwrap().ram_regions();
+        arch::configure_system(
+            &mem,
+            &ram_regions,
+            &cmdline_cstring,
+            boot_vcpus,
+            self.devices.serial_device_info(),
+            self.devices.rtc_device_info(),
+            self.devices.mmio_device_info(),
+            false,
+        )
+        .map_err(Error::ConfigureSystem)?;
+
+        Ok(entry_addr)
+    }
+
+    // The firmware finds the RAM size through the CMOS and builds its own
+    // tables, so none of the kernel boot structures are written.
+    #[cfg(target_arch = "x86_64")]
+    fn load_firmware(&mut self) -> Result<()> {
+        self.memory_manager
+            .lock()
+            .unwrap()
+            .load_firmware(self.firmware.as_mut().unwrap())
+            .map_err(Error::MemoryManager)
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    fn load_firmware(&mut self) -> Result<()> {
+        Err(Error::FirmwareUnsupported)
+    }
+
     pub fn shutdown(&mut self) -> Result<()> {
         let mut state = self.state.try_write().map_err(|_| Error::PoisonedState)?;
         let new_state = VmState::Shutdown;
 
         state.valid_transition(new_state)?;
 
-        if self.on_tty {
-            // Don't forget to set the terminal in canonical mode
-            // before to exit.
-            io::stdin()
-                .lock()
-                .set_canon_mode()
-                .map_err(Error::SetTerminalCanon)?;
-        }
+        // Don't forget to set the terminal in canonical mode before to exit.
+        restore_terminal()?;
 
         // Trigger the termination of the signal_handler thread
         if let Some(signals) = self.signals.take() {
@@ -515,39 +1075,217 @@ impl Vm {
         }
 
         if let Some(desired_memory) = desired_memory {
-            if self
+            let new_region = self
                 .memory_manager
                 .lock()
                 .unwrap()
                 .resize(desired_memory)
-                .map_err(Error::MemoryManager)?
-            {
+                .map_err(Error::MemoryManager)?;
+            if let Some(new_region) = new_region {
+                self.devices
+                    .update_memory(&new_region)
+                    .map_err(Error::DeviceManager)?;
                 self.devices
                     .notify_hotplug(HotPlugNotificationFlags::MEMORY_DEVICES_CHANGED)
                     .map_err(Error::DeviceManager)?;
+
+                let mut config = self.config.lock().unwrap();
+                config.memory.hotplugged_size =
+                    Some(config.memory.hotplugged_size.unwrap_or(0) + new_region.len());
             }
             self.config.lock().unwrap().memory.size = desired_memory;
         }
         Ok(())
     }
 
-    fn os_signal_handler(signals: Signals, console_input_clone: Arc<Console>, on_tty: bool) {
+    /// Injects an NMI into the given vCPU, kicking it out of the guest if
+    /// it is running.
+    pub fn send_nmi(&self, cpu_id: u8) -> Result<()> {
+        self.nmi(Some(cpu_id))
+    }
+
+    /// Injects an NMI into every running vCPU.
+    pub fn send_nmi_to_all(&self) -> Result<()> {
+        self.nmi(None)
+    }
+
+    fn nmi(&self, cpu_id: Option<u8>) -> Result<()> {
+        match self.get_state()? {
+            VmState::Running | VmState::Paused => {}
+            _ => return Err(Error::VmNotRunning),
+        }
+
+        self.cpu_manager
+            .lock()
+            .unwrap()
+            .nmi(cpu_id)
+            .map_err(Error::CpuManager)
+    }
+
+    /// Types scancodes on the PS/2 keyboard of the guest.
+    pub fn send_keys(&self, scancodes: &[u8]) -> Result<()> {
+        match self.get_state()? {
+            VmState::Running | VmState::Paused => {}
+            _ => return Err(Error::VmNotRunning),
+        }
+
+        self.devices
+            .send_keys(scancodes)
+            .map_err(Error::DeviceManager)
+    }
+
+    /// Returns the advice KSM got about each guest RAM region.
+    pub fn mergeable_regions(&self) -> Vec<MergeableRegion> {
+        self.memory_manager.lock().unwrap().mergeable_regions()
+    }
+
+    /// The size of the guest RAM, hotplugged memory included.
+    pub fn ram_size(&self) -> u64 {
+        self.memory_manager.lock().unwrap().current_ram()
+    }
+
+    /// Returns the vCPUs which stopped on an error, and the errors. Each
+    /// failure is only returned once.
+    pub fn vcpu_failures(&self) -> Vec<cpu::VcpuFailure> {
+        self.cpu_manager.lock().unwrap().vcpu_failures()
+    }
+
+    /// Returns the register dump of every vCPU, pausing them meanwhile if
+    /// the VM is running. The vCPUs state is left untouched.
+    #[cfg(target_arch = "x86_64")]
+    pub fn dump_all_vcpus(&self) -> Result<Vec<String>> {
+        let state = self.get_state()?;
+        match state {
+            VmState::Running | VmState::Paused => {}
+            _ => return Err(Error::VmNotRunning),
+        }
+
+        let mut cpu_manager = self.cpu_manager.lock().unwrap();
+        // Paused vCPUs are left paused.
+        let running = state == VmState::Running;
+        if running {
+            cpu_manager.pause().map_err(Error::PauseCpus)?;
+        }
+        let dumps = cpu_manager.dump_vcpus().map_err(Error::CpuManager);
+        if running {
+            cpu_manager.resume().map_err(Error::ResumeCpus)?;
+        }
+
+        dumps
+    }
+
+    pub fn resize_disk(&self, index: usize, new_len: Option<u64>, force: bool) -> Result<()> {
+        self.devices
+            .resize_disk(index, new_len, force)
+            .map_err(Error::DeviceManager)
+    }
+
+    /// Brings the link of the virtio-net device `id` up or down.
+    pub fn set_link(&self, id: &str, up: bool) -> Result<()> {
+        self.devices
+            .set_net_link(id, up)
+            .map_err(Error::DeviceManager)
+    }
+
+    /// The index in the VM config of the disk `id`.
+    pub fn disk_index(&self, id: &str) -> Result<usize> {
+        self.devices.disk_index(id).map_err(Error::DeviceManager)
+    }
+
+    /// The id of the disk at `index` in the VM config.
+    pub fn disk_id(&self, index: usize) -> Result<String> {
+        self.devices.disk_id(index).map_err(Error::DeviceManager)
+    }
+
+    /// The id of the device attached to the PCI device id `index`.
+    pub fn device_at(&self, index: usize) -> Result<String> {
+        self.devices.device_at(index).map_err(Error::DeviceManager)
+    }
+
+    pub fn devices(&self) -> BTreeMap<String, DeviceResources> {
+        self.devices.devices().clone()
+    }
+
+    pub fn balloon_stats(&self) -> Result<vm_virtio::BalloonStats> {
+        self.devices.balloon_stats().map_err(Error::DeviceManager)
+    }
+
+    /// Changes the generation ID of the VM, telling the guest it runs from a
+    /// copy of its memory.
+    pub fn update_generation_id(&self) -> Result<()> {
+        match self.get_state()? {
+            VmState::Running | VmState::Paused => {}
+            _ => return Err(Error::VmNotRunning),
+        }
+
+        self.devices
+            .update_generation_id()
+            .map_err(Error::DeviceManager)
+    }
+
+    pub fn set_balloon_target(&self, size: u64) -> Result<()> {
+        self.devices
+            .set_balloon_target(size)
+            .map_err(Error::DeviceManager)
+    }
+
+    pub fn screenshot(&self, destination: &Path) -> Result<()> {
+        self.devices
+            .screenshot(destination)
+            .map_err(Error::DeviceManager)
+    }
+
+    pub fn vnc_display(&self) -> Result<(Arc<Mutex<Framebuffer>>, InputEvents)> {
+        self.devices.vnc_display().map_err(Error::DeviceManager)
+    }
+
+    pub fn add_disk(&mut self, disk_cfg: &DiskConfig) -> Result<(String, u32)> {
+        if self.get_state()? != VmState::Running {
+            return Err(Error::VmNotRunning);
+        }
+
+        self.devices
+            .add_disk(disk_cfg)
+            .map_err(Error::DeviceManager)
+    }
+
+    pub fn add_net(&mut self, net_cfg: &NetConfig) -> Result<(String, u32)> {
+        if self.get_state()? != VmState::Running {
+            return Err(Error::VmNotRunning);
+        }
+
+        self.devices.add_net(net_cfg).map_err(Error::DeviceManager)
+    }
+
+    /// Starts unplugging the device `id`, which is done with once returned
+    /// by `poll_device_removals()`.
+    pub fn remove_device(&mut self, id: &str) -> Result<()> {
+        if self.get_state()? != VmState::Running {
+            return Err(Error::VmNotRunning);
+        }
+
+        self.devices.remove_device(id).map_err(Error::DeviceManager)
+    }
+
+    /// Moves the device removals forward, on a tick of the timer returned by
+    /// `device_removal_timer_fd()`. Returns the devices done with.
+    pub fn poll_device_removals(&mut self) -> Vec<(String, Result<()>)> {
+        self.devices
+            .poll_device_removals()
+            .into_iter()
+            .map(|(id, result)| (id, result.map_err(Error::DeviceManager)))
+            .collect()
+    }
+
+    pub fn device_removal_timer_fd(&self) -> RawFd {
+        self.devices.removal_timer_fd()
+    }
+
+    fn os_signal_handler(signals: Signals, console_input_clone: Arc<Console>) {
         for signal in signals.forever() {
-            match signal {
-                SIGWINCH => {
-                    let (col, row) = get_win_size();
-                    console_input_clone.update_console_size(col, row);
-                }
-                SIGTERM | SIGINT => {
-                    if on_tty {
-                        io::stdin()
-                            .lock()
-                            .set_canon_mode()
-                            .expect("failed to restore terminal mode");
-                    }
-                    std::process::exit((signal != SIGTERM) as i32);
-                }
-                _ => (),
+            if signal == SIGWINCH {
+                let (col, row) = get_win_size();
+                console_input_clone.update_console_size(col, row);
             }
         }
     }
@@ -561,47 +1299,111 @@ impl Vm {
         let new_state = VmState::Running;
         current_state.valid_transition(new_state)?;
 
-        let entry_addr = self.load_kernel()?;
-
-        self.cpu_manager
-            .lock()
-            .unwrap()
-            .start_boot_vcpus(entry_addr)
-            .map_err(Error::CpuManager)?;
+        // A restored VM resumes where it was stopped, without booting the
+        // kernel again.
+        if self.cpu_manager.lock().unwrap().has_restored_vcpus() {
+            self.memory_manager.lock().unwrap().wait_for_prefault();
+            self.cpu_manager
+                .lock()
+                .unwrap()
+                .start_restored_vcpus()
+                .map_err(Error::CpuManager)?;
+            // The virtio devices the driver had activated are activated
+            // again on resume, once the vCPUs can take their interrupts.
+            self.devices.resume().map_err(Error::Resume)?;
+        } else {
+            // A firmware starts from the reset vector, where the vCPUs are
+            // pointed at when created.
+            let entry_addr = if self.firmware.is_some() {
+                self.load_firmware()?;
+                None
+            } else {
+                Some(self.load_kernel()?)
+            };
+
+            self.memory_manager.lock().unwrap().wait_for_prefault();
+            self.cpu_manager
+                .lock()
+                .unwrap()
+                .start_boot_vcpus(entry_addr)
+                .map_err(Error::CpuManager)?;
+        }
 
         if self.devices.console().input_enabled() {
             let console = self.devices.console().clone();
-            let signals = Signals::new(&[SIGWINCH, SIGINT, SIGTERM]);
+            // SIGTERM and SIGINT are read by the VMM, which shuts the VM
+            // down and restores the terminal.
+            let signals = Signals::new(&[SIGWINCH]);
             match signals {
                 Ok(signals) => {
                     self.signals = Some(signals.clone());
 
-                    let on_tty = self.on_tty;
                     self.threads.push(
                         thread::Builder::new()
                             .name("signal_handler".to_string())
-                            .spawn(move || Vm::os_signal_handler(signals, console, on_tty))
+                            .spawn(move || Vm::os_signal_handler(signals, console))
                             .map_err(Error::SignalHandlerSpawn)?,
                     );
                 }
                 Err(e) => error!("Signal not found {}", e),
             }
 
-            if self.on_tty {
-                io::stdin()
-                    .lock()
-                    .set_raw_mode()
-                    .map_err(Error::SetTerminalRaw)?;
+            if self.raw_tty {
+                set_terminal_raw()?;
             }
         }
 
         let mut state = self.state.try_write().map_err(|_| Error::PoisonedState)?;
         *state = new_state;
+        self.boot_ts = Some(Instant::now());
 
         Ok(())
     }
 
-    pub fn handle_stdin(&self) -> Result<()> {
+    /// How long the VM has been running for, pauses included, if it has been
+    /// booted.
+    pub fn uptime(&self) -> Option<Duration> {
+        self.boot_ts.map(|ts| ts.elapsed())
+    }
+
+    /// The kernel kept in memory, to be booted again by the next VM when
+    /// rebooting.
+    pub fn kernel_image(&self) -> Option<KernelImage> {
+        self.kernel_image.clone()
+    }
+
+    /// Returns the host address the guest memory at `addr` is mapped at,
+    /// failing if it isn't backed by any memory region.
+    ///
+    /// The pointer stays valid as long as the region holding it is mapped,
+    /// and the guest may change the memory it points to at any time.
+    pub fn translate(&self, addr: GuestAddress) -> Result<*const u8> {
+        self.memory_manager
+            .lock()
+            .unwrap()
+            .translate(addr)
+            .map_err(Error::MemoryManager)
+    }
+
+    /// Same as `translate()`, checking that the `len` bytes from `addr` are
+    /// all mapped, within a single memory region.
+    pub fn translate_range(&self, addr: GuestAddress, len: usize) -> Result<*const u8> {
+        self.memory_manager
+            .lock()
+            .unwrap()
+            .translate_range(addr, len)
+            .map_err(Error::MemoryManager)
+    }
+
+    /// The buffered output of the serial port, if it goes to a file or
+    /// stdout.
+    pub fn serial_buffer(&self) -> Option<&SerialBuffer> {
+        self.devices.serial_buffer()
+    }
+
+    /// Forwards pending stdin bytes to the console, returning how many were
+    /// read. Zero means stdin reached its end.
+    pub fn handle_stdin(&self) -> Result<usize> {
         let mut out = [0u8; 64];
         let count = io::stdin()
             .lock()
@@ -615,7 +1417,86 @@ impl Vm {
                 .map_err(Error::Console)?;
         }
 
-        Ok(())
+        Ok(count)
+    }
+
+    /// Saves the VM to the `dir` directory, so that it can be restored with
+    /// `Vm::restore()`. A running VM is paused while being saved.
+    pub fn snapshot(&mut self, dir: &Path) -> Result<()> {
+        let current_state = self.get_state()?;
+        match current_state {
+            VmState::Running => self.pause().map_err(Error::Pause)?,
+            VmState::Paused => {}
+            _ => return Err(Error::VmNotRunning),
+        }
+
+        let result = self.save(dir);
+
+        if current_state == VmState::Running {
+            self.resume().map_err(Error::Resume)?;
+        }
+
+        result
+    }
+
+    fn save(&self, dir: &Path) -> Result<()> {
+        fs::create_dir_all(dir).map_err(Error::SnapshotIo)?;
+
+        // This waits for the vCPUs to be stopped, so it must come before
+        // the memory is saved.
+        let snapshot = Snapshotable::snapshot(self).map_err(Error::Snapshot)?;
+
+        let memory_file =
+            File::create(dir.join(SNAPSHOT_MEMORY_FILE)).map_err(Error::SnapshotIo)?;
+        self.memory_manager
+            .lock()
+            .unwrap()
+            .dump(&memory_file)
+            .map_err(Error::MemoryManager)?;
+
+        let state_file = File::create(dir.join(SNAPSHOT_STATE_FILE)).map_err(Error::SnapshotIo)?;
+        serde_json::to_writer(state_file, &snapshot).map_err(Error::SnapshotSerialization)?;
+
+        let config_file =
+            File::create(dir.join(SNAPSHOT_CONFIG_FILE)).map_err(Error::SnapshotIo)?;
+        serde_json::to_writer(config_file, &*self.config.lock().unwrap())
+            .map_err(Error::SnapshotSerialization)
+    }
+
+    /// Creates a VM from a snapshot taken by `Vm::snapshot()`, using the
+    /// `config` saved along with it. Booting the VM resumes it.
+    ///
+    /// The devices get their state back, virtio devices resuming the
+    /// requests they hadn't completed. The snapshots of the VMs having
+    /// devices that cannot save their state fail instead.
+    pub fn restore(
+        dir: &Path,
+        config: Arc<Mutex<VmConfig>>,
+        exit_evt: EventFd,
+        reset_evt: EventFd,
+    ) -> Result<Self> {
+        let state_file = File::open(dir.join(SNAPSHOT_STATE_FILE)).map_err(Error::SnapshotIo)?;
+        let snapshot: Snapshot =
+            serde_json::from_reader(state_file).map_err(Error::SnapshotSerialization)?;
+
+        let mut vm = Vm::new(config, exit_evt, reset_evt)?;
+        Snapshotable::restore(&mut vm, snapshot).map_err(Error::Restore)?;
+
+        let memory_file = File::open(dir.join(SNAPSHOT_MEMORY_FILE)).map_err(Error::SnapshotIo)?;
+        vm.memory_manager
+            .lock()
+            .unwrap()
+            .load(&memory_file)
+            .map_err(Error::MemoryManager)?;
+
+        // The guest may be one of several copies, which shouldn't share the
+        // state derived from the generation ID, such as the RNG seeds.
+        #[cfg(feature = "acpi")]
+        vm.devices
+            .update_generation_id()
+            .map_err(Error::DeviceManager)?;
+
+        Ok(vm)
     }
 
     /// Gets a thread-safe reference counted pointer to the VM configuration.
@@ -623,6 +1504,22 @@ impl Vm {
         Arc::clone(&self.config)
     }
 
+    /// The (address, size) of the RAM regions hot-added to the guest.
+    pub fn hotplugged_ram_regions(&self) -> Vec<(u64, u64)> {
+        self.memory_manager
+            .lock()
+            .unwrap()
+            .hotplugged_regions()
+            .iter()
+            .map(|region| (region.start_addr().raw_value(), region.len()))
+            .collect()
+    }
+
+    /// The paths the VMM needs access to for this VM.
+    pub fn landlock_rules(&self) -> Vec<landlock::Rule> {
+        landlock::rules(&self.config.lock().unwrap())
+    }
+
     /// Get the VM state. Returns an error if the state is poisoned.
     pub fn get_state(&self) -> Result<VmState> {
         self.state
@@ -632,6 +1529,14 @@ impl Vm {
     }
 }
 
+impl Drop for Vm {
+    fn drop(&mut self) {
+        if let Err(e) = restore_terminal() {
+            warn!("Could not restore the terminal mode: {}", e);
+        }
+    }
+}
+
 impl Pausable for Vm {
     fn pause(&mut self) -> std::result::Result<(), MigratableError> {
         let mut state = self
@@ -673,7 +1578,97 @@ impl Pausable for Vm {
     }
 }
 
-impl Snapshotable for Vm {}
+const VM_SNAPSHOT_ID: &str = "vm";
+
+// Bumped whenever the state saved by a component changes, the snapshots taken
+// by other versions being refused.
+const VM_SNAPSHOT_VERSION: u16 = 1;
+
+#[derive(Deserialize, Serialize)]
+struct VmSnapshotState {
+    version: u16,
+}
+
+impl Snapshotable for Vm {
+    fn id(&self) -> String {
+        VM_SNAPSHOT_ID.to_string()
+    }
+
+    fn snapshot(&self) -> std::result::Result<Snapshot, MigratableError> {
+        let current_state = self
+            .get_state()
+            .map_err(|e| MigratableError::Snapshot(anyhow!("Could not get VM state: {:?}", e)))?;
+        if current_state != VmState::Paused {
+            return Err(MigratableError::Snapshot(anyhow!(
+                "Trying to snapshot while VM is {:?}",
+                current_state
+            )));
+        }
+        // The state of the in-kernel PICs, IOAPIC and PIT is not saved, the
+        // guest would not get its interrupts back once restored.
+        #[cfg(target_arch = "x86_64")]
+        {
+            if self.config.lock().unwrap().create_irqchip_kind == IrqChipKind::Kernel {
+                return Err(MigratableError::Snapshot(anyhow!(
+                    "Cannot snapshot a VM using the in-kernel irqchip"
+                )));
+            }
+        }
+
+        let mut snapshot = Snapshot::new(&self.id());
+        add_snapshot_state(
+            &mut snapshot,
+            &VmSnapshotState {
+                version: VM_SNAPSHOT_VERSION,
+            },
+        )?;
+        snapshot.add_snapshot(self.cpu_manager.lock().unwrap().snapshot()?);
+        snapshot.add_snapshot(self.memory_manager.lock().unwrap().snapshot()?);
+        snapshot.add_snapshot(self.devices.snapshot()?);
+
+        Ok(snapshot)
+    }
+
+    fn restore(&mut self, snapshot: Snapshot) -> std::result::Result<(), MigratableError> {
+        let version = snapshot_state::<VmSnapshotState>(&snapshot)?.map(|state| state.version);
+        if version != Some(VM_SNAPSHOT_VERSION) {
+            return Err(MigratableError::Restore(anyhow!(
+                "Unsupported snapshot version {:?}, expected {}",
+                version,
+                VM_SNAPSHOT_VERSION
+            )));
+        }
+
+        let mut snapshots = snapshot.snapshots;
+        let mut take = |id: String| {
+            snapshots
+                .remove(&id)
+                .map(|snapshot| *snapshot)
+                .ok_or_else(|| MigratableError::Restore(anyhow!("Missing {} snapshot", id)))
+        };
+
+        let memory_manager_id = self.memory_manager.lock().unwrap().id();
+        self.memory_manager
+            .lock()
+            .unwrap()
+            .restore(take(memory_manager_id)?)?;
+        // The devices were created before the hot-added RAM was restored.
+        let hotplugged_regions = self.memory_manager.lock().unwrap().hotplugged_regions();
+        for region in hotplugged_regions {
+            self.devices.update_memory(&region).map_err(|e| {
+                MigratableError::Restore(anyhow!("Could not update device memory: {:?}", e))
+            })?;
+        }
+        self.devices.restore(take(self.devices.id())?)?;
+        let cpu_manager_id = self.cpu_manager.lock().unwrap().id();
+        self.cpu_manager
+            .lock()
+            .unwrap()
+            .restore(take(cpu_manager_id)?)?;
+
+        Ok(())
+    }
+}
 impl Migratable for Vm {}
 
 #[cfg(test)]
@@ -732,20 +1727,36 @@ mod tests {
     fn test_vm_paused_transitions() {
         test_vm_state_transitions(VmState::Paused);
     }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn test_missing_kvm_capability_error() {
+        let e = Error::MissingKvmCapability(vec![Cap::Pit2, Cap::SplitIrqchip]);
+        assert_eq!(
+            e.to_string(),
+            "host kernel lacks KVM_CAP_PIT2, KVM_CAP_SPLIT_IRQCHIP"
+        );
+    }
 }
 
+// Real mode code writing the sum of al and bl, as a digit, then a new line
+// to the serial port, before halting. This example based on
+// https://lwn.net/Articles/658511/
+#[cfg(target_arch = "x86_64")]
+pub const TEST_VM_CODE: [u8; 12] = [
+    0xba, 0xf8, 0x03, /* mov $0x3f8, %dx */
+    0x00, 0xd8, /* add %bl, %al */
+    0x04, b'0', /* add $'0', %al */
+    0xee, /* out %al, (%dx) */
+    0xb0, b'\n', /* mov $'\n', %al */
+    0xee,  /* out %al, (%dx) */
+    0xf4,  /* hlt */
+];
+
+#[cfg(target_arch = "x86_64")]
 #[allow(unused)]
 pub fn test_vm() {
-    // This example based on https://lwn.net/Articles/658511/
-    let code = [
-        0xba, 0xf8, 0x03, /* mov $0x3f8, %dx */
-        0x00, 0xd8, /* add %bl, %al */
-        0x04, b'0', /* add $'0', %al */
-        0xee, /* out %al, (%dx) */
-        0xb0, b'\n', /* mov $'\n', %al */
-        0xee,  /* out %al, (%dx) */
-        0xf4,  /* hlt */
-    ];
+    let code = TEST_VM_CODE;
 
     let mem_size = 0x1000;
     let load_addr = GuestAddress(0x1000);
@@ -784,53 +1795,48 @@ pub fn test_vm() {
     vcpu_regs.rflags = 2;
     vcpu_fd.set_regs(&vcpu_regs).expect("set regs failed");
 
+    let handler = TestExitHandler {};
     loop {
-        match vcpu_fd.run().expect("run failed") {
-            VcpuExit::IoIn(addr, data) => {
-                println!(
-                    "IO in -- addr: {:#x} data [{:?}]",
-                    addr,
-                    str::from_utf8(&data).unwrap()
-                );
-            }
-            VcpuExit::IoOut(addr, data) => {
-                println!(
-                    "IO out -- addr: {:#x} data [{:?}]",
-                    addr,
-                    str::from_utf8(&data).unwrap()
-                );
-            }
-            VcpuExit::MmioRead(_addr, _data) => {}
-            VcpuExit::MmioWrite(_addr, _data) => {}
-            VcpuExit::Unknown => {}
-            VcpuExit::Exception => {}
-            VcpuExit::Hypercall => {}
-            VcpuExit::Debug => {}
-            VcpuExit::Hlt => {
-                println!("HLT");
-            }
-            VcpuExit::IrqWindowOpen => {}
-            VcpuExit::Shutdown => {}
-            VcpuExit::FailEntry => {}
-            VcpuExit::Intr => {}
-            VcpuExit::SetTpr => {}
-            VcpuExit::TprAccess => {}
-            VcpuExit::S390Sieic => {}
-            VcpuExit::S390Reset => {}
-            VcpuExit::Dcr => {}
-            VcpuExit::Nmi => {}
-            VcpuExit::InternalError => {}
-            VcpuExit::Osi => {}
-            VcpuExit::PaprHcall => {}
-            VcpuExit::S390Ucontrol => {}
-            VcpuExit::Watchdog => {}
-            VcpuExit::S390Tsch => {}
-            VcpuExit::Epr => {}
-            VcpuExit::SystemEvent => {}
-            VcpuExit::S390Stsi => {}
-            VcpuExit::IoapicEoi(_vector) => {}
-            VcpuExit::Hyperv => {}
-        }
-        //        r => panic!("unexpected exit reason: {:?}", r),
+        handler
+            .handle(vcpu_fd.run().expect("run failed"))
+            .expect("exit handling failed");
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+#[allow(unused)]
+struct TestExitHandler {}
+
+#[cfg(target_arch = "x86_64")]
+impl VcpuExitHandler for TestExitHandler {
+    fn io_in(&self, addr: u16, data: &mut [u8]) -> cpu::Result<bool> {
+        println!(
+            "IO in -- addr: {:#x} data [{:?}]",
+            addr,
+            str::from_utf8(data).unwrap()
+        );
+        Ok(true)
+    }
+
+    fn io_out(&self, addr: u16, data: &[u8]) -> cpu::Result<bool> {
+        println!(
+            "IO out -- addr: {:#x} data [{:?}]",
+            addr,
+            str::from_utf8(data).unwrap()
+        );
+        Ok(true)
+    }
+
+    fn hlt(&self) -> cpu::Result<bool> {
+        println!("HLT");
+        Ok(true)
+    }
+
+    fn shutdown(&self) -> cpu::Result<bool> {
+        Ok(true)
+    }
+
+    fn unhandled(&self, _exit: VcpuExit) -> cpu::Result<bool> {
+        Ok(true)
     }
 }
@@ -0,0 +1,93 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! VM generation ID, telling the guest it runs from a copy of its memory.
+//!
+//! The ID is a random UUID found in the guest memory, at the address the
+//! `VGEN` ACPI device gives through its `VGIA` and `ADDR` objects. It changes
+//! each time the VM is restored from a snapshot, or when requested through
+//! the API, the guest being notified through the GED so that it can reseed
+//! its random number generators.
+
+use acpi_tables::aml::{self, Aml};
+use arch::layout;
+use std::fs::File;
+use std::io::{self, Read};
+use vm_memory::{Bytes, GuestMemoryError, GuestMemoryMmap};
+
+pub(crate) const RANDOM_SOURCE: &str = "/dev/urandom";
+
+/// Size of the generation ID, in bytes.
+pub const GENERATION_ID_SIZE: usize = 16;
+
+/// Notification value telling the guest the generation ID changed.
+pub const GENERATION_ID_NOTIFY: u8 = 0x80;
+
+/// Returns a new random generation ID, in the form of a version 4 UUID.
+pub fn new_generation_id() -> io::Result<[u8; GENERATION_ID_SIZE]> {
+    let mut id = [0u8; GENERATION_ID_SIZE];
+    File::open(RANDOM_SOURCE)?.read_exact(&mut id)?;
+
+    // Version 4, variant 1 as described by RFC 4122.
+    id[6] = (id[6] & 0x0f) | 0x40;
+    id[8] = (id[8] & 0x3f) | 0x80;
+
+    Ok(id)
+}
+
+/// Writes the generation ID where the guest finds it.
+pub fn write_generation_id(
+    guest_mem: &GuestMemoryMmap,
+    id: &[u8; GENERATION_ID_SIZE],
+) -> Result<(), GuestMemoryError> {
+    guest_mem.write_slice(id, layout::VMGENID_START)
+}
+
+/// The ACPI device the guest finds the generation ID through.
+pub struct VmGenIdDevice {}
+
+impl Aml for VmGenIdDevice {
+    fn to_aml_bytes(&self) -> Vec<u8> {
+        let addr = layout::VMGENID_START.0;
+        let addr_lo = addr as u32;
+        let addr_hi = (addr >> 32) as u32;
+
+        aml::Device::new(
+            "_SB_.VGEN".into(),
+            vec![
+                &aml::Name::new("_HID".into(), &"VMGENCTR"),
+                &aml::Name::new("_CID".into(), &"VM_Gen_Counter"),
+                &aml::Name::new("_DDN".into(), &"VM_Gen_Counter"),
+                &aml::Name::new("VGIA".into(), &addr),
+                // The address split into its low and high 32 bits, as the
+                // guest expects it.
+                &aml::Name::new("ADDR".into(), &aml::Package::new(vec![&addr_lo, &addr_hi])),
+            ],
+        )
+        .to_aml_bytes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_new_generation_id() {
+        let id = new_generation_id().unwrap();
+        assert_eq!(id[6] >> 4, 4);
+        assert_eq!(id[8] >> 6, 2);
+
+        assert_ne!(id, new_generation_id().unwrap());
+    }
+
+    #[test]
+    fn test_generation_id_location() {
+        // The ID sits between the ACPI tables and the legacy BIOS area.
+        let end = layout::VMGENID_START.0 + GENERATION_ID_SIZE as u64;
+        assert!(layout::VMGENID_START.0 > layout::RSDP_POINTER.0);
+        assert!(end <= layout::LEGACY_BIOS_START.0);
+    }
+}
@@ -0,0 +1,1072 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Serves the display of the virtio-gpu device over VNC.
+//!
+//! The server implements the RFB protocol (versions 3.3 to 3.8) with no
+//! authentication and the Raw encoding only. A single client is served at a
+//! time: while one is connected, new connections are closed right away.
+//!
+//! Everything runs from the VMM event loop, the listening socket, the client
+//! connection and the refresh timer all being registered with the VMM epoll
+//! context. The content of the framebuffer is compared against what the
+//! client has last been sent, tile by tile, so that incremental updates only
+//! carry what the guest actually changed. They are sent on the next timer
+//! tick, which bounds how often a client gets refreshed.
+//!
+//! The client connection is non-blocking. What the socket doesn't take right
+//! away is buffered and sent on the next timer ticks, a client still
+//! receiving an update being sent the next one once done with it. A client
+//! letting too much output pile up is disconnected.
+//!
+//! The keyboard and pointer events of the client are forwarded to the guest
+//! through a virtio-input device.
+
+use std::cmp;
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use vm_virtio::gpu::Framebuffer;
+use vm_virtio::input::{
+    InputEvent, InputEvents, ABS_X, ABS_Y, BTN_LEFT, BTN_MIDDLE, BTN_RIGHT, EV_ABS, EV_KEY, EV_REL,
+    INPUT_ABS_MAX, REL_WHEEL,
+};
+use vmm_sys_util::timerfd::TimerFd;
+
+const PROTOCOL_VERSION: &[u8] = b"RFB 003.008\n";
+const SECURITY_NONE: u8 = 1;
+const DESKTOP_NAME: &[u8] = b"Cloud Hypervisor";
+
+// Client to server messages.
+const SET_PIXEL_FORMAT: u8 = 0;
+const SET_ENCODINGS: u8 = 2;
+const FRAMEBUFFER_UPDATE_REQUEST: u8 = 3;
+const KEY_EVENT: u8 = 4;
+const POINTER_EVENT: u8 = 5;
+const CLIENT_CUT_TEXT: u8 = 6;
+
+// Server to client messages.
+const FRAMEBUFFER_UPDATE: u8 = 0;
+const ENCODING_RAW: i32 = 0;
+
+// Pointer buttons, as reported by the clients.
+const POINTER_BUTTONS: &[(u8, u16)] = &[(0x01, BTN_LEFT), (0x02, BTN_MIDDLE), (0x04, BTN_RIGHT)];
+const POINTER_WHEEL_UP: u8 = 0x08;
+const POINTER_WHEEL_DOWN: u8 = 0x10;
+
+const BYTES_PER_PIXEL: usize = 4;
+// Granularity at which the framebuffer is compared against what the client
+// has been sent.
+const TILE_SIZE: u32 = 64;
+const REFRESH_INTERVAL: Duration = Duration::from_millis(33);
+// Output the client has yet to receive, beyond which it's disconnected. A
+// single message is always buffered, whatever its size, as a full update of
+// a large framebuffer doesn't fit.
+const MAX_PENDING_OUTPUT: usize = 64 << 20;
+const READ_SIZE: usize = 4096;
+// Pasted text is ignored, but still has to be buffered to be skipped.
+const MAX_CUT_TEXT_LEN: usize = 1 << 20;
+
+fn be16(bytes: &[u8]) -> u16 {
+    u16::from_be_bytes([bytes[0], bytes[1]])
+}
+
+fn be32(bytes: &[u8]) -> u32 {
+    u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+}
+
+fn invalid_data(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct PixelFormat {
+    bits_per_pixel: u8,
+    depth: u8,
+    big_endian: bool,
+    true_colour: bool,
+    red_max: u16,
+    green_max: u16,
+    blue_max: u16,
+    red_shift: u8,
+    green_shift: u8,
+    blue_shift: u8,
+}
+
+impl PixelFormat {
+    // The layout of the framebuffer pixels: blue, green, red, and an unused
+    // byte.
+    const NATIVE: PixelFormat = PixelFormat {
+        bits_per_pixel: 32,
+        depth: 24,
+        big_endian: false,
+        true_colour: true,
+        red_max: 255,
+        green_max: 255,
+        blue_max: 255,
+        red_shift: 16,
+        green_shift: 8,
+        blue_shift: 0,
+    };
+
+    fn parse(bytes: &[u8]) -> Self {
+        PixelFormat {
+            bits_per_pixel: bytes[0],
+            depth: bytes[1],
+            big_endian: bytes[2] != 0,
+            true_colour: bytes[3] != 0,
+            red_max: be16(&bytes[4..]),
+            green_max: be16(&bytes[6..]),
+            blue_max: be16(&bytes[8..]),
+            red_shift: bytes[10],
+            green_shift: bytes[11],
+            blue_shift: bytes[12],
+        }
+    }
+
+    fn write(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&[
+            self.bits_per_pixel,
+            self.depth,
+            self.big_endian as u8,
+            self.true_colour as u8,
+        ]);
+        buf.extend_from_slice(&self.red_max.to_be_bytes());
+        buf.extend_from_slice(&self.green_max.to_be_bytes());
+        buf.extend_from_slice(&self.blue_max.to_be_bytes());
+        buf.extend_from_slice(&[self.red_shift, self.green_shift, self.blue_shift, 0, 0, 0]);
+    }
+
+    // Colour maps aren't supported.
+    fn is_supported(&self) -> bool {
+        let bits = self.bits_per_pixel;
+        self.true_colour
+            && (bits == 8 || bits == 16 || bits == 32)
+            && self.red_shift < bits
+            && self.green_shift < bits
+            && self.blue_shift < bits
+    }
+
+    // Whether the framebuffer pixels can be sent as they are, the depth
+    // making no difference.
+    fn is_native(&self) -> bool {
+        PixelFormat {
+            depth: Self::NATIVE.depth,
+            ..*self
+        } == Self::NATIVE
+    }
+
+    // Appends a framebuffer pixel to `buf`, in this format.
+    fn encode(&self, pixel: &[u8], buf: &mut Vec<u8>) {
+        let scale = |value: u8, max: u16| u32::from(value) * u32::from(max) / 255;
+        let value = scale(pixel[2], self.red_max) << self.red_shift
+            | scale(pixel[1], self.green_max) << self.green_shift
+            | scale(pixel[0], self.blue_max) << self.blue_shift;
+
+        match (self.bits_per_pixel, self.big_endian) {
+            (8, _) => buf.push(value as u8),
+            (16, false) => buf.extend_from_slice(&(value as u16).to_le_bytes()),
+            (16, true) => buf.extend_from_slice(&(value as u16).to_be_bytes()),
+            (_, false) => buf.extend_from_slice(&value.to_le_bytes()),
+            (_, true) => buf.extend_from_slice(&value.to_be_bytes()),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Rect {
+    x: u32,
+    y: u32,
+    width: u32,
+    height: u32,
+}
+
+impl Rect {
+    fn clip(self, width: u32, height: u32) -> Rect {
+        let x = cmp::min(self.x, width);
+        let y = cmp::min(self.y, height);
+        Rect {
+            x,
+            y,
+            width: cmp::min(self.width, width - x),
+            height: cmp::min(self.height, height - y),
+        }
+    }
+}
+
+// The tiles of `area` whose content differs between `data` and `shadow`,
+// both being `stride` bytes wide images.
+fn dirty_rects(data: &[u8], shadow: &[u8], stride: usize, area: Rect) -> Vec<Rect> {
+    let mut rects = Vec::new();
+
+    let mut y = area.y;
+    while y < area.y + area.height {
+        let height = cmp::min(TILE_SIZE - y % TILE_SIZE, area.y + area.height - y);
+        let mut x = area.x;
+        while x < area.x + area.width {
+            let width = cmp::min(TILE_SIZE - x % TILE_SIZE, area.x + area.width - x);
+            let dirty = (y..y + height).any(|row| {
+                let start = row as usize * stride + x as usize * BYTES_PER_PIXEL;
+                let end = start + width as usize * BYTES_PER_PIXEL;
+                data[start..end] != shadow[start..end]
+            });
+            if dirty {
+                rects.push(Rect {
+                    x,
+                    y,
+                    width,
+                    height,
+                });
+            }
+            x += width;
+        }
+        y += height;
+    }
+
+    rects
+}
+
+// The linux key code of an X11 keysym. Shifted symbols map to the key
+// producing them, the client sending the shift key events on its own.
+fn keysym_to_code(keysym: u32) -> Option<u16> {
+    const LETTERS: [u16; 26] = [
+        30, 48, 46, 32, 18, 33, 34, 35, 23, 36, 37, 38, 50, 49, 24, 25, 16, 19, 31, 20, 22, 47, 17,
+        45, 21, 44,
+    ];
+
+    let code = match keysym {
+        0x20..=0x7e => match keysym as u8 {
+            c @ b'a'..=b'z' => LETTERS[usize::from(c - b'a')],
+            c @ b'A'..=b'Z' => LETTERS[usize::from(c - b'A')],
+            c @ b'1'..=b'9' => u16::from(c - b'1') + 2,
+            b'0' | b')' => 11,
+            b'!' => 2,
+            b'@' => 3,
+            b'#' => 4,
+            b'$' => 5,
+            b'%' => 6,
+            b'^' => 7,
+            b'&' => 8,
+            b'*' => 9,
+            b'(' => 10,
+            b'-' | b'_' => 12,
+            b'=' | b'+' => 13,
+            b'[' | b'{' => 26,
+            b']' | b'}' => 27,
+            b';' | b':' => 39,
+            b'\'' | b'"' => 40,
+            b'`' | b'~' => 41,
+            b'\\' | b'|' => 43,
+            b',' | b'<' => 51,
+            b'.' | b'>' => 52,
+            b'/' | b'?' => 53,
+            b' ' => 57,
+            _ => return None,
+        },
+        // BackSpace, Tab and ISO_Left_Tab, Return, Pause, Escape.
+        0xff08 => 14,
+        0xff09 | 0xfe20 => 15,
+        0xff0d => 28,
+        0xff13 => 119,
+        0xff1b => 1,
+        // Home, Left, Up, Right, Down, Page_Up, Page_Down, End.
+        0xff50 => 102,
+        0xff51 => 105,
+        0xff52 => 103,
+        0xff53 => 106,
+        0xff54 => 108,
+        0xff55 => 104,
+        0xff56 => 109,
+        0xff57 => 107,
+        // Print, Insert, Menu, KP_Enter.
+        0xff61 => 99,
+        0xff63 => 110,
+        0xff67 => 127,
+        0xff8d => 96,
+        // F1 to F10, then F11 and F12.
+        0xffbe..=0xffc7 => (keysym - 0xffbe) as u16 + 59,
+        0xffc8 => 87,
+        0xffc9 => 88,
+        // Shift, Control, Caps_Lock, Alt and Super, left then right.
+        0xffe1 => 42,
+        0xffe2 => 54,
+        0xffe3 => 29,
+        0xffe4 => 97,
+        0xffe5 => 58,
+        0xffe9 => 56,
+        0xffea => 100,
+        0xffeb => 125,
+        0xffec => 126,
+        // Delete.
+        0xffff => 111,
+        _ => return None,
+    };
+
+    Some(code)
+}
+
+// The events describing a pointer move to (x, y) on a `width` x `height`
+// display, and the button changes between `previous_mask` and `mask`.
+fn pointer_events(
+    previous_mask: u8,
+    mask: u8,
+    x: u16,
+    y: u16,
+    width: u32,
+    height: u32,
+) -> Vec<InputEvent> {
+    let scale = |value: u16, size: u32| {
+        let max = cmp::max(size, 2) - 1;
+        cmp::min(u32::from(value), max) * INPUT_ABS_MAX / max
+    };
+
+    let mut events = vec![
+        InputEvent::new(EV_ABS, ABS_X, scale(x, width)),
+        InputEvent::new(EV_ABS, ABS_Y, scale(y, height)),
+    ];
+
+    for &(button, code) in POINTER_BUTTONS {
+        if (previous_mask ^ mask) & button != 0 {
+            events.push(InputEvent::new(EV_KEY, code, u32::from(mask & button != 0)));
+        }
+    }
+
+    // The wheel is reported as buttons, a press meaning a notch.
+    let pressed = mask & !previous_mask;
+    if pressed & POINTER_WHEEL_UP != 0 {
+        events.push(InputEvent::new(EV_REL, REL_WHEEL, 1));
+    }
+    if pressed & POINTER_WHEEL_DOWN != 0 {
+        events.push(InputEvent::new(EV_REL, REL_WHEEL, -1i32 as u32));
+    }
+
+    events.push(InputEvent::sync());
+    events
+}
+
+#[derive(Debug, PartialEq)]
+enum Message {
+    SetPixelFormat(PixelFormat),
+    SetEncodings,
+    FramebufferUpdateRequest { incremental: bool, area: Rect },
+    KeyEvent { down: bool, keysym: u32 },
+    PointerEvent { mask: u8, x: u16, y: u16 },
+    ClientCutText,
+}
+
+// Parses the client message at the start of `buf`, and returns it along with
+// its length, or None if it isn't complete yet.
+fn parse_message(buf: &[u8]) -> io::Result<Option<(Message, usize)>> {
+    let msg_type = match buf.first() {
+        Some(msg_type) => *msg_type,
+        None => return Ok(None),
+    };
+
+    let len = match msg_type {
+        SET_PIXEL_FORMAT => 20,
+        SET_ENCODINGS if buf.len() >= 4 => 4 + 4 * usize::from(be16(&buf[2..])),
+        FRAMEBUFFER_UPDATE_REQUEST => 10,
+        KEY_EVENT => 8,
+        POINTER_EVENT => 6,
+        CLIENT_CUT_TEXT if buf.len() >= 8 => {
+            let text_len = be32(&buf[4..]) as usize;
+            if text_len > MAX_CUT_TEXT_LEN {
+                return Err(invalid_data(format!("{} bytes of cut text", text_len)));
+            }
+            8 + text_len
+        }
+        SET_ENCODINGS | CLIENT_CUT_TEXT => return Ok(None),
+        _ => return Err(invalid_data(format!("unknown message type {}", msg_type))),
+    };
+    if buf.len() < len {
+        return Ok(None);
+    }
+
+    let msg = match msg_type {
+        SET_PIXEL_FORMAT => Message::SetPixelFormat(PixelFormat::parse(&buf[4..])),
+        SET_ENCODINGS => Message::SetEncodings,
+        FRAMEBUFFER_UPDATE_REQUEST => Message::FramebufferUpdateRequest {
+            incremental: buf[1] != 0,
+            area: Rect {
+                x: u32::from(be16(&buf[2..])),
+                y: u32::from(be16(&buf[4..])),
+                width: u32::from(be16(&buf[6..])),
+                height: u32::from(be16(&buf[8..])),
+            },
+        },
+        KEY_EVENT => Message::KeyEvent {
+            down: buf[1] != 0,
+            keysym: be32(&buf[4..]),
+        },
+        POINTER_EVENT => Message::PointerEvent {
+            mask: buf[1],
+            x: be16(&buf[2..]),
+            y: be16(&buf[4..]),
+        },
+        _ => Message::ClientCutText,
+    };
+
+    Ok(Some((msg, len)))
+}
+
+struct Display {
+    framebuffer: Arc<Mutex<Framebuffer>>,
+    input: InputEvents,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum State {
+    // Waiting for the client protocol version.
+    Version,
+    // Waiting for the client to pick a security type.
+    Security,
+    // Waiting for the ClientInit message.
+    Init,
+    Running,
+}
+
+struct Client {
+    stream: TcpStream,
+    state: State,
+    minor_version: u8,
+    // Received bytes not making a complete message yet.
+    buffer: Vec<u8>,
+    format: PixelFormat,
+    // Area of the pending incremental update request.
+    update_request: Option<Rect>,
+    // The framebuffer, as the client has last been sent it.
+    shadow: Vec<u8>,
+    buttons: u8,
+    // Output the socket hasn't taken yet.
+    out: Vec<u8>,
+}
+
+impl Client {
+    fn new(stream: TcpStream) -> io::Result<Self> {
+        stream.set_nodelay(true)?;
+        stream.set_nonblocking(true)?;
+        let mut client = Client {
+            stream,
+            state: State::Version,
+            minor_version: 0,
+            buffer: Vec::new(),
+            format: PixelFormat::NATIVE,
+            update_request: None,
+            shadow: Vec::new(),
+            buttons: 0,
+            out: Vec::new(),
+        };
+        client.send(PROTOCOL_VERSION)?;
+        Ok(client)
+    }
+
+    // Queues `data`, and sends as much of the output as the socket takes.
+    fn send(&mut self, data: &[u8]) -> io::Result<()> {
+        if !self.out.is_empty() && self.out.len() + data.len() > MAX_PENDING_OUTPUT {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "the client is not reading its updates",
+            ));
+        }
+        self.out.extend_from_slice(data);
+        self.flush()
+    }
+
+    // Sends as much of the pending output as the socket takes, without
+    // blocking.
+    fn flush(&mut self) -> io::Result<()> {
+        let mut sent = 0;
+        while sent < self.out.len() {
+            match self.stream.write(&self.out[sent..]) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(len) => sent += len,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        self.out.drain(..sent);
+        Ok(())
+    }
+
+    // Reads what the client sent and handles every complete message.
+    // Returns false once the client closed the connection.
+    fn handle_readable(&mut self, display: &Display) -> io::Result<bool> {
+        let mut data = [0u8; READ_SIZE];
+        let len = match self.stream.read(&mut data) {
+            Ok(0) => return Ok(false),
+            Ok(len) => len,
+            Err(e)
+                if e.kind() == io::ErrorKind::Interrupted
+                    || e.kind() == io::ErrorKind::WouldBlock =>
+            {
+                return Ok(true)
+            }
+            Err(e) => return Err(e),
+        };
+        self.buffer.extend_from_slice(&data[..len]);
+
+        loop {
+            let consumed = self.process(display)?;
+            if consumed == 0 {
+                break;
+            }
+            self.buffer.drain(..consumed);
+        }
+
+        Ok(true)
+    }
+
+    // Handles the first complete message of the buffer, and returns its
+    // length, or 0 if there's none.
+    fn process(&mut self, display: &Display) -> io::Result<usize> {
+        match self.state {
+            State::Version => {
+                if self.buffer.len() < PROTOCOL_VERSION.len() {
+                    return Ok(0);
+                }
+                let version = &self.buffer[..PROTOCOL_VERSION.len()];
+                if !version.starts_with(b"RFB 003.") {
+                    return Err(invalid_data("unknown protocol".to_string()));
+                }
+                // Unknown 3.x versions must be handled as 3.3.
+                self.minor_version = match &version[8..11] {
+                    b"007" => 7,
+                    b"008" => 8,
+                    _ => 3,
+                };
+
+                if self.minor_version == 3 {
+                    // The server picks the security type on its own.
+                    self.send(&u32::from(SECURITY_NONE).to_be_bytes())?;
+                    self.state = State::Init;
+                } else {
+                    self.send(&[1, SECURITY_NONE])?;
+                    self.state = State::Security;
+                }
+                Ok(PROTOCOL_VERSION.len())
+            }
+            State::Security => {
+                let security = match self.buffer.first() {
+                    Some(security) => *security,
+                    None => return Ok(0),
+                };
+                if security != SECURITY_NONE {
+                    return Err(invalid_data(format!("unknown security type {}", security)));
+                }
+                if self.minor_version == 8 {
+                    self.send(&0u32.to_be_bytes())?;
+                }
+                self.state = State::Init;
+                Ok(1)
+            }
+            State::Init => {
+                // The shared flag doesn't matter, a single client is served.
+                if self.buffer.is_empty() {
+                    return Ok(0);
+                }
+                self.send_server_init(display)?;
+                self.state = State::Running;
+                Ok(1)
+            }
+            State::Running => match parse_message(&self.buffer)? {
+                Some((msg, len)) => {
+                    self.handle_message(msg, display)?;
+                    Ok(len)
+                }
+                None => Ok(0),
+            },
+        }
+    }
+
+    fn send_server_init(&mut self, display: &Display) -> io::Result<()> {
+        let (width, height) = {
+            let framebuffer = display.framebuffer.lock().unwrap();
+            (framebuffer.width(), framebuffer.height())
+        };
+
+        let mut msg = Vec::new();
+        msg.extend_from_slice(&(width as u16).to_be_bytes());
+        msg.extend_from_slice(&(height as u16).to_be_bytes());
+        PixelFormat::NATIVE.write(&mut msg);
+        msg.extend_from_slice(&(DESKTOP_NAME.len() as u32).to_be_bytes());
+        msg.extend_from_slice(DESKTOP_NAME);
+        self.send(&msg)
+    }
+
+    fn handle_message(&mut self, msg: Message, display: &Display) -> io::Result<()> {
+        match msg {
+            Message::SetPixelFormat(format) => {
+                if !format.is_supported() {
+                    return Err(invalid_data(format!(
+                        "unsupported pixel format {:?}",
+                        format
+                    )));
+                }
+                self.format = format;
+            }
+            Message::FramebufferUpdateRequest { incremental, area } => {
+                if incremental {
+                    self.update_request = Some(area);
+                } else {
+                    let framebuffer = display.framebuffer.lock().unwrap();
+                    self.send_update(&framebuffer, area, false)?;
+                }
+            }
+            Message::KeyEvent { down, keysym } => match keysym_to_code(keysym) {
+                Some(code) => display.input.send(&[
+                    InputEvent::new(EV_KEY, code, u32::from(down)),
+                    InputEvent::sync(),
+                ]),
+                None => debug!("Ignoring unknown keysym {:#x}", keysym),
+            },
+            Message::PointerEvent { mask, x, y } => {
+                let (width, height) = {
+                    let framebuffer = display.framebuffer.lock().unwrap();
+                    (framebuffer.width(), framebuffer.height())
+                };
+                display
+                    .input
+                    .send(&pointer_events(self.buttons, mask, x, y, width, height));
+                self.buttons = mask;
+            }
+            Message::SetEncodings | Message::ClientCutText => {}
+        }
+
+        Ok(())
+    }
+
+    // Sends the output left over, then the pending incremental update, if
+    // anything changed since the client has last been sent the framebuffer.
+    // A client still receiving an update gets the next one once done.
+    fn refresh(&mut self, display: &Display) -> io::Result<()> {
+        self.flush()?;
+        if !self.out.is_empty() {
+            return Ok(());
+        }
+        if let Some(area) = self.update_request {
+            let framebuffer = display.framebuffer.lock().unwrap();
+            if self.send_update(&framebuffer, area, true)? {
+                self.update_request = None;
+            }
+        }
+        Ok(())
+    }
+
+    // Sends `area` of the framebuffer, only the parts of it which changed
+    // for an incremental update. Returns whether an update has been sent,
+    // which doesn't happen for an incremental update with no change.
+    fn send_update(
+        &mut self,
+        framebuffer: &Framebuffer,
+        area: Rect,
+        incremental: bool,
+    ) -> io::Result<bool> {
+        let data = framebuffer.data();
+        if self.shadow.len() != data.len() {
+            self.shadow = vec![0; data.len()];
+        }
+
+        let stride = framebuffer.width() as usize * BYTES_PER_PIXEL;
+        let area = area.clip(framebuffer.width(), framebuffer.height());
+        let rects = if incremental {
+            dirty_rects(data, &self.shadow, stride, area)
+        } else {
+            vec![area]
+        };
+        if incremental && rects.is_empty() {
+            return Ok(false);
+        }
+
+        let mut msg = vec![FRAMEBUFFER_UPDATE, 0];
+        msg.extend_from_slice(&(rects.len() as u16).to_be_bytes());
+        for rect in rects {
+            for value in &[rect.x, rect.y, rect.width, rect.height] {
+                msg.extend_from_slice(&(*value as u16).to_be_bytes());
+            }
+            msg.extend_from_slice(&ENCODING_RAW.to_be_bytes());
+
+            for row in rect.y..rect.y + rect.height {
+                let start = row as usize * stride + rect.x as usize * BYTES_PER_PIXEL;
+                let end = start + rect.width as usize * BYTES_PER_PIXEL;
+                let pixels = &data[start..end];
+                if self.format.is_native() {
+                    msg.extend_from_slice(pixels);
+                } else {
+                    for pixel in pixels.chunks_exact(BYTES_PER_PIXEL) {
+                        self.format.encode(pixel, &mut msg);
+                    }
+                }
+                self.shadow[start..end].copy_from_slice(pixels);
+            }
+        }
+
+        self.send(&msg)?;
+        Ok(true)
+    }
+}
+
+/// VNC server displaying the framebuffer of a VM.
+pub struct VncServer {
+    listener: TcpListener,
+    timer: TimerFd,
+    display: Display,
+    client: Option<Client>,
+}
+
+impl VncServer {
+    /// Listens for VNC clients on `addr`, displaying `framebuffer` and
+    /// forwarding the input of the clients to `input`.
+    pub fn new(
+        addr: SocketAddr,
+        framebuffer: Arc<Mutex<Framebuffer>>,
+        input: InputEvents,
+    ) -> io::Result<Self> {
+        let listener = TcpListener::bind(addr)?;
+        listener.set_nonblocking(true)?;
+
+        Ok(VncServer {
+            listener,
+            timer: TimerFd::new()?,
+            display: Display { framebuffer, input },
+            client: None,
+        })
+    }
+
+    /// Displays another framebuffer, e.g. the one of a rebooted VM.
+    pub fn set_display(&mut self, framebuffer: Arc<Mutex<Framebuffer>>, input: InputEvents) {
+        self.display = Display { framebuffer, input };
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// The listening socket, readable when a client connects.
+    pub fn listener_fd(&self) -> RawFd {
+        self.listener.as_raw_fd()
+    }
+
+    /// The refresh timer, only armed while a client is connected.
+    pub fn timer_fd(&self) -> RawFd {
+        self.timer.as_raw_fd()
+    }
+
+    /// The connection of the current client, if any.
+    pub fn client_fd(&self) -> Option<RawFd> {
+        self.client.as_ref().map(|client| client.stream.as_raw_fd())
+    }
+
+    /// Accepts a pending connection, and returns its fd if it became the
+    /// current client.
+    pub fn accept(&mut self) -> Option<RawFd> {
+        let stream = match self.listener.accept() {
+            Ok((stream, addr)) => {
+                if self.client.is_some() {
+                    warn!("Refusing VNC client {}, another one is connected", addr);
+                    return None;
+                }
+                info!("VNC client {} connected", addr);
+                stream
+            }
+            Err(e) => {
+                if e.kind() != io::ErrorKind::WouldBlock {
+                    warn!("Cannot accept VNC client: {}", e);
+                }
+                return None;
+            }
+        };
+
+        let armed = Client::new(stream).and_then(|client| {
+            self.timer.reset(REFRESH_INTERVAL, Some(REFRESH_INTERVAL))?;
+            Ok(client)
+        });
+        match armed {
+            Ok(client) => {
+                self.client = Some(client);
+                self.client_fd()
+            }
+            Err(e) => {
+                warn!("Cannot set the VNC client up: {}", e);
+                None
+            }
+        }
+    }
+
+    /// Handles what the client sent. Returns false once it has to be
+    /// disconnected.
+    pub fn handle_client(&mut self) -> bool {
+        let display = &self.display;
+        let result = match self.client.as_mut() {
+            Some(client) => client.handle_readable(display),
+            None => return false,
+        };
+
+        match result {
+            Ok(connected) => {
+                if !connected {
+                    info!("VNC client disconnected");
+                }
+                connected
+            }
+            Err(e) => {
+                warn!("Disconnecting VNC client: {}", e);
+                false
+            }
+        }
+    }
+
+    /// Handles a refresh timer tick. Returns false once the client has to be
+    /// disconnected.
+    pub fn handle_timer(&mut self) -> bool {
+        let client = match self.client.as_mut() {
+            Some(client) => client,
+            // The timer is disarmed, and must not be waited on.
+            None => return false,
+        };
+
+        if let Err(e) = self.timer.wait() {
+            warn!("Cannot read the VNC refresh timer: {}", e);
+        }
+        match client.refresh(&self.display) {
+            Ok(()) => true,
+            Err(e) => {
+                warn!("Disconnecting VNC client: {}", e);
+                false
+            }
+        }
+    }
+
+    /// Closes the connection of the current client.
+    pub fn disconnect(&mut self) {
+        self.client = None;
+        if let Err(e) = self.timer.clear() {
+            warn!("Cannot disarm the VNC refresh timer: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use vm_virtio::gpu::Gpu;
+    use vm_virtio::input::{Input, EV_SYN};
+
+    #[test]
+    fn test_parse_message() {
+        assert_eq!(parse_message(&[]).unwrap(), None);
+        assert_eq!(parse_message(&[KEY_EVENT, 1, 0, 0, 0]).unwrap(), None);
+        assert_eq!(
+            parse_message(&[KEY_EVENT, 1, 0, 0, 0, 0, 0xff, 0x0d, POINTER_EVENT]).unwrap(),
+            Some((
+                Message::KeyEvent {
+                    down: true,
+                    keysym: 0xff0d
+                },
+                8
+            ))
+        );
+        assert_eq!(
+            parse_message(&[FRAMEBUFFER_UPDATE_REQUEST, 1, 0, 1, 0, 2, 0, 3, 0, 4]).unwrap(),
+            Some((
+                Message::FramebufferUpdateRequest {
+                    incremental: true,
+                    area: Rect {
+                        x: 1,
+                        y: 2,
+                        width: 3,
+                        height: 4
+                    }
+                },
+                10
+            ))
+        );
+        assert_eq!(
+            parse_message(&[SET_ENCODINGS, 0, 0, 2, 0, 0, 0, 0]).unwrap(),
+            None
+        );
+        assert_eq!(
+            parse_message(&[SET_ENCODINGS, 0, 0, 1, 0, 0, 0, 0]).unwrap(),
+            Some((Message::SetEncodings, 8))
+        );
+        assert_eq!(
+            parse_message(&[CLIENT_CUT_TEXT, 0, 0, 0, 0, 0, 0, 2, b'h', b'i']).unwrap(),
+            Some((Message::ClientCutText, 10))
+        );
+        assert!(parse_message(&[CLIENT_CUT_TEXT, 0, 0, 0, 0xff, 0, 0, 0]).is_err());
+        assert!(parse_message(&[42]).is_err());
+    }
+
+    #[test]
+    fn test_pixel_format() {
+        let mut bytes = Vec::new();
+        PixelFormat::NATIVE.write(&mut bytes);
+        assert_eq!(bytes.len(), 16);
+        assert_eq!(PixelFormat::parse(&bytes), PixelFormat::NATIVE);
+        assert!(PixelFormat::NATIVE.is_native());
+
+        // RGB565, big endian.
+        let format = PixelFormat {
+            bits_per_pixel: 16,
+            depth: 16,
+            big_endian: true,
+            true_colour: true,
+            red_max: 31,
+            green_max: 63,
+            blue_max: 31,
+            red_shift: 11,
+            green_shift: 5,
+            blue_shift: 0,
+        };
+        assert!(format.is_supported());
+        assert!(!format.is_native());
+        let mut pixel = Vec::new();
+        format.encode(&[0xff, 0x00, 0xff, 0x00], &mut pixel);
+        assert_eq!(pixel, vec![0xf8, 0x1f]);
+
+        assert!(!PixelFormat {
+            true_colour: false,
+            ..PixelFormat::NATIVE
+        }
+        .is_supported());
+    }
+
+    #[test]
+    fn test_dirty_rects() {
+        let (width, height) = (100, 70);
+        let stride = width * BYTES_PER_PIXEL;
+        let shadow = vec![0u8; stride * height];
+        let mut data = shadow.clone();
+        let all = Rect {
+            x: 0,
+            y: 0,
+            width: width as u32,
+            height: height as u32,
+        };
+        assert!(dirty_rects(&data, &shadow, stride, all).is_empty());
+
+        // A pixel in the last tile.
+        data[(65 * width + 70) * BYTES_PER_PIXEL] = 1;
+        assert_eq!(
+            dirty_rects(&data, &shadow, stride, all),
+            vec![Rect {
+                x: 64,
+                y: 64,
+                width: 36,
+                height: 6
+            }]
+        );
+        // Areas are split on the tile grid.
+        let area = Rect {
+            x: 60,
+            y: 60,
+            width: 20,
+            height: 10,
+        };
+        assert_eq!(
+            dirty_rects(&data, &shadow, stride, area),
+            vec![Rect {
+                x: 64,
+                y: 64,
+                width: 16,
+                height: 6
+            }]
+        );
+    }
+
+    #[test]
+    fn test_input_mapping() {
+        assert_eq!(keysym_to_code(u32::from(b'a')), Some(30));
+        assert_eq!(keysym_to_code(u32::from(b'Q')), Some(16));
+        assert_eq!(keysym_to_code(u32::from(b'0')), Some(11));
+        assert_eq!(keysym_to_code(u32::from(b'?')), Some(53));
+        assert_eq!(keysym_to_code(0xffc0), Some(61));
+        assert_eq!(keysym_to_code(0xffe3), Some(29));
+        assert_eq!(keysym_to_code(0x20ac), None);
+
+        assert_eq!(
+            pointer_events(0, 0x01 | POINTER_WHEEL_DOWN, 99, 0, 100, 50),
+            vec![
+                InputEvent::new(EV_ABS, ABS_X, INPUT_ABS_MAX),
+                InputEvent::new(EV_ABS, ABS_Y, 0),
+                InputEvent::new(EV_KEY, BTN_LEFT, 1),
+                InputEvent::new(EV_REL, REL_WHEEL, -1i32 as u32),
+                InputEvent::new(EV_SYN, 0, 0),
+            ]
+        );
+        // Only changes are reported.
+        assert_eq!(
+            pointer_events(0x01 | POINTER_WHEEL_DOWN, 0x04, 200, 0, 100, 50),
+            vec![
+                InputEvent::new(EV_ABS, ABS_X, INPUT_ABS_MAX),
+                InputEvent::new(EV_ABS, ABS_Y, 0),
+                InputEvent::new(EV_KEY, BTN_LEFT, 0),
+                InputEvent::new(EV_KEY, BTN_RIGHT, 1),
+                InputEvent::sync(),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_handshake_and_update() {
+        let gpu = Gpu::new(4, 2);
+        let input = Input::new().unwrap();
+        let mut server = VncServer::new(
+            "127.0.0.1:0".parse().unwrap(),
+            gpu.framebuffer(),
+            input.events(),
+        )
+        .unwrap();
+
+        let mut stream = TcpStream::connect(server.local_addr().unwrap()).unwrap();
+        assert!(server.accept().is_some());
+        let mut version = [0u8; 12];
+        stream.read_exact(&mut version).unwrap();
+        assert_eq!(&version, PROTOCOL_VERSION);
+
+        // Another client is refused while this one is connected.
+        let _other = TcpStream::connect(server.local_addr().unwrap()).unwrap();
+        assert!(server.accept().is_none());
+
+        stream.write_all(PROTOCOL_VERSION).unwrap();
+        assert!(server.handle_client());
+        let mut security = [0u8; 2];
+        stream.read_exact(&mut security).unwrap();
+        assert_eq!(security, [1, SECURITY_NONE]);
+
+        stream.write_all(&[SECURITY_NONE, 1]).unwrap();
+        assert!(server.handle_client());
+        let mut init = [0u8; 4 + 24 + 16];
+        stream.read_exact(&mut init).unwrap();
+        assert_eq!(&init[..4], &[0, 0, 0, 0]);
+        assert_eq!(&init[4..8], &[0, 4, 0, 2]);
+        assert_eq!(&init[24..28], &[0, 0, 0, DESKTOP_NAME.len() as u8]);
+        assert_eq!(&init[28..], DESKTOP_NAME);
+
+        stream
+            .write_all(&[FRAMEBUFFER_UPDATE_REQUEST, 0, 0, 0, 0, 0, 0, 4, 0, 2])
+            .unwrap();
+        assert!(server.handle_client());
+        let mut update = [0u8; 4 + 12 + 4 * 2 * 4];
+        stream.read_exact(&mut update).unwrap();
+        assert_eq!(&update[..4], &[FRAMEBUFFER_UPDATE, 0, 0, 1]);
+        assert_eq!(&update[4..16], &[0, 0, 0, 0, 0, 4, 0, 2, 0, 0, 0, 0]);
+
+        // Nothing changed, an incremental update stays pending.
+        stream
+            .write_all(&[FRAMEBUFFER_UPDATE_REQUEST, 1, 0, 0, 0, 0, 0, 4, 0, 2])
+            .unwrap();
+        assert!(server.handle_client());
+        assert!(server.handle_timer());
+        assert!(server.client.as_ref().unwrap().update_request.is_some());
+
+        drop(stream);
+        assert!(!server.handle_client());
+        server.disconnect();
+        assert!(server.client_fd().is_none());
+    }
+}